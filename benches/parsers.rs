@@ -0,0 +1,5 @@
+//! Throughput of the frame parsers, run with `cargo bench --features bench`
+use criterion::{criterion_group, criterion_main};
+
+criterion_group!(parsers, sensorflow::bench::benchmarks);
+criterion_main!(parsers);
@@ -0,0 +1,44 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    grpc();
+    #[cfg(feature = "capi")]
+    capi();
+}
+
+/// C header of the functions exported by src/capi.rs
+#[cfg(feature = "capi")]
+fn capi() {
+    println!("cargo:rerun-if-changed=src/capi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    cbindgen::generate(&dir)
+        .expect("Failed to generate C header")
+        .write_to_file(std::path::Path::new(&dir).join("include/sensorflow.h"));
+}
+
+/// Service stubs of proto/sensorflow.proto for the messages defined in src/output/grpc.rs
+#[cfg(feature = "grpc")]
+fn grpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type("super::proto::StreamRequest")
+            .output_type(output)
+            .codec_path("tonic_prost::ProstCodec")
+    };
+    let service = Service::builder()
+        .name("Sensorflow")
+        .package("sensorflow.v1")
+        .method(
+            method("subscribe", "Subscribe", "super::proto::Measurement")
+                .server_streaming()
+                .build(),
+        )
+        .method(method("get_latest", "GetLatest", "super::proto::LatestResponse").build())
+        .build();
+    Builder::new().compile(&[service]);
+}
@@ -0,0 +1,5 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sensorflow::{devices::elm327::ElmResponse, fuzz::frames};
+
+fuzz_target!(|data: &[u8]| frames::<ElmResponse>(data));
@@ -0,0 +1,5 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sensorflow::{devices::enocean::EnOceanFrame, fuzz::frames};
+
+fuzz_target!(|data: &[u8]| frames::<EnOceanFrame>(data));
@@ -0,0 +1,5 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sensorflow::{devices::jeelink::JeeLinkFrame, fuzz::frames};
+
+fuzz_target!(|data: &[u8]| frames::<JeeLinkFrame>(data));
@@ -0,0 +1,5 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sensorflow::{devices::pms::PmsFrame, fuzz::frames};
+
+fuzz_target!(|data: &[u8]| frames::<PmsFrame>(data));
@@ -0,0 +1,5 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sensorflow::{devices::wmbus::ImstFrame, fuzz::frames};
+
+fuzz_target!(|data: &[u8]| frames::<ImstFrame>(data));
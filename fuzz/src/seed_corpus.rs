@@ -0,0 +1,14 @@
+//! Write the seeds of all fuzz targets to `corpus/<target>/`, run from the `fuzz` directory
+use sensorflow::fuzz::{seeds, TARGETS};
+use std::path::Path;
+
+fn main() -> std::io::Result<()> {
+    for target in TARGETS {
+        let directory = Path::new("corpus").join(target);
+        std::fs::create_dir_all(&directory)?;
+        for (i, seed) in seeds(target).iter().enumerate() {
+            std::fs::write(directory.join(format!("seed-{}", i)), seed)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,47 @@
+#ifndef SENSORFLOW_H
+#define SENSORFLOW_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit */
+
+#include <stdint.h>
+
+// Input opened by sensorflow_open
+typedef struct SensorflowDevice SensorflowDevice;
+
+// Open the input described by `config`, the TOML of one `[[inputs]]` entry, e.g.
+// `type = "jeelink"` and `device = "/dev/ttyUSB0"`. Returns NULL on error.
+//
+// # Safety
+//
+// `config` must be a valid, NUL terminated string.
+struct SensorflowDevice *sensorflow_open(const char *config);
+
+// Wait up to `timeout_ms` milliseconds for the next measurement of `device`, or indefinitely
+// if negative. Returns the measurement as JSON, to be released by [sensorflow_free_string].
+// Returns NULL on timeout, at the end of the input or on error; [sensorflow_last_error] is
+// NULL only on timeout.
+//
+// # Safety
+//
+// `device` must have been returned by [sensorflow_open] and not been closed.
+char *sensorflow_poll(struct SensorflowDevice *device, int64_t timeout_ms);
+
+// Release a string returned by [sensorflow_poll]. Does nothing for NULL.
+//
+// # Safety
+//
+// `string` must have been returned by [sensorflow_poll] and not been released.
+void sensorflow_free_string(char *string);
+
+// Close `device` and release its resources. Does nothing for NULL.
+//
+// # Safety
+//
+// `device` must have been returned by [sensorflow_open] and not been closed.
+void sensorflow_close(struct SensorflowDevice *device);
+
+// Message of the last error on the calling thread, NULL if the last call succeeded. Valid
+// until the next call on the thread.
+const char *sensorflow_last_error(void);
+
+#endif  /* SENSORFLOW_H */
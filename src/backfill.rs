@@ -0,0 +1,209 @@
+//! Replay of recorded measurements into outputs.
+//!
+//! Measurements recorded by the `file` output in the `json` format, e.g. while the database was
+//! unreachable, are written to sinks as fast as they accept them. The recorded timestamps are
+//! kept, and sinks are flushed every `batch` measurements instead of after each one. Files
+//! ending in `.gz`, as left by a compressing rotation, are decompressed on the fly.
+use crate::{measurement::Measurement, output::json::from_json, output::OutputSink};
+use anyhow::Context;
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// Outcome of a backfill
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BackfillStats {
+    /// Measurements written to every sink
+    pub written: u64,
+    /// Recorded measurements without a timestamp, which cannot be placed in time
+    pub skipped: u64,
+}
+
+/// Open a recording, decompressing it if its name ends in `.gz`
+pub fn open(path: &Path) -> anyhow::Result<Box<dyn BufRead>> {
+    let file = File::open(path).with_context(|| format!("Cannot open {}", path.display()))?;
+    let reader: Box<dyn Read> = match path.extension().is_some_and(|e| e == "gz") {
+        true => Box::new(GzDecoder::new(file)),
+        false => Box::new(file),
+    };
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+/// Write the measurements of `recording` to all `sinks`, flushing them every `batch`
+/// measurements. Sinks are started before and shut down after the replay. If the replay fails,
+/// the sinks started so far are shut down as well, keeping what they buffered, and the error
+/// of the replay is returned.
+pub async fn backfill(
+    recording: impl BufRead,
+    sinks: &mut [Box<dyn OutputSink>],
+    batch: usize,
+) -> anyhow::Result<BackfillStats> {
+    let mut started = 0;
+    let mut result = Ok(BackfillStats::default());
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.start().await {
+            result = Err(e);
+            break;
+        }
+        started += 1;
+    }
+    if result.is_ok() {
+        result = replay(recording, sinks, batch).await;
+    }
+    for sink in sinks[..started].iter_mut() {
+        if let Err(e) = sink.shutdown().await {
+            match result {
+                Ok(_) => result = Err(e),
+                Err(_) => eprintln!("Failed to shut down sink: {:#}", e),
+            }
+        }
+    }
+    result
+}
+
+async fn replay(
+    recording: impl BufRead,
+    sinks: &mut [Box<dyn OutputSink>],
+    batch: usize,
+) -> anyhow::Result<BackfillStats> {
+    let mut stats = BackfillStats::default();
+    let mut pending = 0;
+    for (number, line) in recording.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let measurement = parse(&line).with_context(|| format!("Line {}", number + 1))?;
+        if measurement.time.is_none() {
+            stats.skipped += 1;
+            continue;
+        }
+        for sink in sinks.iter_mut() {
+            sink.write(&measurement).await?;
+        }
+        stats.written += 1;
+        pending += 1;
+        if pending >= batch {
+            for sink in sinks.iter_mut() {
+                sink.flush().await?;
+            }
+            pending = 0;
+        }
+    }
+    Ok(stats)
+}
+
+fn parse(line: &str) -> anyhow::Result<Measurement> {
+    from_json(&serde_json::from_str(line)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{backfill, open, BackfillStats};
+    use crate::{measurement::Measurement, output::OutputSink};
+    use async_trait::async_trait;
+    use chrono::DateTime;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::{BufRead, Write};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Recorder {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl OutputSink for Recorder {
+        async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+            let time = measurement.time.unwrap().timestamp_millis();
+            self.events.lock().unwrap().push(time.to_string());
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> anyhow::Result<()> {
+            self.events.lock().unwrap().push("flush".into());
+            Ok(())
+        }
+    }
+
+    const RECORDING: &str = r#"{"measurement":"power","tags":{},"fields":{"power":1.5},"timestamp":1000}
+{"measurement":"power","tags":{},"fields":{"power":1.5},"timestamp":null}
+{"measurement":"power","tags":{},"fields":{"power":2.5},"timestamp":2000}
+
+{"measurement":"power","tags":{},"fields":{"power":3.5},"timestamp":3000}
+"#;
+
+    #[tokio::test]
+    async fn test_backfill() {
+        let recorder = Recorder::default();
+        let mut sinks: Vec<Box<dyn OutputSink>> = vec![Box::new(recorder.clone())];
+        let stats = backfill(RECORDING.as_bytes(), &mut sinks, 2).await.unwrap();
+        assert_eq!(
+            stats,
+            BackfillStats {
+                written: 3,
+                skipped: 1
+            }
+        );
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            ["1000", "2000", "flush", "3000", "flush"]
+        );
+
+        let error = backfill("{}\n".as_bytes(), &mut sinks, 2)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Line 1");
+        // Shut down despite the error
+        assert_eq!(recorder.events.lock().unwrap().last().unwrap(), "flush");
+    }
+
+    struct Unreachable;
+
+    #[async_trait]
+    impl OutputSink for Unreachable {
+        async fn start(&mut self) -> anyhow::Result<()> {
+            anyhow::bail!("Connection refused")
+        }
+
+        async fn write(&mut self, _: &Measurement) -> anyhow::Result<()> {
+            unreachable!("Not started")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_start_shuts_down_started_sinks() {
+        let recorder = Recorder::default();
+        let mut sinks: Vec<Box<dyn OutputSink>> =
+            vec![Box::new(recorder.clone()), Box::new(Unreachable)];
+        let error = backfill(RECORDING.as_bytes(), &mut sinks, 2)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Connection refused");
+        assert_eq!(*recorder.events.lock().unwrap(), ["flush"]);
+    }
+
+    #[test]
+    fn test_open_compressed() {
+        let path = std::env::temp_dir().join(format!(
+            "sensorflow-backfill-{:08x}.ndjson.gz",
+            rand::random::<u32>()
+        ));
+        let mut encoder = GzEncoder::new(
+            std::fs::File::create(&path).unwrap(),
+            Compression::default(),
+        );
+        encoder.write_all(RECORDING.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+        let mut line = String::new();
+        open(&path).unwrap().read_line(&mut line).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            super::parse(&line).unwrap(),
+            Measurement::new("power")
+                .add_field("power", 1.5)
+                .add_time(DateTime::from_timestamp_millis(1000))
+        );
+    }
+}
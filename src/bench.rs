@@ -0,0 +1,251 @@
+//! Throughput of the frame parsers.
+//!
+//! A capture of the bytes sent by a device is fed to the parser of its protocol in reads of a
+//! fixed size, as a serial port would deliver them. [run] measures the frames per second and,
+//! if [CountingAllocator] is the global allocator, the allocations per frame. The criterion
+//! benchmarks in `benches/parsers.rs` use [benchmarks]. Requires the `bench` feature.
+use crate::{
+    devices::{cul::CulFrame, davis::LoopPacket, elm327::ElmResponse, enocean::EnOceanFrame},
+    devices::{jeelink::JeeLinkFrame, pms::PmsFrame, wmbus::AmberFrame, wmbus::ImstFrame},
+    error::FrameCheckError,
+    Frame,
+};
+use bytes::BytesMut;
+use criterion::{Criterion, Throughput};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt::{self, Display};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// System allocator counting allocations
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Number of allocations so far, always zero unless [CountingAllocator] is the global allocator
+pub fn allocations() -> u64 {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+/// Frames found in a capture
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub frames: u64,
+    /// Frames which failed to parse
+    pub errors: u64,
+}
+
+/// Feed `data` to the parser of `F` in reads of `chunk` bytes
+pub fn parse_capture<F: Frame>(data: &[u8], chunk: usize) -> Counts {
+    let mut buffer = BytesMut::with_capacity(256);
+    let mut counts = Counts::default();
+    for read in data.chunks(chunk.max(1)) {
+        buffer.extend_from_slice(read);
+        loop {
+            match F::check(&mut buffer) {
+                Ok(frame) => match F::parse(frame) {
+                    Ok(frame) => {
+                        black_box(frame);
+                        counts.frames += 1;
+                    }
+                    Err(_) => counts.errors += 1,
+                },
+                Err(FrameCheckError::Incomplete) => break,
+                Err(FrameCheckError::ChecksumError { .. }) => counts.errors += 1,
+                Err(_) => {
+                    counts.errors += 1;
+                    break;
+                }
+            }
+        }
+    }
+    counts
+}
+
+/// Protocol of a capture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    JeeLink,
+    Cul,
+    EnOcean,
+    Davis,
+    Pms,
+    Elm327,
+    /// Wireless M-Bus through an IMST iM871A
+    Imst,
+    /// Wireless M-Bus through an Amber AMB8465
+    Amber,
+}
+
+impl Protocol {
+    pub const ALL: [Protocol; 8] = [
+        Protocol::JeeLink,
+        Protocol::Cul,
+        Protocol::EnOcean,
+        Protocol::Davis,
+        Protocol::Pms,
+        Protocol::Elm327,
+        Protocol::Imst,
+        Protocol::Amber,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::JeeLink => "jeelink",
+            Protocol::Cul => "cul",
+            Protocol::EnOcean => "enocean",
+            Protocol::Davis => "davis",
+            Protocol::Pms => "pms",
+            Protocol::Elm327 => "elm327",
+            Protocol::Imst => "imst",
+            Protocol::Amber => "amber",
+        }
+    }
+
+    /// Feed `data` to the parser of the protocol in reads of `chunk` bytes
+    pub fn parse_capture(&self, data: &[u8], chunk: usize) -> Counts {
+        match self {
+            Protocol::JeeLink => parse_capture::<JeeLinkFrame>(data, chunk),
+            Protocol::Cul => parse_capture::<CulFrame>(data, chunk),
+            Protocol::EnOcean => parse_capture::<EnOceanFrame>(data, chunk),
+            Protocol::Davis => parse_capture::<LoopPacket>(data, chunk),
+            Protocol::Pms => parse_capture::<PmsFrame>(data, chunk),
+            Protocol::Elm327 => parse_capture::<ElmResponse>(data, chunk),
+            Protocol::Imst => parse_capture::<ImstFrame>(data, chunk),
+            Protocol::Amber => parse_capture::<AmberFrame>(data, chunk),
+        }
+    }
+
+    /// A valid frame, for protocols benchmarked without a capture
+    pub fn sample(&self) -> Option<&'static [u8]> {
+        match self {
+            Protocol::JeeLink => Some(b"OK 9 50 1 4 193 65\r\n"),
+            Protocol::Cul => Some(b"F12340011\r\n"),
+            Protocol::Elm327 => Some(b"41 0C 1A F8\r\r>"),
+            Protocol::Pms => Some(
+                b"\x42\x4d\x00\x1c\x00\x05\x00\x08\x00\x09\x00\x05\x00\x08\x00\x09\x04\x92\x01\x54\
+                  \x00\x32\x00\x04\x00\x01\x00\x00\x00\x00\x01\xf9",
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// Throughput of a parser on a capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub protocol: Protocol,
+    /// Bytes parsed in all runs
+    pub bytes: u64,
+    /// Frames found in all runs
+    pub counts: Counts,
+    pub elapsed: Duration,
+    pub allocations: u64,
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.elapsed.as_secs_f64();
+        writeln!(
+            f,
+            "{}: {} frames, {} errors in {} bytes, {:.3} s",
+            self.protocol.name(),
+            self.counts.frames,
+            self.counts.errors,
+            self.bytes,
+            seconds
+        )?;
+        writeln!(
+            f,
+            "{:.0} frames/s, {:.1} MB/s",
+            self.counts.frames as f64 / seconds,
+            self.bytes as f64 / seconds / 1e6
+        )?;
+        let frames = (self.counts.frames + self.counts.errors).max(1);
+        write!(
+            f,
+            "{:.2} allocations/frame",
+            self.allocations as f64 / frames as f64
+        )
+    }
+}
+
+/// Parse a capture `runs` times in reads of `chunk` bytes
+pub fn run(protocol: Protocol, data: &[u8], chunk: usize, runs: u32) -> Report {
+    let mut counts = Counts::default();
+    let allocations = allocations();
+    let start = Instant::now();
+    for _ in 0..runs {
+        let run = protocol.parse_capture(data, chunk);
+        counts.frames += run.frames;
+        counts.errors += run.errors;
+    }
+    Report {
+        protocol,
+        bytes: data.len() as u64 * runs as u64,
+        counts,
+        elapsed: start.elapsed(),
+        allocations: self::allocations() - allocations,
+    }
+}
+
+/// Criterion benchmarks of all parsers with a [Protocol::sample], on 1000 frames each
+pub fn benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for protocol in Protocol::ALL {
+        let Some(sample) = protocol.sample() else {
+            continue;
+        };
+        let capture = sample.repeat(1000);
+        group.throughput(Throughput::Elements(1000));
+        group.bench_function(protocol.name(), |b| {
+            b.iter(|| protocol.parse_capture(black_box(&capture), 64))
+        });
+    }
+    group.finish();
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run, Counts, Protocol};
+
+    #[test]
+    fn test_parse_capture() {
+        let mut capture = Protocol::JeeLink.sample().unwrap().repeat(3);
+        capture.extend_from_slice(b"OK 9 50 x 4 193 65\r\n");
+        for chunk in [1, 7, 64] {
+            assert_eq!(
+                Protocol::JeeLink.parse_capture(&capture, chunk),
+                Counts {
+                    frames: 3,
+                    errors: 1
+                }
+            );
+        }
+        for protocol in [Protocol::Cul, Protocol::Pms, Protocol::Elm327] {
+            let counts = protocol.parse_capture(&protocol.sample().unwrap().repeat(2), 16);
+            assert_eq!(counts.frames, 2, "{}", protocol.name());
+        }
+
+        let report = run(Protocol::JeeLink, &capture, 64, 2);
+        assert_eq!(report.bytes, 2 * capture.len() as u64);
+        assert_eq!(report.counts.frames, 6);
+    }
+}
@@ -7,11 +7,10 @@ async fn main() -> anyhow::Result<()> {
     let mut reader = devices::JeeLink::new(DEVICE)?;
 
     while let Ok(frame) = reader.read_frame().await {
-        match frame {
-            Some(frame) => println!("{}", frame.to_string()),
-            None => (),
+        if let Some(frame) = frame {
+            println!("{}", frame.to_string());
         }
     }
 
-    return Ok(());
+    Ok(())
 }
@@ -1,15 +1,63 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use sensorflow::{
-    devices::{self, Device},
-    output::ToOutput,
+    clock::TimestampPolicy,
+    config::{Config, OutputConfig, Reloader},
+    devices::{
+        self,
+        poll::Polled,
+        wmbus::{Mode, Receiver, WMBusConfig},
+        Device,
+    },
+    output::{influx::LineProtocolSink, stringify::StringifySink, validate::Validator, OutputSink},
+    pipeline::{ChannelConfig, OverflowPolicy, Pipeline, PipelineMetrics},
+    state::State,
+    transform::{
+        counter::{CounterConfig, CounterDeltas},
+        identity::{IdentityConfig, IdentityResolver, Remap},
+        metadata::{MetadataTags, SensorMetadata},
+        pressure::{PressureConfig, SeaLevelPressure},
+        schema::{SchemaMode, SchemaValidator},
+        tags::{StaticTags, Tags},
+        totals::{TotalConfig, Totals},
+        watchdog::{Watchdog, WatchdogConfig},
+        Transform,
+    },
 };
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+#[cfg(feature = "bench")]
+#[global_allocator]
+static ALLOCATOR: sensorflow::bench::CountingAllocator = sensorflow::bench::CountingAllocator;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about=None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input device to read from
     // #[arg(long, short)]
-    device: String,
+    #[arg(required_unless_present = "config")]
+    device: Option<String>,
+
+    /// Pipeline file defining inputs and outputs, replaces all other options. Changes of the file
+    /// are applied on SIGHUP
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Run the pipeline without writing measurements, printing statistics of valid
+    /// measurements and parse errors every minute instead of the measurements
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Count the bytes of frames and the garbage between them for the jeelink, cul, enocean and
+    /// nmea inputs, reported with hexdumps of the garbage by --dry-run
+    #[arg(long)]
+    diagnostics: bool,
 
     /// Input protocol
     #[arg(long, value_enum, default_value_t=ProtoEnum::Jeelink)]
@@ -18,12 +66,314 @@ struct Cli {
     /// Output protocol
     #[arg(long, value_enum, default_value_t=OutEnum::Stringify)]
     output: OutEnum,
+
+    /// Output format replacing the output protocol: template=PATH renders each measurement with
+    /// the Tera template in PATH
+    #[cfg(feature = "template")]
+    #[arg(long, value_parser = parse_format)]
+    format: Option<FormatArg>,
+
+    /// Firmware command enabling RSSI reporting of the JeeLink
+    #[arg(long)]
+    rssi_command: Option<String>,
+
+    /// Wireless M-Bus receiver hardware
+    #[arg(long, value_enum, default_value_t=WMBusReceiverEnum::Imst)]
+    wmbus_receiver: WMBusReceiverEnum,
+
+    /// Wireless M-Bus radio mode
+    #[arg(long, value_enum, default_value_t=WMBusModeEnum::T1)]
+    wmbus_mode: WMBusModeEnum,
+
+    /// AES key of a wireless M-Bus meter as METER_ID=HEXKEY, may be given multiple times
+    #[arg(long)]
+    wmbus_key: Vec<String>,
+
+    /// OBD-II parameter queried from an ELM327 adapter, may be given multiple times [default:
+    /// rpm, coolant_temperature, speed]
+    #[arg(long)]
+    obd_pid: Vec<devices::elm327::Pid>,
+
+    /// Request readings of a particulate matter sensor on the poll interval instead of receiving
+    /// them continuously
+    #[arg(long)]
+    pms_passive: bool,
+
+    /// Address of an I2C sensor, e.g. 0x77, defaults to the usual address of the sensor type
+    #[arg(long, value_parser = parse_i2c_address)]
+    i2c_address: Option<u8>,
+
+    /// Offset of the GPIO line counting pulses
+    #[arg(long, default_value_t = 0)]
+    gpio_line: u32,
+
+    /// Pulses per unit of a pulse counting meter, e.g. 1000 for 1000 imp/kWh
+    #[arg(long, default_value_t = 1000.)]
+    pulses_per_unit: f64,
+
+    /// Value queried from an SNMP agent as FIELD=OID or FIELD=OID*SCALE, may be given multiple
+    /// times
+    #[arg(long)]
+    snmp_oid: Vec<String>,
+
+    /// SNMPv2c community
+    #[arg(long, default_value = "public")]
+    snmp_community: String,
+
+    /// SNMPv3 user, SNMPv2c is used if not given
+    #[arg(long)]
+    snmp_user: Option<String>,
+
+    /// SNMPv3 authentication password
+    #[arg(long, default_value = "")]
+    snmp_auth_password: String,
+
+    /// SNMPv3 authentication protocol
+    #[arg(long, value_enum, default_value_t=SnmpAuthEnum::Sha1)]
+    snmp_auth_protocol: SnmpAuthEnum,
+
+    /// SNMPv3 privacy password, enables AES encryption
+    #[arg(long)]
+    snmp_priv_password: Option<String>,
+
+    /// Logical name of a LaCrosse sensor as NAME=ID, may be given multiple times. Readings of the
+    /// sensor are tagged with the name, which is kept when the sensor gets a new id on a battery
+    /// change
+    #[arg(long)]
+    sensor: Vec<String>,
+
+    /// Ask before assigning a new id to a sensor given by --sensor
+    #[arg(long)]
+    confirm_identity: bool,
+
+    /// Seconds added to the times carried by measurements, e.g. of remote inputs
+    #[arg(long, default_value_t = 0, allow_hyphen_values = true)]
+    timestamp_shift: i64,
+
+    /// Drop measurements whose time lies more than this many seconds ahead
+    #[arg(long)]
+    max_timestamp_future: Option<u64>,
+
+    /// Drop measurements whose time lies more than this many seconds back
+    #[arg(long)]
+    max_timestamp_age: Option<u64>,
+
+    /// Move times beyond --max-timestamp-future or --max-timestamp-age to the limit instead of
+    /// dropping the measurement
+    #[arg(long)]
+    clamp_timestamps: bool,
+
+    /// Tag added to every measurement as KEY=VALUE, e.g. site=home, may be given multiple times
+    #[arg(long)]
+    tag: Vec<String>,
+
+    /// TOML file with the attributes of the sensors, added to their measurements as tags. Sensors
+    /// with an expected interval are reported when they stop sending
+    #[arg(long)]
+    metadata: Option<PathBuf>,
+
+    /// Report sensors which missed this many consecutive reports. Their intervals are learned
+    /// unless given by --metadata
+    #[arg(long)]
+    watchdog_misses: Option<u32>,
+
+    /// Cumulative field of meters, e.g. volume, may be given multiple times. Its deltas and rates
+    /// are added, as for fields marked as counters by their device
+    #[arg(long)]
+    counter: Vec<String>,
+
+    /// Running total of a field per period as FIELD=PERIOD, e.g. energy_delta=day, with the
+    /// period one of hour, day, week, month and year in UTC. May be given multiple times
+    #[arg(long)]
+    total: Vec<String>,
+
+    /// Altitude of the sensors in metres, to add their pressure reduced to sea level. Altitudes of
+    /// sensors in --metadata take precedence
+    #[arg(long, allow_hyphen_values = true)]
+    altitude: Option<f64>,
+
+    /// File keeping the ids of the sensors given by --sensor, the sensors watched by the watchdog
+    /// and the running --total sums across restarts
+    #[arg(long)]
+    state: Option<PathBuf>,
+
+    /// Seconds between two queries of polled inputs and between two pulse counter readings
+    #[arg(long, default_value_t = 60)]
+    poll_interval: u64,
+
+    /// Number of measurements buffered between pipeline stages
+    #[arg(long, default_value_t = 1024)]
+    queue_capacity: usize,
+
+    /// Behaviour if a queue between pipeline stages is full
+    #[arg(long, value_enum, default_value_t=PolicyEnum::Block)]
+    overflow: PolicyEnum,
+
+    /// Check measurements against the schemas of the devices, dropping or tagging those which
+    /// do not conform
+    #[arg(long, value_enum)]
+    schema: Option<SchemaEnum>,
+
+    /// Print the queue depths, drops and lags of the pipeline and the frame statistics of the
+    /// inputs to stderr every this many seconds
+    #[arg(long)]
+    metrics_interval: Option<u64>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Validate a pipeline file and print a summary of the pipeline, exits with an error if the
+    /// file is invalid
+    CheckConfig {
+        /// Pipeline file
+        config: PathBuf,
+
+        /// Also open all inputs and start all outputs, to check devices and connectivity
+        #[arg(long)]
+        probe: bool,
+    },
+    /// Interactive console to the firmware of a JeeLink: lines are sent as firmware commands and
+    /// everything the JeeLink prints is shown
+    Console {
+        /// Serial port of the JeeLink
+        port: String,
+
+        /// Firmware command toggling the RSSI reporting, sent by :rssi
+        #[arg(long)]
+        rssi_command: Option<String>,
+    },
+    /// Write measurements recorded by a file output in the json format, e.g. during a database
+    /// outage, to the outputs of a pipeline file at full speed, keeping their timestamps
+    Backfill {
+        /// Recording, decompressed if its name ends in .gz
+        #[arg(long)]
+        from: PathBuf,
+
+        /// Pipeline file whose outputs receive the measurements, its inputs are not opened
+        #[arg(long)]
+        to: PathBuf,
+
+        /// Number of measurements written between flushes of the outputs
+        #[arg(long, default_value_t = 5000)]
+        batch: usize,
+    },
+    /// Measure the throughput and allocations of a frame parser on a capture of a device
+    #[cfg(feature = "bench")]
+    Bench {
+        /// Protocol of the capture
+        #[arg(long, value_enum)]
+        input: BenchInputEnum,
+
+        /// Raw bytes received from the device
+        #[arg(long)]
+        file: PathBuf,
+
+        /// Bytes per read
+        #[arg(long, default_value_t = 64)]
+        chunk: usize,
+
+        /// Number of times the capture is parsed
+        #[arg(long, default_value_t = 100)]
+        runs: u32,
+    },
+}
+
+#[cfg(feature = "bench")]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum BenchInputEnum {
+    Jeelink,
+    Cul,
+    Enocean,
+    Davis,
+    Pms,
+    Elm327,
+    /// Wireless M-Bus through an IMST iM871A
+    WmbusImst,
+    /// Wireless M-Bus through an Amber AMB8465
+    WmbusAmber,
+}
+
+#[cfg(feature = "bench")]
+impl From<BenchInputEnum> for sensorflow::bench::Protocol {
+    fn from(input: BenchInputEnum) -> Self {
+        use sensorflow::bench::Protocol;
+        match input {
+            BenchInputEnum::Jeelink => Protocol::JeeLink,
+            BenchInputEnum::Cul => Protocol::Cul,
+            BenchInputEnum::Enocean => Protocol::EnOcean,
+            BenchInputEnum::Davis => Protocol::Davis,
+            BenchInputEnum::Pms => Protocol::Pms,
+            BenchInputEnum::Elm327 => Protocol::Elm327,
+            BenchInputEnum::WmbusImst => Protocol::Imst,
+            BenchInputEnum::WmbusAmber => Protocol::Amber,
+        }
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum ProtoEnum {
     /// Jeelink v3
     Jeelink,
+    /// Busware CUL with culfw
+    Cul,
+    /// EnOcean gateway speaking ESP3
+    Enocean,
+    /// Wireless M-Bus receiver
+    Wmbus,
+    /// Davis Vantage Pro2/Vue console or WeatherLink logger
+    Davis,
+    /// ELM327 OBD-II adapter
+    Elm327,
+    /// GNSS receiver speaking NMEA 0183
+    Nmea,
+    /// Plantower PMS5003/PMS7003 particulate matter sensor
+    Pms,
+    /// WeatherFlow Tempest hub broadcasts, the device argument is the address to listen on, e.g.
+    /// 0.0.0.0:50222
+    Weatherflow,
+    /// DS18B20 sensors on the 1-Wire bus, the device argument is the sysfs directory
+    Ds18b20,
+    /// Host sensors of the hwmon subsystem, the device argument is the sysfs directory
+    Hwmon,
+    /// Bosch BME280 on I2C, the device argument is the bus, e.g. /dev/i2c-1
+    #[cfg(feature = "i2c")]
+    Bme280,
+    /// Sensirion SHT31 on I2C, the device argument is the bus
+    #[cfg(feature = "i2c")]
+    Sht31,
+    /// Sensirion SCD4x on I2C, the device argument is the bus
+    #[cfg(feature = "i2c")]
+    Scd4x,
+    /// CoAP resource observed for notifications, the device argument is its URI
+    #[cfg(feature = "coap")]
+    Coap,
+    /// SNMP agent, the device argument is the host with optional port
+    #[cfg(feature = "snmp")]
+    Snmp,
+    /// Pulse counter on a GPIO line, the device argument is the chip, e.g. /dev/gpiochip0
+    #[cfg(feature = "gpio")]
+    GpioPulses,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum WMBusReceiverEnum {
+    /// IMST iM871A
+    Imst,
+    /// Amber AMB8465
+    Amber,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum WMBusModeEnum {
+    T1,
+    C1,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum SnmpAuthEnum {
+    Md5,
+    Sha1,
+    Sha256,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -34,40 +384,524 @@ enum OutEnum {
     Influxdb,
 }
 
+#[cfg(feature = "template")]
+#[derive(Clone)]
+enum FormatArg {
+    Template(PathBuf),
+}
+
+#[cfg(feature = "template")]
+fn parse_format(s: &str) -> Result<FormatArg, String> {
+    match s.split_once('=') {
+        Some(("template", path)) => Ok(FormatArg::Template(path.into())),
+        _ => Err(format!("Expected template=PATH, got {}", s)),
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum PolicyEnum {
+    /// Wait for the next stage
+    Block,
+    /// Discard new measurements
+    DropNewest,
+    /// Discard the oldest queued measurements
+    DropOldest,
+}
+
+impl From<PolicyEnum> for OverflowPolicy {
+    fn from(policy: PolicyEnum) -> Self {
+        match policy {
+            PolicyEnum::Block => OverflowPolicy::Block,
+            PolicyEnum::DropNewest => OverflowPolicy::DropNewest,
+            PolicyEnum::DropOldest => OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum SchemaEnum {
+    /// Drop nonconforming measurements
+    Reject,
+    /// Tag nonconforming measurements with schemaError
+    Flag,
+}
+
+impl From<SchemaEnum> for SchemaMode {
+    fn from(mode: SchemaEnum) -> Self {
+        match mode {
+            SchemaEnum::Reject => SchemaMode::Reject,
+            SchemaEnum::Flag => SchemaMode::Flag,
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let Cli {
-        device,
-        input,
-        output,
-    } = Cli::parse();
+    let cli = Cli::parse();
 
-    let mut reader = make_reader(input, device)?;
+    match &cli.command {
+        Some(Command::CheckConfig { config, probe }) => return check_config(config, *probe).await,
+        Some(Command::Console { port, rssi_command }) => {
+            return console(port, rssi_command.as_deref()).await
+        }
+        Some(Command::Backfill { from, to, batch }) => return backfill(from, to, *batch).await,
+        #[cfg(feature = "bench")]
+        Some(Command::Bench {
+            input,
+            file,
+            chunk,
+            runs,
+        }) => {
+            let capture = std::fs::read(file)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", file.display(), e))?;
+            println!(
+                "{}",
+                sensorflow::bench::run((*input).into(), &capture, *chunk, *runs)
+            );
+            return Ok(());
+        }
+        None => (),
+    }
 
-    loop {
-        let res = reader.read_frame().await;
-        match res {
-            Ok(Some(frame)) => println!("{}", to_output(output, frame)),
-            Ok(_) => (),
-            Err(e) => Err(e)?,
+    let pipeline = match &cli.config {
+        Some(path) if cli.dry_run => Config::load(path)?.build_validating(&Validator::new())?,
+        Some(path) => {
+            let (pipeline, reloader) = Config::load(path)?.build_reloadable()?;
+            let hangup = signal(SignalKind::hangup())?;
+            tokio::spawn(reload_on_request(path.clone(), hangup, reloader));
+            pipeline
+        }
+        None => {
+            let config = ChannelConfig {
+                capacity: cli.queue_capacity,
+                policy: cli.overflow.into(),
+            };
+            let timestamps = TimestampPolicy {
+                shift: cli.timestamp_shift,
+                max_future: cli.max_timestamp_future,
+                max_age: cli.max_timestamp_age,
+                clamp: cli.clamp_timestamps,
+            };
+            timestamps.check()?;
+            let mut pipeline = Pipeline::new(config).with_timestamps(timestamps);
+            let state = cli.state.as_ref().map(State::open).transpose()?;
+            if !cli.sensor.is_empty() {
+                pipeline = pipeline.add_transform(identity_resolver(&cli, state.as_ref())?);
+            }
+            // Before the transforms adding fields
+            if let Some(mode) = cli.schema {
+                pipeline = pipeline.add_transform(Box::new(SchemaValidator::builtin(mode.into())));
+            }
+            let metadata = match &cli.metadata {
+                Some(path) => {
+                    let metadata = Arc::new(SensorMetadata::load(path)?);
+                    pipeline =
+                        pipeline.add_transform(Box::new(MetadataTags::new(metadata.clone())));
+                    metadata
+                }
+                None => Arc::default(),
+            };
+            let watchdog = match cli.watchdog_misses {
+                Some(misses) => Some(WatchdogConfig {
+                    misses,
+                    ..Default::default()
+                }),
+                None => metadata.has_intervals().then(WatchdogConfig::default),
+            };
+            if let Some(config) = watchdog {
+                let mut watchdog = Watchdog::with_config(metadata.clone(), config)?;
+                if let Some(state) = &state {
+                    watchdog = watchdog.with_state(state.clone())?;
+                }
+                pipeline = pipeline.add_transform(Box::new(watchdog));
+            }
+            if let Some(altitude) = cli.altitude {
+                let config = PressureConfig {
+                    altitude: Some(altitude),
+                    ..Default::default()
+                };
+                pipeline =
+                    pipeline.add_transform(Box::new(SeaLevelPressure::new(config, metadata)));
+            }
+            if !cli.counter.is_empty() {
+                pipeline = pipeline.add_transform(Box::new(CounterDeltas::new(CounterConfig {
+                    fields: cli.counter.clone(),
+                    ..Default::default()
+                })));
+            }
+            if !cli.total.is_empty() {
+                pipeline = pipeline.add_transform(totals(&cli, state.as_ref())?);
+            }
+            if !cli.tag.is_empty() {
+                pipeline = pipeline.add_transform(static_tags(&cli)?);
+            }
+            if cli.dry_run {
+                let validator = Validator::new();
+                pipeline
+                    .add_input(validator.wrap(make_reader(&cli)?))
+                    .add_output(Box::new(validator.sink()))
+            } else {
+                pipeline
+                    .add_input(make_reader(&cli)?)
+                    .add_output(make_sink(&cli)?)
+            }
         }
+    };
+
+    if let Some(seconds) = cli.metrics_interval {
+        tokio::spawn(log_metrics(
+            pipeline.metrics(),
+            Duration::from_secs(seconds),
+        ));
+    }
+    // Stopping the service sends SIGTERM, which has to flush the outputs and the state as well
+    let mut terminate = signal(SignalKind::terminate())?;
+    pipeline
+        .run_until(async move {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        })
+        .await
+}
+
+/// Print the metrics of the pipeline every `period`
+async fn log_metrics(metrics: PipelineMetrics, period: Duration) {
+    let mut ticks = tokio::time::interval(period.max(Duration::from_secs(1)));
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        eprintln!("{}", metrics);
     }
 }
 
-fn make_reader(input: ProtoEnum, path: String) -> anyhow::Result<Box<dyn Device>> {
-    match input {
-        ProtoEnum::Jeelink => match devices::JeeLink::new(path) {
-            Ok(device) => Ok(Box::new(device)),
+/// Apply changes of the pipeline file on every SIGHUP and every request through the API
+async fn reload_on_request(path: PathBuf, mut hangup: Signal, mut reloader: Reloader) {
+    let requests = reloader.requests();
+    loop {
+        tokio::select! {
+            signal = hangup.recv() => {
+                if signal.is_none() {
+                    break;
+                }
+            }
+            _ = requests.requested() => (),
+        }
+        let result = match Config::load(&path) {
+            Ok(config) => reloader.reload(config).await,
             Err(e) => Err(e),
+        };
+        match result {
+            Ok(changes) => {
+                for change in changes {
+                    eprintln!("{}", change);
+                }
+                eprintln!("Reloaded {}", path.display());
+            }
+            Err(e) => eprintln!("Failed to reload {}: {:#}", path.display(), e),
+        }
+    }
+}
+
+async fn check_config(path: &PathBuf, probe: bool) -> anyhow::Result<()> {
+    let config = Config::load(path)?;
+    print!("{}", config.summary());
+    let mut errors = config.check();
+    if probe && errors.is_empty() {
+        errors = config.probe().await;
+    }
+    for error in &errors {
+        eprintln!("{}", error);
+    }
+    match errors.len() {
+        0 => {
+            println!("{} is valid", path.display());
+            Ok(())
+        }
+        n => Err(anyhow::anyhow!("{} problems in {}", n, path.display())),
+    }
+}
+
+async fn backfill(from: &Path, to: &Path, batch: usize) -> anyhow::Result<()> {
+    let mut sinks = Config::load(to)?
+        .outputs
+        .into_iter()
+        .map(OutputConfig::build)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let stats =
+        sensorflow::backfill::backfill(sensorflow::backfill::open(from)?, &mut sinks, batch)
+            .await?;
+    println!(
+        "Wrote {} measurements, skipped {} without timestamp",
+        stats.written, stats.skipped
+    );
+    Ok(())
+}
+
+const CONSOLE_HELP: &str = "\
+:verbose  toggle decoding of received frames
+:rssi     send the command of --rssi-command
+:quit     leave the console
+Other lines are sent to the firmware, e.g. h for its help";
+
+async fn console(port: &str, rssi_command: Option<&str>) -> anyhow::Result<()> {
+    use devices::jeelink::Console;
+    use tokio::io::AsyncBufReadExt;
+
+    let mut console = Console::open(port)?;
+    let mut input = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let mut verbose = false;
+    println!("Connected to {}, :help lists the console commands", port);
+    loop {
+        tokio::select! {
+            line = console.read_line() => {
+                let Some(line) = line? else {
+                    return Ok(());
+                };
+                println!("{}", line);
+                match Console::decode(&line) {
+                    Some(Ok(frame)) if verbose => println!("  {}", frame),
+                    Some(Err(e)) if verbose => println!("  Invalid frame: {:#}", e),
+                    _ => (),
+                }
+            }
+            command = input.next_line() => match command?.as_deref().map(str::trim) {
+                None | Some(":quit") => return Ok(()),
+                Some(":help") => println!("{}", CONSOLE_HELP),
+                Some(":verbose") => {
+                    verbose = !verbose;
+                    println!("Decoding {}", if verbose { "on" } else { "off" });
+                }
+                Some(":rssi") => match rssi_command {
+                    Some(command) => console.send(command).await?,
+                    None => println!("No --rssi-command given"),
+                },
+                Some("") => (),
+                Some(command) => console.send(command).await?,
+            },
+        }
+    }
+}
+
+fn wmbus_config(cli: &Cli) -> anyhow::Result<WMBusConfig> {
+    let mut wmbus = WMBusConfig {
+        receiver: match cli.wmbus_receiver {
+            WMBusReceiverEnum::Imst => Receiver::Imst,
+            WMBusReceiverEnum::Amber => Receiver::Amber,
+        },
+        mode: match cli.wmbus_mode {
+            WMBusModeEnum::T1 => Mode::T1,
+            WMBusModeEnum::C1 => Mode::C1,
         },
+        ..Default::default()
+    };
+    for key in &cli.wmbus_key {
+        let (id, key) = key
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected METER_ID=HEXKEY, got {}", key))?;
+        wmbus.add_key(id, key)?;
+    }
+    Ok(wmbus)
+}
+
+fn make_reader(cli: &Cli) -> anyhow::Result<Box<dyn Device>> {
+    let path = cli.device.clone().unwrap_or_default();
+    let poll_interval = Duration::from_secs(cli.poll_interval);
+    match cli.input {
+        ProtoEnum::Jeelink => {
+            let device = devices::JeeLink::with_config(
+                path,
+                devices::jeelink::JeeLinkConfig {
+                    rssi_command: cli.rssi_command.clone(),
+                    ..Default::default()
+                },
+            )?;
+            match cli.diagnostics {
+                true => Ok(Box::new(device.with_diagnostics())),
+                false => Ok(Box::new(device)),
+            }
+        }
+        ProtoEnum::Cul => {
+            let device = devices::Cul::new(path)?;
+            match cli.diagnostics {
+                true => Ok(Box::new(device.with_diagnostics())),
+                false => Ok(Box::new(device)),
+            }
+        }
+        ProtoEnum::Enocean => {
+            let device = devices::EnOcean::new(path)?;
+            match cli.diagnostics {
+                true => Ok(Box::new(device.with_diagnostics())),
+                false => Ok(Box::new(device)),
+            }
+        }
+        ProtoEnum::Wmbus => Ok(Box::new(devices::WMBus::new(path, wmbus_config(cli)?)?)),
+        ProtoEnum::Davis => Ok(Box::new(devices::Davis::new(path)?)),
+        ProtoEnum::Elm327 => {
+            let pids = match cli.obd_pid.as_slice() {
+                [] => devices::elm327::Pid::DEFAULT.to_vec(),
+                pids => pids.to_vec(),
+            };
+            Ok(Box::new(
+                devices::Elm327::new(path, pids)?.polled(poll_interval),
+            ))
+        }
+        ProtoEnum::Nmea => {
+            let device = devices::Nmea::new(path)?;
+            match cli.diagnostics {
+                true => Ok(Box::new(device.with_diagnostics())),
+                false => Ok(Box::new(device)),
+            }
+        }
+        ProtoEnum::Pms => {
+            let device = devices::Pms::new(path)?;
+            if cli.pms_passive {
+                Ok(Box::new(device.passive(poll_interval)))
+            } else {
+                Ok(Box::new(device))
+            }
+        }
+        ProtoEnum::Weatherflow => Ok(Box::new(devices::WeatherFlow::bind(&path)?)),
+        ProtoEnum::Ds18b20 => Ok(Box::new(Polled::new(
+            devices::OneWire::new(path),
+            poll_interval,
+        ))),
+        ProtoEnum::Hwmon => Ok(Box::new(Polled::new(
+            devices::Hwmon::new(path),
+            poll_interval,
+        ))),
+        #[cfg(feature = "i2c")]
+        ProtoEnum::Bme280 | ProtoEnum::Sht31 | ProtoEnum::Scd4x => {
+            use devices::i2c::{I2cSensorConfig, Model};
+            let model = match cli.input {
+                ProtoEnum::Bme280 => Model::Bme280,
+                ProtoEnum::Sht31 => Model::Sht31,
+                _ => Model::Scd4x,
+            };
+            let mut config = I2cSensorConfig::new(path, model, poll_interval);
+            if let Some(address) = cli.i2c_address {
+                config.address = address;
+            }
+            Ok(Box::new(config.polled()?))
+        }
+        #[cfg(feature = "coap")]
+        ProtoEnum::Coap => Ok(Box::new(devices::coap::CoapObserve::new(&path)?)),
+        #[cfg(feature = "snmp")]
+        ProtoEnum::Snmp => {
+            use devices::snmp::{Credentials, SnmpConfig};
+            use snmp2::v3::AuthProtocol;
+            let oids = cli
+                .snmp_oid
+                .iter()
+                .map(|oid| oid.parse())
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut config = SnmpConfig::new(path, oids);
+            config.credentials = match &cli.snmp_user {
+                Some(username) => Credentials::V3 {
+                    username: username.clone(),
+                    auth_protocol: match cli.snmp_auth_protocol {
+                        SnmpAuthEnum::Md5 => AuthProtocol::Md5,
+                        SnmpAuthEnum::Sha1 => AuthProtocol::Sha1,
+                        SnmpAuthEnum::Sha256 => AuthProtocol::Sha256,
+                    },
+                    auth_password: cli.snmp_auth_password.clone(),
+                    privacy_password: cli.snmp_priv_password.clone(),
+                },
+                None => Credentials::V2c {
+                    community: cli.snmp_community.clone(),
+                },
+            };
+            Ok(Box::new(config.polled(poll_interval)))
+        }
+        #[cfg(feature = "gpio")]
+        ProtoEnum::GpioPulses => {
+            let config = devices::gpio::PulseCounterConfig::new(
+                path,
+                cli.gpio_line,
+                cli.pulses_per_unit,
+                poll_interval,
+            );
+            Ok(Box::new(devices::gpio::PulseCounter::new(&config)?))
+        }
+    }
+}
+
+fn static_tags(cli: &Cli) -> anyhow::Result<Box<dyn Transform>> {
+    let mut tags = Tags::new();
+    for tag in &cli.tag {
+        let (key, value) = tag
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected KEY=VALUE, got {}", tag))?;
+        tags.insert(key.into(), value.into());
+    }
+    Ok(Box::new(StaticTags::new(tags)))
+}
+
+fn totals(cli: &Cli, state: Option<&State>) -> anyhow::Result<Box<dyn Transform>> {
+    let mut configs = Vec::new();
+    for total in &cli.total {
+        let (field, period) = total
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected FIELD=PERIOD, got {}", total))?;
+        configs.push(TotalConfig {
+            measurement: None,
+            field: field.into(),
+            period: period.parse()?,
+            utc_offset: None,
+        });
+    }
+    let totals = Totals::new(configs)?;
+    Ok(match state {
+        Some(state) => Box::new(totals.with_state(state.clone())?),
+        None => Box::new(totals),
+    })
+}
+
+fn identity_resolver(cli: &Cli, state: Option<&State>) -> anyhow::Result<Box<dyn Transform>> {
+    let mut config = IdentityConfig::default();
+    for sensor in &cli.sensor {
+        let (name, id) = sensor
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected NAME=ID, got {}", sensor))?;
+        config.sensors.insert(name.into(), id.into());
+    }
+    let resolver = match cli.confirm_identity {
+        true => IdentityResolver::with_confirm(config, Box::new(confirm)),
+        false => IdentityResolver::new(config),
+    };
+    Ok(match state {
+        Some(state) => Box::new(resolver.with_state(state.clone())?),
+        None => Box::new(resolver),
+    })
+}
+
+fn confirm(remap: &Remap) -> bool {
+    // Transforms run on a worker thread of the runtime, which may block while asking
+    tokio::task::block_in_place(|| {
+        eprint!("{}. Use the new id? [y/N] ", remap);
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+    })
+}
+
+fn parse_i2c_address(s: &str) -> Result<u8, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
     }
 }
 
-fn to_output(output: OutEnum, frame: Box<dyn ToOutput>) -> String {
-    match output {
-        OutEnum::Stringify => frame.to_string(),
-        OutEnum::Influxdb => frame.to_lineprotocol().to_string(),
+fn make_sink(cli: &Cli) -> anyhow::Result<Box<dyn OutputSink>> {
+    #[cfg(feature = "template")]
+    if let Some(FormatArg::Template(path)) = &cli.format {
+        use sensorflow::output::template::{Template, TemplateSink};
+        return Ok(Box::new(TemplateSink::stdout(Template::from_file(path)?)));
     }
+    Ok(match cli.output {
+        OutEnum::Stringify => Box::new(StringifySink::stdout()),
+        OutEnum::Influxdb => Box::new(LineProtocolSink::stdout()),
+    })
 }
 
 #[test]
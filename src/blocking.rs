@@ -0,0 +1,367 @@
+//! Pipeline without an async runtime.
+//!
+//! On small single-board computers a runtime may not be worth its threads and memory. [Device]
+//! and [OutputSink] are the blocking counterparts of [crate::devices::Device] and
+//! [crate::output::OutputSink], while frames, measurements, transforms and encodings are shared
+//! with the async pipeline. Every input of a [Pipeline] reads in its own thread, and the thread
+//! running the pipeline applies the transforms and writes to the outputs.
+//!
+//! ```no_run
+//! use sensorflow::blocking::{Pipeline, SerialDevice};
+//! use sensorflow::devices::jeelink::JeeLinkFrame;
+//! use sensorflow::output::writer::{Format, WriterSink};
+//!
+//! Pipeline::new(1024)
+//!     .add_input(Box::new(SerialDevice::<JeeLinkFrame>::open(
+//!         "jeelink",
+//!         "/dev/ttyUSB0",
+//!         57600,
+//!     )?))
+//!     .add_output(Box::new(WriterSink::new(std::io::stdout(), Format::LineProtocol)))
+//!     .run()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use crate::{
+    clock::TimestampPolicy, output::ToOutput, transform::Transform, Frame, FramedListener,
+    Measurement,
+};
+use anyhow::Context;
+use chrono::Utc;
+use serialport::TTYPort;
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread::JoinHandle;
+
+pub mod output;
+
+/// Blocking source of frames, see [crate::devices::Device]
+pub trait Device: Send {
+    /// Read the next frame, `None` once the device is exhausted
+    fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>>;
+
+    /// Kind of the device, e.g. `jeelink`
+    fn name(&self) -> &str;
+
+    /// Where the device is attached, e.g. the path of the serial port
+    fn address(&self) -> &str;
+}
+
+/// Blocking destination of measurements, see [crate::output::OutputSink]
+pub trait OutputSink: Send {
+    /// Prepare the sink, e.g. open connections.
+    fn start(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Write a single measurement.
+    fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()>;
+
+    /// Emit all buffered measurements.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Flush and release all resources held by the sink.
+    fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.flush()
+    }
+}
+
+/// Device sending frames of type `F` over a serial port, e.g. a JeeLink or CUL stick
+pub struct SerialDevice<F> {
+    reader: FramedListener<TTYPort, F>,
+    name: &'static str,
+    path: String,
+}
+
+impl<F: Frame> SerialDevice<F> {
+    pub fn open(name: &'static str, path: &str, baud_rate: u32) -> anyhow::Result<Self> {
+        let port = serialport::new(path, baud_rate)
+            .open_native()
+            .with_context(|| format!("Failed to open {}", path))?;
+        Ok(SerialDevice::new(name, path, port))
+    }
+
+    /// Device on an open port, e.g. one end of a pseudo-terminal pair
+    pub fn new(name: &'static str, path: &str, port: TTYPort) -> Self {
+        SerialDevice {
+            reader: FramedListener::new(port),
+            name,
+            path: path.into(),
+        }
+    }
+}
+
+impl<F: Frame + ToOutput + Send + 'static> Device for SerialDevice<F> {
+    fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        Ok(self
+            .reader
+            .read_frame()?
+            .map(|frame| Box::new(frame) as Box<dyn ToOutput>))
+    }
+
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn address(&self) -> &str {
+        &self.path
+    }
+}
+
+/// Inputs, transforms and outputs run by threads.
+///
+/// Inputs pass measurements through a queue holding `capacity` measurements. Inputs block
+/// while it is full, so a slow output slows down reading instead of growing the queue.
+pub struct Pipeline {
+    inputs: Vec<Box<dyn Device>>,
+    transforms: Vec<Box<dyn Transform>>,
+    outputs: Vec<Box<dyn OutputSink>>,
+    capacity: usize,
+    timestamps: TimestampPolicy,
+}
+
+impl Pipeline {
+    pub fn new(capacity: usize) -> Pipeline {
+        Pipeline {
+            inputs: Vec::new(),
+            transforms: Vec::new(),
+            outputs: Vec::new(),
+            capacity,
+            timestamps: TimestampPolicy::default(),
+        }
+    }
+
+    pub fn add_input(mut self, input: Box<dyn Device>) -> Pipeline {
+        self.inputs.push(input);
+        self
+    }
+
+    /// Handle the times carried by measurements, see [TimestampPolicy]
+    pub fn with_timestamps(mut self, timestamps: TimestampPolicy) -> Pipeline {
+        self.timestamps = timestamps;
+        self
+    }
+
+    pub fn add_transform(mut self, transform: Box<dyn Transform>) -> Pipeline {
+        self.transforms.push(transform);
+        self
+    }
+
+    pub fn add_output(mut self, output: Box<dyn OutputSink>) -> Pipeline {
+        self.outputs.push(output);
+        self
+    }
+
+    /// Run the pipeline in the calling thread until all inputs are exhausted or one of the
+    /// stages fails.
+    ///
+    /// Outputs are shut down in either case. After a failing output, the threads of the inputs
+    /// are left to end with their next frame.
+    pub fn run(self) -> anyhow::Result<()> {
+        let Pipeline {
+            inputs,
+            mut transforms,
+            mut outputs,
+            capacity,
+            timestamps,
+        } = self;
+        for output in outputs.iter_mut() {
+            output.start()?;
+        }
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        let threads: Vec<JoinHandle<anyhow::Result<()>>> = inputs
+            .into_iter()
+            .map(|input| {
+                let tx = tx.clone();
+                std::thread::spawn(move || read_input(input, timestamps, tx))
+            })
+            .collect();
+        drop(tx);
+
+        let mut res = write_outputs(rx, &mut transforms, &mut outputs);
+        if res.is_ok() {
+            for thread in threads {
+                let input_res = match thread.join() {
+                    Ok(input_res) => input_res,
+                    Err(_) => Err(anyhow::anyhow!("Input thread panicked")),
+                };
+                if res.is_ok() {
+                    res = input_res;
+                }
+            }
+        }
+        for output in outputs.iter_mut() {
+            let shutdown_res = output.shutdown();
+            if res.is_ok() {
+                res = shutdown_res;
+            }
+        }
+        res
+    }
+}
+
+fn read_input(
+    mut input: Box<dyn Device>,
+    timestamps: TimestampPolicy,
+    tx: mpsc::SyncSender<Measurement>,
+) -> anyhow::Result<()> {
+    loop {
+        let frame = input.read_frame().with_context(|| {
+            format!(
+                "Failed to read from device {} at {}",
+                input.name(),
+                input.address()
+            )
+        })?;
+        let Some(frame) = frame else {
+            return Ok(());
+        };
+        // Rejected by the timestamp policy
+        let Some(measurement) = timestamps.stamp(frame.to_measurement(), Utc::now()) else {
+            continue;
+        };
+        if tx.send(measurement).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Write the measurements of all inputs, flushing the outputs whenever the queue ran empty
+fn write_outputs(
+    rx: mpsc::Receiver<Measurement>,
+    transforms: &mut [Box<dyn Transform>],
+    outputs: &mut [Box<dyn OutputSink>],
+) -> anyhow::Result<()> {
+    let mut next = rx.recv().ok();
+    while let Some(measurement) = next {
+        let mut measurements = vec![measurement];
+        for transform in transforms.iter_mut() {
+            measurements = measurements
+                .into_iter()
+                .flat_map(|measurement| transform.apply(measurement))
+                .collect();
+        }
+        for measurement in &measurements {
+            for output in outputs.iter_mut() {
+                output.write(measurement)?;
+            }
+        }
+        next = match rx.try_recv() {
+            Ok(measurement) => Some(measurement),
+            Err(TryRecvError::Empty) => {
+                for output in outputs.iter_mut() {
+                    output.flush()?;
+                }
+                rx.recv().ok()
+            }
+            Err(TryRecvError::Disconnected) => None,
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Device, OutputSink, Pipeline, SerialDevice};
+    use crate::{
+        devices::jeelink::JeeLinkFrame, output::ToOutput, transform::Transform, Measurement,
+    };
+    use serialport::TTYPort;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    struct Counter {
+        next: u64,
+        last: u64,
+    }
+
+    impl Device for Counter {
+        fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+            if self.next > self.last {
+                return Ok(None);
+            }
+            self.next += 1;
+            Ok(Some(Box::new(
+                Measurement::new("counter").add_field("value", self.next - 1),
+            )))
+        }
+
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn address(&self) -> &str {
+            "memory"
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Capture {
+        written: Arc<Mutex<Vec<Measurement>>>,
+        flushes: Arc<Mutex<usize>>,
+    }
+
+    impl OutputSink for Capture {
+        fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+            self.written.lock().unwrap().push(measurement.clone());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> anyhow::Result<()> {
+            *self.flushes.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    struct Double;
+
+    impl Transform for Double {
+        fn apply(&mut self, measurement: Measurement) -> Vec<Measurement> {
+            vec![measurement.clone(), measurement]
+        }
+    }
+
+    #[test]
+    fn test_blocking_pipeline() {
+        let capture = Capture::default();
+        Pipeline::new(2)
+            .add_input(Box::new(Counter { next: 0, last: 9 }))
+            .add_input(Box::new(Counter { next: 10, last: 14 }))
+            .add_transform(Box::new(Double))
+            .add_output(Box::new(capture.clone()))
+            .run()
+            .unwrap();
+        let written = capture.written.lock().unwrap();
+        assert_eq!(written.len(), 30);
+        assert!(written.iter().all(|m| m.time.is_some()));
+        // Shutting down flushes, too
+        assert!(*capture.flushes.lock().unwrap() >= 1);
+    }
+
+    #[test]
+    fn test_blocking_pipeline_fails_with_input() {
+        let (mut firmware, port) = TTYPort::pair().unwrap();
+        // Unplugged within the second frame. Unread input is discarded on hangup
+        let unplug = std::thread::spawn(move || {
+            firmware.write_all(b"OK 9 50 1 4 193 65\r\nOK 9 1").unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+        });
+        let capture = Capture::default();
+        let error = Pipeline::new(16)
+            .add_input(Box::new(SerialDevice::<JeeLinkFrame>::new(
+                "jeelink",
+                "/dev/pts/test",
+                port,
+            )))
+            .add_output(Box::new(capture.clone()))
+            .run()
+            .unwrap_err();
+        unplug.join().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Failed to read from device jeelink at /dev/pts/test"
+        );
+        assert_eq!(capture.written.lock().unwrap()[0].name, "tempHum");
+    }
+}
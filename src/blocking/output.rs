@@ -0,0 +1,299 @@
+//! Blocking sinks.
+//!
+//! [WriterSink] writes to stdout, files or pipes in one of the formats of
+//! [crate::output::writer::Format], [CsvSink] writes a row per field for spreadsheets and, with
+//! the `sqlite` feature, [SqliteSink] inserts a row per field into an SQLite database.
+use super::OutputSink;
+use crate::{
+    measurement::Measurement,
+    output::writer::{Encode, WriterSink},
+};
+use std::io::{BufWriter, Write};
+
+impl<W: Write + Send, E: Encode> OutputSink for WriterSink<W, E> {
+    fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        self.write_measurement(measurement)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.flush_writer()
+    }
+}
+
+/// Tags as `key=value` pairs separated by `;`, e.g. `sensorId=12;room=cellar`
+fn join_tags(measurement: &Measurement) -> String {
+    measurement
+        .tags
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Cell of a CSV row, quoted if needed
+fn csv_cell(text: &str) -> std::borrow::Cow<'_, str> {
+    match text.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", text.replace('"', "\"\"")).into(),
+        false => text.into(),
+    }
+}
+
+/// Sink writing CSV with the columns `time,measurement,tags,field,value`, one row per field.
+///
+/// The time is in RFC 3339 format and empty for measurements without one.
+pub struct CsvSink<W: Write> {
+    writer: BufWriter<W>,
+    header: bool,
+}
+
+impl<W: Write> CsvSink<W> {
+    /// Sink starting with a header row
+    pub fn new(writer: W) -> CsvSink<W> {
+        CsvSink {
+            writer: BufWriter::new(writer),
+            header: true,
+        }
+    }
+
+    /// Sink without header row, e.g. to append to an existing file
+    pub fn without_header(writer: W) -> CsvSink<W> {
+        CsvSink {
+            header: false,
+            ..CsvSink::new(writer)
+        }
+    }
+
+    /// Flush and return the writer
+    pub fn into_inner(self) -> anyhow::Result<W> {
+        self.writer.into_inner().map_err(|e| e.into_error().into())
+    }
+}
+
+impl<W: Write + Send> OutputSink for CsvSink<W> {
+    fn start(&mut self) -> anyhow::Result<()> {
+        if self.header {
+            self.writer
+                .write_all(b"time,measurement,tags,field,value\n")?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let time = measurement.time.map(|t| t.to_rfc3339()).unwrap_or_default();
+        let tags = join_tags(measurement);
+        for (field, value) in &measurement.fields {
+            writeln!(
+                self.writer,
+                "{},{},{},{},{}",
+                time,
+                csv_cell(&measurement.name),
+                csv_cell(&tags),
+                csv_cell(field),
+                csv_cell(&value.to_string())
+            )?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteSink;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::OutputSink;
+    use crate::measurement::{FieldValue, Measurement};
+    use rusqlite::{params, types::Value, Connection};
+    use std::path::Path;
+
+    const SCHEMA: &str = "\
+        CREATE TABLE IF NOT EXISTS measurements (
+            time INTEGER,
+            measurement TEXT NOT NULL,
+            tags TEXT NOT NULL,
+            field TEXT NOT NULL,
+            value
+        );
+        CREATE INDEX IF NOT EXISTS measurements_time ON measurements (measurement, time);";
+
+    fn value(field: &FieldValue) -> Value {
+        match field {
+            FieldValue::Float(x) => Value::Real(*x),
+            FieldValue::Integer(x) => Value::Integer(*x),
+            FieldValue::UInteger(x) => match i64::try_from(*x) {
+                Ok(x) => Value::Integer(x),
+                Err(_) => Value::Real(*x as f64),
+            },
+            FieldValue::String(s) => Value::Text(s.clone()),
+            FieldValue::Boolean(b) => Value::Integer(*b as i64),
+        }
+    }
+
+    /// Sink inserting a row per field into the table `measurements` of an SQLite database.
+    ///
+    /// Rows have the time in milliseconds since the epoch, the measurement name, the tags as
+    /// JSON object, the field name and the value in its SQLite type, booleans as 0 or 1. Rows
+    /// written between two flushes are committed in one transaction, which saves SD cards
+    /// from a sync per measurement.
+    pub struct SqliteSink {
+        connection: Connection,
+        in_transaction: bool,
+    }
+
+    impl SqliteSink {
+        /// Open or create a database file
+        pub fn open(path: impl AsRef<Path>) -> anyhow::Result<SqliteSink> {
+            SqliteSink::new(Connection::open(path)?)
+        }
+
+        /// Sink on an open connection, creating the table if it does not exist
+        pub fn new(connection: Connection) -> anyhow::Result<SqliteSink> {
+            connection.execute_batch(SCHEMA)?;
+            Ok(SqliteSink {
+                connection,
+                in_transaction: false,
+            })
+        }
+
+        /// Connection to the database, e.g. for queries
+        pub fn connection(&self) -> &Connection {
+            &self.connection
+        }
+    }
+
+    impl OutputSink for SqliteSink {
+        fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+            if !self.in_transaction {
+                self.connection.execute_batch("BEGIN")?;
+                self.in_transaction = true;
+            }
+            let time = measurement.time.map(|t| t.timestamp_millis());
+            let tags = serde_json::Value::Object(
+                measurement
+                    .tags
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone().into()))
+                    .collect(),
+            )
+            .to_string();
+            let mut insert = self.connection.prepare_cached(
+                "INSERT INTO measurements (time, measurement, tags, field, value) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for (field, field_value) in &measurement.fields {
+                insert.execute(params![
+                    time,
+                    measurement.name,
+                    tags,
+                    field,
+                    value(field_value)
+                ])?;
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> anyhow::Result<()> {
+            if self.in_transaction {
+                self.connection.execute_batch("COMMIT")?;
+                self.in_transaction = false;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CsvSink;
+    use crate::Measurement;
+    use crate::{blocking::OutputSink, output::writer::Format, output::writer::WriterSink};
+    use chrono::DateTime;
+
+    fn measurement() -> Measurement {
+        Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_tag("room", "living, dining")
+            .add_field("temperature", 21.5)
+            .add_field("comment", "say \"hi\"")
+            .add_time(DateTime::from_timestamp(1700000000, 0))
+    }
+
+    #[test]
+    fn test_csv_sink() {
+        let mut sink = CsvSink::new(Vec::new());
+        sink.start().unwrap();
+        sink.write(&measurement()).unwrap();
+        sink.write(&Measurement::new("m").add_field("x", true))
+            .unwrap();
+        sink.shutdown().unwrap();
+        assert_eq!(
+            String::from_utf8(sink.into_inner().unwrap()).unwrap(),
+            "time,measurement,tags,field,value\n\
+             2023-11-14T22:13:20+00:00,tempHum,\"sensorId=12;room=living, dining\",temperature,21.5\n\
+             2023-11-14T22:13:20+00:00,tempHum,\"sensorId=12;room=living, dining\",comment,\"say \"\"hi\"\"\"\n\
+             ,m,,x,true\n"
+        );
+    }
+
+    #[test]
+    fn test_writer_sink() {
+        let mut sink = WriterSink::new(Vec::new(), Format::LineProtocol);
+        OutputSink::write(&mut sink, &measurement()).unwrap();
+        OutputSink::shutdown(&mut sink).unwrap();
+        let output = String::from_utf8(sink.into_inner().unwrap()).unwrap();
+        assert_eq!(output, Format::LineProtocol.line(&measurement()));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_sink() {
+        use super::SqliteSink;
+        let mut sink = SqliteSink::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+        sink.write(&measurement()).unwrap();
+        sink.write(&Measurement::new("power").add_field("on", true))
+            .unwrap();
+        sink.flush().unwrap();
+        let rows: Vec<(Option<i64>, String, String, String, String)> = sink
+            .connection()
+            .prepare("SELECT time, measurement, tags, field, CAST(value AS TEXT) FROM measurements")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let tags = r#"{"room":"living, dining","sensorId":"12"}"#;
+        assert_eq!(
+            rows,
+            [
+                (
+                    Some(1700000000000),
+                    "tempHum".into(),
+                    tags.into(),
+                    "temperature".into(),
+                    "21.5".into()
+                ),
+                (
+                    Some(1700000000000),
+                    "tempHum".into(),
+                    tags.into(),
+                    "comment".into(),
+                    "say \"hi\"".into()
+                ),
+                (None, "power".into(), "{}".into(), "on".into(), "1".into()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! C ABI for embedding a single input into other gateways.
+//!
+//! A device is opened from the TOML of one `[[inputs]]` entry of the configuration and polled
+//! for measurements, which are returned as JSON strings of [to_json]. Every device runs on its
+//! own single threaded runtime, which is only driven while polling. Functions returning NULL
+//! leave a message for [sensorflow_last_error] on the calling thread. The header
+//! `include/sensorflow.h` is generated by cbindgen when building with the `capi` feature, a
+//! shared library by `cargo rustc --release --lib --features capi --crate-type cdylib`.
+//! Requires the `capi` feature.
+use crate::{
+    clock::{Clock, SystemClock},
+    config::InputConfig,
+    devices::Device,
+    output::json::to_json,
+};
+use anyhow::Context;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: Option<String>) {
+    let message = message.map(|m| CString::new(m.replace('\0', " ")).unwrap_or_default());
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+/// Run `f`, recording its error or panic and returning NULL instead
+fn guard<T>(f: impl FnOnce() -> anyhow::Result<*mut T>) -> *mut T {
+    set_last_error(None);
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(ptr)) => ptr,
+        Ok(Err(e)) => {
+            set_last_error(Some(format!("{:#}", e)));
+            ptr::null_mut()
+        }
+        Err(_) => {
+            set_last_error(Some("Panic in sensorflow".into()));
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Input opened by [sensorflow_open]
+pub struct SensorflowDevice {
+    runtime: Runtime,
+    device: Box<dyn Device>,
+}
+
+impl SensorflowDevice {
+    fn open(config: &str) -> anyhow::Result<SensorflowDevice> {
+        let config: InputConfig = toml::from_str(config).context("Invalid input configuration")?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        // Devices may register timers and IO resources when built
+        let device = {
+            let _guard = runtime.enter();
+            config.build()?
+        };
+        Ok(SensorflowDevice { runtime, device })
+    }
+
+    /// Next measurement as JSON, `None` on timeout
+    fn poll(&mut self, timeout: Option<Duration>) -> anyhow::Result<Option<String>> {
+        let device = &mut self.device;
+        let frame = self.runtime.block_on(async {
+            match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, device.read_frame())
+                    .await
+                    .ok(),
+                None => Some(device.read_frame().await),
+            }
+        });
+        let Some(frame) = frame else {
+            return Ok(None);
+        };
+        let frame = frame.with_context(|| {
+            format!(
+                "Failed to read from device {} at {}",
+                device.name(),
+                device.address()
+            )
+        })?;
+        let frame = frame.context("End of input")?;
+        let measurement = frame.to_measurement().stamp(SystemClock.now());
+        Ok(Some(to_json(&measurement).to_string()))
+    }
+}
+
+/// Open the input described by `config`, the TOML of one `[[inputs]]` entry, e.g.
+/// `type = "jeelink"` and `device = "/dev/ttyUSB0"`. Returns NULL on error.
+///
+/// # Safety
+///
+/// `config` must be a valid, NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn sensorflow_open(config: *const c_char) -> *mut SensorflowDevice {
+    guard(|| {
+        anyhow::ensure!(!config.is_null(), "No configuration");
+        let config = CStr::from_ptr(config)
+            .to_str()
+            .context("Configuration is not UTF-8")?;
+        Ok(Box::into_raw(Box::new(SensorflowDevice::open(config)?)))
+    })
+}
+
+/// Wait up to `timeout_ms` milliseconds for the next measurement of `device`, or indefinitely
+/// if negative. Returns the measurement as JSON, to be released by [sensorflow_free_string].
+/// Returns NULL on timeout, at the end of the input or on error; [sensorflow_last_error] is
+/// NULL only on timeout.
+///
+/// # Safety
+///
+/// `device` must have been returned by [sensorflow_open] and not been closed.
+#[no_mangle]
+pub unsafe extern "C" fn sensorflow_poll(
+    device: *mut SensorflowDevice,
+    timeout_ms: i64,
+) -> *mut c_char {
+    guard(|| {
+        let device = device.as_mut().context("No device")?;
+        let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+        Ok(match device.poll(timeout)? {
+            Some(json) => CString::new(json)?.into_raw(),
+            None => ptr::null_mut(),
+        })
+    })
+}
+
+/// Release a string returned by [sensorflow_poll]. Does nothing for NULL.
+///
+/// # Safety
+///
+/// `string` must have been returned by [sensorflow_poll] and not been released.
+#[no_mangle]
+pub unsafe extern "C" fn sensorflow_free_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Close `device` and release its resources. Does nothing for NULL.
+///
+/// # Safety
+///
+/// `device` must have been returned by [sensorflow_open] and not been closed.
+#[no_mangle]
+pub unsafe extern "C" fn sensorflow_close(device: *mut SensorflowDevice) {
+    if !device.is_null() {
+        drop(Box::from_raw(device));
+    }
+}
+
+/// Message of the last error on the calling thread, NULL if the last call succeeded. Valid
+/// until the next call on the thread.
+#[no_mangle]
+pub extern "C" fn sensorflow_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |m| m.as_ptr()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::Value;
+
+    fn last_error() -> Option<String> {
+        let message = sensorflow_last_error();
+        (!message.is_null()).then(|| {
+            unsafe { CStr::from_ptr(message) }
+                .to_string_lossy()
+                .into_owned()
+        })
+    }
+
+    #[test]
+    fn test_poll() {
+        let dir = std::env::temp_dir().join(format!("sensorflow-capi-{}", std::process::id()));
+        let chip = dir.join("hwmon0");
+        std::fs::create_dir_all(&chip).unwrap();
+        std::fs::write(chip.join("name"), "coretemp\n").unwrap();
+        std::fs::write(chip.join("temp1_input"), "45000\n").unwrap();
+
+        let config = format!(
+            "type = \"hwmon\"\npath = \"{}\"\ninterval = 3600",
+            dir.display()
+        );
+        let config = CString::new(config).unwrap();
+        unsafe {
+            let device = sensorflow_open(config.as_ptr());
+            assert!(!device.is_null(), "{:?}", last_error());
+
+            let json = sensorflow_poll(device, -1);
+            assert!(!json.is_null(), "{:?}", last_error());
+            let measurement: Value =
+                serde_json::from_str(CStr::from_ptr(json).to_str().unwrap()).unwrap();
+            sensorflow_free_string(json);
+            assert_eq!(measurement["measurement"], "hwmon");
+            assert_eq!(measurement["fields"]["temperature"], 45.);
+            assert!(measurement["timestamp"].is_number());
+
+            // The next reading is an hour away
+            assert!(sensorflow_poll(device, 10).is_null());
+            assert_eq!(last_error(), None);
+            sensorflow_close(device);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let config = CString::new("type = \"unknown\"").unwrap();
+        unsafe {
+            assert!(sensorflow_open(config.as_ptr()).is_null());
+            assert!(last_error()
+                .unwrap()
+                .starts_with("Invalid input configuration"));
+            assert!(sensorflow_open(ptr::null()).is_null());
+            assert_eq!(last_error().unwrap(), "No configuration");
+            assert!(sensorflow_poll(ptr::null_mut(), 0).is_null());
+            assert_eq!(last_error().unwrap(), "No device");
+            sensorflow_free_string(ptr::null_mut());
+            sensorflow_close(ptr::null_mut());
+        }
+    }
+}
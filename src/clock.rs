@@ -0,0 +1,208 @@
+//! Source of the current time.
+//!
+//! Measurements without a time of their own are stamped by the pipeline with the time of its
+//! [Clock] when they are read. The [SystemClock] is used by default, a [MockClock] makes the
+//! timestamps deterministic, e.g. in tests or when replaying recorded data.
+//!
+//! Measurements which carry a time of their own, e.g. of remote inputs or replayed data, keep
+//! it unless a [TimestampPolicy] shifts, clamps or rejects it.
+use crate::Measurement;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// Wall clock time of the system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Clock which only moves when told to. Clones share the time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    time: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl MockClock {
+    pub fn new(time: DateTime<Utc>) -> MockClock {
+        MockClock {
+            time: Arc::new(Mutex::new(time)),
+        }
+    }
+
+    pub fn set(&self, time: DateTime<Utc>) {
+        *self.time.lock().expect("clock lock poisoned") = time;
+    }
+
+    pub fn advance(&self, delta: TimeDelta) {
+        *self.time.lock().expect("clock lock poisoned") += delta;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.time.lock().expect("clock lock poisoned")
+    }
+}
+
+/// Handling of the timestamps carried by measurements. By default they are preserved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct TimestampPolicy {
+    /// Seconds added to every timestamp, e.g. to correct the clock of a remote device
+    #[serde(default)]
+    pub shift: i64,
+    /// Seconds a shifted timestamp may lie ahead of the clock
+    pub max_future: Option<u64>,
+    /// Seconds a shifted timestamp may lie behind the clock
+    pub max_age: Option<u64>,
+    /// Move timestamps beyond `max_future` or `max_age` to the limit instead of dropping the
+    /// measurement
+    #[serde(default)]
+    pub clamp: bool,
+}
+
+impl TimestampPolicy {
+    /// Fail if the shift or a limit is beyond the range of timestamps
+    pub fn check(&self) -> anyhow::Result<()> {
+        if TimeDelta::try_seconds(self.shift).is_none() {
+            anyhow::bail!("Timestamp shift of {} s is out of range", self.shift);
+        }
+        for limit in [self.max_future, self.max_age].into_iter().flatten() {
+            if limit_delta(limit).is_none() {
+                anyhow::bail!("Timestamp limit of {} s is out of range", limit);
+            }
+        }
+        Ok(())
+    }
+
+    /// Timestamp of a measurement carrying `time` received at `now`, `None` if it is rejected.
+    /// Timestamps which cannot be shifted without leaving the range of timestamps are rejected,
+    /// limits beyond that range do not apply.
+    pub fn apply(&self, time: DateTime<Utc>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let time = time.checked_add_signed(TimeDelta::try_seconds(self.shift)?)?;
+        let latest = self
+            .max_future
+            .and_then(limit_delta)
+            .and_then(|limit| now.checked_add_signed(limit));
+        let earliest = self
+            .max_age
+            .and_then(limit_delta)
+            .and_then(|limit| now.checked_sub_signed(limit));
+        match (latest, earliest) {
+            (Some(latest), _) if time > latest => self.clamp.then_some(latest),
+            (_, Some(earliest)) if time < earliest => self.clamp.then_some(earliest),
+            _ => Some(time),
+        }
+    }
+
+    /// Apply the policy to the time of `measurement`, or stamp it with `now` if it has none.
+    /// Returns `None` if the measurement is rejected.
+    pub fn stamp(&self, mut measurement: Measurement, now: DateTime<Utc>) -> Option<Measurement> {
+        measurement.time = match measurement.time {
+            Some(time) => Some(self.apply(time, now)?),
+            None => Some(now),
+        };
+        Some(measurement)
+    }
+}
+
+/// Duration of a limit in seconds, `None` if it is out of range
+fn limit_delta(limit: u64) -> Option<TimeDelta> {
+    TimeDelta::try_seconds(i64::try_from(limit).ok()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Clock, MockClock, TimestampPolicy};
+    use crate::Measurement;
+    use chrono::{DateTime, TimeDelta};
+
+    #[test]
+    fn test_mock_clock() {
+        let start = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let clock = MockClock::new(start);
+        let shared = clock.clone();
+        assert_eq!(clock.now(), start);
+        shared.advance(TimeDelta::seconds(60));
+        assert_eq!(clock.now(), start + TimeDelta::seconds(60));
+        shared.set(start);
+        assert_eq!(clock.now(), start);
+    }
+
+    #[test]
+    fn test_timestamp_policy() {
+        let now = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let hours = |h: i64| now + TimeDelta::hours(h);
+        let measurement = Measurement::new("m").add_time(Some(hours(-2)));
+
+        let preserve = TimestampPolicy::default();
+        assert_eq!(preserve.apply(hours(1000), now), Some(hours(1000)));
+        assert_eq!(
+            preserve.stamp(Measurement::new("m"), now).unwrap().time,
+            Some(now)
+        );
+
+        let reject = TimestampPolicy {
+            shift: 3600,
+            max_future: Some(60),
+            max_age: Some(86400),
+            clamp: false,
+        };
+        assert_eq!(
+            reject.stamp(measurement.clone(), now).unwrap().time,
+            Some(hours(-1))
+        );
+        assert_eq!(reject.apply(hours(0), now), None);
+        assert_eq!(reject.apply(hours(-30), now), None);
+        // Measurements stamped with the clock are not shifted
+        assert_eq!(
+            reject.stamp(Measurement::new("m"), now).unwrap().time,
+            Some(now)
+        );
+
+        let clamp = TimestampPolicy {
+            clamp: true,
+            ..reject
+        };
+        assert_eq!(
+            clamp.apply(hours(0), now),
+            Some(now + TimeDelta::seconds(60))
+        );
+        assert_eq!(clamp.apply(hours(-30), now), Some(hours(-24)));
+    }
+
+    #[test]
+    fn test_timestamp_policy_out_of_range() {
+        let now = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let unlimited = TimestampPolicy {
+            max_future: Some(u64::MAX),
+            max_age: Some(i64::MAX as u64 / 1000 + 1),
+            ..Default::default()
+        };
+        assert!(unlimited.check().is_err());
+        assert_eq!(unlimited.apply(now, now), Some(now));
+
+        let shift = TimestampPolicy {
+            shift: 3600,
+            ..Default::default()
+        };
+        assert!(shift.check().is_ok());
+        assert_eq!(shift.apply(DateTime::<chrono::Utc>::MAX_UTC, now), None);
+        let shift = TimestampPolicy {
+            shift: i64::MIN,
+            ..Default::default()
+        };
+        assert!(shift.check().is_err());
+        assert_eq!(shift.apply(now, now), None);
+    }
+}
@@ -0,0 +1,1709 @@
+//! Pipeline definition read from a TOML file.
+//!
+//! ```toml
+//! [pipeline]
+//! queue_capacity = 1024
+//! overflow = "drop-oldest"
+//! state = "/var/lib/sensorflow/state.json"
+//! schema = "flag"
+//! tags = { site = "home", host = "gateway" }
+//! metadata = "/etc/sensorflow/sensors.toml"
+//!
+//! [pipeline.identity]
+//! sensors = { garden = "12", kitchen = "23" }
+//!
+//! [pipeline.timestamps]
+//! max_future = 60
+//! max_age = 86400
+//!
+//! [pipeline.watchdog]
+//! misses = 3
+//!
+//! [pipeline.counters]
+//! fields = ["volume"]
+//! rollover = { volume = 100000 }
+//!
+//! [[pipeline.totals]]
+//! field = "energy_delta"
+//! period = "day"
+//!
+//! [pipeline.pressure]
+//! altitude = 520.0
+//!
+//! [[pipeline.batteries]]
+//! match = { model = "WSDCGQ11LM" }
+//! field = "voltage"
+//! scale = 0.001
+//! chemistry = "cr2032"
+//!
+//! [pipeline.position]
+//! max_age = 30
+//!
+//! [[pipeline.events]]
+//! name = "freeze"
+//! field = "temperature"
+//! below = 0.0
+//!
+//! [[inputs]]
+//! type = "http"
+//! url = "http://shelly-plug/status"
+//! interval = 30
+//! measurement = "power"
+//! tags = { room = "kitchen" }
+//! fields = { power = "/meters/0/power", temperature = "/temperature" }
+//!
+//! [[inputs]]
+//! type = "jeelink"
+//! device = "/dev/ttyUSB0"
+//! tags = { site = "cottage" }
+//!
+//! [[inputs]]
+//! type = "wmbus"
+//! device = "/dev/ttyUSB1"
+//! receiver = "amber"
+//! mode = "c1"
+//! keys = { "12345678" = "${WATER_METER_KEY}" }
+//!
+//! [[outputs]]
+//! type = "influxdb"
+//!
+//! [[outputs]]
+//! type = "statsd"
+//! address = "127.0.0.1:8125"
+//! retry = { max_attempts = 10, max_backoff = 60000 }
+//! ```
+//!
+//! Failed writes of outputs are retried with backoff if the error is temporary, e.g. a lost
+//! connection, see [crate::output::retry].
+//!
+//! Passwords and tokens may be taken from the environment or from files, see [secrets].
+use crate::{
+    clock::TimestampPolicy,
+    devices::{self, http::HttpPollConfig, poll::Polled, Device},
+    input::line::SerialSettings,
+    output::{
+        api::{ApiConfig, ApiSink},
+        batch::{batched, FlushSchedule},
+        domoticz::{DomoticzConfig, DomoticzSink},
+        exec::{ExecConfig, ExecSink},
+        fhem::{FhemConfig, FhemSink},
+        file::{FileConfig, FileSink},
+        influx::LineProtocolSink,
+        journal::{JournalConfig, JournalSink},
+        loxone::{LoxoneConfig, LoxoneSink},
+        openhab::{OpenHabConfig, OpenHabSink},
+        remote_write::{RemoteWriteConfig, RemoteWriteSink},
+        retry::{Retry, RetryPolicy},
+        signalk::{SignalKConfig, SignalKSink},
+        statsd::{StatsdConfig, StatsdSink},
+        stringify::StringifySink,
+        udp::{UdpConfig, UdpSink},
+        validate::Validator,
+        OutputSink,
+    },
+    pipeline::{ChannelConfig, OverflowPolicy, Pipeline, PipelineControl, StageId},
+    state::State,
+    transform::{
+        battery::{BatteryConfig, BatteryLevels},
+        counter::{CounterConfig, CounterDeltas},
+        events::{EventDetector, EventRule},
+        identity::{IdentityConfig, IdentityResolver},
+        metadata::{MetadataTags, SensorMetadata},
+        position::{GeoTagging, PositionConfig},
+        pressure::{PressureConfig, SeaLevelPressure},
+        schema::{SchemaMode, SchemaValidator},
+        tags::{tag_input, StaticTags, Tags},
+        totals::{TotalConfig, Totals},
+        watchdog::{Watchdog, WatchdogConfig},
+        Transform,
+    },
+};
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+pub mod secrets;
+
+fn default_interval() -> u64 {
+    60
+}
+
+/// Queue settings of the pipeline
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct PipelineSection {
+    #[serde(default = "default_capacity")]
+    pub queue_capacity: usize,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+    /// File keeping state of outputs, totals, sensor ids and the watchdog across restarts, see
+    /// [State]
+    #[serde(default)]
+    pub state: Option<PathBuf>,
+    /// Check measurements against the schemas of the devices, see [SchemaValidator], before
+    /// any other transform but the identity adds fields
+    #[serde(default)]
+    pub schema: Option<SchemaMode>,
+    /// Tags added to every measurement, see [StaticTags]
+    #[serde(default)]
+    pub tags: Tags,
+    /// Stable names of sensors changing their id, see [IdentityResolver]
+    #[serde(default)]
+    pub identity: Option<IdentityConfig>,
+    /// File with the attributes of the sensors, see [SensorMetadata]
+    #[serde(default)]
+    pub metadata: Option<PathBuf>,
+    /// Report sensors which stopped sending, see [Watchdog]. Enabled with the defaults if any
+    /// sensor of the metadata has an interval.
+    #[serde(default)]
+    pub watchdog: Option<WatchdogConfig>,
+    /// Add deltas and rates of cumulative fields, see [CounterDeltas]
+    #[serde(default)]
+    pub counters: Option<CounterConfig>,
+    /// Running totals of fields per period, see [Totals]
+    #[serde(default)]
+    pub totals: Vec<TotalConfig>,
+    /// Add the sea level pressure, see [SeaLevelPressure]
+    #[serde(default)]
+    pub pressure: Option<PressureConfig>,
+    /// Charge of batteries by sensor model, see [BatteryLevels]
+    #[serde(default)]
+    pub batteries: Vec<BatteryConfig>,
+    /// Attach the latest position to measurements, see [GeoTagging]. Enabled with the defaults
+    /// if there is an `nmea` input.
+    #[serde(default)]
+    pub position: Option<PositionConfig>,
+    /// Rules of events, see [EventDetector]
+    #[serde(default)]
+    pub events: Vec<EventRule>,
+    /// Handling of the times carried by measurements
+    #[serde(default)]
+    pub timestamps: TimestampPolicy,
+}
+
+fn default_capacity() -> usize {
+    ChannelConfig::default().capacity
+}
+
+impl Default for PipelineSection {
+    fn default() -> Self {
+        PipelineSection {
+            queue_capacity: default_capacity(),
+            overflow: OverflowPolicy::default(),
+            state: None,
+            schema: None,
+            tags: Tags::new(),
+            identity: None,
+            metadata: None,
+            watchdog: None,
+            counters: None,
+            totals: Vec::new(),
+            pressure: None,
+            batteries: Vec::new(),
+            position: None,
+            events: Vec::new(),
+            timestamps: TimestampPolicy::default(),
+        }
+    }
+}
+
+/// Input of the pipeline, selected by its `type`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum InputConfig {
+    Jeelink {
+        device: String,
+        rssi_command: Option<String>,
+        /// Line settings of the port, see [SerialSettings]
+        #[serde(default)]
+        serial: SerialSettings,
+        /// Count frame and garbage bytes, see [crate::FramedListener::with_diagnostics]
+        #[serde(default)]
+        diagnostics: bool,
+        #[serde(default)]
+        tags: Tags,
+    },
+    Cul {
+        device: String,
+        #[serde(default)]
+        serial: SerialSettings,
+        #[serde(default)]
+        diagnostics: bool,
+        #[serde(default)]
+        tags: Tags,
+    },
+    Enocean {
+        device: String,
+        #[serde(default)]
+        serial: SerialSettings,
+        #[serde(default)]
+        diagnostics: bool,
+        #[serde(default)]
+        tags: Tags,
+    },
+    Nmea {
+        device: String,
+        #[serde(default)]
+        serial: SerialSettings,
+        #[serde(default)]
+        diagnostics: bool,
+        #[serde(default)]
+        tags: Tags,
+    },
+    Wmbus {
+        device: String,
+        #[serde(default)]
+        receiver: devices::wmbus::Receiver,
+        #[serde(default)]
+        mode: devices::wmbus::Mode,
+        /// AES keys of encrypting meters as 32 hex characters, by meter id
+        #[serde(default)]
+        keys: BTreeMap<String, String>,
+        #[serde(default)]
+        tags: Tags,
+    },
+    Davis {
+        device: String,
+        #[serde(default)]
+        tags: Tags,
+    },
+    Elm327 {
+        device: String,
+        /// Parameters queried on every poll
+        #[serde(default = "default_pids")]
+        pids: Vec<devices::elm327::Pid>,
+        #[serde(default = "default_interval")]
+        interval: u64,
+        #[serde(default)]
+        tags: Tags,
+    },
+    Pms {
+        device: String,
+        /// Request a reading every `interval` instead of receiving them continuously
+        #[serde(default)]
+        passive: bool,
+        #[serde(default = "default_interval")]
+        interval: u64,
+        #[serde(default)]
+        tags: Tags,
+    },
+    Ds18b20 {
+        #[serde(default = "default_onewire_path")]
+        path: String,
+        #[serde(default = "default_interval")]
+        interval: u64,
+        #[serde(default)]
+        tags: Tags,
+    },
+    Hwmon {
+        #[serde(default = "default_hwmon_path")]
+        path: String,
+        #[serde(default = "default_interval")]
+        interval: u64,
+        #[serde(default)]
+        tags: Tags,
+    },
+    Weatherflow {
+        /// Address to receive the broadcasts of the hub on
+        #[serde(default = "default_weatherflow_address")]
+        address: String,
+        #[serde(default)]
+        tags: Tags,
+    },
+    Http(HttpPollConfig),
+    #[cfg(feature = "i2c")]
+    I2c {
+        #[serde(default = "default_i2c_bus")]
+        bus: String,
+        model: devices::i2c::Model,
+        /// Address of the sensor, the usual one of the model if not set
+        address: Option<u8>,
+        #[serde(default = "default_interval")]
+        interval: u64,
+        #[serde(default)]
+        tags: Tags,
+    },
+    #[cfg(feature = "snmp")]
+    Snmp(devices::snmp::SnmpPollConfig),
+    #[cfg(feature = "coap")]
+    Coap {
+        /// Observed resource, e.g. `coap://[fd00::1]/sensors/temp`
+        uri: String,
+        #[serde(default)]
+        tags: Tags,
+    },
+    #[cfg(feature = "gpio")]
+    Gpio {
+        #[serde(default = "default_gpio_chip")]
+        chip: String,
+        /// Offset of the line on the chip
+        line: u32,
+        /// Pulses per unit of the meter, e.g. 1000 for 1000 imp/kWh
+        pulses_per_unit: f64,
+        #[serde(default = "default_edge")]
+        edge: devices::gpio::Edge,
+        /// Milliseconds a pulse must be apart from its predecessor
+        #[serde(default = "default_debounce")]
+        debounce: u64,
+        #[serde(default = "default_interval")]
+        interval: u64,
+        #[serde(default)]
+        tags: Tags,
+    },
+    #[cfg(feature = "mqtt")]
+    Ttn(devices::ttn::TtnConfig),
+    #[cfg(feature = "mqtt")]
+    Zigbee2mqtt(devices::zigbee2mqtt::Zigbee2MqttConfig),
+}
+
+/// Settings of a wM-Bus receiver with the keys given as hex
+fn wmbus_config(
+    receiver: devices::wmbus::Receiver,
+    mode: devices::wmbus::Mode,
+    keys: &BTreeMap<String, String>,
+) -> anyhow::Result<devices::wmbus::WMBusConfig> {
+    let mut config = devices::wmbus::WMBusConfig {
+        receiver,
+        mode,
+        ..Default::default()
+    };
+    for (meter_id, key) in keys {
+        config
+            .add_key(meter_id.as_str(), key)
+            .with_context(|| format!("Invalid key of meter {}", meter_id))?;
+    }
+    Ok(config)
+}
+
+fn default_onewire_path() -> String {
+    devices::onewire::SYSFS_PATH.into()
+}
+
+fn default_hwmon_path() -> String {
+    devices::hwmon::SYSFS_PATH.into()
+}
+
+fn default_pids() -> Vec<devices::elm327::Pid> {
+    devices::elm327::Pid::DEFAULT.to_vec()
+}
+
+fn default_weatherflow_address() -> String {
+    devices::weatherflow::DEFAULT_ADDRESS.into()
+}
+
+#[cfg(feature = "gpio")]
+fn default_gpio_chip() -> String {
+    "/dev/gpiochip0".into()
+}
+
+#[cfg(feature = "gpio")]
+fn default_edge() -> devices::gpio::Edge {
+    devices::gpio::Edge::Falling
+}
+
+#[cfg(feature = "gpio")]
+fn default_debounce() -> u64 {
+    20
+}
+
+#[cfg(feature = "i2c")]
+fn default_i2c_bus() -> String {
+    "/dev/i2c-1".into()
+}
+
+impl InputConfig {
+    /// Open the input, adding its `tags` to its measurements
+    pub fn build(self) -> anyhow::Result<Box<dyn Device>> {
+        Ok(match self {
+            InputConfig::Jeelink {
+                device,
+                rssi_command,
+                serial,
+                diagnostics,
+                tags,
+            } => {
+                let mut jeelink = devices::JeeLink::with_config(
+                    device,
+                    devices::jeelink::JeeLinkConfig {
+                        rssi_command,
+                        serial,
+                    },
+                )?;
+                if diagnostics {
+                    jeelink = jeelink.with_diagnostics();
+                }
+                tag_input(Box::new(jeelink), tags)
+            }
+            InputConfig::Cul {
+                device,
+                serial,
+                diagnostics,
+                tags,
+            } => {
+                let mut cul = devices::Cul::with_serial(device, &serial)?;
+                if diagnostics {
+                    cul = cul.with_diagnostics();
+                }
+                tag_input(Box::new(cul), tags)
+            }
+            InputConfig::Enocean {
+                device,
+                serial,
+                diagnostics,
+                tags,
+            } => {
+                let mut enocean = devices::EnOcean::with_serial(device, &serial)?;
+                if diagnostics {
+                    enocean = enocean.with_diagnostics();
+                }
+                tag_input(Box::new(enocean), tags)
+            }
+            InputConfig::Nmea {
+                device,
+                serial,
+                diagnostics,
+                tags,
+            } => {
+                let mut nmea = devices::Nmea::with_serial(device, &serial)?;
+                if diagnostics {
+                    nmea = nmea.with_diagnostics();
+                }
+                tag_input(Box::new(nmea), tags)
+            }
+            InputConfig::Wmbus {
+                device,
+                receiver,
+                mode,
+                keys,
+                tags,
+            } => {
+                let config = wmbus_config(receiver, mode, &keys)?;
+                tag_input(Box::new(devices::WMBus::new(device, config)?), tags)
+            }
+            InputConfig::Davis { device, tags } => {
+                tag_input(Box::new(devices::Davis::new(device)?), tags)
+            }
+            InputConfig::Elm327 {
+                device,
+                pids,
+                interval,
+                tags,
+            } => {
+                let elm327 = devices::Elm327::new(device, pids)?;
+                tag_input(Box::new(elm327.polled(Duration::from_secs(interval))), tags)
+            }
+            InputConfig::Pms {
+                device,
+                passive,
+                interval,
+                tags,
+            } => {
+                let mut pms = devices::Pms::new(device)?;
+                if passive {
+                    pms = pms.passive(Duration::from_secs(interval));
+                }
+                tag_input(Box::new(pms), tags)
+            }
+            InputConfig::Ds18b20 {
+                path,
+                interval,
+                tags,
+            } => tag_input(
+                Box::new(Polled::new(
+                    devices::OneWire::new(path),
+                    Duration::from_secs(interval),
+                )),
+                tags,
+            ),
+            InputConfig::Hwmon {
+                path,
+                interval,
+                tags,
+            } => tag_input(
+                Box::new(Polled::new(
+                    devices::Hwmon::new(path),
+                    Duration::from_secs(interval),
+                )),
+                tags,
+            ),
+            InputConfig::Weatherflow { address, tags } => {
+                tag_input(Box::new(devices::WeatherFlow::bind(&address)?), tags)
+            }
+            InputConfig::Http(config) => Box::new(config.polled()?),
+            #[cfg(feature = "i2c")]
+            InputConfig::I2c {
+                bus,
+                model,
+                address,
+                interval,
+                tags,
+            } => {
+                let mut config =
+                    devices::i2c::I2cSensorConfig::new(bus, model, Duration::from_secs(interval));
+                if let Some(address) = address {
+                    config.address = address;
+                }
+                tag_input(Box::new(config.polled()?), tags)
+            }
+            #[cfg(feature = "snmp")]
+            InputConfig::Snmp(config) => {
+                let tags = config.tags.clone();
+                tag_input(Box::new(config.polled()?), tags)
+            }
+            #[cfg(feature = "coap")]
+            InputConfig::Coap { uri, tags } => {
+                tag_input(Box::new(devices::coap::CoapObserve::new(&uri)?), tags)
+            }
+            #[cfg(feature = "gpio")]
+            InputConfig::Gpio {
+                chip,
+                line,
+                pulses_per_unit,
+                edge,
+                debounce,
+                interval,
+                tags,
+            } => {
+                let config = devices::gpio::PulseCounterConfig {
+                    edge,
+                    debounce: Duration::from_millis(debounce),
+                    ..devices::gpio::PulseCounterConfig::new(
+                        chip,
+                        line,
+                        pulses_per_unit,
+                        Duration::from_secs(interval),
+                    )
+                };
+                tag_input(Box::new(devices::gpio::PulseCounter::new(&config)?), tags)
+            }
+            #[cfg(feature = "mqtt")]
+            InputConfig::Ttn(config) => {
+                let tags = config.tags.clone();
+                tag_input(Box::new(config.input()?), tags)
+            }
+            #[cfg(feature = "mqtt")]
+            InputConfig::Zigbee2mqtt(config) => {
+                let tags = config.tags.clone();
+                tag_input(Box::new(config.input()?), tags)
+            }
+        })
+    }
+}
+
+/// Output of the pipeline, selected by its `type`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum OutputConfig {
+    Stringify,
+    Influxdb,
+    Signalk(SignalKConfig),
+    Domoticz(DomoticzConfig),
+    Openhab(OpenHabConfig),
+    Loxone(LoxoneConfig),
+    Fhem(FhemConfig),
+    Statsd(StatsdConfig),
+    Udp(UdpConfig),
+    RemoteWrite(RemoteWriteConfig),
+    Journal(JournalConfig),
+    Exec(ExecConfig),
+    File(FileConfig),
+    Api(ApiConfig),
+    #[cfg(feature = "arrow")]
+    Arrow(crate::output::arrow::ArrowStreamConfig),
+    #[cfg(feature = "aws-iot")]
+    AwsIot(crate::output::aws_iot::AwsIotConfig),
+    #[cfg(feature = "azure-iot")]
+    AzureIot(crate::output::azure_iot::AzureIotConfig),
+    #[cfg(feature = "dbus")]
+    Dbus(crate::output::dbus::DbusConfig),
+    #[cfg(feature = "email")]
+    Email(crate::output::email::EmailConfig),
+    #[cfg(feature = "grpc")]
+    Grpc(crate::output::grpc::GrpcConfig),
+    #[cfg(feature = "knx")]
+    Knx(crate::output::knx::KnxConfig),
+    #[cfg(feature = "mqtt")]
+    Mqtt(crate::output::mqtt::MqttOutputConfig),
+    #[cfg(feature = "parquet")]
+    Parquet(crate::output::parquet::ParquetConfig),
+    #[cfg(feature = "pubsub")]
+    Pubsub(crate::output::pubsub::PubSubConfig),
+    #[cfg(feature = "template")]
+    Template(crate::output::template::TemplateConfig),
+}
+
+impl OutputConfig {
+    pub fn build(self) -> anyhow::Result<Box<dyn OutputSink>> {
+        self.build_with_state(None)
+    }
+
+    /// Like [OutputConfig::build], with outputs keeping state across restarts in `state`
+    pub fn build_with_state(self, state: Option<&State>) -> anyhow::Result<Box<dyn OutputSink>> {
+        self.build_with(state, None)
+    }
+
+    /// Like [OutputConfig::build_with_state], with the API passing reload requests to `reload`
+    ///
+    /// Failed operations of every output are retried, see [OutputConfig::retry], before they
+    /// are batched by their flush schedule.
+    pub fn build_with(
+        self,
+        state: Option<&State>,
+        reload: Option<&ReloadRequests>,
+    ) -> anyhow::Result<Box<dyn OutputSink>> {
+        let retry = self.retry()?;
+        let flush = self.flush();
+        let sink: Box<dyn OutputSink> = match self {
+            OutputConfig::Stringify => Box::new(StringifySink::stdout()),
+            OutputConfig::Influxdb => Box::new(LineProtocolSink::stdout()),
+            OutputConfig::Signalk(config) => Box::new(SignalKSink::new(config)),
+            OutputConfig::Domoticz(config) => Box::new(DomoticzSink::new(config)?),
+            OutputConfig::Openhab(config) => Box::new(OpenHabSink::new(config)?),
+            OutputConfig::Loxone(config) => Box::new(LoxoneSink::new(config)?),
+            OutputConfig::Fhem(config) => Box::new(FhemSink::new(config)),
+            OutputConfig::Statsd(config) => Box::new(StatsdSink::new(config)),
+            OutputConfig::Udp(config) => Box::new(UdpSink::new(config)),
+            OutputConfig::RemoteWrite(config) => Box::new(RemoteWriteSink::new(config)?),
+            OutputConfig::Journal(config) => Box::new(JournalSink::new(config)),
+            OutputConfig::Exec(config) => Box::new(ExecSink::new(config)?),
+            OutputConfig::File(config) => Box::new(FileSink::new(config)),
+            OutputConfig::Api(config) => {
+                let mut sink = ApiSink::new(config)?;
+                if let Some(state) = state {
+                    sink = sink.with_state(state.clone());
+                }
+                if let Some(reload) = reload {
+                    sink = sink.with_reload(reload.clone());
+                }
+                Box::new(sink)
+            }
+            #[cfg(feature = "arrow")]
+            OutputConfig::Arrow(config) => {
+                Box::new(crate::output::arrow::ArrowStreamSink::new(config))
+            }
+            #[cfg(feature = "aws-iot")]
+            OutputConfig::AwsIot(config) => Box::new(config.sink()?),
+            #[cfg(feature = "azure-iot")]
+            OutputConfig::AzureIot(config) => Box::new(config.sink()?),
+            #[cfg(feature = "dbus")]
+            OutputConfig::Dbus(config) => Box::new(crate::output::dbus::DbusSink::new(config)),
+            #[cfg(feature = "email")]
+            OutputConfig::Email(config) => Box::new(crate::output::email::EmailSink::new(config)?),
+            #[cfg(feature = "grpc")]
+            OutputConfig::Grpc(config) => Box::new(crate::output::grpc::GrpcSink::new(config)),
+            #[cfg(feature = "knx")]
+            OutputConfig::Knx(config) => Box::new(crate::output::knx::KnxSink::new(config)?),
+            #[cfg(feature = "mqtt")]
+            OutputConfig::Mqtt(config) => Box::new(config.sink()?),
+            #[cfg(feature = "parquet")]
+            OutputConfig::Parquet(config) => {
+                Box::new(crate::output::parquet::ParquetSink::new(config))
+            }
+            #[cfg(feature = "pubsub")]
+            OutputConfig::Pubsub(config) => Box::new(config.sink()?),
+            #[cfg(feature = "template")]
+            OutputConfig::Template(config) => {
+                use crate::output::template::{Template, TemplateSink};
+                Box::new(TemplateSink::stdout(Template::from_file(&config.path)?))
+            }
+        };
+        Ok(batched(Box::new(Retry::new(sink, retry)), flush))
+    }
+
+    /// Retries of the output as configured by its `retry`, the defaults of [RetryPolicy] for
+    /// outputs without
+    pub fn retry(&self) -> anyhow::Result<RetryPolicy> {
+        match self {
+            OutputConfig::Signalk(config) => config.retry.policy(),
+            OutputConfig::Domoticz(config) => config.retry.policy(),
+            OutputConfig::Openhab(config) => config.retry.policy(),
+            OutputConfig::Loxone(config) => config.retry.policy(),
+            OutputConfig::Fhem(config) => config.retry.policy(),
+            OutputConfig::Statsd(config) => config.retry.policy(),
+            OutputConfig::Udp(config) => config.retry.policy(),
+            OutputConfig::RemoteWrite(config) => Ok(config.retry_policy()),
+            OutputConfig::Journal(config) => config.retry.policy(),
+            OutputConfig::Exec(config) => config.retry.policy(),
+            #[cfg(feature = "dbus")]
+            OutputConfig::Dbus(config) => config.retry.policy(),
+            #[cfg(feature = "email")]
+            OutputConfig::Email(config) => config.retry.policy(),
+            #[cfg(feature = "knx")]
+            OutputConfig::Knx(config) => config.retry.policy(),
+            #[cfg(feature = "pubsub")]
+            OutputConfig::Pubsub(config) => config.retry.policy(),
+            _ => Ok(RetryPolicy::default()),
+        }
+    }
+
+    /// Flush schedule of batching outputs, see [batched]
+    fn flush(&self) -> Option<FlushSchedule> {
+        match self {
+            OutputConfig::RemoteWrite(config) => config.flush,
+            OutputConfig::Exec(config) => config.flush,
+            #[cfg(feature = "azure-iot")]
+            OutputConfig::AzureIot(config) => config.flush,
+            #[cfg(feature = "parquet")]
+            OutputConfig::Parquet(config) => config.flush,
+            _ => None,
+        }
+    }
+}
+
+/// Content of a pipeline file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub pipeline: PipelineSection,
+    #[serde(default)]
+    pub inputs: Vec<InputConfig>,
+    #[serde(default)]
+    pub outputs: Vec<OutputConfig>,
+}
+
+impl Config {
+    /// Parse a pipeline file, resolving references to secrets, see [secrets]
+    pub fn from_toml(content: &str) -> anyhow::Result<Config> {
+        let mut table: toml::Table = toml::from_str(content)?;
+        match secrets::resolve(&mut table)? {
+            true => Ok(Config::deserialize(table)?),
+            // Parsed again for errors with line numbers
+            false => Ok(toml::from_str(content)?),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Config> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        Config::from_toml(&content)
+    }
+
+    /// State file of the pipeline, if configured
+    pub fn open_state(&self) -> anyhow::Result<Option<State>> {
+        self.pipeline.state.as_ref().map(State::open).transpose()
+    }
+
+    /// Open all inputs and outputs and wire them into a pipeline
+    pub fn build(self) -> anyhow::Result<Pipeline> {
+        let state = self.open_state()?;
+        self.build_with(state.as_ref(), None)
+    }
+
+    fn build_with(
+        self,
+        state: Option<&State>,
+        reload: Option<&ReloadRequests>,
+    ) -> anyhow::Result<Pipeline> {
+        self.pipeline.timestamps.check()?;
+        let mut pipeline = Pipeline::new(ChannelConfig {
+            capacity: self.pipeline.queue_capacity,
+            policy: self.pipeline.overflow,
+        })
+        .with_timestamps(self.pipeline.timestamps);
+        for transform in self.transforms(state)? {
+            pipeline = pipeline.add_transform(transform);
+        }
+        for input in self.inputs {
+            pipeline = pipeline.add_input(input.build()?);
+        }
+        for output in self.outputs {
+            pipeline = pipeline.add_output(output.build_with(state, reload)?);
+        }
+        Ok(pipeline)
+    }
+
+    /// Whether there is an input with positions for the [GeoTagging]
+    fn has_positions(&self) -> bool {
+        self.inputs
+            .iter()
+            .any(|input| matches!(input, InputConfig::Nmea { .. }))
+    }
+
+    /// Transforms of the `[pipeline]` section, in the order measurements pass them
+    fn transforms(&self, state: Option<&State>) -> anyhow::Result<Vec<Box<dyn Transform>>> {
+        let section = self.pipeline.clone();
+        let mut transforms: Vec<Box<dyn Transform>> = Vec::new();
+        if let Some(config) = section.identity {
+            let resolver = IdentityResolver::new(config);
+            transforms.push(match state {
+                Some(state) => Box::new(resolver.with_state(state.clone())?),
+                None => Box::new(resolver),
+            });
+        }
+        // The schemas describe the measurements of the devices, before enrichment
+        if let Some(mode) = section.schema {
+            transforms.push(Box::new(SchemaValidator::builtin(mode)));
+        }
+        let metadata = match &section.metadata {
+            Some(path) => {
+                let metadata = Arc::new(SensorMetadata::load(path)?);
+                transforms.push(Box::new(MetadataTags::new(metadata.clone())));
+                metadata
+            }
+            None => Arc::default(),
+        };
+        let watchdog = match section.watchdog {
+            Some(config) => Some(config),
+            None => metadata.has_intervals().then(WatchdogConfig::default),
+        };
+        if let Some(config) = watchdog {
+            let mut watchdog = Watchdog::with_config(metadata.clone(), config)?;
+            if let Some(state) = state {
+                watchdog = watchdog.with_state(state.clone())?;
+            }
+            transforms.push(Box::new(watchdog));
+        }
+        if let Some(config) = section.pressure {
+            transforms.push(Box::new(SeaLevelPressure::new(config, metadata)));
+        }
+        let position = match section.position {
+            Some(config) => Some(config),
+            None => self.has_positions().then(PositionConfig::default),
+        };
+        if let Some(config) = position {
+            transforms.push(Box::new(GeoTagging::new(config)));
+        }
+        if !section.batteries.is_empty() {
+            transforms.push(Box::new(BatteryLevels::new(section.batteries)?));
+        }
+        if let Some(config) = section.counters {
+            transforms.push(Box::new(CounterDeltas::new(config)));
+        }
+        if !section.totals.is_empty() {
+            let mut totals = Totals::new(section.totals)?;
+            if let Some(state) = state {
+                totals = totals.with_state(state.clone())?;
+            }
+            transforms.push(Box::new(totals));
+        }
+        if !section.events.is_empty() {
+            transforms.push(Box::new(EventDetector::new(section.events)?));
+        }
+        if !section.tags.is_empty() {
+            transforms.push(Box::new(StaticTags::new(section.tags)));
+        }
+        Ok(transforms)
+    }
+
+    /// Open all inputs, wrapped by `validator`, and wire them into a pipeline with the sink of
+    /// the validator as only output. The configured outputs are not used.
+    pub fn build_validating(self, validator: &Validator) -> anyhow::Result<Pipeline> {
+        let mut pipeline = Pipeline::new(ChannelConfig {
+            capacity: self.pipeline.queue_capacity,
+            policy: self.pipeline.overflow,
+        });
+        for input in self.inputs {
+            pipeline = pipeline.add_input(validator.wrap(input.build()?));
+        }
+        Ok(pipeline.add_output(Box::new(validator.sink())))
+    }
+
+    /// Like [Config::build], with a [Reloader] to apply changes of the configuration to the
+    /// running pipeline
+    pub fn build_reloadable(self) -> anyhow::Result<(Pipeline, Reloader)> {
+        let state = self.open_state()?;
+        let reload = ReloadRequests::default();
+        let pipeline = self.clone().build_with(state.as_ref(), Some(&reload))?;
+        // Inputs and outputs are numbered in the order they were added
+        let inputs = self.inputs.len() as StageId;
+        let reloader = Reloader {
+            control: pipeline.control(),
+            positions: self.has_positions(),
+            pipeline: self.pipeline,
+            state,
+            reload,
+            inputs: self.inputs.into_iter().zip(0..).collect(),
+            outputs: self.outputs.into_iter().zip(inputs..).collect(),
+        };
+        Ok((pipeline, reloader))
+    }
+}
+
+impl InputConfig {
+    /// Short description for the summary of `check-config`
+    pub fn describe(&self) -> String {
+        match self {
+            InputConfig::Jeelink { device, .. } => format!("jeelink on {}", device),
+            InputConfig::Cul { device, .. } => format!("cul on {}", device),
+            InputConfig::Enocean { device, .. } => format!("enocean on {}", device),
+            InputConfig::Nmea { device, .. } => format!("nmea on {}", device),
+            InputConfig::Wmbus { device, .. } => format!("wmbus on {}", device),
+            InputConfig::Davis { device, .. } => format!("davis on {}", device),
+            InputConfig::Elm327 {
+                device, interval, ..
+            } => format!("elm327 on {} every {} s", device, interval),
+            InputConfig::Pms {
+                device,
+                passive: true,
+                interval,
+                ..
+            } => format!("pms on {} every {} s", device, interval),
+            InputConfig::Pms { device, .. } => format!("pms on {}", device),
+            InputConfig::Ds18b20 { path, interval, .. } => {
+                format!("ds18b20 in {} every {} s", path, interval)
+            }
+            InputConfig::Hwmon { path, interval, .. } => {
+                format!("hwmon in {} every {} s", path, interval)
+            }
+            InputConfig::Weatherflow { address, .. } => format!("weatherflow on {}", address),
+            InputConfig::Http(config) => {
+                format!("http {} every {} s", config.url, config.interval)
+            }
+            #[cfg(feature = "i2c")]
+            InputConfig::I2c {
+                bus,
+                model,
+                address,
+                interval,
+                ..
+            } => format!(
+                "i2c {} on {}@{:#04x} every {} s",
+                model.name(),
+                bus,
+                address.unwrap_or(model.default_address()),
+                interval
+            ),
+            #[cfg(feature = "snmp")]
+            InputConfig::Snmp(config) => {
+                format!("snmp {} every {} s", config.target, config.interval)
+            }
+            #[cfg(feature = "coap")]
+            InputConfig::Coap { uri, .. } => format!("coap {}", uri),
+            #[cfg(feature = "gpio")]
+            InputConfig::Gpio {
+                chip,
+                line,
+                interval,
+                ..
+            } => format!("gpio {} line {} every {} s", chip, line, interval),
+            #[cfg(feature = "mqtt")]
+            InputConfig::Ttn(config) => {
+                format!("ttn {} on {}", config.application_id, config.broker)
+            }
+            #[cfg(feature = "mqtt")]
+            InputConfig::Zigbee2mqtt(config) => format!("zigbee2mqtt on {}", config.broker),
+        }
+    }
+
+    /// Problems detectable without opening the input
+    fn check(&self) -> Result<(), String> {
+        let exists = |path: &str| match Path::new(path).exists() {
+            true => Ok(()),
+            false => Err(format!("{} does not exist", path)),
+        };
+        match self {
+            InputConfig::Jeelink { device, .. }
+            | InputConfig::Cul { device, .. }
+            | InputConfig::Enocean { device, .. }
+            | InputConfig::Nmea { device, .. }
+            | InputConfig::Davis { device, .. }
+            | InputConfig::Elm327 { device, .. }
+            | InputConfig::Pms { device, .. } => exists(device),
+            InputConfig::Wmbus {
+                device,
+                receiver,
+                mode,
+                keys,
+                ..
+            } => wmbus_config(*receiver, *mode, keys)
+                .map_err(|e| format!("{:#}", e))
+                .and_then(|_| exists(device)),
+            InputConfig::Ds18b20 { path, .. } | InputConfig::Hwmon { path, .. } => exists(path),
+            InputConfig::Weatherflow { address, .. } => address
+                .parse::<std::net::SocketAddr>()
+                .map(|_| ())
+                .map_err(|e| format!("Invalid address {}: {}", address, e)),
+            InputConfig::Http(config) => reqwest::Url::parse(&config.url)
+                .map(|_| ())
+                .map_err(|e| format!("Invalid URL {}: {}", config.url, e)),
+            #[cfg(feature = "i2c")]
+            InputConfig::I2c { bus, .. } => exists(bus),
+            #[cfg(feature = "snmp")]
+            InputConfig::Snmp(config) => config.snmp().map(|_| ()).map_err(|e| e.to_string()),
+            #[cfg(feature = "coap")]
+            InputConfig::Coap { uri, .. } => devices::coap::parse_uri(uri)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "gpio")]
+            InputConfig::Gpio { chip, .. } => exists(chip),
+            #[cfg(feature = "mqtt")]
+            InputConfig::Ttn(_) | InputConfig::Zigbee2mqtt(_) => Ok(()),
+        }
+    }
+}
+
+impl OutputConfig {
+    /// Type of the output as in the pipeline file
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OutputConfig::Stringify => "stringify",
+            OutputConfig::Influxdb => "influxdb",
+            OutputConfig::Signalk(_) => "signalk",
+            OutputConfig::Domoticz(_) => "domoticz",
+            OutputConfig::Openhab(_) => "openhab",
+            OutputConfig::Loxone(_) => "loxone",
+            OutputConfig::Fhem(_) => "fhem",
+            OutputConfig::Statsd(_) => "statsd",
+            OutputConfig::Udp(_) => "udp",
+            OutputConfig::RemoteWrite(_) => "remote_write",
+            OutputConfig::Journal(_) => "journal",
+            OutputConfig::Exec(_) => "exec",
+            OutputConfig::File(_) => "file",
+            OutputConfig::Api(_) => "api",
+            #[cfg(feature = "arrow")]
+            OutputConfig::Arrow(_) => "arrow",
+            #[cfg(feature = "aws-iot")]
+            OutputConfig::AwsIot(_) => "aws_iot",
+            #[cfg(feature = "azure-iot")]
+            OutputConfig::AzureIot(_) => "azure_iot",
+            #[cfg(feature = "dbus")]
+            OutputConfig::Dbus(_) => "dbus",
+            #[cfg(feature = "email")]
+            OutputConfig::Email(_) => "email",
+            #[cfg(feature = "grpc")]
+            OutputConfig::Grpc(_) => "grpc",
+            #[cfg(feature = "knx")]
+            OutputConfig::Knx(_) => "knx",
+            #[cfg(feature = "mqtt")]
+            OutputConfig::Mqtt(_) => "mqtt",
+            #[cfg(feature = "parquet")]
+            OutputConfig::Parquet(_) => "parquet",
+            #[cfg(feature = "pubsub")]
+            OutputConfig::Pubsub(_) => "pubsub",
+            #[cfg(feature = "template")]
+            OutputConfig::Template(_) => "template",
+        }
+    }
+}
+
+impl Config {
+    /// Human readable summary of the resolved pipeline
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "Queues of {} measurements, {:?} on overflow\n",
+            self.pipeline.queue_capacity, self.pipeline.overflow
+        );
+        for (i, input) in self.inputs.iter().enumerate() {
+            summary.push_str(&format!("Input {}: {}\n", i + 1, input.describe()));
+        }
+        for (i, output) in self.outputs.iter().enumerate() {
+            summary.push_str(&format!("Output {}: {}\n", i + 1, output.kind()));
+        }
+        summary
+    }
+
+    /// Validate the configuration without opening inputs or connecting outputs: device paths
+    /// exist, URLs parse and outputs can be constructed, which checks e.g. addresses,
+    /// connection strings and credential files. Returns a description of every problem.
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.inputs.is_empty() {
+            errors.push("No inputs".to_string());
+        }
+        if self.outputs.is_empty() {
+            errors.push("No outputs".to_string());
+        }
+        if let Some(path) = &self.pipeline.metadata {
+            if let Err(e) = SensorMetadata::load(path) {
+                errors.push(e.to_string());
+            }
+        }
+        if let Err(e) = self.pipeline.timestamps.check() {
+            errors.push(format!("{:#}", e));
+        }
+        if let Some(config) = self.pipeline.watchdog {
+            if let Err(e) = Watchdog::with_config(Arc::default(), config) {
+                errors.push(format!("{:#}", e));
+            }
+        }
+        if let Err(e) = Totals::new(self.pipeline.totals.clone()) {
+            errors.push(format!("{:#}", e));
+        }
+        if let Err(e) = BatteryLevels::new(self.pipeline.batteries.clone()) {
+            errors.push(format!("{:#}", e));
+        }
+        if let Err(e) = EventDetector::new(self.pipeline.events.clone()) {
+            errors.push(format!("{:#}", e));
+        }
+        for (i, input) in self.inputs.iter().enumerate() {
+            if let Err(e) = input.check() {
+                errors.push(format!("Input {} ({}): {}", i + 1, input.describe(), e));
+            }
+        }
+        for (i, output) in self.outputs.iter().enumerate() {
+            if let Err(e) = output.clone().build() {
+                errors.push(format!("Output {} ({}): {:#}", i + 1, output.kind(), e));
+            }
+        }
+        errors
+    }
+
+    /// Open every input and start and shut down every output, to check devices, listen
+    /// addresses and connectivity. Returns a description of every problem.
+    pub async fn probe(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (i, input) in self.inputs.iter().enumerate() {
+            if let Err(e) = input.clone().build() {
+                errors.push(format!("Input {} ({}): {:#}", i + 1, input.describe(), e));
+            }
+        }
+        for (i, output) in self.outputs.iter().enumerate() {
+            let probe = async {
+                let mut sink = output.clone().build()?;
+                sink.start().await?;
+                sink.shutdown().await
+            };
+            let result = match tokio::time::timeout(Duration::from_secs(10), probe).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!("Timed out")),
+            };
+            if let Err(e) = result {
+                errors.push(format!("Output {} ({}): {:#}", i + 1, output.kind(), e));
+            }
+        }
+        errors
+    }
+}
+
+/// Configurations of running stages
+type Running<T> = Vec<(T, StageId)>;
+
+/// Split running stages into those which are kept and those which are removed, and return the
+/// configurations of the stages to add. Stages with unchanged configuration are kept.
+fn diff<T: PartialEq>(running: Running<T>, wanted: Vec<T>) -> (Running<T>, Running<T>, Vec<T>) {
+    let mut removed = running;
+    let mut kept = Vec::new();
+    let mut added = Vec::new();
+    for config in wanted {
+        match removed.iter().position(|(running, _)| *running == config) {
+            Some(i) => kept.push(removed.remove(i)),
+            None => added.push(config),
+        }
+    }
+    (kept, removed, added)
+}
+
+/// Requests to reload the pipeline file, e.g. through `POST /reload` of the [ApiSink]
+#[derive(Debug, Clone, Default)]
+pub struct ReloadRequests(Arc<Notify>);
+
+impl ReloadRequests {
+    pub fn request(&self) {
+        self.0.notify_one();
+    }
+
+    /// Wait for the next request. A request made while nobody was waiting is kept.
+    pub async fn requested(&self) {
+        self.0.notified().await
+    }
+}
+
+/// Applies a changed configuration to a running pipeline
+pub struct Reloader {
+    control: PipelineControl,
+    pipeline: PipelineSection,
+    /// Whether an input reports positions
+    positions: bool,
+    state: Option<State>,
+    reload: ReloadRequests,
+    inputs: Running<InputConfig>,
+    outputs: Running<OutputConfig>,
+}
+
+impl Reloader {
+    /// Requests made to reload the pipeline file, to be answered by [Reloader::reload]
+    pub fn requests(&self) -> ReloadRequests {
+        self.reload.clone()
+    }
+
+    /// Stop inputs and outputs which were removed or changed and start those which were added or
+    /// changed, while unchanged ones keep running. Queued measurements are passed on to the
+    /// outputs of the new configuration. Returns a description of every change.
+    ///
+    /// Nothing is changed if the configuration is invalid. If a stage fails to start, changes
+    /// before stay applied and the next reload retries the rest.
+    pub async fn reload(&mut self, config: Config) -> anyhow::Result<Vec<String>> {
+        let errors = config.check();
+        if !errors.is_empty() {
+            anyhow::bail!("{}", errors.join("; "));
+        }
+        let mut changes = Vec::new();
+        // Settings of the queues, the state and the timestamps are only applied on start
+        let mut applied = config.pipeline.clone();
+        applied.queue_capacity = self.pipeline.queue_capacity;
+        applied.overflow = self.pipeline.overflow;
+        applied.state.clone_from(&self.pipeline.state);
+        applied.timestamps = self.pipeline.timestamps;
+        if applied != config.pipeline {
+            changes.push(
+                "Changed queue, state or timestamp settings take effect after a restart"
+                    .to_string(),
+            );
+        }
+        if applied != self.pipeline || config.has_positions() != self.positions {
+            let transforms = config.transforms(self.state.as_ref())?;
+            self.control.set_transforms(transforms)?;
+            changes.push("Replaced the transforms".to_string());
+        }
+        self.positions = config.has_positions();
+        self.pipeline = config.pipeline;
+
+        let (kept, removed, added) = diff(std::mem::take(&mut self.inputs), config.inputs);
+        self.inputs = kept;
+        // Stop inputs first, a changed input may open the same device
+        for (input, id) in removed {
+            self.control.remove_input(id).await;
+            changes.push(format!("Stopped input {}", input.describe()));
+        }
+        for input in added {
+            let device = input
+                .clone()
+                .build()
+                .with_context(|| format!("Failed to open input {}", input.describe()))?;
+            self.inputs
+                .push((input.clone(), self.control.add_input(device)?));
+            changes.push(format!("Started input {}", input.describe()));
+        }
+
+        let (kept, removed, added) = diff(self.outputs.clone(), config.outputs);
+        let sinks = added
+            .iter()
+            .map(|output| {
+                output
+                    .clone()
+                    .build_with(self.state.as_ref(), Some(&self.reload))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.outputs = kept;
+        let replaced = self
+            .control
+            .replace_outputs(removed.iter().map(|(_, id)| *id).collect(), sinks)
+            .await?;
+        let mut errors = Vec::new();
+        for ((output, _), result) in removed.iter().zip(replaced.removed) {
+            match result {
+                Ok(()) => changes.push(format!("Stopped output {}", output.kind())),
+                Err(e) => errors.push(format!("Output {}: {:#}", output.kind(), e)),
+            }
+        }
+        for (output, result) in added.into_iter().zip(replaced.added) {
+            match result {
+                Ok(id) => {
+                    changes.push(format!("Started output {}", output.kind()));
+                    self.outputs.push((output, id));
+                }
+                Err(e) => errors.push(format!("Output {}: {:#}", output.kind(), e)),
+            }
+        }
+        if !errors.is_empty() {
+            anyhow::bail!("{}", errors.join("; "));
+        }
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff, Config, InputConfig, OutputConfig, Tags};
+    use crate::{
+        devices::{elm327::Pid, wmbus::Mode},
+        output::retry::RetryPolicy,
+        pipeline::OverflowPolicy,
+        transform::{battery::Chemistry, events::RuleValue, schema::SchemaMode, totals::Period},
+        Measurement,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::from_toml(
+            r#"
+            [pipeline]
+            overflow = "drop-oldest"
+            schema = "flag"
+            tags = { site = "home" }
+            metadata = "/etc/sensorflow/sensors.toml"
+            timestamps = { max_age = 86400, clamp = true }
+            watchdog = { misses = 5 }
+            counters = { fields = ["volume"] }
+            totals = [{ field = "volume_delta", period = "month" }]
+            pressure = { altitude = 520 }
+            position = { max_age = 30, geohash = 6 }
+            events = [{ name = "door_open", field = "closed", becomes = false }]
+            batteries = [{ match = { model = "WSDCGQ11LM" }, field = "voltage", chemistry = "cr2032" }]
+            identity = { sensors = { garden = "12" }, silence = 300 }
+
+            [[inputs]]
+            type = "http"
+            url = "http://shelly-plug/status"
+            interval = 30
+            fields = { power = "/meters/0/power" }
+
+            [[inputs]]
+            type = "jeelink"
+            device = "/dev/ttyUSB0"
+            tags = { room = "attic" }
+
+            [[inputs]]
+            type = "ds18b20"
+
+            [[outputs]]
+            type = "influxdb"
+
+            [[outputs]]
+            type = "statsd"
+            retry = { max_attempts = 10, max_backoff = 60000 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.pipeline.queue_capacity, 1024);
+        assert_eq!(config.pipeline.overflow, OverflowPolicy::DropOldest);
+        assert_eq!(config.pipeline.schema, Some(SchemaMode::Flag));
+        assert_eq!(config.pipeline.tags["site"], "home");
+        assert_eq!(
+            config.pipeline.metadata,
+            Some("/etc/sensorflow/sensors.toml".into())
+        );
+        let watchdog = config.pipeline.watchdog.unwrap();
+        assert_eq!((watchdog.misses, watchdog.min_samples), (5, 3));
+        assert_eq!(config.pipeline.counters.unwrap().fields, ["volume"]);
+        assert_eq!(config.pipeline.totals[0].period, Period::Month);
+        let pressure = config.pipeline.pressure.unwrap();
+        assert_eq!(pressure.altitude, Some(520.));
+        assert_eq!(pressure.fields, ["pressure", "station_pressure"]);
+        let position = config.pipeline.position.unwrap();
+        assert_eq!((position.max_age, position.geohash), (30, Some(6)));
+        assert!(position.fields);
+        let event = &config.pipeline.events[0];
+        assert_eq!(event.becomes, Some(RuleValue::Boolean(false)));
+        assert_eq!(event.window, 600);
+        let battery = &config.pipeline.batteries[0];
+        assert_eq!(
+            (battery.chemistry, battery.cells),
+            (Some(Chemistry::Cr2032), 1)
+        );
+        let identity = config.pipeline.identity.unwrap();
+        assert_eq!(identity.sensors["garden"], "12");
+        assert_eq!((identity.silence, identity.max_humidity_delta), (300, 10.));
+        assert_eq!(config.pipeline.timestamps.max_age, Some(86400));
+        assert!(config.pipeline.timestamps.clamp);
+        let InputConfig::Http(http) = &config.inputs[0] else {
+            panic!("not an http input")
+        };
+        assert_eq!(http.interval, 30);
+        assert_eq!(http.measurement, "http");
+        assert_eq!(http.fields["power"], "/meters/0/power");
+        assert_eq!(
+            config.inputs[1],
+            InputConfig::Jeelink {
+                device: "/dev/ttyUSB0".into(),
+                rssi_command: None,
+                serial: Default::default(),
+                diagnostics: false,
+                tags: [("room".into(), "attic".into())].into(),
+            }
+        );
+        assert_eq!(
+            config.inputs[2],
+            InputConfig::Ds18b20 {
+                path: "/sys/bus/w1/devices".into(),
+                interval: 60,
+                tags: Tags::new(),
+            }
+        );
+        assert_eq!(config.outputs[0], OutputConfig::Influxdb);
+        let retry = config.outputs[1].retry().unwrap();
+        assert_eq!(retry.max_attempts, 10);
+        assert_eq!(retry.max_backoff, Duration::from_secs(60));
+        assert_eq!(config.outputs[0].retry().unwrap(), RetryPolicy::default());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize_config() {
+        let config = Config::from_toml(
+            r#"
+            [[inputs]]
+            type = "http"
+            url = "http://shelly-plug/status"
+            tags = { room = "kitchen" }
+            fields = { power = "/meters/0/power" }
+
+            [[inputs]]
+            type = "cul"
+            device = "/dev/ttyACM0"
+
+            [[outputs]]
+            type = "stringify"
+            "#,
+        )
+        .unwrap();
+        let toml = toml::to_string(&config).unwrap();
+        assert_eq!(Config::from_toml(&toml).unwrap(), config);
+    }
+
+    #[test]
+    fn test_reject_invalid_config() {
+        assert!(Config::from_toml("[[inputs]]\ntype = \"unknown\"").is_err());
+        assert!(Config::from_toml("[[inputs]]\ntype = \"cul\"").is_err());
+        assert!(Config::from_toml("[[inputs]]\ntype = \"cul\"\ndevice = \"a\"\nbaud = 1").is_err());
+        assert!(Config::from_toml("[[inputs]]\ntype = \"http\"\nurl = \"http://a\"").is_err());
+    }
+
+    #[test]
+    fn test_resolve_secrets() {
+        std::env::set_var("SENSORFLOW_TEST_DOMOTICZ", "hunter2");
+        std::env::set_var("SENSORFLOW_TEST_METER", "000102030405060708090a0b0c0d0e0f");
+        let config = Config::from_toml(
+            r#"
+            [[inputs]]
+            type = "wmbus"
+            device = "/dev/sensorflow-missing"
+            mode = "c1"
+            keys = { "12345678" = "${SENSORFLOW_TEST_METER}" }
+
+            [[outputs]]
+            type = "domoticz"
+            url = "http://domoticz:8080"
+            username = "sensorflow"
+            password = "${SENSORFLOW_TEST_DOMOTICZ}"
+            devices = []
+            "#,
+        )
+        .unwrap();
+        let OutputConfig::Domoticz(domoticz) = &config.outputs[0] else {
+            panic!("Not a Domoticz output");
+        };
+        assert_eq!(domoticz.password.as_deref(), Some("hunter2"));
+        let InputConfig::Wmbus { mode, keys, .. } = &config.inputs[0] else {
+            panic!("Not a wM-Bus input");
+        };
+        assert_eq!(*mode, Mode::C1);
+        assert_eq!(keys["12345678"], "000102030405060708090a0b0c0d0e0f");
+        assert_eq!(
+            config.check()[0],
+            "Input 1 (wmbus on /dev/sensorflow-missing): /dev/sensorflow-missing does not exist"
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let config = Config::from_toml(
+            r#"
+            [[inputs]]
+            type = "jeelink"
+            device = "/dev/sensorflow-missing"
+
+            [[inputs]]
+            type = "http"
+            url = "shelly-plug/status"
+            fields = { power = "/meters/0/power" }
+
+            [[outputs]]
+            type = "influxdb"
+
+            [[outputs]]
+            type = "exec"
+            command = []
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.summary(),
+            "Queues of 1024 measurements, Block on overflow\n\
+             Input 1: jeelink on /dev/sensorflow-missing\n\
+             Input 2: http shelly-plug/status every 60 s\n\
+             Output 1: influxdb\n\
+             Output 2: exec\n"
+        );
+        let errors = config.check();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(
+            errors[0],
+            "Input 1 (jeelink on /dev/sensorflow-missing): /dev/sensorflow-missing does not exist"
+        );
+        assert!(errors[1].starts_with("Input 2 (http shelly-plug/status every 60 s): Invalid URL"));
+        assert_eq!(
+            errors[2],
+            "Output 2 (exec): Command of exec output is empty"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reload_pipeline_section() {
+        let config = |pipeline: &str| {
+            Config::from_toml(&format!(
+                "[pipeline]\n{}\n\n\
+                 [[inputs]]\ntype = \"hwmon\"\npath = {:?}\ninterval = 3600\n\n\
+                 [[outputs]]\ntype = \"stringify\"\n",
+                pipeline,
+                std::env::temp_dir(),
+            ))
+            .unwrap()
+        };
+        let (pipeline, mut reloader) = config(r#"tags = { site = "home" }"#)
+            .build_reloadable()
+            .unwrap();
+        let task = tokio::spawn(pipeline.run());
+        let changes = reloader
+            .reload(config(r#"tags = { site = "boat" }"#))
+            .await
+            .unwrap();
+        assert_eq!(changes, ["Replaced the transforms"]);
+        let changes = reloader
+            .reload(config("queue_capacity = 16\ntags = { site = \"boat\" }"))
+            .await
+            .unwrap();
+        assert_eq!(
+            changes,
+            ["Changed queue, state or timestamp settings take effect after a restart"]
+        );
+        task.abort();
+    }
+
+    #[test]
+    fn test_schema_before_enrichment() {
+        let config = Config::from_toml(
+            r#"
+            [pipeline]
+            schema = "reject"
+
+            [pipeline.pressure]
+            altitude = 500.0
+
+            [[inputs]]
+            type = "nmea"
+            device = "/dev/ttyUSB1"
+            "#,
+        )
+        .unwrap();
+        let mut transforms = config.transforms(None).unwrap();
+        let mut apply = |measurement: Measurement| {
+            transforms
+                .iter_mut()
+                .fold(vec![measurement], |measurements, transform| {
+                    measurements
+                        .into_iter()
+                        .flat_map(|measurement| transform.apply(measurement))
+                        .collect()
+                })
+        };
+        let position = Measurement::new("position")
+            .add_tag("receiver", "GP")
+            .add_field("latitude", 53.55)
+            .add_field("longitude", 9.99);
+        assert_eq!(apply(position).len(), 1);
+
+        let temperature = Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_tag("sensorType", 1)
+            .add_field("temperature", 21.5);
+        let measurements = apply(temperature);
+        assert_eq!(measurements.len(), 1);
+        assert!(measurements[0].field("latitude").is_some());
+
+        let weather = Measurement::new("weather")
+            .add_tag("serialNumber", "ST-00000512")
+            .add_tag("hub", "HB-00000001")
+            .add_field("station_pressure", 955.)
+            .add_field("air_temperature", 12.);
+        let measurements = apply(weather);
+        assert_eq!(measurements.len(), 1);
+        assert!(measurements[0]
+            .field("station_pressure_sea_level")
+            .is_some());
+
+        // Devices are still checked
+        let mistyped = Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_tag("sensorType", 1)
+            .add_field("temperature", "warm");
+        assert!(apply(mistyped).is_empty());
+    }
+
+    #[cfg(feature = "i2c")]
+    #[test]
+    fn test_i2c_inputs() {
+        let config = Config::from_toml(
+            r#"
+            [[inputs]]
+            type = "i2c"
+            model = "bme280"
+            address = 0x77
+            interval = 30
+
+            [[inputs]]
+            type = "i2c"
+            bus = "/dev/i2c-0"
+            model = "scd4x"
+            "#,
+        )
+        .unwrap();
+        let inputs: Vec<_> = config.inputs.iter().map(|i| i.describe()).collect();
+        assert_eq!(
+            inputs,
+            [
+                "i2c bme280 on /dev/i2c-1@0x77 every 30 s",
+                "i2c scd4x on /dev/i2c-0@0x62 every 60 s",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_device_inputs() {
+        let config = Config::from_toml(
+            r#"
+            [[inputs]]
+            type = "davis"
+            device = "/dev/ttyUSB0"
+
+            [[inputs]]
+            type = "elm327"
+            device = "/dev/rfcomm0"
+            pids = ["rpm", "engine_load"]
+            interval = 5
+
+            [[inputs]]
+            type = "pms"
+            device = "/dev/ttyAMA0"
+            passive = true
+
+            [[inputs]]
+            type = "weatherflow"
+            "#,
+        )
+        .unwrap();
+        let InputConfig::Elm327 { pids, .. } = &config.inputs[1] else {
+            panic!("Not an ELM327 input");
+        };
+        assert_eq!(pids, &[Pid::EngineRpm, Pid::EngineLoad]);
+        let inputs: Vec<_> = config.inputs.iter().map(|i| i.describe()).collect();
+        assert_eq!(
+            inputs,
+            [
+                "davis on /dev/ttyUSB0",
+                "elm327 on /dev/rfcomm0 every 5 s",
+                "pms on /dev/ttyAMA0 every 60 s",
+                "weatherflow on 0.0.0.0:50222",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let (kept, removed, added) = diff(vec![("a", 0), ("b", 1), ("a", 2)], vec!["a", "c", "b"]);
+        assert_eq!(kept, [("a", 0), ("b", 1)]);
+        assert_eq!(removed, [("a", 2)]);
+        assert_eq!(added, ["c"]);
+    }
+}
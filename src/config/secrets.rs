@@ -0,0 +1,134 @@
+//! References to secrets in pipeline files.
+//!
+//! Tokens and passwords need not be written into the pipeline file. Any string value may refer
+//! to environment variables as `${NAME}`, e.g. `password = "${MQTT_PASSWORD}"`, and `$${`
+//! stands for a literal `${`. A value of the form `@file:/run/secrets/token` is replaced by the
+//! content of the file without trailing line breaks, as written by Docker and systemd
+//! credentials. References are resolved when the file is loaded, so a reload picks up rotated
+//! secrets, and a missing variable or file fails loading.
+use anyhow::Context;
+use toml::{Table, Value};
+
+/// Prefix of values read from a file
+pub const FILE_PREFIX: &str = "@file:";
+
+/// Replace all references in the string values of `table`, returns whether there were any
+pub fn resolve(table: &mut Table) -> anyhow::Result<bool> {
+    let mut resolved = false;
+    for (key, value) in table.iter_mut() {
+        resolved |= resolve_value(value, key)?;
+    }
+    Ok(resolved)
+}
+
+fn resolve_value(value: &mut Value, path: &str) -> anyhow::Result<bool> {
+    match value {
+        Value::String(s) => match resolve_str(s).with_context(|| format!("Secret of {}", path))? {
+            Some(resolved) => {
+                *s = resolved;
+                Ok(true)
+            }
+            None => Ok(false),
+        },
+        Value::Array(values) => {
+            let mut resolved = false;
+            for (i, value) in values.iter_mut().enumerate() {
+                resolved |= resolve_value(value, &format!("{}[{}]", path, i))?;
+            }
+            Ok(resolved)
+        }
+        Value::Table(table) => {
+            let mut resolved = false;
+            for (key, value) in table.iter_mut() {
+                resolved |= resolve_value(value, &format!("{}.{}", path, key))?;
+            }
+            Ok(resolved)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Value of a string with references, `None` if it has none
+pub fn resolve_str(s: &str) -> anyhow::Result<Option<String>> {
+    if let Some(path) = s.strip_prefix(FILE_PREFIX) {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+        return Ok(Some(content.trim_end_matches(['\r', '\n']).to_string()));
+    }
+    if !s.contains("${") {
+        return Ok(None);
+    }
+    let mut resolved = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            resolved.push_str(&rest[..start - 1]);
+            resolved.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        resolved.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("Unterminated reference in {:?}", s))?;
+        let name = &rest[start + 2..start + end];
+        let value = std::env::var(name)
+            .with_context(|| format!("Environment variable {} is not set", name))?;
+        resolved.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    resolved.push_str(rest);
+    Ok(Some(resolved))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{resolve, resolve_str};
+
+    #[test]
+    fn test_environment() {
+        std::env::set_var("SENSORFLOW_TEST_TOKEN", "s3cret");
+        assert_eq!(
+            resolve_str("Bearer ${SENSORFLOW_TEST_TOKEN}!").unwrap(),
+            Some("Bearer s3cret!".into())
+        );
+        assert_eq!(
+            resolve_str("$${SENSORFLOW_TEST_TOKEN} is ${SENSORFLOW_TEST_TOKEN}").unwrap(),
+            Some("${SENSORFLOW_TEST_TOKEN} is s3cret".into())
+        );
+        assert_eq!(resolve_str("sensorflow/{measurement}").unwrap(), None);
+        assert_eq!(
+            resolve_str("${SENSORFLOW_TEST_UNSET}")
+                .unwrap_err()
+                .to_string(),
+            "Environment variable SENSORFLOW_TEST_UNSET is not set"
+        );
+        assert!(resolve_str("${SENSORFLOW_TEST_TOKEN").is_err());
+    }
+
+    #[test]
+    fn test_file() {
+        let path =
+            std::env::temp_dir().join(format!("sensorflow-secret-{:08x}", rand::random::<u32>()));
+        std::fs::write(&path, "api-key\n").unwrap();
+        let mut table: toml::Table = toml::from_str(&format!(
+            r#"
+            [[outputs]]
+            type = "openhab"
+            token = "@file:{}"
+            [[outputs]]
+            type = "api"
+            token = "${{SENSORFLOW_TEST_MISSING}}"
+            "#,
+            path.display()
+        ))
+        .unwrap();
+        let error = resolve(&mut table).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error.to_string(), "Secret of outputs[1].token");
+        assert_eq!(table["outputs"][0]["token"].as_str(), Some("api-key"));
+
+        let mut table: toml::Table = toml::from_str("type = \"stringify\"").unwrap();
+        assert!(!resolve(&mut table).unwrap());
+    }
+}
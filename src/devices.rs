@@ -1,14 +1,182 @@
 //! Read from IO devices.
+//!
+//! Frames and readings of the devices are plain data with public fields, or keep their fields
+//! private and provide a getter of the same name for each, returning `Copy` values by value
+//! and others by reference.
 
+#[cfg(not(target_arch = "wasm32"))]
+use crate::input::line::SerialSettings;
 use async_trait::async_trait;
+use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
 
-pub use jeelink::JeeLink;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::{
+    cul::Cul, davis::Davis, elm327::Elm327, enocean::EnOcean, hwmon::Hwmon, jeelink::JeeLink,
+    nmea::Nmea, onewire::OneWire, pms::Pms, weatherflow::WeatherFlow, wmbus::WMBus,
+};
 
-use crate::output::ToOutput;
+use crate::{
+    clock::Clock,
+    input::{error::DeviceError, FrameStats},
+    output::ToOutput,
+    schema::Schema,
+};
 
+#[cfg(feature = "coap")]
+pub mod coap;
+pub mod cul;
+pub mod davis;
+pub mod elm327;
+pub mod enocean;
+#[cfg(feature = "gpio")]
+pub mod gpio;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod http;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod hwmon;
+#[cfg(feature = "i2c")]
+pub mod i2c;
 pub mod jeelink;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod nmea;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod onewire;
+pub mod pms;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod poll;
+#[cfg(feature = "snmp")]
+pub mod snmp;
+pub mod status;
+#[cfg(feature = "mqtt")]
+pub mod ttn;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod weatherflow;
+pub mod wmbus;
+#[cfg(feature = "mqtt")]
+pub mod zigbee2mqtt;
+
+/// Connection state of a device
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum DeviceHealth {
+    /// No read attempted yet
+    #[default]
+    Unknown,
+    /// Last read succeeded
+    Connected,
+    /// Device closed the connection
+    Disconnected,
+    /// Last read failed with the given error
+    Failed(String),
+}
+
+impl DeviceHealth {
+    /// Health of a device after a read returned the given result
+    pub fn after_read<T>(res: &anyhow::Result<Option<T>>) -> DeviceHealth {
+        match res {
+            Ok(Some(_)) => DeviceHealth::Connected,
+            Ok(None) => DeviceHealth::Disconnected,
+            Err(e) => match e.downcast_ref::<DeviceError>() {
+                Some(DeviceError::ConnectionLost) => DeviceHealth::Disconnected,
+                _ => DeviceHealth::Failed(e.to_string()),
+            },
+        }
+    }
+}
 
 #[async_trait]
-pub trait Device {
+pub trait Device: Send {
+    /// Read the next frame, `None` once the device is exhausted.
+    ///
+    /// Implementations should be cancellation safe: a read dropped before it completed must not
+    /// lose received data, so callers may abandon it, e.g. with [Device::read_frame_timeout].
     async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>>;
+
+    /// Read the next frame, failing with [DeviceError::Timeout] if none arrives within
+    /// `timeout`, e.g. for watchdogs on devices which went quiet
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn read_frame_timeout(
+        &mut self,
+        timeout: std::time::Duration,
+    ) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        tokio::time::timeout(timeout, self.read_frame())
+            .await
+            .map_err(|_| DeviceError::Timeout(timeout))?
+    }
+
+    /// Kind of the device, e.g. `jeelink`
+    fn name(&self) -> &str;
+
+    /// Where the device is attached, e.g. the path of the serial port
+    fn address(&self) -> &str;
+
+    fn health(&self) -> DeviceHealth {
+        DeviceHealth::Unknown
+    }
+
+    /// Bytes and frames received, if the device reads a byte stream in diagnostics mode, see
+    /// [FramedListener::with_diagnostics]
+    ///
+    /// [FramedListener::with_diagnostics]: crate::FramedListener::with_diagnostics
+    fn frame_stats(&self) -> Option<FrameStats> {
+        None
+    }
+
+    /// Use the clock of the pipeline reading the device, for devices which timestamp data of
+    /// their own, e.g. the status of sensors. The system clock is used otherwise.
+    fn set_clock(&mut self, _clock: Arc<dyn Clock>) {}
+}
+
+/// Schemas of the measurements of all devices with a fixed set of fields
+pub fn schemas() -> Vec<Schema> {
+    [
+        cul::schemas(),
+        davis::schemas(),
+        elm327::schemas(),
+        enocean::schemas(),
+        #[cfg(feature = "gpio")]
+        gpio::schemas(),
+        #[cfg(not(target_arch = "wasm32"))]
+        hwmon::schemas(),
+        #[cfg(feature = "i2c")]
+        i2c::schemas(),
+        jeelink::schemas(),
+        nmea::schemas(),
+        #[cfg(not(target_arch = "wasm32"))]
+        onewire::schemas(),
+        pms::schemas(),
+        status::schemas(),
+        #[cfg(not(target_arch = "wasm32"))]
+        weatherflow::schemas(),
+        wmbus::schemas(),
+    ]
+    .concat()
+}
+
+/// Open a serial port for non-exclusive, asynchronous access
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn open_serial(path: &str, baud_rate: u32) -> anyhow::Result<SerialStream> {
+    open_serial_with(path, baud_rate, &SerialSettings::default())
+}
+
+/// Open a serial port with the given line settings, see [open_serial]. The baud rate of the
+/// settings replaces `baud_rate`.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn open_serial_with(
+    path: &str,
+    baud_rate: u32,
+    settings: &SerialSettings,
+) -> anyhow::Result<SerialStream> {
+    let baud_rate = settings.baud_rate.unwrap_or(baud_rate);
+    let mut port = settings
+        .configure(tokio_serial::new(path, baud_rate))?
+        .open_native_async()?;
+
+    #[cfg(unix)]
+    port.set_exclusive(false)?;
+    settings.apply_lines(&mut port)?;
+
+    Ok(port)
 }
@@ -0,0 +1,376 @@
+//! Constrained sensors, e.g. battery-powered 6LoWPAN or Thread nodes, observed via CoAP.
+//!
+//! A GET with the Observe option registers for notifications of a resource, given as URI like
+//! `coap://[fd00::1]/sensors/temp`. Every notification is converted into a measurement.
+//! Confirmable notifications are acknowledged. If no notification arrives before the Max-Age of
+//! the last one expires, the registration is renewed, so resources without observe support are
+//! polled instead.
+//!
+//! Payloads in plain text, JSON and SenML (JSON or CBOR) are understood.
+use crate::{
+    input::senml::{self, SenmlError},
+    measurement::{Measurement, ToMeasurement},
+    output::ToOutput,
+};
+use async_trait::async_trait;
+use coap_lite::{
+    option_value::OptionValueU32, CoapOption, ContentFormat, MessageClass, MessageType, Packet,
+    RequestType, ResponseType,
+};
+use std::collections::VecDeque;
+use std::fmt::{self, Display};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use super::{http::field_value, Device, DeviceHealth};
+
+/// Port of CoAP servers
+pub const DEFAULT_PORT: u16 = 5683;
+
+/// Freshness of a notification without Max-Age option
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Time granted in addition to Max-Age before registering again
+const REREGISTER_MARGIN: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug, PartialEq)]
+pub enum CoapError {
+    #[error("Invalid CoAP URI {0}")]
+    InvalidUri(String),
+    #[error("Server responded with {0}")]
+    Response(String),
+    #[error("Unsupported content format {0}")]
+    UnsupportedFormat(usize),
+    #[error("Invalid payload: {0}")]
+    InvalidPayload(String),
+    #[error(transparent)]
+    Senml(#[from] SenmlError),
+}
+
+/// Split a URI into the server address and the path segments
+pub fn parse_uri(uri: &str) -> Result<(String, Vec<String>), CoapError> {
+    let invalid = || CoapError::InvalidUri(uri.to_string());
+    let rest = uri.strip_prefix("coap://").ok_or_else(invalid)?;
+    let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+    if authority.is_empty() {
+        return Err(invalid());
+    }
+    // The colon of a port follows the closing bracket of IPv6 addresses
+    let has_port = authority
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.contains(']'));
+    let address = if has_port {
+        authority.to_string()
+    } else {
+        format!("{}:{}", authority, DEFAULT_PORT)
+    };
+    let path = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(String::from)
+        .collect();
+    Ok((address, path))
+}
+
+/// Convert the payload of a notification into measurements
+///
+/// Plain text is read as a single number named `value`, the scalar members of a JSON object
+/// become fields of the same name. Both are emitted as measurement `coap`, SenML records are
+/// grouped as described in [senml::to_measurements].
+pub fn decode_payload(
+    format: Option<ContentFormat>,
+    payload: &[u8],
+) -> Result<Vec<Measurement>, CoapError> {
+    let invalid = |e: &dyn ToString| CoapError::InvalidPayload(e.to_string());
+    match format {
+        None | Some(ContentFormat::TextPlain) => {
+            let text = std::str::from_utf8(payload)
+                .map_err(|e| invalid(&e))?
+                .trim();
+            let value: f64 = text.parse().map_err(|e| invalid(&e))?;
+            Ok(vec![Measurement::new("coap").add_field("value", value)])
+        }
+        Some(ContentFormat::ApplicationJSON) => {
+            let document: serde_json::Value =
+                serde_json::from_slice(payload).map_err(|e| invalid(&e))?;
+            let object = document
+                .as_object()
+                .ok_or_else(|| invalid(&"JSON payload is not an object"))?;
+            let mut measurement = Measurement::new("coap");
+            for (key, value) in object {
+                if let Some(value) = field_value(value) {
+                    measurement = measurement.add_field(key, value);
+                }
+            }
+            if measurement.fields.is_empty() {
+                return Ok(vec![]);
+            }
+            Ok(vec![measurement])
+        }
+        Some(ContentFormat::ApplicationSenmlJSON) => Ok(senml::to_measurements(
+            &senml::decode_json(payload)?,
+            "coap",
+        )),
+        Some(ContentFormat::ApplicationSenmlCBOR) => Ok(senml::to_measurements(
+            &senml::decode_cbor(payload)?,
+            "coap",
+        )),
+        Some(format) => Err(CoapError::UnsupportedFormat(usize::from(format))),
+    }
+}
+
+/// Measurement decoded from a notification
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoapReading {
+    pub uri: String,
+    pub measurement: Measurement,
+}
+
+impl ToOutput for CoapReading {}
+
+impl Display for CoapReading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CoAP {}: {}", self.uri, self.measurement)
+    }
+}
+
+impl ToMeasurement for CoapReading {
+    fn to_measurement(&self) -> Measurement {
+        self.measurement.clone().add_tag("uri", &self.uri)
+    }
+}
+
+/// Observer of a single resource
+pub struct CoapObserve {
+    socket: UdpSocket,
+    uri: String,
+    path: Vec<String>,
+    token: Vec<u8>,
+    message_id: u16,
+    /// Time to renew the registration, `None` if not registered
+    renew_at: Option<Instant>,
+    /// Measurements of the last notification not yet returned
+    pending: VecDeque<Measurement>,
+    health: DeviceHealth,
+}
+
+impl CoapObserve {
+    /// Observe the resource at `uri`. Must be called within a Tokio runtime.
+    pub fn new(uri: &str) -> anyhow::Result<Self> {
+        let (address, path) = parse_uri(uri)?;
+        let bind = if address.starts_with('[') {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let socket = std::net::UdpSocket::bind(bind)?;
+        socket.connect(&address)?;
+        socket.set_nonblocking(true)?;
+        Ok(CoapObserve {
+            socket: UdpSocket::from_std(socket)?,
+            uri: uri.to_string(),
+            path,
+            token: rand::random::<[u8; 4]>().to_vec(),
+            message_id: rand::random(),
+            renew_at: None,
+            pending: VecDeque::new(),
+            health: DeviceHealth::Unknown,
+        })
+    }
+
+    async fn register(&mut self) -> anyhow::Result<()> {
+        let mut request = Packet::new();
+        request.header.set_type(MessageType::NonConfirmable);
+        request.header.code = MessageClass::Request(RequestType::Get);
+        self.message_id = self.message_id.wrapping_add(1);
+        request.header.message_id = self.message_id;
+        request.set_token(self.token.clone());
+        request.set_observe_value(0);
+        for segment in &self.path {
+            request.add_option(CoapOption::UriPath, segment.as_bytes().to_vec());
+        }
+        self.socket.send(&request.to_bytes()?).await?;
+        // Registration is renewed if the server does not answer
+        self.renew_at = Some(Instant::now() + DEFAULT_MAX_AGE + REREGISTER_MARGIN);
+        Ok(())
+    }
+
+    async fn acknowledge(&mut self, notification: &Packet) -> anyhow::Result<()> {
+        let mut ack = Packet::new();
+        ack.header.set_type(MessageType::Acknowledgement);
+        ack.header.code = MessageClass::Empty;
+        ack.header.message_id = notification.header.message_id;
+        self.socket.send(&ack.to_bytes()?).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Device for CoapObserve {
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        let mut buffer = [0; 1500];
+        loop {
+            if let Some(measurement) = self.pending.pop_front() {
+                return Ok(Some(Box::new(CoapReading {
+                    uri: self.uri.clone(),
+                    measurement,
+                })));
+            }
+            let renew_at = match self.renew_at {
+                Some(renew_at) if renew_at > Instant::now() => renew_at,
+                _ => {
+                    self.register().await?;
+                    continue;
+                }
+            };
+            let Ok(res) = tokio::time::timeout_at(renew_at, self.socket.recv(&mut buffer)).await
+            else {
+                self.renew_at = None;
+                continue;
+            };
+            let len = match res {
+                Ok(len) => len,
+                Err(e) => {
+                    self.health = DeviceHealth::Failed(e.to_string());
+                    return Err(e.into());
+                }
+            };
+            let Ok(packet) = Packet::from_bytes(&buffer[..len]) else {
+                continue;
+            };
+            if packet.get_token() != self.token {
+                continue;
+            }
+            if packet.header.get_type() == MessageType::Confirmable {
+                self.acknowledge(&packet).await?;
+            }
+            if packet.header.code != MessageClass::Response(ResponseType::Content) {
+                let e = CoapError::Response(packet.header.get_code());
+                self.health = DeviceHealth::Failed(e.to_string());
+                return Err(e.into());
+            }
+            let max_age = packet
+                .get_first_option_as::<OptionValueU32>(CoapOption::MaxAge)
+                .and_then(Result::ok)
+                .map_or(DEFAULT_MAX_AGE, |max_age| {
+                    Duration::from_secs(max_age.0 as u64)
+                });
+            self.renew_at = Some(Instant::now() + max_age + REREGISTER_MARGIN);
+            self.health = DeviceHealth::Connected;
+
+            let format = packet.get_content_format();
+            self.pending
+                .extend(decode_payload(format, &packet.payload)?);
+        }
+    }
+
+    fn name(&self) -> &str {
+        "coap"
+    }
+
+    fn address(&self) -> &str {
+        &self.uri
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_payload, parse_uri, CoapError, CoapObserve};
+    use crate::{devices::Device, measurement::FieldValue};
+    use coap_lite::{CoapOption, ContentFormat, MessageClass, MessageType, Packet, ResponseType};
+
+    #[test]
+    fn test_parse_uri() {
+        assert_eq!(
+            parse_uri("coap://[fd00::1]/sensors/temp").unwrap(),
+            (
+                "[fd00::1]:5683".into(),
+                vec!["sensors".into(), "temp".into()]
+            )
+        );
+        assert_eq!(
+            parse_uri("coap://node.local:1234").unwrap(),
+            ("node.local:1234".into(), vec![])
+        );
+        assert_eq!(
+            parse_uri("http://node"),
+            Err(CoapError::InvalidUri("http://node".into()))
+        );
+    }
+
+    #[test]
+    fn test_decode_payload() {
+        assert_eq!(
+            decode_payload(Some(ContentFormat::TextPlain), b"21.5").unwrap()[0].fields,
+            [("value".into(), FieldValue::Float(21.5))]
+        );
+        assert_eq!(
+            decode_payload(
+                Some(ContentFormat::ApplicationJSON),
+                br#"{"t":21,"on":true,"nested":{}}"#
+            )
+            .unwrap()[0]
+                .fields,
+            [
+                ("on".into(), FieldValue::Boolean(true)),
+                ("t".into(), FieldValue::Integer(21))
+            ]
+        );
+        let measurements = decode_payload(
+            Some(ContentFormat::ApplicationSenmlJSON),
+            br#"[{"bn":"node1/","n":"temp","u":"Cel","v":21.5},{"n":"hum","u":"%RH","v":40}]"#,
+        )
+        .unwrap();
+        assert_eq!(measurements.len(), 1);
+        assert_eq!(measurements[0].name, "node1");
+        assert_eq!(measurements[0].field("hum"), Some(&FieldValue::Float(40.)));
+        assert!(decode_payload(Some(ContentFormat::ImagePng), b"").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_observe_and_acknowledge() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("coap://{}/temp", server.local_addr().unwrap());
+        let mut device = CoapObserve::new(&uri).unwrap();
+        let reading = tokio::spawn(async move {
+            let frame = device.read_frame().await.unwrap().unwrap();
+            frame.to_measurement()
+        });
+
+        let mut buffer = [0; 1500];
+        let (len, client) = server.recv_from(&mut buffer).await.unwrap();
+        let request = Packet::from_bytes(&buffer[..len]).unwrap();
+        assert_eq!(request.get_observe_value(), Some(Ok(0)));
+        assert_eq!(
+            request.get_first_option(CoapOption::UriPath),
+            Some(&b"temp".to_vec())
+        );
+
+        let mut notification = Packet::new();
+        notification.header.set_type(MessageType::Confirmable);
+        notification.header.code = MessageClass::Response(ResponseType::Content);
+        notification.header.message_id = 77;
+        notification.set_token(request.get_token().to_vec());
+        notification.set_observe_value(12);
+        notification.set_content_format(ContentFormat::TextPlain);
+        notification.payload = b"19.25".to_vec();
+        server
+            .send_to(&notification.to_bytes().unwrap(), client)
+            .await
+            .unwrap();
+
+        let (len, _) = server.recv_from(&mut buffer).await.unwrap();
+        let ack = Packet::from_bytes(&buffer[..len]).unwrap();
+        assert_eq!(ack.header.get_type(), MessageType::Acknowledgement);
+        assert_eq!(ack.header.message_id, 77);
+
+        let measurement = reading.await.unwrap();
+        assert_eq!(measurement.field("value"), Some(&FieldValue::Float(19.25)));
+    }
+}
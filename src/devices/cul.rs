@@ -0,0 +1,621 @@
+//! Busware CUL sticks running the culfw firmware.
+//!
+//! The firmware reports every received message as a line of hex characters, prefixed by a
+//! letter identifying the protocol. On startup, RSSI reporting (`X21`) and the LaCrosse native
+//! mode (`Nr1`) are enabled.
+use crate::{
+    error::*,
+    input::{checksum::crc8, decode_hex},
+    measurement::{Measurement, ToMeasurement, Unit},
+    output::ToOutput,
+    schema::{FieldType, Schema},
+    EncodableFrame, LineFrame,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    input::{line::SerialSettings, FrameStats},
+    FramedListener,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use async_trait::async_trait;
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use std::fmt::{self, Display};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_serial::SerialStream;
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::status::StatusFilter;
+use super::status::{Battery, SensorStatus, ToSensorStatus};
+#[cfg(not(target_arch = "wasm32"))]
+use super::{open_serial_with, Device, DeviceHealth};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::clock::{Clock, SystemClock};
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Baud rate of the device. The CUL is a USB CDC device, most firmware builds use 38.4 KBd
+const BAUD_RATE: u32 = 38400;
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Commands sent on startup: report messages with RSSI, receive LaCrosse in native mode
+const INIT_COMMANDS: [&str; 2] = ["X21\r\n", "Nr1\r\n"];
+
+#[cfg(not(target_arch = "wasm32"))]
+pub struct Cul {
+    reader: FramedListener<SerialStream, CulFrame>,
+    path: String,
+    health: DeviceHealth,
+    initialized: bool,
+    /// Status of the sensor of the last frame, returned by the next read
+    status: Option<SensorStatus>,
+    statuses: StatusFilter,
+    clock: Arc<dyn Clock>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl Device for Cul {
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        if !self.initialized {
+            for command in INIT_COMMANDS {
+                self.reader.write_all(command.as_bytes()).await?;
+            }
+            self.initialized = true;
+        }
+        if let Some(status) = self.status.take() {
+            return Ok(Some(Box::new(status)));
+        }
+        loop {
+            let res = self.reader.read_frame().await;
+            self.health = DeviceHealth::after_read(&res);
+            match res {
+                // Command responses and unsupported protocols carry no measurement
+                Ok(Some(CulFrame::Unknown(_))) => continue,
+                Ok(Some(frame)) => {
+                    self.status = frame
+                        .sensor_status(self.clock.now())
+                        .and_then(|status| self.statuses.update(status));
+                    return Ok(Some(Box::new(frame)));
+                }
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "cul"
+    }
+
+    fn address(&self) -> &str {
+        &self.path
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
+
+    fn frame_stats(&self) -> Option<FrameStats> {
+        self.reader.stats().cloned()
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Cul {
+    pub fn new<'a>(path: impl Into<std::borrow::Cow<'a, str>>) -> anyhow::Result<Self> {
+        Self::with_serial(path, &SerialSettings::default())
+    }
+
+    /// Open the device with line settings other than 8N1
+    pub fn with_serial<'a>(
+        path: impl Into<std::borrow::Cow<'a, str>>,
+        serial: &SerialSettings,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+        let port = open_serial_with(&path, BAUD_RATE, serial)?;
+
+        Ok(Cul {
+            reader: FramedListener::new(port).with_auto_baud(
+                &serial.auto_baud,
+                Duration::from_secs(serial.probe_timeout),
+                INIT_COMMANDS.concat().as_bytes(),
+            ),
+            path: path.into_owned(),
+            health: DeviceHealth::Connected,
+            // Sent at every probed rate instead
+            initialized: !serial.auto_baud.is_empty(),
+            status: None,
+            statuses: StatusFilter::default(),
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Count frame and garbage bytes, see [FramedListener::with_diagnostics]
+    pub fn with_diagnostics(mut self) -> Self {
+        self.reader = self.reader.with_diagnostics();
+        self
+    }
+}
+
+/// Message received by a CUL
+#[derive(Debug, Clone, PartialEq)]
+pub enum CulFrame {
+    /// FS20 switch or sensor
+    Fs20 {
+        housecode: String,
+        button: u8,
+        command: u8,
+        rssi: Option<f64>,
+    },
+    /// Homematic (BidCoS) message, with the payload following the header undecoded
+    Homematic {
+        counter: u8,
+        flags: u8,
+        message_type: u8,
+        source: String,
+        destination: String,
+        payload: Vec<u8>,
+        rssi: Option<f64>,
+    },
+    /// LaCrosse IT+ temperature/humidity sensor
+    LaCrosse {
+        id: u8,
+        new_battery: bool,
+        weak_battery: bool,
+        temperature: f64,
+        humidity: Option<u8>,
+        rssi: Option<f64>,
+    },
+    /// Any other line, e.g. responses to commands
+    Unknown(String),
+}
+
+/// Convert the RSSI byte reported by culfw to dBm
+fn rssi_dbm(raw: u8) -> f64 {
+    if raw >= 128 {
+        (raw as f64 - 256.) / 2. - 74.
+    } else {
+        raw as f64 / 2. - 74.
+    }
+}
+
+/// Convert an RSSI in dBm to the byte reported by culfw
+fn rssi_raw(dbm: f64) -> u8 {
+    ((dbm + 74.) * 2.).round() as i8 as u8
+}
+
+/// Polynomial of the CRC8 of LaCrosse IT+ sensors
+const LACROSSE_CRC8_POLY: u8 = 0x31;
+
+impl CulFrame {
+    /// `F` + house code (2 bytes) + button + command + optional extension + RSSI
+    fn parse_fs20(line: &str) -> Result<CulFrame, FrameValidation> {
+        let data = decode_hex(line)?;
+        let (rssi, data) = match data.len() {
+            4 => (None, &data[..]),
+            5 | 6 => (data.last().copied().map(rssi_dbm), &data[..4]),
+            _ => return Err(FrameValidation::WrongNumberOfFields(line.to_string())),
+        };
+        Ok(CulFrame::Fs20 {
+            housecode: format!("{:02X}{:02X}", data[0], data[1]),
+            button: data[2],
+            command: data[3],
+            rssi,
+        })
+    }
+
+    /// `A` + length + message of given length + RSSI
+    fn parse_homematic(line: &str) -> Result<CulFrame, FrameValidation> {
+        let data = decode_hex(line)?;
+        let len = *data
+            .first()
+            .ok_or_else(|| FrameValidation::WrongNumberOfFields(line.to_string()))?
+            as usize;
+        if len < 9 || data.len() < len + 1 {
+            return Err(FrameValidation::WrongNumberOfFields(line.to_string()));
+        }
+        let message = &data[1..=len];
+        Ok(CulFrame::Homematic {
+            counter: message[0],
+            flags: message[1],
+            message_type: message[2],
+            source: message[3..6].iter().map(|b| format!("{:02X}", b)).collect(),
+            destination: message[6..9].iter().map(|b| format!("{:02X}", b)).collect(),
+            payload: message[9..].to_vec(),
+            rssi: data.get(len + 1).copied().map(rssi_dbm),
+        })
+    }
+
+    /// `N` + mode (1 byte) + LaCrosse raw frame (5 bytes) + RSSI
+    fn parse_lacrosse(line: &str) -> Result<CulFrame, FrameValidation> {
+        let data = decode_hex(line)?;
+        if data.len() < 6 {
+            return Err(FrameValidation::WrongNumberOfFields(line.to_string()));
+        }
+        let raw = &data[1..6];
+        if raw[0] >> 4 != 0x9 || crc8(LACROSSE_CRC8_POLY, &raw[..4]) != raw[4] {
+            return Err(FrameValidation::InvalidChars(line.to_string()));
+        }
+        let id = ((raw[0] & 0x0F) << 2) | (raw[1] >> 6);
+        let new_battery = raw[1] & 0x20 != 0;
+        let bcd = (raw[1] & 0x0F) as u16 * 100 + (raw[2] >> 4) as u16 * 10 + (raw[2] & 0x0F) as u16;
+        // 106 is sent by sensors without humidity, 125 on a sensor error
+        let humidity = match raw[3] & 0x7F {
+            hum @ 0..=100 => Some(hum),
+            _ => None,
+        };
+        Ok(CulFrame::LaCrosse {
+            id,
+            new_battery,
+            weak_battery: raw[3] & 0x80 != 0,
+            temperature: (bcd as f64 - 400.) / 10.,
+            humidity,
+            rssi: data.get(6).copied().map(rssi_dbm),
+        })
+    }
+}
+
+impl LineFrame for CulFrame {
+    fn parse_line(line: &str) -> anyhow::Result<Self> {
+        let line = line.trim();
+        let frame = match line.split_at(line.chars().next().map_or(0, char::len_utf8)) {
+            ("F", data) => Self::parse_fs20(data)?,
+            ("A", data) => Self::parse_homematic(data)?,
+            ("N", data) => Self::parse_lacrosse(data)?,
+            _ => CulFrame::Unknown(line.to_string()),
+        };
+        Ok(frame)
+    }
+}
+
+impl EncodableFrame for CulFrame {
+    fn encode(&self) -> BytesMut {
+        let (prefix, data, rssi) = match self {
+            CulFrame::Fs20 {
+                housecode,
+                button,
+                command,
+                rssi,
+            } => {
+                let mut data = decode_hex(housecode).unwrap_or_default();
+                data.extend([*button, *command]);
+                ("F", data, rssi)
+            }
+            CulFrame::Homematic {
+                counter,
+                flags,
+                message_type,
+                source,
+                destination,
+                payload,
+                rssi,
+            } => {
+                let mut data = vec![9 + payload.len() as u8, *counter, *flags, *message_type];
+                data.extend(decode_hex(source).unwrap_or_default());
+                data.extend(decode_hex(destination).unwrap_or_default());
+                data.extend(payload);
+                ("A", data, rssi)
+            }
+            CulFrame::LaCrosse {
+                id,
+                new_battery,
+                weak_battery,
+                temperature,
+                humidity,
+                rssi,
+            } => {
+                let bcd = (temperature * 10. + 400.).round() as u16;
+                let mut raw = vec![
+                    0x90 | id >> 2,
+                    id << 6 | u8::from(*new_battery) << 5 | (bcd / 100) as u8,
+                    (((bcd / 10 % 10) << 4) | (bcd % 10)) as u8,
+                    humidity.unwrap_or(106) | u8::from(*weak_battery) << 7,
+                ];
+                raw.push(crc8(LACROSSE_CRC8_POLY, &raw));
+                // Mode of the LaCrosse receiver, not decoded
+                let mut data = vec![0x01];
+                data.extend(raw);
+                ("N", data, rssi)
+            }
+            CulFrame::Unknown(line) => return BytesMut::from(format!("{}\r\n", line).as_bytes()),
+        };
+        let mut line = String::from(prefix);
+        for byte in data.into_iter().chain(rssi.map(rssi_raw)) {
+            line.push_str(&format!("{:02X}", byte));
+        }
+        line.push_str("\r\n");
+        BytesMut::from(line.as_bytes())
+    }
+}
+
+impl ToOutput for CulFrame {}
+
+impl Display for CulFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CulFrame::Fs20 {
+                housecode,
+                button,
+                command,
+                ..
+            } => write!(
+                f,
+                "FS20 {}: Button {:02X}, Command {:02X}",
+                housecode, button, command
+            ),
+            CulFrame::Homematic {
+                source,
+                destination,
+                message_type,
+                ..
+            } => write!(
+                f,
+                "Homematic {} -> {}: Type {:02X}",
+                source, destination, message_type
+            ),
+            CulFrame::LaCrosse {
+                id,
+                temperature,
+                humidity,
+                ..
+            } => match humidity {
+                Some(hum) => write!(
+                    f,
+                    "LaCrosse {:2}: Temperatur {:4}, Humidity {:2}",
+                    id, temperature, hum
+                ),
+                None => write!(f, "LaCrosse {:2}: Temperatur {:4}", id, temperature),
+            },
+            CulFrame::Unknown(line) => write!(f, "{}", line),
+        }
+    }
+}
+
+fn add_rssi(measurement: Measurement, rssi: &Option<f64>) -> Measurement {
+    match rssi {
+        Some(rssi) => measurement.add_field("rssi", *rssi),
+        None => measurement,
+    }
+}
+
+impl ToSensorStatus for CulFrame {
+    fn sensor_status(&self, time: DateTime<Utc>) -> Option<SensorStatus> {
+        match self {
+            CulFrame::LaCrosse {
+                id,
+                new_battery,
+                weak_battery,
+                rssi,
+                ..
+            } => Some(SensorStatus {
+                sensor_id: id.to_string(),
+                battery: Battery::from_flags(*weak_battery, *new_battery),
+                rssi: *rssi,
+                last_seen: time,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl ToMeasurement for CulFrame {
+    fn to_measurement(&self) -> Measurement {
+        match self {
+            CulFrame::Fs20 {
+                housecode,
+                button,
+                command,
+                rssi,
+            } => add_rssi(
+                Measurement::new("fs20")
+                    .add_tag("housecode", housecode)
+                    .add_tag("button", format!("{:02X}", button))
+                    .add_field("command", *command as u64),
+                rssi,
+            ),
+            CulFrame::Homematic {
+                counter,
+                flags,
+                message_type,
+                source,
+                destination,
+                payload,
+                rssi,
+            } => add_rssi(
+                Measurement::new("homematic")
+                    .add_tag("source", source)
+                    .add_tag("destination", destination)
+                    .add_tag("messageType", format!("{:02X}", message_type))
+                    .add_field("counter", *counter as u64)
+                    .add_field("flags", *flags as u64)
+                    .add_field(
+                        "payload",
+                        payload
+                            .iter()
+                            .map(|b| format!("{:02X}", b))
+                            .collect::<String>(),
+                    ),
+                rssi,
+            ),
+            CulFrame::LaCrosse {
+                id,
+                temperature,
+                humidity,
+                ..
+            } => {
+                let measurement = Measurement::new("tempHum")
+                    .add_tag("sensorId", id)
+                    .add_field("temperature", *temperature);
+                match humidity {
+                    Some(hum) => measurement.add_field("humidity", *hum as u64),
+                    None => measurement,
+                }
+            }
+            CulFrame::Unknown(line) => Measurement::new("cul").add_field("line", line.as_str()),
+        }
+    }
+}
+
+/// Schemas of the measurements of [CulFrame], except of unknown frames
+pub fn schemas() -> Vec<Schema> {
+    vec![
+        Schema::new("fs20")
+            .add_tag("housecode")
+            .add_tag("button")
+            .add_field("command", FieldType::UInteger, None)
+            .add_optional_field("rssi", FieldType::Float, None),
+        Schema::new("homematic")
+            .add_tag("source")
+            .add_tag("destination")
+            .add_tag("messageType")
+            .add_field("counter", FieldType::UInteger, None)
+            .add_field("flags", FieldType::UInteger, None)
+            .add_field("payload", FieldType::String, None)
+            .add_optional_field("rssi", FieldType::Float, None),
+        Schema::new("tempHum")
+            .add_tag("sensorId")
+            .add_field("temperature", FieldType::Float, Unit::Celsius)
+            .add_optional_field("humidity", FieldType::UInteger, Unit::Percent),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Battery, CulFrame, EncodableFrame, SensorStatus, ToSensorStatus};
+    use crate::output::influx::ToLineProtocol;
+    use crate::Frame;
+    use bytes::BytesMut;
+    use chrono::DateTime;
+
+    fn parse(line: &str) -> CulFrame {
+        CulFrame::parse(BytesMut::from(line.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_check_skips_empty_lines() {
+        let mut buf = BytesMut::from(&b"\r\nF12340011\r\nV 1.67"[..]);
+        assert_eq!(
+            CulFrame::check(&mut buf),
+            Ok(BytesMut::from(&b"F12340011"[..]))
+        );
+        assert_eq!(buf, &b"V 1.67"[..]);
+    }
+
+    #[test]
+    fn test_encoding() {
+        for line in [
+            "F1234001180",
+            "A0B0A8670123456ABCDEF01021E",
+            "N01930617413C4A",
+            "V 1.67 CUL868",
+        ] {
+            let frame = parse(line);
+            assert_eq!(frame.encode(), format!("{}\r\n", line).as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_fs20_parsing() {
+        assert_eq!(
+            parse("F1234001180"),
+            CulFrame::Fs20 {
+                housecode: "1234".into(),
+                button: 0,
+                command: 0x11,
+                rssi: Some(-138.),
+            }
+        );
+    }
+
+    #[test]
+    fn test_homematic_parsing() {
+        assert_eq!(
+            parse("A0B0A8670123456ABCDEF01021E"),
+            CulFrame::Homematic {
+                counter: 0x0A,
+                flags: 0x86,
+                message_type: 0x70,
+                source: "123456".into(),
+                destination: "ABCDEF".into(),
+                payload: vec![0x01, 0x02],
+                rssi: Some(-59.),
+            }
+        );
+    }
+
+    #[test]
+    fn test_lacrosse_parsing() {
+        let frame = parse("N01930617413C4A");
+        assert_eq!(
+            frame,
+            CulFrame::LaCrosse {
+                id: 12,
+                new_battery: false,
+                weak_battery: false,
+                temperature: 21.7,
+                humidity: Some(65),
+                rssi: Some(-37.),
+            }
+        );
+        assert_eq!(
+            frame.to_lineprotocol().to_string(),
+            "tempHum,sensorId=12 temperature=21.7,humidity=65u"
+        );
+        let time = DateTime::from_timestamp(1700000000, 0).unwrap();
+        assert_eq!(
+            frame.sensor_status(time),
+            Some(SensorStatus {
+                sensor_id: "12".into(),
+                battery: Battery::Ok,
+                rssi: Some(-37.),
+                last_seen: time,
+            })
+        );
+        assert_eq!(parse("F12340011").sensor_status(time), None);
+    }
+
+    #[test]
+    fn test_lacrosse_invalid_humidity_is_dropped() {
+        let frame = |humidity| CulFrame::LaCrosse {
+            id: 12,
+            new_battery: false,
+            weak_battery: true,
+            temperature: 21.7,
+            humidity,
+            rssi: None,
+        };
+        for raw in [106, 125, 101] {
+            assert_eq!(
+                CulFrame::parse(frame(Some(raw)).encode()).unwrap(),
+                frame(None)
+            );
+        }
+        assert_eq!(
+            CulFrame::parse(frame(Some(100)).encode()).unwrap(),
+            frame(Some(100))
+        );
+    }
+
+    #[test]
+    fn test_lacrosse_with_bad_crc_is_rejected() {
+        assert!(CulFrame::parse(BytesMut::from(&b"N019306174100"[..])).is_err());
+    }
+
+    #[test]
+    fn test_unknown_lines_are_kept() {
+        assert_eq!(
+            parse("V 1.67 CUL868"),
+            CulFrame::Unknown("V 1.67 CUL868".into())
+        );
+        // The prefix is a character, not a byte
+        assert_eq!(parse("\u{e9}1234"), CulFrame::Unknown("\u{e9}1234".into()));
+    }
+}
@@ -0,0 +1,459 @@
+//! Davis Vantage Pro2 and Vantage Vue consoles or WeatherLink loggers on a serial line.
+//!
+//! The console is woken up by a line feed and then asked for a number of LOOP packets with
+//! `LOOP n`. It acknowledges with `0x06` and sends a 99 byte packet every two seconds. Each
+//! packet starts with `LOO` and ends with a CRC-CCITT over the whole packet. Once all requested
+//! packets are received, or if the console stays silent, the next batch is requested.
+//!
+//! Values are converted to metric units. Rain is counted in clicks of the standard 0.01"
+//! collector.
+#[cfg(not(target_arch = "wasm32"))]
+use crate::FramedListener;
+use crate::{
+    error::*,
+    input::{binary::Framing, checksum::crc16_xmodem},
+    measurement::{Measurement, ToMeasurement, Unit},
+    output::ToOutput,
+    schema::{FieldType, Schema},
+    EncodableFrame, Frame,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use async_trait::async_trait;
+use bytes::BytesMut;
+use std::fmt::{self, Display};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_serial::SerialStream;
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::{open_serial, Device, DeviceHealth};
+
+#[cfg(not(target_arch = "wasm32"))]
+const BAUD_RATE: u32 = 19200;
+
+const PACKET_LEN: usize = 99;
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Packets requested by a single `LOOP` command
+const LOOP_COUNT: u32 = 100;
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Time for the console to wake up after receiving a line feed
+const WAKE_UP_DELAY: Duration = Duration::from_millis(1200);
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Time without packets after which the console is woken up again
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Length of a rain click in mm
+const RAIN_CLICK_MM: f64 = 0.254;
+
+fn fahrenheit_to_celsius(f: f64) -> f64 {
+    (f - 32.) * 5. / 9.
+}
+
+fn mph_to_meters_per_second(mph: f64) -> f64 {
+    mph * 0.44704
+}
+
+/// Current conditions of a LOOP packet, `None` if the sensor is not present
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LoopPacket {
+    /// Barometric pressure reduced to sea level in hPa
+    pub barometer: Option<f64>,
+    /// Temperature in °C
+    pub inside_temperature: Option<f64>,
+    /// Relative humidity in %
+    pub inside_humidity: Option<u8>,
+    pub outside_temperature: Option<f64>,
+    pub outside_humidity: Option<u8>,
+    /// Wind speed in m/s
+    pub wind_speed: Option<f64>,
+    /// Average wind speed over 10 minutes in m/s
+    pub wind_speed_10min: Option<f64>,
+    /// Wind direction in degrees, 0 if calm
+    pub wind_direction: Option<u16>,
+    /// Rain rate in mm/h
+    pub rain_rate: f64,
+    /// Rain since midnight in mm
+    pub day_rain: f64,
+    pub uv_index: Option<f64>,
+    /// Solar radiation in W/m²
+    pub solar_radiation: Option<u16>,
+    /// Voltage of the console battery in V
+    pub console_battery: f64,
+}
+
+impl Frame for LoopPacket {
+    /// Returns the full packet, starting with `LOO`.
+    ///
+    /// Packets with invalid CRC are rejected with [FrameCheckError::ChecksumError].
+    fn check(buffer: &mut BytesMut) -> Result<BytesMut, FrameCheckError> {
+        // The CRC follows the packet, most significant byte first
+        const FRAMING: Framing = Framing::fixed(b"LOO", PACKET_LEN).checksum(|packet| {
+            let (data, crc) = packet.split_at(packet.len() - 2);
+            (
+                crc16_xmodem(data) as u32,
+                u16::from_be_bytes([crc[0], crc[1]]) as u32,
+            )
+        });
+        FRAMING.check(buffer)
+    }
+
+    fn parse(buffer: BytesMut) -> anyhow::Result<Self> {
+        if buffer.len() != PACKET_LEN {
+            Err(FrameValidation::WrongNumberOfFields(format!(
+                "{:02X?}",
+                &buffer[..]
+            )))?;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([buffer[i], buffer[i + 1]]);
+        let i16_at = |i: usize| i16::from_le_bytes([buffer[i], buffer[i + 1]]);
+        let temperature = |i: usize| {
+            let raw = i16_at(i);
+            (raw != i16::MAX).then(|| fahrenheit_to_celsius(raw as f64 / 10.))
+        };
+        let humidity = |i: usize| (buffer[i] != 0xff).then_some(buffer[i]);
+        let wind_speed = |raw: u8| (raw != 0xff).then(|| mph_to_meters_per_second(raw as f64));
+
+        Ok(LoopPacket {
+            barometer: match u16_at(7) {
+                0 => None,
+                raw => Some(raw as f64 / 1000. * 33.8639),
+            },
+            inside_temperature: temperature(9),
+            inside_humidity: humidity(11),
+            outside_temperature: temperature(12),
+            outside_humidity: humidity(33),
+            wind_speed: wind_speed(buffer[14]),
+            wind_speed_10min: wind_speed(buffer[15]),
+            wind_direction: match u16_at(16) {
+                0x7fff => None,
+                360 => Some(0),
+                raw => Some(raw),
+            },
+            rain_rate: u16_at(41) as f64 * RAIN_CLICK_MM,
+            uv_index: (buffer[43] != 0xff).then(|| buffer[43] as f64 / 10.),
+            solar_radiation: match u16_at(44) {
+                0x7fff => None,
+                raw => Some(raw),
+            },
+            day_rain: u16_at(50) as f64 * RAIN_CLICK_MM,
+            console_battery: u16_at(87) as f64 * 300. / 512. / 100.,
+        })
+    }
+}
+
+impl EncodableFrame for LoopPacket {
+    /// Encodes the readings into a LOOP packet, all other fields are zero
+    fn encode(&self) -> BytesMut {
+        let mut packet = [0u8; PACKET_LEN];
+        packet[..3].copy_from_slice(b"LOO");
+        let temperature = |celsius: Option<f64>| match celsius {
+            Some(c) => ((c * 9. / 5. + 32.) * 10.).round() as i16 as u16,
+            None => i16::MAX as u16,
+        };
+        let wind_speed = |speed: Option<f64>| match speed {
+            Some(speed) => (speed / 0.44704).round() as u8,
+            None => 0xff,
+        };
+        let words = [
+            (
+                7,
+                self.barometer
+                    .map_or(0, |hpa| (hpa / 33.8639 * 1000.).round() as u16),
+            ),
+            (9, temperature(self.inside_temperature)),
+            (12, temperature(self.outside_temperature)),
+            (16, self.wind_direction.unwrap_or(0x7fff)),
+            (41, (self.rain_rate / RAIN_CLICK_MM).round() as u16),
+            (44, self.solar_radiation.unwrap_or(0x7fff)),
+            (50, (self.day_rain / RAIN_CLICK_MM).round() as u16),
+            (
+                87,
+                (self.console_battery * 100. * 512. / 300.).round() as u16,
+            ),
+        ];
+        for (i, word) in words {
+            packet[i..i + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        packet[11] = self.inside_humidity.unwrap_or(0xff);
+        packet[33] = self.outside_humidity.unwrap_or(0xff);
+        packet[14] = wind_speed(self.wind_speed);
+        packet[15] = wind_speed(self.wind_speed_10min);
+        packet[43] = self.uv_index.map_or(0xff, |uv| (uv * 10.).round() as u8);
+        packet[95..97].copy_from_slice(b"\n\r");
+        let crc = crc16_xmodem(&packet[..PACKET_LEN - 2]);
+        packet[PACKET_LEN - 2..].copy_from_slice(&crc.to_be_bytes());
+        BytesMut::from(&packet[..])
+    }
+}
+
+/// Packet received from a station
+#[derive(Debug, Clone, PartialEq)]
+pub struct DavisReading {
+    pub station: String,
+    pub packet: LoopPacket,
+}
+
+impl ToOutput for DavisReading {}
+
+impl Display for DavisReading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Davis {}:", self.station)?;
+        if let Some(temperature) = self.packet.outside_temperature {
+            write!(f, " Temperature {:.1}", temperature)?;
+        }
+        if let Some(humidity) = self.packet.outside_humidity {
+            write!(f, " Humidity {}", humidity)?;
+        }
+        if let Some(barometer) = self.packet.barometer {
+            write!(f, " Pressure {:.1}", barometer)?;
+        }
+        Ok(())
+    }
+}
+
+impl ToMeasurement for DavisReading {
+    fn to_measurement(&self) -> Measurement {
+        let p = &self.packet;
+        let mut measurement = Measurement::new("weather").add_tag("station", &self.station);
+        let floats = [
+            ("barometer", p.barometer, Some(Unit::Hectopascal)),
+            (
+                "inside_temperature",
+                p.inside_temperature,
+                Some(Unit::Celsius),
+            ),
+            (
+                "outside_temperature",
+                p.outside_temperature,
+                Some(Unit::Celsius),
+            ),
+            ("wind_speed", p.wind_speed, Some(Unit::MetrePerSecond)),
+            (
+                "wind_speed_10min",
+                p.wind_speed_10min,
+                Some(Unit::MetrePerSecond),
+            ),
+            ("uv_index", p.uv_index, None),
+            (
+                "rain_rate",
+                Some(p.rain_rate),
+                Some(Unit::MillimetrePerHour),
+            ),
+            ("day_rain", Some(p.day_rain), Some(Unit::Millimetre)),
+            ("console_battery", Some(p.console_battery), Some(Unit::Volt)),
+        ];
+        for (name, value, unit) in floats {
+            if let Some(value) = value {
+                measurement = measurement.add_field(name, value);
+                if let Some(unit) = unit {
+                    measurement = measurement.add_field_meta(name, unit);
+                }
+            }
+        }
+        let integers = [
+            (
+                "inside_humidity",
+                p.inside_humidity.map(u64::from),
+                Unit::Percent,
+            ),
+            (
+                "outside_humidity",
+                p.outside_humidity.map(u64::from),
+                Unit::Percent,
+            ),
+            (
+                "wind_direction",
+                p.wind_direction.map(u64::from),
+                Unit::Degree,
+            ),
+            (
+                "solar_radiation",
+                p.solar_radiation.map(u64::from),
+                Unit::WattPerSquareMetre,
+            ),
+        ];
+        for (name, value, unit) in integers {
+            if let Some(value) = value {
+                measurement = measurement
+                    .add_field(name, value)
+                    .add_field_meta(name, unit);
+            }
+        }
+        measurement
+    }
+}
+
+/// Schema of the measurements of [DavisReading]
+pub fn schemas() -> Vec<Schema> {
+    let floats = [
+        ("barometer", Some(Unit::Hectopascal)),
+        ("inside_temperature", Some(Unit::Celsius)),
+        ("outside_temperature", Some(Unit::Celsius)),
+        ("wind_speed", Some(Unit::MetrePerSecond)),
+        ("wind_speed_10min", Some(Unit::MetrePerSecond)),
+        ("uv_index", None),
+        ("rain_rate", Some(Unit::MillimetrePerHour)),
+        ("day_rain", Some(Unit::Millimetre)),
+        ("console_battery", Some(Unit::Volt)),
+    ];
+    let integers = [
+        ("inside_humidity", Unit::Percent),
+        ("outside_humidity", Unit::Percent),
+        ("wind_direction", Unit::Degree),
+        ("solar_radiation", Unit::WattPerSquareMetre),
+    ];
+    let mut schema = Schema::new("weather").add_tag("station");
+    for (name, unit) in floats {
+        schema = schema.add_optional_field(name, FieldType::Float, unit);
+    }
+    for (name, unit) in integers {
+        schema = schema.add_optional_field(name, FieldType::UInteger, unit);
+    }
+    vec![schema]
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Vantage console streaming LOOP packets
+pub struct Davis {
+    reader: FramedListener<SerialStream, LoopPacket>,
+    path: String,
+    health: DeviceHealth,
+    /// Packets outstanding from the last `LOOP` command
+    remaining: u32,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Davis {
+    pub fn new<'a>(path: impl Into<std::borrow::Cow<'a, str>>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let port = open_serial(&path, BAUD_RATE)?;
+
+        Ok(Davis {
+            reader: FramedListener::new(port),
+            path: path.into_owned(),
+            health: DeviceHealth::Connected,
+            remaining: 0,
+        })
+    }
+
+    /// Wake up the console and request the next batch of packets
+    async fn request_packets(&mut self) -> anyhow::Result<()> {
+        self.reader.write_all(b"\n").await?;
+        tokio::time::sleep(WAKE_UP_DELAY).await;
+        self.reader
+            .write_all(format!("LOOP {}\n", LOOP_COUNT).as_bytes())
+            .await?;
+        self.remaining = LOOP_COUNT;
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl Device for Davis {
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        loop {
+            if self.remaining == 0 {
+                self.request_packets().await?;
+            }
+            let Ok(res) = tokio::time::timeout(SILENCE_TIMEOUT, self.reader.read_frame()).await
+            else {
+                self.remaining = 0;
+                continue;
+            };
+            self.health = DeviceHealth::after_read(&res);
+            self.remaining -= 1;
+            return match res? {
+                Some(packet) => Ok(Some(Box::new(DavisReading {
+                    station: self.path.clone(),
+                    packet,
+                }))),
+                None => Ok(None),
+            };
+        }
+    }
+
+    fn name(&self) -> &str {
+        "davis"
+    }
+
+    fn address(&self) -> &str {
+        &self.path
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{crc16_xmodem, LoopPacket, PACKET_LEN};
+    use crate::{error::FrameCheckError, Frame};
+    use bytes::BytesMut;
+
+    fn packet() -> Vec<u8> {
+        let mut packet = vec![0u8; PACKET_LEN];
+        packet[..3].copy_from_slice(b"LOO");
+        packet[7..9].copy_from_slice(&29921u16.to_le_bytes());
+        packet[9..11].copy_from_slice(&720i16.to_le_bytes());
+        packet[11] = 40;
+        packet[12..14].copy_from_slice(&i16::MAX.to_le_bytes());
+        packet[14] = 10;
+        packet[15] = 0xff;
+        packet[16..18].copy_from_slice(&360u16.to_le_bytes());
+        packet[33] = 0xff;
+        packet[41..43].copy_from_slice(&10u16.to_le_bytes());
+        packet[43] = 0xff;
+        packet[44..46].copy_from_slice(&0x7fffu16.to_le_bytes());
+        packet[50..52].copy_from_slice(&25u16.to_le_bytes());
+        packet[87..89].copy_from_slice(&768u16.to_le_bytes());
+        packet[95..97].copy_from_slice(b"\n\r");
+        let crc = crc16_xmodem(&packet[..97]);
+        packet[97..].copy_from_slice(&crc.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_parse_packet() {
+        // Wake-up response and acknowledgement precede the first packet
+        let mut buffer = BytesMut::from(&[&b"\n\r\x06"[..], &packet()].concat()[..]);
+        let packet = LoopPacket::parse(LoopPacket::check(&mut buffer).unwrap()).unwrap();
+        assert!(buffer.is_empty());
+
+        assert!((packet.barometer.unwrap() - 1013.25).abs() < 0.05);
+        assert!((packet.inside_temperature.unwrap() - 22.22).abs() < 0.01);
+        assert_eq!(packet.inside_humidity, Some(40));
+        assert_eq!(packet.outside_temperature, None);
+        assert_eq!(packet.outside_humidity, None);
+        assert!((packet.wind_speed.unwrap() - 4.4704).abs() < 1e-9);
+        assert_eq!(packet.wind_speed_10min, None);
+        assert_eq!(packet.wind_direction, Some(0));
+        assert!((packet.rain_rate - 2.54).abs() < 1e-9);
+        assert!((packet.day_rain - 6.35).abs() < 1e-9);
+        assert_eq!(packet.uv_index, None);
+        assert_eq!(packet.solar_radiation, None);
+        assert!((packet.console_battery - 4.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_check_rejects_bad_crc() {
+        let mut corrupt = packet();
+        corrupt[20] ^= 0x01;
+        let mut buffer = BytesMut::from(&[&corrupt[..], &packet()].concat()[..]);
+        assert!(matches!(
+            LoopPacket::check(&mut buffer),
+            Err(FrameCheckError::ChecksumError { .. })
+        ));
+        let data = LoopPacket::check(&mut buffer).unwrap();
+        assert_eq!(&data[..], &packet()[..]);
+
+        let mut partial = BytesMut::from(&packet()[..50]);
+        assert!(LoopPacket::check(&mut partial).is_err());
+        assert_eq!(partial.len(), 50);
+    }
+}
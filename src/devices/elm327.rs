@@ -0,0 +1,354 @@
+//! OBD-II diagnostics of vehicles through ELM327 compatible adapters, connected by USB serial or
+//! Bluetooth (e.g. `/dev/rfcomm0`).
+//!
+//! On startup the adapter is reset, echo and line feeds are disabled and the protocol is detected
+//! automatically. On every poll, the current value of each configured mode 01 PID is requested.
+//! The adapter terminates every response with the `>` prompt.
+#[cfg(not(target_arch = "wasm32"))]
+use crate::FramedListener;
+use crate::{
+    error::*,
+    input::delimiter::Delimiter,
+    measurement::{Measurement, ToMeasurement},
+    output::ToOutput,
+    schema::{FieldType, Schema},
+    EncodableFrame, Frame,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use async_trait::async_trait;
+use bytes::BytesMut;
+use serde::Deserialize;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_serial::SerialStream;
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::{
+    open_serial,
+    poll::{Poll, Polled},
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Baud rate of the device. Most clones ship configured for 38.4 KBd
+const BAUD_RATE: u32 = 38400;
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Commands sent on startup: reset, echo off, line feeds off, headers off, automatic protocol
+const INIT_COMMANDS: [&str; 5] = ["ATZ\r", "ATE0\r", "ATL0\r", "ATH0\r", "ATSP0\r"];
+
+/// Current data parameters of service 01, named by their field in pipeline files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "snake_case")]
+pub enum Pid {
+    /// Calculated engine load in %
+    EngineLoad,
+    /// Engine coolant temperature in °C
+    CoolantTemperature,
+    /// Engine speed in rpm
+    #[serde(rename = "rpm")]
+    EngineRpm,
+    /// Vehicle speed in km/h
+    #[serde(rename = "speed")]
+    VehicleSpeed,
+    /// Intake air temperature in °C
+    IntakeAirTemperature,
+    /// Throttle position in %
+    ThrottlePosition,
+}
+
+impl Pid {
+    pub const DEFAULT: [Pid; 3] = [Pid::EngineRpm, Pid::CoolantTemperature, Pid::VehicleSpeed];
+
+    pub const ALL: [Pid; 6] = [
+        Pid::EngineLoad,
+        Pid::CoolantTemperature,
+        Pid::EngineRpm,
+        Pid::VehicleSpeed,
+        Pid::IntakeAirTemperature,
+        Pid::ThrottlePosition,
+    ];
+
+    pub fn code(&self) -> u8 {
+        match self {
+            Pid::EngineLoad => 0x04,
+            Pid::CoolantTemperature => 0x05,
+            Pid::EngineRpm => 0x0c,
+            Pid::VehicleSpeed => 0x0d,
+            Pid::IntakeAirTemperature => 0x0f,
+            Pid::ThrottlePosition => 0x11,
+        }
+    }
+
+    /// Field name of the value in measurements
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pid::EngineLoad => "engine_load",
+            Pid::CoolantTemperature => "coolant_temperature",
+            Pid::EngineRpm => "rpm",
+            Pid::VehicleSpeed => "speed",
+            Pid::IntakeAirTemperature => "intake_air_temperature",
+            Pid::ThrottlePosition => "throttle_position",
+        }
+    }
+
+    /// Number of data bytes in the response
+    fn len(&self) -> usize {
+        match self {
+            Pid::EngineRpm => 2,
+            _ => 1,
+        }
+    }
+
+    /// Value of the data bytes following mode and PID in the response
+    fn decode(&self, data: &[u8]) -> f64 {
+        let a = data[0] as f64;
+        match self {
+            Pid::EngineLoad | Pid::ThrottlePosition => a * 100. / 255.,
+            Pid::CoolantTemperature | Pid::IntakeAirTemperature => a - 40.,
+            Pid::EngineRpm => (256. * a + data[1] as f64) / 4.,
+            Pid::VehicleSpeed => a,
+        }
+    }
+
+    /// Request of the current value
+    #[cfg(not(target_arch = "wasm32"))]
+    fn command(&self) -> String {
+        format!("01{:02X}\r", self.code())
+    }
+}
+
+impl FromStr for Pid {
+    type Err = anyhow::Error;
+
+    /// Parse a field name, e.g. `rpm`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Pid::EngineLoad,
+            Pid::CoolantTemperature,
+            Pid::EngineRpm,
+            Pid::VehicleSpeed,
+            Pid::IntakeAirTemperature,
+            Pid::ThrottlePosition,
+        ]
+        .into_iter()
+        .find(|pid| pid.name() == s)
+        .ok_or_else(|| anyhow::anyhow!("Unsupported PID {}", s))
+    }
+}
+
+/// Everything the adapter sent before its prompt
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElmResponse(pub Vec<String>);
+
+impl ElmResponse {
+    /// Value of `pid` if the response answers its request
+    ///
+    /// Adapters without support for disabling spaces or echo are handled by ignoring whitespace
+    /// and all lines not starting with the positive response to the request.
+    pub fn value(&self, pid: Pid) -> Option<f64> {
+        let prefix = [0x41, pid.code()];
+        self.0.iter().find_map(|line| {
+            let hex: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+            let bytes = decode_hex(&hex)?;
+            let data = bytes.strip_prefix(&prefix)?;
+            (data.len() >= pid.len()).then(|| pid.decode(data))
+        })
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl Frame for ElmResponse {
+    fn check(buffer: &mut BytesMut) -> Result<BytesMut, FrameCheckError> {
+        // Responses end with the prompt
+        const DELIMITER: Delimiter = Delimiter::end(b">");
+        DELIMITER.check(buffer)
+    }
+
+    fn parse(buffer: BytesMut) -> anyhow::Result<Self> {
+        let text = std::str::from_utf8(&buffer)?;
+        Ok(ElmResponse(
+            text.split(['\r', '\n'])
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect(),
+        ))
+    }
+}
+
+impl EncodableFrame for ElmResponse {
+    fn encode(&self) -> BytesMut {
+        let mut response = BytesMut::new();
+        for line in &self.0 {
+            response.extend_from_slice(line.as_bytes());
+            response.extend_from_slice(b"\r");
+        }
+        response.extend_from_slice(b"\r>");
+        response
+    }
+}
+
+/// Current value of a single PID
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObdReading {
+    pub adapter: String,
+    pub pid: Pid,
+    pub value: f64,
+}
+
+impl ToOutput for ObdReading {}
+
+impl Display for ObdReading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OBD {}: {}", self.pid.name(), self.value)
+    }
+}
+
+impl ToMeasurement for ObdReading {
+    fn to_measurement(&self) -> Measurement {
+        Measurement::new("obd")
+            .add_tag("adapter", &self.adapter)
+            .add_field(self.pid.name(), self.value)
+    }
+}
+
+/// Schema of the measurements of [ObdReading]
+pub fn schemas() -> Vec<Schema> {
+    let schema = Schema::new("obd").add_tag("adapter");
+    vec![Pid::ALL.iter().fold(schema, |schema, pid| {
+        schema.add_optional_field(pid.name(), FieldType::Float, None)
+    })]
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// ELM327 adapter queried for a fixed set of PIDs
+pub struct Elm327 {
+    reader: FramedListener<SerialStream, ElmResponse>,
+    path: String,
+    pids: Vec<Pid>,
+    initialized: bool,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Elm327 {
+    pub fn new<'a>(
+        path: impl Into<std::borrow::Cow<'a, str>>,
+        pids: Vec<Pid>,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+        let port = open_serial(&path, BAUD_RATE)?;
+
+        Ok(Elm327 {
+            reader: FramedListener::new(port),
+            path: path.into_owned(),
+            pids,
+            initialized: false,
+        })
+    }
+
+    /// Device querying the adapter on the given interval
+    pub fn polled(self, period: Duration) -> Polled<Elm327> {
+        Polled::new(self, period)
+    }
+
+    async fn request(&mut self, command: &str) -> anyhow::Result<ElmResponse> {
+        self.reader.write_all(command.as_bytes()).await?;
+        self.reader
+            .read_frame()
+            .await?
+            .ok_or_else(|| DeviceError::ConnectionLost.into())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl Poll for Elm327 {
+    type Frame = ObdReading;
+
+    /// Query all PIDs. PIDs not supported by the vehicle (`NO DATA`) are skipped.
+    async fn poll(&mut self) -> anyhow::Result<Vec<ObdReading>> {
+        if !self.initialized {
+            for command in INIT_COMMANDS {
+                self.request(command).await?;
+            }
+            self.initialized = true;
+        }
+        let mut readings = vec![];
+        for pid in self.pids.clone() {
+            let response = self.request(&pid.command()).await?;
+            if let Some(value) = response.value(pid) {
+                readings.push(ObdReading {
+                    adapter: self.path.clone(),
+                    pid,
+                    value,
+                });
+            }
+        }
+        Ok(readings)
+    }
+
+    fn name(&self) -> &str {
+        "elm327"
+    }
+
+    fn address(&self) -> &str {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ElmResponse, Pid};
+    use crate::Frame;
+    use bytes::BytesMut;
+
+    #[test]
+    fn test_check_splits_at_prompt() {
+        let mut buffer = BytesMut::from("SEARCHING...\r41 0C 1A F8\r\r>41 0D");
+        let response = ElmResponse::parse(ElmResponse::check(&mut buffer).unwrap()).unwrap();
+        assert_eq!(response.0, ["SEARCHING...", "41 0C 1A F8"]);
+        assert_eq!(&buffer[..], b"41 0D");
+        assert!(ElmResponse::check(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn test_decode_values() {
+        let response = |lines: &[&str]| ElmResponse(lines.iter().map(|l| l.to_string()).collect());
+        assert_eq!(
+            response(&["SEARCHING...", "41 0C 1A F8"]).value(Pid::EngineRpm),
+            Some(1726.)
+        );
+        assert_eq!(
+            response(&["010D", "410D32"]).value(Pid::VehicleSpeed),
+            Some(50.)
+        );
+        assert_eq!(
+            response(&["41 05 7B"]).value(Pid::CoolantTemperature),
+            Some(83.)
+        );
+        assert_eq!(response(&["NO DATA"]).value(Pid::EngineRpm), None);
+        // Answer to another PID or truncated
+        assert_eq!(response(&["41 0D 32"]).value(Pid::EngineRpm), None);
+        assert_eq!(response(&["41 0C 1A"]).value(Pid::EngineRpm), None);
+    }
+
+    #[test]
+    fn test_pid_from_name() {
+        assert_eq!("rpm".parse::<Pid>().unwrap(), Pid::EngineRpm);
+        assert!("boost".parse::<Pid>().is_err());
+        assert_eq!(Pid::CoolantTemperature.command(), "0105\r");
+    }
+}
@@ -0,0 +1,524 @@
+//! EnOcean gateways speaking the ESP3 serial protocol, e.g. the USB300.
+//!
+//! Only radio telegrams (ERP1) are decoded. The equipment profile (EEP) is derived from the
+//! telegram type: RPS telegrams are taken as rocker switches (F6-02-01), 1BS telegrams as
+//! contacts (D5-00-01) and 4BS telegrams as temperature sensors with a range of 0 to 40 °C
+//! (A5-02-05).
+use crate::{
+    error::*,
+    input::{binary::Framing, checksum::crc8},
+    measurement::{Measurement, ToMeasurement, Unit},
+    output::ToOutput,
+    schema::{FieldType, Schema},
+    EncodableFrame, Frame,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    input::{line::SerialSettings, FrameStats},
+    FramedListener,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use async_trait::async_trait;
+use bytes::BytesMut;
+use std::fmt::{self, Display};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_serial::SerialStream;
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::{open_serial_with, Device, DeviceHealth};
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Baud rate of the device. ESP3 uses 57.6 KBd
+const BAUD_RATE: u32 = 57600;
+
+const SYNC_BYTE: u8 = 0x55;
+/// Sync byte, 4 header bytes and the header CRC
+const HEADER_LEN: usize = 6;
+/// Polynomial of the CRC8 of header and data
+const CRC8_POLY: u8 = 0x07;
+const PACKET_TYPE_RADIO_ERP1: u8 = 0x01;
+
+const RORG_RPS: u8 = 0xF6;
+const RORG_1BS: u8 = 0xD5;
+const RORG_4BS: u8 = 0xA5;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub struct EnOcean {
+    reader: FramedListener<SerialStream, EnOceanFrame>,
+    path: String,
+    health: DeviceHealth,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl Device for EnOcean {
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        loop {
+            let res = self.reader.read_frame().await;
+            self.health = DeviceHealth::after_read(&res);
+            match res {
+                // Responses, events and teach-in telegrams carry no measurement
+                Ok(Some(EnOceanFrame::TeachIn { .. } | EnOceanFrame::Other { .. })) => continue,
+                Ok(Some(frame)) => return Ok(Some(Box::new(frame))),
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "enocean"
+    }
+
+    fn address(&self) -> &str {
+        &self.path
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
+
+    fn frame_stats(&self) -> Option<FrameStats> {
+        self.reader.stats().cloned()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl EnOcean {
+    pub fn new<'a>(path: impl Into<std::borrow::Cow<'a, str>>) -> anyhow::Result<Self> {
+        Self::with_serial(path, &SerialSettings::default())
+    }
+
+    /// Open the device with line settings other than 8N1
+    pub fn with_serial<'a>(
+        path: impl Into<std::borrow::Cow<'a, str>>,
+        serial: &SerialSettings,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+        let port = open_serial_with(&path, BAUD_RATE, serial)?;
+
+        Ok(EnOcean {
+            reader: FramedListener::new(port).with_auto_baud(
+                &serial.auto_baud,
+                Duration::from_secs(serial.probe_timeout),
+                &[],
+            ),
+            path: path.into_owned(),
+            health: DeviceHealth::Connected,
+        })
+    }
+
+    /// Count frame and garbage bytes, see [FramedListener::with_diagnostics]
+    pub fn with_diagnostics(mut self) -> Self {
+        self.reader = self.reader.with_diagnostics();
+        self
+    }
+}
+
+/// Packet received from an EnOcean gateway
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnOceanFrame {
+    /// Rocker switch (F6-02-01)
+    Rocker {
+        sender: u32,
+        button: u8,
+        pressed: bool,
+        dbm: Option<i16>,
+    },
+    /// Window or door contact (D5-00-01)
+    Contact {
+        sender: u32,
+        closed: bool,
+        dbm: Option<i16>,
+    },
+    /// Temperature sensor (A5-02-05)
+    Temperature {
+        sender: u32,
+        temperature: f64,
+        dbm: Option<i16>,
+    },
+    /// Teach-in telegram of a sensor
+    TeachIn { sender: u32, rorg: u8 },
+    /// Any other packet, e.g. responses and events of the gateway
+    Other { packet_type: u8 },
+}
+
+impl EnOceanFrame {
+    fn parse_erp1(data: &[u8], optional: &[u8]) -> Result<EnOceanFrame, FrameValidation> {
+        // RORG, at least one data byte, sender id and status
+        if data.len() < 7 {
+            return Err(FrameValidation::WrongNumberOfFields(format!(
+                "{:02X?}",
+                data
+            )));
+        }
+        let rorg = data[0];
+        let user_data = &data[1..data.len() - 5];
+        let sender = u32::from_be_bytes(
+            data[data.len() - 5..data.len() - 1]
+                .try_into()
+                .expect("slice of length 4"),
+        );
+        // Optional data: sub telegram number, destination id, dBm, security level
+        let dbm = optional.get(5).map(|dbm| -(*dbm as i16));
+
+        let frame = match (rorg, user_data) {
+            (RORG_RPS, [db0]) => EnOceanFrame::Rocker {
+                sender,
+                button: db0 >> 5,
+                pressed: db0 & 0x10 != 0,
+                dbm,
+            },
+            (RORG_1BS, [db0]) if db0 & 0x08 == 0 => EnOceanFrame::TeachIn { sender, rorg },
+            (RORG_1BS, [db0]) => EnOceanFrame::Contact {
+                sender,
+                closed: db0 & 0x01 != 0,
+                dbm,
+            },
+            (RORG_4BS, [_, _, _, db0]) if db0 & 0x08 == 0 => EnOceanFrame::TeachIn { sender, rorg },
+            (RORG_4BS, [_, _, db1, _]) => EnOceanFrame::Temperature {
+                sender,
+                temperature: (255 - db1) as f64 * 40. / 255.,
+                dbm,
+            },
+            _ => EnOceanFrame::Other {
+                packet_type: PACKET_TYPE_RADIO_ERP1,
+            },
+        };
+        Ok(frame)
+    }
+}
+
+impl Frame for EnOceanFrame {
+    /// Returns the full packet, starting with the sync byte.
+    ///
+    /// Packets with an invalid checksum of the data are rejected with
+    /// [FrameCheckError::ChecksumError].
+    fn check(buffer: &mut BytesMut) -> Result<BytesMut, FrameCheckError> {
+        const FRAMING: Framing = Framing::prefixed(&[SYNC_BYTE], HEADER_LEN, |header| {
+            // Not a sync byte, but part of data
+            if crc8(CRC8_POLY, &header[1..5]) != header[5] {
+                return None;
+            }
+            let data_len = u16::from_be_bytes([header[1], header[2]]) as usize;
+            let optional_len = header[3] as usize;
+            Some(HEADER_LEN + data_len + optional_len + 1)
+        })
+        .checksum(|packet| {
+            let (data, crc) = packet[HEADER_LEN..].split_at(packet.len() - HEADER_LEN - 1);
+            (crc8(CRC8_POLY, data) as u32, crc[0] as u32)
+        });
+        FRAMING.check(buffer)
+    }
+
+    fn parse(buffer: BytesMut) -> anyhow::Result<Self> {
+        let data_len = match buffer.get(1..3) {
+            Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+            None => 0,
+        };
+        if buffer.len() < HEADER_LEN + data_len + 1 {
+            Err(FrameValidation::WrongNumberOfFields(format!(
+                "{:02X?}",
+                &buffer[..]
+            )))?;
+        }
+        let packet_type = buffer[4];
+        let data = &buffer[HEADER_LEN..HEADER_LEN + data_len];
+        let optional = &buffer[HEADER_LEN + data_len..buffer.len() - 1];
+        match packet_type {
+            PACKET_TYPE_RADIO_ERP1 => Ok(Self::parse_erp1(data, optional)?),
+            packet_type => Ok(EnOceanFrame::Other { packet_type }),
+        }
+    }
+}
+
+impl EncodableFrame for EnOceanFrame {
+    fn encode(&self) -> BytesMut {
+        let (packet_type, rorg, user_data, sender, dbm) = match self {
+            EnOceanFrame::Rocker {
+                sender,
+                button,
+                pressed,
+                dbm,
+            } => {
+                let db0 = button << 5 | u8::from(*pressed) << 4;
+                (PACKET_TYPE_RADIO_ERP1, RORG_RPS, vec![db0], *sender, *dbm)
+            }
+            EnOceanFrame::Contact {
+                sender,
+                closed,
+                dbm,
+            } => {
+                let db0 = 0x08 | u8::from(*closed);
+                (PACKET_TYPE_RADIO_ERP1, RORG_1BS, vec![db0], *sender, *dbm)
+            }
+            EnOceanFrame::Temperature {
+                sender,
+                temperature,
+                dbm,
+            } => {
+                let db1 = 255 - (temperature * 255. / 40.).round() as u8;
+                let user_data = vec![0, 0, db1, 0x08];
+                (PACKET_TYPE_RADIO_ERP1, RORG_4BS, user_data, *sender, *dbm)
+            }
+            EnOceanFrame::TeachIn { sender, rorg } => {
+                let user_data = match *rorg {
+                    RORG_4BS => vec![0; 4],
+                    _ => vec![0],
+                };
+                (PACKET_TYPE_RADIO_ERP1, *rorg, user_data, *sender, None)
+            }
+            // Telegram of an unsupported RORG
+            EnOceanFrame::Other {
+                packet_type: PACKET_TYPE_RADIO_ERP1,
+            } => (PACKET_TYPE_RADIO_ERP1, 0, vec![0], 0, None),
+            EnOceanFrame::Other { packet_type } => (*packet_type, 0, Vec::new(), 0, None),
+        };
+        let mut data = Vec::new();
+        if packet_type == PACKET_TYPE_RADIO_ERP1 {
+            data.push(rorg);
+            data.extend(user_data);
+            data.extend(sender.to_be_bytes());
+            // Status
+            data.push(0);
+        }
+        let optional = match dbm {
+            // Sub telegram number, broadcast destination, dBm, security level
+            Some(dbm) => vec![1, 0xff, 0xff, 0xff, 0xff, -dbm as u8, 0],
+            None => Vec::new(),
+        };
+        let header = [
+            (data.len() >> 8) as u8,
+            data.len() as u8,
+            optional.len() as u8,
+            packet_type,
+        ];
+        let mut packet = BytesMut::from(&[SYNC_BYTE][..]);
+        packet.extend_from_slice(&header);
+        packet.extend_from_slice(&[crc8(CRC8_POLY, &header)]);
+        let start = packet.len();
+        packet.extend_from_slice(&data);
+        packet.extend_from_slice(&optional);
+        let crc = crc8(CRC8_POLY, &packet[start..]);
+        packet.extend_from_slice(&[crc]);
+        packet
+    }
+}
+
+impl ToOutput for EnOceanFrame {}
+
+impl Display for EnOceanFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnOceanFrame::Rocker {
+                sender,
+                button,
+                pressed,
+                ..
+            } => write!(
+                f,
+                "Rocker {:08X}: Button {}, pressed: {}",
+                sender, button, pressed
+            ),
+            EnOceanFrame::Contact { sender, closed, .. } => {
+                write!(f, "Contact {:08X}: closed: {}", sender, closed)
+            }
+            EnOceanFrame::Temperature {
+                sender,
+                temperature,
+                ..
+            } => write!(f, "Sensor {:08X}: Temperatur {:.1}", sender, temperature),
+            EnOceanFrame::TeachIn { sender, rorg } => {
+                write!(f, "Teach-in {:08X}: RORG {:02X}", sender, rorg)
+            }
+            EnOceanFrame::Other { packet_type } => write!(f, "Packet type {:02X}", packet_type),
+        }
+    }
+}
+
+fn add_dbm(measurement: Measurement, dbm: &Option<i16>) -> Measurement {
+    match dbm {
+        Some(dbm) => measurement.add_field("rssi", *dbm as i64),
+        None => measurement,
+    }
+}
+
+impl ToMeasurement for EnOceanFrame {
+    fn to_measurement(&self) -> Measurement {
+        match self {
+            EnOceanFrame::Rocker {
+                sender,
+                button,
+                pressed,
+                dbm,
+            } => add_dbm(
+                Measurement::new("rocker")
+                    .add_tag("senderId", format!("{:08X}", sender))
+                    .add_field("button", *button as u64)
+                    .add_field("pressed", *pressed),
+                dbm,
+            ),
+            EnOceanFrame::Contact {
+                sender,
+                closed,
+                dbm,
+            } => add_dbm(
+                Measurement::new("contact")
+                    .add_tag("senderId", format!("{:08X}", sender))
+                    .add_field("closed", *closed),
+                dbm,
+            ),
+            EnOceanFrame::Temperature {
+                sender,
+                temperature,
+                dbm,
+            } => add_dbm(
+                Measurement::new("temperature")
+                    .add_tag("senderId", format!("{:08X}", sender))
+                    .add_field("temperature", *temperature),
+                dbm,
+            ),
+            EnOceanFrame::TeachIn { sender, rorg } => Measurement::new("teachIn")
+                .add_tag("senderId", format!("{:08X}", sender))
+                .add_field("rorg", *rorg as u64),
+            EnOceanFrame::Other { packet_type } => {
+                Measurement::new("enocean").add_field("packetType", *packet_type as u64)
+            }
+        }
+    }
+}
+
+/// Schemas of the measurements of [EnOceanFrame]
+pub fn schemas() -> Vec<Schema> {
+    let rssi = |schema: Schema| schema.add_optional_field("rssi", FieldType::Integer, None);
+    vec![
+        rssi(
+            Schema::new("rocker")
+                .add_tag("senderId")
+                .add_field("button", FieldType::UInteger, None)
+                .add_field("pressed", FieldType::Boolean, None),
+        ),
+        rssi(Schema::new("contact").add_tag("senderId").add_field(
+            "closed",
+            FieldType::Boolean,
+            None,
+        )),
+        rssi(Schema::new("temperature").add_tag("senderId").add_field(
+            "temperature",
+            FieldType::Float,
+            Unit::Celsius,
+        )),
+        Schema::new("teachIn")
+            .add_tag("senderId")
+            .add_field("rorg", FieldType::UInteger, None),
+        Schema::new("enocean").add_field("packetType", FieldType::UInteger, None),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{crc8, EnOceanFrame, Frame, CRC8_POLY};
+    use crate::error::FrameCheckError;
+    use bytes::BytesMut;
+
+    fn packet(packet_type: u8, data: &[u8], optional: &[u8]) -> Vec<u8> {
+        let mut header = vec![0x55];
+        header.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        header.push(optional.len() as u8);
+        header.push(packet_type);
+        header.push(crc8(CRC8_POLY, &header[1..5]));
+        let mut body = data.to_vec();
+        body.extend_from_slice(optional);
+        let crc = crc8(CRC8_POLY, &body);
+        header.extend(body);
+        header.push(crc);
+        header
+    }
+
+    fn radio(data: &[u8]) -> Vec<u8> {
+        packet(0x01, data, &[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0x45, 0x00])
+    }
+
+    fn check_and_parse(bytes: &[u8]) -> EnOceanFrame {
+        let mut buf = BytesMut::from(bytes);
+        EnOceanFrame::parse(EnOceanFrame::check(&mut buf).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_check_rejects_garbage_and_bad_crc() {
+        let mut bytes = vec![0x00, 0x55, 0x12];
+        let mut corrupt = radio(&[0xF6, 0x30, 0x01, 0x02, 0x03, 0x04, 0x30]);
+        *corrupt.last_mut().unwrap() ^= 0xFF;
+        bytes.extend(corrupt);
+        let valid = radio(&[0xF6, 0x50, 0x01, 0x02, 0x03, 0x04, 0x30]);
+        bytes.extend(&valid);
+
+        let mut buf = BytesMut::from(&bytes[..]);
+        assert!(matches!(
+            EnOceanFrame::check(&mut buf),
+            Err(FrameCheckError::ChecksumError { .. })
+        ));
+        assert_eq!(
+            EnOceanFrame::check(&mut buf),
+            Ok(BytesMut::from(&valid[..]))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_check_detects_incomplete_packet() {
+        let valid = radio(&[0xF6, 0x50, 0x01, 0x02, 0x03, 0x04, 0x30]);
+        let mut buf = BytesMut::from(&valid[..valid.len() - 1]);
+        assert!(EnOceanFrame::check(&mut buf).is_err());
+        assert_eq!(buf.len(), valid.len() - 1);
+    }
+
+    #[test]
+    fn test_rocker_parsing() {
+        assert_eq!(
+            check_and_parse(&radio(&[0xF6, 0x50, 0x01, 0x02, 0x03, 0x04, 0x30])),
+            EnOceanFrame::Rocker {
+                sender: 0x01020304,
+                button: 2,
+                pressed: true,
+                dbm: Some(-69),
+            }
+        );
+    }
+
+    #[test]
+    fn test_contact_parsing() {
+        assert_eq!(
+            check_and_parse(&radio(&[0xD5, 0x09, 0x01, 0x02, 0x03, 0x04, 0x00])),
+            EnOceanFrame::Contact {
+                sender: 0x01020304,
+                closed: true,
+                dbm: Some(-69),
+            }
+        );
+        assert_eq!(
+            check_and_parse(&radio(&[0xD5, 0x00, 0x01, 0x02, 0x03, 0x04, 0x00])),
+            EnOceanFrame::TeachIn {
+                sender: 0x01020304,
+                rorg: 0xD5,
+            }
+        );
+    }
+
+    #[test]
+    fn test_temperature_parsing() {
+        let frame = check_and_parse(&radio(&[
+            0xA5, 0x00, 0x00, 0x00, 0x08, 0x01, 0x02, 0x03, 0x04, 0x00,
+        ]));
+        assert_eq!(
+            frame,
+            EnOceanFrame::Temperature {
+                sender: 0x01020304,
+                temperature: 40.,
+                dbm: Some(-69),
+            }
+        );
+    }
+}
@@ -0,0 +1,307 @@
+//! Pulse counter on a GPIO line, e.g. for the S0 interface of energy meters, water meters or
+//! tipping bucket rain gauges.
+//!
+//! Edges are read from the Linux GPIO character device on a dedicated thread. Pulses closer to
+//! their predecessor than the debounce time are ignored. Once per interval, the total count and
+//! the rate since the last interval are emitted, both converted to the unit of the meter using
+//! its pulses per unit, e.g. 1000 impulses per kWh.
+use crate::{
+    measurement::{Measurement, ToMeasurement},
+    output::ToOutput,
+    schema::{FieldType, Schema},
+};
+use async_trait::async_trait;
+use gpio_cdev::{Chip, EventRequestFlags, LineRequestFlags};
+use serde::Deserialize;
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+use super::{Device, DeviceHealth};
+
+/// Edge of the signal counted as a pulse
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+/// Where the meter is attached and how its pulses are converted
+#[derive(Debug, Clone, PartialEq)]
+pub struct PulseCounterConfig {
+    /// GPIO character device, e.g. `/dev/gpiochip0`
+    pub chip: PathBuf,
+    /// Offset of the line on the chip
+    pub line: u32,
+    pub edge: Edge,
+    /// Minimum time between two pulses
+    pub debounce: Duration,
+    /// Pulses per unit of the meter, e.g. 1000 for 1000 imp/kWh
+    pub pulses_per_unit: f64,
+    /// Time between two emitted readings
+    pub interval: Duration,
+}
+
+impl PulseCounterConfig {
+    /// Counter of falling edges, as pulled low by S0 outputs, debounced by 20 ms
+    pub fn new(
+        chip: impl Into<PathBuf>,
+        line: u32,
+        pulses_per_unit: f64,
+        interval: Duration,
+    ) -> PulseCounterConfig {
+        PulseCounterConfig {
+            chip: chip.into(),
+            line,
+            edge: Edge::Falling,
+            debounce: Duration::from_millis(20),
+            pulses_per_unit,
+            interval,
+        }
+    }
+}
+
+/// Drops edges following their predecessor within the debounce time
+#[derive(Debug)]
+pub struct Debounce {
+    min_gap_ns: u64,
+    last: Option<u64>,
+}
+
+impl Debounce {
+    pub fn new(debounce: Duration) -> Debounce {
+        Debounce {
+            min_gap_ns: debounce.as_nanos() as u64,
+            last: None,
+        }
+    }
+
+    /// Whether the edge at `timestamp` in nanoseconds is a new pulse
+    pub fn accept(&mut self, timestamp: u64) -> bool {
+        match self.last {
+            Some(last) if timestamp.saturating_sub(last) < self.min_gap_ns => false,
+            _ => {
+                self.last = Some(timestamp);
+                true
+            }
+        }
+    }
+}
+
+/// Pulses counted on a line
+#[derive(Debug, Clone, PartialEq)]
+pub struct PulseReading {
+    /// Chip and line, e.g. `/dev/gpiochip0:17`
+    pub line: String,
+    /// Pulses since start
+    pub count: u64,
+    /// Units since start
+    pub total: f64,
+    /// Units per hour during the last interval, e.g. kW for a meter counting kWh
+    pub rate: f64,
+}
+
+impl ToOutput for PulseReading {}
+
+impl Display for PulseReading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Line {}: {} pulses, total {:.3}, rate {:.3}/h",
+            self.line, self.count, self.total, self.rate
+        )
+    }
+}
+
+impl ToMeasurement for PulseReading {
+    fn to_measurement(&self) -> Measurement {
+        Measurement::new("pulses")
+            .add_tag("line", &self.line)
+            .add_field("count", self.count)
+            .add_field("total", self.total)
+            .add_field("rate", self.rate)
+    }
+}
+
+/// Schema of the measurements of [PulseReading]
+pub fn schemas() -> Vec<Schema> {
+    vec![Schema::new("pulses")
+        .add_tag("line")
+        .add_field("count", FieldType::UInteger, None)
+        .add_field("total", FieldType::Float, None)
+        .add_field("rate", FieldType::Float, None)]
+}
+
+/// Device emitting the count of a GPIO line once per interval
+pub struct PulseCounter {
+    address: String,
+    count: Arc<AtomicU64>,
+    error: Arc<Mutex<Option<String>>>,
+    pulses_per_unit: f64,
+    interval: Interval,
+    last: Option<(Instant, u64)>,
+    health: DeviceHealth,
+}
+
+impl PulseCounter {
+    /// Request the line and start counting
+    ///
+    /// The counting thread blocks on the line until the process exits.
+    pub fn new(config: &PulseCounterConfig) -> anyhow::Result<PulseCounter> {
+        let mut chip = Chip::new(&config.chip)?;
+        let flags = match config.edge {
+            Edge::Rising => EventRequestFlags::RISING_EDGE,
+            Edge::Falling => EventRequestFlags::FALLING_EDGE,
+        };
+        let events =
+            chip.get_line(config.line)?
+                .events(LineRequestFlags::INPUT, flags, "sensorflow")?;
+
+        let count = Arc::new(AtomicU64::new(0));
+        let error = Arc::new(Mutex::new(None));
+        let mut debounce = Debounce::new(config.debounce);
+        {
+            let count = count.clone();
+            let error = error.clone();
+            std::thread::Builder::new()
+                .name("gpio-pulses".into())
+                .spawn(move || {
+                    for event in events {
+                        match event {
+                            Ok(event) if debounce.accept(event.timestamp()) => {
+                                count.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Ok(_) => (),
+                            Err(e) => {
+                                *error.lock().expect("error lock poisoned") = Some(e.to_string());
+                                return;
+                            }
+                        }
+                    }
+                })?;
+        }
+
+        Ok(PulseCounter::with_counter(
+            format!("{}:{}", config.chip.display(), config.line),
+            count,
+            error,
+            config.pulses_per_unit,
+            config.interval,
+        ))
+    }
+
+    fn with_counter(
+        address: String,
+        count: Arc<AtomicU64>,
+        error: Arc<Mutex<Option<String>>>,
+        pulses_per_unit: f64,
+        period: Duration,
+    ) -> PulseCounter {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        PulseCounter {
+            address,
+            count,
+            error,
+            pulses_per_unit,
+            interval,
+            last: None,
+            health: DeviceHealth::Unknown,
+        }
+    }
+}
+
+#[async_trait]
+impl Device for PulseCounter {
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        loop {
+            self.interval.tick().await;
+            if let Some(e) = self.error.lock().expect("error lock poisoned").clone() {
+                self.health = DeviceHealth::Failed(e.clone());
+                return Err(anyhow::anyhow!(e));
+            }
+            let now = Instant::now();
+            let count = self.count.load(Ordering::Relaxed);
+            // The first tick only marks the start of the first interval
+            let Some((last_time, last_count)) = self.last.replace((now, count)) else {
+                continue;
+            };
+            let hours = now.duration_since(last_time).as_secs_f64() / 3600.;
+            let units = (count - last_count) as f64 / self.pulses_per_unit;
+            self.health = DeviceHealth::Connected;
+            return Ok(Some(Box::new(PulseReading {
+                line: self.address.clone(),
+                count,
+                total: count as f64 / self.pulses_per_unit,
+                rate: if hours > 0. { units / hours } else { 0. },
+            })));
+        }
+    }
+
+    fn name(&self) -> &str {
+        "gpio"
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Debounce, PulseCounter};
+    use crate::{devices::Device, measurement::FieldValue};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_debounce_drops_bouncing_edges() {
+        let mut debounce = Debounce::new(Duration::from_millis(20));
+        let ms = 1_000_000;
+        let accepted: Vec<_> = [0, 5 * ms, 19 * ms, 20 * ms, 100 * ms]
+            .into_iter()
+            .filter(|t| debounce.accept(*t))
+            .collect();
+        assert_eq!(accepted, [0, 20 * ms, 100 * ms]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_over_interval() {
+        let count = Arc::new(AtomicU64::new(0));
+        let mut counter = PulseCounter::with_counter(
+            "chip:1".into(),
+            count.clone(),
+            Default::default(),
+            1000.,
+            Duration::from_secs(60),
+        );
+        let reading = tokio::spawn(async move {
+            counter
+                .read_frame()
+                .await
+                .unwrap()
+                .unwrap()
+                .to_measurement()
+        });
+        tokio::task::yield_now().await;
+        // 50 Wh within one minute is 3 kW
+        count.store(50, Ordering::Relaxed);
+        let measurement = reading.await.unwrap();
+        assert_eq!(measurement.field("count").unwrap().to_string(), "50");
+        assert_eq!(measurement.field("total").unwrap().to_string(), "0.05");
+        let Some(FieldValue::Float(rate)) = measurement.field("rate") else {
+            panic!("rate missing")
+        };
+        assert!((rate - 3.).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,245 @@
+//! Generic poller for JSON over HTTP, e.g. the local APIs of Shelly or Tasmota devices and
+//! Fronius inverters.
+//!
+//! The URL is requested on every poll and each configured field is extracted from the response
+//! by a JSON pointer (RFC 6901), e.g. `/meters/0/power`. Fields missing from a response are
+//! skipped.
+use crate::{
+    measurement::{FieldValue, Measurement, ToMeasurement},
+    output::ToOutput,
+    tls::TlsConfig,
+};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use super::poll::{Poll, Polled};
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn default_measurement() -> String {
+    "http".into()
+}
+
+/// Request and mapping of a response to a measurement
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct HttpPollConfig {
+    pub url: String,
+    /// Seconds between two requests
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Seconds to wait for a response
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Name of the emitted measurement
+    #[serde(default = "default_measurement")]
+    pub measurement: String,
+    /// Static tags added to the measurement
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Field names and the JSON pointers of their values
+    pub fields: BTreeMap<String, String>,
+    /// TLS settings of `https` URLs, see [crate::tls]
+    pub tls: Option<TlsConfig>,
+}
+
+impl HttpPollConfig {
+    /// Poll the URL on the configured interval
+    pub fn polled(self) -> anyhow::Result<Polled<HttpPoller>> {
+        let period = Duration::from_secs(self.interval);
+        Ok(Polled::new(HttpPoller::new(self)?, period))
+    }
+}
+
+/// Convert a JSON value to a field value, `None` for null, arrays and objects
+pub fn field_value(value: &Value) -> Option<FieldValue> {
+    match value {
+        Value::Bool(b) => Some(FieldValue::Boolean(*b)),
+        Value::Number(n) => n
+            .as_i64()
+            .map(FieldValue::Integer)
+            .or_else(|| n.as_u64().map(FieldValue::UInteger))
+            .or_else(|| n.as_f64().map(FieldValue::Float)),
+        Value::String(s) => Some(FieldValue::String(s.clone())),
+        Value::Null | Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+/// Values extracted from a single response
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpReading {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub values: Vec<(String, FieldValue)>,
+}
+
+impl HttpReading {
+    /// Extract the configured fields from a response body
+    pub fn extract(config: &HttpPollConfig, body: &[u8]) -> anyhow::Result<HttpReading> {
+        let document: Value = serde_json::from_slice(body)?;
+        Ok(HttpReading {
+            measurement: config.measurement.clone(),
+            tags: config
+                .tags
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            values: config
+                .fields
+                .iter()
+                .filter_map(|(field, pointer)| {
+                    Some((field.clone(), field_value(document.pointer(pointer)?)?))
+                })
+                .collect(),
+        })
+    }
+}
+
+impl ToOutput for HttpReading {}
+
+impl Display for HttpReading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.measurement)?;
+        for (field, value) in &self.values {
+            write!(f, " {}={}", field, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl ToMeasurement for HttpReading {
+    fn to_measurement(&self) -> Measurement {
+        let mut measurement = Measurement::new(&self.measurement);
+        for (key, value) in &self.tags {
+            measurement = measurement.add_tag(key, value);
+        }
+        for (field, value) in &self.values {
+            measurement = measurement.add_field(field, value.clone());
+        }
+        measurement
+    }
+}
+
+/// JSON endpoint polled for a set of fields
+pub struct HttpPoller {
+    config: HttpPollConfig,
+    client: reqwest::Client,
+}
+
+impl HttpPoller {
+    pub fn new(config: HttpPollConfig) -> anyhow::Result<HttpPoller> {
+        let mut client = reqwest::Client::builder().timeout(Duration::from_secs(config.timeout));
+        if let Some(tls) = &config.tls {
+            client = tls.http_client(client)?;
+        }
+        let client = client.build()?;
+        Ok(HttpPoller { config, client })
+    }
+}
+
+#[async_trait]
+impl Poll for HttpPoller {
+    type Frame = HttpReading;
+
+    async fn poll(&mut self) -> anyhow::Result<Vec<HttpReading>> {
+        let body = self
+            .client
+            .get(&self.config.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let reading = HttpReading::extract(&self.config, &body)?;
+        if reading.values.is_empty() {
+            return Ok(vec![]);
+        }
+        Ok(vec![reading])
+    }
+
+    fn name(&self) -> &str {
+        "http"
+    }
+
+    fn address(&self) -> &str {
+        &self.config.url
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HttpPollConfig, HttpPoller, HttpReading};
+    use crate::{devices::poll::Poll, measurement::FieldValue};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const STATUS: &str = r#"{"meters":[{"power":123.4,"total":50001}],"temperature":21,
+        "update":{"has_update":false},"name":null}"#;
+
+    fn config(url: &str) -> HttpPollConfig {
+        toml::from_str(&format!(
+            r#"
+            url = "{}"
+            measurement = "shelly"
+            tags = {{ room = "kitchen" }}
+            [fields]
+            power = "/meters/0/power"
+            total = "/meters/0/total"
+            temperature = "/temperature"
+            update = "/update/has_update"
+            name = "/name"
+            missing = "/meters/1/power"
+            "#,
+            url
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_extract_fields() {
+        let reading = HttpReading::extract(&config("http://shelly"), STATUS.as_bytes()).unwrap();
+        assert_eq!(reading.tags, [("room".into(), "kitchen".into())]);
+        assert_eq!(
+            reading.values,
+            [
+                ("power".into(), FieldValue::Float(123.4)),
+                ("temperature".into(), FieldValue::Integer(21)),
+                ("total".into(), FieldValue::Integer(50001)),
+                ("update".into(), FieldValue::Boolean(false)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_poll_local_server() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/status", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                STATUS.len(),
+                STATUS
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let mut poller = HttpPoller::new(config(&url)).unwrap();
+        let readings = poller.poll().await.unwrap();
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].values.len(), 4);
+    }
+}
@@ -0,0 +1,261 @@
+//! Host health sensors exposed by the Linux hwmon subsystem, as used by lm-sensors.
+//!
+//! Every chip below `/sys/class/hwmon` is scanned for temperatures, fan speeds and voltages.
+//! Sensors are named by their label, if the driver provides one, or by their channel otherwise.
+use crate::{
+    measurement::{Measurement, ToMeasurement, Unit},
+    output::ToOutput,
+    schema::{FieldType, Schema},
+};
+use async_trait::async_trait;
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+
+use super::poll::Poll;
+
+/// Directory listing all hwmon chips
+pub const SYSFS_PATH: &str = "/sys/class/hwmon";
+
+/// Physical quantity of a hwmon channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorKind {
+    /// `temp*_input` in m°C
+    Temperature,
+    /// `fan*_input` in RPM
+    Fan,
+    /// `in*_input` in mV
+    Voltage,
+}
+
+impl SensorKind {
+    /// Prefix of the sysfs attribute
+    fn prefix(&self) -> &'static str {
+        match self {
+            SensorKind::Temperature => "temp",
+            SensorKind::Fan => "fan",
+            SensorKind::Voltage => "in",
+        }
+    }
+
+    /// Convert the raw sysfs value to °C, RPM or V respectively
+    fn scale(&self, raw: i64) -> f64 {
+        match self {
+            SensorKind::Temperature | SensorKind::Voltage => raw as f64 / 1000.,
+            SensorKind::Fan => raw as f64,
+        }
+    }
+
+    fn unit(&self) -> Unit {
+        match self {
+            SensorKind::Temperature => Unit::Celsius,
+            SensorKind::Fan => Unit::Rpm,
+            SensorKind::Voltage => Unit::Volt,
+        }
+    }
+
+    fn field_name(&self) -> &'static str {
+        match self {
+            SensorKind::Temperature => "temperature",
+            SensorKind::Fan => "fan_speed",
+            SensorKind::Voltage => "voltage",
+        }
+    }
+}
+
+/// Single channel of a hwmon chip
+#[derive(Debug, Clone, PartialEq)]
+pub struct HwmonReading {
+    /// Name of the chip driver, e.g. `coretemp`
+    pub chip: String,
+    /// Label of the channel, e.g. `Package id 0`, or the channel, e.g. `temp1`
+    pub sensor: String,
+    pub kind: SensorKind,
+    pub value: f64,
+}
+
+impl ToOutput for HwmonReading {}
+
+impl Display for HwmonReading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {} {}",
+            self.chip,
+            self.sensor,
+            self.kind.field_name(),
+            self.value
+        )
+    }
+}
+
+impl ToMeasurement for HwmonReading {
+    fn to_measurement(&self) -> Measurement {
+        Measurement::new("hwmon")
+            .add_tag("chip", &self.chip)
+            .add_tag("sensor", &self.sensor)
+            .add_field(self.kind.field_name(), self.value)
+            .add_field_meta(self.kind.field_name(), self.kind.unit())
+    }
+}
+
+/// Schema of the measurements of [HwmonReading], with one of the fields
+pub fn schemas() -> Vec<Schema> {
+    let kinds = [
+        SensorKind::Temperature,
+        SensorKind::Fan,
+        SensorKind::Voltage,
+    ];
+    let schema = Schema::new("hwmon").add_tag("chip").add_tag("sensor");
+    vec![kinds.iter().fold(schema, |schema, kind| {
+        schema.add_optional_field(kind.field_name(), FieldType::Float, kind.unit())
+    })]
+}
+
+/// All chips found below the hwmon sysfs directory
+pub struct Hwmon {
+    path: PathBuf,
+    address: String,
+}
+
+impl Hwmon {
+    pub fn new(path: impl Into<PathBuf>) -> Hwmon {
+        let path = path.into();
+        Hwmon {
+            address: path.display().to_string(),
+            path,
+        }
+    }
+}
+
+async fn read_trimmed(path: &Path) -> Option<String> {
+    tokio::fs::read_to_string(path)
+        .await
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+/// Read all channels of a single chip directory
+async fn read_chip(dir: &Path) -> anyhow::Result<Vec<HwmonReading>> {
+    let chip = match read_trimmed(&dir.join("name")).await {
+        Some(name) => name,
+        None => dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+
+    let mut attributes = vec![];
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        attributes.push(entry.file_name().to_string_lossy().into_owned());
+    }
+    attributes.sort();
+
+    let mut readings = vec![];
+    for attribute in attributes {
+        let Some(channel) = attribute.strip_suffix("_input") else {
+            continue;
+        };
+        let kind = [
+            SensorKind::Temperature,
+            SensorKind::Fan,
+            SensorKind::Voltage,
+        ]
+        .into_iter()
+        .find(|kind| {
+            channel
+                .strip_prefix(kind.prefix())
+                .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+        });
+        let Some(kind) = kind else {
+            continue;
+        };
+        // Channels of unplugged sensors fail to read
+        let Some(raw) = read_trimmed(&dir.join(&attribute))
+            .await
+            .and_then(|raw| raw.parse::<i64>().ok())
+        else {
+            continue;
+        };
+        let sensor = read_trimmed(&dir.join(format!("{}_label", channel)))
+            .await
+            .unwrap_or_else(|| channel.to_string());
+        readings.push(HwmonReading {
+            chip: chip.clone(),
+            sensor,
+            kind,
+            value: kind.scale(raw),
+        });
+    }
+    Ok(readings)
+}
+
+#[async_trait]
+impl Poll for Hwmon {
+    type Frame = HwmonReading;
+
+    async fn poll(&mut self) -> anyhow::Result<Vec<HwmonReading>> {
+        let mut chips = vec![];
+        let mut entries = tokio::fs::read_dir(&self.path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            chips.push(entry.path());
+        }
+        chips.sort();
+
+        let mut readings = vec![];
+        for chip in chips {
+            readings.extend(read_chip(&chip).await?);
+        }
+        Ok(readings)
+    }
+
+    fn name(&self) -> &str {
+        "hwmon"
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Hwmon, HwmonReading, SensorKind};
+    use crate::devices::poll::Poll;
+
+    #[tokio::test]
+    async fn test_poll_reads_labeled_and_unlabeled_channels() {
+        let dir = std::env::temp_dir().join(format!("sensorflow-hwmon-{}", std::process::id()));
+        let chip = dir.join("hwmon0");
+        std::fs::create_dir_all(&chip).unwrap();
+        for (file, content) in [
+            ("name", "coretemp\n"),
+            ("temp1_input", "45000\n"),
+            ("temp1_label", "Package id 0\n"),
+            ("temp1_max", "100000\n"),
+            ("fan2_input", "1200\n"),
+            ("in0_input", "1234\n"),
+            ("power1_input", "5000000\n"),
+        ] {
+            std::fs::write(chip.join(file), content).unwrap();
+        }
+
+        let readings = Hwmon::new(&dir).poll().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let reading = |sensor: &str, kind, value| HwmonReading {
+            chip: "coretemp".into(),
+            sensor: sensor.into(),
+            kind,
+            value,
+        };
+        assert_eq!(
+            readings,
+            [
+                reading("fan2", SensorKind::Fan, 1200.),
+                reading("in0", SensorKind::Voltage, 1.234),
+                reading("Package id 0", SensorKind::Temperature, 45.),
+            ]
+        );
+    }
+}
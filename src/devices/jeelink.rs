@@ -1,68 +1,340 @@
 use crate::{
     error::*,
-    output::influx::{LineProtocol, ToLineProtocol},
+    input::delimiter::Delimiter,
+    input::line::SerialSettings,
+    measurement::{Measurement, ToMeasurement, Unit},
     output::ToOutput,
-    Frame, FramedListener,
+    schema::{FieldType, Schema},
+    EncodableFrame, Frame,
 };
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{input::FrameStats, FramedListener};
+#[cfg(not(target_arch = "wasm32"))]
 use async_trait::async_trait;
-use bytes::{Buf, BytesMut};
-use chrono::Utc;
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
 use std::fmt::{self, Display};
-use tokio_serial::{SerialPortBuilderExt, SerialStream};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_serial::SerialStream;
 
-use super::Device;
+#[cfg(not(target_arch = "wasm32"))]
+use super::status::StatusFilter;
+use super::status::{Battery, SensorStatus, ToSensorStatus};
+#[cfg(not(target_arch = "wasm32"))]
+use super::{open_serial, open_serial_with, Device, DeviceHealth};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::clock::{Clock, SystemClock};
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
 
+#[cfg(not(target_arch = "wasm32"))]
 /// Baud rate of the device. For the JeeLink it is 57.6 KBd
 const BAUD_RATE: u32 = 57600;
 
+/// Settings of the JeeLink firmware applied on startup
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JeeLinkConfig {
+    /// Command making the firmware append the RSSI to every frame, e.g. for antenna debugging.
+    ///
+    /// The command differs between builds of the LaCrosseITPlusReader firmware, see the help
+    /// output of the firmware (`h`). If not set, the RSSI is reported only if already enabled.
+    pub rssi_command: Option<String>,
+    /// Line settings of the port
+    pub serial: SerialSettings,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
 pub struct JeeLink {
     reader: FramedListener<SerialStream, JeeLinkFrame>,
+    path: String,
+    health: DeviceHealth,
+    init_commands: Vec<String>,
+    /// Status of the sensor of the last frame, returned by the next read
+    status: Option<SensorStatus>,
+    statuses: StatusFilter,
+    clock: Arc<dyn Clock>,
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 #[async_trait]
 impl Device for JeeLink {
     async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
-        match self.reader.read_frame().await {
-            Ok(Some(frame)) => Ok(Some(Box::new(frame))),
+        for command in self.init_commands.drain(..) {
+            self.reader.write_all(command.as_bytes()).await?;
+        }
+        if let Some(status) = self.status.take() {
+            return Ok(Some(Box::new(status)));
+        }
+        let res = self.reader.read_frame().await;
+        self.health = DeviceHealth::after_read(&res);
+        match res {
+            Ok(Some(frame)) => {
+                self.status = frame
+                    .sensor_status(self.clock.now())
+                    .and_then(|status| self.statuses.update(status));
+                Ok(Some(Box::new(frame)))
+            }
             Ok(None) => Ok(None),
             Err(e) => Err(e),
         }
     }
+
+    fn name(&self) -> &str {
+        "jeelink"
+    }
+
+    fn address(&self) -> &str {
+        &self.path
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
+
+    fn frame_stats(&self) -> Option<FrameStats> {
+        self.reader.stats().cloned()
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl JeeLink {
     pub fn new<'a>(path: impl Into<std::borrow::Cow<'a, str>>) -> anyhow::Result<Self> {
-        let mut port = tokio_serial::new(path, BAUD_RATE).open_native_async()?;
+        Self::with_config(path, JeeLinkConfig::default())
+    }
 
-        #[cfg(unix)]
-        port.set_exclusive(false)?;
+    /// Open the device and apply the given firmware settings before the first read.
+    pub fn with_config<'a>(
+        path: impl Into<std::borrow::Cow<'a, str>>,
+        config: JeeLinkConfig,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+        let port = open_serial_with(&path, BAUD_RATE, &config.serial)?;
+        let init_commands: Vec<String> = config.rssi_command.into_iter().collect();
 
         Ok(JeeLink {
-            reader: FramedListener::new(port),
+            reader: FramedListener::new(port).with_auto_baud(
+                &config.serial.auto_baud,
+                Duration::from_secs(config.serial.probe_timeout),
+                init_commands.concat().as_bytes(),
+            ),
+            path: path.into_owned(),
+            health: DeviceHealth::Connected,
+            // Sent at every probed rate instead
+            init_commands: match config.serial.auto_baud.is_empty() {
+                true => init_commands,
+                false => Vec::new(),
+            },
+            status: None,
+            statuses: StatusFilter::default(),
+            clock: Arc::new(SystemClock),
         })
     }
+
+    /// Count frame and garbage bytes, see [FramedListener::with_diagnostics]
+    pub fn with_diagnostics(mut self) -> Self {
+        self.reader = self.reader.with_diagnostics();
+        self
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Line based access to the firmware, e.g. to send commands and watch the raw output when
+/// debugging it
+pub struct Console<P = SerialStream> {
+    port: P,
+    buffer: BytesMut,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Console {
+    pub fn open(path: &str) -> anyhow::Result<Console> {
+        Ok(Console::new(open_serial(path, BAUD_RATE)?))
+    }
+
+    /// Frame contained in a line of output, `None` if the line is no frame, e.g. the reply to
+    /// a command
+    pub fn decode(line: &str) -> Option<anyhow::Result<JeeLinkFrame>> {
+        let mut buffer = BytesMut::from(format!("{}\r\n", line).as_bytes());
+        let frame = JeeLinkFrame::check(&mut buffer).ok()?;
+        Some(JeeLinkFrame::parse(frame))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<P: AsyncRead + AsyncWrite + Unpin> Console<P> {
+    pub fn new(port: P) -> Console<P> {
+        Console {
+            port,
+            buffer: BytesMut::with_capacity(256),
+        }
+    }
+
+    /// Send a firmware command, e.g. `v` to print the version
+    pub async fn send(&mut self, command: &str) -> anyhow::Result<()> {
+        self.port.write_all(command.as_bytes()).await?;
+        self.port.flush().await?;
+        Ok(())
+    }
+
+    /// Next line of output without the line ending, `None` once the port is closed. No data is
+    /// lost if the future is dropped before it completes.
+    pub async fn read_line(&mut self) -> anyhow::Result<Option<String>> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&byte| byte == b'\n') {
+                let line = self.buffer.split_to(end + 1);
+                let line = String::from_utf8_lossy(&line);
+                return Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()));
+            }
+            if self.port.read_buf(&mut self.buffer).await? == 0 {
+                return match self.buffer.is_empty() {
+                    true => Ok(None),
+                    false => Err(DeviceError::ConnectionLost)?,
+                };
+            }
+        }
+    }
+}
+
+/// Sensor family as reported in the type field of the LaCrosseITPlusReader firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SensorModel {
+    /// Type 1: TX29-IT, TX27-IT, TX29DTH-IT, TX37, 30.3143.IT, 30.3144.IT
+    Tx29,
+    /// Type 2: TX35DTH-IT, 30.3155WD, 30.3156WD
+    Tx35,
+    /// Any other type id
+    Unknown(u8),
+}
+
+impl SensorModel {
+    /// Numeric type id as transmitted by the firmware
+    pub fn type_id(&self) -> u8 {
+        match self {
+            SensorModel::Tx29 => 1,
+            SensorModel::Tx35 => 2,
+            SensorModel::Unknown(id) => *id,
+        }
+    }
+}
+
+impl From<u8> for SensorModel {
+    fn from(type_id: u8) -> Self {
+        match type_id {
+            1 => SensorModel::Tx29,
+            2 => SensorModel::Tx35,
+            id => SensorModel::Unknown(id),
+        }
+    }
+}
+
+/// Humidity code sent by sensors without a humidity sensor
+const HUMIDITY_NOT_PRESENT: u8 = 106;
+/// Humidity code sent by sensors with a failing humidity sensor
+const HUMIDITY_ERROR: u8 = 125;
+
+/// Decode the humidity byte, with the weak battery bit already removed. Values beyond 100 %
+/// other than the error code, including [HUMIDITY_NOT_PRESENT], are no humidity.
+fn decode_humidity(raw: u8) -> Humidity {
+    match raw {
+        0..=100 => Humidity::Percent(raw),
+        HUMIDITY_ERROR => Humidity::Error,
+        _ => Humidity::NotPresent,
+    }
+}
+
+/// Relative humidity reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Humidity {
+    /// Relative humidity in percent
+    Percent(u8),
+    /// Sensor has no humidity sensor
+    NotPresent,
+    /// Humidity sensor reported an error
+    Error,
+}
+
+impl Display for Humidity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Humidity::Percent(hum) => write!(f, "{:2}", hum),
+            Humidity::NotPresent => write!(f, "--"),
+            Humidity::Error => write!(f, "error"),
+        }
+    }
 }
+
 /// Data Frame received from JeeLink device
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct JeeLinkFrame {
     id: u8,
-    sensor_type: u8,
+    model: SensorModel,
     new_battery: bool,
     weak_battery: bool,
     temperature: f32,
-    humidity: u8,
+    humidity: Humidity,
+    /// Received signal strength in dBm, if reported by the firmware
+    rssi: Option<i16>,
 }
 
 impl JeeLinkFrame {
+    /// Sensor id, changing with every battery change
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn model(&self) -> SensorModel {
+        self.model
+    }
+
+    /// The sensor was switched on recently and accepts a new id
+    pub fn new_battery(&self) -> bool {
+        self.new_battery
+    }
+
+    pub fn weak_battery(&self) -> bool {
+        self.weak_battery
+    }
+
+    /// Temperature in °C
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    pub fn humidity(&self) -> Humidity {
+        self.humidity
+    }
+
+    /// Received signal strength in dBm, if reported by the firmware
+    pub fn rssi(&self) -> Option<i16> {
+        self.rssi
+    }
+
     /// Validate string to be parsable as a Frame object.
     fn validate(s: &str) -> Result<(), FrameValidation> {
-        if !s
-            .chars()
-            .all(|c| c.is_numeric() || c.is_whitespace() || c.is_ascii_control() || c.is_control())
-        {
+        if !s.chars().all(|c| {
+            c.is_numeric()
+                || c == '-'
+                || c.is_whitespace()
+                || c.is_ascii_control()
+                || c.is_control()
+        }) {
             return Err(FrameValidation::InvalidChars(s.to_string()));
         }
-        if s.chars().filter(|c| c.is_whitespace()).count() != 4 {
+        // The RSSI is an optional fifth field
+        if !(4..=5).contains(&s.chars().filter(|c| c.is_whitespace()).count()) {
             return Err(FrameValidation::WrongNumberOfFields(s.to_string()));
         }
         Ok(())
@@ -71,92 +343,119 @@ impl JeeLinkFrame {
 
 impl Frame for JeeLinkFrame {
     fn check(buffer: &mut BytesMut) -> Result<BytesMut, FrameCheckError> {
-        const START_SEQ: &[u8; 5] = b"OK 9 ";
-        const END_SEQ: &[u8; 2] = b"\r\n";
-        loop {
-            if buffer.remaining() <= START_SEQ.len() {
-                return Err(FrameCheckError::Incomplete);
-            }
-            if buffer.chunk().starts_with(START_SEQ) {
-                break;
-            }
-            buffer.advance(1)
-        }
-
-        if let Some((i, _)) = buffer
-            .windows(2)
-            .enumerate()
-            .find(|(_, win)| win == END_SEQ)
-        {
-            let mut frame_data = buffer.split_to(i);
-            frame_data.advance(START_SEQ.len());
-            buffer.advance(END_SEQ.len());
-            Ok(frame_data)
-        } else {
-            Err(FrameCheckError::Incomplete)
-        }
+        const DELIMITER: Delimiter = Delimiter::new(b"OK 9 ", b"\r\n");
+        DELIMITER.check(buffer)
     }
 
     fn parse(buffer: BytesMut) -> anyhow::Result<Self> {
-        let s = std::str::from_utf8(&buffer)?;
+        let s = std::str::from_utf8(&buffer).map_err(|_| FrameParseError::InvalidUtf8)?;
         Self::validate(s)?;
 
-        let fields: Vec<&str> = s.split(|b| b == ' ').collect();
+        // Fields may be separated by any whitespace accepted by the validation
+        let mut fields = s.split_whitespace();
+        let mut next = |name| fields.next().ok_or(FrameParseError::MissingField(name));
 
-        let id: u8 = fields[0].parse()?;
+        let id: u8 = FrameParseError::parse("id", next("id")?)?;
 
-        let (new_battery, sensor_type) = {
-            let field: u8 = fields[1].parse()?;
-            ((field / 128) != 0, field % 128)
+        let (new_battery, model) = {
+            let field: u8 = FrameParseError::parse("type", next("type")?)?;
+            ((field / 128) != 0, SensorModel::from(field % 128))
         };
 
         let temp = {
-            let field1: u16 = fields[2].parse()?;
-            let field2: u16 = fields[3].parse()?;
-            let temp: u16 = (field1 << 8) + field2;
+            let high: u8 = FrameParseError::parse("temperature", next("temperature")?)?;
+            let low: u8 = FrameParseError::parse("temperature", next("temperature")?)?;
+            let temp = u16::from_be_bytes([high, low]);
             let temp: f32 = (temp as f32 - 1000.) / 10.;
             temp
         };
 
         let (weak_battery, hum) = {
-            let field: u8 = fields[4].parse()?;
+            let field: u8 = FrameParseError::parse("humidity", next("humidity")?)?;
             // first bit is weak battery flag
-            ((field & 0x80 != 0), field & 0x7F)
+            ((field & 0x80 != 0), decode_humidity(field & 0x7F))
+        };
+
+        let rssi = match fields.next() {
+            Some(value) => Some(FrameParseError::parse("rssi", value)?),
+            None => None,
         };
 
         Ok(JeeLinkFrame {
             id,
-            sensor_type,
+            model,
             new_battery,
             weak_battery,
             temperature: temp,
             humidity: hum,
+            rssi,
         })
     }
 }
 
+impl EncodableFrame for JeeLinkFrame {
+    fn encode(&self) -> BytesMut {
+        let model = self.model.type_id() | u8::from(self.new_battery) << 7;
+        let [high, low] = ((self.temperature * 10. + 1000.).round() as u16).to_be_bytes();
+        let humidity = match self.humidity {
+            Humidity::Percent(hum) => hum,
+            Humidity::NotPresent => HUMIDITY_NOT_PRESENT,
+            Humidity::Error => HUMIDITY_ERROR,
+        } | u8::from(self.weak_battery) << 7;
+        let mut line = format!("OK 9 {} {} {} {} {}", self.id, model, high, low, humidity);
+        if let Some(rssi) = self.rssi {
+            line.push_str(&format!(" {}", rssi));
+        }
+        line.push_str("\r\n");
+        BytesMut::from(line.as_bytes())
+    }
+}
+
 impl ToOutput for JeeLinkFrame {}
 
 impl Display for JeeLinkFrame {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
                 f,
-                "Sensor {:2}: Type {:2}, Temperatur {:4}, Humidity {:2}, weak battery: {}, new battery: {}",
-                self.id, self.sensor_type, self.temperature, self.humidity, self.weak_battery, self.new_battery
+                "Sensor {:2}: Type {:2}, Temperatur {:4}, Humidity {}, weak battery: {}, new battery: {}",
+                self.id, self.model.type_id(), self.temperature, self.humidity, self.weak_battery, self.new_battery
             )
     }
 }
 
-impl ToLineProtocol for JeeLinkFrame {
-    fn to_lineprotocol(&self) -> LineProtocol {
-        LineProtocol::new("tempHum")
+impl ToMeasurement for JeeLinkFrame {
+    fn to_measurement(&self) -> Measurement {
+        let measurement = Measurement::new("tempHum")
             .add_tag("sensorId", self.id)
-            .add_tag("sensorType", self.sensor_type)
-            .add_value("temperature", self.temperature as f64)
-            .add_value("humidity", self.humidity as u64)
-            .add_value("weak_battery", self.weak_battery)
-            .add_value("new_battery", self.new_battery)
-            .add_time(Some(Utc::now()))
+            .add_tag("sensorType", self.model.type_id())
+            .add_field("temperature", self.temperature as f64)
+            .add_field_meta("temperature", Unit::Celsius);
+        match self.humidity {
+            Humidity::Percent(hum) => measurement
+                .add_field("humidity", hum as u64)
+                .add_field_meta("humidity", Unit::Percent),
+            Humidity::NotPresent | Humidity::Error => measurement,
+        }
+    }
+}
+
+/// Schema of the measurements of [JeeLinkFrame]
+pub fn schemas() -> Vec<Schema> {
+    vec![Schema::new("tempHum")
+        .add_tag("sensorId")
+        .add_tag("sensorType")
+        .add_field("temperature", FieldType::Float, Unit::Celsius)
+        .add_optional_field("humidity", FieldType::UInteger, Unit::Percent)]
+}
+
+impl ToSensorStatus for JeeLinkFrame {
+    fn sensor_status(&self, time: DateTime<Utc>) -> Option<SensorStatus> {
+        Some(SensorStatus {
+            sensor_id: self.id.to_string(),
+            battery: Battery::from_flags(self.weak_battery, self.new_battery),
+            rssi: self.rssi.map(f64::from),
+            last_seen: time,
+        })
     }
 }
 
@@ -164,8 +463,14 @@ impl ToLineProtocol for JeeLinkFrame {
 mod test {
     use crate::output::influx::ToLineProtocol;
 
-    use super::{Frame, FrameCheckError, JeeLinkFrame};
+    use super::{
+        Battery, Console, EncodableFrame, Frame, FrameCheckError, Humidity, JeeLinkFrame,
+        SensorModel, SensorStatus, ToSensorStatus,
+    };
+    use crate::error::FrameParseError;
     use bytes::BytesMut;
+    use chrono::DateTime;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     #[test]
     fn test_frame_parsing() {
@@ -174,15 +479,72 @@ mod test {
             frame,
             JeeLinkFrame {
                 id: 50,
-                sensor_type: 1,
+                model: SensorModel::Tx29,
                 new_battery: false,
                 weak_battery: false,
                 temperature: 21.7,
-                humidity: 65
+                humidity: Humidity::Percent(65),
+                rssi: None,
             }
         );
     }
 
+    #[test]
+    fn test_frame_accessors() {
+        let frame = JeeLinkFrame::parse(BytesMut::from(&b"50 130 4 193 193 -72"[..])).unwrap();
+        assert_eq!(frame.id(), 50);
+        assert_eq!(frame.model(), SensorModel::Tx35);
+        assert!(frame.new_battery());
+        assert!(frame.weak_battery());
+        assert_eq!(frame.temperature(), 21.7);
+        assert_eq!(frame.humidity(), Humidity::Percent(65));
+        assert_eq!(frame.rssi(), Some(-72));
+    }
+
+    #[test]
+    fn test_frame_parsing_of_special_humidity_codes() {
+        let frame = JeeLinkFrame::parse(BytesMut::from(&b"12 130 4 193 234"[..])).unwrap();
+        assert_eq!(
+            frame,
+            JeeLinkFrame {
+                id: 12,
+                model: SensorModel::Tx35,
+                new_battery: true,
+                weak_battery: true,
+                temperature: 21.7,
+                humidity: Humidity::NotPresent,
+                rssi: None,
+            }
+        );
+
+        let frame = JeeLinkFrame::parse(BytesMut::from(&b"12 2 4 193 125"[..])).unwrap();
+        assert_eq!(frame.humidity, Humidity::Error);
+        let frame = JeeLinkFrame::parse(BytesMut::from(&b"12 1 4 193 101"[..])).unwrap();
+        assert_eq!(frame.humidity, Humidity::NotPresent);
+        // Unknown types send the same codes
+        let frame = JeeLinkFrame::parse(BytesMut::from(&b"12 7 4 193 106"[..])).unwrap();
+        assert_eq!(frame.model, SensorModel::Unknown(7));
+        assert_eq!(frame.humidity, Humidity::NotPresent);
+    }
+
+    #[test]
+    fn test_frame_parsing_with_rssi() {
+        let frame = JeeLinkFrame::parse(BytesMut::from(&b"50 1 4 193 65 -72"[..])).unwrap();
+        assert_eq!(frame.rssi, Some(-72));
+        assert_eq!(frame.humidity, Humidity::Percent(65));
+    }
+
+    #[test]
+    fn test_frame_encoding() {
+        for data in [&b"50 129 4 193 65 -72"[..], b"12 2 4 193 253"] {
+            let frame = JeeLinkFrame::parse(BytesMut::from(data)).unwrap();
+            let mut encoded = frame.encode();
+            assert_eq!(encoded, [&b"OK 9 "[..], data, b"\r\n"].concat());
+            let data = JeeLinkFrame::check(&mut encoded).unwrap();
+            assert_eq!(JeeLinkFrame::parse(data).unwrap(), frame);
+        }
+    }
+
     #[test]
     fn test_frame_check_detects_incomplete_frame() {
         assert_eq!(
@@ -212,15 +574,125 @@ mod test {
     fn test_frame_correctly_translated_to_lineprotocol() {
         let frame = JeeLinkFrame {
             id: 50,
-            sensor_type: 1,
+            model: SensorModel::Tx29,
+            new_battery: false,
+            weak_battery: false,
+            temperature: 21.5,
+            humidity: Humidity::Percent(65),
+            rssi: None,
+        };
+        assert_eq!(
+            format!("{}", frame.to_lineprotocol()),
+            "tempHum,sensorId=50,sensorType=1 temperature=21.5,humidity=65u"
+        );
+    }
+
+    #[test]
+    fn test_missing_humidity_is_omitted_from_lineprotocol() {
+        let frame = JeeLinkFrame {
+            id: 50,
+            model: SensorModel::Tx35,
             new_battery: false,
             weak_battery: false,
             temperature: 21.5,
-            humidity: 65,
+            humidity: Humidity::NotPresent,
+            rssi: Some(-72),
         };
         assert_eq!(
-                format!("{}", frame.to_lineprotocol()),
-                "tempHum,sensorId=50,sensorType=1 temperature=21.5,humidity=65u,weak_battery=false,new_battery=false"
-            );
+            format!("{}", frame.to_lineprotocol()),
+            "tempHum,sensorId=50,sensorType=2 temperature=21.5"
+        );
+    }
+
+    #[test]
+    fn test_sensor_status() {
+        let frame = JeeLinkFrame::parse(BytesMut::from("50 1 4 193 193 -72")).unwrap();
+        let time = DateTime::from_timestamp(1700000000, 0).unwrap();
+        assert_eq!(
+            frame.sensor_status(time),
+            Some(SensorStatus {
+                sensor_id: "50".into(),
+                battery: Battery::Low,
+                rssi: Some(-72.),
+                last_seen: time,
+            })
+        );
+    }
+
+    fn parse_error(data: &[u8]) -> FrameParseError {
+        JeeLinkFrame::parse(BytesMut::from(data))
+            .unwrap_err()
+            .downcast()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_frame_parsing_tolerates_any_whitespace() {
+        // Used to panic by indexing the fields split at spaces
+        let frame = JeeLinkFrame::parse(BytesMut::from(&b"50\t1\t4\t193\t65"[..])).unwrap();
+        assert_eq!(frame.id, 50);
+        assert_eq!(frame.humidity, Humidity::Percent(65));
+    }
+
+    #[test]
+    fn test_frame_parsing_errors() {
+        // Passes the validation by its number of separators
+        assert_eq!(
+            parse_error(b"50 1 4 193 "),
+            FrameParseError::MissingField("humidity")
+        );
+        assert_eq!(
+            parse_error(b"50 1 4 256 65"),
+            FrameParseError::OutOfRange {
+                field: "temperature",
+                value: "256".into()
+            }
+        );
+        assert_eq!(
+            parse_error(b"50 1 4 193 65 -99999"),
+            FrameParseError::OutOfRange {
+                field: "rssi",
+                value: "-99999".into()
+            }
+        );
+        assert_eq!(
+            parse_error(b"50 1 - 193 65"),
+            FrameParseError::InvalidNumber {
+                field: "temperature",
+                value: "-".into()
+            }
+        );
+        assert_eq!(
+            parse_error(b"50 1 4 193 \xff"),
+            FrameParseError::InvalidUtf8
+        );
+    }
+
+    #[tokio::test]
+    async fn test_console() {
+        let (port, mut firmware) = tokio::io::duplex(256);
+        let mut console = Console::new(port);
+        console.send("v").await.unwrap();
+        let mut command = [0; 1];
+        firmware.read_exact(&mut command).await.unwrap();
+        assert_eq!(&command, b"v");
+
+        firmware
+            .write_all(b"\r\n[LaCrosseITPlusReader.10.1s (RFM69CW f:868300 r:17241)]\r\nOK 9 50 1 4 193 65\r\nOK 9 ")
+            .await
+            .unwrap();
+        assert_eq!(console.read_line().await.unwrap().unwrap(), "");
+        let banner = console.read_line().await.unwrap().unwrap();
+        assert!(banner.starts_with("[LaCrosseITPlusReader"));
+        assert!(Console::decode(&banner).is_none());
+        let line = console.read_line().await.unwrap().unwrap();
+        assert_eq!(line, "OK 9 50 1 4 193 65");
+        let frame = Console::decode(&line).unwrap().unwrap();
+        assert_eq!(frame.temperature, 21.7);
+        assert!(Console::decode("OK 9 50 x 4 193 65").unwrap().is_err());
+
+        // A partial line at the end of the stream is an error
+        drop(firmware);
+        assert!(console.read_line().await.is_err());
     }
 }
@@ -0,0 +1,246 @@
+//! Base for inputs receiving messages from an MQTT broker.
+//!
+//! A [Subscription] names the topics of interest and decodes their messages into frames,
+//! [MqttInput] maintains the connection to the broker and turns a subscription into a [Device].
+//! Topics are subscribed again after every reconnect. Brokers are reached over TLS if `tls` is
+//! configured, see [crate::tls].
+use super::{Device, DeviceHealth};
+use crate::{output::ToOutput, tls::TlsConfig};
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Port of MQTT brokers without TLS
+pub const DEFAULT_PORT: u16 = 1883;
+
+/// Port of MQTT brokers with TLS
+pub const TLS_PORT: u16 = 8883;
+
+/// Wait between connection attempts
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Connection to the broker
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    /// Host of the broker, with optional port
+    pub broker: String,
+    /// Random if absent
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<TlsConfig>,
+}
+
+impl MqttConfig {
+    pub fn new(broker: impl Into<String>) -> MqttConfig {
+        MqttConfig {
+            broker: broker.into(),
+            client_id: None,
+            username: None,
+            password: None,
+            tls: None,
+        }
+    }
+
+    pub(crate) fn options(&self) -> anyhow::Result<MqttOptions> {
+        let (host, port) = match self.broker.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse()?),
+            None if self.tls.is_some() => (self.broker.as_str(), TLS_PORT),
+            None => (self.broker.as_str(), DEFAULT_PORT),
+        };
+        let client_id = self
+            .client_id
+            .clone()
+            .unwrap_or_else(|| format!("sensorflow-{:08x}", rand::random::<u32>()));
+        let mut options = MqttOptions::new(client_id, host, port);
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.clone().unwrap_or_default());
+        }
+        if let Some(tls) = &self.tls {
+            options.set_transport(tls.mqtt_transport()?);
+        }
+        Ok(options)
+    }
+}
+
+/// Topics of interest and the decoding of their messages
+pub trait Subscription: Send {
+    type Frame: ToOutput + Send + 'static;
+
+    /// Topic filters to subscribe to, wildcards are allowed
+    fn topics(&self) -> Vec<String>;
+
+    /// Decode a received message. Messages which cannot be decoded are skipped.
+    fn decode(&mut self, topic: &str, payload: &[u8]) -> anyhow::Result<Vec<Self::Frame>>;
+
+    /// Kind of the input, e.g. `ttn`
+    fn name(&self) -> &str;
+}
+
+/// Turns a [Subscription] into a [Device]
+pub struct MqttInput<S: Subscription> {
+    subscription: S,
+    client: AsyncClient,
+    eventloop: EventLoop,
+    broker: String,
+    pending: VecDeque<S::Frame>,
+    health: DeviceHealth,
+}
+
+impl<S: Subscription> MqttInput<S> {
+    /// Connects on the first read
+    pub fn new(config: &MqttConfig, subscription: S) -> anyhow::Result<MqttInput<S>> {
+        // Subscriptions are the only requests sent
+        let capacity = subscription.topics().len().max(1);
+        let (client, eventloop) = AsyncClient::new(config.options()?, capacity);
+        Ok(MqttInput {
+            subscription,
+            client,
+            eventloop,
+            broker: config.broker.clone(),
+            pending: VecDeque::new(),
+            health: DeviceHealth::Unknown,
+        })
+    }
+
+    pub fn subscription(&self) -> &S {
+        &self.subscription
+    }
+}
+
+#[async_trait]
+impl<S: Subscription> Device for MqttInput<S> {
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                return Ok(Some(Box::new(frame)));
+            }
+            match self.eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    self.health = DeviceHealth::Connected;
+                    for topic in self.subscription.topics() {
+                        self.client.try_subscribe(topic, QoS::AtMostOnce)?;
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(message))) => {
+                    if let Ok(frames) = self.subscription.decode(&message.topic, &message.payload) {
+                        self.pending.extend(frames);
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    // The event loop reconnects on the next poll
+                    self.health = DeviceHealth::Failed(e.to_string());
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.subscription.name()
+    }
+
+    fn address(&self) -> &str {
+        &self.broker
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
+}
+
+/// Minimal MQTT 3.1.1 broker for tests, accepting one client and publishing `messages` once it
+/// subscribed
+#[cfg(test)]
+pub(crate) async fn serve_once(
+    listener: tokio::net::TcpListener,
+    messages: Vec<(String, Vec<u8>)>,
+) -> Vec<String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut socket, _) = listener.accept().await.unwrap();
+    let mut buffer = [0; 1024];
+    // CONNECT
+    let _ = socket.read(&mut buffer).await.unwrap();
+    socket.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+    // SUBSCRIBE with packet id, topic filter and QoS
+    let len = socket.read(&mut buffer).await.unwrap();
+    assert_eq!(buffer[0], 0x82);
+    let topic_len = u16::from_be_bytes([buffer[4], buffer[5]]) as usize;
+    let topics = vec![String::from_utf8_lossy(&buffer[6..6 + topic_len]).to_string()];
+    assert!(len >= 6 + topic_len);
+    socket
+        .write_all(&[0x90, 0x03, buffer[2], buffer[3], 0x00])
+        .await
+        .unwrap();
+    for (topic, payload) in messages {
+        let mut packet = vec![0x30];
+        let mut remaining = 2 + topic.len() + payload.len();
+        loop {
+            let byte = (remaining % 128) as u8;
+            remaining /= 128;
+            if remaining == 0 {
+                packet.push(byte);
+                break;
+            }
+            packet.push(byte | 0x80);
+        }
+        packet.extend((topic.len() as u16).to_be_bytes());
+        packet.extend(topic.as_bytes());
+        packet.extend(payload);
+        socket.write_all(&packet).await.unwrap();
+    }
+    // Keep the connection open until the client is done
+    let _ = socket.read(&mut buffer).await;
+    topics
+}
+
+#[cfg(test)]
+mod test {
+    use super::{serve_once, MqttConfig, MqttInput, Subscription};
+    use crate::{devices::Device, measurement::Measurement};
+
+    /// Emits the payloads of all messages as measurement named after the topic
+    struct Raw;
+
+    impl Subscription for Raw {
+        type Frame = Measurement;
+
+        fn topics(&self) -> Vec<String> {
+            vec!["sensors/#".into()]
+        }
+
+        fn decode(&mut self, topic: &str, payload: &[u8]) -> anyhow::Result<Vec<Measurement>> {
+            let value: f64 = std::str::from_utf8(payload)?.parse()?;
+            Ok(vec![Measurement::new(topic).add_field("value", value)])
+        }
+
+        fn name(&self) -> &str {
+            "raw"
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_and_decode() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = MqttConfig::new(listener.local_addr().unwrap().to_string());
+        let broker = tokio::spawn(serve_once(
+            listener,
+            vec![
+                ("sensors/a".into(), b"invalid".to_vec()),
+                ("sensors/b".into(), b"21.5".to_vec()),
+            ],
+        ));
+
+        let mut input = MqttInput::new(&config, Raw).unwrap();
+        let frame = input.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame.to_string(), "sensors/b: value=21.5");
+        drop(input);
+        assert_eq!(broker.await.unwrap(), ["sensors/#"]);
+    }
+}
@@ -0,0 +1,365 @@
+//! GNSS receivers speaking NMEA 0183 on a serial line, e.g. the GPS of a boat or a USB mouse.
+//!
+//! Sentences start with `$`, followed by the talker and the sentence type, e.g. `GPGGA` or
+//! `GNRMC`, comma separated fields, `*` and the XOR of all characters between `$` and `*` as
+//! two hex digits. Fixes are taken from GGA sentences, with the altitude, and RMC sentences,
+//! with speed and course. Other sentences and those without a valid fix are ignored.
+#[cfg(not(target_arch = "wasm32"))]
+use crate::FramedListener;
+use crate::{
+    error::*,
+    input::{checksum::xor8, delimiter::Delimiter},
+    measurement::{Measurement, ToMeasurement, Unit},
+    output::ToOutput,
+    schema::{FieldType, Schema},
+    Frame,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{input::line::SerialSettings, input::FrameStats};
+#[cfg(not(target_arch = "wasm32"))]
+use async_trait::async_trait;
+use bytes::BytesMut;
+use std::fmt::{self, Display};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_serial::SerialStream;
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::{open_serial_with, Device, DeviceHealth};
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Baud rate of the device. NMEA 0183 specifies 4.8 KBd, many receivers use 9.6 KBd
+const BAUD_RATE: u32 = 4800;
+
+/// Knots in m/s
+const KNOT: f64 = 1852. / 3600.;
+
+/// Position reported by the receiver
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Fix {
+    /// Latitude in degrees, positive to the north
+    pub latitude: f64,
+    /// Longitude in degrees, positive to the east
+    pub longitude: f64,
+    /// Altitude above mean sea level in m, of GGA sentences
+    pub altitude: Option<f64>,
+    /// Satellites used for the fix, of GGA sentences
+    pub satellites: Option<u8>,
+    /// Horizontal dilution of precision, of GGA sentences
+    pub hdop: Option<f64>,
+    /// Speed over ground in m/s, of RMC sentences
+    pub speed: Option<f64>,
+    /// Course over ground in degrees, of RMC sentences
+    pub course: Option<f64>,
+}
+
+/// Sentence of a receiver
+#[derive(Debug, Clone, PartialEq)]
+pub enum NmeaSentence {
+    Fix(Fix),
+    /// Other sentences and those without a valid fix, by their type, e.g. `GPGSV`
+    Other(String),
+}
+
+/// Degrees of a coordinate as `ddmm.mmmm` and its hemisphere
+fn coordinate(value: &str, hemisphere: &str) -> Option<f64> {
+    let point = value.find('.').unwrap_or(value.len());
+    let degrees: f64 = value.get(..point.checked_sub(2)?)?.parse().ok()?;
+    let minutes: f64 = value.get(point - 2..)?.parse().ok()?;
+    let degrees = degrees + minutes / 60.;
+    match hemisphere {
+        "N" | "E" => Some(degrees),
+        "S" | "W" => Some(-degrees),
+        _ => None,
+    }
+}
+
+impl NmeaSentence {
+    /// Sentence of the fields between `$` and `*`
+    fn from_fields(fields: &[&str]) -> anyhow::Result<NmeaSentence> {
+        let kind = fields[0];
+        let field = |i: usize| fields.get(i).copied().unwrap_or_default();
+        let number = |i: usize| field(i).parse::<f64>().ok();
+        let position = |i: usize| {
+            let latitude = coordinate(field(i), field(i + 1));
+            let longitude = coordinate(field(i + 2), field(i + 3));
+            latitude.zip(longitude)
+        };
+        let fix = match kind.get(2..) {
+            // Fix quality 0 is no fix
+            Some("GGA") if !matches!(field(6), "" | "0") => {
+                position(2).map(|(latitude, longitude)| Fix {
+                    latitude,
+                    longitude,
+                    altitude: number(9),
+                    satellites: field(7).parse().ok(),
+                    hdop: number(8),
+                    ..Default::default()
+                })
+            }
+            // Status V is a warning of no fix
+            Some("RMC") if field(2) == "A" => position(3).map(|(latitude, longitude)| Fix {
+                latitude,
+                longitude,
+                speed: number(7).map(|knots| knots * KNOT),
+                course: number(8),
+                ..Default::default()
+            }),
+            _ => None,
+        };
+        Ok(match fix {
+            Some(fix) => NmeaSentence::Fix(fix),
+            None => NmeaSentence::Other(kind.into()),
+        })
+    }
+}
+
+impl Frame for NmeaSentence {
+    /// Returns the sentence between `$` and the checksum.
+    ///
+    /// Sentences without a checksum are skipped, those with an invalid one are removed and
+    /// rejected with [FrameCheckError::ChecksumError].
+    fn check(buffer: &mut BytesMut) -> Result<BytesMut, FrameCheckError> {
+        const DELIMITER: Delimiter = Delimiter::new(b"$", b"\n");
+        loop {
+            let mut sentence = DELIMITER.check(buffer)?;
+            if sentence.last() == Some(&b'\r') {
+                sentence.truncate(sentence.len() - 1);
+            }
+            let Some(star) = sentence.iter().rposition(|&b| b == b'*') else {
+                continue;
+            };
+            let Some(actual) = std::str::from_utf8(&sentence[star + 1..])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            else {
+                continue;
+            };
+            let expected = xor8(&sentence[..star]);
+            if expected != actual {
+                return Err(FrameCheckError::ChecksumError {
+                    expected: expected as u32,
+                    actual: actual as u32,
+                });
+            }
+            sentence.truncate(star);
+            return Ok(sentence);
+        }
+    }
+
+    fn parse(buffer: BytesMut) -> anyhow::Result<Self> {
+        let text = std::str::from_utf8(&buffer)?;
+        let fields: Vec<&str> = text.split(',').collect();
+        NmeaSentence::from_fields(&fields)
+    }
+}
+
+/// Fix of a receiver
+#[derive(Debug, Clone, PartialEq)]
+pub struct Position {
+    /// Path of the receiver
+    pub receiver: String,
+    pub fix: Fix,
+}
+
+impl ToOutput for Position {}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Position {:.6}, {:.6}",
+            self.fix.latitude, self.fix.longitude
+        )
+    }
+}
+
+impl ToMeasurement for Position {
+    fn to_measurement(&self) -> Measurement {
+        let fix = &self.fix;
+        let mut measurement = Measurement::new("position")
+            .add_tag("receiver", &self.receiver)
+            .add_field("latitude", fix.latitude)
+            .add_field_meta("latitude", Unit::Degree)
+            .add_field("longitude", fix.longitude)
+            .add_field_meta("longitude", Unit::Degree);
+        if let Some(altitude) = fix.altitude {
+            measurement = measurement
+                .add_field("altitude", altitude)
+                .add_field_meta("altitude", Unit::from_symbol("m"));
+        }
+        if let Some(satellites) = fix.satellites {
+            measurement = measurement.add_field("satellites", satellites as u64);
+        }
+        if let Some(hdop) = fix.hdop {
+            measurement = measurement.add_field("hdop", hdop);
+        }
+        if let Some(speed) = fix.speed {
+            measurement = measurement
+                .add_field("speed", speed)
+                .add_field_meta("speed", Unit::MetrePerSecond);
+        }
+        if let Some(course) = fix.course {
+            measurement = measurement
+                .add_field("course", course)
+                .add_field_meta("course", Unit::Degree);
+        }
+        measurement
+    }
+}
+
+/// Schema of the measurements of [Position]
+pub fn schemas() -> Vec<Schema> {
+    vec![Schema::new("position")
+        .add_tag("receiver")
+        .add_field("latitude", FieldType::Float, Unit::Degree)
+        .add_field("longitude", FieldType::Float, Unit::Degree)
+        .add_optional_field("altitude", FieldType::Float, Unit::from_symbol("m"))
+        .add_optional_field("satellites", FieldType::UInteger, None)
+        .add_optional_field("hdop", FieldType::Float, None)
+        .add_optional_field("speed", FieldType::Float, Unit::MetrePerSecond)
+        .add_optional_field("course", FieldType::Float, Unit::Degree)]
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// NMEA 0183 receiver
+pub struct Nmea {
+    reader: FramedListener<SerialStream, NmeaSentence>,
+    path: String,
+    health: DeviceHealth,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl Device for Nmea {
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        loop {
+            let res = self.reader.read_frame().await;
+            self.health = DeviceHealth::after_read(&res);
+            match res {
+                Ok(Some(NmeaSentence::Other(_))) => continue,
+                Ok(Some(NmeaSentence::Fix(fix))) => {
+                    return Ok(Some(Box::new(Position {
+                        receiver: self.path.clone(),
+                        fix,
+                    })))
+                }
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "nmea"
+    }
+
+    fn address(&self) -> &str {
+        &self.path
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
+
+    fn frame_stats(&self) -> Option<FrameStats> {
+        self.reader.stats().cloned()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Nmea {
+    pub fn new<'a>(path: impl Into<std::borrow::Cow<'a, str>>) -> anyhow::Result<Self> {
+        Self::with_serial(path, &SerialSettings::default())
+    }
+
+    /// Open the device with line settings other than 4800 8N1
+    pub fn with_serial<'a>(
+        path: impl Into<std::borrow::Cow<'a, str>>,
+        serial: &SerialSettings,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+        let port = open_serial_with(&path, BAUD_RATE, serial)?;
+
+        Ok(Nmea {
+            reader: FramedListener::new(port).with_auto_baud(
+                &serial.auto_baud,
+                Duration::from_secs(serial.probe_timeout),
+                b"",
+            ),
+            path: path.into_owned(),
+            health: DeviceHealth::Connected,
+        })
+    }
+
+    /// Count frame and garbage bytes, see [FramedListener::with_diagnostics]
+    pub fn with_diagnostics(mut self) -> Self {
+        self.reader = self.reader.with_diagnostics();
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{coordinate, NmeaSentence};
+    use crate::{error::FrameCheckError, Frame};
+    use bytes::BytesMut;
+
+    fn parse(buffer: &mut BytesMut) -> NmeaSentence {
+        NmeaSentence::parse(NmeaSentence::check(buffer).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_fixes() {
+        let mut buffer = BytesMut::from(
+            "\x00$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n\
+             $GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A\r\n\
+             $GPGSV,2,1,08,01,40,083,46,02,17,308,41,12,07,344,39,14,22,228,45*75\r\n$GP",
+        );
+        let NmeaSentence::Fix(gga) = parse(&mut buffer) else {
+            panic!("no fix")
+        };
+        assert!((gga.latitude - 48.1173).abs() < 1e-9);
+        assert!((gga.longitude - 11.516_667).abs() < 1e-6);
+        assert_eq!(
+            (gga.altitude, gga.satellites, gga.hdop, gga.speed),
+            (Some(545.4), Some(8), Some(0.9), None)
+        );
+        let NmeaSentence::Fix(rmc) = parse(&mut buffer) else {
+            panic!("no fix")
+        };
+        assert!((rmc.speed.unwrap() - 11.523).abs() < 1e-3);
+        assert_eq!((rmc.course, rmc.altitude), (Some(84.4), None));
+        assert_eq!(parse(&mut buffer), NmeaSentence::Other("GPGSV".into()));
+        assert!(NmeaSentence::check(&mut buffer).is_err());
+        assert_eq!(&buffer[..], b"$GP");
+    }
+
+    #[test]
+    fn test_skip_invalid() {
+        let mut buffer = BytesMut::from(
+            "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*48\r\n\
+             $GNRMC,123520,V,,,,,,,230394,,,N*45\n",
+        );
+        // Invalid checksum
+        assert!(matches!(
+            NmeaSentence::check(&mut buffer),
+            Err(FrameCheckError::ChecksumError { .. })
+        ));
+        assert_eq!(parse(&mut buffer), NmeaSentence::Other("GNRMC".into()));
+        let sentence = NmeaSentence::from_fields(&["GPGGA", "", "", "", "", "", "0"]).unwrap();
+        assert_eq!(sentence, NmeaSentence::Other("GPGGA".into()));
+        // Fix without a position
+        let sentence = NmeaSentence::from_fields(&["GPRMC", "", "A", "", "", "", ""]).unwrap();
+        assert_eq!(sentence, NmeaSentence::Other("GPRMC".into()));
+    }
+
+    #[test]
+    fn test_coordinate() {
+        assert_eq!(coordinate("3330.000", "S"), Some(-33.5));
+        assert_eq!(coordinate("00030.00", "W"), Some(-0.5));
+        assert_eq!(coordinate("4807.038", "X"), None);
+        assert_eq!(coordinate("7", "N"), None);
+    }
+}
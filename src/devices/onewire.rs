@@ -0,0 +1,217 @@
+//! DS18B20 temperature sensors attached to the 1-Wire bus, read through the Linux `w1` sysfs
+//! interface, e.g. on the GPIO header of a Raspberry Pi.
+use crate::{
+    measurement::{Measurement, ToMeasurement, Unit},
+    output::ToOutput,
+    schema::{FieldType, Schema},
+};
+use async_trait::async_trait;
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+use super::poll::{Poll, Polled};
+
+/// Directory listing all 1-Wire slaves
+pub const SYSFS_PATH: &str = "/sys/bus/w1/devices";
+
+/// Family code of the DS18B20, prefix of the slave directory names
+const FAMILY_DS18B20: &str = "28-";
+
+/// Value reported after power-on before the first conversion
+const POWER_ON_RESET_MILLIDEGREES: i32 = 85000;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum W1SlaveError {
+    #[error("Malformed w1_slave content: {0}")]
+    Malformed(String),
+    #[error("CRC check failed")]
+    CrcMismatch,
+    #[error("Sensor not ready, power-on reset value read")]
+    PowerOnReset,
+}
+
+/// Temperature reading of a single DS18B20
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ds18b20Reading {
+    /// 1-Wire id of the sensor, e.g. `28-0316a2795dff`
+    pub id: String,
+    /// Temperature in °C
+    pub temperature: f64,
+}
+
+impl Ds18b20Reading {
+    /// Parse the content of a `w1_slave` file, e.g.
+    ///
+    /// ```text
+    /// 72 01 4b 46 7f ff 0e 10 57 : crc=57 YES
+    /// 72 01 4b 46 7f ff 0e 10 57 t=23125
+    /// ```
+    pub fn parse(id: impl Into<String>, content: &str) -> Result<Ds18b20Reading, W1SlaveError> {
+        let mut lines = content.lines();
+        let (Some(crc_line), Some(data_line)) = (lines.next(), lines.next()) else {
+            return Err(W1SlaveError::Malformed(content.to_string()));
+        };
+        if !crc_line.trim_end().ends_with("YES") {
+            return Err(W1SlaveError::CrcMismatch);
+        }
+        let millidegrees: i32 = data_line
+            .rsplit_once("t=")
+            .and_then(|(_, t)| t.trim().parse().ok())
+            .ok_or_else(|| W1SlaveError::Malformed(content.to_string()))?;
+        if millidegrees == POWER_ON_RESET_MILLIDEGREES {
+            return Err(W1SlaveError::PowerOnReset);
+        }
+        Ok(Ds18b20Reading {
+            id: id.into(),
+            temperature: millidegrees as f64 / 1000.,
+        })
+    }
+}
+
+impl ToOutput for Ds18b20Reading {}
+
+impl Display for Ds18b20Reading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Sensor {}: Temperatur {}", self.id, self.temperature)
+    }
+}
+
+impl ToMeasurement for Ds18b20Reading {
+    fn to_measurement(&self) -> Measurement {
+        Measurement::new("temperature")
+            .add_tag("sensorId", &self.id)
+            .add_field("temperature", self.temperature)
+    }
+}
+
+/// Schema of the measurements of [Ds18b20Reading]
+pub fn schemas() -> Vec<Schema> {
+    vec![Schema::new("temperature").add_tag("sensorId").add_field(
+        "temperature",
+        FieldType::Float,
+        Unit::Celsius,
+    )]
+}
+
+/// All DS18B20 sensors found in the sysfs directory
+pub struct OneWire {
+    path: PathBuf,
+    address: String,
+}
+
+impl OneWire {
+    pub fn new(path: impl Into<PathBuf>) -> OneWire {
+        let path = path.into();
+        OneWire {
+            address: path.display().to_string(),
+            path,
+        }
+    }
+
+    /// Device polling all sensors in the default sysfs directory on the given interval
+    pub fn polled(period: Duration) -> Polled<OneWire> {
+        Polled::new(OneWire::new(SYSFS_PATH), period)
+    }
+}
+
+#[async_trait]
+impl Poll for OneWire {
+    type Frame = Ds18b20Reading;
+
+    /// Read all sensors. Sensors failing the CRC check or not ready are skipped.
+    async fn poll(&mut self) -> anyhow::Result<Vec<Ds18b20Reading>> {
+        let mut ids = vec![];
+        let mut entries = tokio::fs::read_dir(&self.path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let id = entry.file_name().to_string_lossy().into_owned();
+            if id.starts_with(FAMILY_DS18B20) {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+
+        let mut readings = vec![];
+        for id in ids {
+            let content = tokio::fs::read_to_string(self.path.join(&id).join("w1_slave")).await?;
+            match Ds18b20Reading::parse(id, &content) {
+                Ok(reading) => readings.push(reading),
+                Err(W1SlaveError::CrcMismatch | W1SlaveError::PowerOnReset) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(readings)
+    }
+
+    fn name(&self) -> &str {
+        "ds18b20"
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Ds18b20Reading, OneWire, W1SlaveError};
+    use crate::devices::poll::Poll;
+
+    const VALID: &str = "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n\
+                         72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+
+    #[test]
+    fn test_parse_valid_reading() {
+        assert_eq!(
+            Ds18b20Reading::parse("28-1", VALID),
+            Ok(Ds18b20Reading {
+                id: "28-1".into(),
+                temperature: 23.125
+            })
+        );
+        let negative = "ff ff : crc=57 YES\nff ff t=-1250\n";
+        assert_eq!(
+            Ds18b20Reading::parse("28-1", negative).unwrap().temperature,
+            -1.25
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_crc_and_reset_value() {
+        let bad_crc = "72 01 : crc=57 NO\n72 01 t=23125\n";
+        assert_eq!(
+            Ds18b20Reading::parse("28-1", bad_crc),
+            Err(W1SlaveError::CrcMismatch)
+        );
+        let reset = "50 05 : crc=57 YES\n50 05 t=85000\n";
+        assert_eq!(
+            Ds18b20Reading::parse("28-1", reset),
+            Err(W1SlaveError::PowerOnReset)
+        );
+        assert!(matches!(
+            Ds18b20Reading::parse("28-1", "garbage"),
+            Err(W1SlaveError::Malformed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_poll_reads_all_ds18b20() {
+        let dir = std::env::temp_dir().join(format!("sensorflow-w1-{}", std::process::id()));
+        for (id, content) in [
+            ("28-b", VALID),
+            ("28-a", VALID),
+            ("28-c", "00 : crc=00 NO\n00 t=0\n"),
+            ("w1_bus_master1", ""),
+        ] {
+            std::fs::create_dir_all(dir.join(id)).unwrap();
+            std::fs::write(dir.join(id).join("w1_slave"), content).unwrap();
+        }
+
+        let readings = OneWire::new(&dir).poll().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let ids: Vec<_> = readings.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, ["28-a", "28-b"]);
+    }
+}
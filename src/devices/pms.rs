@@ -0,0 +1,375 @@
+//! Plantower PMS5003 and PMS7003 particulate matter sensors on a serial line.
+//!
+//! Frames start with `0x42 0x4d`, followed by the big endian length of the remainder and the
+//! 16 bit data words. The last word is the sum of all preceding bytes. In active mode, the
+//! default, the sensor sends a frame about every second. In passive mode, a frame is sent only
+//! on request.
+#[cfg(not(target_arch = "wasm32"))]
+use crate::FramedListener;
+use crate::{
+    error::*,
+    input::{binary::Framing, checksum::sum16},
+    measurement::{Measurement, ToMeasurement, Unit},
+    output::ToOutput,
+    schema::{FieldType, Schema},
+    EncodableFrame, Frame,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use async_trait::async_trait;
+use bytes::BytesMut;
+use std::fmt::{self, Display};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::time::{Interval, MissedTickBehavior};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_serial::SerialStream;
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::{open_serial, Device, DeviceHealth};
+
+#[cfg(not(target_arch = "wasm32"))]
+const BAUD_RATE: u32 = 9600;
+
+const START: [u8; 2] = [0x42, 0x4d];
+
+/// Start sequence and length field
+const HEADER_LEN: usize = 4;
+
+/// Length field of data frames: 13 data words and the checksum
+const DATA_LEN: usize = 28;
+
+/// Length field of command responses: command, data byte and the checksum
+const RESPONSE_LEN: usize = 4;
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Switch to passive mode
+const CMD_PASSIVE_MODE: [u8; 7] = [0x42, 0x4d, 0xe1, 0x00, 0x00, 0x01, 0x70];
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Request a frame in passive mode
+const CMD_READ: [u8; 7] = [0x42, 0x4d, 0xe2, 0x00, 0x00, 0x01, 0x71];
+
+/// Concentrations and particle counts of a single frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct PmsReading {
+    /// PM1.0, PM2.5 and PM10 in µg/m³ under atmospheric conditions
+    pub pm1_0: u16,
+    pub pm2_5: u16,
+    pub pm10: u16,
+    /// PM1.0, PM2.5 and PM10 in µg/m³ for standard particles (CF=1)
+    pub pm1_0_cf1: u16,
+    pub pm2_5_cf1: u16,
+    pub pm10_cf1: u16,
+    /// Particles per 0.1 l beyond 0.3, 0.5, 1.0, 2.5, 5.0 and 10 µm
+    pub counts: [u16; 6],
+}
+
+/// Frame sent by the sensor
+#[derive(Debug, Clone, PartialEq)]
+pub enum PmsFrame {
+    Data(PmsReading),
+    /// Acknowledgement of a command
+    CommandResponse,
+}
+
+impl Frame for PmsFrame {
+    /// Returns the full frame, starting with the start sequence.
+    ///
+    /// Frames with invalid checksums are rejected with [FrameCheckError::ChecksumError].
+    fn check(buffer: &mut BytesMut) -> Result<BytesMut, FrameCheckError> {
+        // Other lengths are a start sequence within the data of another frame
+        const FRAMING: Framing = Framing::prefixed(&START, HEADER_LEN, |header| {
+            match u16::from_be_bytes([header[2], header[3]]) as usize {
+                len @ (DATA_LEN | RESPONSE_LEN) => Some(HEADER_LEN + len),
+                _ => None,
+            }
+        })
+        .checksum(|frame| {
+            let (data, checksum) = frame.split_at(frame.len() - 2);
+            let checksum = u16::from_be_bytes([checksum[0], checksum[1]]);
+            (sum16(data) as u32, checksum as u32)
+        });
+        FRAMING.check(buffer)
+    }
+
+    fn parse(buffer: BytesMut) -> anyhow::Result<Self> {
+        if buffer.len() != HEADER_LEN + DATA_LEN {
+            return Ok(PmsFrame::CommandResponse);
+        }
+        let word = |i: usize| {
+            let offset = HEADER_LEN + 2 * i;
+            u16::from_be_bytes([buffer[offset], buffer[offset + 1]])
+        };
+        Ok(PmsFrame::Data(PmsReading {
+            pm1_0_cf1: word(0),
+            pm2_5_cf1: word(1),
+            pm10_cf1: word(2),
+            pm1_0: word(3),
+            pm2_5: word(4),
+            pm10: word(5),
+            counts: [word(6), word(7), word(8), word(9), word(10), word(11)],
+        }))
+    }
+}
+
+impl EncodableFrame for PmsFrame {
+    fn encode(&self) -> BytesMut {
+        let mut frame = BytesMut::from(&START[..]);
+        match self {
+            PmsFrame::Data(reading) => {
+                frame.extend_from_slice(&(DATA_LEN as u16).to_be_bytes());
+                let words = [
+                    reading.pm1_0_cf1,
+                    reading.pm2_5_cf1,
+                    reading.pm10_cf1,
+                    reading.pm1_0,
+                    reading.pm2_5,
+                    reading.pm10,
+                ];
+                for word in words.iter().chain(&reading.counts) {
+                    frame.extend_from_slice(&word.to_be_bytes());
+                }
+                // Reserved
+                frame.extend_from_slice(&[0, 0]);
+            }
+            // Acknowledgement of the passive mode command
+            PmsFrame::CommandResponse => {
+                frame.extend_from_slice(&(RESPONSE_LEN as u16).to_be_bytes());
+                frame.extend_from_slice(&[0xe1, 0x00]);
+            }
+        }
+        let checksum = sum16(&frame);
+        frame.extend_from_slice(&checksum.to_be_bytes());
+        frame
+    }
+}
+
+/// Reading of a sensor
+#[derive(Debug, Clone, PartialEq)]
+pub struct PmsOutput {
+    pub sensor: String,
+    pub reading: PmsReading,
+}
+
+impl ToOutput for PmsOutput {}
+
+impl Display for PmsOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PMS {}: PM1.0 {} PM2.5 {} PM10 {}",
+            self.sensor, self.reading.pm1_0, self.reading.pm2_5, self.reading.pm10
+        )
+    }
+}
+
+/// Fields of [PmsReading::counts]
+const COUNT_FIELDS: [&str; 6] = [
+    "count_0_3",
+    "count_0_5",
+    "count_1_0",
+    "count_2_5",
+    "count_5_0",
+    "count_10",
+];
+
+impl ToMeasurement for PmsOutput {
+    fn to_measurement(&self) -> Measurement {
+        let r = &self.reading;
+        let mut measurement = Measurement::new("particulates").add_tag("sensor", &self.sensor);
+        let concentrations = [
+            ("pm1_0", r.pm1_0),
+            ("pm2_5", r.pm2_5),
+            ("pm10", r.pm10),
+            ("pm1_0_cf1", r.pm1_0_cf1),
+            ("pm2_5_cf1", r.pm2_5_cf1),
+            ("pm10_cf1", r.pm10_cf1),
+        ];
+        for (name, concentration) in concentrations {
+            measurement = measurement
+                .add_field(name, concentration as u64)
+                .add_field_meta(name, Unit::MicrogramPerCubicMetre);
+        }
+        for (name, count) in COUNT_FIELDS.iter().zip(r.counts) {
+            measurement = measurement.add_field(*name, count as u64);
+        }
+        measurement
+    }
+}
+
+/// Schema of the measurements of [PmsOutput]
+pub fn schemas() -> Vec<Schema> {
+    let concentrations = [
+        "pm1_0",
+        "pm2_5",
+        "pm10",
+        "pm1_0_cf1",
+        "pm2_5_cf1",
+        "pm10_cf1",
+    ];
+    let mut schema = Schema::new("particulates").add_tag("sensor");
+    for name in concentrations {
+        schema = schema.add_field(name, FieldType::UInteger, Unit::MicrogramPerCubicMetre);
+    }
+    for name in COUNT_FIELDS {
+        schema = schema.add_field(name, FieldType::UInteger, None);
+    }
+    vec![schema]
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// PMS5003 or PMS7003 sensor
+pub struct Pms {
+    reader: FramedListener<SerialStream, PmsFrame>,
+    path: String,
+    health: DeviceHealth,
+    /// Interval of requests in passive mode
+    passive: Option<Interval>,
+    initialized: bool,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl Device for Pms {
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        if !self.initialized && self.passive.is_some() {
+            self.reader.write_all(&CMD_PASSIVE_MODE).await?;
+            self.initialized = true;
+        }
+        if let Some(interval) = self.passive.as_mut() {
+            interval.tick().await;
+            self.reader.write_all(&CMD_READ).await?;
+        }
+        loop {
+            let res = self.reader.read_frame().await;
+            self.health = DeviceHealth::after_read(&res);
+            match res {
+                Ok(Some(PmsFrame::CommandResponse)) => continue,
+                Ok(Some(PmsFrame::Data(reading))) => {
+                    return Ok(Some(Box::new(PmsOutput {
+                        sensor: self.path.clone(),
+                        reading,
+                    })))
+                }
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "pms"
+    }
+
+    fn address(&self) -> &str {
+        &self.path
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Pms {
+    /// Sensor in active mode, reporting about once per second
+    pub fn new<'a>(path: impl Into<std::borrow::Cow<'a, str>>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let port = open_serial(&path, BAUD_RATE)?;
+
+        Ok(Pms {
+            reader: FramedListener::new(port),
+            path: path.into_owned(),
+            health: DeviceHealth::Connected,
+            passive: None,
+            initialized: false,
+        })
+    }
+
+    /// Switch the sensor to passive mode and request a frame once per `period`
+    pub fn passive(mut self, period: Duration) -> Self {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.passive = Some(interval);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sum16, PmsFrame, PmsReading, CMD_PASSIVE_MODE, CMD_READ};
+    use crate::{error::FrameCheckError, Frame};
+    use bytes::BytesMut;
+
+    fn data_frame() -> Vec<u8> {
+        let mut frame = vec![0x42, 0x4d, 0x00, 0x1c];
+        for word in [5u16, 8, 9, 5, 8, 9, 1170, 340, 50, 4, 1, 0, 0] {
+            frame.extend(word.to_be_bytes());
+        }
+        frame.extend(sum16(&frame).to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_command_checksums() {
+        for command in [CMD_PASSIVE_MODE, CMD_READ] {
+            assert_eq!(sum16(&command[..5]).to_be_bytes(), command[5..]);
+        }
+    }
+
+    #[test]
+    fn test_parse_data_frame() {
+        let mut buffer = BytesMut::from(&[&[0x00, 0x42][..], &data_frame()].concat()[..]);
+        let frame = PmsFrame::parse(PmsFrame::check(&mut buffer).unwrap()).unwrap();
+        assert_eq!(
+            frame,
+            PmsFrame::Data(PmsReading {
+                pm1_0: 5,
+                pm2_5: 8,
+                pm10: 9,
+                pm1_0_cf1: 5,
+                pm2_5_cf1: 8,
+                pm10_cf1: 9,
+                counts: [1170, 340, 50, 4, 1, 0],
+            })
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_check_rejects_bad_checksum_and_passes_command_responses() {
+        let mut corrupt = data_frame();
+        corrupt[10] ^= 0xff;
+        let response = [0x42, 0x4d, 0x00, 0x04, 0xe1, 0x00, 0x01, 0x74];
+        let mut buffer = BytesMut::from(&[&corrupt[..], &response, &data_frame()].concat()[..]);
+
+        assert!(matches!(
+            PmsFrame::check(&mut buffer),
+            Err(FrameCheckError::ChecksumError { .. })
+        ));
+        let frame = PmsFrame::parse(PmsFrame::check(&mut buffer).unwrap()).unwrap();
+        assert_eq!(frame, PmsFrame::CommandResponse);
+        let frame = PmsFrame::parse(PmsFrame::check(&mut buffer).unwrap()).unwrap();
+        assert!(matches!(frame, PmsFrame::Data(_)));
+    }
+
+    #[test]
+    fn test_check_skips_unknown_lengths() {
+        // Start sequence in garbage, announcing a frame long enough to swallow the next one
+        let mut buffer = BytesMut::from(&[0x42, 0x4d, 0x00, 0x40, 0x00][..]);
+        buffer.extend_from_slice(&data_frame());
+        let frame = PmsFrame::parse(PmsFrame::check(&mut buffer).unwrap()).unwrap();
+        assert!(matches!(frame, PmsFrame::Data(_)));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_check_keeps_partial_frame() {
+        let frame = data_frame();
+        let mut buffer = BytesMut::from(&frame[..10]);
+        assert!(PmsFrame::check(&mut buffer).is_err());
+        buffer.extend_from_slice(&frame[10..]);
+        assert!(PmsFrame::check(&mut buffer).is_ok());
+    }
+}
@@ -0,0 +1,82 @@
+//! Adapter for inputs which are queried on a fixed interval instead of streaming data.
+use super::{Device, DeviceHealth};
+use crate::input::error::DeviceError;
+use crate::output::ToOutput;
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::{Interval, MissedTickBehavior};
+
+/// Source of readings which is queried periodically
+#[async_trait]
+pub trait Poll: Send {
+    type Frame: ToOutput + Send + 'static;
+
+    /// Query all readings currently available
+    async fn poll(&mut self) -> anyhow::Result<Vec<Self::Frame>>;
+
+    /// Kind of the source, e.g. `ds18b20`
+    fn name(&self) -> &str;
+
+    /// Where the source is attached
+    fn address(&self) -> &str;
+}
+
+/// Turns a [Poll] source into a [Device], querying it once per interval
+pub struct Polled<P: Poll> {
+    source: P,
+    interval: Interval,
+    pending: VecDeque<P::Frame>,
+    health: DeviceHealth,
+}
+
+impl<P: Poll> Polled<P> {
+    pub fn new(source: P, period: Duration) -> Polled<P> {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Polled {
+            source,
+            interval,
+            pending: VecDeque::new(),
+            health: DeviceHealth::Unknown,
+        }
+    }
+
+    pub fn source(&self) -> &P {
+        &self.source
+    }
+}
+
+#[async_trait]
+impl<P: Poll> Device for Polled<P> {
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                return Ok(Some(Box::new(frame)));
+            }
+            self.interval.tick().await;
+            match self.source.poll().await {
+                Ok(frames) => {
+                    self.health = DeviceHealth::Connected;
+                    self.pending.extend(frames);
+                }
+                Err(e) => {
+                    self.health = DeviceHealth::Failed(e.to_string());
+                    return Err(e.context(DeviceError::PollFailed));
+                }
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    fn address(&self) -> &str {
+        self.source.address()
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
+}
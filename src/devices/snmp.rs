@@ -0,0 +1,499 @@
+//! Network devices queried by SNMP, e.g. the battery charge of a UPS or the temperature of a
+//! switch.
+//!
+//! A configurable set of OIDs is requested with a single GET on every poll, using SNMPv2c with a
+//! community or SNMPv3 with user based security. Each OID is mapped to a field of one
+//! measurement, optionally scaled.
+//!
+//! ```toml
+//! [[inputs]]
+//! type = "snmp"
+//! target = "ups.local"
+//! oids = ["charge=1.3.6.1.2.1.33.1.2.4.0", "load=1.3.6.1.2.1.33.1.4.4.1.5.1"]
+//! username = "monitor"
+//! auth_password = "${SNMP_AUTH_PASSWORD}"
+//! privacy_password = "@file:/run/secrets/snmp_priv"
+//! ```
+//!
+//! In pipeline files the passwords can refer to secrets, see [crate::config::secrets], instead
+//! of being passed on the command line.
+use crate::{
+    measurement::{FieldValue, Measurement, ToMeasurement},
+    output::ToOutput,
+};
+use async_trait::async_trait;
+use serde::Deserialize;
+use snmp2::{
+    v3::{Auth, AuthProtocol, Cipher, Security},
+    AsyncSession, Oid, Value,
+};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+use super::poll::{Poll, Polled};
+
+/// Port of SNMP agents
+pub const DEFAULT_PORT: u16 = 161;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SnmpError {
+    #[error("Invalid OID mapping {0}, expected FIELD=OID or FIELD=OID*SCALE")]
+    InvalidMapping(String),
+    #[error("SNMP request failed: {0}")]
+    Request(String),
+    #[error("No response from {0}")]
+    Timeout(String),
+    #[error("Agent returned error status {0}")]
+    ErrorStatus(u32),
+}
+
+/// Authentication against the agent
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials {
+    V2c {
+        community: String,
+    },
+    V3 {
+        username: String,
+        auth_protocol: AuthProtocol,
+        auth_password: String,
+        /// Password for AES-128 encryption, no encryption if absent
+        privacy_password: Option<String>,
+    },
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Credentials::V2c {
+            community: "public".into(),
+        }
+    }
+}
+
+/// OID read into a field
+#[derive(Debug, Clone, PartialEq)]
+pub struct OidMapping {
+    pub field: String,
+    pub oid: String,
+    /// Factor applied to numeric values, e.g. 0.1 for values in tenths
+    pub scale: Option<f64>,
+}
+
+impl FromStr for OidMapping {
+    type Err = SnmpError;
+
+    /// Parse `FIELD=OID` or `FIELD=OID*SCALE`, e.g. `charge=1.3.6.1.2.1.33.1.2.4.0`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SnmpError::InvalidMapping(s.to_string());
+        let (field, oid) = s.split_once('=').ok_or_else(invalid)?;
+        let (oid, scale) = match oid.split_once('*') {
+            Some((oid, scale)) => (oid, Some(scale.parse().map_err(|_| invalid())?)),
+            None => (oid, None),
+        };
+        let oid = oid.trim_start_matches('.');
+        if field.is_empty() || Oid::from_str(oid).is_err() {
+            return Err(invalid());
+        }
+        Ok(OidMapping {
+            field: field.to_string(),
+            oid: oid.to_string(),
+            scale,
+        })
+    }
+}
+
+/// Agent and the values requested from it
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnmpConfig {
+    /// Host of the agent, with optional port
+    pub target: String,
+    pub credentials: Credentials,
+    pub oids: Vec<OidMapping>,
+    /// Name of the emitted measurement
+    pub measurement: String,
+    pub timeout: Duration,
+}
+
+impl SnmpConfig {
+    pub fn new(target: impl Into<String>, oids: Vec<OidMapping>) -> SnmpConfig {
+        SnmpConfig {
+            target: target.into(),
+            credentials: Credentials::default(),
+            oids,
+            measurement: "snmp".into(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Poll the agent on the given interval
+    pub fn polled(self, period: Duration) -> Polled<Snmp> {
+        Polled::new(Snmp::new(self), period)
+    }
+}
+
+/// Hash of the SNMPv3 authentication
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum AuthHash {
+    Md5,
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl From<AuthHash> for AuthProtocol {
+    fn from(hash: AuthHash) -> Self {
+        match hash {
+            AuthHash::Md5 => AuthProtocol::Md5,
+            AuthHash::Sha1 => AuthProtocol::Sha1,
+            AuthHash::Sha256 => AuthProtocol::Sha256,
+        }
+    }
+}
+
+fn default_community() -> String {
+    "public".into()
+}
+
+fn default_measurement() -> String {
+    "snmp".into()
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+/// Agent polled by a pipeline file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct SnmpPollConfig {
+    /// Host of the agent, with optional port
+    pub target: String,
+    /// Values as `FIELD=OID` or `FIELD=OID*SCALE`
+    pub oids: Vec<String>,
+    /// SNMPv2c community
+    #[serde(default = "default_community")]
+    pub community: String,
+    /// SNMPv3 user, SNMPv2c is used if not set
+    pub username: Option<String>,
+    #[serde(default)]
+    pub auth_protocol: AuthHash,
+    #[serde(default)]
+    pub auth_password: String,
+    /// Password for AES-128 encryption, no encryption if not set
+    pub privacy_password: Option<String>,
+    /// Name of the emitted measurement
+    #[serde(default = "default_measurement")]
+    pub measurement: String,
+    /// Seconds between two requests
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Static tags added to the measurement
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl SnmpPollConfig {
+    /// Settings of the agent
+    pub fn snmp(&self) -> Result<SnmpConfig, SnmpError> {
+        let oids = self
+            .oids
+            .iter()
+            .map(|oid| oid.parse())
+            .collect::<Result<_, _>>()?;
+        let mut config = SnmpConfig::new(&self.target, oids);
+        config.measurement.clone_from(&self.measurement);
+        config.credentials = match &self.username {
+            Some(username) => Credentials::V3 {
+                username: username.clone(),
+                auth_protocol: self.auth_protocol.into(),
+                auth_password: self.auth_password.clone(),
+                privacy_password: self.privacy_password.clone(),
+            },
+            None => Credentials::V2c {
+                community: self.community.clone(),
+            },
+        };
+        Ok(config)
+    }
+
+    /// Poll the agent on the configured interval
+    pub fn polled(&self) -> Result<Polled<Snmp>, SnmpError> {
+        Ok(self.snmp()?.polled(Duration::from_secs(self.interval)))
+    }
+}
+
+/// Convert a value of a response, `None` for missing objects and non-scalar types
+pub fn field_value(value: &Value, scale: Option<f64>) -> Option<FieldValue> {
+    let value = match *value {
+        Value::Boolean(b) => FieldValue::Boolean(b),
+        Value::Integer(i) => FieldValue::Integer(i),
+        Value::Counter32(u) | Value::Unsigned32(u) | Value::Timeticks(u) => {
+            FieldValue::UInteger(u as u64)
+        }
+        Value::Counter64(u) => FieldValue::UInteger(u),
+        Value::OctetString(s) => {
+            let s = String::from_utf8_lossy(s).trim().to_string();
+            // Some agents report numbers as strings, e.g. sensors of the LM-SENSORS-MIB
+            match s.parse::<f64>() {
+                Ok(x) => FieldValue::Float(x),
+                Err(_) => FieldValue::String(s),
+            }
+        }
+        Value::IpAddress(ip) => FieldValue::String(std::net::Ipv4Addr::from(ip).to_string()),
+        _ => return None,
+    };
+    match (scale, value) {
+        (Some(scale), FieldValue::Integer(i)) => Some(FieldValue::Float(i as f64 * scale)),
+        (Some(scale), FieldValue::UInteger(u)) => Some(FieldValue::Float(u as f64 * scale)),
+        (Some(scale), FieldValue::Float(x)) => Some(FieldValue::Float(x * scale)),
+        (_, value) => Some(value),
+    }
+}
+
+/// Values of a single poll
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnmpReading {
+    pub target: String,
+    pub measurement: String,
+    pub values: Vec<(String, FieldValue)>,
+}
+
+impl ToOutput for SnmpReading {}
+
+impl Display for SnmpReading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SNMP {}:", self.target)?;
+        for (field, value) in &self.values {
+            write!(f, " {}={}", field, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl ToMeasurement for SnmpReading {
+    fn to_measurement(&self) -> Measurement {
+        let mut measurement = Measurement::new(&self.measurement).add_tag("agent", &self.target);
+        for (field, value) in &self.values {
+            measurement = measurement.add_field(field, value.clone());
+        }
+        measurement
+    }
+}
+
+/// SNMP agent polled for a fixed set of OIDs
+pub struct Snmp {
+    config: SnmpConfig,
+    session: Option<AsyncSession>,
+}
+
+impl Snmp {
+    pub fn new(config: SnmpConfig) -> Snmp {
+        Snmp {
+            config,
+            session: None,
+        }
+    }
+
+    async fn connect(&self) -> anyhow::Result<AsyncSession> {
+        let target = if self.config.target.contains(':') {
+            self.config.target.clone()
+        } else {
+            format!("{}:{}", self.config.target, DEFAULT_PORT)
+        };
+        let session = match &self.config.credentials {
+            Credentials::V2c { community } => {
+                AsyncSession::new_v2c(target, community.as_bytes(), 0).await?
+            }
+            Credentials::V3 {
+                username,
+                auth_protocol,
+                auth_password,
+                privacy_password,
+            } => {
+                let auth = match privacy_password {
+                    Some(password) => Auth::AuthPriv {
+                        cipher: Cipher::Aes128,
+                        privacy_password: password.as_bytes().to_vec(),
+                    },
+                    None => Auth::AuthNoPriv,
+                };
+                let security = Security::new(username.as_bytes(), auth_password.as_bytes())
+                    .with_auth_protocol(*auth_protocol)
+                    .with_auth(auth);
+                let mut session = AsyncSession::new_v3(target, 0, security).await?;
+                // Discover the engine id of the agent
+                session
+                    .init()
+                    .await
+                    .map_err(|e| SnmpError::Request(format!("{:?}", e)))?;
+                session
+            }
+        };
+        Ok(session)
+    }
+
+    async fn request(&mut self) -> anyhow::Result<Vec<(String, FieldValue)>> {
+        let oids = self
+            .config
+            .oids
+            .iter()
+            .map(|mapping| Oid::from_str(&mapping.oid))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| SnmpError::Request(format!("{:?}", e)))?;
+        let oid_refs: Vec<&Oid> = oids.iter().collect();
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => self.session.insert(self.connect().await?),
+        };
+        let response = tokio::time::timeout(self.config.timeout, session.get_many(&oid_refs))
+            .await
+            .map_err(|_| SnmpError::Timeout(self.config.target.clone()))?
+            .map_err(|e| SnmpError::Request(format!("{:?}", e)))?;
+        if response.error_status != 0 {
+            return Err(SnmpError::ErrorStatus(response.error_status).into());
+        }
+        // Variables are returned in the order requested
+        Ok(self
+            .config
+            .oids
+            .iter()
+            .zip(response.varbinds)
+            .filter_map(|(mapping, (_, value))| {
+                Some((mapping.field.clone(), field_value(&value, mapping.scale)?))
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Poll for Snmp {
+    type Frame = SnmpReading;
+
+    async fn poll(&mut self) -> anyhow::Result<Vec<SnmpReading>> {
+        let values = match self.request().await {
+            Ok(values) => values,
+            Err(e) => {
+                // Start over with a new session, e.g. after the agent rebooted
+                self.session = None;
+                return Err(e);
+            }
+        };
+        if values.is_empty() {
+            return Ok(vec![]);
+        }
+        Ok(vec![SnmpReading {
+            target: self.config.target.clone(),
+            measurement: self.config.measurement.clone(),
+            values,
+        }])
+    }
+
+    fn name(&self) -> &str {
+        "snmp"
+    }
+
+    fn address(&self) -> &str {
+        &self.config.target
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{field_value, Credentials, OidMapping, SnmpError, SnmpPollConfig};
+    use crate::measurement::FieldValue;
+    use snmp2::{v3::AuthProtocol, Value};
+
+    #[test]
+    fn test_parse_mapping() {
+        assert_eq!(
+            "charge=1.3.6.1.2.1.33.1.2.4.0".parse(),
+            Ok(OidMapping {
+                field: "charge".into(),
+                oid: "1.3.6.1.2.1.33.1.2.4.0".into(),
+                scale: None,
+            })
+        );
+        assert_eq!(
+            "voltage=.1.3.6.1.2.1.33.1.2.5.0*0.1"
+                .parse::<OidMapping>()
+                .unwrap(),
+            OidMapping {
+                field: "voltage".into(),
+                oid: "1.3.6.1.2.1.33.1.2.5.0".into(),
+                scale: Some(0.1),
+            }
+        );
+        for invalid in ["charge", "=1.3.6", "charge=1.3.x", "charge=1.3.6*a"] {
+            assert_eq!(
+                invalid.parse::<OidMapping>(),
+                Err(SnmpError::InvalidMapping(invalid.into()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_field_values() {
+        assert_eq!(
+            field_value(&Value::Integer(-3), None),
+            Some(FieldValue::Integer(-3))
+        );
+        assert_eq!(
+            field_value(&Value::Counter64(7), None),
+            Some(FieldValue::UInteger(7))
+        );
+        assert_eq!(
+            field_value(&Value::Unsigned32(2305), Some(0.1)),
+            Some(FieldValue::Float(230.5))
+        );
+        assert_eq!(
+            field_value(&Value::OctetString(b"42.5 "), None),
+            Some(FieldValue::Float(42.5))
+        );
+        assert_eq!(
+            field_value(&Value::OctetString(b"online"), Some(2.)),
+            Some(FieldValue::String("online".into()))
+        );
+        assert_eq!(field_value(&Value::NoSuchInstance, None), None);
+    }
+
+    #[test]
+    fn test_poll_config() {
+        let config: SnmpPollConfig = toml::from_str(
+            r#"
+            target = "ups.local"
+            oids = ["charge=1.3.6.1.2.1.33.1.2.4.0", "load=1.3.6.1.2.1.33.1.4.4.1.5.1*0.1"]
+            username = "monitor"
+            auth_protocol = "sha256"
+            auth_password = "secret"
+            "#,
+        )
+        .unwrap();
+        let snmp = config.snmp().unwrap();
+        assert_eq!(snmp.oids.len(), 2);
+        assert_eq!(snmp.oids[1].scale, Some(0.1));
+        assert_eq!(
+            snmp.credentials,
+            Credentials::V3 {
+                username: "monitor".into(),
+                auth_protocol: AuthProtocol::Sha256,
+                auth_password: "secret".into(),
+                privacy_password: None,
+            }
+        );
+
+        let config = SnmpPollConfig {
+            oids: vec!["charge".into()],
+            ..config
+        };
+        assert_eq!(
+            config.snmp(),
+            Err(SnmpError::InvalidMapping("charge".into()))
+        );
+    }
+}
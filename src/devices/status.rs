@@ -0,0 +1,170 @@
+//! Health of battery powered radio sensors, as seen by their receiver.
+//!
+//! Receivers of LaCrosse IT+ sensors emit a `sensor_status` measurement after a reading if the
+//! battery state of the sensor changed, otherwise at most once per [STATUS_INTERVAL]. It is
+//! tagged with the `sensorId` of the reading and carries the battery state as `battery`, one of
+//! `ok`, `low` and `new`, the time of the reading as `last_seen` in seconds since the epoch and
+//! the signal strength as `rssi` if reported.
+use crate::{
+    measurement::{Measurement, ToMeasurement, Unit},
+    output::ToOutput,
+    schema::{FieldType, Schema},
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+};
+
+/// Interval at which the status of a sensor is repeated while its battery state is unchanged
+pub const STATUS_INTERVAL: TimeDelta = TimeDelta::minutes(5);
+
+/// Battery state reported by a sensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Battery {
+    Ok,
+    /// Battery needs to be replaced soon
+    Low,
+    /// Battery was replaced recently, the sensor sends a new id
+    New,
+}
+
+impl Battery {
+    /// State of the weak and new battery flags of a sensor, a weak battery takes precedence
+    pub fn from_flags(weak: bool, new: bool) -> Battery {
+        match (weak, new) {
+            (true, _) => Battery::Low,
+            (false, true) => Battery::New,
+            (false, false) => Battery::Ok,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Battery::Ok => "ok",
+            Battery::Low => "low",
+            Battery::New => "new",
+        }
+    }
+}
+
+/// Status of a sensor at its last reading
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorStatus {
+    pub sensor_id: String,
+    pub battery: Battery,
+    /// Received signal strength in dBm
+    pub rssi: Option<f64>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Frames of sensors reporting their status
+pub trait ToSensorStatus {
+    /// Status of the sending sensor if received at `time`, `None` if the frame has none
+    fn sensor_status(&self, time: DateTime<Utc>) -> Option<SensorStatus>;
+}
+
+impl ToOutput for SensorStatus {}
+
+/// Passes the status of a sensor if its battery state changed or [STATUS_INTERVAL] passed since
+/// its status was last passed
+#[derive(Debug, Default)]
+pub struct StatusFilter {
+    /// Battery state and time of the last status passed per sensor
+    last: HashMap<String, (Battery, DateTime<Utc>)>,
+}
+
+impl StatusFilter {
+    pub fn update(&mut self, status: SensorStatus) -> Option<SensorStatus> {
+        if let Some((battery, time)) = self.last.get(&status.sensor_id) {
+            if *battery == status.battery && status.last_seen - *time < STATUS_INTERVAL {
+                return None;
+            }
+        }
+        self.last
+            .insert(status.sensor_id.clone(), (status.battery, status.last_seen));
+        Some(status)
+    }
+}
+
+impl Display for SensorStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Sensor {:2}: battery {}",
+            self.sensor_id,
+            self.battery.as_str()
+        )?;
+        if let Some(rssi) = self.rssi {
+            write!(f, ", RSSI {} dBm", rssi)?;
+        }
+        Ok(())
+    }
+}
+
+impl ToMeasurement for SensorStatus {
+    fn to_measurement(&self) -> Measurement {
+        let measurement = Measurement::new("sensor_status")
+            .add_tag("sensorId", &self.sensor_id)
+            .add_field("battery", self.battery.as_str())
+            .add_field("last_seen", self.last_seen.timestamp())
+            .add_time(Some(self.last_seen));
+        match self.rssi {
+            Some(rssi) => measurement
+                .add_field("rssi", rssi)
+                .add_field_meta("rssi", Unit::Dbm),
+            None => measurement,
+        }
+    }
+}
+
+/// Schema of the measurements of [SensorStatus]
+pub fn schemas() -> Vec<Schema> {
+    vec![Schema::new("sensor_status")
+        .add_tag("sensorId")
+        .add_field("battery", FieldType::String, None)
+        .add_field("last_seen", FieldType::Integer, None)
+        .add_optional_field("rssi", FieldType::Float, Unit::Dbm)]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Battery, SensorStatus, StatusFilter, STATUS_INTERVAL};
+    use crate::output::influx::ToLineProtocol;
+    use chrono::{DateTime, TimeDelta};
+
+    #[test]
+    fn test_status_to_lineprotocol() {
+        assert_eq!(Battery::from_flags(true, true), Battery::Low);
+        assert_eq!(Battery::from_flags(false, true), Battery::New);
+        let status = SensorStatus {
+            sensor_id: "50".into(),
+            battery: Battery::from_flags(false, false),
+            rssi: Some(-72.),
+            last_seen: DateTime::from_timestamp(1700000000, 0).unwrap(),
+        };
+        assert_eq!(
+            status.to_lineprotocol().to_string(),
+            "sensor_status,sensorId=50 battery=\"ok\",last_seen=1700000000i,rssi=-72 1700000000000000000"
+        );
+    }
+
+    #[test]
+    fn test_filter_passes_changes_and_intervals() {
+        let time = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let status = |id: &str, battery, seconds| SensorStatus {
+            sensor_id: id.into(),
+            battery,
+            rssi: None,
+            last_seen: time + TimeDelta::seconds(seconds),
+        };
+        let mut filter = StatusFilter::default();
+        assert!(filter.update(status("50", Battery::Ok, 0)).is_some());
+        assert!(filter.update(status("50", Battery::Ok, 4)).is_none());
+        assert!(filter.update(status("51", Battery::Ok, 4)).is_some());
+        assert!(filter.update(status("50", Battery::Low, 8)).is_some());
+        assert!(filter.update(status("50", Battery::Low, 12)).is_none());
+        let later = STATUS_INTERVAL.num_seconds() + 8;
+        assert!(filter.update(status("50", Battery::Low, later)).is_some());
+    }
+}
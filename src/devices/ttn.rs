@@ -0,0 +1,374 @@
+//! LoRaWAN end devices of an application on The Things Network (TTN) or The Things Stack v3.
+//!
+//! Uplinks are received from the MQTT integration of the network server. The values of an
+//! uplink are taken from the payload decoded by the application's payload formatter, or decoded
+//! locally from the raw payload in Cayenne LPP. Radio metrics of the best gateway are added to
+//! each measurement.
+use crate::{
+    input::cayenne,
+    measurement::{FieldValue, Measurement, ToMeasurement},
+    output::ToOutput,
+    tls::TlsConfig,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use thiserror::Error;
+
+use super::{
+    http::field_value,
+    mqtt::{MqttConfig, MqttInput, Subscription},
+};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum TtnError {
+    #[error("Invalid JSON: {0}")]
+    Json(String),
+    #[error("Missing or invalid {0} in uplink")]
+    MissingField(&'static str),
+    #[error("Invalid payload: {0}")]
+    Payload(String),
+}
+
+/// Source of the values of an uplink
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadFormat {
+    /// Scalar members of `decoded_payload`, as produced by the application's payload formatter
+    #[default]
+    Decoded,
+    /// Raw payload decoded as Cayenne LPP
+    CayenneLpp,
+}
+
+fn default_tenant() -> String {
+    "ttn".into()
+}
+
+fn default_measurement() -> String {
+    "lorawan".into()
+}
+
+/// Application to receive uplinks from
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct TtnConfig {
+    /// MQTT server of the cluster, e.g. `eu1.cloud.thethings.network`
+    pub broker: String,
+    pub application_id: String,
+    /// `ttn` for The Things Network
+    #[serde(default = "default_tenant")]
+    pub tenant_id: String,
+    /// API key with the right to read application traffic
+    pub api_key: String,
+    /// Connect on port 8883 with TLS, e.g. `tls = {}` for the public clusters
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub payload_format: PayloadFormat,
+    /// Name of the emitted measurements
+    #[serde(default = "default_measurement")]
+    pub measurement: String,
+    /// Tags added to the measurements when opened as input of a pipeline file
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl TtnConfig {
+    pub fn new(
+        broker: impl Into<String>,
+        application_id: impl Into<String>,
+        api_key: impl Into<String>,
+    ) -> TtnConfig {
+        TtnConfig {
+            broker: broker.into(),
+            application_id: application_id.into(),
+            tenant_id: default_tenant(),
+            api_key: api_key.into(),
+            tls: None,
+            payload_format: PayloadFormat::default(),
+            measurement: default_measurement(),
+            tags: BTreeMap::new(),
+        }
+    }
+
+    /// Subscribe to the uplinks of all end devices of the application
+    pub fn input(self) -> anyhow::Result<MqttInput<Ttn>> {
+        let mut mqtt = MqttConfig::new(&self.broker);
+        mqtt.username = Some(format!("{}@{}", self.application_id, self.tenant_id));
+        mqtt.password = Some(self.api_key.clone());
+        mqtt.tls = self.tls.clone();
+        MqttInput::new(&mqtt, Ttn { config: self })
+    }
+}
+
+/// Decoded uplink of an end device
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uplink {
+    pub measurement: String,
+    pub application_id: String,
+    pub device_id: String,
+    pub dev_eui: Option<String>,
+    pub f_port: Option<u64>,
+    pub f_cnt: Option<u64>,
+    pub received_at: Option<DateTime<Utc>>,
+    /// Signal strength at the best gateway in dBm
+    pub rssi: Option<f64>,
+    /// Signal to noise ratio at the best gateway in dB
+    pub snr: Option<f64>,
+    pub values: Vec<(String, FieldValue)>,
+}
+
+fn str_field(message: &Value, pointer: &str, name: &'static str) -> Result<String, TtnError> {
+    message
+        .pointer(pointer)
+        .and_then(Value::as_str)
+        .map(String::from)
+        .ok_or(TtnError::MissingField(name))
+}
+
+impl Uplink {
+    /// Parse the JSON envelope of an uplink message
+    pub fn parse(
+        data: &[u8],
+        format: PayloadFormat,
+        measurement: &str,
+    ) -> Result<Uplink, TtnError> {
+        let message: Value =
+            serde_json::from_slice(data).map_err(|e| TtnError::Json(e.to_string()))?;
+        let uplink = message
+            .get("uplink_message")
+            .ok_or(TtnError::MissingField("uplink_message"))?;
+        let values = match format {
+            PayloadFormat::Decoded => uplink
+                .get("decoded_payload")
+                .and_then(Value::as_object)
+                .map(|payload| {
+                    payload
+                        .iter()
+                        .filter_map(|(key, value)| Some((key.clone(), field_value(value)?)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            PayloadFormat::CayenneLpp => match uplink.get("frm_payload").and_then(Value::as_str) {
+                Some(payload) => {
+                    let payload = STANDARD
+                        .decode(payload)
+                        .map_err(|e| TtnError::Payload(e.to_string()))?;
+                    cayenne::decode(&payload).map_err(|e| TtnError::Payload(e.to_string()))?
+                }
+                None => vec![],
+            },
+        };
+        // Gateway with the strongest signal
+        let best = uplink
+            .get("rx_metadata")
+            .and_then(Value::as_array)
+            .and_then(|gateways| {
+                gateways.iter().max_by(|a, b| {
+                    let rssi = |gateway: &Value| gateway["rssi"].as_f64().unwrap_or(f64::MIN);
+                    rssi(a).total_cmp(&rssi(b))
+                })
+            });
+        Ok(Uplink {
+            measurement: measurement.to_string(),
+            application_id: str_field(
+                &message,
+                "/end_device_ids/application_ids/application_id",
+                "application_id",
+            )?,
+            device_id: str_field(&message, "/end_device_ids/device_id", "device_id")?,
+            dev_eui: str_field(&message, "/end_device_ids/dev_eui", "dev_eui").ok(),
+            f_port: uplink["f_port"].as_u64(),
+            f_cnt: uplink["f_cnt"].as_u64(),
+            received_at: message["received_at"]
+                .as_str()
+                .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                .map(|time| time.with_timezone(&Utc)),
+            rssi: best.and_then(|gateway| gateway["rssi"].as_f64()),
+            snr: best.and_then(|gateway| gateway["snr"].as_f64()),
+            values,
+        })
+    }
+}
+
+impl ToOutput for Uplink {}
+
+impl Display for Uplink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LoRaWAN {}/{}:", self.application_id, self.device_id)?;
+        for (field, value) in &self.values {
+            write!(f, " {}={}", field, value)?;
+        }
+        if let Some(rssi) = self.rssi {
+            write!(f, " RSSI {} dBm", rssi)?;
+        }
+        Ok(())
+    }
+}
+
+impl ToMeasurement for Uplink {
+    fn to_measurement(&self) -> Measurement {
+        let mut measurement = Measurement::new(&self.measurement)
+            .add_tag("application", &self.application_id)
+            .add_tag("device", &self.device_id);
+        if let Some(dev_eui) = &self.dev_eui {
+            measurement = measurement.add_tag("devEui", dev_eui);
+        }
+        for (field, value) in &self.values {
+            measurement = measurement.add_field(field, value.clone());
+        }
+        if let Some(f_cnt) = self.f_cnt {
+            measurement = measurement.add_field("f_cnt", f_cnt);
+        }
+        if let Some(rssi) = self.rssi {
+            measurement = measurement.add_field("rssi", rssi);
+        }
+        if let Some(snr) = self.snr {
+            measurement = measurement.add_field("snr", snr);
+        }
+        measurement.add_time(self.received_at)
+    }
+}
+
+/// Uplink subscription of an application
+pub struct Ttn {
+    config: TtnConfig,
+}
+
+impl Subscription for Ttn {
+    type Frame = Uplink;
+
+    fn topics(&self) -> Vec<String> {
+        vec![format!(
+            "v3/{}@{}/devices/+/up",
+            self.config.application_id, self.config.tenant_id
+        )]
+    }
+
+    fn decode(&mut self, _topic: &str, payload: &[u8]) -> anyhow::Result<Vec<Uplink>> {
+        Ok(vec![Uplink::parse(
+            payload,
+            self.config.payload_format,
+            &self.config.measurement,
+        )?])
+    }
+
+    fn name(&self) -> &str {
+        "ttn"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PayloadFormat, TtnConfig, TtnError, Uplink};
+    use crate::{
+        devices::{mqtt::serve_once, Device},
+        measurement::{FieldValue, ToMeasurement},
+    };
+    use chrono::DateTime;
+
+    const UPLINK: &str = r#"{
+        "end_device_ids": {
+            "device_id": "eui-70b3d57ed0000001",
+            "application_ids": {"application_id": "garden"},
+            "dev_eui": "70B3D57ED0000001",
+            "dev_addr": "260B1234"
+        },
+        "received_at": "2024-03-01T12:00:00.123456789Z",
+        "uplink_message": {
+            "f_port": 1,
+            "f_cnt": 42,
+            "frm_payload": "AWcA6wJoYA==",
+            "decoded_payload": {"temperature": 23.5, "humidity": 48, "status": {"ok": true}},
+            "rx_metadata": [
+                {"gateway_ids": {"gateway_id": "gw-1"}, "rssi": -101, "snr": 2.5},
+                {"gateway_ids": {"gateway_id": "gw-2"}, "rssi": -87, "snr": 7.25}
+            ]
+        }
+    }"#;
+
+    #[test]
+    fn test_parse_decoded_payload() {
+        let uplink = Uplink::parse(UPLINK.as_bytes(), PayloadFormat::Decoded, "lorawan").unwrap();
+        assert_eq!(uplink.device_id, "eui-70b3d57ed0000001");
+        assert_eq!(uplink.f_cnt, Some(42));
+        assert_eq!(
+            uplink.received_at,
+            DateTime::from_timestamp_nanos(1709294400123456789).into()
+        );
+        let measurement = uplink.to_measurement();
+        assert_eq!(measurement.tag("application"), Some("garden"));
+        assert_eq!(measurement.tag("devEui"), Some("70B3D57ED0000001"));
+        assert_eq!(
+            measurement.field("humidity"),
+            Some(&FieldValue::Integer(48))
+        );
+        assert_eq!(measurement.field("status"), None);
+        assert_eq!(measurement.field("rssi"), Some(&FieldValue::Float(-87.)));
+        assert_eq!(measurement.field("snr"), Some(&FieldValue::Float(7.25)));
+    }
+
+    #[test]
+    fn test_parse_cayenne_payload() {
+        let uplink =
+            Uplink::parse(UPLINK.as_bytes(), PayloadFormat::CayenneLpp, "lorawan").unwrap();
+        assert_eq!(
+            uplink.values,
+            [
+                ("temperature_1".into(), FieldValue::Float(23.5)),
+                ("humidity_2".into(), FieldValue::Float(48.)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(
+            Uplink::parse(
+                br#"{"end_device_ids":{}}"#,
+                PayloadFormat::Decoded,
+                "lorawan"
+            ),
+            Err(TtnError::MissingField("uplink_message"))
+        );
+        assert_eq!(
+            Uplink::parse(
+                br#"{"end_device_ids":{},"uplink_message":{}}"#,
+                PayloadFormat::Decoded,
+                "lorawan"
+            ),
+            Err(TtnError::MissingField("application_id"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_receive_uplink() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TtnConfig::new(
+            listener.local_addr().unwrap().to_string(),
+            "garden",
+            "NNSXS.KEY",
+        );
+        let broker = tokio::spawn(serve_once(
+            listener,
+            vec![(
+                "v3/garden@ttn/devices/eui-70b3d57ed0000001/up".into(),
+                UPLINK.as_bytes().to_vec(),
+            )],
+        ));
+
+        let mut input = config.input().unwrap();
+        let frame = input.read_frame().await.unwrap().unwrap();
+        assert_eq!(
+            frame.to_measurement().tag("device"),
+            Some("eui-70b3d57ed0000001")
+        );
+        drop(input);
+        assert_eq!(broker.await.unwrap(), ["v3/garden@ttn/devices/+/up"]);
+    }
+}
@@ -0,0 +1,399 @@
+//! WeatherFlow Tempest weather stations, received from the hub's local UDP broadcast.
+//!
+//! The hub broadcasts JSON messages on port 50222 to the LAN, no cloud access is required. Full
+//! observations (`obs_st`), wind updates every three seconds (`rapid_wind`) and the hub's own
+//! status (`hub_status`) are decoded, all other message types are skipped.
+use crate::{
+    measurement::{Measurement, ToMeasurement},
+    output::ToOutput,
+    schema::{FieldType, Schema},
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::fmt::{self, Display};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+use super::{Device, DeviceHealth};
+
+/// Address the hub broadcasts to
+pub const DEFAULT_ADDRESS: &str = "0.0.0.0:50222";
+
+/// Fields of an `obs_st` observation in order, `None` for values not emitted
+const OBS_ST_FIELDS: [Option<&str>; 18] = [
+    None,
+    Some("wind_lull"),
+    Some("wind_avg"),
+    Some("wind_gust"),
+    Some("wind_direction"),
+    None,
+    Some("station_pressure"),
+    Some("air_temperature"),
+    Some("relative_humidity"),
+    Some("illuminance"),
+    Some("uv_index"),
+    Some("solar_radiation"),
+    Some("rain_accumulated"),
+    Some("precipitation_type"),
+    Some("lightning_distance"),
+    Some("lightning_count"),
+    Some("battery"),
+    None,
+];
+
+#[derive(Error, Debug, PartialEq)]
+pub enum TempestError {
+    #[error("Invalid JSON: {0}")]
+    Json(String),
+    #[error("Missing or invalid {0} in message")]
+    MissingField(&'static str),
+}
+
+/// Decoded broadcast message
+#[derive(Debug, Clone, PartialEq)]
+pub enum TempestMessage {
+    /// Observation of a Tempest station, units as sent: m/s, °, hPa, °C, %, lux, W/m², mm, km, V
+    Observation {
+        serial_number: String,
+        hub_sn: String,
+        time: DateTime<Utc>,
+        values: Vec<(&'static str, f64)>,
+    },
+    /// Instantaneous wind
+    RapidWind {
+        serial_number: String,
+        hub_sn: String,
+        time: DateTime<Utc>,
+        /// Speed in m/s
+        speed: f64,
+        /// Direction in degrees
+        direction: f64,
+    },
+    HubStatus {
+        serial_number: String,
+        time: DateTime<Utc>,
+        /// Uptime in s
+        uptime: u64,
+        /// Signal strength of the WiFi connection in dBm
+        rssi: i64,
+    },
+    /// Any other message type, e.g. rain start or lightning events
+    Other(String),
+}
+
+fn str_field(message: &Value, name: &'static str) -> Result<String, TempestError> {
+    message[name]
+        .as_str()
+        .map(String::from)
+        .ok_or(TempestError::MissingField(name))
+}
+
+fn time_field(value: &Value, name: &'static str) -> Result<DateTime<Utc>, TempestError> {
+    value
+        .as_i64()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .ok_or(TempestError::MissingField(name))
+}
+
+impl TempestMessage {
+    pub fn parse(data: &[u8]) -> Result<TempestMessage, TempestError> {
+        let message: Value =
+            serde_json::from_slice(data).map_err(|e| TempestError::Json(e.to_string()))?;
+        let message_type = str_field(&message, "type")?;
+        match message_type.as_str() {
+            "obs_st" => {
+                // A message may carry several observations, the last is the most recent
+                let obs = message["obs"]
+                    .as_array()
+                    .and_then(|obs| obs.last())
+                    .and_then(Value::as_array)
+                    .ok_or(TempestError::MissingField("obs"))?;
+                let values = OBS_ST_FIELDS
+                    .iter()
+                    .zip(obs)
+                    .filter_map(|(name, value)| Some(((*name)?, value.as_f64()?)))
+                    .collect();
+                Ok(TempestMessage::Observation {
+                    serial_number: str_field(&message, "serial_number")?,
+                    hub_sn: str_field(&message, "hub_sn")?,
+                    time: time_field(&obs[0], "obs")?,
+                    values,
+                })
+            }
+            "rapid_wind" => {
+                let ob = message["ob"]
+                    .as_array()
+                    .filter(|ob| ob.len() >= 3)
+                    .ok_or(TempestError::MissingField("ob"))?;
+                Ok(TempestMessage::RapidWind {
+                    serial_number: str_field(&message, "serial_number")?,
+                    hub_sn: str_field(&message, "hub_sn")?,
+                    time: time_field(&ob[0], "ob")?,
+                    speed: ob[1].as_f64().ok_or(TempestError::MissingField("ob"))?,
+                    direction: ob[2].as_f64().ok_or(TempestError::MissingField("ob"))?,
+                })
+            }
+            "hub_status" => Ok(TempestMessage::HubStatus {
+                serial_number: str_field(&message, "serial_number")?,
+                time: time_field(&message["timestamp"], "timestamp")?,
+                uptime: message["uptime"]
+                    .as_u64()
+                    .ok_or(TempestError::MissingField("uptime"))?,
+                rssi: message["rssi"]
+                    .as_i64()
+                    .ok_or(TempestError::MissingField("rssi"))?,
+            }),
+            _ => Ok(TempestMessage::Other(message_type)),
+        }
+    }
+}
+
+impl ToOutput for TempestMessage {}
+
+impl Display for TempestMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TempestMessage::Observation {
+                serial_number,
+                values,
+                ..
+            } => {
+                write!(f, "Tempest {}:", serial_number)?;
+                for (name, value) in values {
+                    write!(f, " {}={}", name, value)?;
+                }
+                Ok(())
+            }
+            TempestMessage::RapidWind {
+                serial_number,
+                speed,
+                direction,
+                ..
+            } => write!(
+                f,
+                "Tempest {}: Wind {} m/s from {}°",
+                serial_number, speed, direction
+            ),
+            TempestMessage::HubStatus {
+                serial_number,
+                uptime,
+                rssi,
+                ..
+            } => write!(
+                f,
+                "Hub {}: Uptime {} s RSSI {}",
+                serial_number, uptime, rssi
+            ),
+            TempestMessage::Other(message_type) => write!(f, "Message {}", message_type),
+        }
+    }
+}
+
+impl ToMeasurement for TempestMessage {
+    fn to_measurement(&self) -> Measurement {
+        match self {
+            TempestMessage::Observation {
+                serial_number,
+                hub_sn,
+                time,
+                values,
+            } => {
+                let mut measurement = Measurement::new("weather")
+                    .add_tag("serialNumber", serial_number)
+                    .add_tag("hub", hub_sn)
+                    .add_time(Some(*time));
+                for (name, value) in values {
+                    measurement = measurement.add_field(*name, *value);
+                }
+                measurement
+            }
+            TempestMessage::RapidWind {
+                serial_number,
+                hub_sn,
+                time,
+                speed,
+                direction,
+            } => Measurement::new("wind")
+                .add_tag("serialNumber", serial_number)
+                .add_tag("hub", hub_sn)
+                .add_field("speed", *speed)
+                .add_field("direction", *direction)
+                .add_time(Some(*time)),
+            TempestMessage::HubStatus {
+                serial_number,
+                time,
+                uptime,
+                rssi,
+            } => Measurement::new("hubStatus")
+                .add_tag("serialNumber", serial_number)
+                .add_field("uptime", *uptime)
+                .add_field("rssi", *rssi)
+                .add_time(Some(*time)),
+            TempestMessage::Other(message_type) => {
+                Measurement::new("tempest").add_tag("type", message_type)
+            }
+        }
+    }
+}
+
+/// Schemas of the measurements of [TempestMessage]
+pub fn schemas() -> Vec<Schema> {
+    let observation = Schema::new("weather")
+        .add_tag("serialNumber")
+        .add_tag("hub");
+    vec![
+        OBS_ST_FIELDS
+            .iter()
+            .flatten()
+            .fold(observation, |schema, name| {
+                schema.add_optional_field(*name, FieldType::Float, None)
+            }),
+        Schema::new("wind")
+            .add_tag("serialNumber")
+            .add_tag("hub")
+            .add_field("speed", FieldType::Float, None)
+            .add_field("direction", FieldType::Float, None),
+        Schema::new("hubStatus")
+            .add_tag("serialNumber")
+            .add_field("uptime", FieldType::UInteger, None)
+            .add_field("rssi", FieldType::Integer, None),
+        Schema::new("tempest").add_tag("type"),
+    ]
+}
+
+/// Listener for hub broadcasts
+pub struct WeatherFlow {
+    socket: UdpSocket,
+    address: String,
+    health: DeviceHealth,
+}
+
+impl WeatherFlow {
+    /// Listen on `address`, usually [DEFAULT_ADDRESS]. Must be called within a Tokio runtime.
+    pub fn bind(address: &str) -> anyhow::Result<Self> {
+        let socket = std::net::UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket)?;
+        Ok(WeatherFlow {
+            socket,
+            address: address.to_string(),
+            health: DeviceHealth::Connected,
+        })
+    }
+}
+
+#[async_trait]
+impl Device for WeatherFlow {
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        let mut buffer = [0; 2048];
+        loop {
+            let res = self.socket.recv(&mut buffer).await;
+            self.health = match &res {
+                Ok(_) => DeviceHealth::Connected,
+                Err(e) => DeviceHealth::Failed(e.to_string()),
+            };
+            let len = res?;
+            match TempestMessage::parse(&buffer[..len]) {
+                Ok(TempestMessage::Other(_)) => continue,
+                Ok(message) => return Ok(Some(Box::new(message))),
+                // Anyone on the LAN may send to the port, ignore what is not a Tempest message
+                Err(_) => continue,
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "weatherflow"
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TempestError, TempestMessage, WeatherFlow};
+    use crate::{devices::Device, measurement::ToMeasurement};
+    use chrono::DateTime;
+
+    const OBS_ST: &str = r#"{"serial_number":"ST-00000512","type":"obs_st","hub_sn":"HB-00013030",
+        "obs":[[1588948614,0.18,0.22,0.27,144,6,1017.57,22.37,50.26,328,0.03,3,0.000000,0,0,0,2.410,1]],
+        "firmware_revision":129}"#;
+
+    #[test]
+    fn test_parse_observation() {
+        let TempestMessage::Observation {
+            serial_number,
+            hub_sn,
+            time,
+            values,
+        } = TempestMessage::parse(OBS_ST.as_bytes()).unwrap()
+        else {
+            panic!("not an observation")
+        };
+        assert_eq!(serial_number, "ST-00000512");
+        assert_eq!(hub_sn, "HB-00013030");
+        assert_eq!(time, DateTime::from_timestamp(1588948614, 0).unwrap());
+        assert_eq!(values.len(), 15);
+        assert!(values.contains(&("air_temperature", 22.37)));
+        assert!(values.contains(&("station_pressure", 1017.57)));
+        assert!(values.contains(&("battery", 2.41)));
+    }
+
+    #[test]
+    fn test_parse_rapid_wind_and_hub_status() {
+        let wind = r#"{"serial_number":"SK-00008453","type":"rapid_wind","hub_sn":"HB-00000001",
+            "ob":[1493322445,2.3,128]}"#;
+        let measurement = TempestMessage::parse(wind.as_bytes())
+            .unwrap()
+            .to_measurement();
+        assert_eq!(
+            measurement.to_string(),
+            "wind serialNumber=SK-00008453 hub=HB-00000001: speed=2.3 direction=128 \
+             @ 2017-04-27T19:47:25+00:00"
+        );
+
+        let status = r#"{"serial_number":"HB-00000001","type":"hub_status","firmware_revision":"35",
+            "uptime":1670133,"rssi":-62,"timestamp":1495724691,"reset_flags":"BOR,PIN,POR"}"#;
+        let measurement = TempestMessage::parse(status.as_bytes())
+            .unwrap()
+            .to_measurement();
+        assert_eq!(measurement.field("rssi").unwrap().to_string(), "-62");
+    }
+
+    #[test]
+    fn test_parse_other_and_invalid() {
+        let event = r#"{"serial_number":"SK-00008453","type":"evt_precip","hub_sn":"HB-00000001",
+            "evt":[1493322445]}"#;
+        assert_eq!(
+            TempestMessage::parse(event.as_bytes()),
+            Ok(TempestMessage::Other("evt_precip".into()))
+        );
+        assert_eq!(
+            TempestMessage::parse(br#"{"type":"obs_st","obs":[]}"#),
+            Err(TempestError::MissingField("obs"))
+        );
+        assert!(matches!(
+            TempestMessage::parse(b"garbage"),
+            Err(TempestError::Json(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_receive_broadcast() {
+        let mut device = WeatherFlow::bind("127.0.0.1:0").unwrap();
+        let target = device.socket.local_addr().unwrap();
+        let sender = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.send_to(b"garbage", target).await.unwrap();
+        sender.send_to(OBS_ST.as_bytes(), target).await.unwrap();
+
+        let frame = device.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame.to_measurement().name, "weather");
+    }
+}
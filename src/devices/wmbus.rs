@@ -0,0 +1,836 @@
+//! Wireless M-Bus receivers (Amber AMB8465, IMST iM871A).
+//!
+//! Both receivers decode the radio layer (mode T or C) and forward the link layer telegram
+//! without CRCs over the serial interface, wrapped in their own framing. Telegrams encrypted
+//! with security mode 5 (AES-128-CBC) are decrypted if a key for the meter is configured.
+//!
+//! The IMST receiver is switched to the configured mode on startup. Amber receivers keep the
+//! mode stored in their configuration.
+#[cfg(not(target_arch = "wasm32"))]
+use crate::FramedListener;
+use crate::{
+    error::*,
+    input::{checksum::xor8, decode_hex},
+    measurement::{Measurement, ToMeasurement},
+    output::ToOutput,
+    schema::{FieldType, Schema},
+    EncodableFrame, Frame,
+};
+use aes::cipher::{block_padding::NoPadding, BlockDecryptMut, KeyIvInit};
+#[cfg(not(target_arch = "wasm32"))]
+use async_trait::async_trait;
+use bytes::{Buf, BytesMut};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_serial::SerialStream;
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::{open_serial, Device, DeviceHealth};
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Baud rate of both receivers
+const BAUD_RATE: u32 = 57600;
+
+/// Receiver hardware
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum Receiver {
+    #[default]
+    Imst,
+    Amber,
+}
+
+/// Radio mode of the receiver
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Frequent transmit, 868.95 MHz
+    #[default]
+    T1,
+    /// Compact mode, 868.95 MHz
+    C1,
+}
+
+impl Mode {
+    /// Link mode id in the configuration of the IMST receiver
+    #[cfg(not(target_arch = "wasm32"))]
+    fn imst_link_mode(&self) -> u8 {
+        match self {
+            Mode::T1 => 3,
+            Mode::C1 => 6,
+        }
+    }
+}
+
+/// Settings of a wM-Bus receiver
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WMBusConfig {
+    pub receiver: Receiver,
+    pub mode: Mode,
+    /// AES keys of encrypting meters, by meter id
+    pub keys: HashMap<String, [u8; 16]>,
+}
+
+impl WMBusConfig {
+    /// Add the key of a meter, given as 32 hex characters
+    pub fn add_key(&mut self, meter_id: impl Into<String>, key: &str) -> anyhow::Result<()> {
+        let bytes = decode_hex(key)?;
+        let key = bytes
+            .try_into()
+            .map_err(|_| FrameValidation::WrongNumberOfFields(key.to_string()))?;
+        self.keys.insert(meter_id.into(), key);
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+enum Reader {
+    Imst(FramedListener<SerialStream, ImstFrame>),
+    Amber(FramedListener<SerialStream, AmberFrame>),
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub struct WMBus {
+    reader: Reader,
+    path: String,
+    health: DeviceHealth,
+    config: WMBusConfig,
+    initialized: bool,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl Device for WMBus {
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        if !self.initialized {
+            if let Reader::Imst(reader) = &mut self.reader {
+                reader
+                    .write_all(&ImstFrame::set_link_mode(self.config.mode))
+                    .await?;
+            }
+            self.initialized = true;
+        }
+        loop {
+            let res = match &mut self.reader {
+                Reader::Imst(reader) => reader.read_frame().await.map(|f| f.map(|f| f.telegram)),
+                Reader::Amber(reader) => reader.read_frame().await.map(|f| f.map(|f| f.telegram)),
+            };
+            self.health = DeviceHealth::after_read(&res);
+            match res {
+                // Responses of the receiver carry no telegram
+                Ok(Some(None)) => continue,
+                Ok(Some(Some(telegram))) => {
+                    match telegram.decode(&self.config.keys, self.config.mode) {
+                        Ok(reading) => return Ok(Some(Box::new(reading))),
+                        // A wrong key is a configuration error
+                        Err(e @ TelegramError::DecryptionFailed(_)) => return Err(e.into()),
+                        // Telegrams of other meters in range
+                        Err(_) => continue,
+                    }
+                }
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "wmbus"
+    }
+
+    fn address(&self) -> &str {
+        &self.path
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WMBus {
+    pub fn new<'a>(
+        path: impl Into<std::borrow::Cow<'a, str>>,
+        config: WMBusConfig,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+        let port = open_serial(&path, BAUD_RATE)?;
+        let reader = match config.receiver {
+            Receiver::Imst => Reader::Imst(FramedListener::new(port)),
+            Receiver::Amber => Reader::Amber(FramedListener::new(port)),
+        };
+
+        Ok(WMBus {
+            reader,
+            path: path.into_owned(),
+            health: DeviceHealth::Connected,
+            config,
+            initialized: false,
+        })
+    }
+}
+
+/// Link layer telegram, starting with the C-field
+#[derive(Debug, Clone, PartialEq)]
+pub struct Telegram {
+    data: Vec<u8>,
+    rssi: Option<i16>,
+}
+
+/// HCI message of the IMST iM871A
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImstFrame {
+    telegram: Option<Telegram>,
+}
+
+impl ImstFrame {
+    const START_OF_FRAME: u8 = 0xA5;
+    const ENDPOINT_DEVMGMT: u8 = 0x01;
+    const ENDPOINT_RADIOLINK: u8 = 0x02;
+    #[cfg(not(target_arch = "wasm32"))]
+    const MSG_SET_CONFIG_REQ: u8 = 0x03;
+    const MSG_SET_CONFIG_RSP: u8 = 0x04;
+    const MSG_RX_IND: u8 = 0x03;
+    const FLAG_TIMESTAMP: u8 = 0x20;
+    const FLAG_RSSI: u8 = 0x40;
+    const FLAG_CRC: u8 = 0x80;
+
+    /// Received telegram, `None` for responses of the receiver
+    pub fn telegram(&self) -> Option<&Telegram> {
+        self.telegram.as_ref()
+    }
+
+    /// Request switching to the given mode, without storing it permanently
+    #[cfg(not(target_arch = "wasm32"))]
+    fn set_link_mode(mode: Mode) -> Vec<u8> {
+        vec![
+            Self::START_OF_FRAME,
+            Self::ENDPOINT_DEVMGMT,
+            Self::MSG_SET_CONFIG_REQ,
+            3,
+            // volatile, only link mode given, link mode
+            0x00,
+            0x02,
+            mode.imst_link_mode(),
+        ]
+    }
+
+    fn trailer_len(control: u8) -> usize {
+        let mut len = 0;
+        if control & Self::FLAG_TIMESTAMP != 0 {
+            len += 4;
+        }
+        if control & Self::FLAG_RSSI != 0 {
+            len += 1;
+        }
+        if control & Self::FLAG_CRC != 0 {
+            len += 2;
+        }
+        len
+    }
+}
+
+impl Frame for ImstFrame {
+    /// Returns the full HCI message. The optional CRC is not verified.
+    fn check(buffer: &mut BytesMut) -> Result<BytesMut, FrameCheckError> {
+        match buffer.iter().position(|b| *b == Self::START_OF_FRAME) {
+            Some(i) => buffer.advance(i),
+            None => {
+                buffer.clear();
+                return Err(FrameCheckError::Incomplete);
+            }
+        }
+        if buffer.len() < 4 {
+            return Err(FrameCheckError::Incomplete);
+        }
+        let len = 4 + buffer[3] as usize + Self::trailer_len(buffer[1]);
+        if buffer.len() < len {
+            return Err(FrameCheckError::Incomplete);
+        }
+        Ok(buffer.split_to(len))
+    }
+
+    fn parse(buffer: BytesMut) -> anyhow::Result<Self> {
+        if buffer.len() < 4 || buffer.len() < 4 + buffer[3] as usize {
+            Err(FrameValidation::WrongNumberOfFields(format!(
+                "{:02X?}",
+                &buffer[..]
+            )))?;
+        }
+        let endpoint = buffer[1] & 0x0F;
+        if endpoint != Self::ENDPOINT_RADIOLINK || buffer[2] != Self::MSG_RX_IND {
+            return Ok(ImstFrame { telegram: None });
+        }
+        Ok(ImstFrame {
+            telegram: Some(Telegram {
+                data: buffer[4..4 + buffer[3] as usize].to_vec(),
+                rssi: None,
+            }),
+        })
+    }
+}
+
+impl EncodableFrame for ImstFrame {
+    /// Encodes a telegram as received message without trailers, else the response to a
+    /// configuration request
+    fn encode(&self) -> BytesMut {
+        let (endpoint, message, payload) = match &self.telegram {
+            Some(telegram) => (
+                Self::ENDPOINT_RADIOLINK,
+                Self::MSG_RX_IND,
+                &telegram.data[..],
+            ),
+            None => (Self::ENDPOINT_DEVMGMT, Self::MSG_SET_CONFIG_RSP, &[0][..]),
+        };
+        let mut frame =
+            BytesMut::from(&[Self::START_OF_FRAME, endpoint, message, payload.len() as u8][..]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+}
+
+/// Command frame of the Amber AMB8465 in command mode
+#[derive(Debug, Clone, PartialEq)]
+pub struct AmberFrame {
+    telegram: Option<Telegram>,
+}
+
+impl AmberFrame {
+    const START_OF_FRAME: u8 = 0xFF;
+    const CMD_DATA_IND: u8 = 0x03;
+    const CMD_RESET_CNF: u8 = 0x85;
+
+    /// Received telegram, `None` for responses of the receiver
+    pub fn telegram(&self) -> Option<&Telegram> {
+        self.telegram.as_ref()
+    }
+}
+
+impl EncodableFrame for AmberFrame {
+    /// Encodes a telegram as data indication, else the confirmation of a reset
+    fn encode(&self) -> BytesMut {
+        let (command, payload) = match &self.telegram {
+            Some(telegram) => {
+                let mut payload = vec![telegram.data.len() as u8];
+                payload.extend(&telegram.data);
+                payload.extend(telegram.rssi.map(|rssi| rssi as i8 as u8));
+                (Self::CMD_DATA_IND, payload)
+            }
+            None => (Self::CMD_RESET_CNF, Vec::new()),
+        };
+        let mut frame = BytesMut::from(&[Self::START_OF_FRAME, command, payload.len() as u8][..]);
+        frame.extend_from_slice(&payload);
+        let checksum = xor8(&frame);
+        frame.extend_from_slice(&[checksum]);
+        frame
+    }
+}
+
+impl Frame for AmberFrame {
+    /// Returns start byte, command, length and payload. Frames with bad checksum are rejected
+    /// with [FrameCheckError::ChecksumError], after removing their start byte.
+    fn check(buffer: &mut BytesMut) -> Result<BytesMut, FrameCheckError> {
+        match buffer.iter().position(|b| *b == Self::START_OF_FRAME) {
+            Some(i) => buffer.advance(i),
+            None => {
+                buffer.clear();
+                return Err(FrameCheckError::Incomplete);
+            }
+        }
+        if buffer.len() < 3 {
+            return Err(FrameCheckError::Incomplete);
+        }
+        let len = 3 + buffer[2] as usize;
+        if buffer.len() < len + 1 {
+            return Err(FrameCheckError::Incomplete);
+        }
+        let (expected, actual) = (xor8(&buffer[..len]), buffer[len]);
+        if expected != actual {
+            buffer.advance(1);
+            return Err(FrameCheckError::ChecksumError {
+                expected: expected as u32,
+                actual: actual as u32,
+            });
+        }
+        let frame = buffer.split_to(len);
+        buffer.advance(1);
+        Ok(frame)
+    }
+
+    fn parse(buffer: BytesMut) -> anyhow::Result<Self> {
+        if buffer.len() < 4 || buffer[1] != Self::CMD_DATA_IND {
+            return Ok(AmberFrame { telegram: None });
+        }
+        // Payload starts with the L-field, an appended byte is the RSSI
+        let payload = &buffer[3..];
+        let len = payload[0] as usize;
+        if payload.len() < len + 1 {
+            Err(FrameValidation::WrongNumberOfFields(format!(
+                "{:02X?}",
+                &buffer[..]
+            )))?;
+        }
+        Ok(AmberFrame {
+            telegram: Some(Telegram {
+                data: payload[1..=len].to_vec(),
+                rssi: payload.get(len + 1).map(|rssi| *rssi as i8 as i16),
+            }),
+        })
+    }
+}
+
+/// Errors decoding the application layer of a telegram
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum TelegramError {
+    #[error("Telegram too short: {0} bytes")]
+    TooShort(usize),
+    #[error("Unsupported CI field {0:#04X}")]
+    UnsupportedCi(u8),
+    #[error("No key for encrypted telegram of meter {0}")]
+    MissingKey(String),
+    #[error("Unsupported security mode {0}")]
+    UnsupportedSecurity(u8),
+    #[error("Decryption of telegram of meter {0} failed, wrong key?")]
+    DecryptionFailed(String),
+}
+
+/// Value of a data record of a meter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quantity {
+    /// Energy in Wh
+    Energy(f64),
+    /// Volume in m³
+    Volume(f64),
+    /// Power in W
+    Power(f64),
+    /// Volume flow in m³/h
+    VolumeFlow(f64),
+    /// Flow temperature in °C
+    FlowTemperature(f64),
+    /// Return temperature in °C
+    ReturnTemperature(f64),
+}
+
+impl Quantity {
+    fn field_name(&self) -> &'static str {
+        match self {
+            Quantity::Energy(_) => "energy",
+            Quantity::Volume(_) => "volume",
+            Quantity::Power(_) => "power",
+            Quantity::VolumeFlow(_) => "volume_flow",
+            Quantity::FlowTemperature(_) => "flow_temperature",
+            Quantity::ReturnTemperature(_) => "return_temperature",
+        }
+    }
+
+    fn value(&self) -> f64 {
+        match self {
+            Quantity::Energy(x)
+            | Quantity::Volume(x)
+            | Quantity::Power(x)
+            | Quantity::VolumeFlow(x)
+            | Quantity::FlowTemperature(x)
+            | Quantity::ReturnTemperature(x) => *x,
+        }
+    }
+
+    /// Decode a primary VIF into a quantity, given the raw value
+    fn from_vif(vif: u8, raw: f64) -> Option<Quantity> {
+        let n = (vif & 0x07) as i32;
+        let nn = (vif & 0x03) as i32;
+        match vif & 0x7F {
+            0x00..=0x07 => Some(Quantity::Energy(scale(raw, n - 3))),
+            // J to Wh
+            0x08..=0x0F => Some(Quantity::Energy(scale(raw, n) / 3600.)),
+            0x10..=0x17 => Some(Quantity::Volume(scale(raw, n - 6))),
+            0x28..=0x2F => Some(Quantity::Power(scale(raw, n - 3))),
+            0x38..=0x3F => Some(Quantity::VolumeFlow(scale(raw, n - 6))),
+            0x58..=0x5B => Some(Quantity::FlowTemperature(scale(raw, nn - 3))),
+            0x5C..=0x5F => Some(Quantity::ReturnTemperature(scale(raw, nn - 3))),
+            _ => None,
+        }
+    }
+}
+
+/// Multiply by a power of ten, dividing for negative exponents to avoid rounding artifacts
+fn scale(raw: f64, exp: i32) -> f64 {
+    if exp < 0 {
+        raw / 10f64.powi(-exp)
+    } else {
+        raw * 10f64.powi(exp)
+    }
+}
+
+/// Decoded telegram of a meter
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeterReading {
+    pub meter_id: String,
+    pub manufacturer: String,
+    pub device_type: u8,
+    pub mode: Mode,
+    pub values: Vec<Quantity>,
+    pub rssi: Option<i16>,
+}
+
+impl MeterReading {
+    fn device_type_name(&self) -> &'static str {
+        match self.device_type {
+            0x02 => "electricity",
+            0x03 => "gas",
+            0x04 | 0x0C => "heat",
+            0x06 => "warm_water",
+            0x07 => "water",
+            0x08 => "heat_cost_allocator",
+            0x16 => "cold_water",
+            _ => "other",
+        }
+    }
+}
+
+fn bcd(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |acc, b| acc * 100 + ((b >> 4) * 10 + (b & 0x0F)) as u64)
+}
+
+fn manufacturer(m: u16) -> String {
+    [(m >> 10) & 0x1F, (m >> 5) & 0x1F, m & 0x1F]
+        .iter()
+        .map(|c| (*c as u8 + 64) as char)
+        .collect()
+}
+
+/// Parse the data records following the header. Only current values (storage number 0) of
+/// known quantities are returned.
+fn parse_records(mut data: &[u8]) -> Vec<Quantity> {
+    let mut values = vec![];
+    while let Some((&dif, rest)) = data.split_first() {
+        data = rest;
+        match dif {
+            // Idle filler
+            0x2F => continue,
+            // Manufacturer specific data follows
+            0x0F | 0x1F => break,
+            _ => (),
+        }
+        let mut storage = (dif >> 6) & 0x01;
+        let mut ext = dif & 0x80 != 0;
+        while ext {
+            let Some((&dife, rest)) = data.split_first() else {
+                return values;
+            };
+            data = rest;
+            storage |= dife & 0x0F;
+            ext = dife & 0x80 != 0;
+        }
+        let Some((&vif, rest)) = data.split_first() else {
+            return values;
+        };
+        data = rest;
+        let mut ext = vif & 0x80 != 0;
+        while ext {
+            let Some((&vife, rest)) = data.split_first() else {
+                return values;
+            };
+            data = rest;
+            ext = vife & 0x80 != 0;
+        }
+        let len = match dif & 0x0F {
+            0x00 | 0x08 => 0,
+            0x01 | 0x09 => 1,
+            0x02 | 0x0A => 2,
+            0x03 | 0x0B => 3,
+            0x04 | 0x05 | 0x0C => 4,
+            0x06 | 0x0E => 6,
+            0x07 => 8,
+            // Variable length and special functions are not supported
+            _ => return values,
+        };
+        if data.len() < len {
+            return values;
+        }
+        let (bytes, rest) = data.split_at(len);
+        data = rest;
+        let raw = match dif & 0x0F {
+            0x05 => f32::from_le_bytes(bytes.try_into().expect("4 bytes")) as f64,
+            0x09..=0x0E => bcd(bytes) as f64,
+            _ => {
+                let mut buf = [0u8; 8];
+                buf[..len].copy_from_slice(bytes);
+                // sign extend
+                if len > 0 && len < 8 && bytes[len - 1] & 0x80 != 0 {
+                    buf[len..].fill(0xFF);
+                }
+                i64::from_le_bytes(buf) as f64
+            }
+        };
+        if storage != 0 {
+            continue;
+        }
+        if let Some(quantity) = Quantity::from_vif(vif, raw) {
+            values.push(quantity);
+        }
+    }
+    values
+}
+
+impl Telegram {
+    const CI_SHORT_HEADER: u8 = 0x7A;
+    const CI_LONG_HEADER: u8 = 0x72;
+
+    /// Link layer telegram, starting with the C-field
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Received signal strength in dBm, if reported by the receiver
+    pub fn rssi(&self) -> Option<i16> {
+        self.rssi
+    }
+
+    /// Decode the telegram, decrypting it if necessary
+    pub fn decode(
+        &self,
+        keys: &HashMap<String, [u8; 16]>,
+        mode: Mode,
+    ) -> Result<MeterReading, TelegramError> {
+        let data = &self.data;
+        // C, M (2), A (6), CI
+        if data.len() < 10 {
+            return Err(TelegramError::TooShort(data.len()));
+        }
+        let mut man = u16::from_le_bytes([data[1], data[2]]);
+        let mut address = data[3..9].to_vec();
+        let ci = data[9];
+        let header = &data[10..];
+        let (access_number, config, body) = match ci {
+            Self::CI_SHORT_HEADER if header.len() >= 4 => (
+                header[0],
+                u16::from_le_bytes([header[2], header[3]]),
+                &header[4..],
+            ),
+            Self::CI_LONG_HEADER if header.len() >= 12 => {
+                // The application layer address replaces the link layer address
+                address = header[0..4].to_vec();
+                address.extend_from_slice(&header[6..8]);
+                man = u16::from_le_bytes([header[4], header[5]]);
+                (
+                    header[8],
+                    u16::from_le_bytes([header[10], header[11]]),
+                    &header[12..],
+                )
+            }
+            Self::CI_SHORT_HEADER | Self::CI_LONG_HEADER => {
+                return Err(TelegramError::TooShort(data.len()))
+            }
+            ci => return Err(TelegramError::UnsupportedCi(ci)),
+        };
+        let meter_id = format!("{:08}", bcd(&address[0..4]));
+
+        let security = ((config >> 8) & 0x1F) as u8;
+        let body = match security {
+            0 => body.to_vec(),
+            5 => {
+                let key = keys
+                    .get(&meter_id)
+                    .ok_or_else(|| TelegramError::MissingKey(meter_id.clone()))?;
+                let blocks = ((config >> 4) & 0x0F) as usize;
+                if body.len() < blocks * 16 {
+                    return Err(TelegramError::TooShort(data.len()));
+                }
+                let mut iv = [access_number; 16];
+                iv[0..2].copy_from_slice(&man.to_le_bytes());
+                iv[2..8].copy_from_slice(&address);
+                let mut body = body.to_vec();
+                cbc::Decryptor::<aes::Aes128>::new(key.into(), &iv.into())
+                    .decrypt_padded_mut::<NoPadding>(&mut body[..blocks * 16])
+                    .map_err(|_| TelegramError::DecryptionFailed(meter_id.clone()))?;
+                if !body.starts_with(&[0x2F, 0x2F]) {
+                    return Err(TelegramError::DecryptionFailed(meter_id));
+                }
+                body
+            }
+            security => return Err(TelegramError::UnsupportedSecurity(security)),
+        };
+
+        Ok(MeterReading {
+            meter_id,
+            manufacturer: manufacturer(man),
+            device_type: address[5],
+            mode,
+            values: parse_records(&body),
+            rssi: self.rssi,
+        })
+    }
+}
+
+impl ToOutput for MeterReading {}
+
+impl Display for MeterReading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Meter {} ({} {})",
+            self.meter_id,
+            self.manufacturer,
+            self.device_type_name()
+        )?;
+        for value in &self.values {
+            write!(f, ", {}: {}", value.field_name(), value.value())?;
+        }
+        Ok(())
+    }
+}
+
+impl ToMeasurement for MeterReading {
+    fn to_measurement(&self) -> Measurement {
+        let mut measurement = Measurement::new("meter")
+            .add_tag("meterId", &self.meter_id)
+            .add_tag("manufacturer", &self.manufacturer)
+            .add_tag("deviceType", self.device_type_name())
+            .add_tag(
+                "mode",
+                match self.mode {
+                    Mode::T1 => "T1",
+                    Mode::C1 => "C1",
+                },
+            );
+        for value in &self.values {
+            measurement = measurement.add_field(value.field_name(), value.value());
+        }
+        match self.rssi {
+            Some(rssi) => measurement.add_field("rssi", rssi as i64),
+            None => measurement,
+        }
+    }
+}
+
+/// Schema of the measurements of [MeterReading]
+pub fn schemas() -> Vec<Schema> {
+    let quantities = [
+        "energy",
+        "volume",
+        "power",
+        "volume_flow",
+        "flow_temperature",
+        "return_temperature",
+    ];
+    let schema = Schema::new("meter")
+        .add_tag("meterId")
+        .add_tag("manufacturer")
+        .add_tag("deviceType")
+        .add_tag("mode")
+        .add_optional_field("rssi", FieldType::Integer, None);
+    vec![quantities.iter().fold(schema, |schema, name| {
+        schema.add_optional_field(*name, FieldType::Float, None)
+    })]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        AmberFrame, Frame, ImstFrame, Mode, Quantity, Telegram, TelegramError, WMBusConfig,
+    };
+    use aes::cipher::{block_padding::NoPadding, BlockEncryptMut, KeyIvInit};
+    use bytes::BytesMut;
+
+    const KEY: &str = "000102030405060708090A0B0C0D0E0F";
+
+    /// Water meter 12345678 of manufacturer "KAM", short header, given security config
+    fn telegram(config: u16, body: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x44, 0x2D, 0x2C, 0x78, 0x56, 0x34, 0x12, 0x01, 0x07, 0x7A];
+        data.extend_from_slice(&[0x2A, 0x00]);
+        data.extend_from_slice(&config.to_le_bytes());
+        data.extend_from_slice(body);
+        data
+    }
+
+    /// Volume 1234.567 m³ (VIF 0x13: litres), flow temperature 21.5 °C (VIF 0x59: 0.01 °C)
+    const RECORDS: [u8; 10] = [0x04, 0x13, 0x87, 0xD6, 0x12, 0x00, 0x02, 0x59, 0x66, 0x08];
+
+    #[test]
+    fn test_decode_unencrypted_telegram() {
+        let telegram = Telegram {
+            data: telegram(0x0000, &RECORDS),
+            rssi: Some(-60),
+        };
+        let reading = telegram.decode(&Default::default(), Mode::T1).unwrap();
+        assert_eq!(reading.meter_id, "12345678");
+        assert_eq!(reading.manufacturer, "KAM");
+        assert_eq!(reading.device_type, 0x07);
+        assert_eq!(
+            reading.values,
+            [Quantity::Volume(1234.567), Quantity::FlowTemperature(21.5)]
+        );
+    }
+
+    #[test]
+    fn test_decode_encrypted_telegram() {
+        let mut config = WMBusConfig::default();
+        config.add_key("12345678", KEY).unwrap();
+        let key = config.keys["12345678"];
+
+        let mut plain = vec![0x2F, 0x2F];
+        plain.extend_from_slice(&RECORDS);
+        plain.resize(16, 0x2F);
+        let mut iv = [0x2A; 16];
+        iv[..8].copy_from_slice(&[0x2D, 0x2C, 0x78, 0x56, 0x34, 0x12, 0x01, 0x07]);
+        cbc::Encryptor::<aes::Aes128>::new(&key.into(), &iv.into())
+            .encrypt_padded_mut::<NoPadding>(&mut plain, 16)
+            .unwrap();
+
+        // security mode 5, one encrypted block
+        let telegram = Telegram {
+            data: telegram(0x0510, &plain),
+            rssi: None,
+        };
+        assert_eq!(
+            telegram.decode(&Default::default(), Mode::T1),
+            Err(TelegramError::MissingKey("12345678".into()))
+        );
+        let reading = telegram.decode(&config.keys, Mode::T1).unwrap();
+        assert_eq!(
+            reading.values,
+            [Quantity::Volume(1234.567), Quantity::FlowTemperature(21.5)]
+        );
+
+        let mut wrong = config.keys.clone();
+        wrong.insert("12345678".into(), [0; 16]);
+        assert_eq!(
+            telegram.decode(&wrong, Mode::T1),
+            Err(TelegramError::DecryptionFailed("12345678".into()))
+        );
+    }
+
+    #[test]
+    fn test_imst_deframing() {
+        let data = telegram(0x0000, &RECORDS);
+        let mut bytes = vec![0x00, 0xA5, 0x42, 0x03, data.len() as u8];
+        bytes.extend_from_slice(&data);
+        // RSSI flag set, one trailing byte
+        bytes.push(0x55);
+        let mut buf = BytesMut::from(&bytes[..]);
+        let frame = ImstFrame::parse(ImstFrame::check(&mut buf).unwrap()).unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(frame.telegram.unwrap().data, data);
+    }
+
+    #[test]
+    fn test_amber_deframing() {
+        let data = telegram(0x0000, &RECORDS);
+        let mut bytes = vec![0xFF, 0x03, data.len() as u8 + 2, data.len() as u8];
+        bytes.extend_from_slice(&data);
+        bytes.push(0xC4);
+        bytes.push(bytes.iter().fold(0, |cs, b| cs ^ b));
+        let mut buf = BytesMut::from(&bytes[..]);
+        let frame = AmberFrame::parse(AmberFrame::check(&mut buf).unwrap()).unwrap();
+        let telegram = frame.telegram.unwrap();
+        assert_eq!(telegram.data, data);
+        assert_eq!(telegram.rssi, Some(-60));
+    }
+}
@@ -0,0 +1,403 @@
+//! Zigbee devices bridged by Zigbee2MQTT.
+//!
+//! Zigbee2MQTT publishes the state of every device as JSON to `<base topic>/<friendly name>`
+//! and its availability to `<base topic>/<friendly name>/availability`. The retained device list
+//! on `<base topic>/bridge/devices` provides model and vendor, which are added as tags to the
+//! measurements of a device once known.
+use crate::{
+    measurement::{FieldValue, Measurement, ToMeasurement},
+    output::ToOutput,
+    tls::TlsConfig,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display};
+use thiserror::Error;
+
+use super::{
+    http::field_value,
+    mqtt::{MqttConfig, MqttInput, Subscription},
+};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum Zigbee2MqttError {
+    #[error("Invalid JSON: {0}")]
+    Json(String),
+    #[error("Invalid availability {0}")]
+    Availability(String),
+}
+
+fn default_base_topic() -> String {
+    "zigbee2mqtt".into()
+}
+
+/// Broker of the Zigbee2MQTT instance
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct Zigbee2MqttConfig {
+    /// Host of the broker, with optional port
+    pub broker: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<TlsConfig>,
+    /// `base_topic` of the Zigbee2MQTT configuration
+    #[serde(default = "default_base_topic")]
+    pub base_topic: String,
+    /// Tags added to the measurements when opened as input of a pipeline file
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Zigbee2MqttConfig {
+    pub fn new(broker: impl Into<String>) -> Zigbee2MqttConfig {
+        Zigbee2MqttConfig {
+            broker: broker.into(),
+            username: None,
+            password: None,
+            tls: None,
+            base_topic: default_base_topic(),
+            tags: BTreeMap::new(),
+        }
+    }
+
+    /// Subscribe to all devices of the bridge
+    pub fn input(self) -> anyhow::Result<MqttInput<Zigbee2Mqtt>> {
+        let mut mqtt = MqttConfig::new(&self.broker);
+        mqtt.username = self.username.clone();
+        mqtt.password = self.password.clone();
+        mqtt.tls = self.tls.clone();
+        MqttInput::new(&mqtt, Zigbee2Mqtt::new(self.base_topic))
+    }
+}
+
+/// Model of a device as listed by the bridge
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceModel {
+    pub model: String,
+    pub vendor: Option<String>,
+}
+
+/// Decoded message of a device
+#[derive(Debug, Clone, PartialEq)]
+pub enum Zigbee2MqttMessage {
+    /// State of a device, including the link quality if reported
+    State {
+        friendly_name: String,
+        model: Option<DeviceModel>,
+        values: Vec<(String, FieldValue)>,
+    },
+    Availability {
+        friendly_name: String,
+        model: Option<DeviceModel>,
+        online: bool,
+    },
+}
+
+/// Add the scalar members of a JSON object, nested objects with their names joined by `_`
+fn flatten(
+    prefix: &str,
+    object: &serde_json::Map<String, Value>,
+    values: &mut Vec<(String, FieldValue)>,
+) {
+    for (key, value) in object {
+        let name = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}_{}", prefix, key)
+        };
+        match value {
+            Value::Object(nested) => flatten(&name, nested, values),
+            value => values.extend(field_value(value).map(|value| (name, value))),
+        }
+    }
+}
+
+/// Parse a device state
+pub fn parse_state(payload: &[u8]) -> Result<Vec<(String, FieldValue)>, Zigbee2MqttError> {
+    let state: serde_json::Map<String, Value> =
+        serde_json::from_slice(payload).map_err(|e| Zigbee2MqttError::Json(e.to_string()))?;
+    let mut values = vec![];
+    flatten("", &state, &mut values);
+    Ok(values)
+}
+
+/// Parse an availability, either JSON (`{"state":"online"}`) or the legacy plain payload
+pub fn parse_availability(payload: &[u8]) -> Result<bool, Zigbee2MqttError> {
+    let state = match serde_json::from_slice::<Value>(payload) {
+        Ok(Value::Object(object)) => object
+            .get("state")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        _ => String::from_utf8_lossy(payload).trim().to_string(),
+    };
+    match state.as_str() {
+        "online" => Ok(true),
+        "offline" => Ok(false),
+        _ => Err(Zigbee2MqttError::Availability(state)),
+    }
+}
+
+/// Parse the device list of the bridge into models by friendly name
+pub fn parse_devices(payload: &[u8]) -> Result<HashMap<String, DeviceModel>, Zigbee2MqttError> {
+    let devices: Vec<Value> =
+        serde_json::from_slice(payload).map_err(|e| Zigbee2MqttError::Json(e.to_string()))?;
+    Ok(devices
+        .iter()
+        .filter_map(|device| {
+            // The coordinator has no definition
+            let definition = device.get("definition")?;
+            Some((
+                device["friendly_name"].as_str()?.to_string(),
+                DeviceModel {
+                    model: definition["model"].as_str()?.to_string(),
+                    vendor: definition["vendor"].as_str().map(String::from),
+                },
+            ))
+        })
+        .collect())
+}
+
+impl ToOutput for Zigbee2MqttMessage {}
+
+impl Display for Zigbee2MqttMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Zigbee2MqttMessage::State {
+                friendly_name,
+                values,
+                ..
+            } => {
+                write!(f, "Zigbee {}:", friendly_name)?;
+                for (field, value) in values {
+                    write!(f, " {}={}", field, value)?;
+                }
+                Ok(())
+            }
+            Zigbee2MqttMessage::Availability {
+                friendly_name,
+                online,
+                ..
+            } => write!(
+                f,
+                "Zigbee {}: {}",
+                friendly_name,
+                if *online { "online" } else { "offline" }
+            ),
+        }
+    }
+}
+
+fn add_device_tags(
+    measurement: Measurement,
+    friendly_name: &str,
+    model: &Option<DeviceModel>,
+) -> Measurement {
+    let mut measurement = measurement.add_tag("device", friendly_name);
+    if let Some(model) = model {
+        measurement = measurement.add_tag("model", &model.model);
+        if let Some(vendor) = &model.vendor {
+            measurement = measurement.add_tag("vendor", vendor);
+        }
+    }
+    measurement
+}
+
+impl ToMeasurement for Zigbee2MqttMessage {
+    fn to_measurement(&self) -> Measurement {
+        match self {
+            Zigbee2MqttMessage::State {
+                friendly_name,
+                model,
+                values,
+            } => {
+                let mut measurement =
+                    add_device_tags(Measurement::new("zigbee"), friendly_name, model);
+                for (field, value) in values {
+                    measurement = measurement.add_field(field, value.clone());
+                }
+                measurement
+            }
+            Zigbee2MqttMessage::Availability {
+                friendly_name,
+                model,
+                online,
+            } => add_device_tags(Measurement::new("zigbeeAvailability"), friendly_name, model)
+                .add_field("online", *online),
+        }
+    }
+}
+
+/// Subscription to all topics of a bridge
+pub struct Zigbee2Mqtt {
+    base_topic: String,
+    models: HashMap<String, DeviceModel>,
+}
+
+impl Zigbee2Mqtt {
+    pub fn new(base_topic: impl Into<String>) -> Zigbee2Mqtt {
+        Zigbee2Mqtt {
+            base_topic: base_topic.into(),
+            models: HashMap::new(),
+        }
+    }
+}
+
+impl Subscription for Zigbee2Mqtt {
+    type Frame = Zigbee2MqttMessage;
+
+    fn topics(&self) -> Vec<String> {
+        vec![format!("{}/#", self.base_topic)]
+    }
+
+    fn decode(&mut self, topic: &str, payload: &[u8]) -> anyhow::Result<Vec<Zigbee2MqttMessage>> {
+        let Some(name) = topic
+            .strip_prefix(&self.base_topic)
+            .and_then(|name| name.strip_prefix('/'))
+        else {
+            return Ok(vec![]);
+        };
+        if name == "bridge/devices" {
+            self.models = parse_devices(payload)?;
+            return Ok(vec![]);
+        }
+        // Other bridge topics and commands sent to devices
+        if name.starts_with("bridge/") || name.ends_with("/set") || name.ends_with("/get") {
+            return Ok(vec![]);
+        }
+        if let Some(friendly_name) = name.strip_suffix("/availability") {
+            return Ok(vec![Zigbee2MqttMessage::Availability {
+                friendly_name: friendly_name.to_string(),
+                model: self.models.get(friendly_name).cloned(),
+                online: parse_availability(payload)?,
+            }]);
+        }
+        let values = parse_state(payload)?;
+        if values.is_empty() {
+            return Ok(vec![]);
+        }
+        Ok(vec![Zigbee2MqttMessage::State {
+            friendly_name: name.to_string(),
+            model: self.models.get(name).cloned(),
+            values,
+        }])
+    }
+
+    fn name(&self) -> &str {
+        "zigbee2mqtt"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_availability, parse_state, Zigbee2Mqtt, Zigbee2MqttConfig};
+    use crate::{
+        devices::{mqtt::serve_once, mqtt::Subscription, Device},
+        measurement::{FieldValue, ToMeasurement},
+    };
+
+    const DEVICES: &str = r#"[
+        {"friendly_name":"Coordinator","ieee_address":"0x00124b0001","type":"Coordinator"},
+        {"friendly_name":"living room/climate","ieee_address":"0x00158d0002",
+         "definition":{"model":"WSDCGQ11LM","vendor":"Aqara","description":"Sensor"}}
+    ]"#;
+
+    #[test]
+    fn test_parse_state() {
+        let values = parse_state(
+            br#"{"temperature":21.5,"linkquality":120,"state":"ON","color":{"x":0.3,"y":0.4},
+                 "last_seen":null}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            values,
+            [
+                ("color_x".into(), FieldValue::Float(0.3)),
+                ("color_y".into(), FieldValue::Float(0.4)),
+                ("linkquality".into(), FieldValue::Integer(120)),
+                ("state".into(), FieldValue::String("ON".into())),
+                ("temperature".into(), FieldValue::Float(21.5)),
+            ]
+        );
+        assert!(parse_state(b"[1]").is_err());
+    }
+
+    #[test]
+    fn test_parse_availability() {
+        assert_eq!(parse_availability(br#"{"state":"online"}"#), Ok(true));
+        assert_eq!(parse_availability(b"offline"), Ok(false));
+        assert!(parse_availability(b"unknown").is_err());
+    }
+
+    #[test]
+    fn test_decode_topics() {
+        let mut bridge = Zigbee2Mqtt::new("zigbee2mqtt");
+        assert!(bridge
+            .decode("zigbee2mqtt/bridge/devices", DEVICES.as_bytes())
+            .unwrap()
+            .is_empty());
+        assert!(bridge
+            .decode("zigbee2mqtt/bridge/state", br#"{"state":"online"}"#)
+            .unwrap()
+            .is_empty());
+        assert!(bridge
+            .decode("zigbee2mqtt/lamp/set", br#"{"state":"ON"}"#)
+            .unwrap()
+            .is_empty());
+
+        let state = bridge
+            .decode(
+                "zigbee2mqtt/living room/climate",
+                br#"{"temperature":21.5,"linkquality":120}"#,
+            )
+            .unwrap()
+            .remove(0)
+            .to_measurement();
+        assert_eq!(
+            state.to_string(),
+            "zigbee device=living room/climate model=WSDCGQ11LM vendor=Aqara: linkquality=120 \
+             temperature=21.5"
+        );
+
+        let availability = bridge
+            .decode("zigbee2mqtt/plug/availability", b"offline")
+            .unwrap()
+            .remove(0)
+            .to_measurement();
+        assert_eq!(
+            availability.to_string(),
+            "zigbeeAvailability device=plug: online=false"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_receive_state() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Zigbee2MqttConfig::new(listener.local_addr().unwrap().to_string());
+        let broker = tokio::spawn(serve_once(
+            listener,
+            vec![
+                (
+                    "zigbee2mqtt/bridge/devices".into(),
+                    DEVICES.as_bytes().to_vec(),
+                ),
+                (
+                    "zigbee2mqtt/living room/climate".into(),
+                    br#"{"humidity":45}"#.to_vec(),
+                ),
+            ],
+        ));
+
+        let mut input = config.input().unwrap();
+        let measurement = input.read_frame().await.unwrap().unwrap().to_measurement();
+        assert_eq!(measurement.tag("model"), Some("WSDCGQ11LM"));
+        assert_eq!(
+            measurement.field("humidity"),
+            Some(&FieldValue::Integer(45))
+        );
+        drop(input);
+        assert_eq!(broker.await.unwrap(), ["zigbee2mqtt/#"]);
+    }
+}
@@ -0,0 +1,120 @@
+//! Support of the fuzz targets in `fuzz/`.
+//!
+//! Every frame protocol has a fuzz target feeding arbitrary bytes to [frames], which fails if a
+//! parser panics or [Frame::check] returns a frame without consuming the buffer. [seeds] are
+//! valid frames of each target, the initial corpus. Requires the `fuzz` feature.
+use crate::{error::FrameCheckError, Frame};
+use bytes::BytesMut;
+
+/// Names of the fuzz targets, one per frame protocol
+pub const TARGETS: [&str; 8] = [
+    "jeelink",
+    "cul",
+    "enocean",
+    "davis",
+    "pms",
+    "elm327",
+    "wmbus_imst",
+    "wmbus_amber",
+];
+
+/// Feed `data` to the parser of `F` like a serial port would. The first byte selects the size
+/// of the reads, so frames split across reads are covered as well.
+pub fn frames<F: Frame>(data: &[u8]) {
+    let Some((&size, data)) = data.split_first() else {
+        return;
+    };
+    let mut buffer = BytesMut::new();
+    for read in data.chunks(size as usize % 64 + 1) {
+        buffer.extend_from_slice(read);
+        loop {
+            let len = buffer.len();
+            match F::check(&mut buffer) {
+                Ok(frame) => {
+                    assert!(buffer.len() < len, "Frame returned without consuming input");
+                    let _ = F::parse(frame);
+                }
+                Err(FrameCheckError::ChecksumError { .. }) => {
+                    assert!(buffer.len() < len, "Checksum error without consuming input");
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Valid frames of a fuzz target, each prefixed with the read size
+pub fn seeds(target: &str) -> &'static [&'static [u8]] {
+    match target {
+        "jeelink" => &[
+            b"\x10OK 9 50 1 4 193 65\r\n",
+            b"\x03OK 9 12 130 4 193 234 -71\r\n",
+        ],
+        "cul" => &[b"\x10F12340011\r\n", b"\x02V 1.67 CUL868\r\nF12340011\r\n"],
+        "enocean" => &[
+            b"\x10\x55\x00\x07\x07\x01\x7a\xf6\x50\x01\x02\x03\x04\x30\x01\xff\xff\xff\xff\x45\
+              \x00\xa4",
+        ],
+        "davis" => &[
+            b"\x20\x0a\x0d\x06\x4c\x4f\x4f\x00\x00\x00\x00\xe1\x74\xd0\x02\x28\xff\x7f\x0a\xff\
+              \x68\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\xff\x00\x00\
+              \x00\x00\x00\x00\x00\x0a\x00\xff\xff\x7f\x00\x00\x00\x00\x19\x00\x00\x00\x00\x00\
+              \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+              \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x03\x00\x00\x00\x00\x00\x00\x0a\
+              \x0d\x68\xe4",
+        ],
+        "pms" => &[
+            b"\x10\x42\x4d\x00\x1c\x00\x05\x00\x08\x00\x09\x00\x05\x00\x08\x00\x09\x04\x92\x01\
+              \x54\x00\x32\x00\x04\x00\x01\x00\x00\x00\x00\x01\xf9",
+        ],
+        "elm327" => &[b"\x08SEARCHING...\r41 0C 1A F8\r\r>"],
+        "wmbus_imst" => &[
+            b"\x10\x00\xa5\x42\x03\x18\x44\x2d\x2c\x78\x56\x34\x12\x01\x07\x7a\x2a\x00\x00\x00\
+              \x04\x13\x87\xd6\x12\x00\x02\x59\x66\x08\x55",
+        ],
+        "wmbus_amber" => &[
+            b"\x10\xff\x03\x1a\x18\x44\x2d\x2c\x78\x56\x34\x12\x01\x07\x7a\x2a\x00\x00\x00\x04\
+              \x13\x87\xd6\x12\x00\x02\x59\x66\x08\xc4\x40",
+        ],
+        _ => &[],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{frames, seeds, TARGETS};
+    use crate::devices::{
+        cul::CulFrame, davis::LoopPacket, elm327::ElmResponse, enocean::EnOceanFrame,
+        jeelink::JeeLinkFrame, pms::PmsFrame, wmbus::AmberFrame, wmbus::ImstFrame,
+    };
+    use crate::Frame;
+    use bytes::BytesMut;
+
+    /// Number of frames in a seed which parse
+    fn parsed<F: Frame>(seed: &[u8]) -> usize {
+        frames::<F>(seed);
+        let mut buffer = BytesMut::from(&seed[1..]);
+        std::iter::from_fn(|| F::check(&mut buffer).ok())
+            .filter(|frame| F::parse(frame.clone()).is_ok())
+            .count()
+    }
+
+    #[test]
+    fn test_seeds_are_valid_frames() {
+        for target in TARGETS {
+            for seed in seeds(target) {
+                let count = match target {
+                    "jeelink" => parsed::<JeeLinkFrame>(seed),
+                    "cul" => parsed::<CulFrame>(seed),
+                    "enocean" => parsed::<EnOceanFrame>(seed),
+                    "davis" => parsed::<LoopPacket>(seed),
+                    "pms" => parsed::<PmsFrame>(seed),
+                    "elm327" => parsed::<ElmResponse>(seed),
+                    "wmbus_imst" => parsed::<ImstFrame>(seed),
+                    _ => parsed::<AmberFrame>(seed),
+                };
+                assert!(count > 0, "{}: {:02x?}", target, seed);
+            }
+        }
+    }
+}
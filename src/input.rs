@@ -1,18 +1,36 @@
 //! Read from IO devices.
-use crate::error::FrameCheckError;
+use crate::error::FrameValidation;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::error::{FrameCheckError, InvalidFrame};
+#[cfg(not(target_arch = "wasm32"))]
 use crate::Frame;
+#[cfg(not(target_arch = "wasm32"))]
 use bytes::BytesMut;
+#[cfg(not(target_arch = "wasm32"))]
 use std::marker::PhantomData;
 
+pub mod binary;
+pub mod cayenne;
+pub mod checksum;
+pub mod delimiter;
+pub mod line;
+pub mod senml;
+
 /// Listener on IO device
 ///
 /// Allows to read frames from device stream.
+#[cfg(not(target_arch = "wasm32"))]
 pub struct FramedListener<P, F> {
     port: P,
     buffer: BytesMut,
     frame_type: PhantomData<F>,
+    /// Statistics of the diagnostics mode
+    stats: Option<FrameStats>,
+    /// Baud rates probed on the next read, see [Self::with_auto_baud]
+    auto_baud: Option<AutoBaud>,
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl<P, F: Frame> FramedListener<P, F> {
     pub fn new(port: P) -> FramedListener<P, F> {
         FramedListener {
@@ -20,31 +38,150 @@ impl<P, F: Frame> FramedListener<P, F> {
             // Allocate buffer with 256 bytes
             buffer: BytesMut::with_capacity(256),
             frame_type: PhantomData,
+            stats: None,
+            auto_baud: None,
         }
     }
 
+    /// Count the bytes of frames and the garbage between them, keeping samples of the
+    /// garbage, e.g. to debug flaky USB links. Costs a copy of the buffer per check.
+    pub fn with_diagnostics(mut self) -> Self {
+        self.stats = Some(FrameStats::default());
+        self
+    }
+
+    /// Statistics since the listener was created, `None` without diagnostics mode
+    pub fn stats(&self) -> Option<&FrameStats> {
+        self.stats.as_ref()
+    }
+
     fn parse(&mut self) -> anyhow::Result<Option<F>> {
-        match F::check(&mut self.buffer) {
+        let received = self.stats.is_some().then(|| self.buffer.clone());
+        let res = F::check(&mut self.buffer);
+        if let (Some(stats), Some(received)) = (self.stats.as_mut(), received) {
+            let consumed = &received[..received.len() - self.buffer.len()];
+            match &res {
+                Ok(_) => {
+                    let (garbage, frame) =
+                        consumed.split_at(consumed.len() - frame_len::<F>(consumed));
+                    stats.discard(garbage);
+                    stats.frames += 1;
+                    stats.frame_bytes += frame.len() as u64;
+                }
+                Err(FrameCheckError::Incomplete) => stats.discard(consumed),
+                Err(_) => {
+                    stats.failures += 1;
+                    stats.discard(consumed);
+                }
+            }
+        }
+        match res {
             Ok(frame_data) => {
-                // parse frame
-                let frame = F::parse(frame_data)?;
+                // parse frame, keeping the bytes for diagnostics
+                let bytes = frame_data.to_vec();
+                let frame = F::parse(frame_data).map_err(|e| {
+                    if let Some(stats) = self.stats.as_mut() {
+                        stats.failures += 1;
+                    }
+                    e.context(InvalidFrame(bytes))
+                })?;
                 Ok(Some(frame))
             }
             Err(FrameCheckError::Incomplete) => Ok(None),
             Err(err) => Err(err.into()),
         }
     }
+
+    /// Result of a read once the device closed the stream: `None` if it ended between frames
+    fn end_of_stream(&self) -> anyhow::Result<Option<F>> {
+        match self.buffer.is_empty() {
+            true => Ok(None),
+            false => Err(error::DeviceError::ConnectionLost)?,
+        }
+    }
+}
+
+/// Baud rates to probe, see [FramedListener::with_auto_baud]
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone)]
+struct AutoBaud {
+    rates: Vec<u32>,
+    timeout: std::time::Duration,
+    init: Vec<u8>,
+}
+
+/// Length of the frame at the end of `consumed`, the bytes removed by a check returning it.
+///
+/// A check of the bytes without the last one skips the same garbage and stops at the start of
+/// the then incomplete frame.
+#[cfg(not(target_arch = "wasm32"))]
+fn frame_len<F: Frame>(consumed: &[u8]) -> usize {
+    let Some((_, truncated)) = consumed.split_last() else {
+        return 0;
+    };
+    let mut truncated = BytesMut::from(truncated);
+    match F::check(&mut truncated) {
+        Err(FrameCheckError::Incomplete) => truncated.len() + 1,
+        _ => consumed.len(),
+    }
+}
+
+/// Number of garbage sequences kept as samples by [FrameStats]
+#[cfg(not(target_arch = "wasm32"))]
+const MAX_DISCARDED: usize = 5;
+
+/// Length to which samples of garbage are truncated
+#[cfg(not(target_arch = "wasm32"))]
+const MAX_DISCARDED_LEN: usize = 256;
+
+/// Bytes read by a [FramedListener] in diagnostics mode
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FrameStats {
+    /// Complete frames, including those which failed to parse
+    pub frames: u64,
+    /// Bytes of complete frames, including start and end sequences
+    pub frame_bytes: u64,
+    /// Bytes skipped while searching the start of a frame, e.g. line noise or replies to
+    /// commands, and bytes of frames with a wrong checksum
+    pub garbage_bytes: u64,
+    /// Frames with a wrong checksum or which failed to parse
+    pub failures: u64,
+    /// First sequences of garbage, each truncated to 256 bytes, see [hexdump]
+    pub discarded: Vec<Vec<u8>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FrameStats {
+    fn discard(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.garbage_bytes += bytes.len() as u64;
+        if self.discarded.len() < MAX_DISCARDED {
+            self.discarded
+                .push(bytes[..bytes.len().min(MAX_DISCARDED_LEN)].to_vec());
+        }
+    }
+}
+
+impl std::fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} frames of {} bytes, {} garbage bytes, {} failures",
+            self.frames, self.frame_bytes, self.garbage_bytes, self.failures
+        )
+    }
 }
 
 /// Module for creating data frames from the byte stream read from a device
 pub mod protocol {
 
+    use super::delimiter::Delimiter;
     use bytes::BytesMut;
 
-    use crate::output::ToOutput;
-
     /// Trait for protocol frame objects.
-    pub trait Frame: Sized + ToOutput {
+    pub trait Frame: Sized {
         /// Check if a full frame is available in the buffer and returns it if possible.
         ///
         /// The input buffer will be advanced until a start sequence of a frame is reached.
@@ -57,6 +194,61 @@ pub mod protocol {
         fn parse(buffer: BytesMut) -> anyhow::Result<Self>;
     }
 
+    /// Frames of line oriented protocols, which only need to parse a line.
+    ///
+    /// Lines end with `\n` or `\r\n` and must be valid UTF-8. Empty lines are skipped, and a
+    /// line which fails to parse does not affect the next one.
+    ///
+    /// ```
+    /// use sensorflow::{Frame, LineFrame};
+    ///
+    /// struct Reading(f64);
+    ///
+    /// impl LineFrame for Reading {
+    ///     fn parse_line(line: &str) -> anyhow::Result<Self> {
+    ///         Ok(Reading(line.trim().parse()?))
+    ///     }
+    /// }
+    ///
+    /// let mut buffer = bytes::BytesMut::from(&b"\r\n21.5\r\n"[..]);
+    /// let line = Reading::check(&mut buffer).unwrap();
+    /// assert_eq!(Reading::parse(line).unwrap().0, 21.5);
+    /// ```
+    pub trait LineFrame: Sized {
+        /// Parse a line without its line ending
+        fn parse_line(line: &str) -> anyhow::Result<Self>;
+    }
+
+    impl<T: LineFrame> Frame for T {
+        fn check(buffer: &mut BytesMut) -> Result<BytesMut, error::FrameCheckError> {
+            const DELIMITER: Delimiter = Delimiter::end(b"\n");
+            loop {
+                let mut line = DELIMITER.check(buffer)?;
+                if line.last() == Some(&b'\r') {
+                    line.truncate(line.len() - 1);
+                }
+                if !line.is_empty() {
+                    return Ok(line);
+                }
+            }
+        }
+
+        fn parse(buffer: BytesMut) -> anyhow::Result<Self> {
+            let line =
+                std::str::from_utf8(&buffer).map_err(|_| error::FrameParseError::InvalidUtf8)?;
+            T::parse_line(line)
+        }
+    }
+
+    /// Trait for protocol frames which can be turned back into the bytes sent by a device.
+    pub trait EncodableFrame: Frame {
+        /// Returns the frame as sent by the device, including start and end sequences.
+        ///
+        /// Passing the bytes to [Frame::check] and [Frame::parse] returns an equal frame for
+        /// every frame returned by [Frame::parse].
+        fn encode(&self) -> BytesMut;
+    }
+
     pub mod error {
         use thiserror::Error;
 
@@ -64,10 +256,52 @@ pub mod protocol {
         pub enum FrameCheckError {
             #[error("No complete frame in buffer")]
             Incomplete,
+            /// The checksum of a complete frame does not match. The frame is not returned, and
+            /// enough of it has been removed from the buffer for the next check to search
+            /// behind its start.
+            #[error("Checksum {actual:#x} does not match {expected:#x}")]
+            ChecksumError { expected: u32, actual: u32 },
             #[error("Other error occured: {0}")]
             Other(String),
         }
 
+        /// Reason a frame could not be parsed
+        #[derive(Error, Debug, Clone, PartialEq)]
+        pub enum FrameParseError {
+            #[error("Frame data is not valid UTF-8")]
+            InvalidUtf8,
+            #[error("Missing field {0}")]
+            MissingField(&'static str),
+            #[error("Value {value} of field {field} is out of range")]
+            OutOfRange { field: &'static str, value: String },
+            #[error("Value {value} of field {field} is not a number")]
+            InvalidNumber { field: &'static str, value: String },
+        }
+
+        impl FrameParseError {
+            /// Parse a numeric field
+            pub fn parse<T>(field: &'static str, value: &str) -> Result<T, FrameParseError>
+            where
+                T: std::str::FromStr<Err = std::num::ParseIntError>,
+            {
+                use std::num::IntErrorKind;
+                value
+                    .parse()
+                    .map_err(|e: std::num::ParseIntError| match e.kind() {
+                        IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => {
+                            FrameParseError::OutOfRange {
+                                field,
+                                value: value.into(),
+                            }
+                        }
+                        _ => FrameParseError::InvalidNumber {
+                            field,
+                            value: value.into(),
+                        },
+                    })
+            }
+        }
+
         #[derive(Error, Debug, PartialEq)]
         pub enum FrameValidation {
             #[error("Frame data contains invalid characters. Input: {0}")]
@@ -79,15 +313,92 @@ pub mod protocol {
 }
 
 /// Serial devices such as USB
+#[cfg(not(target_arch = "wasm32"))]
 pub mod serial {
     use super::FramedListener;
     use crate::Frame;
     use serialport::TTYPort;
     use std::io::Read;
-    use tokio::io::AsyncReadExt;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     impl<F> FramedListener<tokio_serial::SerialStream, F> {
+        /// Send raw bytes to the device, e.g. firmware commands
+        pub async fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+            AsyncWriteExt::write_all(&mut self.port, data).await?;
+            AsyncWriteExt::flush(&mut self.port).await?;
+            Ok(())
+        }
+
+        /// Probe the baud rates `rates` on the first read, see [Self::probe_baud_rate]. Nothing
+        /// is probed if `rates` is empty.
+        pub fn with_auto_baud(mut self, rates: &[u32], timeout: Duration, init: &[u8]) -> Self {
+            self.auto_baud = (!rates.is_empty()).then(|| super::AutoBaud {
+                rates: rates.to_vec(),
+                timeout,
+                init: init.to_vec(),
+            });
+            self
+        }
+
+        /// Switch the port to each of `rates` in turn and send `init`, e.g. commands making
+        /// the firmware report frames, until a valid frame arrives within `timeout`. Returns
+        /// the rate and the frame, `None` once the device closed the stream.
+        ///
+        /// Frames which fail the check or the parsing do not end the probe of a rate, as
+        /// noise of a wrong rate may look like the start of a frame.
+        pub async fn probe_baud_rate(
+            &mut self,
+            rates: &[u32],
+            timeout: Duration,
+            init: &[u8],
+        ) -> anyhow::Result<Option<(u32, F)>>
+        where
+            F: Frame,
+        {
+            use crate::error::{FrameCheckError, InvalidFrame};
+            use serialport::SerialPort;
+
+            for &rate in rates {
+                self.port.set_baud_rate(rate)?;
+                self.buffer.clear();
+                if !init.is_empty() {
+                    self.write_all(init).await?;
+                }
+                let deadline = tokio::time::Instant::now() + timeout;
+                loop {
+                    match tokio::time::timeout_at(deadline, self.read_buffered()).await {
+                        Ok(Ok(frame)) => return Ok(frame.map(|frame| (rate, frame))),
+                        Ok(Err(e)) if e.is::<InvalidFrame>() || e.is::<FrameCheckError>() => {}
+                        Ok(Err(e)) => return Err(e),
+                        Err(_) => break,
+                    }
+                }
+            }
+            let rates: Vec<String> = rates.iter().map(u32::to_string).collect();
+            anyhow::bail!("No valid frame at any baud rate of {}", rates.join(", "))
+        }
+
+        /// Read the next frame, `None` once the device closed the stream. Probes the baud rate
+        /// first if configured by [Self::with_auto_baud].
+        ///
+        /// Cancellation safe: if the returned future is dropped, e.g. by a timeout, all bytes
+        /// received so far stay buffered and the next call continues with them.
         pub async fn read_frame(&mut self) -> anyhow::Result<Option<F>>
+        where
+            F: Frame,
+        {
+            if let Some(probe) = self.auto_baud.clone() {
+                let probed = self
+                    .probe_baud_rate(&probe.rates, probe.timeout, &probe.init)
+                    .await?;
+                self.auto_baud = None;
+                return Ok(probed.map(|(_, frame)| frame));
+            }
+            self.read_buffered().await
+        }
+
+        async fn read_buffered(&mut self) -> anyhow::Result<Option<F>>
         where
             F: Frame,
         {
@@ -97,32 +408,84 @@ pub mod serial {
                 }
 
                 if 0 == AsyncReadExt::read_buf(&mut self.port, &mut self.buffer).await? {
-                    // stream closed. If buffer empty, normal close.
-                    if self.buffer.is_empty() {
-                        return Ok(None);
-                    } else {
-                        return Err(super::error::DeviceError::ConnectionLost)?;
-                    }
+                    return self.end_of_stream();
                 }
             }
         }
+
+        /// Read the next frame, failing with [DeviceError::Timeout] if none is complete within
+        /// `timeout`. Reading may continue after a timeout, see [Self::read_frame].
+        ///
+        /// [DeviceError::Timeout]: super::error::DeviceError::Timeout
+        pub async fn read_frame_timeout(&mut self, timeout: Duration) -> anyhow::Result<Option<F>>
+        where
+            F: Frame,
+        {
+            tokio::time::timeout(timeout, self.read_frame())
+                .await
+                .map_err(|_| super::error::DeviceError::Timeout(timeout))?
+        }
     }
 
+    /// Longest single wait for data of the blocking reader
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Blocking access, e.g. for callers without an async runtime.
+    ///
+    /// Reads wait in `poll(2)` for data, replacing the timeout of the port, so a quiet device
+    /// costs no CPU time.
     impl<F> FramedListener<TTYPort, F> {
+        /// Read the next frame, `None` once the device closed the port
         pub fn read_frame(&mut self) -> anyhow::Result<Option<F>>
         where
             F: Frame,
         {
+            self.read_frame_within(None)
+        }
+
+        /// Read the next frame, failing with [DeviceError::Timeout] if none is complete within
+        /// `timeout`. Received bytes stay buffered for the next read.
+        ///
+        /// [DeviceError::Timeout]: super::error::DeviceError::Timeout
+        pub fn read_frame_timeout(&mut self, timeout: Duration) -> anyhow::Result<Option<F>>
+        where
+            F: Frame,
+        {
+            self.read_frame_within(Some(timeout))
+        }
+
+        fn read_frame_within(&mut self, timeout: Option<Duration>) -> anyhow::Result<Option<F>>
+        where
+            F: Frame,
+        {
+            use serialport::SerialPort;
+            use std::io::ErrorKind;
+
+            let deadline = timeout.map(|timeout| (Instant::now() + timeout, timeout));
             let mut stack_buf = [b'0'; 256];
             loop {
                 if let Some(frame) = self.parse()? {
                     return Ok(Some(frame));
                 }
 
+                let wait = match deadline {
+                    None => POLL_INTERVAL,
+                    Some((deadline, timeout)) => {
+                        match deadline.saturating_duration_since(Instant::now()) {
+                            Duration::ZERO => {
+                                return Err(super::error::DeviceError::Timeout(timeout))?
+                            }
+                            remaining => remaining.min(POLL_INTERVAL),
+                        }
+                    }
+                };
+                self.port.set_timeout(wait)?;
                 match self.port.read(&mut stack_buf) {
-                    Ok(n) if n == 0 => (),
+                    // Readable without data, or hung up: the device closed the port
+                    Ok(0) => return self.end_of_stream(),
+                    Err(e) if e.kind() == ErrorKind::BrokenPipe => return self.end_of_stream(),
                     Ok(n) => self.buffer.extend_from_slice(&stack_buf[0..n]),
-                    Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => (),
+                    Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
                     Err(e) => return Err(e)?,
                 }
             }
@@ -130,6 +493,104 @@ pub mod serial {
     }
 }
 
+/// Ports of runtimes other than tokio, e.g. async-std or smol, which implement the IO traits of
+/// the `futures` crate.
+///
+/// ```no_run
+/// # async fn run(stream: impl futures_io::AsyncRead + futures_io::AsyncWrite + Unpin) -> anyhow::Result<()> {
+/// use sensorflow::{devices::jeelink::JeeLinkFrame, input::futures::FuturesIo, FramedListener};
+///
+/// let mut reader = FramedListener::<_, JeeLinkFrame>::new(FuturesIo(stream));
+/// while let Some(frame) = reader.read_frame().await? {
+///     println!("{}", frame);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(all(feature = "futures-io", not(target_arch = "wasm32")))]
+pub mod futures {
+    use super::FramedListener;
+    use crate::Frame;
+    use futures_io::{AsyncRead, AsyncWrite};
+    use std::future::poll_fn;
+    use std::pin::Pin;
+
+    /// Port implementing the `futures` IO traits
+    pub struct FuturesIo<P>(pub P);
+
+    impl<P: AsyncRead + AsyncWrite + Unpin, F> FramedListener<FuturesIo<P>, F> {
+        /// Send raw bytes to the device, e.g. firmware commands
+        pub async fn write_all(&mut self, mut data: &[u8]) -> anyhow::Result<()> {
+            let port = &mut self.port.0;
+            while !data.is_empty() {
+                let n = poll_fn(|cx| Pin::new(&mut *port).poll_write(cx, data)).await?;
+                if n == 0 {
+                    return Err(std::io::Error::from(std::io::ErrorKind::WriteZero))?;
+                }
+                data = &data[n..];
+            }
+            poll_fn(|cx| Pin::new(&mut *port).poll_flush(cx)).await?;
+            Ok(())
+        }
+    }
+
+    impl<P: AsyncRead + Unpin, F> FramedListener<FuturesIo<P>, F> {
+        /// Read the next frame, `None` once the device closed the stream.
+        ///
+        /// Cancellation safe like the read of tokio ports, so the timeout of any runtime can
+        /// abandon it.
+        pub async fn read_frame(&mut self) -> anyhow::Result<Option<F>>
+        where
+            F: Frame,
+        {
+            let mut stack_buf = [b'0'; 256];
+            loop {
+                if let Some(frame) = self.parse()? {
+                    return Ok(Some(frame));
+                }
+
+                let port = &mut self.port.0;
+                let n = poll_fn(|cx| Pin::new(&mut *port).poll_read(cx, &mut stack_buf)).await?;
+                if n == 0 {
+                    return self.end_of_stream();
+                }
+                self.buffer.extend_from_slice(&stack_buf[..n]);
+            }
+        }
+    }
+}
+
+/// Hexdump of 16 bytes per line with offset and printable characters, e.g.
+/// `00000000  4f 4b 20 39  |OK 9|`
+pub fn hexdump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<_> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text: String = chunk
+                .iter()
+                .map(|&byte| match byte {
+                    0x20..=0x7e => byte as char,
+                    _ => '.',
+                })
+                .collect();
+            format!("{:08x}  {:<47}  |{}|\n", i * 16, hex.join(" "), text)
+        })
+        .collect()
+}
+
+/// Bytes of the hex string `s` of the text protocols, e.g. `0A1F`
+pub fn decode_hex(s: &str) -> Result<Vec<u8>, FrameValidation> {
+    if !s.len().is_multiple_of(2) || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(FrameValidation::InvalidChars(s.to_string()));
+    }
+    Ok((0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).expect("validated as hex"))
+        .collect())
+}
+
 pub mod error {
     use thiserror::Error;
 
@@ -137,5 +598,141 @@ pub mod error {
     pub enum DeviceError {
         #[error("Connection lost to device")]
         ConnectionLost,
+        #[error("No frame received within {0:?}")]
+        Timeout(std::time::Duration),
+        /// A periodic query failed, the next one may succeed. Attached as context to the error
+        /// of the query.
+        #[error("Failed to poll device")]
+        PollFailed,
+    }
+
+    /// Bytes of a complete frame which could not be parsed, attached as context to the parse
+    /// error. Reading may continue with the next frame.
+    #[derive(Error, Debug, Clone, PartialEq)]
+    #[error("Invalid frame of {} bytes", .0.len())]
+    pub struct InvalidFrame(pub Vec<u8>);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_hex, hexdump};
+
+    #[test]
+    fn test_hexdump() {
+        assert_eq!(
+            hexdump(b"OK 9 1 4 129 106\r\n\x00"),
+            "00000000  4f 4b 20 39 20 31 20 34 20 31 32 39 20 31 30 36  |OK 9 1 4 129 106|\n\
+             00000010  0d 0a 00                                         |...|\n"
+        );
+        assert_eq!(hexdump(b""), "");
+    }
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("0a1F").unwrap(), [0x0a, 0x1f]);
+        assert!(decode_hex("").unwrap().is_empty());
+        assert!(decode_hex("0a1").is_err());
+        assert!(decode_hex("0g").is_err());
+    }
+
+    #[test]
+    fn test_frame_stats() {
+        use crate::{devices::jeelink::JeeLinkFrame, error::InvalidFrame, FramedListener};
+
+        let mut reader = FramedListener::<(), JeeLinkFrame>::new(()).with_diagnostics();
+        reader.buffer.extend_from_slice(
+            b"\x00\xffOK 9 50 1 4 193 65\r\n[LaCrosseITPlusReader.10.1s]\r\n\
+              OK 9 12 1 4 200 55\r\nOK 9 x\r\n",
+        );
+        assert_eq!(reader.parse().unwrap().unwrap().id(), 50);
+        assert_eq!(reader.parse().unwrap().unwrap().id(), 12);
+        let error = reader.parse().unwrap_err();
+        assert!(error.downcast_ref::<InvalidFrame>().is_some());
+        assert!(reader.parse().unwrap().is_none());
+
+        let stats = reader.stats().unwrap();
+        assert_eq!(
+            stats.to_string(),
+            "3 frames of 48 bytes, 32 garbage bytes, 1 failures"
+        );
+        assert_eq!(
+            stats.discarded,
+            [
+                b"\x00\xff".to_vec(),
+                b"[LaCrosseITPlusReader.10.1s]\r\n".to_vec()
+            ]
+        );
+        assert!(FramedListener::<(), JeeLinkFrame>::new(())
+            .stats()
+            .is_none());
+    }
+
+    #[cfg(feature = "futures-io")]
+    #[tokio::test]
+    async fn test_futures_io() {
+        use super::futures::FuturesIo;
+        use crate::{devices::jeelink::JeeLinkFrame, input::error::DeviceError, FramedListener};
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        /// Stream returning at most 3 bytes per read, and pending before every read
+        struct Trickle {
+            data: &'static [u8],
+            ready: bool,
+            written: Vec<u8>,
+        }
+
+        impl futures_io::AsyncRead for Trickle {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<std::io::Result<usize>> {
+                if !std::mem::replace(&mut self.ready, false) {
+                    self.ready = true;
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                let n = buf.len().min(self.data.len()).min(3);
+                buf[..n].copy_from_slice(&self.data[..n]);
+                self.data = &self.data[n..];
+                Poll::Ready(Ok(n))
+            }
+        }
+
+        impl futures_io::AsyncWrite for Trickle {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<std::io::Result<usize>> {
+                let n = buf.len().min(3);
+                self.written.extend_from_slice(&buf[..n]);
+                Poll::Ready(Ok(n))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let mut reader = FramedListener::<_, JeeLinkFrame>::new(FuturesIo(Trickle {
+            data: b"\x00OK 9 50 1 4 193 65\r\nOK 9 12 1 4 200 55\r\nOK 9",
+            ready: false,
+            written: Vec::new(),
+        }));
+        reader.write_all(b"1r").await.unwrap();
+        assert_eq!(reader.read_frame().await.unwrap().unwrap().id(), 50);
+        assert_eq!(reader.read_frame().await.unwrap().unwrap().id(), 12);
+        let error = reader.read_frame().await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<DeviceError>(),
+            Some(&DeviceError::ConnectionLost)
+        );
+        assert_eq!(reader.port.0.written, b"1r");
     }
 }
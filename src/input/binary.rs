@@ -0,0 +1,173 @@
+//! Frames of binary protocols with a start sequence and a fixed or length-prefixed size.
+//!
+//! ```
+//! use bytes::BytesMut;
+//! use sensorflow::input::{binary::Framing, checksum::sum16};
+//!
+//! // SDS011: 10 bytes from 0xaa, the low byte of the sum of the data at offset 8
+//! const FRAMING: Framing = Framing::fixed(&[0xaa], 10)
+//!     .checksum(|frame| (sum16(&frame[2..8]) as u8 as u32, frame[8] as u32));
+//!
+//! let frame = [0xaa, 0xc0, 0xd4, 0x04, 0x3a, 0x0a, 0xa1, 0x60, 0x1d, 0xab];
+//! let mut buffer = BytesMut::from(&[&[0x00, 0xab][..], &frame, &[0xaa]].concat()[..]);
+//! assert_eq!(FRAMING.check(&mut buffer).unwrap(), &frame[..]);
+//! assert_eq!(buffer, &[0xaa][..]);
+//! ```
+use super::protocol::error::FrameCheckError;
+use bytes::{Buf, BytesMut};
+use memchr::memmem;
+
+/// Size of the frames
+#[derive(Debug, Clone, Copy)]
+enum Length {
+    Fixed(usize),
+    /// Length of the frame from its header, `None` if the header is invalid
+    Prefixed(usize, fn(&[u8]) -> Option<usize>),
+}
+
+/// Checksum computed over the data of a full frame and the one sent with it
+pub type Checksum = fn(&[u8]) -> (u32, u32);
+
+/// Start sequence, size and checksum of frames
+#[derive(Debug, Clone, Copy)]
+pub struct Framing {
+    start: &'static [u8],
+    length: Length,
+    checksum: Option<Checksum>,
+}
+
+impl Framing {
+    /// Frames of `len` bytes, starting with `start`
+    pub const fn fixed(start: &'static [u8], len: usize) -> Framing {
+        assert!(!start.is_empty(), "Start sequence must not be empty");
+        assert!(len >= start.len(), "Frames must contain the start sequence");
+        Framing {
+            start,
+            length: Length::Fixed(len),
+            checksum: None,
+        }
+    }
+
+    /// Frames starting with `start` and a header of `header_len` bytes including it. `len`
+    /// returns the full length of the frame from the header, or `None` if the header is
+    /// invalid, e.g. since the start sequence occurred in the data of another frame.
+    pub const fn prefixed(
+        start: &'static [u8],
+        header_len: usize,
+        len: fn(&[u8]) -> Option<usize>,
+    ) -> Framing {
+        assert!(!start.is_empty(), "Start sequence must not be empty");
+        assert!(
+            header_len >= start.len(),
+            "Header must contain the start sequence"
+        );
+        Framing {
+            start,
+            length: Length::Prefixed(header_len, len),
+            checksum: None,
+        }
+    }
+
+    /// Reject frames whose checksums differ. `checksum` is called with the full frame and
+    /// returns the checksum computed over its data and the one sent with the frame.
+    pub const fn checksum(mut self, checksum: Checksum) -> Framing {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    /// Implementation of [Frame::check]: remove the next complete and valid frame from
+    /// `buffer` and return it, including its header.
+    ///
+    /// Bytes before the start sequence are discarded, except a tail which may be the beginning
+    /// of the next start sequence. On an invalid header or length the search continues after
+    /// the first byte of the presumed frame. On an invalid checksum that byte is removed as well
+    /// and [FrameCheckError::ChecksumError] returned, the next check continues behind it.
+    ///
+    /// [Frame::check]: super::protocol::Frame::check
+    pub fn check(&self, buffer: &mut BytesMut) -> Result<BytesMut, FrameCheckError> {
+        loop {
+            match memmem::find(buffer, self.start) {
+                Some(i) => buffer.advance(i),
+                None => {
+                    let keep = buffer.len().min(self.start.len() - 1);
+                    buffer.advance(buffer.len() - keep);
+                    return Err(FrameCheckError::Incomplete);
+                }
+            }
+            let len = match self.length {
+                Length::Fixed(len) => len,
+                Length::Prefixed(header_len, _) if buffer.len() < header_len => {
+                    return Err(FrameCheckError::Incomplete)
+                }
+                Length::Prefixed(header_len, len) => match len(&buffer[..header_len]) {
+                    Some(len) if len >= header_len => len,
+                    _ => {
+                        buffer.advance(1);
+                        continue;
+                    }
+                },
+            };
+            if buffer.len() < len {
+                return Err(FrameCheckError::Incomplete);
+            }
+            if let Some(checksum) = self.checksum {
+                let (expected, actual) = checksum(&buffer[..len]);
+                if expected != actual {
+                    // The start sequence may have been part of the data of another frame
+                    buffer.advance(1);
+                    return Err(FrameCheckError::ChecksumError { expected, actual });
+                }
+            }
+            return Ok(buffer.split_to(len));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Framing;
+    use crate::error::FrameCheckError;
+    use bytes::BytesMut;
+
+    #[test]
+    fn test_fixed() {
+        const FRAMING: Framing =
+            Framing::fixed(b"AB", 4).checksum(|frame| (b'!' as u32, frame[3] as u32));
+        let mut buffer = BytesMut::from(&b"xxABc?ABAB1!A"[..]);
+        // The first frame and the one starting in its data have an invalid checksum
+        for actual in [b'?', b'B'] {
+            assert_eq!(
+                FRAMING.check(&mut buffer),
+                Err(FrameCheckError::ChecksumError {
+                    expected: b'!' as u32,
+                    actual: actual as u32
+                })
+            );
+        }
+        assert_eq!(FRAMING.check(&mut buffer).unwrap(), &b"AB1!"[..]);
+        assert_eq!(FRAMING.check(&mut buffer), Err(FrameCheckError::Incomplete));
+        assert_eq!(buffer, &b"A"[..]);
+        buffer.extend_from_slice(b"B2");
+        assert_eq!(FRAMING.check(&mut buffer), Err(FrameCheckError::Incomplete));
+        assert_eq!(buffer, &b"AB2"[..]);
+    }
+
+    #[test]
+    fn test_prefixed() {
+        // Length byte counting the data, which must not be empty
+        const FRAMING: Framing = Framing::prefixed(&[0x55], 2, |header| match header[1] {
+            0 => None,
+            len => Some(2 + len as usize),
+        });
+        let mut buffer = BytesMut::from(&[0x00, 0x55, 0x00, 0x55, 0x02, 0xaa][..]);
+        assert_eq!(FRAMING.check(&mut buffer), Err(FrameCheckError::Incomplete));
+        assert_eq!(buffer, &[0x55, 0x02, 0xaa][..]);
+        buffer.extend_from_slice(&[0xbb, 0x55]);
+        assert_eq!(
+            FRAMING.check(&mut buffer).unwrap(),
+            &[0x55, 0x02, 0xaa, 0xbb][..]
+        );
+        assert_eq!(FRAMING.check(&mut buffer), Err(FrameCheckError::Incomplete));
+        assert_eq!(buffer, &[0x55][..]);
+    }
+}
@@ -0,0 +1,205 @@
+//! Cayenne Low Power Payload (LPP), a compact encoding used by many LoRaWAN end devices.
+//!
+//! A payload is a sequence of values, each prefixed by a channel and a data type. Values are
+//! returned as fields named `<type>_<channel>`, values with several components like the
+//! accelerometer as `<type>_<channel>_<component>`.
+use crate::measurement::FieldValue;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum LppError {
+    #[error("Unknown data type {0} on channel {1}")]
+    UnknownType(u8, u8),
+    #[error("Payload ends within value of channel {0}")]
+    Truncated(u8),
+}
+
+/// Signedness, size in bytes and divisor of a value component
+struct Component {
+    signed: bool,
+    size: usize,
+    divisor: f64,
+}
+
+const fn unsigned(size: usize, divisor: f64) -> Component {
+    Component {
+        signed: false,
+        size,
+        divisor,
+    }
+}
+
+const fn signed(size: usize, divisor: f64) -> Component {
+    Component {
+        signed: true,
+        size,
+        divisor,
+    }
+}
+
+/// Components of a value by name, the name is empty for single values
+type Components = &'static [(&'static str, Component)];
+
+/// Identifier, name and components of the data types
+const DATA_TYPES: &[(u8, &str, Components)] = &[
+    (0, "digital_input", &[("", unsigned(1, 1.))]),
+    (1, "digital_output", &[("", unsigned(1, 1.))]),
+    (2, "analog_input", &[("", signed(2, 100.))]),
+    (3, "analog_output", &[("", signed(2, 100.))]),
+    (100, "generic", &[("", unsigned(4, 1.))]),
+    (101, "illuminance", &[("", unsigned(2, 1.))]),
+    (102, "presence", &[("", unsigned(1, 1.))]),
+    (103, "temperature", &[("", signed(2, 10.))]),
+    (104, "humidity", &[("", unsigned(1, 2.))]),
+    (
+        113,
+        "accelerometer",
+        &[
+            ("x", signed(2, 1000.)),
+            ("y", signed(2, 1000.)),
+            ("z", signed(2, 1000.)),
+        ],
+    ),
+    (115, "barometer", &[("", unsigned(2, 10.))]),
+    (116, "voltage", &[("", unsigned(2, 100.))]),
+    (117, "current", &[("", unsigned(2, 1000.))]),
+    (118, "frequency", &[("", unsigned(4, 1.))]),
+    (120, "percentage", &[("", unsigned(1, 1.))]),
+    (121, "altitude", &[("", signed(2, 1.))]),
+    (125, "concentration", &[("", unsigned(2, 1.))]),
+    (128, "power", &[("", unsigned(2, 1.))]),
+    (130, "distance", &[("", unsigned(4, 1000.))]),
+    (131, "energy", &[("", unsigned(4, 1000.))]),
+    (132, "direction", &[("", unsigned(2, 1.))]),
+    (133, "unix_time", &[("", unsigned(4, 1.))]),
+    (
+        134,
+        "gyrometer",
+        &[
+            ("x", signed(2, 100.)),
+            ("y", signed(2, 100.)),
+            ("z", signed(2, 100.)),
+        ],
+    ),
+    (
+        135,
+        "colour",
+        &[
+            ("r", unsigned(1, 1.)),
+            ("g", unsigned(1, 1.)),
+            ("b", unsigned(1, 1.)),
+        ],
+    ),
+    (
+        136,
+        "gps",
+        &[
+            ("latitude", signed(3, 10000.)),
+            ("longitude", signed(3, 10000.)),
+            ("altitude", signed(3, 100.)),
+        ],
+    ),
+    (142, "switch", &[("", unsigned(1, 1.))]),
+];
+
+/// Read a big endian integer of `bytes.len()` bytes
+fn read_int(bytes: &[u8], signed: bool) -> i64 {
+    let value = bytes
+        .iter()
+        .fold(0i64, |value, byte| (value << 8) | *byte as i64);
+    let bits = 8 * bytes.len() as u32;
+    if signed && value >> (bits - 1) == 1 {
+        value - (1 << bits)
+    } else {
+        value
+    }
+}
+
+/// Decode all values of a payload
+pub fn decode(payload: &[u8]) -> Result<Vec<(String, FieldValue)>, LppError> {
+    let mut values = vec![];
+    let mut rest = payload;
+    while let [channel, type_id, data @ ..] = rest {
+        let (_, name, components) = DATA_TYPES
+            .iter()
+            .find(|(id, _, _)| id == type_id)
+            .ok_or(LppError::UnknownType(*type_id, *channel))?;
+        let size = components.iter().map(|(_, c)| c.size).sum();
+        if data.len() < size {
+            return Err(LppError::Truncated(*channel));
+        }
+        let mut offset = 0;
+        for (component, format) in components.iter() {
+            let raw = read_int(&data[offset..offset + format.size], format.signed);
+            offset += format.size;
+            let field = if component.is_empty() {
+                format!("{}_{}", name, channel)
+            } else {
+                format!("{}_{}_{}", name, channel, component)
+            };
+            let value = if format.divisor == 1. {
+                FieldValue::Integer(raw)
+            } else {
+                FieldValue::Float(raw as f64 / format.divisor)
+            };
+            values.push((field, value));
+        }
+        rest = &data[size..];
+    }
+    if let [channel] = rest {
+        return Err(LppError::Truncated(*channel));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, LppError};
+    use crate::measurement::FieldValue;
+
+    #[test]
+    fn test_decode() {
+        // Examples of the Cayenne LPP documentation
+        assert_eq!(
+            decode(&[0x03, 0x67, 0x01, 0x10, 0x05, 0x67, 0x00, 0xff]).unwrap(),
+            [
+                ("temperature_3".into(), FieldValue::Float(27.2)),
+                ("temperature_5".into(), FieldValue::Float(25.5)),
+            ]
+        );
+        assert_eq!(
+            decode(&[0x06, 0x71, 0x04, 0xd2, 0xfb, 0x2e, 0x00, 0x00]).unwrap(),
+            [
+                ("accelerometer_6_x".into(), FieldValue::Float(1.234)),
+                ("accelerometer_6_y".into(), FieldValue::Float(-1.234)),
+                ("accelerometer_6_z".into(), FieldValue::Float(0.)),
+            ]
+        );
+        assert_eq!(
+            decode(&[0x01, 0x88, 0x06, 0x76, 0x5f, 0xf2, 0x96, 0x0a, 0x00, 0x03, 0xe8]).unwrap(),
+            [
+                ("gps_1_latitude".into(), FieldValue::Float(42.3519)),
+                ("gps_1_longitude".into(), FieldValue::Float(-87.9094)),
+                ("gps_1_altitude".into(), FieldValue::Float(10.)),
+            ]
+        );
+        assert_eq!(
+            decode(&[0x02, 0x65, 0x01, 0x2c, 0x04, 0x00, 0x01]).unwrap(),
+            [
+                ("illuminance_2".into(), FieldValue::Integer(300)),
+                ("digital_input_4".into(), FieldValue::Integer(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reject_invalid() {
+        assert_eq!(
+            decode(&[0x01, 0x50, 0x00]),
+            Err(LppError::UnknownType(0x50, 1))
+        );
+        assert_eq!(decode(&[0x01, 0x67, 0x00]), Err(LppError::Truncated(1)));
+        assert_eq!(decode(&[0x01]), Err(LppError::Truncated(1)));
+        assert_eq!(decode(&[]), Ok(vec![]));
+    }
+}
@@ -0,0 +1,83 @@
+//! Checksums of the frame protocols.
+//!
+//! All CRCs are computed bytewise without lookup tables, the frames are short. A CRC over the
+//! data followed by its CRC is zero for the non-reflected variants, checkers may use that
+//! instead of comparing.
+
+/// CRC-8 with the given polynomial, initial value 0, e.g. 0x07 for EnOcean ESP3 and 0x31 for
+/// LaCrosse IT+
+pub fn crc8(poly: u8, data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ poly
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// CRC-16/XMODEM: polynomial 0x1021, initial value 0, as used by Davis
+pub fn crc16_xmodem(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Reflected CRC-16 with polynomial `poly` in reversed form
+fn crc16_reflected(poly: u16, init: u16, data: &[u8]) -> u16 {
+    data.iter().fold(init, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u16, |crc, _| {
+            if crc & 0x0001 != 0 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// CRC-16/ARC: polynomial 0x8005 reflected, initial value 0, as used by DSMR P1 telegrams
+pub fn crc16_arc(data: &[u8]) -> u16 {
+    crc16_reflected(0xA001, 0, data)
+}
+
+/// CRC-16/X-25: polynomial 0x1021 reflected, initial value and final XOR 0xFFFF, as used by SML
+pub fn crc16_x25(data: &[u8]) -> u16 {
+    !crc16_reflected(0x8408, 0xFFFF, data)
+}
+
+/// Sum of all bytes, as used by the Plantower PMS sensors
+pub fn sum16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |sum, b| sum.wrapping_add(*b as u16))
+}
+
+/// XOR of all bytes, as used by the Amber wireless M-Bus receivers and NMEA 0183
+pub fn xor8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |cs, b| cs ^ b)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_values() {
+        // Check values of the catalogue of parametrised CRC algorithms
+        const CHECK: &[u8] = b"123456789";
+        assert_eq!(crc8(0x07, CHECK), 0xF4);
+        assert_eq!(crc8(0x31, CHECK), 0xA2);
+        assert_eq!(crc16_xmodem(CHECK), 0x31C3);
+        assert_eq!(crc16_arc(CHECK), 0xBB3D);
+        assert_eq!(crc16_x25(CHECK), 0x906E);
+        assert_eq!(sum16(CHECK), 0x01DD);
+        assert_eq!(xor8(CHECK), 0x31);
+    }
+}
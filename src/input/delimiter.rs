@@ -0,0 +1,120 @@
+//! Frames of text protocols, found by their start and end markers.
+//!
+//! ```
+//! use bytes::BytesMut;
+//! use sensorflow::input::delimiter::Delimiter;
+//!
+//! const DELIMITER: Delimiter = Delimiter::new(b"OK 9 ", b"\r\n");
+//!
+//! let mut buffer = BytesMut::from(&b"\x00\xffOK 9 50 1 4 193 65\r\nOK"[..]);
+//! assert_eq!(DELIMITER.check(&mut buffer).unwrap(), &b"50 1 4 193 65"[..]);
+//! assert_eq!(buffer, &b"OK"[..]);
+//! ```
+use super::protocol::error::FrameCheckError;
+use bytes::{Buf, BytesMut};
+use memchr::memmem;
+
+/// Start and end markers of frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delimiter {
+    start: &'static [u8],
+    end: &'static [u8],
+    skip_empty: bool,
+}
+
+impl Delimiter {
+    /// Frames from `start` to `end`, e.g. `OK 9 ` and `\r\n`
+    pub const fn new(start: &'static [u8], end: &'static [u8]) -> Delimiter {
+        assert!(!end.is_empty(), "End marker must not be empty");
+        Delimiter {
+            start,
+            end,
+            skip_empty: false,
+        }
+    }
+
+    /// Frames ending with `end`, e.g. lines
+    pub const fn end(end: &'static [u8]) -> Delimiter {
+        Delimiter::new(b"", end)
+    }
+
+    /// Skip empty frames, e.g. blank lines
+    pub const fn skip_empty(mut self) -> Delimiter {
+        self.skip_empty = true;
+        self
+    }
+
+    /// Implementation of [Frame::check]: remove the next complete frame from `buffer` and
+    /// return it without its markers.
+    ///
+    /// Bytes before the start marker are discarded, except a tail which may be the beginning of
+    /// the next start marker.
+    ///
+    /// [Frame::check]: super::protocol::Frame::check
+    pub fn check(&self, buffer: &mut BytesMut) -> Result<BytesMut, FrameCheckError> {
+        loop {
+            if !self.start.is_empty() {
+                match memmem::find(buffer, self.start) {
+                    Some(i) => buffer.advance(i),
+                    None => {
+                        let keep = buffer.len().min(self.start.len() - 1);
+                        buffer.advance(buffer.len() - keep);
+                        return Err(FrameCheckError::Incomplete);
+                    }
+                }
+            }
+            let Some(i) = memmem::find(&buffer[self.start.len()..], self.end) else {
+                return Err(FrameCheckError::Incomplete);
+            };
+            let mut frame = buffer.split_to(self.start.len() + i);
+            frame.advance(self.start.len());
+            buffer.advance(self.end.len());
+            if frame.is_empty() && self.skip_empty {
+                continue;
+            }
+            return Ok(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Delimiter;
+    use crate::error::FrameCheckError;
+    use bytes::BytesMut;
+
+    #[test]
+    fn test_start_and_end() {
+        let delimiter = Delimiter::new(b"OK 9 ", b"\r\n");
+        let mut buffer = BytesMut::from(&b"garbage OK"[..]);
+        assert_eq!(
+            delimiter.check(&mut buffer),
+            Err(FrameCheckError::Incomplete)
+        );
+        // The tail, which may be the beginning of a start marker, is kept
+        assert_eq!(buffer, &b"e OK"[..]);
+        buffer.extend_from_slice(b" 9 1 2\r\nOK 9 \r\nOK 9 3");
+        assert_eq!(delimiter.check(&mut buffer).unwrap(), &b"1 2"[..]);
+        assert_eq!(delimiter.check(&mut buffer).unwrap(), &b""[..]);
+        assert_eq!(
+            delimiter.check(&mut buffer),
+            Err(FrameCheckError::Incomplete)
+        );
+        assert_eq!(buffer, &b"OK 9 3"[..]);
+    }
+
+    #[test]
+    fn test_lines() {
+        let delimiter = Delimiter::end(b"\r\n").skip_empty();
+        let mut buffer = BytesMut::from(&b"\r\n\r\nF1234\r\nA"[..]);
+        assert_eq!(delimiter.check(&mut buffer).unwrap(), &b"F1234"[..]);
+        assert_eq!(
+            delimiter.check(&mut buffer),
+            Err(FrameCheckError::Incomplete)
+        );
+        assert_eq!(buffer, &b"A"[..]);
+
+        let mut buffer = BytesMut::from(&b">"[..]);
+        assert_eq!(Delimiter::end(b">").check(&mut buffer).unwrap(), &b""[..]);
+    }
+}
@@ -0,0 +1,188 @@
+//! Line settings of serial ports.
+//!
+//! Most devices use 8 data bits, no parity and 1 stop bit (8N1), which is the default. Others,
+//! e.g. optical heads of electricity meters, need different settings like 7E1 at 9600 Bd.
+use serde::Deserialize;
+#[cfg(not(target_arch = "wasm32"))]
+use serialport::{SerialPort, SerialPortBuilder};
+
+/// Parity bit of each character
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+    #[default]
+    None,
+    Odd,
+    Even,
+}
+
+/// Flow control of the port
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControl {
+    #[default]
+    None,
+    /// XON/XOFF characters
+    Software,
+    /// RTS/CTS lines
+    Hardware,
+}
+
+fn default_data_bits() -> u8 {
+    8
+}
+
+fn default_stop_bits() -> u8 {
+    1
+}
+
+fn default_probe_timeout() -> u64 {
+    10
+}
+
+/// Line settings, by default the baud rate of the device and 8N1 without flow control.
+///
+/// ```toml
+/// serial = { data_bits = 7, parity = "even", dtr = true }
+/// ```
+///
+/// Devices whose firmware variants run at different speeds may probe a list of baud rates,
+/// keeping the first at which a valid frame arrives within `probe_timeout` seconds:
+///
+/// ```toml
+/// serial = { auto_baud = [57600, 115200] }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct SerialSettings {
+    /// Baud rate replacing the one of the device
+    pub baud_rate: Option<u32>,
+    /// Baud rates probed in order before the first read, see [Self::probe_timeout]
+    #[serde(default)]
+    pub auto_baud: Vec<u32>,
+    /// Seconds to wait for a valid frame at each rate of [Self::auto_baud]
+    #[serde(default = "default_probe_timeout")]
+    pub probe_timeout: u64,
+    /// Bits per character, 5 to 8
+    #[serde(default = "default_data_bits")]
+    pub data_bits: u8,
+    #[serde(default)]
+    pub parity: Parity,
+    /// 1 or 2
+    #[serde(default = "default_stop_bits")]
+    pub stop_bits: u8,
+    #[serde(default)]
+    pub flow_control: FlowControl,
+    /// Level of DTR set once the port is open, e.g. to power an optical head, left as set by
+    /// the driver if not given
+    pub dtr: Option<bool>,
+    /// Level of RTS set once the port is open
+    pub rts: Option<bool>,
+}
+
+impl Default for SerialSettings {
+    fn default() -> Self {
+        SerialSettings {
+            baud_rate: None,
+            auto_baud: Vec::new(),
+            probe_timeout: default_probe_timeout(),
+            data_bits: default_data_bits(),
+            parity: Parity::default(),
+            stop_bits: default_stop_bits(),
+            flow_control: FlowControl::default(),
+            dtr: None,
+            rts: None,
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SerialSettings {
+    /// Apply the settings besides the baud rate, DTR and RTS to the builder of a port
+    pub fn configure(&self, builder: SerialPortBuilder) -> anyhow::Result<SerialPortBuilder> {
+        use serialport::DataBits;
+
+        let data_bits = match self.data_bits {
+            5 => DataBits::Five,
+            6 => DataBits::Six,
+            7 => DataBits::Seven,
+            8 => DataBits::Eight,
+            bits => anyhow::bail!("Invalid number of data bits {}, expected 5 to 8", bits),
+        };
+        let stop_bits = match self.stop_bits {
+            1 => serialport::StopBits::One,
+            2 => serialport::StopBits::Two,
+            bits => anyhow::bail!("Invalid number of stop bits {}, expected 1 or 2", bits),
+        };
+        let parity = match self.parity {
+            Parity::None => serialport::Parity::None,
+            Parity::Odd => serialport::Parity::Odd,
+            Parity::Even => serialport::Parity::Even,
+        };
+        let flow_control = match self.flow_control {
+            FlowControl::None => serialport::FlowControl::None,
+            FlowControl::Software => serialport::FlowControl::Software,
+            FlowControl::Hardware => serialport::FlowControl::Hardware,
+        };
+        Ok(builder
+            .data_bits(data_bits)
+            .parity(parity)
+            .stop_bits(stop_bits)
+            .flow_control(flow_control))
+    }
+
+    /// Set DTR and RTS of an open port
+    pub fn apply_lines(&self, port: &mut dyn SerialPort) -> anyhow::Result<()> {
+        if let Some(level) = self.dtr {
+            port.write_data_terminal_ready(level)?;
+        }
+        if let Some(level) = self.rts {
+            port.write_request_to_send(level)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Parity, SerialSettings};
+
+    #[test]
+    fn test_settings() {
+        let settings: SerialSettings =
+            toml::from_str("baud_rate = 9600\ndata_bits = 7\nparity = \"even\"\ndtr = true")
+                .unwrap();
+        assert_eq!(
+            settings,
+            SerialSettings {
+                baud_rate: Some(9600),
+                data_bits: 7,
+                parity: Parity::Even,
+                dtr: Some(true),
+                ..SerialSettings::default()
+            }
+        );
+        assert_eq!(
+            toml::from_str::<SerialSettings>("").unwrap(),
+            SerialSettings::default()
+        );
+        assert!(settings
+            .configure(serialport::new("/dev/ttyUSB0", 9600))
+            .is_ok());
+
+        let settings = SerialSettings {
+            stop_bits: 3,
+            ..SerialSettings::default()
+        };
+        assert_eq!(
+            settings
+                .configure(serialport::new("/dev/ttyUSB0", 9600))
+                .unwrap_err()
+                .to_string(),
+            "Invalid number of stop bits 3, expected 1 or 2"
+        );
+    }
+}
@@ -0,0 +1,480 @@
+//! Sensor Measurement Lists (SenML, RFC 8428) in JSON and CBOR representation.
+//!
+//! SenML is sent by many constrained devices over CoAP, MQTT and LwM2M. A pack is a list of
+//! records, where base values (`bn`, `bt`, `bu`, `bv`, `bs`) apply to all following records until
+//! they are overridden. Decoding resolves the base values, so each [Record] is self-contained.
+//!
+//! Records with the same base name and time are grouped into one measurement, see
+//! [to_measurements].
+use crate::measurement::{FieldMeta, FieldValue, Measurement, Unit};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, TimeDelta, Utc};
+use thiserror::Error;
+
+/// Highest SenML version understood
+pub const VERSION: i64 = 10;
+
+/// Times below this value are relative to the current time
+const RELATIVE_TIME_LIMIT: f64 = (1 << 28) as f64;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SenmlError {
+    #[error("Invalid JSON: {0}")]
+    Json(String),
+    #[error("Invalid CBOR: {0}")]
+    Cbor(String),
+    #[error("Invalid value for label {0}")]
+    InvalidValue(String),
+    #[error("Unsupported SenML version {0}")]
+    UnsupportedVersion(i64),
+    #[error("Unknown mandatory label {0}")]
+    UnknownLabel(String),
+    #[error("Record {0} has neither value nor sum")]
+    MissingValue(usize),
+    #[error("Record {0} has no name")]
+    MissingName(usize),
+}
+
+/// Value of a record
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Float(f64),
+    String(String),
+    Boolean(bool),
+    Data(Vec<u8>),
+}
+
+impl From<Value> for FieldValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Float(x) => FieldValue::Float(x),
+            Value::String(s) => FieldValue::String(s),
+            Value::Boolean(b) => FieldValue::Boolean(b),
+            // Opaque data is kept in the base64url encoding of SenML JSON
+            Value::Data(data) => FieldValue::String(URL_SAFE_NO_PAD.encode(data)),
+        }
+    }
+}
+
+/// Record with all base values applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// Base name in effect for the record
+    pub base_name: String,
+    /// Name of the record without the base name
+    pub name: String,
+    pub unit: Option<String>,
+    pub value: Option<Value>,
+    pub sum: Option<f64>,
+    /// Absolute time, `None` if the record was taken roughly now
+    pub time: Option<DateTime<Utc>>,
+}
+
+impl Record {
+    /// Full name of the record, the concatenation of base name and name
+    pub fn full_name(&self) -> String {
+        format!("{}{}", self.base_name, self.name)
+    }
+}
+
+/// Label values as found in either representation
+enum Item {
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    Other,
+}
+
+/// Record as sent, before base values are applied
+#[derive(Default)]
+struct RawRecord {
+    base_version: Option<i64>,
+    base_name: Option<String>,
+    base_time: Option<f64>,
+    base_unit: Option<String>,
+    base_value: Option<f64>,
+    base_sum: Option<f64>,
+    name: Option<String>,
+    unit: Option<String>,
+    value: Option<Value>,
+    sum: Option<f64>,
+    time: Option<f64>,
+}
+
+impl RawRecord {
+    fn set(&mut self, label: &str, item: Item) -> Result<(), SenmlError> {
+        let invalid = || SenmlError::InvalidValue(label.to_string());
+        let number = |item: Item| match item {
+            Item::Number(x) => Ok(x),
+            _ => Err(invalid()),
+        };
+        let text = |item: Item| match item {
+            Item::Text(s) => Ok(s),
+            _ => Err(invalid()),
+        };
+        match label {
+            "bver" => self.base_version = Some(number(item)? as i64),
+            "bn" => self.base_name = Some(text(item)?),
+            "bt" => self.base_time = Some(number(item)?),
+            "bu" => self.base_unit = Some(text(item)?),
+            "bv" => self.base_value = Some(number(item)?),
+            "bs" => self.base_sum = Some(number(item)?),
+            "n" => self.name = Some(text(item)?),
+            "u" => self.unit = Some(text(item)?),
+            "v" => self.value = Some(Value::Float(number(item)?)),
+            "vs" => self.value = Some(Value::String(text(item)?)),
+            "vb" => match item {
+                Item::Bool(b) => self.value = Some(Value::Boolean(b)),
+                _ => return Err(invalid()),
+            },
+            "vd" => {
+                let data = match item {
+                    Item::Bytes(data) => data,
+                    Item::Text(s) => URL_SAFE_NO_PAD
+                        .decode(s.trim_end_matches('='))
+                        .map_err(|_| invalid())?,
+                    _ => return Err(invalid()),
+                };
+                self.value = Some(Value::Data(data));
+            }
+            "s" => self.sum = Some(number(item)?),
+            "t" => self.time = Some(number(item)?),
+            // Update time is only a hint for the next reading
+            "ut" => (),
+            // Labels ending in an underscore must be understood, others may be ignored
+            label if label.ends_with('_') => {
+                return Err(SenmlError::UnknownLabel(label.to_string()))
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// Apply the base values of a pack to its records
+fn resolve(raw: Vec<RawRecord>, now: DateTime<Utc>) -> Result<Vec<Record>, SenmlError> {
+    let mut base = RawRecord::default();
+    let mut records = Vec::with_capacity(raw.len());
+    for (index, raw) in raw.into_iter().enumerate() {
+        if let Some(version) = raw.base_version {
+            if version > VERSION {
+                return Err(SenmlError::UnsupportedVersion(version));
+            }
+        }
+        // Base values stay in effect until overridden
+        base.base_name = raw.base_name.or(base.base_name);
+        base.base_time = raw.base_time.or(base.base_time);
+        base.base_unit = raw.base_unit.or(base.base_unit);
+        base.base_value = raw.base_value.or(base.base_value);
+        base.base_sum = raw.base_sum.or(base.base_sum);
+
+        let value = match raw.value {
+            Some(Value::Float(x)) => Some(Value::Float(x + base.base_value.unwrap_or(0.))),
+            // A base value alone is a valid numeric value
+            None if raw.sum.is_none() => base.base_value.map(Value::Float),
+            value => value,
+        };
+        let sum = raw
+            .sum
+            .map(|s| s + base.base_sum.unwrap_or(0.))
+            .or_else(|| value.is_none().then_some(base.base_sum).flatten());
+        if value.is_none() && sum.is_none() {
+            return Err(SenmlError::MissingValue(index));
+        }
+        let record = Record {
+            base_name: base.base_name.clone().unwrap_or_default(),
+            name: raw.name.unwrap_or_default(),
+            unit: raw.unit.or_else(|| base.base_unit.clone()),
+            value,
+            sum,
+            time: resolve_time(base.base_time.unwrap_or(0.) + raw.time.unwrap_or(0.), now),
+        };
+        if record.base_name.is_empty() && record.name.is_empty() {
+            return Err(SenmlError::MissingName(index));
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn resolve_time(time: f64, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if time == 0. {
+        None
+    } else if time < RELATIVE_TIME_LIMIT {
+        Some(now + TimeDelta::milliseconds((time * 1000.).round() as i64))
+    } else {
+        DateTime::from_timestamp_millis((time * 1000.).round() as i64)
+    }
+}
+
+/// Decode a pack in JSON representation (content format 110)
+pub fn decode_json(payload: &[u8]) -> Result<Vec<Record>, SenmlError> {
+    let pack: Vec<serde_json::Map<String, serde_json::Value>> =
+        serde_json::from_slice(payload).map_err(|e| SenmlError::Json(e.to_string()))?;
+    let mut raw = Vec::with_capacity(pack.len());
+    for entries in pack {
+        let mut record = RawRecord::default();
+        for (label, value) in entries {
+            let item = match value {
+                serde_json::Value::Number(x) => x.as_f64().map_or(Item::Other, Item::Number),
+                serde_json::Value::String(s) => Item::Text(s),
+                serde_json::Value::Bool(b) => Item::Bool(b),
+                _ => Item::Other,
+            };
+            record.set(&label, item)?;
+        }
+        raw.push(record);
+    }
+    resolve(raw, Utc::now())
+}
+
+/// Names of the integer labels of the CBOR representation
+fn cbor_label(label: i128) -> Option<&'static str> {
+    Some(match label {
+        -1 => "bver",
+        -2 => "bn",
+        -3 => "bt",
+        -4 => "bu",
+        -5 => "bv",
+        -6 => "bs",
+        0 => "n",
+        1 => "u",
+        2 => "v",
+        3 => "vs",
+        4 => "vb",
+        5 => "s",
+        6 => "t",
+        7 => "ut",
+        8 => "vd",
+        _ => return None,
+    })
+}
+
+/// Decode a pack in CBOR representation (content format 112)
+pub fn decode_cbor(payload: &[u8]) -> Result<Vec<Record>, SenmlError> {
+    use ciborium::Value as Cbor;
+
+    let pack: Cbor = ciborium::from_reader(payload).map_err(|e| SenmlError::Cbor(e.to_string()))?;
+    let Cbor::Array(pack) = pack else {
+        return Err(SenmlError::Cbor("pack is not an array".into()));
+    };
+    let mut raw = Vec::with_capacity(pack.len());
+    for entries in pack {
+        let Cbor::Map(entries) = entries else {
+            return Err(SenmlError::Cbor("record is not a map".into()));
+        };
+        let mut record = RawRecord::default();
+        for (label, value) in entries {
+            let item = match value {
+                Cbor::Integer(i) => Item::Number(i128::from(i) as f64),
+                Cbor::Float(x) => Item::Number(x),
+                Cbor::Text(s) => Item::Text(s),
+                Cbor::Bool(b) => Item::Bool(b),
+                Cbor::Bytes(data) => Item::Bytes(data),
+                _ => Item::Other,
+            };
+            match label {
+                Cbor::Integer(i) => match cbor_label(i128::from(i)) {
+                    Some(label) => record.set(label, item)?,
+                    // Negative labels of unknown base fields must be understood
+                    None if i128::from(i) < 0 => {
+                        return Err(SenmlError::UnknownLabel(i128::from(i).to_string()))
+                    }
+                    None => (),
+                },
+                Cbor::Text(label) => record.set(&label, item)?,
+                _ => return Err(SenmlError::Cbor("invalid label".into())),
+            }
+        }
+        raw.push(record);
+    }
+    resolve(raw, Utc::now())
+}
+
+/// Group records into measurements
+///
+/// Consecutive records with the same base name and time form one measurement. It is named
+/// after the base name without trailing separators, or `default_name` if there is no base name.
+/// Each record becomes a field named after the record, `value` if it has no name of its own.
+/// The sum of a record is stored in the counter `<name>_sum` and its unit in the tag
+/// `<name>_unit` as well as in the [FieldMeta] of the fields.
+pub fn to_measurements(records: &[Record], default_name: &str) -> Vec<Measurement> {
+    let mut measurements = vec![];
+    let mut group: Option<(&str, Option<DateTime<Utc>>, Measurement)> = None;
+    for record in records {
+        let measurement = match group.take() {
+            Some((base_name, time, measurement))
+                if base_name == record.base_name && time == record.time =>
+            {
+                measurement
+            }
+            previous => {
+                measurements.extend(previous.map(|(_, _, measurement)| measurement));
+                let name = record.base_name.trim_end_matches([':', '/', '.', '_', '-']);
+                let name = if name.is_empty() { default_name } else { name };
+                Measurement::new(name).add_time(record.time)
+            }
+        };
+        let field = if record.name.is_empty() {
+            "value"
+        } else {
+            record.name.as_str()
+        };
+        let mut measurement = measurement;
+        let unit = record.unit.as_deref().map(Unit::from_symbol);
+        if let Some(unit) = &record.unit {
+            measurement = measurement.add_tag(format!("{}_unit", field), unit);
+        }
+        if let Some(value) = &record.value {
+            measurement = measurement.add_field(field, value.clone());
+            if let Some(unit) = &unit {
+                measurement = measurement.add_field_meta(field, unit.clone());
+            }
+        }
+        if let Some(sum) = record.sum {
+            let sum_field = format!("{}_sum", field);
+            measurement = measurement
+                .add_field(&sum_field, sum)
+                .add_field_meta(sum_field, FieldMeta::counter(unit));
+        }
+        group = Some((&record.base_name, record.time, measurement));
+    }
+    measurements.extend(group.map(|(_, _, measurement)| measurement));
+    measurements
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        decode_cbor, decode_json, resolve, to_measurements, RawRecord, Record, SenmlError, Value,
+    };
+    use crate::measurement::{FieldMeta, FieldValue, Unit};
+    use chrono::{DateTime, TimeDelta};
+
+    /// Multiple measurements of RFC 8428, section 5.1.2
+    const PACK: &str = r#"[
+        {"bn":"urn:dev:ow:10e2073a01080063:","bt":1.276020076001e+09,"bu":"A","bver":5,
+         "n":"voltage","u":"V","v":120.1},
+        {"n":"current","t":-5,"v":1.2},
+        {"n":"current","t":-4,"v":1.3}
+    ]"#;
+
+    #[test]
+    fn test_decode_json() {
+        let records = decode_json(PACK.as_bytes()).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(
+            records[0],
+            Record {
+                base_name: "urn:dev:ow:10e2073a01080063:".into(),
+                name: "voltage".into(),
+                unit: Some("V".into()),
+                value: Some(Value::Float(120.1)),
+                sum: None,
+                time: DateTime::from_timestamp_millis(1276020076001),
+            }
+        );
+        assert_eq!(
+            records[1].full_name(),
+            "urn:dev:ow:10e2073a01080063:current"
+        );
+        assert_eq!(records[1].unit.as_deref(), Some("A"));
+        assert_eq!(
+            records[2].time,
+            DateTime::from_timestamp_millis(1276020072001)
+        );
+    }
+
+    #[test]
+    fn test_decode_cbor() {
+        // [{-2: "dev1/", -5: 20.0, 0: "temp", 1: "Cel", 2: 1.5}, {0: "raw", 8: h'0102'}]
+        let payload = [
+            0x82, 0xa5, 0x21, 0x65, b'd', b'e', b'v', b'1', b'/', 0x24, 0xf9, 0x4d, 0x00, 0x00,
+            0x64, b't', b'e', b'm', b'p', 0x01, 0x63, b'C', b'e', b'l', 0x02, 0xf9, 0x3e, 0x00,
+            0xa2, 0x00, 0x63, b'r', b'a', b'w', 0x08, 0x42, 0x01, 0x02,
+        ];
+        let records = decode_cbor(&payload).unwrap();
+        assert_eq!(records[0].full_name(), "dev1/temp");
+        assert_eq!(records[0].value, Some(Value::Float(21.5)));
+        assert_eq!(records[1].value, Some(Value::Data(vec![1, 2])));
+        assert!(decode_cbor(&[0xa0]).is_err());
+    }
+
+    #[test]
+    fn test_reject_invalid_packs() {
+        assert_eq!(
+            decode_json(br#"[{"n":"a"}]"#),
+            Err(SenmlError::MissingValue(0))
+        );
+        assert_eq!(
+            decode_json(br#"[{"v":1}]"#),
+            Err(SenmlError::MissingName(0))
+        );
+        assert_eq!(
+            decode_json(br#"[{"bver":11,"n":"a","v":1}]"#),
+            Err(SenmlError::UnsupportedVersion(11))
+        );
+        assert_eq!(
+            decode_json(br#"[{"n":"a","v":1,"new_":1}]"#),
+            Err(SenmlError::UnknownLabel("new_".into()))
+        );
+        assert_eq!(
+            decode_json(br#"[{"n":"a","v":"1"}]"#),
+            Err(SenmlError::InvalidValue("v".into()))
+        );
+        // Unknown optional labels are ignored
+        assert!(decode_json(br#"[{"n":"a","v":1,"foo":2}]"#).is_ok());
+    }
+
+    #[test]
+    fn test_relative_time() {
+        let now = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let raw = vec![
+            RawRecord {
+                name: Some("a".into()),
+                value: Some(Value::Float(1.)),
+                ..Default::default()
+            },
+            RawRecord {
+                name: Some("b".into()),
+                sum: Some(2.),
+                time: Some(-60.),
+                ..Default::default()
+            },
+        ];
+        let records = resolve(raw, now).unwrap();
+        assert_eq!(records[0].time, None);
+        assert_eq!(records[1].time, Some(now - TimeDelta::seconds(60)));
+    }
+
+    #[test]
+    fn test_group_into_measurements() {
+        let records = decode_json(PACK.as_bytes()).unwrap();
+        let measurements = to_measurements(&records, "senml");
+        assert_eq!(measurements.len(), 3);
+        assert_eq!(measurements[0].name, "urn:dev:ow:10e2073a01080063");
+        assert_eq!(measurements[0].tag("voltage_unit"), Some("V"));
+        assert_eq!(
+            measurements[0].field("voltage"),
+            Some(&FieldValue::Float(120.1))
+        );
+
+        let records = decode_json(
+            br#"[{"n":"temp","u":"Cel","v":21.5},{"n":"energy","u":"Wh","s":1200},
+                 {"n":"door","vb":true}]"#,
+        )
+        .unwrap();
+        let measurements = to_measurements(&records, "senml");
+        assert_eq!(measurements.len(), 1);
+        assert_eq!(
+            measurements[0].to_string(),
+            "senml temp_unit=Cel energy_unit=Wh: temp=21.5 energy_sum=1200 door=true"
+        );
+        assert_eq!(measurements[0].field_meta("temp"), Unit::Celsius.into());
+        assert_eq!(
+            measurements[0].field_meta("energy_sum"),
+            FieldMeta::counter(Some(Unit::Other("Wh".into())))
+        );
+    }
+}
@@ -1,15 +1,47 @@
 extern crate anyhow;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod backfill;
+#[cfg(feature = "bench")]
+pub mod bench;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod clock;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod config;
 pub mod devices;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
 pub mod input;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod mdns;
+pub mod measurement;
 pub mod output;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pipeline;
+pub mod schema;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod state;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod store;
+#[cfg(feature = "proptest")]
+pub mod strategies;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tls;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod transform;
 
 // Rexport main API
-pub use input::protocol::Frame;
+pub use input::protocol::{EncodableFrame, Frame, LineFrame};
+#[cfg(not(target_arch = "wasm32"))]
 pub use input::FramedListener;
+pub use measurement::Measurement;
 
 /// Rexports all error types
 pub mod error {
     pub use crate::input::error::*;
     pub use crate::input::protocol::error::*;
+    pub use crate::output::error::*;
 }
@@ -0,0 +1,117 @@
+//! Announcement of servers on the local network.
+//!
+//! Dashboards and companion apps find gateways without knowing their addresses when the
+//! servers announce themselves via mDNS, as Avahi and Bonjour do. Servers with an `announce`
+//! setting register a service of type [SERVICE_TYPE] under the given instance name, with the
+//! port they listen on, the host's addresses and a TXT record naming the `protocol`, e.g.
+//! `http`. The service is withdrawn when the server shuts down. Announcing requires the `mdns`
+//! feature.
+//!
+//! ```toml
+//! [[outputs]]
+//! type = "api"
+//! listen = "0.0.0.0:8080"
+//! announce = "Cellar gateway"
+//! ```
+
+/// Service type of all announced servers
+pub const SERVICE_TYPE: &str = "_sensorflow._tcp.local.";
+
+/// Name of this host in the `.local` domain, e.g. `raspberrypi.local.`
+#[cfg(feature = "mdns")]
+fn host_name() -> String {
+    let name = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .unwrap_or_default();
+    let name = name.trim().trim_end_matches(".local");
+    match name.is_empty() {
+        true => "sensorflow.local.".into(),
+        false => format!("{}.local.", name),
+    }
+}
+
+/// Registered service, withdrawn when dropped
+pub struct Announcement {
+    #[cfg(feature = "mdns")]
+    daemon: mdns_sd::ServiceDaemon,
+    #[cfg(feature = "mdns")]
+    fullname: String,
+}
+
+impl Announcement {
+    /// Announce the server `instance` listening on `port`, speaking `protocol`
+    #[cfg(feature = "mdns")]
+    pub fn new(instance: &str, port: u16, protocol: &str) -> anyhow::Result<Announcement> {
+        use anyhow::Context;
+        use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+        let properties = [("protocol", protocol)];
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            instance,
+            &host_name(),
+            "",
+            port,
+            &properties[..],
+        )?
+        .enable_addr_auto();
+        let fullname = service.get_fullname().to_string();
+        let daemon = ServiceDaemon::new().context("Failed to start mDNS responder")?;
+        daemon
+            .register(service)
+            .with_context(|| format!("Failed to announce {}", fullname))?;
+        Ok(Announcement { daemon, fullname })
+    }
+
+    #[cfg(not(feature = "mdns"))]
+    pub fn new(_instance: &str, _port: u16, _protocol: &str) -> anyhow::Result<Announcement> {
+        Err(anyhow::anyhow!(
+            "mDNS announcements require the `mdns` feature"
+        ))
+    }
+
+    /// Full name of the service, e.g. `Cellar gateway._sensorflow._tcp.local.`
+    #[cfg(feature = "mdns")]
+    pub fn fullname(&self) -> &str {
+        &self.fullname
+    }
+}
+
+#[cfg(feature = "mdns")]
+impl Drop for Announcement {
+    fn drop(&mut self) {
+        // Goodbye packets are sent by the daemon thread, which ends after them
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Announcement;
+
+    #[cfg(not(feature = "mdns"))]
+    #[test]
+    fn test_announcement_requires_feature() {
+        assert_eq!(
+            Announcement::new("gateway", 8080, "http")
+                .err()
+                .unwrap()
+                .to_string(),
+            "mDNS announcements require the `mdns` feature"
+        );
+    }
+
+    #[cfg(feature = "mdns")]
+    #[test]
+    fn test_announcement() {
+        assert!(super::host_name().ends_with(".local."));
+        // Sandboxes may lack multicast interfaces, the name is known before registering
+        if let Ok(announcement) = Announcement::new("Cellar gateway", 8080, "http") {
+            assert_eq!(
+                announcement.fullname(),
+                "Cellar gateway._sensorflow._tcp.local."
+            );
+        }
+    }
+}
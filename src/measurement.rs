@@ -0,0 +1,440 @@
+//! Device independent representation of sensor readings.
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+/// Value of a single measurement field
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    UInteger(u64),
+    String(String),
+    Boolean(bool),
+}
+
+impl FieldValue {
+    /// Value of a numeric field, `None` for strings and booleans
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Float(x) => Some(*x),
+            Self::Integer(x) => Some(*x as f64),
+            Self::UInteger(x) => Some(*x as f64),
+            Self::String(_) | Self::Boolean(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Float(x) => write!(f, "{}", x),
+            Self::Integer(x) => write!(f, "{}", x),
+            Self::UInteger(x) => write!(f, "{}", x),
+            Self::String(x) => write!(f, "{}", x),
+            Self::Boolean(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl From<i64> for FieldValue {
+    fn from(x: i64) -> Self {
+        FieldValue::Integer(x)
+    }
+}
+
+impl From<u64> for FieldValue {
+    fn from(x: u64) -> Self {
+        FieldValue::UInteger(x)
+    }
+}
+
+impl From<f64> for FieldValue {
+    fn from(x: f64) -> Self {
+        FieldValue::Float(x)
+    }
+}
+
+impl From<&str> for FieldValue {
+    fn from(x: &str) -> Self {
+        FieldValue::String(x.into())
+    }
+}
+
+impl From<String> for FieldValue {
+    fn from(x: String) -> Self {
+        FieldValue::String(x)
+    }
+}
+
+impl From<bool> for FieldValue {
+    fn from(x: bool) -> Self {
+        FieldValue::Boolean(x)
+    }
+}
+
+/// Physical unit of a field
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "String", from = "String")
+)]
+pub enum Unit {
+    Celsius,
+    /// Percent, e.g. of relative humidity
+    Percent,
+    Hectopascal,
+    /// Parts per million, e.g. of CO₂
+    Ppm,
+    MicrogramPerCubicMetre,
+    MetrePerSecond,
+    Millimetre,
+    MillimetrePerHour,
+    /// Angle in degrees, e.g. of the wind direction
+    Degree,
+    Volt,
+    Watt,
+    WattPerSquareMetre,
+    KilowattHour,
+    /// Revolutions per minute
+    Rpm,
+    Dbm,
+    /// Any other unit, by its symbol
+    Other(String),
+}
+
+impl Unit {
+    /// Symbol of the unit, e.g. `°C`
+    pub fn symbol(&self) -> &str {
+        match self {
+            Unit::Celsius => "°C",
+            Unit::Percent => "%",
+            Unit::Hectopascal => "hPa",
+            Unit::Ppm => "ppm",
+            Unit::MicrogramPerCubicMetre => "µg/m³",
+            Unit::MetrePerSecond => "m/s",
+            Unit::Millimetre => "mm",
+            Unit::MillimetrePerHour => "mm/h",
+            Unit::Degree => "°",
+            Unit::Volt => "V",
+            Unit::Watt => "W",
+            Unit::WattPerSquareMetre => "W/m²",
+            Unit::KilowattHour => "kWh",
+            Unit::Rpm => "rpm",
+            Unit::Dbm => "dBm",
+            Unit::Other(symbol) => symbol,
+        }
+    }
+
+    /// Unit of a symbol, also accepting the ASCII spelling and the SenML name of a unit, e.g.
+    /// `Cel` or `%RH`
+    pub fn from_symbol(symbol: &str) -> Unit {
+        match symbol {
+            "°C" | "Cel" => Unit::Celsius,
+            "%" | "%RH" => Unit::Percent,
+            "hPa" => Unit::Hectopascal,
+            "ppm" => Unit::Ppm,
+            "µg/m³" | "ug/m3" => Unit::MicrogramPerCubicMetre,
+            "m/s" => Unit::MetrePerSecond,
+            "mm" => Unit::Millimetre,
+            "mm/h" => Unit::MillimetrePerHour,
+            "°" | "deg" => Unit::Degree,
+            "V" => Unit::Volt,
+            "W" => Unit::Watt,
+            "W/m²" | "W/m2" => Unit::WattPerSquareMetre,
+            "kWh" => Unit::KilowattHour,
+            "rpm" | "RPM" => Unit::Rpm,
+            "dBm" => Unit::Dbm,
+            other => Unit::Other(other.into()),
+        }
+    }
+
+    /// Suffix of metric names in the Prometheus conventions, `None` for other units
+    pub fn metric_suffix(&self) -> Option<&'static str> {
+        Some(match self {
+            Unit::Celsius => "celsius",
+            Unit::Percent => "percent",
+            Unit::Hectopascal => "hectopascals",
+            Unit::Ppm => "ppm",
+            Unit::MicrogramPerCubicMetre => "micrograms_per_cubic_meter",
+            Unit::MetrePerSecond => "meters_per_second",
+            Unit::Millimetre => "millimeters",
+            Unit::MillimetrePerHour => "millimeters_per_hour",
+            Unit::Degree => "degrees",
+            Unit::Volt => "volts",
+            Unit::Watt => "watts",
+            Unit::WattPerSquareMetre => "watts_per_square_meter",
+            Unit::KilowattHour => "kilowatt_hours",
+            Unit::Rpm => "rpm",
+            Unit::Dbm => "dbm",
+            Unit::Other(_) => return None,
+        })
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.symbol())
+    }
+}
+
+impl From<String> for Unit {
+    fn from(symbol: String) -> Self {
+        Unit::from_symbol(&symbol)
+    }
+}
+
+impl From<Unit> for String {
+    fn from(unit: Unit) -> Self {
+        unit.symbol().into()
+    }
+}
+
+/// Whether a field is a momentary value or a total which only ever increases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum FieldKind {
+    #[default]
+    Gauge,
+    Counter,
+}
+
+/// Unit and kind of a field
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FieldMeta {
+    pub unit: Option<Unit>,
+    pub kind: FieldKind,
+}
+
+impl FieldMeta {
+    /// Total in `unit`, or a plain count if `None`
+    pub fn counter(unit: Option<Unit>) -> FieldMeta {
+        FieldMeta {
+            unit,
+            kind: FieldKind::Counter,
+        }
+    }
+}
+
+/// Gauge in the unit
+impl From<Unit> for FieldMeta {
+    fn from(unit: Unit) -> Self {
+        FieldMeta {
+            unit: Some(unit),
+            kind: FieldKind::Gauge,
+        }
+    }
+}
+
+/// A single reading, consisting of a name, identifying tags, values and an optional timestamp.
+///
+/// Measurements are what flows through a pipeline. Devices produce them from their frames and
+/// output sinks serialize them into their respective format.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Measurement {
+    pub name: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    /// Unit and kind of the fields which have them
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub meta: Vec<(String, FieldMeta)>,
+    pub time: Option<DateTime<Utc>>,
+}
+
+impl Measurement {
+    pub fn new(name: impl Into<String>) -> Measurement {
+        Measurement {
+            name: name.into(),
+            tags: vec![],
+            fields: vec![],
+            meta: vec![],
+            time: None,
+        }
+    }
+
+    pub fn add_tag(mut self, name: impl Into<String>, tag: impl fmt::Display) -> Measurement {
+        self.tags.push((name.into(), tag.to_string()));
+        self
+    }
+
+    pub fn add_field<V>(mut self, name: impl Into<String>, value: V) -> Measurement
+    where
+        V: Into<FieldValue>,
+    {
+        self.fields.push((name.into(), value.into()));
+        self
+    }
+
+    /// Set unit and kind of a field, e.g. `add_field_meta("temperature", Unit::Celsius)`
+    pub fn add_field_meta(
+        mut self,
+        name: impl Into<String>,
+        meta: impl Into<FieldMeta>,
+    ) -> Measurement {
+        let name = name.into();
+        let meta = meta.into();
+        match self.meta.iter_mut().find(|(key, _)| *key == name) {
+            Some((_, existing)) => *existing = meta,
+            None => self.meta.push((name, meta)),
+        }
+        self
+    }
+
+    pub fn add_time(mut self, time: Option<DateTime<Utc>>) -> Measurement {
+        self.time = time;
+        self
+    }
+
+    /// Set the timestamp if the measurement does not carry one yet.
+    pub fn stamp(mut self, time: DateTime<Utc>) -> Measurement {
+        self.time.get_or_insert(time);
+        self
+    }
+
+    /// Returns the value of the tag with the given name
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the value of the field with the given name
+    pub fn field(&self, name: &str) -> Option<&FieldValue> {
+        self.fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns unit and kind of the field with the given name, a gauge without unit if unknown
+    pub fn field_meta(&self, name: &str) -> FieldMeta {
+        self.meta
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, meta)| meta.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (key, value) in &self.tags {
+            write!(f, " {}={}", key, value)?;
+        }
+        write!(f, ":")?;
+        for (key, value) in &self.fields {
+            write!(f, " {}={}", key, value)?;
+        }
+        if let Some(time) = self.time {
+            write!(f, " @ {}", time.to_rfc3339())?;
+        }
+        Ok(())
+    }
+}
+
+/// Conversion of device frames into measurements
+pub trait ToMeasurement {
+    fn to_measurement(&self) -> Measurement;
+}
+
+impl ToMeasurement for Measurement {
+    fn to_measurement(&self) -> Measurement {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FieldKind, FieldMeta, FieldValue, Measurement, Unit};
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_stamp_keeps_existing_time() {
+        let t1 = Utc.timestamp_opt(1, 0).unwrap();
+        let t2 = Utc.timestamp_opt(2, 0).unwrap();
+        assert_eq!(Measurement::new("m").stamp(t1).time, Some(t1));
+        assert_eq!(
+            Measurement::new("m").add_time(Some(t1)).stamp(t2).time,
+            Some(t1)
+        );
+    }
+
+    #[test]
+    fn test_lookup_of_tags_and_fields() {
+        let m = Measurement::new("m")
+            .add_tag("sensorId", 5)
+            .add_field("temperature", 21.5);
+        assert_eq!(m.tag("sensorId"), Some("5"));
+        assert_eq!(m.tag("other"), None);
+        assert_eq!(m.field("temperature"), Some(&FieldValue::Float(21.5)));
+    }
+
+    #[test]
+    fn test_display() {
+        let m = Measurement::new("tempHum")
+            .add_tag("sensorId", 5)
+            .add_field("temperature", 21.5)
+            .add_field("weak_battery", false);
+        assert_eq!(
+            m.to_string(),
+            "tempHum sensorId=5: temperature=21.5 weak_battery=false"
+        );
+    }
+
+    #[test]
+    fn test_field_meta() {
+        let m = Measurement::new("meter")
+            .add_field("power", 230.)
+            .add_field_meta("power", Unit::Watt)
+            .add_field("energy", 12.5)
+            .add_field_meta("energy", Unit::Watt)
+            .add_field_meta("energy", FieldMeta::counter(Some(Unit::KilowattHour)))
+            .add_field("state", "on");
+        assert_eq!(m.field_meta("power"), Unit::Watt.into());
+        assert_eq!(m.field_meta("energy").kind, FieldKind::Counter);
+        assert_eq!(m.field_meta("energy").unit, Some(Unit::KilowattHour));
+        assert_eq!(m.field_meta("state"), FieldMeta::default());
+        assert_eq!(m.meta.len(), 2);
+    }
+
+    #[test]
+    fn test_unit_symbols() {
+        assert_eq!(Unit::from_symbol("Cel"), Unit::Celsius);
+        assert_eq!(Unit::from_symbol("%RH"), Unit::Percent);
+        assert_eq!(Unit::from_symbol("l/h"), Unit::Other("l/h".into()));
+        for unit in [
+            Unit::Celsius,
+            Unit::MicrogramPerCubicMetre,
+            Unit::WattPerSquareMetre,
+        ] {
+            assert_eq!(Unit::from_symbol(unit.symbol()), unit);
+        }
+        assert_eq!(Unit::Hectopascal.to_string(), "hPa");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let m = Measurement::new("tempHum")
+            .add_tag("sensorId", 5)
+            .add_field("temperature", 21.5)
+            .add_field("count", 3u64)
+            .add_field_meta("temperature", Unit::Celsius)
+            .add_time(Utc.timestamp_opt(1700000000, 0).single());
+        let json = serde_json::to_value(&m).unwrap();
+        assert_eq!(json["fields"][1][1]["uinteger"], 3);
+        assert_eq!(json["time"], "2023-11-14T22:13:20Z");
+        assert_eq!(
+            json["meta"][0][1],
+            serde_json::json!({"unit": "°C", "kind": "gauge"})
+        );
+        assert_eq!(serde_json::from_value::<Measurement>(json).unwrap(), m);
+    }
+}
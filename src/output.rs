@@ -1,18 +1,193 @@
 //! Adapter for data output
+use crate::measurement::{Measurement, ToMeasurement};
+use async_trait::async_trait;
 
-pub trait ToOutput: ToString + influx::ToLineProtocol {}
+#[cfg(not(target_arch = "wasm32"))]
+pub mod api;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "aws-iot")]
+pub mod aws_iot;
+#[cfg(feature = "azure-iot")]
+pub mod azure_iot;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod batch;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod domoticz;
+#[cfg(feature = "email")]
+pub mod email;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod exec;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod fhem;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod file;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod journal;
+pub mod json;
+#[cfg(feature = "knx")]
+pub mod knx;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod loxone;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod openhab;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+#[cfg(feature = "pubsub")]
+pub mod pubsub;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod remote_write;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod retry;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod signalk;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod statsd;
+#[cfg(feature = "template")]
+pub mod template;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod udp;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod validate;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod writer;
+
+pub trait ToOutput: ToString + ToMeasurement {}
+
+impl ToOutput for Measurement {}
+
+/// Destination of measurements.
+///
+/// A sink is started once before the first write and shut down once after the last one.
+/// Implementations may buffer writes, but must have emitted all data after `flush` returned.
+#[async_trait]
+pub trait OutputSink: Send {
+    /// Prepare the sink, e.g. open connections.
+    async fn start(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Write a single measurement.
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()>;
+
+    /// Emit all buffered measurements.
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Time at which the sink wants to be flushed even if no measurement arrives until then,
+    /// e.g. to bound the latency of a batch.
+    fn next_flush(&self) -> Option<tokio::time::Instant> {
+        None
+    }
+
+    /// Flush and release all resources held by the sink.
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.flush().await
+    }
+}
+
+#[async_trait]
+impl<S: OutputSink + ?Sized> OutputSink for Box<S> {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        (**self).start().await
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        (**self).write(measurement).await
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        (**self).flush().await
+    }
+
+    fn next_flush(&self) -> Option<tokio::time::Instant> {
+        (**self).next_flush()
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        (**self).shutdown().await
+    }
+}
+
+pub mod error {
+    use thiserror::Error;
+
+    /// Errors sinks may return to control retrying, see [super::retry]
+    #[derive(Error, Debug)]
+    pub enum SinkError {
+        #[error("Temporary failure, retry possible: {0}")]
+        Retryable(String),
+        #[error("Permanent failure: {0}")]
+        Fatal(String),
+    }
+}
+
+/// Human readable output
+pub mod stringify {
+    use super::OutputSink;
+    use crate::Measurement;
+    use async_trait::async_trait;
+    use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+
+    /// Sink writing one human readable line per measurement
+    pub struct StringifySink<W: AsyncWrite> {
+        writer: BufWriter<W>,
+    }
+
+    impl<W: AsyncWrite> StringifySink<W> {
+        pub fn new(writer: W) -> StringifySink<W> {
+            StringifySink {
+                writer: BufWriter::new(writer),
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    impl StringifySink<tokio::io::Stdout> {
+        pub fn stdout() -> Self {
+            Self::new(tokio::io::stdout())
+        }
+    }
+
+    #[async_trait]
+    impl<W: AsyncWrite + Unpin + Send> OutputSink for StringifySink<W> {
+        async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+            self.writer
+                .write_all(format!("{}\n", measurement).as_bytes())
+                .await?;
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> anyhow::Result<()> {
+            self.writer.flush().await?;
+            Ok(())
+        }
+    }
+}
 
 pub mod influx {
+    use super::OutputSink;
+    use crate::measurement::{FieldValue, Measurement, ToMeasurement};
+    use async_trait::async_trait;
     use chrono::{DateTime, Utc};
     use std::fmt;
+    use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
 
+    /// Time of a line, omitted if not representable as nanoseconds, i.e. after 2262
     struct LineProtocolTime(Option<DateTime<Utc>>);
 
     impl fmt::Display for LineProtocolTime {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            match self {
-                LineProtocolTime(Some(time)) => write!(f, " {}", time.timestamp_nanos()),
-                _ => Ok(()),
+            match self.0.and_then(|time| time.timestamp_nanos_opt()) {
+                Some(nanos) => write!(f, " {}", nanos),
+                None => Ok(()),
             }
         }
     }
@@ -23,6 +198,8 @@ pub mod influx {
         }
     }
 
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
     pub enum LineProtocolValue {
         Float(f64),
         Integer(i64),
@@ -32,15 +209,39 @@ pub mod influx {
         Tag(String),
     }
 
+    /// Characters escaped in measurement names
+    const NAME_SPECIAL: &[char] = &[',', ' '];
+
+    /// Characters escaped in tag keys, tag values and field keys
+    const KEY_SPECIAL: &[char] = &[',', '=', ' '];
+
+    /// Characters escaped in string field values
+    const STRING_SPECIAL: &[char] = &['"', '\\'];
+
+    /// Write `text` with a backslash before each of `special`
+    fn escaped(f: &mut fmt::Formatter<'_>, text: &str, special: &[char]) -> fmt::Result {
+        for c in text.chars() {
+            if special.contains(&c) {
+                f.write_str("\\")?;
+            }
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+
     impl fmt::Display for LineProtocolValue {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             match self {
                 Self::Float(x) => write!(f, "{}", x),
                 Self::Integer(x) => write!(f, "{}i", x),
                 Self::UInteger(x) => write!(f, "{}u", x),
-                Self::String(x) => write!(f, "\"{}\"", x),
+                Self::String(x) => {
+                    f.write_str("\"")?;
+                    escaped(f, x, STRING_SPECIAL)?;
+                    f.write_str("\"")
+                }
                 Self::Boolean(x) => write!(f, "{}", x),
-                Self::Tag(x) => write!(f, "{}", x),
+                Self::Tag(x) => escaped(f, x, KEY_SPECIAL),
             }
         }
     }
@@ -75,11 +276,24 @@ pub mod influx {
         }
     }
 
+    impl From<&FieldValue> for LineProtocolValue {
+        fn from(x: &FieldValue) -> Self {
+            match x {
+                FieldValue::Float(x) => LineProtocolValue::Float(*x),
+                FieldValue::Integer(x) => LineProtocolValue::Integer(*x),
+                FieldValue::UInteger(x) => LineProtocolValue::UInteger(*x),
+                FieldValue::String(x) => LineProtocolValue::String(x.clone()),
+                FieldValue::Boolean(x) => LineProtocolValue::Boolean(*x),
+            }
+        }
+    }
+
     struct Item(String, LineProtocolValue);
 
     impl fmt::Display for Item {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            write!(f, "{}={}", self.0, self.1)
+            escaped(f, &self.0, KEY_SPECIAL)?;
+            write!(f, "={}", self.1)
         }
     }
 
@@ -87,6 +301,12 @@ pub mod influx {
         fn to_lineprotocol(&self) -> LineProtocol;
     }
 
+    impl<T: ToMeasurement + ?Sized> ToLineProtocol for T {
+        fn to_lineprotocol(&self) -> LineProtocol {
+            LineProtocol::from(&self.to_measurement())
+        }
+    }
+
     pub struct LineProtocol {
         measurement: String,
         tags: Vec<Item>,
@@ -96,12 +316,12 @@ pub mod influx {
 
     impl LineProtocol {
         pub fn new(measurement: impl Into<String>) -> LineProtocol {
-            return LineProtocol {
+            LineProtocol {
                 measurement: measurement.into(),
                 tags: vec![],
                 values: vec![],
                 time: None.into(),
-            };
+            }
         }
 
         pub fn add_tag(mut self, name: impl Into<String>, tag: impl fmt::Display) -> LineProtocol {
@@ -124,6 +344,78 @@ pub mod influx {
             self.time = time.into();
             self
         }
+
+        /// Whether there are no fields, line protocol requires at least one
+        pub fn is_empty(&self) -> bool {
+            self.values.is_empty()
+        }
+    }
+
+    /// Line protocol has no representation for NaN and infinity, such fields are left out
+    impl From<&Measurement> for LineProtocol {
+        fn from(m: &Measurement) -> Self {
+            LineProtocol {
+                measurement: m.name.clone(),
+                tags: m
+                    .tags
+                    .iter()
+                    .map(|(k, v)| Item(k.clone(), LineProtocolValue::Tag(v.clone())))
+                    .collect(),
+                values: m
+                    .fields
+                    .iter()
+                    .filter(|(_, v)| !matches!(v, FieldValue::Float(x) if !x.is_finite()))
+                    .map(|(k, v)| Item(k.clone(), v.into()))
+                    .collect(),
+                time: m.time.into(),
+            }
+        }
+    }
+
+    /// Sink writing measurements in InfluxDB line protocol, one per line
+    ///
+    /// Measurements without a finite field are skipped.
+    pub struct LineProtocolSink<W: AsyncWrite> {
+        writer: BufWriter<W>,
+    }
+
+    impl<W: AsyncWrite> LineProtocolSink<W> {
+        pub fn new(writer: W) -> LineProtocolSink<W> {
+            LineProtocolSink {
+                writer: BufWriter::new(writer),
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    impl LineProtocolSink<tokio::io::Stdout> {
+        pub fn stdout() -> Self {
+            Self::new(tokio::io::stdout())
+        }
+    }
+
+    #[async_trait]
+    impl<W: AsyncWrite + Unpin + Send> OutputSink for LineProtocolSink<W> {
+        async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+            if let Some(time) = measurement.time {
+                if time.timestamp_nanos_opt().is_none() {
+                    anyhow::bail!("Time {} is out of the range of line protocol", time);
+                }
+            }
+            let line = LineProtocol::from(measurement);
+            if line.is_empty() {
+                return Ok(());
+            }
+            self.writer
+                .write_all(format!("{}\n", line).as_bytes())
+                .await?;
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> anyhow::Result<()> {
+            self.writer.flush().await?;
+            Ok(())
+        }
     }
 
     impl fmt::Display for LineProtocol {
@@ -137,11 +429,8 @@ pub mod influx {
                 .map(|item| format!("{}", item))
                 .collect::<Vec<_>>()
                 .join(",");
-            write!(
-                f,
-                "{}{} {}{}",
-                self.measurement, tag_string, value_string, self.time
-            )
+            escaped(f, &self.measurement, NAME_SPECIAL)?;
+            write!(f, "{} {}{}", tag_string, value_string, self.time)
         }
     }
 
@@ -150,7 +439,8 @@ pub mod influx {
 
         use chrono::{DateTime, NaiveDate, Utc};
 
-        use super::LineProtocol;
+        use super::{LineProtocol, LineProtocolSink};
+        use crate::{output::OutputSink, Measurement};
 
         #[test]
         fn line_protocol_fmt() {
@@ -180,7 +470,7 @@ pub mod influx {
                 "measurement1,tag1=1,tag2=something "
             );
 
-            let date = DateTime::<Utc>::from_utc(
+            let date = DateTime::<Utc>::from_naive_utc_and_offset(
                 NaiveDate::from_ymd_opt(2016, 7, 8)
                     .expect("should work")
                     .and_hms_nano_opt(9, 10, 11, 1)
@@ -199,5 +489,67 @@ pub mod influx {
                 "measurement1,tag1=1 keyI64=1i 1467969011000000001"
             );
         }
+
+        #[test]
+        fn test_escaping() {
+            let measurement = Measurement::new("room temp,1")
+                .add_tag("vendor", "IKEA of Sweden")
+                .add_tag("a=b", "c,d")
+                .add_field("line", "say \"hi\" C:\\")
+                .add_field("field key", 1u64);
+            assert_eq!(
+                LineProtocol::from(&measurement).to_string(),
+                r#"room\ temp\,1,vendor=IKEA\ of\ Sweden,a\=b=c\,d line="say \"hi\" C:\\",field\ key=1u"#
+            );
+        }
+
+        #[tokio::test]
+        async fn test_sink_writes_one_line_per_measurement() {
+            let mut buf = vec![];
+            {
+                let mut sink = LineProtocolSink::new(&mut buf);
+                sink.start().await.unwrap();
+                for id in [1, 2] {
+                    sink.write(&Measurement::new("m").add_tag("id", id).add_field("v", 1.5))
+                        .await
+                        .unwrap();
+                }
+                sink.shutdown().await.unwrap();
+            }
+            assert_eq!(
+                String::from_utf8(buf).unwrap(),
+                "m,id=1 v=1.5\nm,id=2 v=1.5\n"
+            );
+        }
+
+        #[tokio::test]
+        async fn test_non_finite_fields_skipped() {
+            let measurement = Measurement::new("m")
+                .add_field("nan", f64::NAN)
+                .add_field("inf", f64::INFINITY)
+                .add_field("v", 1.5);
+            assert_eq!(LineProtocol::from(&measurement).to_string(), "m v=1.5");
+
+            let mut buf = vec![];
+            {
+                let mut sink = LineProtocolSink::new(&mut buf);
+                sink.write(&Measurement::new("m").add_field("v", f64::NAN))
+                    .await
+                    .unwrap();
+                sink.write(&measurement).await.unwrap();
+                sink.shutdown().await.unwrap();
+            }
+            assert_eq!(String::from_utf8(buf).unwrap(), "m v=1.5\n");
+        }
+
+        #[tokio::test]
+        async fn test_time_out_of_range() {
+            let time = DateTime::from_timestamp(10_000_000_000, 0);
+            let measurement = Measurement::new("m").add_field("v", 1.5).add_time(time);
+            assert_eq!(LineProtocol::from(&measurement).to_string(), "m v=1.5");
+            let mut buf = vec![];
+            let mut sink = LineProtocolSink::new(&mut buf);
+            assert!(sink.write(&measurement).await.is_err());
+        }
     }
 }
@@ -0,0 +1,567 @@
+//! Columnar representation of measurements and streaming in the Arrow IPC format.
+//!
+//! Measurements of the same name become a record batch with a `time` column, a string column
+//! per tag and a typed column per field. Fields with integers and floats become floats, fields
+//! with mixed other types strings.
+//!
+//! [ArrowStreamSink] serves measurements to analytical consumers: a client connects over TCP,
+//! sends the name of a measurement followed by a newline and receives Arrow IPC streams of
+//! record batches of that measurement, e.g. with pyarrow
+//!
+//! ```python
+//! sock = socket.create_connection(("sensorflow", 8815))
+//! sock.sendall(b"tempHum\n")
+//! reader = sock.makefile("rb")
+//! while True:
+//!     for batch in pyarrow.ipc.open_stream(reader):
+//!         print(batch.to_pandas())
+//! ```
+//!
+//! The schema of a stream is taken from the first batch sent. Once a batch has tags or fields
+//! which are not in the schema, e.g. `humidity` of a sensor whose first frame had none, the
+//! stream ends and a new one starts with a schema including them, so clients read the streams
+//! one after the other. Batches are formed on every flush of the pipeline. Clients which do not
+//! keep up lose batches. With `announce`, the server is announced on the local
+//! network, see [crate::mdns]. Requires the `arrow` feature.
+//!
+//! The server has no authentication, anyone who can connect receives the measurements. It
+//! listens on localhost by default, set `listen`, e.g. to `0.0.0.0:8815`, only to expose it to
+//! a trusted network.
+use super::OutputSink;
+use crate::mdns::Announcement;
+use crate::measurement::{FieldValue, Measurement};
+use arrow_array::{
+    ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray,
+    TimestampMillisecondArray, UInt64Array,
+};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Batches queued per client before further batches are dropped
+const CLIENT_QUEUE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColumnType {
+    Float,
+    Integer,
+    UInteger,
+    String,
+    Boolean,
+}
+
+impl ColumnType {
+    fn of(value: &FieldValue) -> ColumnType {
+        match value {
+            FieldValue::Float(_) => ColumnType::Float,
+            FieldValue::Integer(_) => ColumnType::Integer,
+            FieldValue::UInteger(_) => ColumnType::UInteger,
+            FieldValue::String(_) => ColumnType::String,
+            FieldValue::Boolean(_) => ColumnType::Boolean,
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            ColumnType::Float | ColumnType::Integer | ColumnType::UInteger
+        )
+    }
+
+    /// Type of a column holding values of both types
+    fn merge(self, other: ColumnType) -> ColumnType {
+        if self == other {
+            self
+        } else if self.is_numeric() && other.is_numeric() {
+            ColumnType::Float
+        } else {
+            ColumnType::String
+        }
+    }
+
+    fn data_type(&self) -> DataType {
+        match self {
+            ColumnType::Float => DataType::Float64,
+            ColumnType::Integer => DataType::Int64,
+            ColumnType::UInteger => DataType::UInt64,
+            ColumnType::String => DataType::Utf8,
+            ColumnType::Boolean => DataType::Boolean,
+        }
+    }
+
+    fn from_data_type(data_type: &DataType) -> Option<ColumnType> {
+        match data_type {
+            DataType::Float64 => Some(ColumnType::Float),
+            DataType::Int64 => Some(ColumnType::Integer),
+            DataType::UInt64 => Some(ColumnType::UInteger),
+            DataType::Utf8 => Some(ColumnType::String),
+            DataType::Boolean => Some(ColumnType::Boolean),
+            _ => None,
+        }
+    }
+}
+
+/// Values of a column, tags and fields of other types are stored as strings
+fn column(measurements: &[Measurement], name: &str, column: ColumnType) -> ArrayRef {
+    let values = measurements.iter().map(|m| m.field(name));
+    match column {
+        ColumnType::Float => Arc::new(Float64Array::from_iter(values.map(|v| match v? {
+            FieldValue::Float(x) => Some(*x),
+            FieldValue::Integer(x) => Some(*x as f64),
+            FieldValue::UInteger(x) => Some(*x as f64),
+            FieldValue::String(_) | FieldValue::Boolean(_) => None,
+        }))),
+        ColumnType::Integer => Arc::new(Int64Array::from_iter(values.map(|v| match v? {
+            FieldValue::Integer(x) => Some(*x),
+            _ => None,
+        }))),
+        ColumnType::UInteger => Arc::new(UInt64Array::from_iter(values.map(|v| match v? {
+            FieldValue::UInteger(x) => Some(*x),
+            _ => None,
+        }))),
+        ColumnType::String => Arc::new(StringArray::from_iter(measurements.iter().map(
+            |m| match (m.tag(name), m.field(name)) {
+                (Some(tag), _) => Some(tag.to_string()),
+                (None, Some(FieldValue::String(s))) => Some(s.clone()),
+                (None, value) => value.map(|v| v.to_string()),
+            },
+        ))),
+        ColumnType::Boolean => Arc::new(BooleanArray::from_iter(values.map(|v| match v? {
+            FieldValue::Boolean(b) => Some(*b),
+            _ => None,
+        }))),
+    }
+}
+
+/// Schema of the record batch of measurements of the same name
+pub fn schema(measurements: &[Measurement]) -> SchemaRef {
+    let mut tags: Vec<&str> = Vec::new();
+    let mut fields: Vec<(&str, ColumnType)> = Vec::new();
+    for measurement in measurements {
+        for (key, _) in &measurement.tags {
+            if !tags.contains(&key.as_str()) {
+                tags.push(key);
+            }
+        }
+        for (key, value) in &measurement.fields {
+            match fields.iter_mut().find(|(name, _)| name == key) {
+                Some((_, column)) => *column = column.merge(ColumnType::of(value)),
+                None => fields.push((key, ColumnType::of(value))),
+            }
+        }
+    }
+    let mut schema = vec![Field::new(
+        "time",
+        DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+        false,
+    )];
+    schema.extend(
+        tags.iter()
+            .map(|tag| Field::new(*tag, DataType::Utf8, true)),
+    );
+    schema.extend(
+        fields
+            .iter()
+            .map(|(field, column)| Field::new(*field, column.data_type(), true)),
+    );
+    Arc::new(Schema::new(schema))
+}
+
+/// Schema with the columns of `current` and those of `batch`, `None` if `current` holds the
+/// columns of `batch` already
+fn widened(current: &Schema, batch: &Schema) -> Option<SchemaRef> {
+    let mut fields: Vec<Field> = current
+        .fields()
+        .iter()
+        .map(|f| f.as_ref().clone())
+        .collect();
+    let mut changed = false;
+    for field in batch.fields() {
+        match fields.iter_mut().find(|f| f.name() == field.name()) {
+            Some(existing) => {
+                let types = (
+                    ColumnType::from_data_type(existing.data_type()),
+                    ColumnType::from_data_type(field.data_type()),
+                );
+                // The time column has no column type
+                let (Some(existing_type), Some(field_type)) = types else {
+                    continue;
+                };
+                let merged = existing_type.merge(field_type).data_type();
+                if merged != *existing.data_type() {
+                    *existing = existing.clone().with_data_type(merged);
+                    changed = true;
+                }
+            }
+            None => {
+                fields.push(field.as_ref().clone());
+                changed = true;
+            }
+        }
+    }
+    changed.then(|| Arc::new(Schema::new(fields)))
+}
+
+/// Columns of measurements in the given schema. Tags and fields not in the schema are
+/// dropped, measurements without time are stored with the epoch.
+pub fn record_batch_with_schema(
+    schema: SchemaRef,
+    measurements: &[Measurement],
+) -> anyhow::Result<RecordBatch> {
+    let mut columns: Vec<ArrayRef> = Vec::new();
+    for field in schema.fields() {
+        if let DataType::Timestamp(TimeUnit::Millisecond, _) = field.data_type() {
+            columns.push(Arc::new(
+                TimestampMillisecondArray::from_iter_values(
+                    measurements
+                        .iter()
+                        .map(|m| m.time.map_or(0, |time| time.timestamp_millis())),
+                )
+                .with_timezone("UTC"),
+            ));
+            continue;
+        }
+        let column_type = ColumnType::from_data_type(field.data_type())
+            .ok_or_else(|| anyhow::anyhow!("Unsupported column type {}", field.data_type()))?;
+        columns.push(column(measurements, field.name(), column_type));
+    }
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+/// Columns of measurements of the same name
+pub fn record_batch(measurements: &[Measurement]) -> anyhow::Result<RecordBatch> {
+    record_batch_with_schema(schema(measurements), measurements)
+}
+
+fn default_listen() -> String {
+    "127.0.0.1:8815".into()
+}
+
+/// Address clients connect to
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct ArrowStreamConfig {
+    /// Address of the server, `127.0.0.1:8815` by default
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    /// Instance name announced via mDNS, see [crate::mdns]
+    pub announce: Option<String>,
+}
+
+struct Client {
+    measurement: String,
+    batches: mpsc::Sender<Arc<Vec<Measurement>>>,
+}
+
+type Clients = Arc<Mutex<Vec<Client>>>;
+
+/// Stream batches of a measurement to a client until it disconnects
+async fn serve(socket: TcpStream, clients: Clients) -> anyhow::Result<()> {
+    let mut socket = BufReader::new(socket);
+    let mut measurement = String::new();
+    socket.read_line(&mut measurement).await?;
+    let (tx, mut rx) = mpsc::channel(CLIENT_QUEUE);
+    clients.lock().unwrap().push(Client {
+        measurement: measurement.trim().to_string(),
+        batches: tx,
+    });
+
+    let mut writer: Option<(StreamWriter<Vec<u8>>, SchemaRef)> = None;
+    while let Some(measurements) = rx.recv().await {
+        let batch_schema = schema(&measurements);
+        let schema = match &writer {
+            None => Some(batch_schema),
+            Some((_, current)) => widened(current, &batch_schema),
+        };
+        // A new stream for a new schema
+        if let Some(schema) = schema {
+            if let Some((mut previous, _)) = writer.take() {
+                previous.finish()?;
+                socket.write_all(previous.get_ref()).await?;
+            }
+            writer = Some((StreamWriter::try_new(Vec::new(), &schema)?, schema));
+        }
+        let (writer, schema) = writer.as_mut().expect("stream is started");
+        writer.write(&record_batch_with_schema(schema.clone(), &measurements)?)?;
+        let bytes = std::mem::take(writer.get_mut());
+        socket.write_all(&bytes).await?;
+    }
+    if let Some((mut writer, _)) = writer {
+        writer.finish()?;
+        socket.write_all(writer.get_ref()).await?;
+    }
+    Ok(())
+}
+
+/// Sink serving Arrow IPC streams to connected clients
+pub struct ArrowStreamSink {
+    config: ArrowStreamConfig,
+    clients: Clients,
+    /// Measurements since the last flush by name
+    pending: BTreeMap<String, Vec<Measurement>>,
+    task: Option<JoinHandle<()>>,
+    announcement: Option<Announcement>,
+}
+
+impl ArrowStreamSink {
+    pub fn new(config: ArrowStreamConfig) -> ArrowStreamSink {
+        ArrowStreamSink {
+            config,
+            clients: Default::default(),
+            pending: BTreeMap::new(),
+            task: None,
+            announcement: None,
+        }
+    }
+}
+
+#[async_trait]
+impl OutputSink for ArrowStreamSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(&self.config.listen).await?;
+        if let Some(instance) = &self.config.announce {
+            let port = listener.local_addr()?.port();
+            self.announcement = Some(Announcement::new(instance, port, "arrow")?);
+        }
+        let clients = self.clients.clone();
+        self.task = Some(tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve(socket, clients.clone()));
+            }
+        }));
+        Ok(())
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let subscribed = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|client| client.measurement == measurement.name);
+        if subscribed {
+            self.pending
+                .entry(measurement.name.clone())
+                .or_default()
+                .push(measurement.clone());
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        let pending: Vec<_> = std::mem::take(&mut self.pending)
+            .into_iter()
+            .map(|(name, measurements)| (name, Arc::new(measurements)))
+            .collect();
+        self.clients.lock().unwrap().retain(|client| {
+            match pending.iter().find(|(name, _)| *name == client.measurement) {
+                // Slow clients lose the batch, disconnected ones are removed
+                Some((_, measurements)) => !matches!(
+                    client.batches.try_send(measurements.clone()),
+                    Err(mpsc::error::TrySendError::Closed(_))
+                ),
+                None => !client.batches.is_closed(),
+            }
+        });
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.flush().await?;
+        self.announcement = None;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        // Ends the streams of all clients
+        self.clients.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{record_batch, record_batch_with_schema, ArrowStreamConfig, ArrowStreamSink};
+    use crate::{measurement::FieldValue, output::OutputSink, Measurement};
+    use arrow_array::{
+        cast::AsArray,
+        types::{Float64Type, UInt64Type},
+    };
+    use arrow_ipc::reader::StreamReader;
+    use arrow_schema::DataType;
+    use chrono::DateTime;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn measurement(time: i64, humidity: impl Into<FieldValue>) -> Measurement {
+        Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.5)
+            .add_field("humidity", humidity)
+            .add_time(DateTime::from_timestamp(time, 0))
+    }
+
+    #[test]
+    fn test_record_batch() {
+        let batch = record_batch(&[
+            measurement(1700000000, 45u64),
+            measurement(1700000060, 44.5).add_tag("room", "kitchen"),
+        ])
+        .unwrap();
+        let schema = batch.schema();
+        let columns: Vec<_> = schema
+            .fields()
+            .iter()
+            .map(|f| (f.name().as_str(), f.data_type().clone()))
+            .collect();
+        assert_eq!(
+            columns[1..],
+            [
+                ("sensorId", DataType::Utf8),
+                ("room", DataType::Utf8),
+                ("temperature", DataType::Float64),
+                ("humidity", DataType::Float64),
+            ]
+        );
+        assert_eq!(batch.column(2).null_count(), 1);
+        assert_eq!(
+            batch.column(4).as_primitive::<Float64Type>().values(),
+            &[45., 44.5]
+        );
+    }
+
+    #[test]
+    fn test_record_batch_with_schema() {
+        let schema = record_batch(&[measurement(1700000000, 45u64)])
+            .unwrap()
+            .schema();
+        let batch = record_batch_with_schema(
+            schema,
+            &[Measurement::new("tempHum")
+                .add_field("humidity", 50u64)
+                .add_field("battery_low", false)],
+        )
+        .unwrap();
+        assert_eq!(batch.num_columns(), 4);
+        assert_eq!(batch.column(1).null_count(), 1);
+        assert_eq!(batch.column(3).data_type(), &DataType::UInt64);
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let mut sink = ArrowStreamSink::new(ArrowStreamConfig {
+            listen: format!("127.0.0.1:{}", port),
+            announce: None,
+        });
+        sink.start().await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        client.write_all(b"tempHum\n").await.unwrap();
+        while sink.clients.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        sink.write(&measurement(1700000000, 45u64)).await.unwrap();
+        sink.write(&Measurement::new("power").add_field("power", 1.))
+            .await
+            .unwrap();
+        sink.write(&measurement(1700000060, 46u64)).await.unwrap();
+        sink.shutdown().await.unwrap();
+
+        let mut stream = Vec::new();
+        client.read_to_end(&mut stream).await.unwrap();
+        let batches: Vec<_> = StreamReader::try_new(stream.as_slice(), None)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].num_rows(), 2);
+        assert_eq!(
+            batches[0]
+                .column_by_name("temperature")
+                .unwrap()
+                .as_primitive::<Float64Type>()
+                .values(),
+            &[21.5, 21.5]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_stream_for_new_fields() {
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let mut sink = ArrowStreamSink::new(ArrowStreamConfig {
+            listen: format!("127.0.0.1:{}", port),
+            announce: None,
+        });
+        sink.start().await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        client.write_all(b"tempHum\n").await.unwrap();
+        while sink.clients.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        let without_humidity = Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.5)
+            .add_time(DateTime::from_timestamp(1700000000, 0));
+        // Wait for every batch to be sent, slow clients lose them
+        let batches = sink.clients.lock().unwrap()[0].batches.clone();
+        for measurement in [
+            without_humidity.clone(),
+            measurement(1700000060, 46u64),
+            without_humidity,
+        ] {
+            sink.write(&measurement).await.unwrap();
+            sink.flush().await.unwrap();
+            while batches.capacity() < batches.max_capacity() {
+                tokio::task::yield_now().await;
+            }
+        }
+        drop(batches);
+        sink.shutdown().await.unwrap();
+
+        let mut stream = Vec::new();
+        client.read_to_end(&mut stream).await.unwrap();
+        let mut bytes = stream.as_slice();
+        let first: Vec<_> = StreamReader::try_new(&mut bytes, None)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(first.len(), 1);
+        assert!(first[0].column_by_name("humidity").is_none());
+        let second: Vec<_> = StreamReader::try_new(&mut bytes, None)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(second.len(), 2);
+        assert_eq!(
+            second[0]
+                .column_by_name("humidity")
+                .unwrap()
+                .as_primitive::<UInt64Type>()
+                .values(),
+            &[46]
+        );
+        assert_eq!(
+            second[1].column_by_name("humidity").unwrap().null_count(),
+            1
+        );
+        assert!(bytes.is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+//! Publishing to AWS IoT Core.
+//!
+//! The device endpoint of the account (`<prefix>-ats.iot.<region>.amazonaws.com`) is reached
+//! on port 8883 with mutual TLS, the thing authenticates with its X.509 certificate. Each
+//! measurement is published as JSON object, so IoT rules can select from it, e.g.
+//! `SELECT fields.temperature FROM 'sensorflow/tempHum/+'`. The topic is a template, see
+//! [super::json::expand_template]. Requires the `aws-iot` feature.
+use super::mqtt::{qos, MqttSink, DEFAULT_BUFFER};
+use anyhow::Context;
+use rumqttc::{MqttOptions, Transport};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Port of the MQTT endpoint with X.509 client certificates
+pub const PORT: u16 = 8883;
+
+fn default_topic() -> String {
+    "sensorflow/{measurement}".into()
+}
+
+fn default_qos() -> u8 {
+    1
+}
+
+fn default_buffer() -> usize {
+    DEFAULT_BUFFER
+}
+
+/// Endpoint, credentials of the thing and topic
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct AwsIotConfig {
+    /// Device data endpoint of the account
+    pub endpoint: String,
+    /// Must be allowed by the policy of the certificate, usually the name of the thing
+    pub client_id: String,
+    /// Amazon root CA, PEM encoded
+    pub ca_file: PathBuf,
+    /// Certificate of the thing, PEM encoded
+    pub cert_file: PathBuf,
+    /// Private key of the certificate, PEM encoded
+    pub key_file: PathBuf,
+    #[serde(default = "default_topic")]
+    pub topic: String,
+    /// AWS IoT supports 0 and 1
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    /// Number of messages kept while the endpoint is unreachable
+    #[serde(default = "default_buffer")]
+    pub buffer: usize,
+}
+
+fn read_pem(path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+impl AwsIotConfig {
+    pub fn sink(&self) -> anyhow::Result<MqttSink> {
+        let ca = read_pem(&self.ca_file)?;
+        let cert = read_pem(&self.cert_file)?;
+        let key = read_pem(&self.key_file)?;
+        let mut options = MqttOptions::new(&self.client_id, &self.endpoint, PORT);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_transport(Transport::tls(ca, Some((cert, key)), None));
+        Ok(MqttSink::new(options, &self.topic, qos(self.qos.min(1))).with_buffer(self.buffer))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AwsIotConfig;
+
+    #[test]
+    fn test_config() {
+        let config: AwsIotConfig = toml::from_str(
+            r#"
+            endpoint = "a1b2c3-ats.iot.eu-central-1.amazonaws.com"
+            client_id = "weather-station"
+            ca_file = "/etc/sensorflow/AmazonRootCA1.pem"
+            cert_file = "/etc/sensorflow/certificate.pem.crt"
+            key_file = "/etc/sensorflow/private.pem.key"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.topic, "sensorflow/{measurement}");
+        assert_eq!(config.qos, 1);
+        assert_eq!(config.buffer, 1000);
+        let err = config.sink().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Failed to read /etc/sensorflow/AmazonRootCA1.pem"
+        );
+    }
+}
@@ -0,0 +1,344 @@
+//! Device-to-cloud messages to Azure IoT Hub.
+//!
+//! The sink connects as the device of the given connection string over MQTT on port 8883 and
+//! authenticates with a shared access signature (SAS) token signed with the device key. Tokens
+//! are valid for `token_ttl` seconds, a fresh one is signed for every connection, so the sink
+//! reconnects right away when the hub closes the connection after a token expired.
+//!
+//! Measurements are sent as JSON (see [super::json::to_json]). With a `batch_size` above 1,
+//! they are collected into JSON arrays, sent once the batch is full, the message would exceed
+//! the size limit of IoT Hub, or the pipeline has no more measurements queued, or as scheduled
+//! by `flush`.
+//! Requires the `azure-iot` feature.
+use super::{
+    batch::FlushSchedule,
+    json::to_json,
+    mqtt::{qos, MqttSink, DEFAULT_BUFFER},
+    OutputSink,
+};
+use crate::measurement::Measurement;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rumqttc::{MqttOptions, Transport};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Port of the MQTT endpoint
+pub const PORT: u16 = 8883;
+
+const API_VERSION: &str = "2021-04-12";
+
+/// Largest device-to-cloud message accepted by IoT Hub
+const MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
+/// Characters kept in URL encoded token parts
+const URL_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+fn default_token_ttl() -> u64 {
+    3600
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+
+fn default_buffer() -> usize {
+    DEFAULT_BUFFER
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ConnectionStringError {
+    #[error("Connection string lacks {0}")]
+    Missing(&'static str),
+    #[error("Shared access key is not valid base64")]
+    InvalidKey,
+}
+
+/// Parts of a device connection string as shown in the Azure portal,
+/// `HostName=<hub>.azure-devices.net;DeviceId=<device>;SharedAccessKey=<key>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionString {
+    pub host_name: String,
+    pub device_id: String,
+    /// Decoded device key
+    pub key: Vec<u8>,
+}
+
+impl std::str::FromStr for ConnectionString {
+    type Err = ConnectionStringError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = |name: &'static str| {
+            s.split(';')
+                .filter_map(|part| part.split_once('='))
+                .find(|(key, _)| key.trim() == name)
+                .map(|(_, value)| value.trim().to_string())
+                .ok_or(ConnectionStringError::Missing(name))
+        };
+        let key = STANDARD
+            .decode(value("SharedAccessKey")?)
+            .map_err(|_| ConnectionStringError::InvalidKey)?;
+        Ok(ConnectionString {
+            host_name: value("HostName")?,
+            device_id: value("DeviceId")?,
+            key,
+        })
+    }
+}
+
+impl ConnectionString {
+    /// SAS token for the device, valid until `expiry` (UNIX timestamp)
+    pub fn sas_token(&self, expiry: i64) -> String {
+        let resource = utf8_percent_encode(
+            &format!("{}/devices/{}", self.host_name, self.device_id),
+            URL_ENCODE,
+        )
+        .to_string();
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(format!("{}\n{}", resource, expiry).as_bytes());
+        let signature = STANDARD.encode(mac.finalize().into_bytes());
+        format!(
+            "SharedAccessSignature sr={}&sig={}&se={}",
+            resource,
+            utf8_percent_encode(&signature, URL_ENCODE),
+            expiry
+        )
+    }
+
+    fn username(&self) -> String {
+        format!(
+            "{}/{}/?api-version={}",
+            self.host_name, self.device_id, API_VERSION
+        )
+    }
+
+    /// Topic of device-to-cloud messages, declaring JSON content for message routing
+    fn topic(&self) -> String {
+        format!(
+            "devices/{}/messages/events/$.ct=application%2Fjson&$.ce=utf-8",
+            self.device_id
+        )
+    }
+}
+
+/// Device and batching
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct AzureIotConfig {
+    pub connection_string: String,
+    /// Lifetime of SAS tokens in seconds
+    #[serde(default = "default_token_ttl")]
+    pub token_ttl: u64,
+    /// Number of measurements per message, 1 sends single JSON objects
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Number of messages kept while the hub is unreachable
+    #[serde(default = "default_buffer")]
+    pub buffer: usize,
+    /// Flush on a schedule instead of whenever the pipeline ran idle, see [super::batch]
+    #[serde(default)]
+    pub flush: Option<FlushSchedule>,
+}
+
+impl AzureIotConfig {
+    pub fn sink(&self) -> anyhow::Result<AzureIotSink> {
+        let connection: ConnectionString = self.connection_string.parse()?;
+        let mut options = MqttOptions::new(&connection.device_id, &connection.host_name, PORT);
+        options.set_keep_alive(Duration::from_secs(60));
+        options.set_transport(Transport::tls_with_default_config());
+        Ok(AzureIotSink::with_options(
+            options,
+            connection,
+            self.token_ttl,
+            self.batch_size,
+            self.buffer,
+        ))
+    }
+}
+
+/// Sink sending measurements as device-to-cloud messages
+pub struct AzureIotSink {
+    sink: MqttSink,
+    topic: String,
+    batch_size: usize,
+    /// Serialized measurements of the current batch
+    batch: Vec<String>,
+    batch_len: usize,
+}
+
+impl AzureIotSink {
+    fn with_options(
+        options: MqttOptions,
+        connection: ConnectionString,
+        token_ttl: u64,
+        batch_size: usize,
+        buffer: usize,
+    ) -> AzureIotSink {
+        let topic = connection.topic();
+        let username = connection.username();
+        let credentials = Arc::new(move || {
+            let expiry = chrono::Utc::now().timestamp() + token_ttl as i64;
+            (username.clone(), connection.sas_token(expiry))
+        });
+        AzureIotSink {
+            // IoT Hub does not support QoS 2
+            sink: MqttSink::new(options, "", qos(1))
+                .with_buffer(buffer)
+                .with_credentials(credentials),
+            topic,
+            batch_size: batch_size.max(1),
+            batch: Vec::new(),
+            batch_len: 0,
+        }
+    }
+
+    async fn send_batch(&mut self) -> anyhow::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let payload = if self.batch_size == 1 {
+            self.batch.remove(0)
+        } else {
+            format!("[{}]", self.batch.join(","))
+        };
+        self.batch.clear();
+        self.batch_len = 0;
+        self.sink
+            .publish(self.topic.clone(), payload.into_bytes())
+            .await
+    }
+}
+
+#[async_trait]
+impl OutputSink for AzureIotSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        self.sink.start().await
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let json = to_json(measurement).to_string();
+        // Brackets and separators of the array
+        if self.batch_len + json.len() + self.batch.len() + 2 > MAX_MESSAGE_SIZE {
+            self.send_batch().await?;
+        }
+        self.batch_len += json.len();
+        self.batch.push(json);
+        if self.batch.len() >= self.batch_size {
+            self.send_batch().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        self.send_batch().await
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.send_batch().await?;
+        self.sink.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AzureIotConfig, AzureIotSink, ConnectionString, ConnectionStringError};
+    use crate::{
+        output::{mqtt::read_packet, OutputSink},
+        Measurement,
+    };
+    use rumqttc::MqttOptions;
+    use serde_json::{json, Value};
+    use tokio::io::AsyncWriteExt;
+
+    const CONNECTION_STRING: &str = "HostName=myhub.azure-devices.net;DeviceId=weather-station;\
+                                     SharedAccessKey=c2Vuc29yZmxvdy10ZXN0LWtleS0wMTIzNDU2Nzg5YWI=";
+
+    #[test]
+    fn test_sas_token() {
+        let connection: ConnectionString = CONNECTION_STRING.parse().unwrap();
+        assert_eq!(connection.host_name, "myhub.azure-devices.net");
+        assert_eq!(connection.device_id, "weather-station");
+        assert_eq!(
+            connection.sas_token(1700000000),
+            "SharedAccessSignature sr=myhub.azure-devices.net%2Fdevices%2Fweather-station\
+             &sig=3LyzEa%2BIl0v%2FvCSaC7qYOLBJZIbrQcPg69DYIavdoEI%3D&se=1700000000"
+        );
+        assert_eq!(
+            connection.username(),
+            "myhub.azure-devices.net/weather-station/?api-version=2021-04-12"
+        );
+        assert_eq!(
+            "HostName=myhub.azure-devices.net;SharedAccessKey=AAAA".parse::<ConnectionString>(),
+            Err(ConnectionStringError::Missing("DeviceId"))
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        let config: AzureIotConfig =
+            toml::from_str(&format!("connection_string = \"{}\"", CONNECTION_STRING)).unwrap();
+        assert_eq!(config.token_ttl, 3600);
+        assert_eq!(config.batch_size, 100);
+    }
+
+    #[tokio::test]
+    async fn test_send_batches() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let hub = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let (_, connect) = read_packet(&mut socket).await;
+            socket.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+            let mut messages = Vec::new();
+            for _ in 0..2 {
+                let (header, body) = read_packet(&mut socket).await;
+                assert_eq!(header & 0xf6, 0x32);
+                let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+                let packet_id = [body[2 + topic_len], body[3 + topic_len]];
+                // The client may already be gone after the last message
+                let _ = socket
+                    .write_all(&[0x40, 0x02, packet_id[0], packet_id[1]])
+                    .await;
+                let payload: Value = serde_json::from_slice(&body[4 + topic_len..]).unwrap();
+                messages.push((topic, payload));
+            }
+            // DISCONNECT
+            assert_eq!(read_packet(&mut socket).await.0, 0xe0);
+            (String::from_utf8_lossy(&connect).to_string(), messages)
+        });
+
+        let options = MqttOptions::new("weather-station", "127.0.0.1", port);
+        let mut sink =
+            AzureIotSink::with_options(options, CONNECTION_STRING.parse().unwrap(), 60, 2, 10);
+        sink.start().await.unwrap();
+        for temperature in [20., 21., 22.] {
+            sink.write(&Measurement::new("weather").add_field("temperature", temperature))
+                .await
+                .unwrap();
+        }
+        sink.flush().await.unwrap();
+        sink.shutdown().await.unwrap();
+
+        let (connect, messages) = hub.await.unwrap();
+        assert!(connect.contains("myhub.azure-devices.net/weather-station/?api-version="));
+        assert!(connect.contains("SharedAccessSignature sr="));
+        assert_eq!(
+            messages[0].0,
+            "devices/weather-station/messages/events/$.ct=application%2Fjson&$.ce=utf-8"
+        );
+        assert_eq!(messages[0].1[1]["fields"], json!({"temperature": 21.0}));
+        assert_eq!(messages[1].1.as_array().unwrap().len(), 1);
+    }
+}
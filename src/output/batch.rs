@@ -0,0 +1,220 @@
+//! Flush scheduling of batching sinks.
+//!
+//! Sinks sending measurements in batches, e.g. [super::remote_write] or [super::azure_iot],
+//! send whatever they collected on every flush. The pipeline flushes whenever its queue ran
+//! empty, which at a few measurements per second means a request per measurement. [batched]
+//! holds back flushes until `size` measurements were written or the oldest unflushed one
+//! waited `interval` seconds, whichever comes first, or until the time the sink asks for by
+//! [OutputSink::next_flush]. Shutting down always flushes.
+//!
+//! ```toml
+//! [[outputs]]
+//! type = "remote_write"
+//! url = "http://mimir:8080/api/v1/push"
+//! flush = { size = 500, interval = 10 }
+//! ```
+use super::OutputSink;
+use crate::measurement::Measurement;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// When to flush a sink, by number of measurements and by time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct FlushSchedule {
+    /// Number of measurements after which the sink is flushed
+    pub size: Option<usize>,
+    /// Maximum time in seconds a measurement is held back
+    pub interval: Option<u64>,
+}
+
+/// Flush `sink` according to `schedule`, if any
+pub fn batched(sink: Box<dyn OutputSink>, schedule: Option<FlushSchedule>) -> Box<dyn OutputSink> {
+    match schedule {
+        Some(schedule) => Box::new(Batched::new(sink, schedule)),
+        None => sink,
+    }
+}
+
+/// Sink flushing its inner sink on a [FlushSchedule] only
+pub struct Batched {
+    sink: Box<dyn OutputSink>,
+    schedule: FlushSchedule,
+    /// Measurements written since the last flush
+    pending: usize,
+    /// Time of the first measurement written since the last flush
+    oldest: Option<Instant>,
+}
+
+impl Batched {
+    pub fn new(sink: Box<dyn OutputSink>, schedule: FlushSchedule) -> Batched {
+        Batched {
+            sink,
+            schedule,
+            pending: 0,
+            oldest: None,
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        self.schedule.size.is_some_and(|size| self.pending >= size)
+            || self.next_flush().is_some_and(|time| time <= Instant::now())
+    }
+
+    /// End of the interval of the oldest unflushed measurement
+    fn interval_end(&self) -> Option<Instant> {
+        let interval = Duration::from_secs(self.schedule.interval?);
+        Some(self.oldest? + interval)
+    }
+
+    async fn flush_now(&mut self) -> anyhow::Result<()> {
+        self.pending = 0;
+        self.oldest = None;
+        self.sink.flush().await
+    }
+}
+
+#[async_trait]
+impl OutputSink for Batched {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        self.sink.start().await
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        self.sink.write(measurement).await?;
+        self.pending += 1;
+        self.oldest.get_or_insert_with(Instant::now);
+        match self.is_due() {
+            true => self.flush_now().await,
+            false => Ok(()),
+        }
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        match self.is_due() {
+            true => self.flush_now().await,
+            false => Ok(()),
+        }
+    }
+
+    /// The end of the interval or the time the inner sink wants to be flushed, if earlier
+    fn next_flush(&self) -> Option<Instant> {
+        match (self.interval_end(), self.sink.next_flush()) {
+            (Some(end), Some(inner)) => Some(end.min(inner)),
+            (end, inner) => end.or(inner),
+        }
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.pending = 0;
+        self.oldest = None;
+        self.sink.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{batched, FlushSchedule};
+    use crate::{measurement::Measurement, output::OutputSink};
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    struct Recorder {
+        events: Arc<Mutex<Vec<String>>>,
+        /// Time the recorder asks to be flushed
+        due: Option<tokio::time::Instant>,
+    }
+
+    #[async_trait]
+    impl OutputSink for Recorder {
+        async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+            let value = measurement.field("value").unwrap().to_string();
+            self.events.lock().unwrap().push(value);
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> anyhow::Result<()> {
+            self.events.lock().unwrap().push("flush".into());
+            Ok(())
+        }
+
+        fn next_flush(&self) -> Option<tokio::time::Instant> {
+            self.due
+        }
+    }
+
+    fn value(value: u64) -> Measurement {
+        Measurement::new("counter").add_field("value", value)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_by_size_or_interval() {
+        let recorder = Recorder::default();
+        let schedule = FlushSchedule {
+            size: Some(3),
+            interval: Some(10),
+        };
+        let mut sink = batched(Box::new(recorder.clone()), Some(schedule));
+        assert_eq!(sink.next_flush(), None);
+
+        for i in 0..4 {
+            sink.write(&value(i)).await.unwrap();
+            sink.flush().await.unwrap();
+        }
+        let due = sink.next_flush().unwrap();
+        tokio::time::sleep(Duration::from_secs(9)).await;
+        sink.flush().await.unwrap();
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            ["0", "1", "2", "flush", "3"]
+        );
+
+        tokio::time::sleep_until(due).await;
+        sink.flush().await.unwrap();
+        assert_eq!(sink.next_flush(), None);
+        sink.write(&value(4)).await.unwrap();
+        sink.shutdown().await.unwrap();
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            ["0", "1", "2", "flush", "3", "flush", "4", "flush"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_when_the_sink_asks() {
+        let recorder = Recorder {
+            due: Some(tokio::time::Instant::now() + Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let schedule = FlushSchedule {
+            size: None,
+            interval: Some(10),
+        };
+        let mut sink = batched(Box::new(recorder.clone()), Some(schedule));
+        assert_eq!(sink.next_flush(), recorder.due);
+
+        sink.write(&value(0)).await.unwrap();
+        assert_eq!(sink.next_flush(), recorder.due);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        sink.flush().await.unwrap();
+        assert_eq!(*recorder.events.lock().unwrap(), ["0", "flush"]);
+    }
+
+    #[test]
+    fn test_schedule_from_toml() {
+        let schedule: FlushSchedule = toml::from_str("interval = 5").unwrap();
+        assert_eq!(
+            schedule,
+            FlushSchedule {
+                size: None,
+                interval: Some(5)
+            }
+        );
+        assert!(toml::from_str::<FlushSchedule>("count = 5").is_err());
+    }
+}
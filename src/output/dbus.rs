@@ -0,0 +1,276 @@
+//! DBus signals for desktop integrations.
+//!
+//! The sink owns a well-known name on the session or system bus and exports the object
+//! `/org/sensorflow/Measurements` with the interface `org.sensorflow.Measurements1`. Each
+//! measurement is emitted as signal
+//!
+//! ```text
+//! Measurement(s name, a{ss} tags, a{sv} fields, x time_ms)
+//! ```
+//!
+//! with a time of 0 if unknown. The method `GetLatest(s name)` returns the latest measurement of
+//! every series, i.e. combination of name and tags, as `a(sa{ss}a{sv}x)`, for all names if
+//! `name` is empty. This allows e.g. panel applets to show values without waiting for the next
+//! signal:
+//!
+//! ```sh
+//! busctl --user call org.sensorflow.Sensorflow /org/sensorflow/Measurements \
+//!     org.sensorflow.Measurements1 GetLatest s tempHum
+//! ```
+//!
+//! Requires the `dbus` feature.
+use super::{error::SinkError, retry::RetryConfig, OutputSink};
+use crate::measurement::{FieldValue, Measurement};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use zbus::{
+    connection::Builder,
+    object_server::SignalEmitter,
+    zvariant::{OwnedValue, Str},
+    Connection,
+};
+
+/// Path of the exported object
+pub const PATH: &str = "/org/sensorflow/Measurements";
+
+fn default_name() -> String {
+    "org.sensorflow.Sensorflow".into()
+}
+
+/// Message bus to connect to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum Bus {
+    #[default]
+    Session,
+    System,
+}
+
+/// Bus and well-known name of the sink
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct DbusConfig {
+    #[serde(default)]
+    pub bus: Bus,
+    #[serde(default = "default_name")]
+    pub name: String,
+    /// Retries of failed writes, see [super::retry]
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+impl Default for DbusConfig {
+    fn default() -> Self {
+        DbusConfig {
+            bus: Bus::default(),
+            name: default_name(),
+            retry: RetryConfig::default(),
+        }
+    }
+}
+
+/// Name, tags, fields and time in milliseconds of a measurement, `(sa{ss}a{sv}x)`
+pub type Record = (
+    String,
+    BTreeMap<String, String>,
+    BTreeMap<String, OwnedValue>,
+    i64,
+);
+
+fn value(value: &FieldValue) -> OwnedValue {
+    match value {
+        FieldValue::Float(x) => (*x).into(),
+        FieldValue::Integer(x) => (*x).into(),
+        FieldValue::UInteger(x) => (*x).into(),
+        FieldValue::String(s) => Str::from(s.clone()).into(),
+        FieldValue::Boolean(b) => (*b).into(),
+    }
+}
+
+/// DBus representation of a measurement
+pub fn record(measurement: &Measurement) -> Record {
+    (
+        measurement.name.clone(),
+        measurement.tags.iter().cloned().collect(),
+        measurement
+            .fields
+            .iter()
+            .map(|(key, field)| (key.clone(), value(field)))
+            .collect(),
+        measurement
+            .time
+            .map(|time| time.timestamp_millis())
+            .unwrap_or(0),
+    )
+}
+
+/// Latest measurement by name and tags
+type Latest = Arc<Mutex<BTreeMap<(String, Vec<(String, String)>), Measurement>>>;
+
+struct Measurements {
+    latest: Latest,
+}
+
+#[zbus::interface(name = "org.sensorflow.Measurements1")]
+impl Measurements {
+    async fn get_latest(&self, name: &str) -> Vec<Record> {
+        self.latest
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|measurement| name.is_empty() || measurement.name == name)
+            .map(record)
+            .collect()
+    }
+
+    #[zbus(signal)]
+    async fn measurement(
+        emitter: &SignalEmitter<'_>,
+        name: &str,
+        tags: BTreeMap<String, String>,
+        fields: BTreeMap<String, OwnedValue>,
+        time_ms: i64,
+    ) -> zbus::Result<()>;
+}
+
+/// Sink emitting measurements as DBus signals
+pub struct DbusSink {
+    config: DbusConfig,
+    latest: Latest,
+    connection: Option<Connection>,
+}
+
+impl DbusSink {
+    pub fn new(config: DbusConfig) -> DbusSink {
+        DbusSink {
+            config,
+            latest: Default::default(),
+            connection: None,
+        }
+    }
+
+    /// Export the object on the connection of `builder`
+    async fn connect(&mut self, builder: Builder<'_>) -> anyhow::Result<()> {
+        let measurements = Measurements {
+            latest: self.latest.clone(),
+        };
+        self.connection = Some(builder.serve_at(PATH, measurements)?.build().await?);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OutputSink for DbusSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        let builder = match self.config.bus {
+            Bus::Session => Builder::session()?,
+            Bus::System => Builder::system()?,
+        };
+        let builder = builder.name(self.config.name.clone())?;
+        self.connect(builder).await
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        self.latest.lock().unwrap().insert(
+            (measurement.name.clone(), measurement.tags.clone()),
+            measurement.clone(),
+        );
+        let Some(connection) = &self.connection else {
+            return Err(SinkError::Fatal("DBus sink not started".into()).into());
+        };
+        let (name, tags, fields, time_ms) = record(measurement);
+        let emitter = SignalEmitter::new(connection, PATH)?;
+        Measurements::measurement(&emitter, &name, tags, fields, time_ms)
+            .await
+            .map_err(|e| SinkError::Retryable(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        if let Some(connection) = self.connection.take() {
+            connection.close().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DbusConfig, DbusSink, Record, PATH};
+    use crate::{output::OutputSink, Measurement};
+    use chrono::DateTime;
+    use std::pin::Pin;
+    use zbus::{
+        connection::Builder, export::futures_core::Stream, message::Type, zvariant::OwnedValue,
+        MessageStream,
+    };
+
+    #[tokio::test]
+    async fn test_signal_and_get_latest() {
+        let (server, client) = tokio::net::UnixStream::pair().unwrap();
+        let mut sink = DbusSink::new(DbusConfig::default());
+        let server = Builder::unix_stream(server)
+            .server(zbus::Guid::generate())
+            .unwrap()
+            .p2p();
+        let (connected, client) = tokio::join!(
+            sink.connect(server),
+            Builder::unix_stream(client).p2p().build()
+        );
+        connected.unwrap();
+        let client = client.unwrap();
+        let mut messages = MessageStream::from(&client);
+
+        for (sensor, temperature) in [(7, 10.), (12, 20.5), (12, 21.)] {
+            sink.write(
+                &Measurement::new("tempHum")
+                    .add_tag("sensorId", sensor)
+                    .add_field("temperature", temperature)
+                    .add_time(DateTime::from_timestamp(1700000000, 0)),
+            )
+            .await
+            .unwrap();
+        }
+
+        let signal = loop {
+            let message = std::future::poll_fn(|cx| Pin::new(&mut messages).poll_next(cx))
+                .await
+                .unwrap()
+                .unwrap();
+            if message.message_type() == Type::Signal {
+                break message;
+            }
+        };
+        let header = signal.header();
+        assert_eq!(header.member().unwrap().as_str(), "Measurement");
+        assert_eq!(
+            header.interface().unwrap().as_str(),
+            "org.sensorflow.Measurements1"
+        );
+        let (name, tags, fields, time_ms): Record = signal.body().deserialize().unwrap();
+        assert_eq!(name, "tempHum");
+        assert_eq!(tags["sensorId"], "7");
+        assert_eq!(fields["temperature"], OwnedValue::from(10.));
+        assert_eq!(time_ms, 1700000000000);
+
+        let reply = client
+            .call_method(
+                None::<&str>,
+                PATH,
+                Some("org.sensorflow.Measurements1"),
+                "GetLatest",
+                &("tempHum",),
+            )
+            .await
+            .unwrap();
+        let latest: Vec<Record> = reply.body().deserialize().unwrap();
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[0].1["sensorId"], "12");
+        assert_eq!(latest[0].2["temperature"], OwnedValue::from(21.));
+        sink.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+//! Temperature and humidity updates of Domoticz devices.
+//!
+//! Each measurement is matched against the configured devices by name and tags. A matching
+//! measurement updates the virtual sensor with the given `idx` through the JSON API
+//! (`/json.htm?type=command&param=udevice`). Depending on the fields present, the update is sent
+//! in the format of a temperature, humidity or combined temperature and humidity sensor.
+use super::{error::SinkError, retry::RetryConfig, OutputSink};
+use crate::{
+    measurement::{FieldValue, Measurement},
+    tls::TlsConfig,
+    transform::selects,
+};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+fn default_temperature_field() -> String {
+    "temperature".into()
+}
+
+fn default_humidity_field() -> String {
+    "humidity".into()
+}
+
+/// Virtual sensor updated by matching measurements
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct DeviceMapping {
+    /// Index of the device in Domoticz
+    pub idx: u32,
+    pub measurement: String,
+    /// Only measurements carrying all of these tags are matched
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default = "default_temperature_field")]
+    pub temperature_field: String,
+    #[serde(default = "default_humidity_field")]
+    pub humidity_field: String,
+}
+
+impl DeviceMapping {
+    fn matches(&self, measurement: &Measurement) -> bool {
+        selects(Some(self.measurement.as_str()), &self.tags, measurement)
+    }
+}
+
+/// Server and its devices
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct DomoticzConfig {
+    /// Base URL of the server, e.g. `http://domoticz:8080`
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub devices: Vec<DeviceMapping>,
+    /// TLS settings of `https` URLs, see [crate::tls]
+    pub tls: Option<TlsConfig>,
+    /// Retries of failed writes, see [super::retry]
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+fn number(value: &FieldValue) -> Option<f64> {
+    match value {
+        FieldValue::Float(x) => Some(*x),
+        FieldValue::Integer(x) => Some(*x as f64),
+        FieldValue::UInteger(x) => Some(*x as f64),
+        FieldValue::String(_) | FieldValue::Boolean(_) => None,
+    }
+}
+
+/// Humidity status expected by Domoticz: normal, comfortable, dry or wet
+fn humidity_status(humidity: f64) -> u8 {
+    if humidity < 30. {
+        2
+    } else if humidity > 70. {
+        3
+    } else if (40. ..=60.).contains(&humidity) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Query parameters `nvalue` and `svalue` of a device update, `None` if the measurement has
+/// neither temperature nor humidity
+pub fn device_values(mapping: &DeviceMapping, measurement: &Measurement) -> Option<(u32, String)> {
+    let temperature = measurement
+        .field(&mapping.temperature_field)
+        .and_then(number);
+    let humidity = measurement.field(&mapping.humidity_field).and_then(number);
+    match (temperature, humidity) {
+        (Some(temperature), Some(humidity)) => Some((
+            0,
+            format!(
+                "{:.1};{:.0};{}",
+                temperature,
+                humidity,
+                humidity_status(humidity)
+            ),
+        )),
+        (Some(temperature), None) => Some((0, format!("{:.1}", temperature))),
+        (None, Some(humidity)) => Some((
+            humidity.round() as u32,
+            humidity_status(humidity).to_string(),
+        )),
+        (None, None) => None,
+    }
+}
+
+/// Sink updating Domoticz devices
+pub struct DomoticzSink {
+    config: DomoticzConfig,
+    client: reqwest::Client,
+}
+
+impl DomoticzSink {
+    pub fn new(config: DomoticzConfig) -> anyhow::Result<DomoticzSink> {
+        let mut client = reqwest::Client::builder().timeout(Duration::from_secs(10));
+        if let Some(tls) = &config.tls {
+            client = tls.http_client(client)?;
+        }
+        let client = client.build()?;
+        Ok(DomoticzSink { config, client })
+    }
+
+    async fn update(&self, idx: u32, nvalue: u32, svalue: &str) -> anyhow::Result<()> {
+        let url = format!("{}/json.htm", self.config.url.trim_end_matches('/'));
+        let mut request = self.client.get(url).query(&[
+            ("type", "command"),
+            ("param", "udevice"),
+            ("idx", &idx.to_string()),
+            ("nvalue", &nvalue.to_string()),
+            ("svalue", svalue),
+        ]);
+        if let Some(username) = &self.config.username {
+            request = request.basic_auth(username, self.config.password.as_ref());
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| SinkError::Retryable(e.to_string()))?;
+        let status = response.status();
+        if status.is_server_error() {
+            return Err(SinkError::Retryable(status.to_string()).into());
+        }
+        if !status.is_success() {
+            return Err(SinkError::Fatal(status.to_string()).into());
+        }
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| SinkError::Retryable(e.to_string()))?;
+        let body: serde_json::Value = serde_json::from_slice(&body)?;
+        if body["status"] != "OK" {
+            return Err(
+                SinkError::Fatal(format!("Update of device {} failed: {}", idx, body)).into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OutputSink for DomoticzSink {
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        for mapping in &self.config.devices {
+            if !mapping.matches(measurement) {
+                continue;
+            }
+            if let Some((nvalue, svalue)) = device_values(mapping, measurement) {
+                self.update(mapping.idx, nvalue, &svalue).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{device_values, DomoticzConfig, DomoticzSink};
+    use crate::{output::OutputSink, Measurement};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn config(url: &str) -> DomoticzConfig {
+        toml::from_str(&format!(
+            r#"
+            url = "{}"
+            [[devices]]
+            idx = 42
+            measurement = "tempHum"
+            tags = {{ sensorId = "12" }}
+            "#,
+            url
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_device_values() {
+        let config = config("http://domoticz");
+        let mapping = &config.devices[0];
+        let measurement = Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.46)
+            .add_field("humidity", 45u64);
+        assert_eq!(
+            device_values(mapping, &measurement),
+            Some((0, "21.5;45;1".into()))
+        );
+        let measurement = Measurement::new("tempHum").add_field("temperature", -3.);
+        assert_eq!(
+            device_values(mapping, &measurement),
+            Some((0, "-3.0".into()))
+        );
+        let measurement = Measurement::new("tempHum").add_field("humidity", 75u64);
+        assert_eq!(device_values(mapping, &measurement), Some((75, "3".into())));
+        assert_eq!(device_values(mapping, &Measurement::new("tempHum")), None);
+    }
+
+    #[tokio::test]
+    async fn test_update_device() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let len = socket.read(&mut request).await.unwrap();
+            let body = r#"{"status":"OK","title":"Update Device"}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request[..len])
+                .lines()
+                .next()
+                .unwrap()
+                .to_string()
+        });
+
+        let mut sink = DomoticzSink::new(config(&url)).unwrap();
+        sink.write(
+            &Measurement::new("tempHum")
+                .add_tag("sensorId", 7)
+                .add_field("temperature", 1.),
+        )
+        .await
+        .unwrap();
+        sink.write(
+            &Measurement::new("tempHum")
+                .add_tag("sensorId", 12)
+                .add_field("temperature", 20.),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            "GET /json.htm?type=command&param=udevice&idx=42&nvalue=0&svalue=20.0 HTTP/1.1"
+        );
+    }
+}
@@ -0,0 +1,421 @@
+//! Periodic email digests.
+//!
+//! Instead of forwarding measurements, the sink summarizes them: for every series, i.e.
+//! combination of name and tags, the minimum, maximum and mean of each numeric field over the
+//! period are listed. Measurements named in `alerts`, by default the `event` measurements of
+//! the [EventDetector] and the `watchdog` measurements of the [Watchdog], are listed
+//! individually in a separate section. Once the period is over the digest is sent over SMTP and
+//! a new period starts; a remaining digest is sent on shutdown. Empty digests are not sent.
+//!
+//! ```toml
+//! [[outputs]]
+//! type = "email"
+//! server = "smtp.example.org"
+//! username = "sensorflow@example.org"
+//! password = "secret"
+//! from = "Sensorflow <sensorflow@example.org>"
+//! to = ["me@example.org"]
+//! interval = 86400
+//! ```
+//!
+//! Requires the `email` feature.
+//!
+//! [EventDetector]: crate::transform::events::EventDetector
+//! [Watchdog]: crate::transform::watchdog::Watchdog
+use super::{error::SinkError, retry::RetryConfig, OutputSink};
+use crate::measurement::{FieldValue, Measurement};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use lettre::{
+    message::header::ContentType, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+fn default_subject() -> String {
+    "Sensorflow digest".into()
+}
+
+fn default_interval() -> u64 {
+    // Daily
+    86400
+}
+
+fn default_alerts() -> Vec<String> {
+    vec!["alert".into(), "event".into(), "watchdog".into()]
+}
+
+/// Encryption of the SMTP connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum Tls {
+    /// Upgrade of a plain connection, port 587 by default
+    #[default]
+    Starttls,
+    /// TLS from the start, port 465 by default
+    Implicit,
+    /// Unencrypted, port 25 by default, only for relays on the local host
+    None,
+}
+
+/// SMTP server, recipients and period of the digest
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    pub server: String,
+    /// Port of the server, default depends on `tls`
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: Tls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(default = "default_subject")]
+    pub subject: String,
+    /// Period of a digest in seconds
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Names of measurements listed individually as alerts
+    #[serde(default = "default_alerts")]
+    pub alerts: Vec<String>,
+    /// Retries of failed writes, see [super::retry]
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+/// Statistics of a field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+}
+
+impl Stats {
+    fn new(value: f64) -> Stats {
+        Stats {
+            count: 1,
+            min: value,
+            max: value,
+            sum: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// Name and tags of a series
+pub type SeriesKey = (String, Vec<(String, String)>);
+
+/// Summary of the measurements of a period
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Digest {
+    /// Statistics by series and name of the field
+    pub series: BTreeMap<SeriesKey, BTreeMap<String, Stats>>,
+    pub alerts: Vec<Measurement>,
+}
+
+impl Digest {
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty() && self.alerts.is_empty()
+    }
+
+    /// Add the numeric fields of a measurement to the statistics of its series
+    pub fn add(&mut self, measurement: &Measurement) {
+        let stats = self
+            .series
+            .entry((measurement.name.clone(), measurement.tags.clone()))
+            .or_default();
+        for (field, value) in &measurement.fields {
+            let value = match value {
+                FieldValue::Float(x) => *x,
+                FieldValue::Integer(x) => *x as f64,
+                FieldValue::UInteger(x) => *x as f64,
+                FieldValue::Boolean(_) | FieldValue::String(_) => continue,
+            };
+            stats
+                .entry(field.clone())
+                .and_modify(|stats| stats.add(value))
+                .or_insert_with(|| Stats::new(value));
+        }
+    }
+
+    /// Plain text body of the digest of the period from `start` to `end`
+    pub fn render(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> String {
+        const TIME: &str = "%Y-%m-%d %H:%M UTC";
+        let mut body = format!(
+            "Measurements from {} to {}\n",
+            start.format(TIME),
+            end.format(TIME)
+        );
+        if !self.alerts.is_empty() {
+            let _ = writeln!(body, "\nAlerts\n");
+            for alert in &self.alerts {
+                let _ = writeln!(body, "  {}", alert);
+            }
+        }
+        for ((name, tags), fields) in &self.series {
+            let _ = write!(body, "\n{}", name);
+            for (key, value) in tags {
+                let _ = write!(body, " {}={}", key, value);
+            }
+            body.push('\n');
+            for (field, stats) in fields {
+                let _ = writeln!(
+                    body,
+                    "  {}: min {} max {} mean {:.2} ({} values)",
+                    field,
+                    stats.min,
+                    stats.max,
+                    stats.mean(),
+                    stats.count
+                );
+            }
+        }
+        body
+    }
+}
+
+/// Sink mailing a digest per period
+pub struct EmailSink {
+    config: EmailConfig,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    digest: Digest,
+    start: DateTime<Utc>,
+}
+
+impl EmailSink {
+    pub fn new(config: EmailConfig) -> anyhow::Result<EmailSink> {
+        type Transport = AsyncSmtpTransport<Tokio1Executor>;
+        let mut builder = match config.tls {
+            Tls::Starttls => Transport::starttls_relay(&config.server)?,
+            Tls::Implicit => Transport::relay(&config.server)?,
+            Tls::None => Transport::builder_dangerous(&config.server),
+        };
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let Some(username) = &config.username {
+            builder = builder.credentials(Credentials::new(
+                username.clone(),
+                config.password.clone().unwrap_or_default(),
+            ));
+        }
+        // Validate addresses early
+        EmailSink::message(&config, String::new())?;
+        Ok(EmailSink {
+            transport: builder.build(),
+            config,
+            digest: Digest::default(),
+            start: Utc::now(),
+        })
+    }
+
+    fn message(config: &EmailConfig, body: String) -> anyhow::Result<Message> {
+        let mut message = Message::builder()
+            .from(config.from.parse()?)
+            .subject(&config.subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &config.to {
+            message = message.to(to.parse()?);
+        }
+        Ok(message.body(body)?)
+    }
+
+    /// Send the digest of the current period and start a new one. The digest is kept if
+    /// sending fails.
+    async fn send_digest(&mut self, end: DateTime<Utc>) -> anyhow::Result<()> {
+        if !self.digest.is_empty() {
+            let body = self.digest.render(self.start, end);
+            let message = EmailSink::message(&self.config, body)?;
+            self.transport
+                .send(message)
+                .await
+                .map_err(|e| SinkError::Retryable(e.to_string()))?;
+        }
+        self.digest = Digest::default();
+        self.start = end;
+        Ok(())
+    }
+
+    /// Send the digest if the period is over
+    async fn send_due(&mut self) -> anyhow::Result<()> {
+        let now = Utc::now();
+        if now >= self.start + TimeDelta::seconds(self.config.interval as i64) {
+            self.send_digest(now).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OutputSink for EmailSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        self.start = Utc::now();
+        Ok(())
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        // Send first, so a retried write does not count the measurement twice
+        self.send_due().await?;
+        if self.config.alerts.contains(&measurement.name) {
+            self.digest.alerts.push(measurement.clone());
+        } else {
+            self.digest.add(measurement);
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        self.send_due().await
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.send_digest(Utc::now()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Digest, EmailConfig, EmailSink, RetryConfig, Tls};
+    use crate::{output::OutputSink, Measurement};
+    use chrono::DateTime;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    fn measurement(sensor: u32, temperature: f64) -> Measurement {
+        Measurement::new("tempHum")
+            .add_tag("sensorId", sensor)
+            .add_field("temperature", temperature)
+            .add_field("model", "LaCrosse")
+    }
+
+    #[test]
+    fn test_render() {
+        let mut digest = Digest::default();
+        digest.add(&measurement(12, 20.5));
+        digest.add(&measurement(12, 21.));
+        digest.add(&measurement(7, 10.));
+        digest.alerts.push(
+            Measurement::new("alert")
+                .add_field("message", "Battery low")
+                .add_time(DateTime::from_timestamp(1700000000, 0)),
+        );
+        let body = digest.render(
+            DateTime::from_timestamp(1699920000, 0).unwrap(),
+            DateTime::from_timestamp(1700006400, 0).unwrap(),
+        );
+        assert_eq!(
+            body,
+            "Measurements from 2023-11-14 00:00 UTC to 2023-11-15 00:00 UTC\n\
+             \n\
+             Alerts\n\
+             \n\
+             \x20 alert: message=Battery low @ 2023-11-14T22:13:20+00:00\n\
+             \n\
+             tempHum sensorId=12\n\
+             \x20 temperature: min 20.5 max 21 mean 20.75 (2 values)\n\
+             \n\
+             tempHum sensorId=7\n\
+             \x20 temperature: min 10 max 10 mean 10.00 (1 values)\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_default_alerts() {
+        let config: EmailConfig = toml::from_str(
+            r#"
+            server = "smtp.example.org"
+            from = "sensorflow@example.org"
+            to = ["me@example.org"]
+            "#,
+        )
+        .unwrap();
+        let mut sink = EmailSink::new(config).unwrap();
+        let silent = Measurement::new("watchdog")
+            .add_tag("measurement", "tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("silent", 190u64)
+            .add_field("expected", 60u64);
+        sink.write(&silent).await.unwrap();
+        sink.write(&measurement(12, 20.5)).await.unwrap();
+        assert_eq!(sink.digest.alerts, [silent]);
+        assert_eq!(sink.digest.series.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = socket.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            writer.write_all(b"220 localhost\r\n").await.unwrap();
+            let mut data = Vec::new();
+            let mut in_data = false;
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let reply: &[u8] = if in_data {
+                    if line != "." {
+                        data.push(line);
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    b"221 bye\r\n"
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            data
+        });
+
+        let mut sink = EmailSink::new(EmailConfig {
+            server: "127.0.0.1".into(),
+            port: Some(port),
+            tls: Tls::None,
+            username: None,
+            password: None,
+            from: "Sensorflow <sensorflow@example.org>".into(),
+            to: vec!["me@example.org".into()],
+            subject: "Digest".into(),
+            interval: 3600,
+            alerts: vec!["alert".into()],
+            retry: RetryConfig::default(),
+        })
+        .unwrap();
+        sink.start().await.unwrap();
+        sink.write(&measurement(12, 20.5)).await.unwrap();
+        // The period is not over yet
+        sink.flush().await.unwrap();
+        assert!(!server.is_finished());
+        sink.shutdown().await.unwrap();
+        drop(sink);
+
+        let data = server.await.unwrap();
+        assert!(data.contains(&"Subject: Digest".to_string()));
+        assert!(data.contains(&"To: me@example.org".to_string()));
+        assert!(
+            data.contains(&"  temperature: min 20.5 max 20.5 mean 20.50 (1 values)".to_string())
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! Measurements piped into a user-defined command.
+//!
+//! An escape hatch for integrations without a dedicated sink: measurements are written to the
+//! standard input of a command, one per line, in one of the formats of [Format]. The command
+//! either runs for the lifetime of the pipeline and is restarted if it exits, or is spawned per
+//! batch, i.e. whenever the pipeline has no more measurements queued or as scheduled by `flush`,
+//! with standard input closed after the batch.
+//!
+//! ```toml
+//! [[outputs]]
+//! type = "exec"
+//! command = ["python3", "/etc/sensorflow/forward.py"]
+//! format = "line_protocol"
+//! mode = "batch"
+//! flush = { size = 100, interval = 60 }
+//! ```
+//!
+//! Standard output and error of the command are inherited.
+pub use super::writer::Format;
+use super::{batch::FlushSchedule, error::SinkError, retry::RetryConfig, OutputSink};
+use crate::measurement::Measurement;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, Command};
+
+/// Lifetime of the command
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// One process for all measurements
+    #[default]
+    LongRunning,
+    /// One process per batch
+    Batch,
+}
+
+/// Command and encoding
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct ExecConfig {
+    /// Program and its arguments
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub format: Format,
+    #[serde(default)]
+    pub mode: Mode,
+    /// Flush on a schedule instead of whenever the pipeline ran idle, see [super::batch]
+    #[serde(default)]
+    pub flush: Option<FlushSchedule>,
+    /// Retries of failed writes, see [super::retry]
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+/// Sink writing measurements to the standard input of a command
+pub struct ExecSink {
+    config: ExecConfig,
+    child: Option<Child>,
+    batch: String,
+}
+
+impl ExecSink {
+    pub fn new(config: ExecConfig) -> anyhow::Result<ExecSink> {
+        if config.command.is_empty() {
+            anyhow::bail!("Command of exec output is empty");
+        }
+        Ok(ExecSink {
+            config,
+            child: None,
+            batch: String::new(),
+        })
+    }
+
+    fn spawn(&self) -> anyhow::Result<Child> {
+        let child = Command::new(&self.config.command[0])
+            .args(&self.config.command[1..])
+            .stdin(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| {
+                SinkError::Fatal(format!("Failed to run {}: {}", self.config.command[0], e))
+            })?;
+        Ok(child)
+    }
+
+    /// Write to the long-running process, restarting it if it exited
+    async fn write_long_running(&mut self, line: &str) -> anyhow::Result<()> {
+        if let Some(child) = self.child.as_mut() {
+            if let Ok(Some(_)) = child.try_wait() {
+                self.child = None;
+            }
+        }
+        let child = match self.child.as_mut() {
+            Some(child) => child,
+            None => self.child.insert(self.spawn()?),
+        };
+        let stdin = child.stdin.as_mut().expect("stdin is piped");
+        if let Err(e) = stdin.write_all(line.as_bytes()).await {
+            self.child = None;
+            return Err(SinkError::Retryable(e.to_string()).into());
+        }
+        Ok(())
+    }
+
+    /// Run the command with the current batch as input
+    async fn run_batch(&mut self) -> anyhow::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let mut child = self.spawn()?;
+        let batch = std::mem::take(&mut self.batch);
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        // The command may exit without reading all of its input
+        let _ = stdin.write_all(batch.as_bytes()).await;
+        drop(stdin);
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(SinkError::Fatal(format!(
+                "{} exited with {}",
+                self.config.command[0], status
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OutputSink for ExecSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        if self.config.mode == Mode::LongRunning {
+            self.child = Some(self.spawn()?);
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let line = self.config.format.line(measurement);
+        match self.config.mode {
+            Mode::LongRunning => self.write_long_running(&line).await,
+            Mode::Batch => {
+                self.batch.push_str(&line);
+                Ok(())
+            }
+        }
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        match self.config.mode {
+            Mode::LongRunning => {
+                if let Some(stdin) = self.child.as_mut().and_then(|child| child.stdin.as_mut()) {
+                    stdin
+                        .flush()
+                        .await
+                        .map_err(|e| SinkError::Retryable(e.to_string()))?;
+                }
+                Ok(())
+            }
+            Mode::Batch => self.run_batch().await,
+        }
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.flush().await?;
+        if let Some(mut child) = self.child.take() {
+            // Closing standard input asks the command to exit
+            drop(child.stdin.take());
+            if tokio::time::timeout(Duration::from_secs(5), child.wait())
+                .await
+                .is_err()
+            {
+                child.kill().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ExecConfig, ExecSink, Format, Mode, RetryConfig};
+    use crate::{output::OutputSink, Measurement};
+    use chrono::DateTime;
+    use std::path::PathBuf;
+
+    fn measurement(temperature: f64) -> Measurement {
+        Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", temperature)
+            .add_time(DateTime::from_timestamp(1700000000, 0))
+    }
+
+    fn output_file() -> PathBuf {
+        std::env::temp_dir().join(format!("sensorflow-exec-{:08x}", rand::random::<u32>()))
+    }
+
+    #[tokio::test]
+    async fn test_long_running() {
+        let path = output_file();
+        let mut sink = ExecSink::new(ExecConfig {
+            command: vec![
+                "sh".into(),
+                "-c".into(),
+                format!("cat > {}", path.display()),
+            ],
+            format: Format::LineProtocol,
+            mode: Mode::LongRunning,
+            flush: None,
+            retry: RetryConfig::default(),
+        })
+        .unwrap();
+        sink.start().await.unwrap();
+        sink.write(&measurement(21.5)).await.unwrap();
+        sink.write(&measurement(22.)).await.unwrap();
+        sink.shutdown().await.unwrap();
+
+        let output = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(output.lines().count(), 2);
+        assert!(output.ends_with("temperature=22 1700000000000000000\n"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        let path = output_file();
+        let mut sink = ExecSink::new(ExecConfig {
+            command: vec![
+                "sh".into(),
+                "-c".into(),
+                format!("wc -l >> {}", path.display()),
+            ],
+            format: Format::Json,
+            mode: Mode::Batch,
+            flush: None,
+            retry: RetryConfig::default(),
+        })
+        .unwrap();
+        sink.start().await.unwrap();
+        sink.write(&measurement(21.5)).await.unwrap();
+        sink.write(&measurement(22.)).await.unwrap();
+        sink.flush().await.unwrap();
+        sink.write(&measurement(22.5)).await.unwrap();
+        sink.shutdown().await.unwrap();
+
+        let counts: Vec<String> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| line.trim().to_string())
+            .collect();
+        assert_eq!(counts, ["2", "1"]);
+        std::fs::remove_file(path).unwrap();
+
+        let mut sink = ExecSink::new(ExecConfig {
+            command: vec!["false".into()],
+            format: Format::Json,
+            mode: Mode::Batch,
+            flush: None,
+            retry: RetryConfig::default(),
+        })
+        .unwrap();
+        sink.write(&measurement(21.5)).await.unwrap();
+        assert!(sink.flush().await.is_err());
+    }
+}
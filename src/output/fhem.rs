@@ -0,0 +1,260 @@
+//! Readings of FHEM devices through the telnet port.
+//!
+//! Installations migrating from FHEM may attach the JeeLink or CUL to sensorflow while FHEM
+//! keeps its devices, logs and plots. Fields of matching measurements are sent as commands on
+//! the telnet port of FHEM, by default `setreading <device> <reading> <value>`, or with
+//! `command = "set"` as `set <device> <reading> <value>` for dummies with a `setList`. Without
+//! `readings`, every field is sent as reading of the same name. Booleans are sent as `on` or
+//! `off`.
+//!
+//! ```toml
+//! [[outputs]]
+//! type = "fhem"
+//! address = "fhem:7072"
+//! password = "${FHEM_TELNET_PASSWORD}"
+//!
+//! [[outputs.devices]]
+//! device = "Cellar_TempHum"
+//! measurement = "tempHum"
+//! tags = { sensorId = "12" }
+//! readings = { temperature = "temperature", humidity = "humidity" }
+//! ```
+//!
+//! FHEM answers only on errors, answers are not read.
+use super::{error::SinkError, retry::RetryConfig, OutputSink};
+use crate::measurement::{FieldValue, Measurement};
+use crate::transform::selects;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+fn default_address() -> String {
+    "127.0.0.1:7072".into()
+}
+
+/// Command updating a reading
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum FhemCommand {
+    /// Set the reading without triggering the device
+    #[default]
+    Setreading,
+    /// Set through the device, e.g. a dummy
+    Set,
+}
+
+/// FHEM device fed by matching measurements
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct FhemDevice {
+    /// Name of the device in FHEM
+    pub device: String,
+    pub measurement: String,
+    /// Only measurements carrying all of these tags are matched
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Readings by field, all fields if empty
+    #[serde(default)]
+    pub readings: BTreeMap<String, String>,
+    #[serde(default)]
+    pub command: FhemCommand,
+}
+
+impl FhemDevice {
+    fn matches(&self, measurement: &Measurement) -> bool {
+        selects(Some(self.measurement.as_str()), &self.tags, measurement)
+    }
+
+    /// Commands updating the readings with the fields of `measurement`
+    pub fn commands(&self, measurement: &Measurement) -> Vec<String> {
+        let command = match self.command {
+            FhemCommand::Setreading => "setreading",
+            FhemCommand::Set => "set",
+        };
+        measurement
+            .fields
+            .iter()
+            .filter_map(|(field, value)| {
+                let reading = match self.readings.is_empty() {
+                    true => field,
+                    false => self.readings.get(field)?,
+                };
+                let value = match value {
+                    FieldValue::Boolean(true) => "on".into(),
+                    FieldValue::Boolean(false) => "off".into(),
+                    // Line breaks would end the command
+                    FieldValue::String(s) => s.replace(['\r', '\n'], " "),
+                    value => value.to_string(),
+                };
+                Some(format!(
+                    "{} {} {} {}\n",
+                    command, self.device, reading, value
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Telnet port and devices
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct FhemConfig {
+    /// Host and port of the telnet port
+    #[serde(default = "default_address")]
+    pub address: String,
+    /// Password of the telnet port, if set in FHEM
+    pub password: Option<String>,
+    pub devices: Vec<FhemDevice>,
+    /// Retries of failed writes, see [super::retry]
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+/// Sink sending readings to FHEM
+pub struct FhemSink {
+    config: FhemConfig,
+    connection: Option<TcpStream>,
+}
+
+impl FhemSink {
+    pub fn new(config: FhemConfig) -> FhemSink {
+        FhemSink {
+            config,
+            connection: None,
+        }
+    }
+
+    async fn connect(&self) -> anyhow::Result<TcpStream> {
+        let mut connection = TcpStream::connect(&self.config.address)
+            .await
+            .map_err(|e| SinkError::Retryable(e.to_string()))?;
+        if let Some(password) = &self.config.password {
+            connection
+                .write_all(format!("{}\n", password).as_bytes())
+                .await
+                .map_err(|e| SinkError::Retryable(e.to_string()))?;
+        }
+        Ok(connection)
+    }
+}
+
+#[async_trait]
+impl OutputSink for FhemSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        self.connection = Some(self.connect().await?);
+        Ok(())
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let commands: String = self
+            .config
+            .devices
+            .iter()
+            .filter(|device| device.matches(measurement))
+            .flat_map(|device| device.commands(measurement))
+            .collect();
+        if commands.is_empty() {
+            return Ok(());
+        }
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => self.connection.insert(self.connect().await?),
+        };
+        if let Err(e) = connection.write_all(commands.as_bytes()).await {
+            // Connect again on the next write
+            self.connection = None;
+            return Err(SinkError::Retryable(e.to_string()).into());
+        }
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        if let Some(mut connection) = self.connection.take() {
+            connection.write_all(b"quit\n").await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FhemConfig, FhemSink};
+    use crate::{output::OutputSink, Measurement};
+    use tokio::io::AsyncReadExt;
+
+    fn config(address: &str) -> FhemConfig {
+        toml::from_str(&format!(
+            r#"
+            address = "{}"
+            password = "s3cret"
+            [[devices]]
+            device = "Cellar_TempHum"
+            measurement = "tempHum"
+            tags = {{ sensorId = "12" }}
+            readings = {{ temperature = "temperature", battery_low = "batteryLow" }}
+            [[devices]]
+            device = "Cellar_Dummy"
+            measurement = "tempHum"
+            command = "set"
+            "#,
+            address
+        ))
+        .unwrap()
+    }
+
+    fn measurement(sensor_id: u32) -> Measurement {
+        Measurement::new("tempHum")
+            .add_tag("sensorId", sensor_id)
+            .add_field("temperature", 12.5)
+            .add_field("humidity", 80u64)
+            .add_field("battery_low", false)
+    }
+
+    #[test]
+    fn test_commands() {
+        let config = config("fhem:7072");
+        assert_eq!(
+            config.devices[0].commands(&measurement(12)),
+            [
+                "setreading Cellar_TempHum temperature 12.5\n",
+                "setreading Cellar_TempHum batteryLow off\n"
+            ]
+        );
+        assert_eq!(
+            config.devices[1].commands(&measurement(12))[1],
+            "set Cellar_Dummy humidity 80\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_telnet() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = String::new();
+            socket.read_to_string(&mut received).await.unwrap();
+            received
+        });
+
+        let mut config = config(&address);
+        config.devices.truncate(1);
+        let mut sink = FhemSink::new(config);
+        sink.start().await.unwrap();
+        sink.write(&measurement(13)).await.unwrap();
+        sink.write(&measurement(12)).await.unwrap();
+        sink.shutdown().await.unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            "s3cret\n\
+             setreading Cellar_TempHum temperature 12.5\n\
+             setreading Cellar_TempHum batteryLow off\n\
+             quit\n"
+        );
+    }
+}
@@ -0,0 +1,446 @@
+//! File output with rotation and retention.
+//!
+//! Measurements are appended to a file in one of the formats of [Format], e.g. NDJSON or line
+//! protocol. The file is rotated once it would exceed `max_size` bytes or was opened `max_age`
+//! seconds ago: it is renamed by appending the time of the rotation, e.g.
+//! `sensorflow.log.20231114T221320`, optionally compressed with gzip, and a new file is started.
+//! Rotated files beyond `max_files` or older than `retention` seconds are deleted, so long-running
+//! loggers do not fill the disk. Only files named like rotated ones are considered. Failures to
+//! compress or delete rotated files are logged and do not fail the write.
+//!
+//! ```toml
+//! [[outputs]]
+//! type = "file"
+//! path = "/var/log/sensorflow/measurements.ndjson"
+//! format = "json"
+//! rotation = { max_size = 10485760, compress = true, max_files = 30 }
+//! ```
+use super::{
+    writer::{Encode, Format},
+    OutputSink,
+};
+use crate::measurement::Measurement;
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Limits of a file and of the rotated files
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct Rotation {
+    /// Size in bytes after which the file is rotated
+    pub max_size: Option<u64>,
+    /// Seconds after opening the file after which it is rotated
+    pub max_age: Option<u64>,
+    /// Compress rotated files with gzip
+    #[serde(default)]
+    pub compress: bool,
+    /// Number of rotated files kept
+    pub max_files: Option<usize>,
+    /// Seconds rotated files are kept
+    pub retention: Option<u64>,
+}
+
+/// Path, format and rotation of the output
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: Format,
+    #[serde(default)]
+    pub rotation: Rotation,
+}
+
+/// Format of the time appended to rotated files
+const ROTATED_TIME: &str = "%Y%m%dT%H%M%S";
+
+/// Compress a file into `<path>.gz` and remove it. A partially written target is removed.
+fn compress(path: &Path) -> std::io::Result<PathBuf> {
+    let mut target = path.as_os_str().to_owned();
+    target.push(".gz");
+    let target = PathBuf::from(target);
+    let write = || {
+        let mut encoder = GzEncoder::new(File::create(&target)?, Compression::default());
+        std::io::copy(&mut File::open(path)?, &mut encoder)?;
+        encoder.finish()?.sync_all()
+    };
+    if let Err(e) = write() {
+        let _ = std::fs::remove_file(&target);
+        return Err(e);
+    }
+    std::fs::remove_file(path)?;
+    Ok(target)
+}
+
+/// Whether `suffix` follows the name of the file in a rotated one: the time of the rotation,
+/// a counter if several rotations happened within a second, and `.gz` if compressed
+fn is_rotated(suffix: &str) -> bool {
+    let suffix = suffix.strip_suffix(".gz").unwrap_or(suffix);
+    let time = match suffix.split_once('-') {
+        Some((time, counter)) => {
+            if counter.is_empty() || !counter.bytes().all(|b| b.is_ascii_digit()) {
+                return false;
+            }
+            time
+        }
+        None => suffix,
+    };
+    time.len() == 15 && NaiveDateTime::parse_from_str(time, ROTATED_TIME).is_ok()
+}
+
+/// File appended to in records, which are never split by a rotation
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    writer: BufWriter<File>,
+    size: u64,
+    opened: SystemTime,
+}
+
+impl RotatingFile {
+    /// Open the file for appending, creating it if necessary
+    pub fn open(path: impl Into<PathBuf>, rotation: Rotation) -> std::io::Result<RotatingFile> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        Ok(RotatingFile {
+            size: metadata.len(),
+            opened: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            writer: BufWriter::new(file),
+            path,
+            rotation,
+        })
+    }
+
+    /// Whether the file has to be rotated before writing `len` bytes
+    pub fn is_due(&self, len: usize, now: SystemTime) -> bool {
+        let too_large = self
+            .rotation
+            .max_size
+            .is_some_and(|max_size| self.size > 0 && self.size + len as u64 > max_size);
+        let too_old = self.rotation.max_age.is_some_and(|max_age| {
+            now.duration_since(self.opened).unwrap_or_default() >= Duration::from_secs(max_age)
+        });
+        too_large || too_old
+    }
+
+    /// Rotated files, oldest first
+    fn rotated(&self) -> std::io::Result<Vec<PathBuf>> {
+        let prefix = format!(
+            "{}.",
+            self.path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        let directory = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut files: Vec<_> = std::fs::read_dir(directory)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                name.strip_prefix(&prefix).is_some_and(is_rotated)
+            })
+            .map(|entry| entry.path())
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    /// Delete rotated files beyond the limits of the retention. Failures are logged, the
+    /// remaining files are still considered.
+    fn clean_up(&self, now: SystemTime) {
+        let mut files = match self.rotated() {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!(
+                    "Failed to list rotated files of {}: {}",
+                    self.path.display(),
+                    e
+                );
+                return;
+            }
+        };
+        let remove = |path: &Path| {
+            if let Err(e) = std::fs::remove_file(path) {
+                eprintln!("Failed to delete {}: {}", path.display(), e);
+            }
+        };
+        if let Some(max_files) = self.rotation.max_files {
+            let excess = files.len().saturating_sub(max_files);
+            for path in files.drain(..excess) {
+                remove(&path);
+            }
+        }
+        if let Some(retention) = self.rotation.retention {
+            for path in files {
+                let modified = match std::fs::metadata(&path).and_then(|m| m.modified()) {
+                    Ok(modified) => modified,
+                    Err(e) => {
+                        eprintln!("Failed to read the age of {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                if now.duration_since(modified).unwrap_or_default() > Duration::from_secs(retention)
+                {
+                    remove(&path);
+                }
+            }
+        }
+    }
+
+    /// Rename the file, compress it if configured, start a new one and apply the retention.
+    /// Only failures to rename the file or to start the new one are returned.
+    pub fn rotate(&mut self, now: SystemTime) -> std::io::Result<()> {
+        self.writer.flush()?;
+        let stem = format!(
+            "{}.{}",
+            self.path.display(),
+            DateTime::<Utc>::from(now).format(ROTATED_TIME)
+        );
+        let mut rotated = PathBuf::from(&stem);
+        let mut counter = 1;
+        while rotated.exists() || Path::new(&format!("{}.gz", rotated.display())).exists() {
+            rotated = PathBuf::from(format!("{}-{}", stem, counter));
+            counter += 1;
+        }
+        std::fs::rename(&self.path, &rotated)?;
+        *self = RotatingFile::open(self.path.clone(), self.rotation.clone())?;
+        self.opened = now;
+        if self.rotation.compress {
+            if let Err(e) = compress(&rotated) {
+                eprintln!("Failed to compress {}: {}", rotated.display(), e);
+            }
+        }
+        self.clean_up(now);
+        Ok(())
+    }
+
+    /// Append a record, rotating the file before if necessary
+    pub fn write_record(&mut self, record: &[u8]) -> std::io::Result<()> {
+        let now = SystemTime::now();
+        if self.is_due(record.len(), now) {
+            self.rotate(now)?;
+        }
+        self.append(record)
+    }
+
+    fn append(&mut self, record: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(record)?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Sink appending measurements to a rotating file
+pub struct FileSink {
+    config: FileConfig,
+    file: Option<RotatingFile>,
+}
+
+impl FileSink {
+    pub fn new(config: FileConfig) -> FileSink {
+        FileSink { config, file: None }
+    }
+
+    fn file(&mut self) -> anyhow::Result<&mut RotatingFile> {
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => RotatingFile::open(&self.config.path, self.config.rotation.clone())
+                .with_context(|| format!("Failed to open {}", self.config.path.display()))?,
+        };
+        Ok(self.file.insert(file))
+    }
+}
+
+#[async_trait]
+impl OutputSink for FileSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        self.file()?;
+        Ok(())
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let record = self.config.format.encode(measurement)?;
+        let now = SystemTime::now();
+        let file = self.file()?;
+        if file.is_due(record.len(), now) {
+            // Compression may take a while
+            let mut file = self.file.take().expect("file is open");
+            let file = tokio::task::spawn_blocking(move || file.rotate(now).map(|_| file))
+                .await?
+                .with_context(|| format!("Failed to rotate {}", self.config.path.display()))?;
+            self.file = Some(file);
+        }
+        self.file()?.append(record.as_bytes())?;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_rotated, FileConfig, FileSink, RotatingFile, Rotation};
+    use crate::{
+        output::{writer::Format, OutputSink},
+        Measurement,
+    };
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    fn directory() -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("sensorflow-file-{:08x}", rand::random::<u32>()));
+        std::fs::create_dir(&directory).unwrap();
+        directory
+    }
+
+    fn file_names(directory: &PathBuf) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_rotation_and_retention() {
+        let directory = directory();
+        let path = directory.join("sensorflow.log");
+        let mut file = RotatingFile::open(
+            &path,
+            Rotation {
+                max_size: Some(10),
+                max_files: Some(2),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+        for i in 0..4 {
+            let now = start + Duration::from_secs(i * 60);
+            assert_eq!(file.is_due(8, now), i > 0);
+            if file.is_due(8, now) {
+                file.rotate(now).unwrap();
+            }
+            file.append(format!("record {}\n", i).as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+        assert_eq!(
+            file_names(&directory),
+            [
+                "sensorflow.log",
+                "sensorflow.log.20231114T221520",
+                "sensorflow.log.20231114T221620",
+            ]
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "record 3\n");
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_retention_keeps_other_files() {
+        let directory = directory();
+        let path = directory.join("sensorflow.log");
+        for name in ["sensorflow.log.bak", "sensorflow.log.20231114T221520.tmp"] {
+            std::fs::write(directory.join(name), "").unwrap();
+        }
+        let mut file = RotatingFile::open(
+            &path,
+            Rotation {
+                max_files: Some(0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        file.rotate(SystemTime::now()).unwrap();
+        assert_eq!(
+            file_names(&directory),
+            [
+                "sensorflow.log",
+                "sensorflow.log.20231114T221520.tmp",
+                "sensorflow.log.bak",
+            ]
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_rotated_names() {
+        assert!(is_rotated("20231114T221520"));
+        assert!(is_rotated("20231114T221520-2.gz"));
+        assert!(!is_rotated("20231114T221520-"));
+        assert!(!is_rotated("20231114T221520.tmp"));
+        assert!(!is_rotated("bak"));
+    }
+
+    #[test]
+    fn test_max_age() {
+        let directory = directory();
+        let file = RotatingFile::open(
+            directory.join("sensorflow.log"),
+            Rotation {
+                max_age: Some(3600),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let now = SystemTime::now();
+        assert!(!file.is_due(1, now));
+        assert!(file.is_due(1, now + Duration::from_secs(3600)));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compressed_rotation() {
+        let directory = directory();
+        let path = directory.join("measurements.ndjson");
+        let mut sink = FileSink::new(FileConfig {
+            path: path.clone(),
+            format: Format::LineProtocol,
+            rotation: Rotation {
+                max_size: Some(30),
+                compress: true,
+                ..Default::default()
+            },
+        });
+        sink.start().await.unwrap();
+        let measurement = Measurement::new("tempHum").add_field("temperature", 21.5);
+        sink.write(&measurement).await.unwrap();
+        sink.write(&measurement).await.unwrap();
+        sink.shutdown().await.unwrap();
+
+        let names = file_names(&directory);
+        assert_eq!(names.len(), 2);
+        assert!(names[1].ends_with(".gz"));
+        let mut rotated = String::new();
+        GzDecoder::new(std::fs::File::open(directory.join(&names[1])).unwrap())
+            .read_to_string(&mut rotated)
+            .unwrap();
+        assert_eq!(rotated, "tempHum temperature=21.5\n");
+        assert_eq!(std::fs::read_to_string(path).unwrap(), rotated);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+//! gRPC API for services consuming measurements.
+//!
+//! The sink runs a server of the `sensorflow.v1.Sensorflow` service defined in
+//! `proto/sensorflow.proto`. `Subscribe` streams measurements as they are written, optionally
+//! filtered by name and tags, `GetLatest` returns the latest measurement of every series, i.e.
+//! combination of name and tags. Subscribers which do not keep up skip measurements.
+//! With `announce`, the server is announced on the local network, see [crate::mdns]. Requires
+//! the `grpc` feature.
+//!
+//! The server has no authentication, anyone who can connect receives all measurements. It
+//! listens on localhost by default, set `listen`, e.g. to `0.0.0.0:50051`, only to expose it to
+//! a trusted network.
+use super::OutputSink;
+use crate::mdns::Announcement;
+use crate::measurement::{FieldValue, Measurement};
+use crate::transform::selects;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+include!(concat!(env!("OUT_DIR"), "/sensorflow.v1.Sensorflow.rs"));
+
+/// Messages of `proto/sensorflow.proto`
+pub mod proto {
+    use std::collections::BTreeMap;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StreamRequest {
+        #[prost(string, tag = "1")]
+        pub measurement: String,
+        #[prost(btree_map = "string, string", tag = "2")]
+        pub tags: BTreeMap<String, String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LatestResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub measurements: Vec<Measurement>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Measurement {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(btree_map = "string, string", tag = "2")]
+        pub tags: BTreeMap<String, String>,
+        #[prost(btree_map = "string, message", tag = "3")]
+        pub fields: BTreeMap<String, FieldValue>,
+        #[prost(int64, optional, tag = "4")]
+        pub time_unix_ms: Option<i64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FieldValue {
+        #[prost(oneof = "field_value::Value", tags = "1, 2, 3, 4, 5")]
+        pub value: Option<field_value::Value>,
+    }
+
+    pub mod field_value {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Value {
+            #[prost(double, tag = "1")]
+            Float(f64),
+            #[prost(int64, tag = "2")]
+            Integer(i64),
+            #[prost(uint64, tag = "3")]
+            Uinteger(u64),
+            #[prost(string, tag = "4")]
+            String(String),
+            #[prost(bool, tag = "5")]
+            Boolean(bool),
+        }
+    }
+}
+
+impl From<&Measurement> for proto::Measurement {
+    fn from(measurement: &Measurement) -> Self {
+        use proto::field_value::Value;
+        let fields = measurement
+            .fields
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    FieldValue::Float(x) => Value::Float(*x),
+                    FieldValue::Integer(x) => Value::Integer(*x),
+                    FieldValue::UInteger(x) => Value::Uinteger(*x),
+                    FieldValue::String(s) => Value::String(s.clone()),
+                    FieldValue::Boolean(b) => Value::Boolean(*b),
+                };
+                (key.clone(), proto::FieldValue { value: Some(value) })
+            })
+            .collect();
+        proto::Measurement {
+            name: measurement.name.clone(),
+            tags: measurement.tags.iter().cloned().collect(),
+            fields,
+            time_unix_ms: measurement.time.map(|time| time.timestamp_millis()),
+        }
+    }
+}
+
+impl proto::StreamRequest {
+    fn matches(&self, measurement: &Measurement) -> bool {
+        let name = Some(self.measurement.as_str()).filter(|name| !name.is_empty());
+        selects(name, &self.tags, measurement)
+    }
+}
+
+fn default_listen() -> String {
+    "127.0.0.1:50051".into()
+}
+
+fn default_queue() -> usize {
+    1024
+}
+
+/// Address of the server
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// Address of the server, `127.0.0.1:50051` by default
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    /// Number of measurements a subscriber may fall behind before skipping
+    #[serde(default = "default_queue")]
+    pub queue: usize,
+    /// Instance name announced via mDNS, see [crate::mdns]
+    pub announce: Option<String>,
+}
+
+/// Latest measurement by name and tags
+type Latest = Arc<Mutex<BTreeMap<(String, Vec<(String, String)>), Arc<Measurement>>>>;
+
+struct Service {
+    measurements: broadcast::Sender<Arc<Measurement>>,
+    latest: Latest,
+}
+
+#[async_trait]
+impl sensorflow_server::Sensorflow for Service {
+    type SubscribeStream =
+        Pin<Box<dyn Stream<Item = Result<proto::Measurement, Status>> + Send + 'static>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<proto::StreamRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let request = request.into_inner();
+        let stream =
+            BroadcastStream::new(self.measurements.subscribe()).filter_map(move |measurement| {
+                match measurement {
+                    Ok(measurement) if request.matches(&measurement) => {
+                        Some(Ok(measurement.as_ref().into()))
+                    }
+                    // Lagged behind or not selected
+                    _ => None,
+                }
+            });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn get_latest(
+        &self,
+        request: Request<proto::StreamRequest>,
+    ) -> Result<Response<proto::LatestResponse>, Status> {
+        let request = request.into_inner();
+        let measurements = self
+            .latest
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|measurement| request.matches(measurement))
+            .map(|measurement| measurement.as_ref().into())
+            .collect();
+        Ok(Response::new(proto::LatestResponse { measurements }))
+    }
+}
+
+/// Sink serving measurements over gRPC
+pub struct GrpcSink {
+    config: GrpcConfig,
+    measurements: broadcast::Sender<Arc<Measurement>>,
+    latest: Latest,
+    task: Option<JoinHandle<()>>,
+    announcement: Option<Announcement>,
+}
+
+impl GrpcSink {
+    pub fn new(config: GrpcConfig) -> GrpcSink {
+        let (measurements, _) = broadcast::channel(config.queue.max(1));
+        GrpcSink {
+            config,
+            measurements,
+            latest: Default::default(),
+            task: None,
+            announcement: None,
+        }
+    }
+}
+
+#[async_trait]
+impl OutputSink for GrpcSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        let listener = tokio::net::TcpListener::bind(&self.config.listen).await?;
+        if let Some(instance) = &self.config.announce {
+            let port = listener.local_addr()?.port();
+            self.announcement = Some(Announcement::new(instance, port, "grpc")?);
+        }
+        let service = Service {
+            measurements: self.measurements.clone(),
+            latest: self.latest.clone(),
+        };
+        self.task = Some(tokio::spawn(async move {
+            let _ = tonic::transport::Server::builder()
+                .add_service(sensorflow_server::SensorflowServer::new(service))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener))
+                .await;
+        }));
+        Ok(())
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let measurement = Arc::new(measurement.clone());
+        self.latest.lock().unwrap().insert(
+            (measurement.name.clone(), measurement.tags.clone()),
+            measurement.clone(),
+        );
+        // Fails only without subscribers
+        let _ = self.measurements.send(measurement);
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.announcement = None;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        proto::{self, field_value::Value},
+        sensorflow_client::SensorflowClient,
+        GrpcConfig, GrpcSink,
+    };
+    use crate::{output::OutputSink, Measurement};
+
+    #[tokio::test]
+    async fn test_subscribe_and_get_latest() {
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let mut sink = GrpcSink::new(GrpcConfig {
+            listen: format!("127.0.0.1:{}", port),
+            queue: 16,
+            announce: None,
+        });
+        sink.start().await.unwrap();
+        let mut client = loop {
+            match SensorflowClient::connect(format!("http://127.0.0.1:{}", port)).await {
+                Ok(client) => break client,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+
+        let request = proto::StreamRequest {
+            measurement: "tempHum".into(),
+            tags: [("sensorId".to_string(), "12".to_string())].into(),
+        };
+        let mut stream = client
+            .subscribe(request.clone())
+            .await
+            .unwrap()
+            .into_inner();
+        for (sensor, temperature) in [(7, 10.), (12, 20.5), (12, 21.)] {
+            sink.write(
+                &Measurement::new("tempHum")
+                    .add_tag("sensorId", sensor)
+                    .add_field("temperature", temperature),
+            )
+            .await
+            .unwrap();
+        }
+
+        let measurement = stream.message().await.unwrap().unwrap();
+        assert_eq!(measurement.tags["sensorId"], "12");
+        assert_eq!(
+            measurement.fields["temperature"].value,
+            Some(Value::Float(20.5))
+        );
+        assert_eq!(measurement.time_unix_ms, None);
+
+        let latest = client.get_latest(request).await.unwrap().into_inner();
+        assert_eq!(latest.measurements.len(), 1);
+        assert_eq!(
+            latest.measurements[0].fields["temperature"].value,
+            Some(Value::Float(21.))
+        );
+        sink.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+//! Structured log entries in the systemd journal.
+//!
+//! Each measurement is sent as one entry over the native protocol of journald. Its name, tags
+//! and fields become journal fields, with names converted to the upper snake case required by
+//! the journal, e.g. `sensorId` to `SENSOR_ID`. This allows filtering like
+//!
+//! ```sh
+//! journalctl -t sensorflow MEASUREMENT=tempHum SENSOR_ID=12 -o verbose
+//! ```
+//!
+//! The message of the entry is the human readable form of the measurement.
+use super::{error::SinkError, retry::RetryConfig, OutputSink};
+use crate::measurement::Measurement;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::path::PathBuf;
+use tokio::net::UnixDatagram;
+
+fn default_socket() -> PathBuf {
+    "/run/systemd/journal/socket".into()
+}
+
+fn default_identifier() -> String {
+    "sensorflow".into()
+}
+
+fn default_priority() -> u8 {
+    // Informational
+    6
+}
+
+/// Socket of journald and fields common to all entries
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct JournalConfig {
+    #[serde(default = "default_socket")]
+    pub socket: PathBuf,
+    /// Value of `SYSLOG_IDENTIFIER`, for `journalctl -t`
+    #[serde(default = "default_identifier")]
+    pub identifier: String,
+    /// Syslog priority from 0 (emergency) to 7 (debug)
+    #[serde(default = "default_priority")]
+    pub priority: u8,
+    /// Retries of failed writes, see [super::retry]
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        JournalConfig {
+            socket: default_socket(),
+            identifier: default_identifier(),
+            priority: default_priority(),
+            retry: RetryConfig::default(),
+        }
+    }
+}
+
+/// Journal field name of a tag or field: upper snake case of letters, digits and underscores,
+/// not starting with an underscore or digit
+pub fn field_name(name: &str) -> String {
+    let mut field = String::new();
+    let mut previous_lower = false;
+    for c in name.chars() {
+        if c.is_ascii_uppercase() && previous_lower {
+            field.push('_');
+        }
+        previous_lower = c.is_ascii_lowercase() || c.is_ascii_digit();
+        field.push(if c.is_ascii_alphanumeric() {
+            c.to_ascii_uppercase()
+        } else {
+            '_'
+        });
+    }
+    let field = field.trim_start_matches('_');
+    if field.is_empty() || field.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("F_{}", field)
+    } else {
+        field.to_string()
+    }
+}
+
+/// Append a field in the native protocol, values with newlines in the binary form
+fn append_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+impl JournalConfig {
+    /// Journal entry of a measurement in the native protocol
+    pub fn entry(&self, measurement: &Measurement) -> Vec<u8> {
+        let mut entry = Vec::new();
+        append_field(&mut entry, "MESSAGE", &measurement.to_string());
+        append_field(&mut entry, "PRIORITY", &self.priority.min(7).to_string());
+        append_field(&mut entry, "SYSLOG_IDENTIFIER", &self.identifier);
+        append_field(&mut entry, "MEASUREMENT", &measurement.name);
+        for (key, value) in &measurement.tags {
+            append_field(&mut entry, &field_name(key), value);
+        }
+        for (key, value) in &measurement.fields {
+            append_field(&mut entry, &field_name(key), &value.to_string());
+        }
+        entry
+    }
+}
+
+/// Sink logging measurements to the journal
+pub struct JournalSink {
+    config: JournalConfig,
+    socket: Option<UnixDatagram>,
+}
+
+impl JournalSink {
+    pub fn new(config: JournalConfig) -> JournalSink {
+        JournalSink {
+            config,
+            socket: None,
+        }
+    }
+}
+
+#[async_trait]
+impl OutputSink for JournalSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        self.socket = Some(UnixDatagram::unbound()?);
+        Ok(())
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let socket = match self.socket.as_mut() {
+            Some(socket) => socket,
+            None => self.socket.insert(UnixDatagram::unbound()?),
+        };
+        socket
+            .send_to(&self.config.entry(measurement), &self.config.socket)
+            .await
+            .map_err(|e| SinkError::Retryable(e.to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{field_name, JournalConfig, JournalSink};
+    use crate::{output::OutputSink, Measurement};
+
+    #[test]
+    fn test_field_name() {
+        assert_eq!(field_name("sensorId"), "SENSOR_ID");
+        assert_eq!(field_name("temperature"), "TEMPERATURE");
+        assert_eq!(field_name("battery_low"), "BATTERY_LOW");
+        assert_eq!(field_name("co2-ppm"), "CO2_PPM");
+        assert_eq!(field_name("_private"), "PRIVATE");
+        assert_eq!(field_name("1wire"), "F_1WIRE");
+    }
+
+    #[test]
+    fn test_entry() {
+        let config = JournalConfig::default();
+        let entry = config.entry(
+            &Measurement::new("tempHum")
+                .add_tag("sensorId", 12)
+                .add_field("note", "a\nb"),
+        );
+        let entry = String::from_utf8(entry).unwrap();
+        assert!(entry.contains("\nPRIORITY=6\nSYSLOG_IDENTIFIER=sensorflow\nMEASUREMENT=tempHum\n"));
+        assert!(entry.contains("\nSENSOR_ID=12\n"));
+        assert!(entry.ends_with("\nNOTE\n\x03\0\0\0\0\0\0\0a\nb\n"));
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let path = std::env::temp_dir().join(format!(
+            "sensorflow-journal-{:08x}.socket",
+            rand::random::<u32>()
+        ));
+        let journald = tokio::net::UnixDatagram::bind(&path).unwrap();
+        let mut sink = JournalSink::new(JournalConfig {
+            socket: path.clone(),
+            ..Default::default()
+        });
+        sink.start().await.unwrap();
+        sink.write(&Measurement::new("tempHum").add_field("temperature", 21.5))
+            .await
+            .unwrap();
+
+        let mut buffer = [0; 1024];
+        let len = journald.recv(&mut buffer).await.unwrap();
+        let entry = String::from_utf8_lossy(&buffer[..len]).to_string();
+        assert!(entry.ends_with("\nTEMPERATURE=21.5\n"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+//! JSON representation of measurements for sinks of cloud services.
+use crate::measurement::{FieldValue, Measurement};
+use chrono::DateTime;
+use serde_json::{json, Map, Value};
+
+/// JSON value of a field
+pub(crate) fn value(value: &FieldValue) -> Value {
+    match value {
+        FieldValue::Float(x) => Value::from(*x),
+        FieldValue::Integer(x) => Value::from(*x),
+        FieldValue::UInteger(x) => Value::from(*x),
+        FieldValue::String(s) => Value::from(s.as_str()),
+        FieldValue::Boolean(b) => Value::from(*b),
+    }
+}
+
+/// Object with the name, tags and fields of a measurement and the time in milliseconds since
+/// the epoch, `null` if unknown
+///
+/// ```json
+/// {"measurement":"tempHum","tags":{"sensorId":"12"},"fields":{"temperature":21.5},"timestamp":1700000000000}
+/// ```
+pub fn to_json(measurement: &Measurement) -> Value {
+    let tags: Map<String, Value> = measurement
+        .tags
+        .iter()
+        .map(|(key, tag)| (key.clone(), Value::from(tag.as_str())))
+        .collect();
+    let fields: Map<String, Value> = measurement
+        .fields
+        .iter()
+        .map(|(key, field)| (key.clone(), value(field)))
+        .collect();
+    json!({
+        "measurement": measurement.name,
+        "tags": tags,
+        "fields": fields,
+        "timestamp": measurement.time.map(|time| time.timestamp_millis()),
+    })
+}
+
+fn field(value: &Value) -> Option<FieldValue> {
+    Some(match value {
+        Value::Number(x) => match (x.as_u64(), x.as_i64(), x.as_f64()) {
+            (Some(x), _, _) => FieldValue::UInteger(x),
+            (_, Some(x), _) => FieldValue::Integer(x),
+            (_, _, x) => FieldValue::Float(x?),
+        },
+        Value::String(s) => FieldValue::String(s.clone()),
+        Value::Bool(b) => FieldValue::Boolean(*b),
+        _ => return None,
+    })
+}
+
+/// Measurement of an object of [to_json]. Integers are read as unsigned if not negative, as
+/// JSON does not tell them apart.
+pub fn from_json(object: &Value) -> anyhow::Result<Measurement> {
+    let name = object["measurement"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("No measurement name"))?;
+    let mut measurement = Measurement::new(name);
+    if let Some(tags) = object["tags"].as_object() {
+        for (key, tag) in tags {
+            let tag = tag
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("Tag {} is not a string", key))?;
+            measurement = measurement.add_tag(key, tag);
+        }
+    }
+    if let Some(fields) = object["fields"].as_object() {
+        for (key, value) in fields {
+            let value = field(value).ok_or_else(|| anyhow::anyhow!("Invalid field {}", key))?;
+            measurement = measurement.add_field(key, value);
+        }
+    }
+    let time = match &object["timestamp"] {
+        Value::Null => None,
+        time => Some(
+            time.as_i64()
+                .and_then(DateTime::from_timestamp_millis)
+                .ok_or_else(|| anyhow::anyhow!("Invalid timestamp {}", time))?,
+        ),
+    };
+    Ok(measurement.add_time(time))
+}
+
+/// Expand the placeholders of a template, `{measurement}` by the name of the measurement and
+/// `{<tag>}` by the value of the tag, `unknown` if the measurement does not carry the tag
+///
+/// Used to derive topics or keys per sensor, e.g. `sensors/{measurement}/{sensorId}`.
+pub fn expand_template(template: &str, measurement: &Measurement) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        expanded.push_str(&rest[..start]);
+        let name = &rest[start + 1..start + len];
+        if name == "measurement" {
+            expanded.push_str(&measurement.name);
+        } else {
+            expanded.push_str(measurement.tag(name).unwrap_or("unknown"));
+        }
+        rest = &rest[start + len + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod test {
+    use super::{expand_template, from_json, to_json};
+    use crate::Measurement;
+    use chrono::DateTime;
+    use serde_json::json;
+
+    #[test]
+    fn test_to_json() {
+        let measurement = Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.5)
+            .add_field("humidity", 45u64)
+            .add_field("lowBattery", false)
+            .add_time(DateTime::from_timestamp_millis(1700000000123));
+        assert_eq!(
+            to_json(&measurement),
+            json!({
+                "measurement": "tempHum",
+                "tags": {"sensorId": "12"},
+                "fields": {"temperature": 21.5, "humidity": 45, "lowBattery": false},
+                "timestamp": 1700000000123i64,
+            })
+        );
+        assert_eq!(to_json(&Measurement::new("m"))["timestamp"], json!(null));
+        let object = to_json(&measurement);
+        assert_eq!(to_json(&from_json(&object).unwrap()), object);
+        assert_eq!(
+            from_json(&json!({"measurement": "m", "fields": {"x": -1, "y": 2.0}})).unwrap(),
+            Measurement::new("m")
+                .add_field("x", -1i64)
+                .add_field("y", 2.)
+        );
+        assert!(from_json(&json!({"measurement": "m", "fields": {"x": null}})).is_err());
+        assert!(from_json(&json!({"tags": {}})).is_err());
+    }
+
+    #[test]
+    fn test_expand_template() {
+        let measurement = Measurement::new("tempHum").add_tag("sensorId", 12);
+        assert_eq!(
+            expand_template("sensors/{measurement}/{sensorId}", &measurement),
+            "sensors/tempHum/12"
+        );
+        assert_eq!(
+            expand_template("{location}/{measurement", &measurement),
+            "unknown/{measurement"
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! Group writes to KNX installations over KNXnet/IP routing.
+//!
+//! Each configured group is fed by a field of matching measurements. The value is encoded in the
+//! datapoint type of the group, e.g. DPT 9.001 for temperatures in °C, and sent as group write
+//! in a routing indication to the multicast group of KNX IP routers, by default
+//! `224.0.23.12:3671`. Wireless sensors thereby appear to wired KNX installations like any other
+//! sensor on the bus. Interfaces supporting tunnelling only are not supported. Requires the
+//! `knx` feature.
+//!
+//! ```toml
+//! [[outputs]]
+//! type = "knx"
+//! source = "1.1.250"
+//!
+//! [[outputs.groups]]
+//! group = "3/1/10"
+//! measurement = "tempHum"
+//! tags = { sensorId = "12" }
+//! field = "temperature"
+//! dpt = "9.001"
+//! ```
+use super::{error::SinkError, retry::RetryConfig, OutputSink};
+use crate::measurement::{FieldValue, Measurement};
+use crate::transform::selects;
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+fn default_gateway() -> String {
+    "224.0.23.12:3671".into()
+}
+
+fn default_source() -> String {
+    "15.15.250".into()
+}
+
+/// Datapoint types values are encoded in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Dpt {
+    /// Boolean, e.g. a window contact
+    #[serde(rename = "1.001")]
+    Switch,
+    /// Temperature in °C as 2-byte float
+    #[default]
+    #[serde(rename = "9.001")]
+    Temperature,
+    /// Relative humidity in % as 2-byte float
+    #[serde(rename = "9.007")]
+    Humidity,
+}
+
+/// Group fed by a field of matching measurements
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct GroupMapping {
+    /// Group address in three-level (`3/1/10`) or two-level (`3/266`) notation
+    pub group: String,
+    pub measurement: String,
+    /// Only measurements carrying all of these tags are matched
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    pub field: String,
+    #[serde(default)]
+    pub dpt: Dpt,
+}
+
+impl GroupMapping {
+    fn matches(&self, measurement: &Measurement) -> bool {
+        selects(Some(self.measurement.as_str()), &self.tags, measurement)
+    }
+}
+
+/// Router and groups
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct KnxConfig {
+    /// Multicast group of the routers or address of a single router, with port
+    #[serde(default = "default_gateway")]
+    pub gateway: String,
+    /// Individual address telegrams are sent from, e.g. `1.1.250`
+    #[serde(default = "default_source")]
+    pub source: String,
+    pub groups: Vec<GroupMapping>,
+    /// Retries of failed writes, see [super::retry]
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+fn parts(address: &str, separator: char, limits: &[u16]) -> Option<u16> {
+    let parts: Vec<&str> = address.split(separator).collect();
+    if parts.len() != limits.len() {
+        return None;
+    }
+    let mut encoded = 0u16;
+    for (part, limit) in parts.iter().zip(limits) {
+        let part: u16 = part.trim().parse().ok()?;
+        if part > *limit {
+            return None;
+        }
+        encoded = encoded * (limit + 1) + part;
+    }
+    Some(encoded)
+}
+
+/// Group address of three-level or two-level notation
+pub fn group_address(address: &str) -> anyhow::Result<u16> {
+    parts(address, '/', &[31, 7, 255])
+        .or_else(|| parts(address, '/', &[31, 2047]))
+        .with_context(|| format!("Invalid group address {}", address))
+}
+
+/// Individual address, e.g. `1.1.250`
+pub fn individual_address(address: &str) -> anyhow::Result<u16> {
+    parts(address, '.', &[15, 15, 255])
+        .with_context(|| format!("Invalid individual address {}", address))
+}
+
+/// 2-byte float of DPT 9: `0.01 * mantissa * 2^exponent` with an 11 bit mantissa in two's
+/// complement. Values out of range are clamped.
+pub fn dpt9(value: f64) -> [u8; 2] {
+    let mut mantissa = (value * 100.).round();
+    let mut exponent = 0u16;
+    while !(-2048. ..=2047.).contains(&mantissa) && exponent < 15 {
+        mantissa = (mantissa / 2.).round();
+        exponent += 1;
+    }
+    let mantissa = mantissa.clamp(-2048., 2047.) as i16;
+    let sign = if mantissa < 0 { 0x8000 } else { 0 };
+    let encoded = sign | exponent << 11 | (mantissa as u16 & 0x07ff);
+    encoded.to_be_bytes()
+}
+
+impl Dpt {
+    /// Data of a group write with `value`, `None` if the type does not fit
+    fn encode(&self, value: &FieldValue) -> Option<Vec<u8>> {
+        let number = match value {
+            FieldValue::Float(x) if x.is_finite() => *x,
+            FieldValue::Integer(x) => *x as f64,
+            FieldValue::UInteger(x) => *x as f64,
+            FieldValue::Boolean(b) if *self == Dpt::Switch => return Some(vec![u8::from(*b)]),
+            _ => return None,
+        };
+        Some(match self {
+            Dpt::Switch => vec![u8::from(number != 0.)],
+            Dpt::Temperature | Dpt::Humidity => dpt9(number).to_vec(),
+        })
+    }
+}
+
+/// Routing indication with a group write of `data` to `group`. Data of a single byte is a
+/// value of at most 6 bits, sent within the APCI.
+pub fn routing_indication(source: u16, group: u16, data: &[u8]) -> Vec<u8> {
+    let mut cemi = vec![
+        0x29, // L_Data.ind
+        0x00, // No additional information
+        0xbc, // Standard frame, no repetition, low priority
+        0xe0, // Group address, hop count 6
+    ];
+    cemi.extend(source.to_be_bytes());
+    cemi.extend(group.to_be_bytes());
+    match data {
+        [small] => cemi.extend([0x01, 0x00, 0x80 | (small & 0x3f)]),
+        _ => {
+            cemi.push(data.len() as u8 + 1);
+            cemi.extend([0x00, 0x80]);
+            cemi.extend(data);
+        }
+    }
+    let mut frame = vec![0x06, 0x10, 0x05, 0x30];
+    frame.extend((6 + cemi.len() as u16).to_be_bytes());
+    frame.extend(cemi);
+    frame
+}
+
+/// Sink writing fields to KNX groups
+pub struct KnxSink {
+    config: KnxConfig,
+    source: u16,
+    /// Encoded group addresses, in the order of the mappings
+    groups: Vec<u16>,
+    socket: Option<(UdpSocket, SocketAddr)>,
+}
+
+impl KnxSink {
+    pub fn new(config: KnxConfig) -> anyhow::Result<KnxSink> {
+        let source = individual_address(&config.source)?;
+        let groups = config
+            .groups
+            .iter()
+            .map(|mapping| group_address(&mapping.group))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(KnxSink {
+            config,
+            source,
+            groups,
+            socket: None,
+        })
+    }
+
+    async fn connect(&self) -> anyhow::Result<(UdpSocket, SocketAddr)> {
+        let gateway = tokio::net::lookup_host(&self.config.gateway)
+            .await
+            .map_err(|e| SinkError::Retryable(e.to_string()))?
+            .next()
+            .with_context(|| format!("No address for {}", self.config.gateway))?;
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        Ok((socket, gateway))
+    }
+}
+
+#[async_trait]
+impl OutputSink for KnxSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        self.socket = Some(self.connect().await?);
+        Ok(())
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let mut frames = Vec::new();
+        for (mapping, group) in self.config.groups.iter().zip(&self.groups) {
+            if !mapping.matches(measurement) {
+                continue;
+            }
+            if let Some(data) = measurement
+                .field(&mapping.field)
+                .and_then(|value| mapping.dpt.encode(value))
+            {
+                frames.push(routing_indication(self.source, *group, &data));
+            }
+        }
+        if frames.is_empty() {
+            return Ok(());
+        }
+        let (socket, gateway) = match self.socket.as_ref() {
+            Some(socket) => socket,
+            None => self.socket.insert(self.connect().await?),
+        };
+        for frame in frames {
+            if let Err(e) = socket.send_to(&frame, *gateway).await {
+                self.socket = None;
+                return Err(SinkError::Retryable(e.to_string()).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dpt9, group_address, individual_address, routing_indication, KnxConfig, KnxSink};
+    use crate::{output::OutputSink, Measurement};
+
+    #[test]
+    fn test_dpt9() {
+        assert_eq!(dpt9(0.), [0x00, 0x00]);
+        assert_eq!(dpt9(20.48), [0x0c, 0x00]);
+        assert_eq!(dpt9(21.5), [0x0c, 0x33]);
+        assert_eq!(dpt9(-30.), [0x8a, 0x24]);
+        assert_eq!(dpt9(670760.96), [0x7f, 0xff]);
+    }
+
+    #[test]
+    fn test_addresses() {
+        assert_eq!(group_address("3/1/10").unwrap(), 0x190a);
+        assert_eq!(group_address("3/266").unwrap(), 0x190a);
+        assert!(group_address("3/8/10").is_err());
+        assert_eq!(individual_address("1.1.250").unwrap(), 0x11fa);
+        assert_eq!(
+            individual_address("1/1/250").unwrap_err().to_string(),
+            "Invalid individual address 1/1/250"
+        );
+    }
+
+    #[test]
+    fn test_routing_indication() {
+        assert_eq!(
+            routing_indication(0x11fa, 0x190a, &[0x0c, 0x33]),
+            [
+                0x06, 0x10, 0x05, 0x30, 0x00, 0x13, 0x29, 0x00, 0xbc, 0xe0, 0x11, 0xfa, 0x19, 0x0a,
+                0x03, 0x00, 0x80, 0x0c, 0x33
+            ]
+        );
+        assert_eq!(
+            routing_indication(0x11fa, 0x0001, &[1])[14..],
+            [0x01, 0x00, 0x81]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_group_write() {
+        let router = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config: KnxConfig = toml::from_str(&format!(
+            r#"
+            gateway = "{}"
+            source = "1.1.250"
+            [[groups]]
+            group = "3/1/10"
+            measurement = "tempHum"
+            tags = {{ sensorId = "12" }}
+            field = "temperature"
+            [[groups]]
+            group = "3/1/11"
+            measurement = "tempHum"
+            field = "humidity"
+            dpt = "9.007"
+            "#,
+            router.local_addr().unwrap()
+        ))
+        .unwrap();
+        let mut sink = KnxSink::new(config).unwrap();
+        sink.start().await.unwrap();
+        sink.write(
+            &Measurement::new("tempHum")
+                .add_tag("sensorId", 13)
+                .add_field("temperature", 21.5)
+                .add_field("humidity", 45u64),
+        )
+        .await
+        .unwrap();
+
+        // Only the humidity group matches
+        let mut buffer = [0; 64];
+        let len = router.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[12..14], [0x19, 0x0b]);
+        assert_eq!(&buffer[17..len], dpt9(45.));
+    }
+}
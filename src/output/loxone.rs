@@ -0,0 +1,303 @@
+//! Values of Loxone Miniserver virtual inputs.
+//!
+//! Each virtual input is fed by a field of matching measurements. With `url`, the value is set
+//! through the web service of the Miniserver (`/dev/sps/io/{name}/{value}`), which needs a user
+//! allowed to operate the input. With `udp`, a datagram `{name}={value}` is sent to a virtual
+//! UDP input, whose commands recognise the value with `{name}=\v`. Booleans are sent as `1` or
+//! `0`.
+//!
+//! ```toml
+//! [[outputs]]
+//! type = "loxone"
+//! url = "http://miniserver"
+//! username = "sensorflow"
+//! password = "${LOXONE_PASSWORD}"
+//!
+//! [[outputs.virtual_inputs]]
+//! name = "CellarTemperature"
+//! measurement = "tempHum"
+//! tags = { sensorId = "12" }
+//! field = "temperature"
+//! ```
+use super::{error::SinkError, retry::RetryConfig, OutputSink};
+use crate::{
+    measurement::{FieldValue, Measurement},
+    tls::TlsConfig,
+    transform::selects,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// Virtual input fed by a field of matching measurements
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct VirtualInput {
+    /// Name of the virtual input in Loxone Config
+    pub name: String,
+    pub measurement: String,
+    /// Only measurements carrying all of these tags are matched
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    pub field: String,
+}
+
+impl VirtualInput {
+    fn matches(&self, measurement: &Measurement) -> bool {
+        selects(Some(self.measurement.as_str()), &self.tags, measurement)
+    }
+}
+
+/// Miniserver and its virtual inputs
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct LoxoneConfig {
+    /// Base URL of the Miniserver, e.g. `http://miniserver`
+    pub url: Option<String>,
+    /// Host and port of a virtual UDP input, e.g. `miniserver:7000`
+    pub udp: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub virtual_inputs: Vec<VirtualInput>,
+    /// TLS settings of `https` URLs, see [crate::tls]
+    pub tls: Option<TlsConfig>,
+    /// Retries of failed writes, see [super::retry]
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+/// Value as understood by the Miniserver, `None` for non-finite floats
+fn value(field: &FieldValue) -> Option<String> {
+    Some(match field {
+        FieldValue::Float(x) if !x.is_finite() => return None,
+        FieldValue::Boolean(b) => u8::from(*b).to_string(),
+        field => field.to_string(),
+    })
+}
+
+enum Transport {
+    Http {
+        url: reqwest::Url,
+        client: reqwest::Client,
+    },
+    Udp {
+        address: String,
+        socket: Option<UdpSocket>,
+    },
+}
+
+/// Sink setting virtual inputs of a Loxone Miniserver
+pub struct LoxoneSink {
+    config: LoxoneConfig,
+    transport: Transport,
+}
+
+impl LoxoneSink {
+    pub fn new(config: LoxoneConfig) -> anyhow::Result<LoxoneSink> {
+        let transport = match (&config.url, &config.udp) {
+            (Some(url), None) => {
+                let url =
+                    reqwest::Url::parse(url).with_context(|| format!("Invalid URL {}", url))?;
+                let mut client = reqwest::Client::builder().timeout(Duration::from_secs(10));
+                if let Some(tls) = &config.tls {
+                    client = tls.http_client(client)?;
+                }
+                Transport::Http {
+                    url,
+                    client: client.build()?,
+                }
+            }
+            (None, Some(address)) => Transport::Udp {
+                address: address.clone(),
+                socket: None,
+            },
+            _ => anyhow::bail!("Loxone outputs need either a url or a udp address"),
+        };
+        Ok(LoxoneSink { config, transport })
+    }
+
+    /// Name and value of all virtual inputs fed by `measurement`
+    pub fn values(&self, measurement: &Measurement) -> Vec<(String, String)> {
+        self.config
+            .virtual_inputs
+            .iter()
+            .filter(|input| input.matches(measurement))
+            .filter_map(|input| {
+                let value = value(measurement.field(&input.field)?)?;
+                Some((input.name.clone(), value))
+            })
+            .collect()
+    }
+
+    async fn set(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        match &mut self.transport {
+            Transport::Http { url, client } => {
+                let mut url = url.clone();
+                url.path_segments_mut()
+                    .map_err(|_| anyhow::anyhow!("URL of the Miniserver cannot be a base"))?
+                    .pop_if_empty()
+                    .extend(["dev", "sps", "io", name, value]);
+                let mut request = client.get(url);
+                if let Some(username) = &self.config.username {
+                    request = request.basic_auth(username, self.config.password.as_ref());
+                }
+                let response = request
+                    .send()
+                    .await
+                    .map_err(|e| SinkError::Retryable(e.to_string()))?;
+                let status = response.status();
+                if status.is_server_error() {
+                    return Err(SinkError::Retryable(status.to_string()).into());
+                }
+                if !status.is_success() {
+                    return Err(SinkError::Fatal(format!(
+                        "Update of virtual input {} failed: {}",
+                        name, status
+                    ))
+                    .into());
+                }
+                Ok(())
+            }
+            Transport::Udp {
+                address,
+                socket: slot,
+            } => {
+                let socket = match slot.as_mut() {
+                    Some(socket) => socket,
+                    None => {
+                        let connected = UdpSocket::bind("0.0.0.0:0").await?;
+                        connected
+                            .connect(address.as_str())
+                            .await
+                            .map_err(|e| SinkError::Retryable(e.to_string()))?;
+                        slot.insert(connected)
+                    }
+                };
+                let datagram = format!("{}={}", name, value);
+                if let Err(e) = socket.send(datagram.as_bytes()).await {
+                    // Resolve the address again on the next write
+                    *slot = None;
+                    return Err(SinkError::Retryable(e.to_string()).into());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl OutputSink for LoxoneSink {
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        for (name, value) in self.values(measurement) {
+            self.set(&name, &value).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LoxoneConfig, LoxoneSink};
+    use crate::{output::OutputSink, Measurement};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn config(transport: &str) -> LoxoneConfig {
+        toml::from_str(&format!(
+            r#"
+            {}
+            [[virtual_inputs]]
+            name = "Cellar Temperature"
+            measurement = "tempHum"
+            tags = {{ sensorId = "12" }}
+            field = "temperature"
+            [[virtual_inputs]]
+            name = "CellarBatteryLow"
+            measurement = "tempHum"
+            tags = {{ sensorId = "12" }}
+            field = "battery_low"
+            "#,
+            transport
+        ))
+        .unwrap()
+    }
+
+    fn measurement(sensor_id: u32) -> Measurement {
+        Measurement::new("tempHum")
+            .add_tag("sensorId", sensor_id)
+            .add_field("temperature", 12.5)
+            .add_field("battery_low", true)
+    }
+
+    #[test]
+    fn test_values() {
+        let sink = LoxoneSink::new(config("url = \"http://miniserver\"")).unwrap();
+        assert_eq!(
+            sink.values(&measurement(12)),
+            [
+                ("Cellar Temperature".into(), "12.5".into()),
+                ("CellarBatteryLow".into(), "1".into())
+            ]
+        );
+        assert!(sink.values(&measurement(13)).is_empty());
+        assert_eq!(
+            LoxoneSink::new(config("")).err().unwrap().to_string(),
+            "Loxone outputs need either a url or a udp address"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("url = \"http://{}/\"", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let len = socket.read(&mut request).await.unwrap();
+                let body = r#"<LL control="dev/sps/io" value="1" Code="200"/>"#;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                requests.push(
+                    String::from_utf8_lossy(&request[..len])
+                        .lines()
+                        .next()
+                        .unwrap()
+                        .to_string(),
+                );
+            }
+            requests
+        });
+
+        let mut sink = LoxoneSink::new(config(&url)).unwrap();
+        sink.write(&measurement(12)).await.unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                "GET /dev/sps/io/Cellar%20Temperature/12.5 HTTP/1.1",
+                "GET /dev/sps/io/CellarBatteryLow/1 HTTP/1.1"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_udp() {
+        let miniserver = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp = format!("udp = \"{}\"", miniserver.local_addr().unwrap());
+        let mut sink = LoxoneSink::new(config(&udp)).unwrap();
+        sink.write(&measurement(12)).await.unwrap();
+
+        let mut buffer = [0; 256];
+        let len = miniserver.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..len], b"Cellar Temperature=12.5");
+    }
+}
@@ -0,0 +1,466 @@
+//! Base for sinks publishing measurements to an MQTT broker.
+//!
+//! Each measurement is published as JSON (see [super::json::to_json]) to a topic derived from a
+//! template. The connection is maintained by a background task which reconnects after failures.
+//! While the broker is unreachable, up to `buffer` messages are kept, after that writes wait,
+//! so the input queues of the pipeline and their overflow policy take over. Credentials with a
+//! limited lifetime, e.g. signed tokens, are renewed before every reconnect.
+//!
+//! As output of a pipeline file, the [TopicLayout] selects topics and payloads as expected by
+//! consumers like Node-RED, ioBroker or Homie controllers:
+//!
+//! ```toml
+//! [[outputs]]
+//! type = "mqtt"
+//! broker = "mosquitto.home"
+//! layout = "flat"
+//! topic = "home/{room}/{measurement}"
+//! retain = true
+//! ```
+//!
+//! With the `homie` layout, the device announces itself, its nodes and properties and keeps its
+//! `$state`, which the broker sets to `lost` if the connection breaks, see [HomieDevice].
+use super::{
+    error::SinkError,
+    json::{expand_template, to_json},
+    OutputSink,
+};
+use crate::{devices::mqtt::MqttConfig, measurement::Measurement, tls::TlsConfig};
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+pub mod layout;
+
+pub use layout::{HomieDevice, TopicLayout};
+use layout::{HomieState, Message};
+
+/// Wait between connection attempts
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Time granted to deliver buffered messages on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of messages kept while disconnected if not configured otherwise
+pub const DEFAULT_BUFFER: usize = 1000;
+
+/// Quality of service from its numeric level, at least once for invalid levels
+pub fn qos(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+/// Source of username and password, called for every connection
+pub type Credentials = Arc<dyn Fn() -> (String, String) + Send + Sync>;
+
+/// Sink publishing one message per measurement
+pub struct MqttSink {
+    options: MqttOptions,
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    buffer: usize,
+    layout: TopicLayout,
+    /// Payload of a measurement in the `json` layout, JSON by default
+    encode: fn(&Measurement) -> Vec<u8>,
+    credentials: Option<Credentials>,
+    /// Announced nodes and properties in the `homie` layout
+    homie: Option<HomieDevice>,
+    client: Option<AsyncClient>,
+    task: Option<JoinHandle<()>>,
+}
+
+fn encode_json(measurement: &Measurement) -> Vec<u8> {
+    to_json(measurement).to_string().into_bytes()
+}
+
+impl MqttSink {
+    /// Publish to the topic given by the template `topic`, see [expand_template]
+    pub fn new(options: MqttOptions, topic: impl Into<String>, qos: QoS) -> MqttSink {
+        MqttSink {
+            options,
+            topic: topic.into(),
+            qos,
+            retain: false,
+            buffer: DEFAULT_BUFFER,
+            layout: TopicLayout::Json,
+            encode: encode_json,
+            credentials: None,
+            homie: None,
+            client: None,
+            task: None,
+        }
+    }
+
+    /// Number of messages kept while the broker is unreachable
+    pub fn with_buffer(mut self, buffer: usize) -> MqttSink {
+        self.buffer = buffer.max(1);
+        self
+    }
+
+    /// Publish with the retain flag, so subscribers get the latest message at once
+    pub fn with_retain(mut self, retain: bool) -> MqttSink {
+        self.retain = retain;
+        self
+    }
+
+    /// Publish in another layout than a JSON object per measurement
+    pub fn with_layout(mut self, layout: TopicLayout) -> MqttSink {
+        self.layout = layout;
+        self.homie = match layout {
+            TopicLayout::Homie => Some(HomieDevice::new(&self.topic)),
+            _ => None,
+        };
+        self
+    }
+
+    /// Replace the JSON payload
+    pub fn with_encoder(mut self, encode: fn(&Measurement) -> Vec<u8>) -> MqttSink {
+        self.encode = encode;
+        self
+    }
+
+    /// Renew username and password before every connection attempt
+    pub fn with_credentials(mut self, credentials: Credentials) -> MqttSink {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    fn connect(&mut self) -> AsyncClient {
+        let mut options = self.options.clone();
+        if let Some(homie) = &self.homie {
+            let (topic, payload) = homie.state(HomieState::Lost);
+            options.set_last_will(LastWill::new(topic, payload, QoS::AtLeastOnce, true));
+        }
+        if let Some(credentials) = &self.credentials {
+            let (username, password) = credentials();
+            options.set_credentials(username, password);
+        }
+        let credentials = self.credentials.clone();
+        let (client, mut eventloop) = AsyncClient::new(options, self.buffer);
+        // The broker published the last will when the previous connection broke
+        let ready = self
+            .homie
+            .as_ref()
+            .map(|homie| homie.state(HomieState::Ready));
+        let republish = client.clone();
+        self.task = Some(tokio::spawn(async move {
+            let mut connected = false;
+            let mut reconnect = false;
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        connected = true;
+                        if let Some((topic, payload)) = ready.as_ref().filter(|_| reconnect) {
+                            let _ = republish.try_publish(
+                                topic,
+                                QoS::AtLeastOnce,
+                                true,
+                                payload.clone(),
+                            );
+                        }
+                        reconnect = true;
+                    }
+                    Ok(_) => (),
+                    // Messages stay in the buffer until the connection is back
+                    Err(_) => {
+                        if let Some(credentials) = &credentials {
+                            let (username, password) = credentials();
+                            eventloop.mqtt_options.set_credentials(username, password);
+                        }
+                        // Reconnect at once if an established connection was closed, e.g.
+                        // because the credentials expired
+                        if !std::mem::take(&mut connected) {
+                            tokio::time::sleep(RECONNECT_DELAY).await;
+                        }
+                    }
+                }
+            }
+        }));
+        client
+    }
+
+    /// Publish a message, waits while the buffer is full
+    pub async fn publish(&mut self, topic: String, payload: Vec<u8>) -> anyhow::Result<()> {
+        self.send(topic, payload, self.retain).await
+    }
+
+    /// Publish retained attributes of the Homie device
+    async fn announce(&mut self, messages: Vec<Message>) -> anyhow::Result<()> {
+        for (topic, payload) in messages {
+            self.send(topic, payload, true).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_state(&mut self, state: HomieState) -> anyhow::Result<()> {
+        match self.homie.as_ref().map(|homie| homie.state(state)) {
+            Some(message) => self.announce(vec![message]).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn send(&mut self, topic: String, payload: Vec<u8>, retain: bool) -> anyhow::Result<()> {
+        let client = match &self.client {
+            Some(client) => client.clone(),
+            None => {
+                let client = self.connect();
+                self.client.insert(client).clone()
+            }
+        };
+        client
+            .publish(topic, self.qos, retain, payload)
+            .await
+            .map_err(|e| SinkError::Fatal(e.to_string()))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OutputSink for MqttSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        if self.client.is_none() {
+            self.client = Some(self.connect());
+        }
+        if let Some(homie) = &self.homie {
+            let attributes = homie.attributes();
+            self.set_state(HomieState::Init).await?;
+            self.announce(attributes).await?;
+            self.set_state(HomieState::Ready).await?;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        if let Some(homie) = self.homie.as_mut() {
+            let discovered = homie.discover(measurement);
+            if !discovered.is_empty() {
+                // Changes of the structure happen in the `init` state
+                self.set_state(HomieState::Init).await?;
+                self.announce(discovered).await?;
+                self.set_state(HomieState::Ready).await?;
+            }
+        }
+        let messages = match self.layout {
+            TopicLayout::Json => vec![(
+                expand_template(&self.topic, measurement),
+                (self.encode)(measurement),
+            )],
+            layout => layout.messages(&self.topic, measurement),
+        };
+        for (topic, payload) in messages {
+            self.publish(topic, payload).await?;
+        }
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        if self.client.is_some() {
+            self.set_state(HomieState::Disconnected).await?;
+        }
+        if let Some(client) = self.client.take() {
+            // Queued behind all buffered messages
+            client
+                .disconnect()
+                .await
+                .map_err(|e| SinkError::Fatal(e.to_string()))?;
+        }
+        if let Some(mut task) = self.task.take() {
+            if tokio::time::timeout(SHUTDOWN_TIMEOUT, &mut task)
+                .await
+                .is_err()
+            {
+                task.abort();
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_qos() -> u8 {
+    1
+}
+
+fn default_buffer() -> usize {
+    DEFAULT_BUFFER
+}
+
+/// Broker, layout and topic of the output
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct MqttOutputConfig {
+    /// Host of the broker, with optional port
+    pub broker: String,
+    /// Random if absent
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub layout: TopicLayout,
+    /// Topic template, see [expand_template]. Defaults to the one of the layout.
+    pub topic: Option<String>,
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+    /// Number of messages kept while the broker is unreachable
+    #[serde(default = "default_buffer")]
+    pub buffer: usize,
+}
+
+impl MqttOutputConfig {
+    pub fn sink(&self) -> anyhow::Result<MqttSink> {
+        let mut mqtt = MqttConfig::new(&self.broker);
+        mqtt.client_id = self.client_id.clone();
+        mqtt.username = self.username.clone();
+        mqtt.password = self.password.clone();
+        mqtt.tls = self.tls.clone();
+        let topic = match &self.topic {
+            Some(topic) => topic.as_str(),
+            None => self.layout.default_topic(),
+        };
+        // Homie expects retained values
+        let retain = self.retain || self.layout == TopicLayout::Homie;
+        Ok(MqttSink::new(mqtt.options()?, topic, qos(self.qos))
+            .with_layout(self.layout)
+            .with_retain(retain)
+            .with_buffer(self.buffer))
+    }
+}
+
+/// Read a single packet of a MQTT 3.1.1 stream, returns the first byte and the rest
+#[cfg(test)]
+pub(crate) async fn read_packet(socket: &mut tokio::net::TcpStream) -> (u8, Vec<u8>) {
+    use tokio::io::AsyncReadExt;
+    let header = socket.read_u8().await.unwrap();
+    let mut len = 0;
+    let mut shift = 0;
+    loop {
+        let byte = socket.read_u8().await.unwrap();
+        len |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0; len];
+    socket.read_exact(&mut body).await.unwrap();
+    (header, body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{qos, read_packet, MqttSink, TopicLayout};
+    use crate::{output::OutputSink, Measurement};
+    use rumqttc::MqttOptions;
+    use serde_json::{json, Value};
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_publish_json() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            assert_eq!(read_packet(&mut socket).await.0, 0x10);
+            socket.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+            let (header, body) = read_packet(&mut socket).await;
+            assert_eq!(header, 0x30);
+            let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+            let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+            let payload: Value = serde_json::from_slice(&body[2 + topic_len..]).unwrap();
+            // DISCONNECT
+            assert_eq!(read_packet(&mut socket).await.0, 0xe0);
+            (topic, payload)
+        });
+
+        let options = MqttOptions::new("test", "127.0.0.1", port);
+        let mut sink = MqttSink::new(options, "sensors/{measurement}/{sensorId}", qos(0));
+        sink.start().await.unwrap();
+        sink.write(
+            &Measurement::new("tempHum")
+                .add_tag("sensorId", 12)
+                .add_field("temperature", 21.5),
+        )
+        .await
+        .unwrap();
+        sink.shutdown().await.unwrap();
+
+        let (topic, payload) = broker.await.unwrap();
+        assert_eq!(topic, "sensors/tempHum/12");
+        assert_eq!(payload["fields"], json!({"temperature": 21.5}));
+    }
+
+    #[tokio::test]
+    async fn test_homie_lifecycle() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let (_, connect) = read_packet(&mut socket).await;
+            socket.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+            let mut messages = Vec::new();
+            loop {
+                let (header, body) = read_packet(&mut socket).await;
+                if header == 0xe0 {
+                    break;
+                }
+                // PUBLISH at QoS 0, retained
+                assert_eq!(header, 0x31);
+                let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+                let payload = String::from_utf8(body[2 + topic_len..].to_vec()).unwrap();
+                messages.push(format!("{} {}", topic, payload));
+            }
+            (connect, messages)
+        });
+
+        let options = MqttOptions::new("test", "127.0.0.1", port);
+        let mut sink = MqttSink::new(options, "homie/cellar", qos(0))
+            .with_layout(TopicLayout::Homie)
+            .with_retain(true);
+        sink.start().await.unwrap();
+        let measurement = Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.5);
+        sink.write(&measurement).await.unwrap();
+        sink.write(&measurement).await.unwrap();
+        sink.shutdown().await.unwrap();
+
+        let (connect, messages) = broker.await.unwrap();
+        // Last will as the end of the payload
+        let connect = String::from_utf8_lossy(&connect);
+        assert!(connect.contains("homie/cellar/$state") && connect.ends_with("lost"));
+        assert_eq!(
+            messages,
+            [
+                "homie/cellar/$state init",
+                "homie/cellar/$homie 4.0",
+                "homie/cellar/$name cellar",
+                "homie/cellar/$extensions ",
+                "homie/cellar/$nodes ",
+                "homie/cellar/$state ready",
+                "homie/cellar/$state init",
+                "homie/cellar/temphum-12/temperature/$name temperature",
+                "homie/cellar/temphum-12/temperature/$datatype float",
+                "homie/cellar/temphum-12/$name tempHum sensorId=12",
+                "homie/cellar/temphum-12/$type tempHum",
+                "homie/cellar/temphum-12/$properties temperature",
+                "homie/cellar/$nodes temphum-12",
+                "homie/cellar/$state ready",
+                "homie/cellar/temphum-12/temperature 21.5",
+                "homie/cellar/temphum-12/temperature 21.5",
+                "homie/cellar/$state disconnected",
+            ]
+        );
+    }
+}
@@ -0,0 +1,359 @@
+//! Topics and payloads expected by common consumers of MQTT messages.
+//!
+//! - `json`: a JSON object of [crate::output::json::to_json] per measurement, published to the
+//!   topic template, e.g. `sensorflow/{measurement}/{sensorId}`
+//! - `flat`: a message per field with the plain value, published to `<topic>/<field>`, as used
+//!   in Node-RED flows and by dashboards subscribing to single values
+//! - `iobroker`: like `flat`, with the state objects the MQTT adapter of ioBroker understands,
+//!   `{"val":21.5,"ack":true,"ts":1700000000000}`
+//! - `homie`: the Homie 4.0 convention, values are published to `<topic>/<node>/<property>`
+//!   with a node per sensor, e.g. `homie/sensorflow/temphum-12/temperature`. [HomieDevice]
+//!   announces the device, its nodes and their properties with datatype and unit, so
+//!   controllers like openHAB discover the sensors
+//!
+//! Names are cleaned of the MQTT wildcards `+` and `#` in topic levels of fields, and reduced to
+//! the lowercase letters, digits and hyphens allowed in Homie IDs.
+use crate::measurement::{FieldValue, Measurement};
+use crate::output::json::{expand_template, to_json, value as json_value};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+/// Topic and payload of a message
+pub type Message = (String, Vec<u8>);
+
+/// Layout of topics and payloads
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum TopicLayout {
+    #[default]
+    Json,
+    Flat,
+    Iobroker,
+    Homie,
+}
+
+/// Level of a topic, without wildcards and separators
+fn level(name: &str) -> String {
+    name.replace(['+', '#', '/'], "_")
+}
+
+/// ID of the Homie convention: lowercase letters, digits and hyphens
+pub fn homie_id(name: &str) -> String {
+    let id: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_lowercase(),
+            false => '-',
+        })
+        .collect();
+    id.split('-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Homie node of a measurement: its name followed by the values of its tags
+pub fn homie_node(measurement: &Measurement) -> String {
+    let mut node = measurement.name.clone();
+    for (_, value) in &measurement.tags {
+        node.push('-');
+        node.push_str(value);
+    }
+    homie_id(&node)
+}
+
+fn plain(value: &FieldValue) -> Vec<u8> {
+    value.to_string().into_bytes()
+}
+
+impl TopicLayout {
+    /// Topic template used if none is configured
+    pub fn default_topic(&self) -> &'static str {
+        match self {
+            TopicLayout::Json => "sensorflow/{measurement}",
+            TopicLayout::Flat | TopicLayout::Iobroker => "sensorflow/{measurement}/{sensorId}",
+            TopicLayout::Homie => "homie/sensorflow",
+        }
+    }
+
+    /// Messages of a measurement, `topic` is a template, see [expand_template]
+    pub fn messages(&self, topic: &str, measurement: &Measurement) -> Vec<Message> {
+        let topic = expand_template(topic, measurement);
+        match self {
+            TopicLayout::Json => vec![(topic, to_json(measurement).to_string().into_bytes())],
+            TopicLayout::Flat => measurement
+                .fields
+                .iter()
+                .map(|(field, value)| (format!("{}/{}", topic, level(field)), plain(value)))
+                .collect(),
+            TopicLayout::Iobroker => measurement
+                .fields
+                .iter()
+                .map(|(field, value)| {
+                    let mut state = json!({ "val": json_value(value), "ack": true });
+                    if let Some(time) = measurement.time {
+                        state["ts"] = time.timestamp_millis().into();
+                    }
+                    // Dots separate the levels of ioBroker object IDs
+                    let id = level(field).replace('.', "_");
+                    (format!("{}/{}", topic, id), state.to_string().into_bytes())
+                })
+                .collect(),
+            TopicLayout::Homie => {
+                let node = homie_node(measurement);
+                measurement
+                    .fields
+                    .iter()
+                    .map(|(field, value)| {
+                        let property = homie_id(field);
+                        (format!("{}/{}/{}", topic, node, property), plain(value))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Version of the Homie convention
+pub const HOMIE_VERSION: &str = "4.0";
+
+/// Lifecycle of a Homie device, published to `$state`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HomieState {
+    /// Attributes are being published
+    Init,
+    Ready,
+    /// Shut down cleanly
+    Disconnected,
+    /// Connection lost, published by the broker as last will
+    Lost,
+}
+
+impl HomieState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HomieState::Init => "init",
+            HomieState::Ready => "ready",
+            HomieState::Disconnected => "disconnected",
+            HomieState::Lost => "lost",
+        }
+    }
+}
+
+/// Datatype of a Homie property
+fn homie_datatype(value: &FieldValue) -> &'static str {
+    match value {
+        FieldValue::Float(_) => "float",
+        FieldValue::Integer(_) | FieldValue::UInteger(_) => "integer",
+        FieldValue::Boolean(_) => "boolean",
+        FieldValue::String(_) => "string",
+    }
+}
+
+/// Homie device with the nodes and properties announced so far.
+///
+/// Nodes and properties are discovered from the measurements written. All attributes are
+/// published retained, the values of the properties are retained, too.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HomieDevice {
+    /// Topic of the device, e.g. `homie/sensorflow`
+    topic: String,
+    /// Properties by node
+    nodes: BTreeMap<String, Vec<String>>,
+}
+
+impl HomieDevice {
+    pub fn new(topic: impl Into<String>) -> HomieDevice {
+        HomieDevice {
+            topic: topic.into(),
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    fn attribute(&self, path: &str, value: impl Into<String>) -> Message {
+        (
+            format!("{}/{}", self.topic, path),
+            value.into().into_bytes(),
+        )
+    }
+
+    /// Topic of `$state`, also used as last will
+    pub fn state_topic(&self) -> String {
+        format!("{}/$state", self.topic)
+    }
+
+    pub fn state(&self, state: HomieState) -> Message {
+        self.attribute("$state", state.as_str())
+    }
+
+    fn nodes(&self) -> Message {
+        let nodes: Vec<&str> = self.nodes.keys().map(String::as_str).collect();
+        self.attribute("$nodes", nodes.join(","))
+    }
+
+    /// Attributes of the device, named after the last level of its topic
+    pub fn attributes(&self) -> Vec<Message> {
+        let name = self.topic.rsplit('/').next().unwrap_or_default();
+        vec![
+            self.attribute("$homie", HOMIE_VERSION),
+            self.attribute("$name", name),
+            self.attribute("$extensions", ""),
+            self.nodes(),
+        ]
+    }
+
+    /// Attributes of the nodes and properties of `measurement` not announced before, empty if
+    /// there are none
+    pub fn discover(&mut self, measurement: &Measurement) -> Vec<Message> {
+        let node = homie_node(measurement);
+        let known = self.nodes.get(&node).cloned();
+        let mut properties = known.clone().unwrap_or_default();
+        let mut messages = Vec::new();
+        for (field, value) in &measurement.fields {
+            let property = homie_id(field);
+            if properties.contains(&property) {
+                continue;
+            }
+            let path = format!("{}/{}", node, property);
+            messages.push(self.attribute(&format!("{}/$name", path), field.as_str()));
+            messages.push(self.attribute(&format!("{}/$datatype", path), homie_datatype(value)));
+            if let Some(unit) = measurement.field_meta(field).unit {
+                messages.push(self.attribute(&format!("{}/$unit", path), unit.symbol()));
+            }
+            properties.push(property);
+        }
+        if messages.is_empty() {
+            return messages;
+        }
+        let mut name = measurement.name.clone();
+        for (key, value) in &measurement.tags {
+            name.push_str(&format!(" {}={}", key, value));
+        }
+        messages.push(self.attribute(&format!("{}/$name", node), name));
+        messages.push(self.attribute(&format!("{}/$type", node), measurement.name.as_str()));
+        messages.push(self.attribute(&format!("{}/$properties", node), properties.join(",")));
+        self.nodes.insert(node, properties);
+        if known.is_none() {
+            messages.push(self.nodes());
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{homie_id, HomieDevice, HomieState, TopicLayout};
+    use crate::Measurement;
+    use chrono::DateTime;
+
+    fn measurement() -> Measurement {
+        Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.5)
+            .add_field("battery_low", false)
+            .add_time(DateTime::from_timestamp(1700000000, 0))
+    }
+
+    fn text(messages: Vec<(String, Vec<u8>)>) -> Vec<(String, String)> {
+        messages
+            .into_iter()
+            .map(|(topic, payload)| (topic, String::from_utf8(payload).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_layouts() {
+        let topic = TopicLayout::Flat.default_topic();
+        assert_eq!(
+            text(TopicLayout::Flat.messages(topic, &measurement())),
+            [
+                ("sensorflow/tempHum/12/temperature".into(), "21.5".into()),
+                ("sensorflow/tempHum/12/battery_low".into(), "false".into())
+            ]
+        );
+        assert_eq!(
+            text(TopicLayout::Iobroker.messages(topic, &measurement()))[0],
+            (
+                "sensorflow/tempHum/12/temperature".into(),
+                r#"{"ack":true,"ts":1700000000000,"val":21.5}"#.into()
+            )
+        );
+        let topic = TopicLayout::Homie.default_topic();
+        assert_eq!(
+            text(TopicLayout::Homie.messages(topic, &measurement()))[1],
+            (
+                "homie/sensorflow/temphum-12/battery-low".into(),
+                "false".into()
+            )
+        );
+        let json = TopicLayout::Json.messages("sensors/{sensorId}", &measurement());
+        assert_eq!(json.len(), 1);
+        assert_eq!(json[0].0, "sensors/12");
+    }
+
+    #[test]
+    fn test_homie_device() {
+        let mut device = HomieDevice::new("homie/sensorflow");
+        assert_eq!(
+            text(device.attributes()),
+            [
+                ("homie/sensorflow/$homie".into(), "4.0".into()),
+                ("homie/sensorflow/$name".into(), "sensorflow".into()),
+                ("homie/sensorflow/$extensions".into(), "".into()),
+                ("homie/sensorflow/$nodes".into(), "".into()),
+            ]
+        );
+        let measurement =
+            measurement().add_field_meta("temperature", crate::measurement::Unit::Celsius);
+        let discovered = text(device.discover(&measurement));
+        assert_eq!(
+            discovered[..3],
+            [
+                (
+                    "homie/sensorflow/temphum-12/temperature/$name".into(),
+                    "temperature".into()
+                ),
+                (
+                    "homie/sensorflow/temphum-12/temperature/$datatype".into(),
+                    "float".into()
+                ),
+                (
+                    "homie/sensorflow/temphum-12/temperature/$unit".into(),
+                    "°C".into()
+                ),
+            ]
+        );
+        assert_eq!(
+            discovered[discovered.len() - 2..],
+            [
+                (
+                    "homie/sensorflow/temphum-12/$properties".into(),
+                    "temperature,battery-low".into()
+                ),
+                ("homie/sensorflow/$nodes".into(), "temphum-12".into()),
+            ]
+        );
+        assert!(device.discover(&measurement).is_empty());
+        // A new property updates the properties of the node only
+        let discovered = text(device.discover(&measurement.add_field("humidity", 45u64)));
+        assert_eq!(
+            discovered.last().unwrap(),
+            &(
+                "homie/sensorflow/temphum-12/$properties".into(),
+                "temperature,battery-low,humidity".into()
+            )
+        );
+        assert_eq!(
+            device.state(HomieState::Lost),
+            ("homie/sensorflow/$state".into(), b"lost".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_homie_id() {
+        assert_eq!(homie_id("Living Room/CO₂"), "living-room-co");
+        assert_eq!(homie_id("_battery_low_"), "battery-low");
+    }
+}
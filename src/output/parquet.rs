@@ -0,0 +1,348 @@
+//! Archival of measurements in Parquet files.
+//!
+//! Measurements are buffered and written into one file per measurement and period, in Hive
+//! style partitions `<directory>/date=2023-11-14/measurement=tempHum/` (with an additional
+//! `hour=22` level for hourly periods), which DataFusion, Pandas and DuckDB read as partition
+//! columns. The columns of a file are those of [super::arrow::record_batch].
+//!
+//! Files of a period are written once the period is over, when `max_rows` measurements are
+//! buffered, and on shutdown. Unlike other sinks, a flush of the pipeline does not write the
+//! current period, to avoid many small files, so buffered measurements are lost if the process
+//! is killed. With a `flush` schedule, see [super::batch], all buffered measurements are
+//! written on that schedule instead, at the cost of more files per period. Requires the
+//! `parquet` feature.
+//!
+//! ```toml
+//! [[outputs]]
+//! type = "parquet"
+//! directory = "/var/lib/sensorflow/archive"
+//! period = "daily"
+//! flush = { size = 10000, interval = 3600 }
+//! ```
+use super::{arrow::record_batch, batch::FlushSchedule, OutputSink};
+use crate::measurement::Measurement;
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+fn default_max_rows() -> usize {
+    100_000
+}
+
+/// Time span covered by a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Hourly,
+    #[default]
+    Daily,
+}
+
+impl Period {
+    fn length(&self) -> TimeDelta {
+        match self {
+            Period::Hourly => TimeDelta::hours(1),
+            Period::Daily => TimeDelta::days(1),
+        }
+    }
+
+    /// Start of the period containing `time`
+    pub fn start(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        time.duration_trunc(self.length()).unwrap_or(time)
+    }
+
+    /// Partition directory of a measurement, relative to the output directory
+    pub fn partition(&self, start: DateTime<Utc>, measurement: &str) -> PathBuf {
+        let mut path = PathBuf::from(start.format("date=%Y-%m-%d").to_string());
+        if *self == Period::Hourly {
+            path.push(start.format("hour=%H").to_string());
+        }
+        path.push(format!(
+            "measurement={}",
+            measurement.replace(['/', '\\'], "_")
+        ));
+        path
+    }
+}
+
+/// Output directory and partitioning
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct ParquetConfig {
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub period: Period,
+    /// Number of buffered measurements of a partition after which a file is written
+    #[serde(default = "default_max_rows")]
+    pub max_rows: usize,
+    /// Write all buffered measurements on this schedule, not only those of ended periods
+    #[serde(default)]
+    pub flush: Option<FlushSchedule>,
+}
+
+/// Write measurements into a new file in `directory`, named after the time of the first one
+fn write_file(directory: &Path, measurements: &[Measurement]) -> anyhow::Result<PathBuf> {
+    let batch = record_batch(measurements)?;
+    std::fs::create_dir_all(directory)
+        .with_context(|| format!("Failed to create {}", directory.display()))?;
+    let stem = measurements
+        .first()
+        .and_then(|m| m.time)
+        .unwrap_or_default()
+        .format("part-%Y%m%dT%H%M%S")
+        .to_string();
+    let mut path = directory.join(format!("{}.parquet", stem));
+    let mut counter = 1;
+    while path.exists() {
+        path = directory.join(format!("{}-{}.parquet", stem, counter));
+        counter += 1;
+    }
+    let file = std::fs::File::create(&path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let written =
+        ArrowWriter::try_new(file, batch.schema(), Some(properties)).and_then(|mut writer| {
+            writer.write(&batch)?;
+            writer.close()
+        });
+    if let Err(e) = written {
+        // Not to leave a truncated file behind
+        let _ = std::fs::remove_file(&path);
+        return Err(e).with_context(|| format!("Failed to write {}", path.display()));
+    }
+    Ok(path)
+}
+
+/// Sink writing Parquet files per measurement and period
+pub struct ParquetSink {
+    config: ParquetConfig,
+    /// Measurements by start of their period and name
+    buffers: BTreeMap<(DateTime<Utc>, String), Vec<Measurement>>,
+}
+
+impl ParquetSink {
+    pub fn new(config: ParquetConfig) -> ParquetSink {
+        ParquetSink {
+            config,
+            buffers: BTreeMap::new(),
+        }
+    }
+
+    async fn write_partition(&mut self, key: (DateTime<Utc>, String)) -> anyhow::Result<()> {
+        let Some(measurements) = self.buffers.remove(&key) else {
+            return Ok(());
+        };
+        let directory = self
+            .config
+            .directory
+            .join(self.config.period.partition(key.0, &key.1));
+        let (measurements, result) = tokio::task::spawn_blocking(move || {
+            let result = write_file(&directory, &measurements);
+            (measurements, result)
+        })
+        .await?;
+        // Keep the measurements for the next flush or the shutdown
+        if result.is_err() {
+            self.buffers.insert(key, measurements);
+        }
+        result.map(|_| ())
+    }
+
+    /// Write all partitions
+    async fn write_all(&mut self) -> anyhow::Result<()> {
+        let keys: Vec<_> = self.buffers.keys().cloned().collect();
+        for key in keys {
+            self.write_partition(key).await?;
+        }
+        Ok(())
+    }
+
+    /// Write all partitions of periods ended before `now`
+    async fn write_completed(&mut self, now: DateTime<Utc>) -> anyhow::Result<()> {
+        let length = self.config.period.length();
+        let completed: Vec<_> = self
+            .buffers
+            .keys()
+            .filter(|(start, _)| *start + length <= now)
+            .cloned()
+            .collect();
+        for key in completed {
+            self.write_partition(key).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OutputSink for ParquetSink {
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let time = measurement.time.unwrap_or_else(Utc::now);
+        let key = (self.config.period.start(time), measurement.name.clone());
+        let buffer = self.buffers.entry(key.clone()).or_default();
+        buffer.push(measurement.clone().add_time(Some(time)));
+        if buffer.len() >= self.config.max_rows {
+            self.write_partition(key).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        match self.config.flush {
+            // Only called on the schedule, see [super::batch::Batched]
+            Some(_) => self.write_all().await,
+            None => self.write_completed(Utc::now()).await,
+        }
+    }
+
+    /// End of the earliest buffered period
+    fn next_flush(&self) -> Option<tokio::time::Instant> {
+        let (start, _) = self.buffers.keys().next()?;
+        let end = *start + self.config.period.length();
+        let wait = (end - Utc::now()).to_std().unwrap_or_default();
+        Some(tokio::time::Instant::now() + wait)
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.write_all().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ParquetConfig, ParquetSink, Period};
+    use crate::{
+        output::{batch::FlushSchedule, OutputSink},
+        Measurement,
+    };
+    use chrono::{DateTime, Utc};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::path::PathBuf;
+
+    fn measurement(time: i64, humidity: impl Into<crate::measurement::FieldValue>) -> Measurement {
+        Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.5)
+            .add_field("humidity", humidity)
+            .add_time(DateTime::from_timestamp(time, 0))
+    }
+
+    #[test]
+    fn test_partition() {
+        let time = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let start = Period::Hourly.start(time);
+        assert_eq!(start.to_rfc3339(), "2023-11-14T22:00:00+00:00");
+        assert_eq!(
+            Period::Hourly.partition(start, "tempHum"),
+            PathBuf::from("date=2023-11-14/hour=22/measurement=tempHum")
+        );
+        assert_eq!(
+            Period::Daily.partition(Period::Daily.start(time), "tempHum"),
+            PathBuf::from("date=2023-11-14/measurement=tempHum")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_files() {
+        let directory =
+            std::env::temp_dir().join(format!("sensorflow-parquet-{:08x}", rand::random::<u32>()));
+        let mut sink = ParquetSink::new(ParquetConfig {
+            directory: directory.clone(),
+            period: Period::Daily,
+            max_rows: 100,
+            flush: None,
+        });
+        sink.write(&measurement(1700000000, 45u64)).await.unwrap();
+        sink.write(&measurement(1700000060, 46u64)).await.unwrap();
+        sink.write(&measurement(1700100000, 47u64)).await.unwrap();
+        // Both days are over
+        sink.flush().await.unwrap();
+        sink.shutdown().await.unwrap();
+
+        let file =
+            directory.join("date=2023-11-14/measurement=tempHum/part-20231114T221320.parquet");
+        let reader = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(file).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let rows: usize = reader.map(|batch| batch.unwrap().num_rows()).sum();
+        assert_eq!(rows, 2);
+        assert!(directory
+            .join("date=2023-11-16/measurement=tempHum")
+            .is_dir());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flush_schedule() {
+        let directory =
+            std::env::temp_dir().join(format!("sensorflow-parquet-{:08x}", rand::random::<u32>()));
+        let config = ParquetConfig {
+            directory: directory.clone(),
+            period: Period::Daily,
+            max_rows: 100,
+            flush: None,
+        };
+        let now = Utc::now().timestamp();
+        let mut sink = ParquetSink::new(config.clone());
+        assert_eq!(sink.next_flush(), None);
+        sink.write(&measurement(now, 45u64)).await.unwrap();
+        // At the end of the day
+        let due = sink.next_flush().unwrap();
+        assert!(due > tokio::time::Instant::now());
+        assert!(due <= tokio::time::Instant::now() + std::time::Duration::from_secs(86400));
+        sink.flush().await.unwrap();
+        assert!(!directory.exists());
+
+        // The current day is written on every flush of the schedule
+        let mut sink = ParquetSink::new(ParquetConfig {
+            flush: Some(FlushSchedule {
+                size: None,
+                interval: Some(60),
+            }),
+            ..config
+        });
+        sink.write(&measurement(now, 45u64)).await.unwrap();
+        sink.flush().await.unwrap();
+        assert_eq!(sink.next_flush(), None);
+        let partition = directory.join(Period::Daily.partition(
+            Period::Daily.start(DateTime::from_timestamp(now, 0).unwrap()),
+            "tempHum",
+        ));
+        assert_eq!(std::fs::read_dir(partition).unwrap().count(), 1);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_write() {
+        // A file where the directory should be
+        let directory =
+            std::env::temp_dir().join(format!("sensorflow-parquet-{:08x}", rand::random::<u32>()));
+        std::fs::write(&directory, "").unwrap();
+        let mut sink = ParquetSink::new(ParquetConfig {
+            directory: directory.clone(),
+            period: Period::Daily,
+            max_rows: 2,
+            flush: None,
+        });
+        sink.write(&measurement(1700000000, 45u64)).await.unwrap();
+        assert!(sink.write(&measurement(1700000060, 46u64)).await.is_err());
+        assert!(sink.shutdown().await.is_err());
+        assert_eq!(sink.buffers.values().map(Vec::len).sum::<usize>(), 2);
+
+        // Written once the directory can be created
+        std::fs::remove_file(&directory).unwrap();
+        sink.shutdown().await.unwrap();
+        assert!(sink.buffers.is_empty());
+        let partition = directory.join("date=2023-11-14/measurement=tempHum");
+        assert_eq!(std::fs::read_dir(partition).unwrap().count(), 1);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
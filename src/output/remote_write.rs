@@ -0,0 +1,699 @@
+//! Prometheus remote write.
+//!
+//! Samples are pushed to receivers of the remote write protocol 1.0, e.g. Prometheus with
+//! `--web.enable-remote-write-receiver`, Mimir, Thanos Receive or VictoriaMetrics. Every
+//! numeric or boolean field becomes a sample of the series `<measurement>_<field>`, suffixed
+//! with the unit of the field and `_total` for counters, labeled with the tags of the
+//! measurement. Samples are collected into batches of up to `batch_size` samples, encoded as
+//! protobuf `WriteRequest`, compressed with snappy and sent once the batch is full or the
+//! pipeline has no more measurements queued, or as scheduled by `flush`. Failed requests are
+//! retried with backoff.
+//!
+//! For monitoring the sensors themselves, `histograms` adds the histogram
+//! `sensorflow_report_interval_seconds` of the intervals between the measurements of every
+//! sensor, labeled with the name of the measurement as `measurement` and its tags, and the
+//! histogram `sensorflow_latency_seconds` of the delay from the time of a measurement to its
+//! write, including parsing and queueing. `last_seen` adds the gauge
+//! `sensorflow_last_seen_timestamp_seconds` of every sensor. These series are sent with every
+//! flush for the sensors which reported since the last one.
+use super::{
+    batch::FlushSchedule,
+    error::SinkError,
+    retry::{Retry, RetryPolicy},
+    OutputSink,
+};
+use crate::{
+    measurement::{FieldKind, FieldValue, Measurement, Unit},
+    tls::TlsConfig,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+fn default_batch_size() -> usize {
+    500
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_interval_buckets() -> Vec<f64> {
+    vec![10., 30., 60., 120., 300., 600., 1800., 3600.]
+}
+
+fn default_latency_buckets() -> Vec<f64> {
+    vec![0.01, 0.05, 0.1, 0.5, 1., 5., 30.]
+}
+
+/// Receiver and batching
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct RemoteWriteConfig {
+    /// Endpoint of the receiver, e.g. `http://mimir:8080/api/v1/push`
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Bearer token, takes precedence over username and password
+    pub token: Option<String>,
+    /// Additional HTTP headers, e.g. `X-Scope-OrgID` of multi-tenant Mimir
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Labels added to all series, tags of the same name take precedence
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Number of samples per request
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Number of attempts of a request including the first one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// TLS settings of `https` URLs, see [crate::tls]
+    pub tls: Option<TlsConfig>,
+    /// Flush on a schedule instead of whenever the pipeline ran idle, see [super::batch]
+    #[serde(default)]
+    pub flush: Option<FlushSchedule>,
+    /// Histograms of the intervals between the measurements of every sensor and of the latency
+    /// of measurements
+    #[serde(default)]
+    pub histograms: bool,
+    /// Upper bounds of the buckets of the interval histograms in seconds
+    #[serde(default = "default_interval_buckets")]
+    pub interval_buckets: Vec<f64>,
+    /// Upper bounds of the buckets of the latency histogram in seconds
+    #[serde(default = "default_latency_buckets")]
+    pub latency_buckets: Vec<f64>,
+    /// Time every sensor was last seen
+    #[serde(default)]
+    pub last_seen: bool,
+}
+
+impl RemoteWriteConfig {
+    /// Retries of failed requests as configured
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts.max(1),
+            ..Default::default()
+        }
+    }
+
+    /// Sink with retries as configured
+    pub fn sink(self) -> anyhow::Result<Retry<RemoteWriteSink>> {
+        let policy = self.retry_policy();
+        Ok(Retry::new(RemoteWriteSink::new(self)?, policy))
+    }
+}
+
+/// Replace characters not allowed in metric and label names
+fn sanitize(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/// `<measurement>_<field>`, followed by the unit and `_total` for counters unless the field
+/// name already ends with them
+fn metric_name(measurement: &Measurement, field: &str) -> String {
+    let mut name = format!("{}_{}", sanitize(&measurement.name), sanitize(field));
+    let meta = measurement.field_meta(field);
+    let suffixes = [
+        meta.unit.as_ref().and_then(Unit::metric_suffix),
+        (meta.kind == FieldKind::Counter).then_some("total"),
+    ];
+    for suffix in suffixes.into_iter().flatten() {
+        if !name.ends_with(&format!("_{}", suffix)) {
+            name.push('_');
+            name.push_str(suffix);
+        }
+    }
+    name
+}
+
+/// Sorted labels and a sample of a series
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    /// Milliseconds since the epoch
+    pub timestamp: i64,
+}
+
+impl RemoteWriteConfig {
+    /// Series of all numeric and boolean fields of a measurement
+    pub fn series(&self, measurement: &Measurement) -> Vec<Series> {
+        let timestamp = measurement
+            .time
+            .unwrap_or_else(chrono::Utc::now)
+            .timestamp_millis();
+        let mut labels = self.labels.clone();
+        for (key, value) in &measurement.tags {
+            labels.insert(sanitize(key), value.clone());
+        }
+        measurement
+            .fields
+            .iter()
+            .filter_map(|(field, value)| {
+                let value = match value {
+                    FieldValue::Float(x) => *x,
+                    FieldValue::Integer(x) => *x as f64,
+                    FieldValue::UInteger(x) => *x as f64,
+                    FieldValue::Boolean(b) => f64::from(u8::from(*b)),
+                    FieldValue::String(_) => return None,
+                };
+                let mut labels = labels.clone();
+                labels.insert("__name__".into(), metric_name(measurement, field));
+                Some(Series {
+                    labels: labels.into_iter().collect(),
+                    value,
+                    timestamp,
+                })
+            })
+            .collect()
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buf, field << 3 | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/// Protobuf encoding of a `WriteRequest` with one sample per series
+pub fn encode(series: &[Series]) -> Vec<u8> {
+    let mut request = Vec::new();
+    for series in series {
+        let mut timeseries = Vec::new();
+        for (name, value) in &series.labels {
+            let mut label = Vec::new();
+            put_bytes(&mut label, 1, name.as_bytes());
+            put_bytes(&mut label, 2, value.as_bytes());
+            put_bytes(&mut timeseries, 1, &label);
+        }
+        let mut sample = vec![1 << 3 | 1];
+        sample.extend_from_slice(&series.value.to_le_bytes());
+        put_varint(&mut sample, 2 << 3);
+        put_varint(&mut sample, series.timestamp as u64);
+        put_bytes(&mut timeseries, 2, &sample);
+        put_bytes(&mut request, 1, &timeseries);
+    }
+    request
+}
+
+/// Cumulative counts of observations up to each bound
+#[derive(Debug, Clone, PartialEq)]
+struct Histogram {
+    bounds: Vec<f64>,
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Histogram {
+        let mut bounds = bounds.to_vec();
+        bounds.sort_by(f64::total_cmp);
+        Histogram {
+            buckets: vec![0; bounds.len()],
+            bounds,
+            sum: 0.,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, bucket) in self.bounds.iter().zip(&mut self.buckets) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Series `<name>_bucket`, `<name>_sum` and `<name>_count`
+    fn series(&self, name: &str, labels: &BTreeMap<String, String>, timestamp: i64) -> Vec<Series> {
+        let series = |suffix: &str, le: Option<String>, value: f64| {
+            let mut labels = labels.clone();
+            labels.insert("__name__".into(), format!("{}_{}", name, suffix));
+            if let Some(le) = le {
+                labels.insert("le".into(), le);
+            }
+            Series {
+                labels: labels.into_iter().collect(),
+                value,
+                timestamp,
+            }
+        };
+        let buckets = self.bounds.iter().map(|bound| bound.to_string());
+        buckets
+            .chain(["+Inf".to_string()])
+            .zip(self.buckets.iter().chain([&self.count]))
+            .map(|(le, count)| series("bucket", Some(le), *count as f64))
+            .chain([
+                series("sum", None, self.sum),
+                series("count", None, self.count as f64),
+            ])
+            .collect()
+    }
+}
+
+/// Reports of a sensor
+struct SensorMetrics {
+    last_seen: DateTime<Utc>,
+    intervals: Histogram,
+    changed: bool,
+}
+
+/// Histograms and gauges about the sensors sending the measurements
+struct FleetMetrics {
+    labels: BTreeMap<String, String>,
+    histograms: bool,
+    last_seen: bool,
+    interval_buckets: Vec<f64>,
+    /// Sensors by their labels
+    sensors: BTreeMap<BTreeMap<String, String>, SensorMetrics>,
+    latency: Histogram,
+}
+
+impl FleetMetrics {
+    fn new(config: &RemoteWriteConfig) -> Option<FleetMetrics> {
+        (config.histograms || config.last_seen).then(|| FleetMetrics {
+            labels: config.labels.clone(),
+            histograms: config.histograms,
+            last_seen: config.last_seen,
+            interval_buckets: config.interval_buckets.clone(),
+            sensors: BTreeMap::new(),
+            latency: Histogram::new(&config.latency_buckets),
+        })
+    }
+
+    /// Record a measurement written at `now`
+    fn observe(&mut self, measurement: &Measurement, now: DateTime<Utc>) {
+        let time = measurement.time.unwrap_or(now);
+        let latency = (now - time).to_std().unwrap_or_default();
+        self.latency.observe(latency.as_secs_f64());
+
+        let mut labels = self.labels.clone();
+        for (key, value) in &measurement.tags {
+            labels.insert(sanitize(key), value.clone());
+        }
+        labels.insert("measurement".into(), measurement.name.clone());
+        match self.sensors.get_mut(&labels) {
+            Some(sensor) => {
+                let interval = (time - sensor.last_seen).to_std().unwrap_or_default();
+                sensor.intervals.observe(interval.as_secs_f64());
+                sensor.last_seen = sensor.last_seen.max(time);
+                sensor.changed = true;
+            }
+            None => {
+                let sensor = SensorMetrics {
+                    last_seen: time,
+                    intervals: Histogram::new(&self.interval_buckets),
+                    changed: true,
+                };
+                self.sensors.insert(labels, sensor);
+            }
+        }
+    }
+
+    /// Series of the sensors which reported since the last call and of the latency
+    fn series(&mut self, now: DateTime<Utc>) -> Vec<Series> {
+        let timestamp = now.timestamp_millis();
+        let mut series = Vec::new();
+        if self.histograms && self.latency.count > 0 {
+            series.extend(self.latency.series(
+                "sensorflow_latency_seconds",
+                &self.labels,
+                timestamp,
+            ));
+        }
+        for (labels, sensor) in &mut self.sensors {
+            if !std::mem::take(&mut sensor.changed) {
+                continue;
+            }
+            if self.last_seen {
+                let mut labels = labels.clone();
+                labels.insert(
+                    "__name__".into(),
+                    "sensorflow_last_seen_timestamp_seconds".into(),
+                );
+                series.push(Series {
+                    labels: labels.into_iter().collect(),
+                    value: sensor.last_seen.timestamp_millis() as f64 / 1000.,
+                    timestamp,
+                });
+            }
+            if self.histograms && sensor.intervals.count > 0 {
+                series.extend(sensor.intervals.series(
+                    "sensorflow_report_interval_seconds",
+                    labels,
+                    timestamp,
+                ));
+            }
+        }
+        series
+    }
+}
+
+/// Sink pushing batches of samples
+pub struct RemoteWriteSink {
+    config: RemoteWriteConfig,
+    client: reqwest::Client,
+    batch: Vec<Series>,
+    fleet: Option<FleetMetrics>,
+    /// Whether the batch holds the fleet series of a flush not sent yet
+    fleet_pending: bool,
+}
+
+impl RemoteWriteSink {
+    pub fn new(config: RemoteWriteConfig) -> anyhow::Result<RemoteWriteSink> {
+        let mut client = reqwest::Client::builder().timeout(Duration::from_secs(30));
+        if let Some(tls) = &config.tls {
+            client = tls.http_client(client)?;
+        }
+        let client = client.build()?;
+        Ok(RemoteWriteSink {
+            fleet: FleetMetrics::new(&config),
+            config,
+            client,
+            batch: Vec::new(),
+            fleet_pending: false,
+        })
+    }
+
+    /// Send the current batch. The batch is kept if sending may succeed later.
+    async fn send_batch(&mut self) -> anyhow::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let body = snap::raw::Encoder::new().compress_vec(&encode(&self.batch))?;
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+            .header(reqwest::header::CONTENT_ENCODING, "snappy")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body);
+        for (name, value) in &self.config.headers {
+            request = request.header(name, value);
+        }
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        } else if let Some(username) = &self.config.username {
+            request = request.basic_auth(username, self.config.password.as_ref());
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| SinkError::Retryable(e.to_string()))?;
+        let status = response.status();
+        if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(SinkError::Retryable(status.to_string()).into());
+        }
+        // Rejected samples would be rejected again, e.g. out of order samples
+        let samples = std::mem::take(&mut self.batch).len();
+        self.fleet_pending = false;
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(SinkError::Fatal(format!(
+                "Receiver rejected {} samples: {} {}",
+                samples, status, body
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OutputSink for RemoteWriteSink {
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        // Send a full batch first, so a retried write does not add the samples twice
+        if self.batch.len() >= self.config.batch_size {
+            self.send_batch().await?;
+        }
+        self.batch.extend(self.config.series(measurement));
+        if let Some(fleet) = &mut self.fleet {
+            fleet.observe(measurement, Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        // A retried flush sends the fleet series of the failed one
+        if let Some(fleet) = self.fleet.as_mut().filter(|_| !self.fleet_pending) {
+            self.batch.extend(fleet.series(Utc::now()));
+            self.fleet_pending = true;
+        }
+        self.send_batch().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, FleetMetrics, RemoteWriteConfig, RemoteWriteSink, Series};
+    use crate::measurement::{FieldMeta, Unit};
+    use crate::{output::OutputSink, Measurement};
+    use chrono::{DateTime, TimeDelta};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn config(url: &str) -> RemoteWriteConfig {
+        toml::from_str(&format!(
+            r#"
+            url = "{}"
+            headers = {{ X-Scope-OrgID = "home" }}
+            labels = {{ job = "sensorflow" }}
+            "#,
+            url
+        ))
+        .unwrap()
+    }
+
+    fn measurement() -> Measurement {
+        Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.5)
+            .add_field("battery-low", true)
+            .add_field("model", "LaCrosse")
+            .add_time(DateTime::from_timestamp(1700000000, 0))
+    }
+
+    #[test]
+    fn test_series() {
+        let series = config("http://mimir").series(&measurement());
+        assert_eq!(
+            series,
+            vec![
+                Series {
+                    labels: vec![
+                        ("__name__".into(), "tempHum_temperature".into()),
+                        ("job".into(), "sensorflow".into()),
+                        ("sensorId".into(), "12".into()),
+                    ],
+                    value: 21.5,
+                    timestamp: 1700000000000,
+                },
+                Series {
+                    labels: vec![
+                        ("__name__".into(), "tempHum_battery_low".into()),
+                        ("job".into(), "sensorflow".into()),
+                        ("sensorId".into(), "12".into()),
+                    ],
+                    value: 1.,
+                    timestamp: 1700000000000,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metric_names() {
+        let measurement = Measurement::new("meter")
+            .add_field("temperature", 21.5)
+            .add_field_meta("temperature", Unit::Celsius)
+            .add_field("energy_total", 1200.)
+            .add_field_meta("energy_total", FieldMeta::counter(Some(Unit::KilowattHour)))
+            .add_field("pulses", 7u64)
+            .add_field_meta("pulses", FieldMeta::counter(None))
+            .add_field("level", 3u64)
+            .add_field_meta("level", Unit::from_symbol("l"));
+        let names: Vec<_> = config("http://mimir")
+            .series(&measurement)
+            .into_iter()
+            .map(|series| series.labels[0].1.clone())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "meter_temperature_celsius",
+                "meter_energy_total_kilowatt_hours_total",
+                "meter_pulses_total",
+                "meter_level",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fleet_metrics() {
+        let mut config = config("http://mimir");
+        config.histograms = true;
+        config.last_seen = true;
+        config.interval_buckets = vec![60., 30.];
+        config.latency_buckets = vec![1.];
+        let mut fleet = FleetMetrics::new(&config).unwrap();
+        let start = measurement().time.unwrap();
+        for seconds in [0, 20, 60] {
+            let measurement = measurement().add_time(Some(start + TimeDelta::seconds(seconds)));
+            fleet.observe(&measurement, start + TimeDelta::seconds(62));
+        }
+        let series = fleet.series(start + TimeDelta::seconds(63));
+        let samples: Vec<_> = series
+            .iter()
+            .map(|series| {
+                let labels: Vec<_> = series
+                    .labels
+                    .iter()
+                    .filter(|(name, _)| name != "job")
+                    .map(|(name, value)| format!("{}={}", name, value))
+                    .collect();
+                (labels.join(","), series.value)
+            })
+            .collect();
+        let sensor = "measurement=tempHum,sensorId=12";
+        let interval = |suffix: &str| {
+            format!(
+                "__name__=sensorflow_report_interval_seconds_{},{}",
+                suffix, sensor
+            )
+        };
+        assert_eq!(
+            samples,
+            [
+                ("__name__=sensorflow_latency_seconds_bucket,le=1".into(), 0.),
+                (
+                    "__name__=sensorflow_latency_seconds_bucket,le=+Inf".into(),
+                    3.
+                ),
+                (
+                    "__name__=sensorflow_latency_seconds_sum".into(),
+                    62. + 42. + 2.
+                ),
+                ("__name__=sensorflow_latency_seconds_count".into(), 3.),
+                (
+                    format!("__name__=sensorflow_last_seen_timestamp_seconds,{}", sensor),
+                    1700000060.
+                ),
+                (interval("bucket,le=30"), 1.),
+                (interval("bucket,le=60"), 2.),
+                (interval("bucket,le=+Inf"), 2.),
+                (interval("sum"), 60.),
+                (interval("count"), 2.),
+            ]
+        );
+        assert_eq!(series[0].timestamp, 1700000063000);
+        // Only the latency until the sensor reports again
+        assert_eq!(fleet.series(start).len(), 4);
+    }
+
+    #[test]
+    fn test_encode() {
+        let series = Series {
+            labels: vec![("__name__".into(), "up".into())],
+            value: 1.,
+            timestamp: 1,
+        };
+        assert_eq!(
+            encode(&[series]),
+            [
+                0x0a, 0x1d, // timeseries
+                0x0a, 0x0e, // label
+                0x0a, 0x08, b'_', b'_', b'n', b'a', b'm', b'e', b'_', b'_', // name
+                0x12, 0x02, b'u', b'p', // value
+                0x12, 0x0b, // sample
+                0x09, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f, // value
+                0x10, 0x01, // timestamp
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/v1/push", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            let (head, body) = loop {
+                let len = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..len]);
+                let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+                    continue;
+                };
+                let head = String::from_utf8_lossy(&request[..end]).to_string();
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if request.len() >= end + 4 + length {
+                    break (head, request[end + 4..].to_vec());
+                }
+            };
+            socket
+                .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            (head, body)
+        });
+
+        let config = config(&url);
+        let expected = encode(&config.series(&measurement()));
+        let mut sink = config.sink().unwrap();
+        sink.write(&measurement()).await.unwrap();
+        sink.flush().await.unwrap();
+
+        let (head, body) = server.await.unwrap();
+        assert!(head.starts_with("POST /api/v1/push HTTP/1.1\r\n"));
+        assert!(head.contains("content-encoding: snappy\r\n"));
+        assert!(head.contains("x-scope-orgid: home\r\n"));
+        assert_eq!(
+            snap::raw::Decoder::new().decompress_vec(&body).unwrap(),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retried_flush() {
+        // Nothing listens on the port anymore
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/v1/push", listener.local_addr().unwrap());
+        drop(listener);
+        let mut config = config(&url);
+        config.last_seen = true;
+        let mut sink = RemoteWriteSink::new(config).unwrap();
+        sink.write(&measurement()).await.unwrap();
+        assert!(sink.flush().await.is_err());
+        let samples = sink.batch.len();
+        assert!(samples > 1);
+        assert!(sink.flush().await.is_err());
+        assert_eq!(sink.batch.len(), samples);
+    }
+}
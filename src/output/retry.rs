@@ -0,0 +1,410 @@
+//! Retrying of failed sink operations.
+use super::{error::SinkError, OutputSink};
+use crate::Measurement;
+use async_trait::async_trait;
+use rand::Rng;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Parameters of the exponential backoff between attempts
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of attempts including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between attempts
+    pub max_backoff: Duration,
+    /// Factor by which the delay grows after every attempt
+    pub multiplier: f64,
+    /// Fraction of the delay that is randomized, between 0 and 1
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.,
+            jitter: 0.2,
+        }
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    RetryPolicy::default().max_attempts
+}
+
+fn default_initial_backoff() -> u64 {
+    RetryPolicy::default().initial_backoff.as_millis() as u64
+}
+
+fn default_max_backoff() -> u64 {
+    RetryPolicy::default().max_backoff.as_millis() as u64
+}
+
+fn default_multiplier() -> f64 {
+    RetryPolicy::default().multiplier
+}
+
+fn default_jitter() -> f64 {
+    RetryPolicy::default().jitter
+}
+
+/// Retries of an output in the pipeline file, e.g. `retry = { max_attempts = 10 }`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Number of attempts including the first one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry in milliseconds
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff: u64,
+    /// Upper bound of the delay between attempts in milliseconds
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: u64,
+    /// Factor by which the delay grows after every attempt, at least 1
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+    /// Fraction of the delay that is randomized, between 0 and 1
+    #[serde(default = "default_jitter")]
+    pub jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: default_max_attempts(),
+            initial_backoff: default_initial_backoff(),
+            max_backoff: default_max_backoff(),
+            multiplier: default_multiplier(),
+            jitter: default_jitter(),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Policy of the configured retries, fails if the multiplier or jitter is out of range
+    pub fn policy(&self) -> anyhow::Result<RetryPolicy> {
+        if !(self.multiplier.is_finite() && self.multiplier >= 1.) {
+            anyhow::bail!("Retry multiplier {} is not at least 1", self.multiplier);
+        }
+        if !(0. ..=1.).contains(&self.jitter) {
+            anyhow::bail!("Retry jitter {} is not between 0 and 1", self.jitter);
+        }
+        Ok(RetryPolicy {
+            max_attempts: self.max_attempts.max(1),
+            initial_backoff: Duration::from_millis(self.initial_backoff),
+            max_backoff: Duration::from_millis(self.max_backoff),
+            multiplier: self.multiplier,
+            jitter: self.jitter,
+        })
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the given retry, starting at 1 for the first retry. Does not include jitter.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let secs = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        // Bounded before building the duration, which would overflow after many retries
+        Duration::try_from_secs_f64(secs.min(self.max_backoff.as_secs_f64()))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+
+    fn backoff_with_jitter(&self, retry: u32) -> Duration {
+        let backoff = self.backoff(retry);
+        // NaN would make `gen_range` panic
+        if self.jitter.is_nan() || self.jitter <= 0. {
+            return backoff;
+        }
+        let jitter = self.jitter.min(1.);
+        let factor = rand::thread_rng().gen_range(1. - jitter..=1. + jitter);
+        Duration::try_from_secs_f64(backoff.as_secs_f64() * factor).unwrap_or(backoff)
+    }
+}
+
+/// Default classification of errors.
+///
+/// Errors explicitly marked by a [SinkError] are classified accordingly. IO errors are
+/// retryable if they indicate a network or timing problem. Everything else is fatal.
+pub fn is_retryable(err: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+    if let Some(err) = err.downcast_ref::<SinkError>() {
+        return matches!(err, SinkError::Retryable(_));
+    }
+    if let Some(err) = err.downcast_ref::<std::io::Error>() {
+        return matches!(
+            err.kind(),
+            ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::BrokenPipe
+                | ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+                | ErrorKind::WouldBlock
+        );
+    }
+    false
+}
+
+/// Wraps a sink and retries failed operations according to a [RetryPolicy].
+pub struct Retry<S> {
+    inner: S,
+    policy: RetryPolicy,
+    classify: fn(&anyhow::Error) -> bool,
+}
+
+impl<S: OutputSink> Retry<S> {
+    pub fn new(inner: S, policy: RetryPolicy) -> Retry<S> {
+        Retry {
+            inner,
+            policy,
+            classify: is_retryable,
+        }
+    }
+
+    /// Replace the function deciding whether an error is worth a retry
+    pub fn with_classifier(mut self, classify: fn(&anyhow::Error) -> bool) -> Retry<S> {
+        self.classify = classify;
+        self
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+/// Sleep before the next attempt, or return the error if no further attempt should be made
+async fn wait_before(
+    policy: &RetryPolicy,
+    classify: fn(&anyhow::Error) -> bool,
+    attempt: u32,
+    err: anyhow::Error,
+) -> anyhow::Result<()> {
+    if attempt >= policy.max_attempts || !classify(&err) {
+        return Err(err);
+    }
+    tokio::time::sleep(policy.backoff_with_jitter(attempt)).await;
+    Ok(())
+}
+
+#[async_trait]
+impl<S: OutputSink> OutputSink for Retry<S> {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        let mut attempt = 1;
+        loop {
+            match self.inner.start().await {
+                Ok(()) => return Ok(()),
+                Err(err) => wait_before(&self.policy, self.classify, attempt, err).await?,
+            }
+            attempt += 1;
+        }
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let mut attempt = 1;
+        loop {
+            match self.inner.write(measurement).await {
+                Ok(()) => return Ok(()),
+                Err(err) => wait_before(&self.policy, self.classify, attempt, err).await?,
+            }
+            attempt += 1;
+        }
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        let mut attempt = 1;
+        loop {
+            match self.inner.flush().await {
+                Ok(()) => return Ok(()),
+                Err(err) => wait_before(&self.policy, self.classify, attempt, err).await?,
+            }
+            attempt += 1;
+        }
+    }
+
+    fn next_flush(&self) -> Option<tokio::time::Instant> {
+        self.inner.next_flush()
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.flush().await?;
+        self.inner.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_retryable, Retry, RetryConfig, RetryPolicy};
+    use crate::{
+        output::{error::SinkError, OutputSink},
+        Measurement,
+    };
+    use async_trait::async_trait;
+    use std::time::Duration;
+
+    /// Fails the given number of times, then succeeds
+    struct Flaky {
+        failures: u32,
+        calls: u32,
+        fatal: bool,
+    }
+
+    impl Flaky {
+        fn new(failures: u32, fatal: bool) -> Flaky {
+            Flaky {
+                failures,
+                calls: 0,
+                fatal,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl OutputSink for Flaky {
+        async fn write(&mut self, _: &Measurement) -> anyhow::Result<()> {
+            self.calls += 1;
+            if self.calls > self.failures {
+                Ok(())
+            } else if self.fatal {
+                Err(SinkError::Fatal("bad credentials".into()))?
+            } else {
+                Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))?
+            }
+        }
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_backoff_grows_exponentially_up_to_max() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.,
+            ..Default::default()
+        };
+        let backoffs: Vec<_> = (1..=4).map(|i| policy.backoff(i).as_secs()).collect();
+        assert_eq!(backoffs, [1, 2, 4, 5]);
+    }
+
+    #[test]
+    fn test_backoff_of_long_outage_stays_at_max() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(66), policy.max_backoff);
+        assert_eq!(policy.backoff(u32::MAX), policy.max_backoff);
+        assert!(policy.backoff_with_jitter(u32::MAX) <= policy.max_backoff.mul_f64(1.2));
+    }
+
+    #[test]
+    fn test_config_policy() {
+        let config = RetryConfig {
+            initial_backoff: 250,
+            max_backoff: 60_000,
+            multiplier: 3.,
+            ..Default::default()
+        };
+        let policy = config.policy().unwrap();
+        assert_eq!(policy.initial_backoff, Duration::from_millis(250));
+        assert_eq!(policy.max_backoff, Duration::from_secs(60));
+        assert_eq!(policy.multiplier, 3.);
+        assert_eq!(
+            RetryConfig::default().policy().unwrap(),
+            RetryPolicy::default()
+        );
+    }
+
+    #[test]
+    fn test_config_out_of_range() {
+        for (multiplier, jitter) in [(f64::NAN, 0.2), (0.5, 0.2), (f64::INFINITY, 0.2)] {
+            let config = RetryConfig {
+                multiplier,
+                jitter,
+                ..Default::default()
+            };
+            assert!(config.policy().is_err(), "{} {}", multiplier, jitter);
+        }
+        for jitter in [f64::NAN, -0.1, 1.5] {
+            let config = RetryConfig {
+                jitter,
+                ..Default::default()
+            };
+            assert!(config.policy().is_err(), "{}", jitter);
+        }
+    }
+
+    #[test]
+    fn test_nan_jitter_of_policy() {
+        let policy = RetryPolicy {
+            jitter: f64::NAN,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff_with_jitter(1), policy.backoff(1));
+    }
+
+    #[test]
+    fn test_classification() {
+        use std::io::{Error, ErrorKind};
+        assert!(is_retryable(&Error::from(ErrorKind::TimedOut).into()));
+        assert!(!is_retryable(
+            &Error::from(ErrorKind::PermissionDenied).into()
+        ));
+        assert!(is_retryable(&SinkError::Retryable("503".into()).into()));
+        assert!(!is_retryable(&anyhow::anyhow!("unknown")));
+    }
+
+    #[tokio::test]
+    async fn test_retryable_errors_are_retried() {
+        let mut sink = Retry::new(Flaky::new(2, false), policy(3));
+        sink.write(&Measurement::new("m")).await.unwrap();
+        assert_eq!(sink.into_inner().calls, 3);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let mut sink = Retry::new(Flaky::new(5, false), policy(3));
+        assert!(sink.write(&Measurement::new("m")).await.is_err());
+        assert_eq!(sink.into_inner().calls, 3);
+    }
+
+    /// Sink asking to be flushed at a fixed time
+    struct Due(tokio::time::Instant);
+
+    #[async_trait]
+    impl OutputSink for Due {
+        async fn write(&mut self, _: &Measurement) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn next_flush(&self) -> Option<tokio::time::Instant> {
+            Some(self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_time_of_inner_sink() {
+        let due = tokio::time::Instant::now() + Duration::from_secs(10);
+        let sink = Retry::new(Due(due), policy(3));
+        assert_eq!(sink.next_flush(), Some(due));
+    }
+
+    #[tokio::test]
+    async fn test_fatal_errors_are_not_retried() {
+        let mut sink = Retry::new(Flaky::new(5, true), policy(3));
+        assert!(sink.write(&Measurement::new("m")).await.is_err());
+        assert_eq!(sink.into_inner().calls, 1);
+    }
+}
@@ -0,0 +1,317 @@
+//! Signal K deltas for marine data servers and chartplotters.
+//!
+//! Fields of measurements are mapped to Signal K paths and sent as delta messages, either as
+//! UDP datagrams (`udp://host:port`) or over the WebSocket stream of the server
+//! (`ws://host:3000/signalk/v1/stream`, requires the `websocket` feature). Signal K expects SI
+//! units, so each mapping can scale and offset the value, e.g. an offset of 273.15 to convert °C
+//! into K. Fields without a mapping are not sent.
+use super::{error::SinkError, retry::RetryConfig, OutputSink};
+use crate::measurement::{FieldValue, Measurement};
+use crate::transform::selects;
+use async_trait::async_trait;
+use chrono::{SecondsFormat, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use tokio::net::UdpSocket;
+
+fn default_context() -> String {
+    "vessels.self".into()
+}
+
+fn default_source() -> String {
+    "sensorflow".into()
+}
+
+fn default_scale() -> f64 {
+    1.
+}
+
+/// Field sent as value of a Signal K path
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct PathMapping {
+    pub measurement: String,
+    pub field: String,
+    /// Only measurements carrying all of these tags are mapped
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Signal K path, e.g. `environment.outside.temperature`
+    pub path: String,
+    /// Factor applied to numeric values
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    /// Added to numeric values after scaling
+    #[serde(default)]
+    pub offset: f64,
+}
+
+impl PathMapping {
+    fn matches(&self, measurement: &Measurement) -> bool {
+        selects(Some(self.measurement.as_str()), &self.tags, measurement)
+    }
+
+    fn value(&self, value: &FieldValue) -> Value {
+        let number = match value {
+            FieldValue::Float(x) => *x,
+            FieldValue::Integer(x) => *x as f64,
+            FieldValue::UInteger(x) => *x as f64,
+            FieldValue::String(s) => return Value::from(s.as_str()),
+            FieldValue::Boolean(b) => return Value::from(*b),
+        };
+        Value::from(number * self.scale + self.offset)
+    }
+}
+
+/// Server and the mapping of fields to paths
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct SignalKConfig {
+    pub url: String,
+    /// Object the values belong to
+    #[serde(default = "default_context")]
+    pub context: String,
+    /// Label of the source of all values
+    #[serde(default = "default_source")]
+    pub source: String,
+    pub paths: Vec<PathMapping>,
+    /// Retries of failed writes, see [super::retry]
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+impl SignalKConfig {
+    pub fn new(url: impl Into<String>, paths: Vec<PathMapping>) -> SignalKConfig {
+        SignalKConfig {
+            url: url.into(),
+            context: default_context(),
+            source: default_source(),
+            paths,
+            retry: RetryConfig::default(),
+        }
+    }
+
+    /// Delta message with all mapped fields of a measurement, `None` if no field is mapped
+    pub fn delta(&self, measurement: &Measurement) -> Option<Value> {
+        let values: Vec<Value> = self
+            .paths
+            .iter()
+            .filter(|mapping| mapping.matches(measurement))
+            .filter_map(|mapping| {
+                let value = measurement.field(&mapping.field)?;
+                Some(json!({"path": mapping.path, "value": mapping.value(value)}))
+            })
+            .collect();
+        if values.is_empty() {
+            return None;
+        }
+        let timestamp = measurement
+            .time
+            .unwrap_or_else(Utc::now)
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+        Some(json!({
+            "context": self.context,
+            "updates": [{
+                "source": {"label": self.source},
+                "timestamp": timestamp,
+                "values": values,
+            }],
+        }))
+    }
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    #[cfg(feature = "websocket")]
+    WebSocket(
+        Box<
+            tokio_tungstenite::WebSocketStream<
+                tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+            >,
+        >,
+    ),
+}
+
+/// Sink sending one delta per measurement
+pub struct SignalKSink {
+    config: SignalKConfig,
+    connection: Option<Connection>,
+}
+
+impl SignalKSink {
+    pub fn new(config: SignalKConfig) -> SignalKSink {
+        SignalKSink {
+            config,
+            connection: None,
+        }
+    }
+
+    async fn connect(&self) -> anyhow::Result<Connection> {
+        let retryable = |e: &dyn ToString| SinkError::Retryable(e.to_string());
+        if let Some(address) = self.config.url.strip_prefix("udp://") {
+            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            socket.connect(address).await.map_err(|e| retryable(&e))?;
+            return Ok(Connection::Udp(socket));
+        }
+        #[cfg(feature = "websocket")]
+        if self.config.url.starts_with("ws://") {
+            // Deltas of the server are not of interest
+            let url = if self.config.url.contains('?') {
+                self.config.url.clone()
+            } else {
+                format!("{}?subscribe=none", self.config.url)
+            };
+            let (stream, _) = tokio_tungstenite::connect_async(url)
+                .await
+                .map_err(|e| retryable(&e))?;
+            return Ok(Connection::WebSocket(Box::new(stream)));
+        }
+        Err(SinkError::Fatal(format!("Unsupported Signal K URL {}", self.config.url)).into())
+    }
+}
+
+#[async_trait]
+impl OutputSink for SignalKSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        self.connection = Some(self.connect().await?);
+        Ok(())
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let Some(delta) = self.config.delta(measurement) else {
+            return Ok(());
+        };
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => self.connection.insert(self.connect().await?),
+        };
+        let res = match connection {
+            Connection::Udp(socket) => socket
+                .send(delta.to_string().as_bytes())
+                .await
+                .map(|_| ())
+                .map_err(|e| SinkError::Retryable(e.to_string())),
+            #[cfg(feature = "websocket")]
+            Connection::WebSocket(stream) => {
+                use futures_util::SinkExt;
+                stream
+                    .send(tokio_tungstenite::tungstenite::Message::Text(
+                        delta.to_string(),
+                    ))
+                    .await
+                    .map_err(|e| SinkError::Retryable(e.to_string()))
+            }
+        };
+        if res.is_err() {
+            // Reconnect on the next write
+            self.connection = None;
+        }
+        Ok(res?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PathMapping, SignalKConfig, SignalKSink};
+    use crate::{output::OutputSink, Measurement};
+    use chrono::DateTime;
+    use serde_json::{json, Value};
+
+    fn config(url: &str) -> SignalKConfig {
+        toml::from_str(&format!(
+            r#"
+            url = "{}"
+            [[paths]]
+            measurement = "weather"
+            field = "air_temperature"
+            path = "environment.outside.temperature"
+            offset = 273.15
+            [[paths]]
+            measurement = "weather"
+            field = "station_pressure"
+            path = "environment.outside.pressure"
+            scale = 100
+            [[paths]]
+            measurement = "environment"
+            field = "humidity"
+            tags = {{ location = "cabin" }}
+            path = "environment.inside.relativeHumidity"
+            scale = 0.01
+            "#,
+            url
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_delta() {
+        let config = config("udp://127.0.0.1:4123");
+        let measurement = Measurement::new("weather")
+            .add_field("air_temperature", 20.)
+            .add_field("station_pressure", 1013i64)
+            .add_field("wind_avg", 3.2)
+            .add_time(DateTime::from_timestamp(1700000000, 0));
+        assert_eq!(
+            config.delta(&measurement).unwrap(),
+            json!({
+                "context": "vessels.self",
+                "updates": [{
+                    "source": {"label": "sensorflow"},
+                    "timestamp": "2023-11-14T22:13:20.000Z",
+                    "values": [
+                        {"path": "environment.outside.temperature", "value": 293.15},
+                        {"path": "environment.outside.pressure", "value": 101300.0},
+                    ],
+                }],
+            })
+        );
+
+        let cockpit = Measurement::new("environment")
+            .add_tag("location", "cockpit")
+            .add_field("humidity", 60.);
+        assert_eq!(config.delta(&cockpit), None);
+        let cabin = Measurement::new("environment")
+            .add_tag("location", "cabin")
+            .add_field("humidity", 60.);
+        assert_eq!(
+            config.delta(&cabin).unwrap()["updates"][0]["values"][0]["value"],
+            json!(0.6)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_udp() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut sink = SignalKSink::new(config(&format!("udp://{}", server.local_addr().unwrap())));
+        sink.start().await.unwrap();
+        sink.write(&Measurement::new("other").add_field("x", 1.))
+            .await
+            .unwrap();
+        sink.write(&Measurement::new("weather").add_field("air_temperature", 0.))
+            .await
+            .unwrap();
+
+        let mut buffer = [0; 1024];
+        let len = server.recv(&mut buffer).await.unwrap();
+        let delta: Value = serde_json::from_slice(&buffer[..len]).unwrap();
+        assert_eq!(delta["updates"][0]["values"][0]["value"], json!(273.15));
+    }
+
+    #[tokio::test]
+    async fn test_reject_unsupported_url() {
+        let mut sink = SignalKSink::new(SignalKConfig::new(
+            "http://localhost",
+            vec![PathMapping {
+                measurement: "m".into(),
+                field: "f".into(),
+                tags: Default::default(),
+                path: "p".into(),
+                scale: 1.,
+                offset: 0.,
+            }],
+        ));
+        assert!(sink.start().await.is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! StatsD gauges over UDP.
+//!
+//! Every numeric or boolean field is sent as gauge named `<prefix>.<measurement>.<field>`, e.g.
+//! `tempHum.temperature:21.5|g`. Tags are attached in the Influx style understood by the statsd
+//! input of Telegraf (`tempHum.temperature,sensorId=12:21.5|g`) or as DogStatsD extension
+//! (`tempHum.temperature:21.5|g|#sensorId:12`, Telegraf with `datadog_extensions = true`).
+//! String fields are not sent. Gauges of one measurement are packed into as few datagrams as
+//! the packet size allows.
+use super::{error::SinkError, retry::RetryConfig, OutputSink};
+use crate::measurement::{FieldValue, Measurement};
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+
+fn default_address() -> String {
+    "127.0.0.1:8125".into()
+}
+
+fn default_max_packet_size() -> usize {
+    // Fits into the MTU of Ethernet without fragmentation
+    1432
+}
+
+/// How tags are attached to metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum TagFormat {
+    /// Appended to the name, `name,tag=value:1|g`
+    #[default]
+    Influx,
+    /// `name:1|g|#tag:value`
+    Dogstatsd,
+    /// Tags are dropped
+    None,
+}
+
+/// Server and format of the metrics
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct StatsdConfig {
+    #[serde(default = "default_address")]
+    pub address: String,
+    /// Prepended to all metric names
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub tag_format: TagFormat,
+    /// Upper bound of the size of a datagram in bytes
+    #[serde(default = "default_max_packet_size")]
+    pub max_packet_size: usize,
+    /// Retries of failed writes, see [super::retry]
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        StatsdConfig {
+            address: default_address(),
+            prefix: None,
+            tag_format: TagFormat::default(),
+            max_packet_size: default_max_packet_size(),
+            retry: RetryConfig::default(),
+        }
+    }
+}
+
+/// Replace characters with a meaning in the StatsD protocol
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            ':' | '|' | ',' | '=' | '#' | '@' | '\n' | ' ' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+impl StatsdConfig {
+    /// Gauges of all numeric and boolean fields of a measurement
+    pub fn gauges(&self, measurement: &Measurement) -> Vec<String> {
+        let mut name = String::new();
+        if let Some(prefix) = &self.prefix {
+            name.push_str(&sanitize(prefix));
+            name.push('.');
+        }
+        name.push_str(&sanitize(&measurement.name));
+        let tags = measurement
+            .tags
+            .iter()
+            .map(|(key, value)| (sanitize(key), sanitize(value)));
+        let (infix, suffix) = match self.tag_format {
+            TagFormat::Influx => (
+                tags.map(|(key, value)| format!(",{}={}", key, value))
+                    .collect(),
+                String::new(),
+            ),
+            TagFormat::Dogstatsd if !measurement.tags.is_empty() => (
+                String::new(),
+                format!(
+                    "|#{}",
+                    tags.map(|(key, value)| format!("{}:{}", key, value))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            ),
+            TagFormat::Dogstatsd | TagFormat::None => (String::new(), String::new()),
+        };
+        let mut gauges = Vec::new();
+        for (field, value) in &measurement.fields {
+            let value = match value {
+                FieldValue::Float(x) if x.is_finite() => x.to_string(),
+                FieldValue::Integer(x) => x.to_string(),
+                FieldValue::UInteger(x) => x.to_string(),
+                FieldValue::Boolean(b) => u8::from(*b).to_string(),
+                FieldValue::Float(_) | FieldValue::String(_) => continue,
+            };
+            let metric = format!("{}.{}{}", name, sanitize(field), infix);
+            // A leading sign changes a gauge relative to its last value, so negative values
+            // are sent after resetting the gauge
+            if value.starts_with('-') {
+                gauges.push(format!("{}:0|g{}", metric, suffix));
+            }
+            gauges.push(format!("{}:{}|g{}", metric, value, suffix));
+        }
+        gauges
+    }
+}
+
+/// Join lines into datagrams of at most `max_size` bytes. Lines exceeding the limit are sent
+/// on their own.
+fn packets(lines: Vec<String>, max_size: usize) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= max_size => {
+                packet.push('\n');
+                packet.push_str(&line);
+            }
+            _ => packets.push(line),
+        }
+    }
+    packets
+}
+
+/// Sink sending gauges to a StatsD server
+pub struct StatsdSink {
+    config: StatsdConfig,
+    socket: Option<UdpSocket>,
+}
+
+impl StatsdSink {
+    pub fn new(config: StatsdConfig) -> StatsdSink {
+        StatsdSink {
+            config,
+            socket: None,
+        }
+    }
+
+    async fn connect(&self) -> anyhow::Result<UdpSocket> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket
+            .connect(&self.config.address)
+            .await
+            .map_err(|e| SinkError::Retryable(e.to_string()))?;
+        Ok(socket)
+    }
+}
+
+#[async_trait]
+impl OutputSink for StatsdSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        self.socket = Some(self.connect().await?);
+        Ok(())
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let gauges = self.config.gauges(measurement);
+        if gauges.is_empty() {
+            return Ok(());
+        }
+        let socket = match self.socket.as_mut() {
+            Some(socket) => socket,
+            None => self.socket.insert(self.connect().await?),
+        };
+        for packet in packets(gauges, self.config.max_packet_size) {
+            if let Err(e) = socket.send(packet.as_bytes()).await {
+                // Resolve the address again on the next write
+                self.socket = None;
+                return Err(SinkError::Retryable(e.to_string()).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{packets, StatsdConfig, StatsdSink, TagFormat};
+    use crate::{output::OutputSink, Measurement};
+
+    fn measurement() -> Measurement {
+        Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_tag("room", "living room")
+            .add_field("temperature", -1.5)
+            .add_field("humidity", 45u64)
+            .add_field("battery_low", false)
+            .add_field("model", "LaCrosse")
+    }
+
+    #[test]
+    fn test_gauges() {
+        let config = StatsdConfig::default();
+        assert_eq!(
+            config.gauges(&measurement()),
+            vec![
+                "tempHum.temperature,sensorId=12,room=living_room:0|g",
+                "tempHum.temperature,sensorId=12,room=living_room:-1.5|g",
+                "tempHum.humidity,sensorId=12,room=living_room:45|g",
+                "tempHum.battery_low,sensorId=12,room=living_room:0|g",
+            ]
+        );
+        let config = StatsdConfig {
+            prefix: Some("home".into()),
+            tag_format: TagFormat::Dogstatsd,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.gauges(&measurement())[2],
+            "home.tempHum.humidity:45|g|#sensorId:12,room:living_room"
+        );
+        let config = StatsdConfig {
+            tag_format: TagFormat::None,
+            ..Default::default()
+        };
+        assert_eq!(config.gauges(&measurement())[2], "tempHum.humidity:45|g");
+    }
+
+    #[test]
+    fn test_packets() {
+        let lines = vec!["a:1|g".to_string(), "b:2|g".into(), "c:3|g".into()];
+        assert_eq!(packets(lines.clone(), 11), vec!["a:1|g\nb:2|g", "c:3|g"]);
+        assert_eq!(packets(lines, 4), vec!["a:1|g", "b:2|g", "c:3|g"]);
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut sink = StatsdSink::new(StatsdConfig {
+            address: server.local_addr().unwrap().to_string(),
+            tag_format: TagFormat::None,
+            ..Default::default()
+        });
+        sink.start().await.unwrap();
+        sink.write(&Measurement::new("power").add_field("power", 42.5))
+            .await
+            .unwrap();
+
+        let mut buffer = [0; 1500];
+        let len = server.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..len], b"power.power:42.5|g");
+    }
+}
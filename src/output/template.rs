@@ -0,0 +1,126 @@
+//! Text output rendered by a user-supplied template.
+//!
+//! Each measurement is rendered by a [Tera](https://keats.github.io/tera/) template, which allows
+//! arbitrary text formats like custom CSV or KNX group writes. The context of the template is the
+//! object of [super::json::to_json] with the additional key `time`, the time as RFC 3339 string
+//! or null:
+//!
+//! ```text
+//! {{ timestamp }};{{ tags.sensorId }};{{ fields.temperature }}
+//! ```
+//!
+//! The output is written as rendered, so the template has to end with a newline to get one line
+//! per measurement. Requires the `template` feature.
+use super::{
+    json::to_json,
+    writer::{AsyncWriterSink, Encode},
+};
+use crate::measurement::Measurement;
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+const NAME: &str = "measurement";
+
+/// Compiled template
+#[derive(Debug, Clone)]
+pub struct Template {
+    tera: tera::Tera,
+}
+
+impl Template {
+    pub fn new(source: &str) -> anyhow::Result<Template> {
+        let mut tera = tera::Tera::default();
+        tera.add_raw_template(NAME, source)?;
+        Ok(Template { tera })
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Template> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read template {}", path.display()))?;
+        Template::new(&source).with_context(|| format!("Invalid template {}", path.display()))
+    }
+
+    /// Text of a measurement
+    pub fn render(&self, measurement: &Measurement) -> anyhow::Result<String> {
+        let mut context = to_json(measurement);
+        context["time"] = measurement
+            .time
+            .map(|time| Value::from(time.to_rfc3339()))
+            .unwrap_or(Value::Null);
+        Ok(self
+            .tera
+            .render(NAME, &tera::Context::from_value(context)?)?)
+    }
+}
+
+/// Template file of the output
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct TemplateConfig {
+    pub path: PathBuf,
+}
+
+impl Encode for Template {
+    fn encode(&self, measurement: &Measurement) -> anyhow::Result<String> {
+        self.render(measurement)
+    }
+}
+
+/// Sink writing measurements rendered by a template
+pub type TemplateSink<W> = AsyncWriterSink<W, Template>;
+
+#[cfg(test)]
+mod test {
+    use super::{Template, TemplateSink};
+    use crate::{output::OutputSink, Measurement};
+    use chrono::DateTime;
+
+    fn measurement() -> Measurement {
+        Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.5)
+            .add_field("humidity", 45u64)
+            .add_time(DateTime::from_timestamp(1700000000, 0))
+    }
+
+    #[test]
+    fn test_render() {
+        let template = Template::new(
+            "{{ time }};{{ measurement }};{{ tags.sensorId }};{{ fields.temperature }}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            template.render(&measurement()).unwrap(),
+            "2023-11-14T22:13:20+00:00;tempHum;12;21.5\n"
+        );
+
+        let template = Template::new(
+            "{% for key, value in fields %}{{ key }}={{ value }}{% if not loop.last %},{% endif %}\
+             {% endfor %}",
+        )
+        .unwrap();
+        assert_eq!(
+            template.render(&measurement()).unwrap(),
+            "humidity=45,temperature=21.5"
+        );
+
+        // Missing values are errors
+        let template = Template::new("{{ fields.pressure }}").unwrap();
+        assert!(template.render(&measurement()).is_err());
+        assert!(Template::new("{{ fields.temperature").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sink() {
+        let template = Template::new("{{ tags.sensorId }} {{ fields.humidity }}\n").unwrap();
+        let mut output = Vec::new();
+        let mut sink = TemplateSink::new(&mut output, template);
+        sink.write(&measurement()).await.unwrap();
+        sink.shutdown().await.unwrap();
+        drop(sink);
+        assert_eq!(output, b"12 45\n");
+    }
+}
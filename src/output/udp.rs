@@ -0,0 +1,151 @@
+//! Measurements as JSON datagrams on the local network.
+//!
+//! Like the hubs of WeatherFlow weather stations, the sink sends every measurement as a
+//! datagram with an object of [super::json::to_json] to a broadcast address or multicast group,
+//! so listeners on the LAN need no configuration besides the port:
+//!
+//! ```toml
+//! [[outputs]]
+//! type = "udp"
+//! address = "239.255.42.1:50222"
+//! ttl = 2
+//! ```
+//!
+//! Nothing is sent back, so datagrams lost on the way are lost for good. A measurement exceeding
+//! the size of a datagram fails the write.
+use super::{error::SinkError, json::to_json, retry::RetryConfig, OutputSink};
+use crate::measurement::Measurement;
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+fn default_address() -> String {
+    "255.255.255.255:50222".into()
+}
+
+fn default_ttl() -> u32 {
+    // Stay within the local network
+    1
+}
+
+/// Destination of the datagrams
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct UdpConfig {
+    /// Broadcast address, multicast group or single listener with port
+    #[serde(default = "default_address")]
+    pub address: String,
+    /// Number of routers datagrams to a multicast group may pass
+    #[serde(default = "default_ttl")]
+    pub ttl: u32,
+    /// Retries of failed writes, see [super::retry]
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        UdpConfig {
+            address: default_address(),
+            ttl: default_ttl(),
+            retry: RetryConfig::default(),
+        }
+    }
+}
+
+/// Sink sending a JSON datagram per measurement
+pub struct UdpSink {
+    config: UdpConfig,
+    socket: Option<(UdpSocket, SocketAddr)>,
+}
+
+impl UdpSink {
+    pub fn new(config: UdpConfig) -> UdpSink {
+        UdpSink {
+            config,
+            socket: None,
+        }
+    }
+
+    async fn connect(&self) -> anyhow::Result<(UdpSocket, SocketAddr)> {
+        let target = tokio::net::lookup_host(&self.config.address)
+            .await
+            .map_err(|e| SinkError::Retryable(e.to_string()))?
+            .next()
+            .with_context(|| format!("No address for {}", self.config.address))?;
+        let socket = match target {
+            SocketAddr::V4(target) => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.set_broadcast(true)?;
+                if target.ip().is_multicast() {
+                    socket.set_multicast_ttl_v4(self.config.ttl)?;
+                }
+                socket
+            }
+            SocketAddr::V6(_) => UdpSocket::bind("[::]:0").await?,
+        };
+        Ok((socket, target))
+    }
+}
+
+#[async_trait]
+impl OutputSink for UdpSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        self.socket = Some(self.connect().await?);
+        Ok(())
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let datagram = to_json(measurement).to_string();
+        let (socket, target) = match self.socket.as_ref() {
+            Some(socket) => socket,
+            None => self.socket.insert(self.connect().await?),
+        };
+        if let Err(e) = socket.send_to(datagram.as_bytes(), *target).await {
+            // Resolve the address again on the next write
+            self.socket = None;
+            return Err(SinkError::Retryable(e.to_string()).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{UdpConfig, UdpSink};
+    use crate::{output::OutputSink, Measurement};
+    use chrono::DateTime;
+
+    #[test]
+    fn test_config() {
+        let config: UdpConfig = toml::from_str("").unwrap();
+        assert_eq!(config, UdpConfig::default());
+        assert_eq!(config.address, "255.255.255.255:50222");
+        assert!(toml::from_str::<UdpConfig>("port = 50222").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let listener = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut sink = UdpSink::new(UdpConfig {
+            address: listener.local_addr().unwrap().to_string(),
+            ..Default::default()
+        });
+        sink.start().await.unwrap();
+        let measurement = Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.5)
+            .add_time(DateTime::from_timestamp(1700000000, 0));
+        sink.write(&measurement).await.unwrap();
+
+        let mut buffer = [0; 1500];
+        let len = listener.recv(&mut buffer).await.unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buffer[..len]).unwrap(),
+            r#"{"fields":{"temperature":21.5},"measurement":"tempHum","tags":{"sensorId":"12"},"timestamp":1700000000000}"#
+        );
+    }
+}
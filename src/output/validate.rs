@@ -0,0 +1,294 @@
+//! Validation of live streams without writing measurements anywhere.
+//!
+//! A [Validator] replaces all outputs of a pipeline by its [ValidatorSink] and wraps all inputs,
+//! so frames which fail to parse are recorded instead of stopping the input. Every minute, a
+//! report of the received measurements and of the parse errors is printed to stdout, with a
+//! hexdump of the offending bytes of the first errors. Inputs in diagnostics mode add the
+//! counts of their frame and garbage bytes and a hexdump of the garbage, see [FrameStats].
+use super::{OutputSink, ToOutput};
+use crate::{
+    clock::Clock,
+    devices::{Device, DeviceHealth},
+    error::InvalidFrame,
+    input::{hexdump, FrameStats},
+    Measurement,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+
+/// Period of the reports
+const INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of parse errors per report shown with their bytes
+const MAX_SAMPLES: usize = 5;
+
+/// Frame which failed to parse
+struct Sample {
+    device: String,
+    reason: String,
+    bytes: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Stats {
+    /// Valid measurements by name
+    measurements: BTreeMap<String, u64>,
+    errors: u64,
+    samples: Vec<Sample>,
+    /// Bytes received by the inputs in diagnostics mode, since they were opened
+    frames: BTreeMap<String, FrameStats>,
+    /// Garbage received by the inputs in diagnostics mode
+    discarded: Vec<Sample>,
+}
+
+/// Statistics shared by the wrapped inputs and the sink
+#[derive(Clone, Default)]
+pub struct Validator {
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl Validator {
+    pub fn new() -> Validator {
+        Validator::default()
+    }
+
+    /// Wrap an input, recording frames it fails to parse instead of failing
+    pub fn wrap(&self, input: Box<dyn Device>) -> Box<dyn Device> {
+        Box::new(ValidatedInput {
+            input,
+            validator: self.clone(),
+            discarded: 0,
+        })
+    }
+
+    /// Sink counting measurements and printing the reports
+    pub fn sink(&self) -> ValidatorSink {
+        ValidatorSink {
+            validator: self.clone(),
+            task: None,
+        }
+    }
+
+    fn stats(&self) -> std::sync::MutexGuard<'_, Stats> {
+        self.stats.lock().expect("stats lock poisoned")
+    }
+
+    /// Report of the statistics since the last report, which are reset
+    pub fn report(&self, now: DateTime<Utc>) -> String {
+        let stats = std::mem::take(&mut *self.stats());
+        let total: u64 = stats.measurements.values().sum();
+        let mut report = format!(
+            "{}: {} measurements ok, {} parse errors\n",
+            now.format("%Y-%m-%d %H:%M:%S"),
+            total,
+            stats.errors
+        );
+        for (name, count) in &stats.measurements {
+            let _ = writeln!(report, "  {}: {}", name, count);
+        }
+        for (device, frames) in &stats.frames {
+            let _ = writeln!(report, "{}: {}", device, frames);
+        }
+        for sample in stats.samples.iter().chain(&stats.discarded) {
+            let _ = writeln!(report, "{}: {}", sample.device, sample.reason);
+            report.push_str(&hexdump(&sample.bytes));
+        }
+        report
+    }
+}
+
+/// Input recording parse errors to a [Validator]
+struct ValidatedInput {
+    input: Box<dyn Device>,
+    validator: Validator,
+    /// Samples of garbage recorded so far
+    discarded: usize,
+}
+
+impl ValidatedInput {
+    fn record_frames(&mut self) {
+        let Some(frames) = self.input.frame_stats() else {
+            return;
+        };
+        let device = format!("{} at {}", self.input.name(), self.input.address());
+        let mut stats = self.validator.stats();
+        for bytes in frames.discarded.iter().skip(self.discarded) {
+            stats.discarded.push(Sample {
+                device: device.clone(),
+                reason: format!("Discarded {} bytes", bytes.len()),
+                bytes: bytes.clone(),
+            });
+        }
+        self.discarded = frames.discarded.len();
+        stats.frames.insert(device, frames);
+    }
+}
+
+#[async_trait]
+impl Device for ValidatedInput {
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        loop {
+            let res = self.input.read_frame().await;
+            self.record_frames();
+            let error = match res {
+                Err(error) => error,
+                res => return res,
+            };
+            let Some(InvalidFrame(bytes)) = error.downcast_ref::<InvalidFrame>() else {
+                return Err(error);
+            };
+            let mut stats = self.validator.stats();
+            stats.errors += 1;
+            if stats.samples.len() < MAX_SAMPLES {
+                stats.samples.push(Sample {
+                    device: format!("{} at {}", self.input.name(), self.input.address()),
+                    reason: error.root_cause().to_string(),
+                    bytes: bytes.clone(),
+                });
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.input.name()
+    }
+
+    fn address(&self) -> &str {
+        self.input.address()
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.input.health()
+    }
+
+    fn frame_stats(&self) -> Option<FrameStats> {
+        self.input.frame_stats()
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.input.set_clock(clock)
+    }
+}
+
+/// Sink counting measurements instead of writing them
+pub struct ValidatorSink {
+    validator: Validator,
+    task: Option<JoinHandle<()>>,
+}
+
+async fn print(report: String) -> anyhow::Result<()> {
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(report.as_bytes()).await?;
+    stdout.flush().await?;
+    Ok(())
+}
+
+#[async_trait]
+impl OutputSink for ValidatorSink {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        let validator = self.validator.clone();
+        self.task = Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if print(validator.report(Utc::now())).await.is_err() {
+                    return;
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        *self
+            .validator
+            .stats()
+            .measurements
+            .entry(measurement.name.clone())
+            .or_default() += 1;
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        print(self.validator.report(Utc::now())).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Validator;
+    use crate::{
+        devices::Device,
+        error::InvalidFrame,
+        output::{OutputSink, ToOutput},
+        pipeline::{ChannelConfig, Pipeline},
+        Measurement,
+    };
+    use anyhow::anyhow;
+    use async_trait::async_trait;
+    use chrono::DateTime;
+
+    /// Input failing to parse every second frame
+    struct Flaky(u8);
+
+    #[async_trait]
+    impl Device for Flaky {
+        async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+            self.0 += 1;
+            match self.0 {
+                1 | 3 => Ok(Some(Box::new(Measurement::new("tempHum")))),
+                2 => Err(anyhow!("Invalid characters").context(InvalidFrame(b"OK 9\xff".to_vec()))),
+                _ => Ok(None),
+            }
+        }
+
+        fn name(&self) -> &str {
+            "flaky"
+        }
+
+        fn address(&self) -> &str {
+            "memory"
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validator() {
+        let validator = Validator::new();
+        Pipeline::new(ChannelConfig::default())
+            .add_input(validator.wrap(Box::new(Flaky(0))))
+            .add_output(Box::new(validator.sink()))
+            .run()
+            .await
+            .unwrap();
+
+        // The parse error did not stop the input and the sink reported on shutdown
+        let now = DateTime::from_timestamp(1700000000, 0).unwrap();
+        assert_eq!(
+            validator.report(now),
+            "2023-11-14 22:13:20: 0 measurements ok, 0 parse errors\n"
+        );
+
+        let validator = Validator::new();
+        let mut input = validator.wrap(Box::new(Flaky(0)));
+        while input.read_frame().await.unwrap().is_some() {}
+        let mut sink = validator.sink();
+        sink.write(&Measurement::new("tempHum")).await.unwrap();
+        assert_eq!(
+            validator.report(now),
+            "2023-11-14 22:13:20: 1 measurements ok, 1 parse errors\n  \
+             tempHum: 1\n\
+             flaky at memory: Invalid characters\n\
+             00000000  4f 4b 20 39 ff                                   |OK 9.|\n"
+        );
+    }
+}
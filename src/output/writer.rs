@@ -0,0 +1,197 @@
+//! Sinks writing serialized measurements to arbitrary writers.
+//!
+//! Files, pipes and sockets can be targets without dedicated sinks: [AsyncWriterSink] writes to
+//! any [AsyncWrite], [WriterSink] to any blocking [std::io::Write], both in a serialization
+//! implementing [Encode], e.g. [Format] or, with the `template` feature, a
+//! [super::template::Template].
+use super::{influx::LineProtocol, json::to_json, OutputSink};
+use crate::measurement::Measurement;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::io::Write;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+
+/// Serialization of measurements into text
+pub trait Encode: Send {
+    /// Text of a measurement, including the newline of line based formats
+    fn encode(&self, measurement: &Measurement) -> anyhow::Result<String>;
+}
+
+/// Built-in line based formats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// Objects of [super::json::to_json]
+    #[default]
+    Json,
+    /// InfluxDB line protocol
+    LineProtocol,
+    /// Human readable form
+    Stringify,
+}
+
+impl Format {
+    /// Line of a measurement, including the newline
+    ///
+    /// Empty for line protocol if the measurement has no finite field.
+    pub fn line(&self, measurement: &Measurement) -> String {
+        match self {
+            Format::Json => format!("{}\n", to_json(measurement)),
+            Format::LineProtocol => {
+                let line = LineProtocol::from(measurement);
+                if line.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}\n", line)
+                }
+            }
+            Format::Stringify => format!("{}\n", measurement),
+        }
+    }
+}
+
+impl Encode for Format {
+    fn encode(&self, measurement: &Measurement) -> anyhow::Result<String> {
+        Ok(self.line(measurement))
+    }
+}
+
+/// Sink writing encoded measurements to an asynchronous writer
+pub struct AsyncWriterSink<W: AsyncWrite, E: Encode> {
+    writer: BufWriter<W>,
+    encoder: E,
+}
+
+impl<W: AsyncWrite, E: Encode> AsyncWriterSink<W, E> {
+    pub fn new(writer: W, encoder: E) -> AsyncWriterSink<W, E> {
+        AsyncWriterSink {
+            writer: BufWriter::new(writer),
+            encoder,
+        }
+    }
+}
+
+impl<E: Encode> AsyncWriterSink<tokio::io::Stdout, E> {
+    pub fn stdout(encoder: E) -> Self {
+        Self::new(tokio::io::stdout(), encoder)
+    }
+}
+
+#[async_trait]
+impl<W: AsyncWrite + Unpin + Send, E: Encode> OutputSink for AsyncWriterSink<W, E> {
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let text = self.encoder.encode(measurement)?;
+        self.writer.write_all(text.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// Sink writing encoded measurements to a blocking writer.
+///
+/// Writes block the task of the sink, so the writer should not block for long, like files or
+/// pipes to a fast reader.
+pub struct WriterSink<W: Write, E: Encode> {
+    writer: std::io::BufWriter<W>,
+    encoder: E,
+}
+
+impl<W: Write, E: Encode> WriterSink<W, E> {
+    pub fn new(writer: W, encoder: E) -> WriterSink<W, E> {
+        WriterSink {
+            writer: std::io::BufWriter::new(writer),
+            encoder,
+        }
+    }
+
+    /// Flush and return the writer
+    pub fn into_inner(self) -> anyhow::Result<W> {
+        self.writer.into_inner().map_err(|e| e.into_error().into())
+    }
+
+    /// Write of both the async and the blocking sink
+    pub(crate) fn write_measurement(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        let text = self.encoder.encode(measurement)?;
+        self.writer.write_all(text.as_bytes())?;
+        Ok(())
+    }
+
+    pub(crate) fn flush_writer(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<W: Write + Send, E: Encode> OutputSink for WriterSink<W, E> {
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        self.write_measurement(measurement)
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        self.flush_writer()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AsyncWriterSink, Format, WriterSink};
+    use crate::{output::OutputSink, Measurement};
+    use chrono::DateTime;
+
+    fn measurement() -> Measurement {
+        Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.5)
+            .add_time(DateTime::from_timestamp(1700000000, 0))
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            Format::Json.line(&measurement()),
+            "{\"fields\":{\"temperature\":21.5},\"measurement\":\"tempHum\",\
+             \"tags\":{\"sensorId\":\"12\"},\"timestamp\":1700000000000}\n"
+        );
+        assert_eq!(
+            Format::LineProtocol.line(&measurement()),
+            "tempHum,sensorId=12 temperature=21.5 1700000000000000000\n"
+        );
+        assert_eq!(
+            Format::Stringify.line(&measurement()),
+            "tempHum sensorId=12: temperature=21.5 @ 2023-11-14T22:13:20+00:00\n"
+        );
+    }
+
+    #[test]
+    fn test_line_protocol_without_finite_fields() {
+        let measurement = Measurement::new("m").add_field("v", f64::NEG_INFINITY);
+        assert_eq!(Format::LineProtocol.line(&measurement), "");
+    }
+
+    #[tokio::test]
+    async fn test_writer_sinks() {
+        let mut sink = WriterSink::new(Vec::new(), Format::LineProtocol);
+        sink.write(&measurement()).await.unwrap();
+        sink.write(&measurement()).await.unwrap();
+        sink.shutdown().await.unwrap();
+        let output = String::from_utf8(sink.into_inner().unwrap()).unwrap();
+        assert_eq!(output.lines().count(), 2);
+
+        let (writer, mut reader) = tokio::io::duplex(1024);
+        let mut sink = AsyncWriterSink::new(writer, Format::Json);
+        sink.write(&measurement()).await.unwrap();
+        sink.shutdown().await.unwrap();
+        drop(sink);
+        let mut output = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut reader, &mut output)
+            .await
+            .unwrap();
+        assert_eq!(output, Format::Json.line(&measurement()));
+    }
+}
@@ -0,0 +1,1321 @@
+//! Wiring of inputs, transforms and outputs.
+//!
+//! Every stage of a pipeline runs as its own asynchronous task. Stages are connected by bounded
+//! queues, so a slow output can not cause unbounded memory growth. What happens once a queue is
+//! full is controlled by its [OverflowPolicy].
+//!
+//! Every output is written by its own worker task, fed by an inbox with the capacity and
+//! overflow policy of the other queues. A stalled output delays the others only once its inbox
+//! is full and the policy blocks, and [PipelineMetrics::outputs] shows how far each one lags.
+//!
+//! Inputs, transforms and outputs of a running pipeline can be changed through a
+//! [PipelineControl]. Queued measurements are kept, so they are not lost by a reconfiguration.
+//!
+//! Transforms are ticked every [TICK] with the time of the pipeline's [Clock], so they can add
+//! measurements while nothing arrives, e.g. alerts about silent sensors.
+use crate::{
+    clock::{Clock, SystemClock, TimestampPolicy},
+    devices::{Device, DeviceHealth},
+    error::{DeviceError, FrameCheckError, FrameParseError, FrameValidation, InvalidFrame},
+    input::FrameStats,
+    output::OutputSink,
+    transform::Transform,
+    Measurement,
+};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+pub use channel::{ChannelConfig, OverflowPolicy, QueueMetrics};
+
+pub mod channel;
+
+/// Interval of [Transform::tick]
+pub const TICK: Duration = Duration::from_secs(10);
+
+/// Identifier of an input or output. Inputs and outputs share one numbering in the order they
+/// are added, starting at 0, which continues for stages added through a [PipelineControl].
+pub type StageId = u64;
+
+/// Identity and health of an input device
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceStatus {
+    pub name: String,
+    pub address: String,
+    pub health: DeviceHealth,
+    /// Bytes and frames received, see [Device::frame_stats]
+    pub frames: Option<FrameStats>,
+}
+
+type Devices = Arc<Mutex<Vec<(StageId, DeviceStatus)>>>;
+
+/// Backlog of an output
+#[derive(Debug)]
+pub struct OutputMetrics {
+    /// Inbox of the worker writing to the output
+    pub inbox: Arc<QueueMetrics>,
+    /// Microseconds the last written measurement waited in the inbox
+    lag: AtomicU64,
+}
+
+impl OutputMetrics {
+    /// Time the last written measurement waited in the inbox
+    pub fn lag(&self) -> Duration {
+        Duration::from_micros(self.lag.load(Ordering::Relaxed))
+    }
+}
+
+type Outputs = Arc<Mutex<Vec<(StageId, Arc<OutputMetrics>)>>>;
+
+fn register(devices: &Devices, id: StageId, input: &dyn Device) {
+    devices.lock().expect("status lock poisoned").push((
+        id,
+        DeviceStatus {
+            name: input.name().into(),
+            address: input.address().into(),
+            health: input.health(),
+            frames: input.frame_stats(),
+        },
+    ));
+}
+
+fn unregister(devices: &Devices, id: StageId) {
+    devices
+        .lock()
+        .expect("status lock poisoned")
+        .retain(|(device, _)| *device != id);
+}
+
+/// Queue depth statistics of all stages and the state of all inputs
+#[derive(Clone)]
+pub struct PipelineMetrics {
+    /// Queue between inputs and transforms
+    pub transform_queue: Arc<QueueMetrics>,
+    /// Queue between transforms and the inboxes of the outputs
+    pub output_queue: Arc<QueueMetrics>,
+    devices: Devices,
+    outputs: Outputs,
+}
+
+impl PipelineMetrics {
+    /// Status of all inputs, in the order they were added
+    pub fn devices(&self) -> Vec<DeviceStatus> {
+        self.devices
+            .lock()
+            .expect("status lock poisoned")
+            .iter()
+            .map(|(_, status)| status.clone())
+            .collect()
+    }
+
+    /// Backlog of all running outputs, in the order they were started
+    pub fn outputs(&self) -> Vec<(StageId, Arc<OutputMetrics>)> {
+        self.outputs.lock().expect("status lock poisoned").clone()
+    }
+}
+
+/// All metrics on one line, e.g. for a periodic log
+impl std::fmt::Display for PipelineMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Transforms {}; outputs {}",
+            self.transform_queue, self.output_queue
+        )?;
+        for (id, output) in self.outputs() {
+            let lag = output.lag().as_secs_f64();
+            write!(f, "; output {} {}, lag {:.3} s", id, output.inbox, lag)?;
+        }
+        for device in self.devices() {
+            write!(
+                f,
+                "; {} on {} {:?}",
+                device.name, device.address, device.health
+            )?;
+            if let Some(frames) = device.frames {
+                write!(f, ", {}", frames)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Change {
+    AddInput(StageId, Box<dyn Device>),
+    RemoveInput(StageId, oneshot::Sender<()>),
+    ReplaceOutputs {
+        remove: Vec<StageId>,
+        add: Vec<(StageId, Box<dyn OutputSink>)>,
+        done: oneshot::Sender<Replaced>,
+    },
+}
+
+/// Outcome of [PipelineControl::replace_outputs]
+#[derive(Debug, Default)]
+pub struct Replaced {
+    /// Result of flushing and shutting down each removed output
+    pub removed: Vec<anyhow::Result<()>>,
+    /// Identifier of each added output, or why it failed to start
+    pub added: Vec<anyhow::Result<StageId>>,
+}
+
+/// Handle to change the stages of a pipeline while it runs, see [Pipeline::control]
+///
+/// As inputs may still be added, a pipeline keeps running after its inputs are exhausted as
+/// long as a handle exists.
+#[derive(Clone)]
+pub struct PipelineControl {
+    changes: mpsc::UnboundedSender<Change>,
+    transforms: mpsc::UnboundedSender<Vec<Box<dyn Transform>>>,
+    next_id: Arc<AtomicU64>,
+    devices: Devices,
+}
+
+impl PipelineControl {
+    fn not_running() -> anyhow::Error {
+        anyhow::anyhow!("Pipeline is not running")
+    }
+
+    /// Start reading from an input
+    pub fn add_input(&self, input: Box<dyn Device>) -> anyhow::Result<StageId> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        register(&self.devices, id, input.as_ref());
+        if self.changes.send(Change::AddInput(id, input)).is_err() {
+            unregister(&self.devices, id);
+            return Err(Self::not_running());
+        }
+        Ok(id)
+    }
+
+    /// Stop reading from an input and close it. Measurements it already read are passed on.
+    pub async fn remove_input(&self, id: StageId) {
+        let (done, removed) = oneshot::channel();
+        if self.changes.send(Change::RemoveInput(id, done)).is_ok() {
+            let _ = removed.await;
+        }
+    }
+
+    /// Shut down the outputs `remove` and start the outputs `add` in their place, in between
+    /// two measurements, so every measurement is written to either set.
+    pub async fn replace_outputs(
+        &self,
+        remove: Vec<StageId>,
+        add: Vec<Box<dyn OutputSink>>,
+    ) -> anyhow::Result<Replaced> {
+        let add = add
+            .into_iter()
+            .map(|output| (self.next_id.fetch_add(1, Ordering::Relaxed), output))
+            .collect();
+        let (done, replaced) = oneshot::channel();
+        self.changes
+            .send(Change::ReplaceOutputs { remove, add, done })
+            .map_err(|_| Self::not_running())?;
+        replaced.await.map_err(|_| Self::not_running())
+    }
+
+    /// Replace all transforms, for measurements not transformed yet
+    pub fn set_transforms(&self, transforms: Vec<Box<dyn Transform>>) -> anyhow::Result<()> {
+        self.transforms
+            .send(transforms)
+            .map_err(|_| Self::not_running())
+    }
+}
+
+pub struct Pipeline {
+    inputs: Vec<(StageId, Box<dyn Device>)>,
+    transforms: Vec<Box<dyn Transform>>,
+    outputs: Vec<(StageId, Box<dyn OutputSink>)>,
+    devices: Devices,
+    output_metrics: Outputs,
+    next_id: Arc<AtomicU64>,
+    config: ChannelConfig,
+    clock: Arc<dyn Clock>,
+    timestamps: TimestampPolicy,
+    input_tx: channel::Sender<Measurement>,
+    transform_rx: channel::Receiver<Measurement>,
+    transform_tx: channel::Sender<Measurement>,
+    output_rx: channel::Receiver<Measurement>,
+    changes_tx: mpsc::UnboundedSender<Change>,
+    changes_rx: mpsc::UnboundedReceiver<Change>,
+    transforms_tx: mpsc::UnboundedSender<Vec<Box<dyn Transform>>>,
+    transforms_rx: mpsc::UnboundedReceiver<Vec<Box<dyn Transform>>>,
+}
+
+impl Pipeline {
+    pub fn new(config: ChannelConfig) -> Pipeline {
+        let (input_tx, transform_rx) = channel::channel(config);
+        let (transform_tx, output_rx) = channel::channel(config);
+        let (changes_tx, changes_rx) = mpsc::unbounded_channel();
+        let (transforms_tx, transforms_rx) = mpsc::unbounded_channel();
+        Pipeline {
+            inputs: vec![],
+            transforms: vec![],
+            outputs: vec![],
+            devices: Default::default(),
+            output_metrics: Default::default(),
+            next_id: Default::default(),
+            config,
+            clock: Arc::new(SystemClock),
+            timestamps: TimestampPolicy::default(),
+            input_tx,
+            transform_rx,
+            transform_tx,
+            output_rx,
+            changes_tx,
+            changes_rx,
+            transforms_tx,
+            transforms_rx,
+        }
+    }
+
+    pub fn add_input(mut self, input: Box<dyn Device>) -> Pipeline {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        register(&self.devices, id, input.as_ref());
+        self.inputs.push((id, input));
+        self
+    }
+
+    /// Clock stamping measurements without a time and ticking the transforms, the system clock
+    /// by default
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Pipeline {
+        self.clock = clock;
+        self
+    }
+
+    /// Handling of the times carried by measurements, preserved by default
+    pub fn with_timestamps(mut self, timestamps: TimestampPolicy) -> Pipeline {
+        self.timestamps = timestamps;
+        self
+    }
+
+    pub fn add_transform(mut self, transform: Box<dyn Transform>) -> Pipeline {
+        self.transforms.push(transform);
+        self
+    }
+
+    pub fn add_output(mut self, output: Box<dyn OutputSink>) -> Pipeline {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.outputs.push((id, output));
+        self
+    }
+
+    pub fn metrics(&self) -> PipelineMetrics {
+        PipelineMetrics {
+            transform_queue: self.transform_rx.metrics(),
+            output_queue: self.output_rx.metrics(),
+            devices: self.devices.clone(),
+            outputs: self.output_metrics.clone(),
+        }
+    }
+
+    /// Handle to change the pipeline once it runs
+    pub fn control(&self) -> PipelineControl {
+        PipelineControl {
+            changes: self.changes_tx.clone(),
+            transforms: self.transforms_tx.clone(),
+            next_id: self.next_id.clone(),
+            devices: self.devices.clone(),
+        }
+    }
+
+    /// Run the pipeline until all inputs are exhausted.
+    pub async fn run(self) -> anyhow::Result<()> {
+        self.run_until(std::future::pending()).await
+    }
+
+    /// Run the pipeline until all inputs are exhausted or `shutdown` completes.
+    ///
+    /// On shutdown, inputs are stopped, all queued measurements are passed on to the outputs
+    /// and the outputs are shut down.
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> anyhow::Result<()> {
+        let Pipeline {
+            inputs,
+            transforms,
+            outputs,
+            devices,
+            output_metrics,
+            config,
+            clock,
+            timestamps,
+            input_tx,
+            transform_rx,
+            transform_tx,
+            output_rx,
+            changes_tx,
+            changes_rx,
+            transforms_tx,
+            transforms_rx,
+            ..
+        } = self;
+        // Only handles of the caller keep the pipeline changeable
+        drop((changes_tx, transforms_tx));
+
+        let (failed_tx, failed_rx) = mpsc::unbounded_channel();
+        let mut stages = Stages {
+            inputs: vec![],
+            outputs: vec![],
+            failed: failed_tx,
+            devices,
+            output_metrics,
+            config,
+            clock,
+            timestamps,
+            input_tx: Some(input_tx),
+        };
+        let mut started: Vec<(StageId, Box<dyn OutputSink>)> = Vec::new();
+        for (id, mut output) in outputs {
+            if let Err(e) = output.start().await {
+                // Release ports and announcements of the outputs already started
+                for (_, mut output) in started {
+                    let _ = output.shutdown().await;
+                }
+                return Err(e);
+            }
+            started.push((id, output));
+        }
+        for (id, output) in started {
+            stages.spawn_output(id, output);
+        }
+        for (id, input) in inputs {
+            stages.spawn_input(id, input);
+        }
+        let transform_task = tokio::spawn(apply_transforms(
+            transforms,
+            transforms_rx,
+            transform_rx,
+            transform_tx,
+            stages.clock.clone(),
+        ));
+
+        let mut res = stages.run(output_rx, changes_rx, failed_rx, shutdown).await;
+        if res.is_err() {
+            // Nothing is written anymore, inputs waiting for a frame would never return
+            stages.stop_inputs();
+        }
+        for (_, task) in std::mem::take(&mut stages.inputs) {
+            match task.await {
+                Ok(Err(e)) if res.is_ok() => res = Err(e),
+                Err(e) if e.is_panic() && res.is_ok() => res = Err(e.into()),
+                _ => (),
+            }
+        }
+        transform_task.await?;
+
+        for worker in std::mem::take(&mut stages.outputs) {
+            let shutdown_res = stages.stop_output(worker).await;
+            if res.is_ok() {
+                res = shutdown_res;
+            }
+        }
+        res
+    }
+}
+
+/// Measurement waiting in the inbox of an output since the given time
+type Delivery = (Instant, Arc<Measurement>);
+
+/// Task writing to an output
+struct OutputWorker {
+    id: StageId,
+    inbox: channel::Sender<Delivery>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+/// Write the measurements of `inbox` until it is closed, flushing whenever it ran empty or the
+/// output asks for it, and shut down the output. Stops at the first failure, closing the inbox
+/// and reporting `id` to `failed`.
+async fn write_output(
+    id: StageId,
+    mut output: Box<dyn OutputSink>,
+    mut inbox: channel::Receiver<Delivery>,
+    metrics: Arc<OutputMetrics>,
+    failed: mpsc::UnboundedSender<StageId>,
+) -> anyhow::Result<()> {
+    loop {
+        let next_flush = output.next_flush();
+        let written = tokio::select! {
+            delivery = inbox.recv() => {
+                let Some((queued, measurement)) = delivery else {
+                    break;
+                };
+                let lag = queued.elapsed().as_micros().try_into().unwrap_or(u64::MAX);
+                metrics.lag.store(lag, Ordering::Relaxed);
+                match output.write(&measurement).await {
+                    Ok(()) if inbox.is_empty() => output.flush().await,
+                    written => written,
+                }
+            }
+            _ = tokio::time::sleep_until(next_flush.unwrap_or_else(tokio::time::Instant::now)),
+                if next_flush.is_some() => output.flush().await,
+        };
+        if let Err(e) = written {
+            drop(inbox);
+            let _ = failed.send(id);
+            let _ = output.shutdown().await;
+            return Err(e);
+        }
+    }
+    output.shutdown().await
+}
+
+/// Running inputs and outputs
+struct Stages {
+    inputs: Vec<(StageId, JoinHandle<anyhow::Result<()>>)>,
+    outputs: Vec<OutputWorker>,
+    /// Outputs whose worker stopped at a failure
+    failed: mpsc::UnboundedSender<StageId>,
+    devices: Devices,
+    output_metrics: Outputs,
+    config: ChannelConfig,
+    clock: Arc<dyn Clock>,
+    timestamps: TimestampPolicy,
+    /// Kept while inputs may be added
+    input_tx: Option<channel::Sender<Measurement>>,
+}
+
+impl Stages {
+    /// Start the worker of a started output
+    fn spawn_output(&mut self, id: StageId, output: Box<dyn OutputSink>) {
+        let (inbox, rx) = channel::channel(self.config);
+        let metrics = Arc::new(OutputMetrics {
+            inbox: inbox.metrics(),
+            lag: AtomicU64::new(0),
+        });
+        self.output_metrics
+            .lock()
+            .expect("status lock poisoned")
+            .push((id, metrics.clone()));
+        let task = tokio::spawn(write_output(id, output, rx, metrics, self.failed.clone()));
+        self.outputs.push(OutputWorker { id, inbox, task });
+    }
+
+    /// Let the worker write the measurements in the inbox and shut down the output
+    async fn stop_output(&mut self, worker: OutputWorker) -> anyhow::Result<()> {
+        let OutputWorker { id, inbox, task } = worker;
+        drop(inbox);
+        let res = task.await;
+        self.output_metrics
+            .lock()
+            .expect("status lock poisoned")
+            .retain(|(output, _)| *output != id);
+        res?
+    }
+
+    fn spawn_input(&mut self, id: StageId, input: Box<dyn Device>) {
+        if let Some(tx) = &self.input_tx {
+            let status = StatusHandle {
+                devices: self.devices.clone(),
+                id,
+            };
+            let task = tokio::spawn(read_input(
+                input,
+                status,
+                self.clock.clone(),
+                self.timestamps,
+                tx.clone(),
+            ));
+            self.inputs.push((id, task));
+        }
+    }
+
+    /// Error of the output whose worker stopped at a failure
+    async fn fail_output(&mut self, id: StageId) -> anyhow::Result<()> {
+        if let Some(i) = self.outputs.iter().position(|worker| worker.id == id) {
+            let worker = self.outputs.remove(i);
+            self.stop_output(worker).await?;
+        }
+        Err(anyhow::anyhow!("Output stopped"))
+    }
+
+    /// Abort all inputs and close the queue once the measurements they already read are passed
+    /// on
+    fn stop_inputs(&mut self) {
+        for (_, task) in self.inputs.iter() {
+            task.abort();
+        }
+        self.input_tx = None;
+    }
+
+    async fn apply(&mut self, change: Change) {
+        match change {
+            Change::AddInput(id, input) => self.spawn_input(id, input),
+            Change::RemoveInput(id, done) => {
+                if let Some(i) = self.inputs.iter().position(|(input, _)| *input == id) {
+                    let (_, task) = self.inputs.remove(i);
+                    task.abort();
+                    let _ = task.await;
+                }
+                unregister(&self.devices, id);
+                let _ = done.send(());
+            }
+            Change::ReplaceOutputs { remove, add, done } => {
+                let mut replaced = Replaced::default();
+                for id in remove {
+                    let position = self.outputs.iter().position(|worker| worker.id == id);
+                    if let Some(i) = position {
+                        let worker = self.outputs.remove(i);
+                        replaced.removed.push(self.stop_output(worker).await);
+                    }
+                }
+                for (id, mut output) in add {
+                    match output.start().await {
+                        Ok(()) => {
+                            self.spawn_output(id, output);
+                            replaced.added.push(Ok(id));
+                        }
+                        Err(e) => replaced.added.push(Err(e)),
+                    }
+                }
+                let _ = done.send(replaced);
+            }
+        }
+    }
+
+    /// Write measurements to the outputs and apply changes until the queue is closed
+    async fn run(
+        &mut self,
+        mut rx: channel::Receiver<Measurement>,
+        mut changes: mpsc::UnboundedReceiver<Change>,
+        mut failed: mpsc::UnboundedReceiver<StageId>,
+        shutdown: impl Future<Output = ()>,
+    ) -> anyhow::Result<()> {
+        tokio::pin!(shutdown);
+        let mut changeable = true;
+        let mut running = true;
+        loop {
+            tokio::select! {
+                measurement = rx.recv() => {
+                    let Some(measurement) = measurement else {
+                        return Ok(());
+                    };
+                    let measurement = Arc::new(measurement);
+                    let mut closed = None;
+                    for worker in self.outputs.iter() {
+                        let delivery = (Instant::now(), measurement.clone());
+                        if worker.inbox.send(delivery).await.is_err() {
+                            closed = Some(worker.id);
+                            break;
+                        }
+                    }
+                    // The inbox is closed once the worker failed to write
+                    if let Some(id) = closed {
+                        return self.fail_output(id).await;
+                    }
+                }
+                Some(id) = failed.recv() => return self.fail_output(id).await,
+                change = changes.recv(), if changeable => match change {
+                    Some(change) => self.apply(change).await,
+                    None => {
+                        changeable = false;
+                        self.input_tx = None;
+                    }
+                },
+                _ = &mut shutdown, if running => {
+                    running = false;
+                    changeable = false;
+                    changes.close();
+                    self.stop_inputs();
+                }
+            }
+        }
+    }
+}
+
+/// Access to the status entry of a single input
+struct StatusHandle {
+    devices: Devices,
+    id: StageId,
+}
+
+impl StatusHandle {
+    fn update(&self, input: &dyn Device) {
+        if let Some((_, status)) = self
+            .devices
+            .lock()
+            .expect("status lock poisoned")
+            .iter_mut()
+            .find(|(id, _)| *id == self.id)
+        {
+            status.health = input.health();
+            status.frames = input.frame_stats();
+        }
+    }
+}
+
+/// Returns true if reading may go on after `error`, e.g. a frame which failed to parse or a failed
+/// query of a polled device, unlike a lost connection
+fn recoverable(error: &anyhow::Error) -> bool {
+    error.is::<InvalidFrame>()
+        || error.is::<FrameCheckError>()
+        || error.is::<FrameParseError>()
+        || error.is::<FrameValidation>()
+        || matches!(error.downcast_ref(), Some(DeviceError::PollFailed))
+}
+
+async fn read_input(
+    mut input: Box<dyn Device>,
+    status: StatusHandle,
+    clock: Arc<dyn Clock>,
+    timestamps: TimestampPolicy,
+    tx: channel::Sender<Measurement>,
+) -> anyhow::Result<()> {
+    input.set_clock(clock.clone());
+    loop {
+        // Convert before the next await, frames are not required to be `Send`
+        let measurement = input
+            .read_frame()
+            .await
+            .map(|frame| frame.map(|frame| timestamps.stamp(frame.to_measurement(), clock.now())));
+        status.update(input.as_ref());
+        let measurement = match measurement {
+            Ok(measurement) => measurement,
+            Err(e) => {
+                let e = e.context(format!(
+                    "Failed to read from device {} at {}",
+                    input.name(),
+                    input.address()
+                ));
+                if !recoverable(&e) {
+                    return Err(e);
+                }
+                eprintln!("{:#}", e);
+                continue;
+            }
+        };
+        let Some(measurement) = measurement else {
+            break;
+        };
+        // Rejected by the timestamp policy
+        let Some(measurement) = measurement else {
+            continue;
+        };
+        if tx.send(measurement).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn apply_transforms(
+    mut transforms: Vec<Box<dyn Transform>>,
+    mut updates: mpsc::UnboundedReceiver<Vec<Box<dyn Transform>>>,
+    mut rx: channel::Receiver<Measurement>,
+    tx: channel::Sender<Measurement>,
+    clock: Arc<dyn Clock>,
+) {
+    let mut updatable = true;
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + TICK, TICK);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        let measurements = tokio::select! {
+            biased;
+            update = updates.recv(), if updatable => {
+                match update {
+                    Some(update) => transforms = update,
+                    None => updatable = false,
+                }
+                continue;
+            }
+            _ = ticks.tick() => {
+                let now = clock.now();
+                let mut measurements = Vec::new();
+                for i in 0..transforms.len() {
+                    let ticked = transforms[i].tick(now);
+                    measurements.extend(transform(&mut transforms, i + 1, ticked));
+                }
+                measurements
+            }
+            // After the ticks, which would never be taken while the queue has a backlog
+            measurement = rx.recv() => match measurement {
+                Some(measurement) => transform(&mut transforms, 0, vec![measurement]),
+                None => return,
+            },
+        };
+        for measurement in measurements {
+            if tx.send(measurement).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Pass `measurements` through the transforms from `first` on
+fn transform(
+    transforms: &mut [Box<dyn Transform>],
+    first: usize,
+    mut measurements: Vec<Measurement>,
+) -> Vec<Measurement> {
+    for transform in transforms.iter_mut().skip(first) {
+        measurements = measurements
+            .into_iter()
+            .flat_map(|m| transform.apply(m))
+            .collect();
+    }
+    measurements
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ChannelConfig, Pipeline};
+    use crate::{
+        clock::{MockClock, TimestampPolicy},
+        devices::{Device, DeviceHealth},
+        error::{DeviceError, InvalidFrame},
+        measurement::ToMeasurement,
+        output::{
+            batch::{batched, FlushSchedule},
+            OutputSink, ToOutput,
+        },
+        transform::Transform,
+        Measurement,
+    };
+    use async_trait::async_trait;
+    use chrono::TimeDelta;
+    use std::sync::{Arc, Mutex};
+
+    struct Counter(u64);
+
+    impl std::fmt::Display for Counter {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl ToMeasurement for Counter {
+        fn to_measurement(&self) -> Measurement {
+            Measurement::new("counter").add_field("value", self.0)
+        }
+    }
+
+    impl ToOutput for Counter {}
+
+    struct CountingDevice {
+        next: u64,
+        last: u64,
+    }
+
+    #[async_trait]
+    impl Device for CountingDevice {
+        async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+            if self.next > self.last {
+                return Ok(None);
+            }
+            self.next += 1;
+            Ok(Some(Box::new(Counter(self.next - 1))))
+        }
+
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn address(&self) -> &str {
+            "memory"
+        }
+
+        fn health(&self) -> DeviceHealth {
+            if self.next > self.last {
+                DeviceHealth::Disconnected
+            } else {
+                DeviceHealth::Connected
+            }
+        }
+    }
+
+    #[derive(Clone, Default)]
+    pub(crate) struct CapturingSink {
+        pub(crate) written: Arc<Mutex<Vec<Measurement>>>,
+        pub(crate) shut_down: Arc<Mutex<bool>>,
+    }
+
+    #[async_trait]
+    impl OutputSink for CapturingSink {
+        async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+            self.written.lock().unwrap().push(measurement.clone());
+            Ok(())
+        }
+
+        async fn shutdown(&mut self) -> anyhow::Result<()> {
+            *self.shut_down.lock().unwrap() = true;
+            Ok(())
+        }
+    }
+
+    struct DropOdd;
+
+    impl Transform for DropOdd {
+        fn apply(&mut self, measurement: Measurement) -> Vec<Measurement> {
+            match measurement.field("value") {
+                Some(crate::measurement::FieldValue::UInteger(x)) if x % 2 == 1 => vec![],
+                _ => vec![measurement],
+            }
+        }
+    }
+
+    #[test]
+    fn test_metrics_line() {
+        let pipeline = Pipeline::new(ChannelConfig::default())
+            .add_input(Box::new(CountingDevice { next: 0, last: 5 }));
+        assert_eq!(
+            pipeline.metrics().to_string(),
+            "Transforms 0/1024 queued, 0 dropped; outputs 0/1024 queued, 0 dropped; \
+             counter on memory Connected"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_passes_measurements_through_transforms() {
+        let sink = CapturingSink::default();
+        let pipeline = Pipeline::new(ChannelConfig::default())
+            .add_input(Box::new(CountingDevice { next: 0, last: 5 }))
+            .add_transform(Box::new(DropOdd))
+            .add_output(Box::new(sink.clone()));
+        let metrics = pipeline.metrics();
+        assert_eq!(metrics.devices()[0].health, DeviceHealth::Connected);
+        pipeline.run().await.unwrap();
+        assert_eq!(metrics.devices()[0].name, "counter");
+        assert_eq!(metrics.devices()[0].health, DeviceHealth::Disconnected);
+
+        let written = sink.written.lock().unwrap();
+        let values: Vec<_> = written
+            .iter()
+            .map(|m| m.field("value").unwrap().to_string())
+            .collect();
+        assert_eq!(values, ["0", "2", "4"]);
+        assert!(written.iter().all(|m| m.time.is_some()));
+        assert!(*sink.shut_down.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_shuts_down_outputs_on_request() {
+        let sink = CapturingSink::default();
+        Pipeline::new(ChannelConfig::default())
+            .add_input(Box::new(CountingDevice {
+                next: 0,
+                last: u64::MAX,
+            }))
+            .add_output(Box::new(sink.clone()))
+            .run_until(tokio::time::sleep(std::time::Duration::from_millis(10)))
+            .await
+            .unwrap();
+        assert!(*sink.shut_down.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_reconfigure_running_pipeline() {
+        let first = CapturingSink::default();
+        let second = CapturingSink::default();
+        let pipeline = Pipeline::new(ChannelConfig::default()).add_output(Box::new(first.clone()));
+        let metrics = pipeline.metrics();
+        let control = pipeline.control();
+        let task = tokio::spawn(pipeline.run());
+
+        let endless = control
+            .add_input(Box::new(CountingDevice {
+                next: 0,
+                last: u64::MAX,
+            }))
+            .unwrap();
+        assert_eq!(endless, 1);
+        while first.written.lock().unwrap().len() < 3 {
+            tokio::task::yield_now().await;
+        }
+        control.remove_input(endless).await;
+        assert!(metrics.devices().is_empty());
+
+        let replaced = control
+            .replace_outputs(vec![0], vec![Box::new(second.clone())])
+            .await
+            .unwrap();
+        assert!(replaced.removed[0].is_ok());
+        assert_eq!(replaced.added[0].as_ref().unwrap(), &2);
+        assert!(*first.shut_down.lock().unwrap());
+        let written = first.written.lock().unwrap().len();
+
+        control.set_transforms(vec![Box::new(DropOdd)]).unwrap();
+        control
+            .add_input(Box::new(CountingDevice { next: 10, last: 13 }))
+            .unwrap();
+        drop(control);
+        task.await.unwrap().unwrap();
+
+        assert_eq!(first.written.lock().unwrap().len(), written);
+        let values: Vec<_> = second
+            .written
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| m.field("value").unwrap().to_string())
+            .collect();
+        assert_eq!(values, ["10", "12"]);
+        assert!(*second.shut_down.lock().unwrap());
+        assert_eq!(metrics.devices().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_stamps_measurements_with_its_clock() {
+        let time = chrono::DateTime::from_timestamp(1700000000, 0).unwrap();
+        let sink = CapturingSink::default();
+        Pipeline::new(ChannelConfig::default())
+            .with_clock(Arc::new(MockClock::new(time)))
+            .add_input(Box::new(CountingDevice { next: 0, last: 1 }))
+            .add_output(Box::new(sink.clone()))
+            .run()
+            .await
+            .unwrap();
+        let written = sink.written.lock().unwrap();
+        assert_eq!(written.len(), 2);
+        assert!(written.iter().all(|m| m.time == Some(time)));
+    }
+
+    /// Input emitting measurements with their own times
+    struct Replay(Vec<Measurement>);
+
+    #[async_trait]
+    impl Device for Replay {
+        async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+            Ok(self
+                .0
+                .pop()
+                .map(|measurement| Box::new(measurement) as Box<dyn ToOutput>))
+        }
+
+        fn name(&self) -> &str {
+            "replay"
+        }
+
+        fn address(&self) -> &str {
+            "memory"
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_applies_timestamp_policy() {
+        let time = chrono::DateTime::from_timestamp(1700000000, 0).unwrap();
+        let at = |days: i64| Measurement::new("m").add_time(Some(time - TimeDelta::days(days)));
+        let sink = CapturingSink::default();
+        Pipeline::new(ChannelConfig::default())
+            .with_clock(Arc::new(MockClock::new(time)))
+            .with_timestamps(TimestampPolicy {
+                max_age: Some(86400),
+                ..Default::default()
+            })
+            .add_input(Box::new(Replay(vec![at(0), at(10), Measurement::new("m")])))
+            .add_output(Box::new(sink.clone()))
+            .run()
+            .await
+            .unwrap();
+        // The input continued after the measurement which was too old
+        let written = sink.written.lock().unwrap();
+        assert_eq!(written.len(), 2);
+        assert!(written.iter().all(|m| m.time == Some(time)));
+    }
+
+    /// Sink writing only once a permit was added
+    struct Stalled(Arc<tokio::sync::Semaphore>);
+
+    #[async_trait]
+    impl OutputSink for Stalled {
+        async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+            self.0.acquire().await?.forget();
+            match measurement.field("value").map(|v| v.to_string()).as_deref() {
+                Some("13") => Err(anyhow::anyhow!("Database gone")),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stalled_output_does_not_delay_others() {
+        let fast = CapturingSink::default();
+        let permits = Arc::new(tokio::sync::Semaphore::new(0));
+        let pipeline = Pipeline::new(ChannelConfig::default())
+            .add_input(Box::new(CountingDevice { next: 0, last: 9 }))
+            .add_output(Box::new(Stalled(permits.clone())))
+            .add_output(Box::new(fast.clone()));
+        let metrics = pipeline.metrics();
+        let task = tokio::spawn(pipeline.run());
+
+        while fast.written.lock().unwrap().len() < 10 {
+            tokio::task::yield_now().await;
+        }
+        let outputs = metrics.outputs();
+        assert_eq!(outputs.len(), 2);
+        let (id, stalled) = &outputs[0];
+        assert_eq!(*id, 1);
+        // One measurement is being written
+        assert_eq!(stalled.inbox.depth(), 9);
+        assert_eq!(outputs[1].1.inbox.depth(), 0);
+
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        permits.add_permits(10);
+        task.await.unwrap().unwrap();
+        assert!(stalled.lag() >= std::time::Duration::from_millis(20));
+        assert!(metrics.outputs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_output_stops_pipeline() {
+        let sink = CapturingSink::default();
+        let error = Pipeline::new(ChannelConfig::default())
+            .add_input(Box::new(CountingDevice {
+                next: 10,
+                last: u64::MAX,
+            }))
+            .add_output(Box::new(Stalled(Arc::new(tokio::sync::Semaphore::new(
+                usize::MAX >> 4,
+            )))))
+            .add_output(Box::new(sink.clone()))
+            .run()
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Database gone");
+        assert!(*sink.shut_down.lock().unwrap());
+    }
+
+    /// Sink failing to start
+    struct Unreachable;
+
+    #[async_trait]
+    impl OutputSink for Unreachable {
+        async fn start(&mut self) -> anyhow::Result<()> {
+            anyhow::bail!("Connection refused")
+        }
+
+        async fn write(&mut self, _measurement: &Measurement) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_start_shuts_down_started_outputs() {
+        let sink = CapturingSink::default();
+        let error = Pipeline::new(ChannelConfig::default())
+            .add_input(Box::new(CountingDevice { next: 0, last: 5 }))
+            .add_output(Box::new(sink.clone()))
+            .add_output(Box::new(Unreachable))
+            .run()
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Connection refused");
+        assert!(*sink.shut_down.lock().unwrap());
+    }
+
+    /// Device sending a few measurements and nothing after
+    struct Quiet(u64);
+
+    #[async_trait]
+    impl Device for Quiet {
+        async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+            if self.0 == 0 {
+                std::future::pending::<()>().await;
+            }
+            self.0 -= 1;
+            Ok(Some(Box::new(Counter(self.0))))
+        }
+
+        fn name(&self) -> &str {
+            "quiet"
+        }
+
+        fn address(&self) -> &str {
+            "memory"
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_output_stops_waiting_inputs() {
+        let pipeline = Pipeline::new(ChannelConfig::default())
+            .add_input(Box::new(Quiet(14)))
+            .add_output(Box::new(Stalled(Arc::new(tokio::sync::Semaphore::new(1)))))
+            .run();
+        let error = tokio::time::timeout(std::time::Duration::from_secs(60), pipeline)
+            .await
+            .expect("pipeline waits for its input")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Database gone");
+    }
+
+    /// Device failing every other read, with an invalid frame or with the given error
+    struct Flaky {
+        reads: u64,
+        error: fn() -> anyhow::Error,
+    }
+
+    #[async_trait]
+    impl Device for Flaky {
+        async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+            self.reads += 1;
+            match self.reads {
+                1 | 3 => Err((self.error)()),
+                2 | 4 => Ok(Some(Box::new(Counter(self.reads)))),
+                _ => Ok(None),
+            }
+        }
+
+        fn name(&self) -> &str {
+            "flaky"
+        }
+
+        fn address(&self) -> &str {
+            "memory"
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_frames_do_not_stop_input() {
+        let sink = CapturingSink::default();
+        Pipeline::new(ChannelConfig::default())
+            .add_input(Box::new(Flaky {
+                reads: 0,
+                error: || anyhow::anyhow!("Missing field").context(InvalidFrame(vec![0])),
+            }))
+            .add_input(Box::new(Flaky {
+                reads: 0,
+                error: || anyhow::anyhow!("No such file").context(DeviceError::PollFailed),
+            }))
+            .add_output(Box::new(sink.clone()))
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(sink.written.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_lost_device_stops_pipeline() {
+        let error = Pipeline::new(ChannelConfig::default())
+            .add_input(Box::new(Flaky {
+                reads: 0,
+                error: || DeviceError::ConnectionLost.into(),
+            }))
+            .run()
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.root_cause().to_string(),
+            DeviceError::ConnectionLost.to_string()
+        );
+    }
+
+    /// Sink recording the seconds since `start` of every flush
+    struct FlushTimes {
+        start: tokio::time::Instant,
+        flushes: Arc<Mutex<Vec<u64>>>,
+    }
+
+    #[async_trait]
+    impl OutputSink for FlushTimes {
+        async fn write(&mut self, _: &Measurement) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> anyhow::Result<()> {
+            let elapsed = self.start.elapsed().as_secs();
+            self.flushes.lock().unwrap().push(elapsed);
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scheduled_flush_of_idle_output() {
+        let flushes = Arc::new(Mutex::new(Vec::new()));
+        let sink = FlushTimes {
+            start: tokio::time::Instant::now(),
+            flushes: flushes.clone(),
+        };
+        let schedule = FlushSchedule {
+            size: None,
+            interval: Some(10),
+        };
+        Pipeline::new(ChannelConfig::default())
+            .add_input(Box::new(Quiet(2)))
+            .add_output(batched(Box::new(sink), Some(schedule)))
+            .run_until(tokio::time::sleep(std::time::Duration::from_secs(60)))
+            .await
+            .unwrap();
+        // Once the interval passed without further measurements, and on shutdown
+        assert_eq!(*flushes.lock().unwrap(), [10, 60]);
+    }
+
+    /// Transform adding a counter on every tick
+    struct Ticking(u64);
+
+    impl Transform for Ticking {
+        fn apply(&mut self, measurement: Measurement) -> Vec<Measurement> {
+            vec![measurement]
+        }
+
+        fn tick(&mut self, now: chrono::DateTime<chrono::Utc>) -> Vec<Measurement> {
+            self.0 += 1;
+            vec![Counter(self.0).to_measurement().add_time(Some(now))]
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ticks_pass_the_following_transforms() {
+        let time = chrono::DateTime::from_timestamp(1700000000, 0).unwrap();
+        let sink = CapturingSink::default();
+        Pipeline::new(ChannelConfig::default())
+            .with_clock(Arc::new(MockClock::new(time)))
+            .add_input(Box::new(Quiet(1)))
+            .add_transform(Box::new(Ticking(0)))
+            .add_transform(Box::new(DropOdd))
+            .add_output(Box::new(sink.clone()))
+            .run_until(tokio::time::sleep(std::time::Duration::from_secs(45)))
+            .await
+            .unwrap();
+        let written = sink.written.lock().unwrap();
+        let values: Vec<_> = written
+            .iter()
+            .map(|m| m.field("value").unwrap().to_string())
+            .collect();
+        // The measurement of the input, then every 10 s
+        assert_eq!(values, ["0", "2", "4"]);
+        assert_eq!(written[1].time, Some(time));
+    }
+
+    /// Transform counting its ticks
+    struct CountTicks(Arc<Mutex<u64>>);
+
+    impl Transform for CountTicks {
+        fn apply(&mut self, measurement: Measurement) -> Vec<Measurement> {
+            vec![measurement]
+        }
+
+        fn tick(&mut self, _now: chrono::DateTime<chrono::Utc>) -> Vec<Measurement> {
+            *self.0.lock().unwrap() += 1;
+            Vec::new()
+        }
+    }
+
+    /// Sink taking a second per measurement
+    struct SlowSink;
+
+    #[async_trait]
+    impl OutputSink for SlowSink {
+        async fn write(&mut self, _measurement: &Measurement) -> anyhow::Result<()> {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ticks_despite_backlog() {
+        let ticks = Arc::new(Mutex::new(0));
+        // The input is faster than the output, so the queues are always full
+        Pipeline::new(ChannelConfig {
+            capacity: 4,
+            ..Default::default()
+        })
+        .add_input(Box::new(Quiet(u64::MAX)))
+        .add_transform(Box::new(CountTicks(ticks.clone())))
+        .add_output(Box::new(SlowSink))
+        .run_until(tokio::time::sleep(std::time::Duration::from_secs(45)))
+        .await
+        .unwrap();
+        // Every 10 s, and possibly once more while the queued measurements are written
+        assert!(*ticks.lock().unwrap() >= 4);
+    }
+}
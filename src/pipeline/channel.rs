@@ -0,0 +1,294 @@
+//! Bounded queue connecting two pipeline stages.
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// What to do when an item is sent to a full queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Wait until the receiver made room
+    #[default]
+    Block,
+    /// Discard the item being sent
+    DropNewest,
+    /// Discard the oldest item in the queue to make room
+    DropOldest,
+}
+
+/// Capacity and overflow behaviour of a queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelConfig {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            capacity: 1024,
+            policy: OverflowPolicy::default(),
+        }
+    }
+}
+
+/// Live statistics of a queue
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+    capacity: usize,
+    depth: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl QueueMetrics {
+    /// Maximum number of queued items
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of items currently waiting in the queue
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// Number of items discarded due to overflow
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl std::fmt::Display for QueueMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{} queued, {} dropped",
+            self.depth(),
+            self.capacity,
+            self.dropped()
+        )
+    }
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    not_empty: Notify,
+    not_full: Notify,
+    metrics: Arc<QueueMetrics>,
+    policy: OverflowPolicy,
+}
+
+/// Returned by [Sender::send] if the receiver is gone. Contains the unsent item.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Closed<T>(pub T);
+
+/// Create a bounded queue.
+pub fn channel<T>(config: ChannelConfig) -> (Sender<T>, Receiver<T>) {
+    let capacity = config.capacity.max(1);
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            senders: 1,
+            receiver_alive: true,
+        }),
+        not_empty: Notify::new(),
+        not_full: Notify::new(),
+        metrics: Arc::new(QueueMetrics {
+            capacity,
+            ..Default::default()
+        }),
+        policy: config.policy,
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Sending half of a queue
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Enqueue an item, applying the overflow policy if the queue is full.
+    pub async fn send(&self, item: T) -> Result<(), Closed<T>> {
+        let shared = &self.shared;
+        let metrics = &shared.metrics;
+        let mut item = Some(item);
+        loop {
+            let not_full = shared.not_full.notified();
+            {
+                let mut state = shared.state.lock().expect("queue lock poisoned");
+                if !state.receiver_alive {
+                    return Err(Closed(item.take().expect("item is only taken once")));
+                }
+                if state.queue.len() >= metrics.capacity {
+                    match shared.policy {
+                        OverflowPolicy::Block => (),
+                        OverflowPolicy::DropNewest => {
+                            metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                            return Ok(());
+                        }
+                        OverflowPolicy::DropOldest => {
+                            state.queue.pop_front();
+                            metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+                if state.queue.len() < metrics.capacity {
+                    state
+                        .queue
+                        .push_back(item.take().expect("item is only taken once"));
+                    metrics.depth.store(state.queue.len(), Ordering::Relaxed);
+                    shared.not_empty.notify_waiters();
+                    return Ok(());
+                }
+            }
+            not_full.await;
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<QueueMetrics> {
+        self.shared.metrics.clone()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared
+            .state
+            .lock()
+            .expect("queue lock poisoned")
+            .senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.senders -= 1;
+        }
+        self.shared.not_empty.notify_waiters();
+    }
+}
+
+/// Receiving half of a queue
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receive the next item. Returns `None` once all senders are gone and the queue is drained.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            let not_empty = self.shared.not_empty.notified();
+            {
+                let mut state = self.shared.state.lock().expect("queue lock poisoned");
+                if let Some(item) = state.queue.pop_front() {
+                    self.shared
+                        .metrics
+                        .depth
+                        .store(state.queue.len(), Ordering::Relaxed);
+                    self.shared.not_full.notify_waiters();
+                    return Some(item);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            not_empty.await;
+        }
+    }
+
+    /// Returns true if no item is waiting in the queue
+    pub fn is_empty(&self) -> bool {
+        self.shared.metrics.depth() == 0
+    }
+
+    pub fn metrics(&self) -> Arc<QueueMetrics> {
+        self.shared.metrics.clone()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.receiver_alive = false;
+        }
+        self.shared.not_full.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{channel, ChannelConfig, Closed, OverflowPolicy};
+    use std::time::Duration;
+
+    fn config(capacity: usize, policy: OverflowPolicy) -> ChannelConfig {
+        ChannelConfig { capacity, policy }
+    }
+
+    #[tokio::test]
+    async fn test_drop_newest_discards_sent_item() {
+        let (tx, mut rx) = channel(config(2, OverflowPolicy::DropNewest));
+        for i in 0..4 {
+            tx.send(i).await.unwrap();
+        }
+        drop(tx);
+        assert_eq!(rx.metrics().dropped(), 2);
+        assert_eq!(rx.recv().await, Some(0));
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_discards_queued_item() {
+        let (tx, mut rx) = channel(config(2, OverflowPolicy::DropOldest));
+        for i in 0..4 {
+            tx.send(i).await.unwrap();
+        }
+        drop(tx);
+        assert_eq!(rx.metrics().dropped(), 2);
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(rx.recv().await, Some(3));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_block_waits_for_receiver() {
+        let (tx, mut rx) = channel(config(1, OverflowPolicy::Block));
+        tx.send(0).await.unwrap();
+        let metrics = tx.metrics();
+        let producer = tokio::spawn(async move { tx.send(1).await });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!producer.is_finished());
+        assert_eq!(metrics.depth(), 1);
+
+        assert_eq!(rx.recv().await, Some(0));
+        producer.await.unwrap().unwrap();
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, None);
+        assert_eq!(metrics.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_send_fails_without_receiver() {
+        let (tx, rx) = channel(ChannelConfig::default());
+        drop(rx);
+        assert_eq!(tx.send(1).await, Err(Closed(1)));
+    }
+}
@@ -0,0 +1,342 @@
+//! Expected shape of measurements.
+//!
+//! Device modules describe the measurements they emit by a [Schema] each, with the tags which
+//! are always present and the type and unit of every field. [Registry::builtin] collects the
+//! schemas of all devices. A measurement conforms if it matches any schema of its name, and
+//! measurements without a schema, e.g. of HTTP or MQTT inputs, are not checked.
+use crate::measurement::{FieldValue, Measurement, Unit};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+/// Type of a field value, as distinguished by InfluxDB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    Float,
+    Integer,
+    UInteger,
+    String,
+    Boolean,
+}
+
+impl FieldType {
+    pub fn of(value: &FieldValue) -> FieldType {
+        match value {
+            FieldValue::Float(_) => FieldType::Float,
+            FieldValue::Integer(_) => FieldType::Integer,
+            FieldValue::UInteger(_) => FieldType::UInteger,
+            FieldValue::String(_) => FieldType::String,
+            FieldValue::Boolean(_) => FieldType::Boolean,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FieldType::Float => "float",
+            FieldType::Integer => "integer",
+            FieldType::UInteger => "unsigned integer",
+            FieldType::String => "string",
+            FieldType::Boolean => "boolean",
+        }
+    }
+}
+
+/// Expected field of a measurement
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldSchema {
+    pub name: String,
+    pub field_type: FieldType,
+    /// Unit of the field, measurements declaring another unit do not conform
+    pub unit: Option<Unit>,
+    pub required: bool,
+}
+
+/// Expected tags and fields of measurements with a name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    pub name: String,
+    /// Tags which are always present, others are allowed
+    pub tags: Vec<String>,
+    pub fields: Vec<FieldSchema>,
+    /// Whether fields which are not listed are allowed, for devices with dynamic fields
+    pub other_fields: bool,
+}
+
+impl Schema {
+    pub fn new(name: impl Into<String>) -> Schema {
+        Schema {
+            name: name.into(),
+            tags: Vec::new(),
+            fields: Vec::new(),
+            other_fields: false,
+        }
+    }
+
+    pub fn add_tag(mut self, name: impl Into<String>) -> Schema {
+        self.tags.push(name.into());
+        self
+    }
+
+    /// Add a field which is always present
+    pub fn add_field(
+        self,
+        name: impl Into<String>,
+        field_type: FieldType,
+        unit: impl Into<Option<Unit>>,
+    ) -> Schema {
+        self.push_field(name.into(), field_type, unit.into(), true)
+    }
+
+    /// Add a field which may be missing
+    pub fn add_optional_field(
+        self,
+        name: impl Into<String>,
+        field_type: FieldType,
+        unit: impl Into<Option<Unit>>,
+    ) -> Schema {
+        self.push_field(name.into(), field_type, unit.into(), false)
+    }
+
+    fn push_field(
+        mut self,
+        name: String,
+        field_type: FieldType,
+        unit: Option<Unit>,
+        required: bool,
+    ) -> Schema {
+        self.fields.push(FieldSchema {
+            name,
+            field_type,
+            unit,
+            required,
+        });
+        self
+    }
+
+    /// Allow fields which are not listed
+    pub fn allow_other_fields(mut self) -> Schema {
+        self.other_fields = true;
+        self
+    }
+
+    /// Deviations of `measurement` from the schema, empty if it conforms
+    pub fn check(&self, measurement: &Measurement) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        for tag in &self.tags {
+            if measurement.tag(tag).is_none() {
+                violations.push(Violation::MissingTag(tag.clone()));
+            }
+        }
+        for field in &self.fields {
+            match measurement.field(&field.name) {
+                None if field.required => {
+                    violations.push(Violation::MissingField(field.name.clone()))
+                }
+                None => {}
+                Some(value) if FieldType::of(value) != field.field_type => {
+                    violations.push(Violation::WrongType {
+                        field: field.name.clone(),
+                        expected: field.field_type,
+                        found: FieldType::of(value),
+                    })
+                }
+                Some(_) => {}
+            }
+            let unit = measurement.field_meta(&field.name).unit;
+            if let (Some(expected), Some(found)) = (&field.unit, unit) {
+                if *expected != found {
+                    violations.push(Violation::WrongUnit {
+                        field: field.name.clone(),
+                        expected: expected.clone(),
+                        found,
+                    });
+                }
+            }
+        }
+        if !self.other_fields {
+            for (name, _) in &measurement.fields {
+                if !self.fields.iter().any(|field| &field.name == name) {
+                    violations.push(Violation::UnexpectedField(name.clone()));
+                }
+            }
+        }
+        violations
+    }
+}
+
+/// Deviation of a measurement from its schema
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    MissingTag(String),
+    MissingField(String),
+    UnexpectedField(String),
+    WrongType {
+        field: String,
+        expected: FieldType,
+        found: FieldType,
+    },
+    WrongUnit {
+        field: String,
+        expected: Unit,
+        found: Unit,
+    },
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::MissingTag(tag) => write!(f, "missing tag {}", tag),
+            Violation::MissingField(field) => write!(f, "missing field {}", field),
+            Violation::UnexpectedField(field) => write!(f, "unexpected field {}", field),
+            Violation::WrongType {
+                field,
+                expected,
+                found,
+            } => write!(
+                f,
+                "field {} is {}, expected {}",
+                field,
+                found.as_str(),
+                expected.as_str()
+            ),
+            Violation::WrongUnit {
+                field,
+                expected,
+                found,
+            } => write!(f, "field {} is in {}, expected {}", field, found, expected),
+        }
+    }
+}
+
+/// Schemas by measurement name
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    schemas: BTreeMap<String, Vec<Schema>>,
+}
+
+impl Registry {
+    /// Registry without schemas
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Registry with the schemas of all devices
+    pub fn builtin() -> Registry {
+        crate::devices::schemas()
+            .into_iter()
+            .fold(Registry::new(), Registry::add_schema)
+    }
+
+    /// Add a schema, in addition to those of the same name
+    pub fn add_schema(mut self, schema: Schema) -> Registry {
+        self.schemas
+            .entry(schema.name.clone())
+            .or_default()
+            .push(schema);
+        self
+    }
+
+    /// Schemas of measurements named `name`
+    pub fn schemas(&self, name: &str) -> &[Schema] {
+        self.schemas.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// Deviations of `measurement` from the closest schema of its name, empty if it conforms
+    /// to one of them or there is none
+    pub fn check(&self, measurement: &Measurement) -> Vec<Violation> {
+        let mut closest: Option<Vec<Violation>> = None;
+        for schema in self.schemas(&measurement.name) {
+            let violations = schema.check(measurement);
+            if violations.is_empty() {
+                return violations;
+            }
+            if closest.as_ref().is_none_or(|c| violations.len() < c.len()) {
+                closest = Some(violations);
+            }
+        }
+        closest.unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FieldType, Registry, Schema, Violation};
+    use crate::{
+        devices::status::{Battery, SensorStatus},
+        measurement::{Measurement, ToMeasurement, Unit},
+    };
+    use chrono::DateTime;
+
+    #[test]
+    fn test_schema() {
+        let schema = Schema::new("tempHum")
+            .add_tag("sensorId")
+            .add_field("temperature", FieldType::Float, Unit::Celsius)
+            .add_optional_field("humidity", FieldType::UInteger, Unit::Percent);
+        let measurement = Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.5);
+        assert_eq!(schema.check(&measurement), []);
+        assert_eq!(
+            schema.check(&measurement.clone().add_field("humidity", 55u64)),
+            []
+        );
+
+        let measurement = Measurement::new("tempHum")
+            .add_field("humidity", 55.)
+            .add_field_meta("humidity", Unit::Celsius)
+            .add_field("rssi", -70i64);
+        let violations = schema.check(&measurement);
+        assert_eq!(
+            violations,
+            [
+                Violation::MissingTag("sensorId".into()),
+                Violation::MissingField("temperature".into()),
+                Violation::WrongType {
+                    field: "humidity".into(),
+                    expected: FieldType::UInteger,
+                    found: FieldType::Float
+                },
+                Violation::WrongUnit {
+                    field: "humidity".into(),
+                    expected: Unit::Percent,
+                    found: Unit::Celsius
+                },
+                Violation::UnexpectedField("rssi".into()),
+            ]
+        );
+        assert_eq!(
+            violations[2].to_string(),
+            "field humidity is float, expected unsigned integer"
+        );
+        assert_eq!(
+            violations[3].to_string(),
+            "field humidity is in °C, expected %"
+        );
+        assert_eq!(schema.allow_other_fields().check(&measurement).len(), 4);
+    }
+
+    #[test]
+    fn test_builtin_registry() {
+        let registry = Registry::builtin();
+        // Both JeeLink and CUL receivers emit LaCrosse readings
+        assert_eq!(registry.schemas("tempHum").len(), 2);
+        let cul = Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.5)
+            .add_field("humidity", 55u64);
+        assert_eq!(registry.check(&cul), []);
+        assert_eq!(
+            registry.check(&cul.add_field("battery", "low")),
+            [Violation::UnexpectedField("battery".into())]
+        );
+        assert_eq!(registry.check(&Measurement::new("power")), []);
+
+        let status = SensorStatus {
+            sensor_id: "12".into(),
+            battery: Battery::New,
+            rssi: Some(-70.),
+            last_seen: DateTime::from_timestamp(1700000000, 0).unwrap(),
+        };
+        assert_eq!(registry.check(&status.to_measurement()), []);
+    }
+}
@@ -0,0 +1,155 @@
+//! Pipeline state kept across restarts.
+//!
+//! A [State] is a small JSON file with one section per component, e.g. the sensor names of
+//! [crate::transform::identity] or the downsampled points of a [crate::store::Store]. Components
+//! restore their section when created and update it whenever it changes. To spare flash
+//! storage, the file is rewritten at most once per interval, by replacing it with a complete
+//! new file, and once more when the last handle of the state is dropped.
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Default time between two writes of the file
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Inner {
+    path: PathBuf,
+    sections: BTreeMap<String, Value>,
+    interval: Duration,
+    written: Option<Instant>,
+    dirty: bool,
+}
+
+impl Inner {
+    fn write(&mut self) -> anyhow::Result<()> {
+        let content = serde_json::to_vec_pretty(&self.sections)?;
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, content)
+            .and_then(|_| std::fs::rename(&tmp, &self.path))
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", self.path.display(), e))?;
+        self.written = Some(Instant::now());
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn due(&self) -> bool {
+        self.written
+            .is_none_or(|written| written.elapsed() >= self.interval)
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if self.dirty {
+            let _ = self.write();
+        }
+    }
+}
+
+/// Handle of a state file, shared by all components keeping state in it
+#[derive(Clone)]
+pub struct State(Arc<Mutex<Inner>>);
+
+impl State {
+    /// State in the file at `path`, empty if the file does not exist yet
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<State> {
+        let path = path.into();
+        let sections = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|e| anyhow::anyhow!("Invalid state in {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => anyhow::bail!("Failed to read {}: {}", path.display(), e),
+        };
+        Ok(State(Arc::new(Mutex::new(Inner {
+            path,
+            sections,
+            interval: DEFAULT_INTERVAL,
+            written: None,
+            dirty: false,
+        }))))
+    }
+
+    /// Write the file at most once per `interval`
+    pub fn with_interval(self, interval: Duration) -> State {
+        self.lock().interval = interval;
+        self
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.0.lock().expect("state lock poisoned")
+    }
+
+    /// Section `key`, `None` if there is none
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> anyhow::Result<Option<T>> {
+        self.lock()
+            .sections
+            .get(key)
+            .map(|value| T::deserialize(value))
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("Invalid state of {}: {}", key, e))
+    }
+
+    /// Whether the next [State::set] writes the file. Components with large state may only
+    /// serialize it then, and on shutdown.
+    pub fn due(&self) -> bool {
+        self.lock().due()
+    }
+
+    /// Replace section `key`, writing the file if due
+    pub fn set<T: Serialize>(&self, key: &str, value: &T) -> anyhow::Result<()> {
+        let value = serde_json::to_value(value)?;
+        let mut inner = self.lock();
+        inner.sections.insert(key.into(), value);
+        inner.dirty = true;
+        if inner.due() {
+            inner.write()?;
+        }
+        Ok(())
+    }
+
+    /// Write the file if any section changed since the last write
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let mut inner = self.lock();
+        if inner.dirty {
+            inner.write()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::State;
+    use std::time::Duration;
+
+    #[test]
+    fn test_state_file() {
+        let path = std::env::temp_dir().join(format!("sensorflow-state-{}", std::process::id()));
+        let state = State::open(&path)
+            .unwrap()
+            .with_interval(Duration::from_secs(3600));
+        assert_eq!(state.get::<u32>("count").unwrap(), None);
+
+        // The first write is due immediately, later ones only after the interval
+        state.set("count", &1).unwrap();
+        assert!(!state.due());
+        state.set("count", &2).unwrap();
+        assert_eq!(State::open(&path).unwrap().get("count").unwrap(), Some(1));
+        state.flush().unwrap();
+        assert_eq!(State::open(&path).unwrap().get("count").unwrap(), Some(2));
+
+        // Pending changes are written when the last handle is dropped
+        let other = state.clone();
+        other.set("name", &"kitchen").unwrap();
+        drop(state);
+        drop(other);
+        let state = State::open(&path).unwrap();
+        assert_eq!(state.get("name").unwrap(), Some("kitchen".to_string()));
+        assert!(state.get::<u32>("name").is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
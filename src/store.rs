@@ -0,0 +1,647 @@
+//! Recent measurements kept in memory.
+//!
+//! The [Store] keeps the measurements of the last hours per sensor in a ring buffer, for
+//! queries like the history endpoint of [crate::output::api]. A sensor is identified by the name
+//! and tags of its measurements, e.g. `tempHum,sensorId=12`, see [series_key], so meters,
+//! senders and stations identified by other tags keep series of their own.
+//!
+//! For longer ranges, measurements are downsampled into [Tier]s of lower resolution, by default
+//! to 1 minute for a week and to 15 minutes for 90 days. A point of a tier has the time of the
+//! start of its interval and the mean of every numeric field over the interval. Fields named
+//! `*direction`, e.g. `wind_direction` in degrees, are averaged as unit vectors, so north-west
+//! and north-east average to north rather than south. Fields named `*gust*` keep their maximum.
+//!
+//! The downsampled points can be kept across restarts in a [State], see [Store::save]. Raw
+//! measurements are not kept, queries fall back to the tiers until new ones are received.
+use crate::{
+    measurement::{FieldValue, Measurement},
+    output::OutputSink,
+    state::State,
+    transform::sensor_key,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Store shared between the sink filling it and its readers
+pub type SharedStore = Arc<RwLock<Store>>;
+
+/// Identifier of the series of a measurement: its name and tags, e.g. `tempHum,sensorId=12`
+pub fn series_key(measurement: &Measurement) -> String {
+    sensor_key(measurement)
+}
+
+/// Resolution and retention of downsampled measurements
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct Tier {
+    /// Length of an interval in seconds
+    pub resolution: u64,
+    /// Hours points are kept
+    pub retention: u64,
+}
+
+impl Tier {
+    /// 1 minute for 7 days and 15 minutes for 90 days
+    pub const DEFAULT: [Tier; 2] = [
+        Tier {
+            resolution: 60,
+            retention: 7 * 24,
+        },
+        Tier {
+            resolution: 900,
+            retention: 90 * 24,
+        },
+    ];
+
+    fn resolution(&self) -> TimeDelta {
+        TimeDelta::seconds(self.resolution.max(1) as i64)
+    }
+
+    fn retention(&self) -> TimeDelta {
+        TimeDelta::hours(self.retention as i64)
+    }
+}
+
+/// How a field is downsampled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Aggregate {
+    Mean,
+    /// Mean of the unit vectors of angles in degrees
+    Direction,
+    Max,
+}
+
+impl Aggregate {
+    fn of(field: &str) -> Aggregate {
+        if field.ends_with("direction") {
+            Aggregate::Direction
+        } else if field.contains("gust") {
+            Aggregate::Max
+        } else {
+            Aggregate::Mean
+        }
+    }
+}
+
+/// Measurements of an interval being downsampled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Bucket {
+    start: DateTime<Utc>,
+    name: String,
+    tags: Vec<(String, String)>,
+    /// Sum and count per field
+    fields: Vec<(String, f64, u64)>,
+    /// Sums of the sines and cosines per direction field
+    #[serde(default)]
+    directions: Vec<(String, f64, f64)>,
+    /// Largest value per gust field
+    #[serde(default)]
+    maxima: Vec<(String, f64)>,
+}
+
+impl Bucket {
+    fn new(start: DateTime<Utc>, measurement: &Measurement) -> Bucket {
+        Bucket {
+            start,
+            name: measurement.name.clone(),
+            tags: measurement.tags.clone(),
+            fields: Vec::new(),
+            directions: Vec::new(),
+            maxima: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, measurement: &Measurement) {
+        for (field, value) in &measurement.fields {
+            let value = match value {
+                FieldValue::Float(x) => *x,
+                FieldValue::Integer(x) => *x as f64,
+                FieldValue::UInteger(x) => *x as f64,
+                FieldValue::Boolean(_) | FieldValue::String(_) => continue,
+            };
+            self.add_value(field, value);
+        }
+    }
+
+    fn add_value(&mut self, field: &str, value: f64) {
+        match Aggregate::of(field) {
+            Aggregate::Mean => match self.fields.iter_mut().find(|(key, _, _)| key == field) {
+                Some((_, sum, count)) => {
+                    *sum += value;
+                    *count += 1;
+                }
+                None => self.fields.push((field.into(), value, 1)),
+            },
+            Aggregate::Direction => {
+                let (sin, cos) = value.to_radians().sin_cos();
+                match self.directions.iter_mut().find(|(key, _, _)| key == field) {
+                    Some((_, sines, cosines)) => {
+                        *sines += sin;
+                        *cosines += cos;
+                    }
+                    None => self.directions.push((field.into(), sin, cos)),
+                }
+            }
+            Aggregate::Max => match self.maxima.iter_mut().find(|(key, _)| key == field) {
+                Some((_, max)) => *max = max.max(value),
+                None => self.maxima.push((field.into(), value)),
+            },
+        }
+    }
+
+    /// Bucket of a single measurement equal to `point`
+    fn of_point(point: &Measurement) -> Option<Bucket> {
+        let mut bucket = Bucket::new(point.time?, point);
+        for (field, value) in &point.fields {
+            if let Some(value) = value.as_f64() {
+                bucket.add_value(field, value);
+            }
+        }
+        Some(bucket)
+    }
+
+    fn point(&self) -> Measurement {
+        let mut point = Measurement::new(&self.name);
+        point.tags = self.tags.clone();
+        for (field, sum, count) in &self.fields {
+            point = point.add_field(field, sum / *count as f64);
+        }
+        for (field, sines, cosines) in &self.directions {
+            point = point.add_field(field, sines.atan2(*cosines).to_degrees().rem_euclid(360.));
+        }
+        for (field, max) in &self.maxima {
+            point = point.add_field(field, *max);
+        }
+        point.add_time(Some(self.start))
+    }
+}
+
+/// Downsampled points of a series in one tier, all measurements of a bucket share the name and
+/// tags of its series
+#[derive(Debug, Clone)]
+struct Downsampled {
+    tier: Tier,
+    points: VecDeque<Measurement>,
+    current: Option<Bucket>,
+}
+
+impl Downsampled {
+    fn insert(&mut self, time: DateTime<Utc>, measurement: &Measurement) {
+        let start = time.duration_trunc(self.tier.resolution()).unwrap_or(time);
+        match &mut self.current {
+            Some(bucket) if bucket.start == start => bucket.add(measurement),
+            // Late measurements of earlier intervals are dropped
+            Some(bucket) if bucket.start > start => {}
+            current => {
+                if let Some(bucket) = current.take() {
+                    self.points.push_back(bucket.point());
+                }
+                let mut bucket = Bucket::new(start, measurement);
+                bucket.add(measurement);
+                *current = Some(bucket);
+            }
+        }
+        let oldest = time - self.tier.retention();
+        while self
+            .points
+            .front()
+            .and_then(|m| m.time)
+            .is_some_and(|t| t < oldest)
+        {
+            self.points.pop_front();
+        }
+    }
+
+    /// Points of the intervals ending after `since`, including the current interval
+    fn points(&self, since: Option<DateTime<Utc>>) -> Vec<Measurement> {
+        let resolution = self.tier.resolution();
+        self.points
+            .iter()
+            .cloned()
+            .chain(self.current.iter().map(Bucket::point))
+            .filter(|m| match (since, m.time) {
+                (Some(since), Some(time)) => time + resolution > since,
+                _ => true,
+            })
+            .collect()
+    }
+}
+
+/// Measurements of a sensor, of one name and set of tags, see [series_key]
+#[derive(Debug, Clone)]
+struct Series {
+    raw: VecDeque<Measurement>,
+    tiers: Vec<Downsampled>,
+}
+
+/// Persisted points of a tier, with the points as buckets of a single measurement
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedTier {
+    resolution: u64,
+    points: Vec<Bucket>,
+    current: Option<Bucket>,
+}
+
+impl Series {
+    fn new(tiers: &[Tier]) -> Series {
+        Series {
+            raw: VecDeque::new(),
+            tiers: tiers
+                .iter()
+                .map(|tier| Downsampled {
+                    tier: *tier,
+                    points: VecDeque::new(),
+                    current: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Ring buffers of recent measurements per sensor
+#[derive(Debug, Clone)]
+pub struct Store {
+    retention: TimeDelta,
+    capacity: usize,
+    tiers: Vec<Tier>,
+    sensors: BTreeMap<String, Series>,
+}
+
+impl Store {
+    /// Store keeping measurements for `retention`, at most `capacity` per sensor
+    pub fn new(retention: Duration, capacity: usize) -> Store {
+        Store {
+            retention: TimeDelta::from_std(retention).unwrap_or(TimeDelta::MAX),
+            capacity: capacity.max(1),
+            tiers: Tier::DEFAULT.to_vec(),
+            sensors: BTreeMap::new(),
+        }
+    }
+
+    /// Replace the tiers of downsampled measurements, from fine to coarse
+    pub fn with_tiers(mut self, tiers: Vec<Tier>) -> Store {
+        self.tiers = tiers;
+        self
+    }
+
+    pub fn shared(self) -> SharedStore {
+        Arc::new(RwLock::new(self))
+    }
+
+    /// Add a measurement, with the current time if it has none, and drop the measurements of
+    /// its sensor which are older than the retention or exceed the capacity
+    pub fn insert(&mut self, measurement: &Measurement) {
+        let time = measurement.time.unwrap_or_else(Utc::now);
+        let series = self
+            .sensors
+            .entry(series_key(measurement))
+            .or_insert_with(|| Series::new(&self.tiers));
+        for tier in &mut series.tiers {
+            tier.insert(time, measurement);
+        }
+        let buffer = &mut series.raw;
+        while buffer.len() >= self.capacity {
+            buffer.pop_front();
+        }
+        buffer.push_back(measurement.clone().add_time(Some(time)));
+        let oldest = time - self.retention;
+        while buffer
+            .front()
+            .and_then(|m| m.time)
+            .is_some_and(|t| t < oldest)
+        {
+            buffer.pop_front();
+        }
+    }
+
+    /// Identifiers of all sensors
+    pub fn sensors(&self) -> impl Iterator<Item = &str> {
+        self.sensors.keys().map(String::as_str)
+    }
+
+    /// Measurements of a sensor in the order received, those at or after `since` if given
+    pub fn history(&self, sensor: &str, since: Option<DateTime<Utc>>) -> Vec<&Measurement> {
+        self.sensors
+            .get(sensor)
+            .into_iter()
+            .flat_map(|series| &series.raw)
+            .filter(|m| match (since, m.time) {
+                (Some(since), Some(time)) => time >= since,
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// Downsampled measurements of a sensor of the intervals ending after `since`, from the tier
+    /// with the given resolution in seconds
+    pub fn downsampled(
+        &self,
+        sensor: &str,
+        since: Option<DateTime<Utc>>,
+        resolution: u64,
+    ) -> Option<Vec<Measurement>> {
+        let series = self.sensors.get(sensor)?;
+        let tier = series
+            .tiers
+            .iter()
+            .find(|tier| tier.tier.resolution == resolution)?;
+        Some(tier.points(since))
+    }
+
+    /// Measurements of a sensor since `since` in the finest resolution covering `since`:
+    /// raw measurements if the oldest one is not newer or `since` is not given, else the first
+    /// tier whose oldest point is not newer, else the coarsest tier
+    pub fn query(&self, sensor: &str, since: Option<DateTime<Utc>>) -> Vec<Measurement> {
+        let Some(series) = self.sensors.get(sensor) else {
+            return Vec::new();
+        };
+        let covers = |oldest: Option<&Measurement>| match (since, oldest.and_then(|m| m.time)) {
+            (None, _) => true,
+            (Some(since), Some(oldest)) => oldest <= since,
+            (Some(_), None) => false,
+        };
+        if series.tiers.is_empty() || covers(series.raw.front()) {
+            return self.history(sensor, since).into_iter().cloned().collect();
+        }
+        let tier = series
+            .tiers
+            .iter()
+            .find(|tier| covers(tier.points.front()))
+            .or(series.tiers.last())
+            .expect("tiers are not empty");
+        tier.points(since)
+    }
+
+    /// Keep the downsampled points of all sensors in section `key` of `state`
+    pub fn save(&self, state: &State, key: &str) -> anyhow::Result<()> {
+        let saved: BTreeMap<&str, Vec<SavedTier>> = self
+            .sensors
+            .iter()
+            .map(|(sensor, series)| {
+                let tiers = series
+                    .tiers
+                    .iter()
+                    .map(|tier| SavedTier {
+                        resolution: tier.tier.resolution,
+                        points: tier.points.iter().filter_map(Bucket::of_point).collect(),
+                        current: tier.current.clone(),
+                    })
+                    .collect();
+                (sensor.as_str(), tiers)
+            })
+            .collect();
+        state.set(key, &saved)
+    }
+
+    /// Restore the downsampled points saved in section `key` of `state`, replacing those of the
+    /// sensors found there. Points of tiers which are no longer configured are dropped.
+    ///
+    /// Sensors are identified by the measurements of their points, so points saved under an
+    /// identifier of an earlier version are restored to the series of their measurements.
+    pub fn restore(&mut self, state: &State, key: &str) -> anyhow::Result<()> {
+        let saved: BTreeMap<String, Vec<SavedTier>> = state.get(key)?.unwrap_or_default();
+        for (sensor, saved) in saved {
+            let sensor = saved
+                .iter()
+                .flat_map(|tier| tier.points.first().or(tier.current.as_ref()))
+                .map(|bucket| series_key(&bucket.point()))
+                .next()
+                .unwrap_or(sensor);
+            let mut series = Series::new(&self.tiers);
+            for tier in &mut series.tiers {
+                let Some(saved) = saved
+                    .iter()
+                    .find(|saved| saved.resolution == tier.tier.resolution)
+                else {
+                    continue;
+                };
+                tier.points = saved.points.iter().map(Bucket::point).collect();
+                tier.current = saved.current.clone();
+            }
+            self.sensors.insert(sensor, series);
+        }
+        Ok(())
+    }
+
+    /// Latest measurement of a sensor
+    pub fn latest(&self, sensor: &str) -> Option<&Measurement> {
+        self.sensors
+            .get(sensor)
+            .and_then(|series| series.raw.back())
+    }
+}
+
+/// Sink adding measurements to a store
+pub struct StoreSink {
+    store: SharedStore,
+}
+
+impl StoreSink {
+    pub fn new(store: SharedStore) -> StoreSink {
+        StoreSink { store }
+    }
+}
+
+#[async_trait]
+impl OutputSink for StoreSink {
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        self.store
+            .write()
+            .expect("store lock poisoned")
+            .insert(measurement);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Store, Tier};
+    use crate::{state::State, Measurement};
+    use chrono::DateTime;
+    use std::time::Duration;
+
+    const SENSOR: &str = "tempHum,sensorId=12";
+
+    fn measurement(sensor: u32, time: i64) -> Measurement {
+        Measurement::new("tempHum")
+            .add_tag("sensorId", sensor)
+            .add_field("temperature", 21.5)
+            .add_time(DateTime::from_timestamp(time, 0))
+    }
+
+    #[test]
+    fn test_retention() {
+        let mut store = Store::new(Duration::from_secs(3600), 100);
+        for time in [0, 1800, 3600, 5400] {
+            store.insert(&measurement(12, time));
+        }
+        store.insert(&measurement(7, 0));
+        store.insert(&Measurement::new("power").add_field("power", 100.));
+        assert_eq!(
+            store.sensors().collect::<Vec<_>>(),
+            ["power", "tempHum,sensorId=12", "tempHum,sensorId=7"]
+        );
+
+        let times = |history: Vec<&Measurement>| -> Vec<i64> {
+            history
+                .iter()
+                .map(|m| m.time.unwrap().timestamp())
+                .collect()
+        };
+        assert_eq!(times(store.history(SENSOR, None)), [1800, 3600, 5400]);
+        assert_eq!(
+            times(store.history(SENSOR, DateTime::from_timestamp(3600, 0))),
+            [3600, 5400]
+        );
+        assert!(store.history("tempHum,sensorId=13", None).is_empty());
+        assert_eq!(
+            store.latest(SENSOR).unwrap().time,
+            DateTime::from_timestamp(5400, 0)
+        );
+    }
+
+    #[test]
+    fn test_sensors_without_sensor_id() {
+        let mut store = Store::new(Duration::from_secs(3600), 100);
+        for meter in ["12345678", "87654321"] {
+            store.insert(
+                &Measurement::new("wmbus")
+                    .add_tag("meterId", meter)
+                    .add_field("volume", 1.5),
+            );
+        }
+        assert_eq!(
+            store.sensors().collect::<Vec<_>>(),
+            ["wmbus,meterId=12345678", "wmbus,meterId=87654321"]
+        );
+        assert_eq!(store.history("wmbus,meterId=12345678", None).len(), 1);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut store = Store::new(Duration::from_secs(3600), 2);
+        for time in 0..5 {
+            store.insert(&measurement(12, time));
+        }
+        assert_eq!(store.history(SENSOR, None).len(), 2);
+        assert_eq!(
+            store.history(SENSOR, None)[0].time,
+            DateTime::from_timestamp(3, 0)
+        );
+    }
+
+    #[test]
+    fn test_downsampling() {
+        let mut store = Store::new(Duration::from_secs(600), 100).with_tiers(vec![
+            Tier {
+                resolution: 60,
+                retention: 1,
+            },
+            Tier {
+                resolution: 900,
+                retention: 24,
+            },
+        ]);
+        // One measurement every 30 s for 2 hours, temperature rising by 1 per minute
+        for i in 0..240 {
+            store.insert(
+                &Measurement::new("tempHum")
+                    .add_tag("sensorId", 12)
+                    .add_field("temperature", i as f64 / 2.)
+                    .add_field("model", "LaCrosse")
+                    .add_time(DateTime::from_timestamp(1700000000 + i * 30, 0)),
+            );
+        }
+        // 2023-11-14T22:13:20 to 2023-11-15T00:13:20
+        let minutes = store.downsampled(SENSOR, None, 60).unwrap();
+        assert_eq!(minutes.len(), 60);
+        assert_eq!(
+            minutes.last().unwrap().time,
+            DateTime::from_timestamp(1700007120, 0)
+        );
+        assert_eq!(
+            minutes.last().unwrap().fields,
+            [("temperature".into(), 119.25.into())]
+        );
+        let quarters = store.downsampled(SENSOR, None, 900).unwrap();
+        assert_eq!(quarters.len(), 9);
+        assert_eq!(quarters[0].time, DateTime::from_timestamp(1699999200, 0));
+        // 22:13:20 to 22:14:50
+        assert_eq!(quarters[0].field("temperature"), Some(&0.75.into()));
+        assert!(store.downsampled(SENSOR, None, 3600).is_none());
+
+        let since = |seconds: i64| DateTime::from_timestamp(1700000000 + seconds, 0);
+        // Raw measurements cover the last 10 minutes
+        assert_eq!(store.query(SENSOR, since(7000)).len(), 6);
+        assert_eq!(store.query(SENSOR, since(5000)).len(), 37);
+        assert_eq!(store.query(SENSOR, since(0)).len(), 9);
+
+        // Downsampled points are kept across restarts, raw measurements are not
+        let path = std::env::temp_dir().join(format!("sensorflow-store-{}", std::process::id()));
+        let state = State::open(&path).unwrap();
+        store.save(&state, "store").unwrap();
+        state.flush().unwrap();
+        let mut restored = Store::new(Duration::from_secs(600), 100).with_tiers(vec![Tier {
+            resolution: 900,
+            retention: 24,
+        }]);
+        restored
+            .restore(&State::open(&path).unwrap(), "store")
+            .unwrap();
+        assert_eq!(restored.downsampled(SENSOR, None, 900), Some(quarters));
+        assert!(restored.downsampled(SENSOR, None, 60).is_none());
+        assert_eq!(restored.history(SENSOR, None).len(), 0);
+        assert_eq!(restored.query(SENSOR, since(0)).len(), 9);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_downsampling_per_measurement() {
+        let mut store = Store::new(Duration::from_secs(600), 100).with_tiers(vec![Tier {
+            resolution: 60,
+            retention: 1,
+        }]);
+        store.insert(&measurement(12, 1700000040));
+        store.insert(
+            &Measurement::new("sensor_status")
+                .add_tag("sensorId", 12)
+                .add_field("rssi", -70i64)
+                .add_time(DateTime::from_timestamp(1700000050, 0)),
+        );
+        let points = store.downsampled(SENSOR, None, 60).unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].fields, [("temperature".into(), 21.5.into())]);
+        let points = store
+            .downsampled("sensor_status,sensorId=12", None, 60)
+            .unwrap();
+        assert_eq!(points[0].name, "sensor_status");
+        assert_eq!(points[0].fields, [("rssi".into(), (-70.).into())]);
+    }
+
+    #[test]
+    fn test_wind() {
+        let mut store = Store::new(Duration::from_secs(600), 100).with_tiers(vec![Tier {
+            resolution: 60,
+            retention: 1,
+        }]);
+        for (i, (direction, gust)) in [(350u64, 5.), (30, 9.5), (10, 7.)].into_iter().enumerate() {
+            store.insert(
+                &Measurement::new("wind")
+                    .add_field("wind_direction", direction)
+                    .add_field("wind_gust", gust)
+                    .add_field("wind_avg", 3.)
+                    .add_time(DateTime::from_timestamp(1700000040 + i as i64 * 5, 0)),
+            );
+        }
+        let point = store.downsampled("wind", None, 60).unwrap().remove(0);
+        let field = |name: &str| point.field(name).and_then(|value| value.as_f64()).unwrap();
+        assert!((field("wind_direction") - 10.).abs() < 1.);
+        assert_eq!(field("wind_gust"), 9.5);
+        assert_eq!(field("wind_avg"), 3.);
+    }
+}
@@ -0,0 +1,312 @@
+//! Proptest strategies generating the byte streams of the frame protocols.
+//!
+//! Every protocol has a strategy of valid frames, built from arbitrary field values. [stream]
+//! concatenates them as a device would send them, [adversarial] interleaves them with
+//! [garbage]. The properties [roundtrip] and [robust] hold for every [Frame], [reencode] for
+//! every [EncodableFrame]; a new protocol is covered by adding a strategy of its frames.
+//! Requires the `proptest` feature.
+use crate::{
+    error::FrameCheckError,
+    input::checksum::{crc16_xmodem, crc8, sum16, xor8},
+    EncodableFrame, Frame,
+};
+use bytes::BytesMut;
+use proptest::{collection::vec, option, prelude::*, test_runner::TestCaseError};
+use std::fmt::Debug;
+
+/// `OK 9` lines of the LaCrosseITPlusReader firmware, with and without RSSI
+pub fn jeelink() -> impl Strategy<Value = Vec<u8>> {
+    (any::<[u8; 5]>(), option::of(any::<i16>())).prop_map(|(fields, rssi)| {
+        let [id, model, high, low, humidity] = fields;
+        let mut line = format!("OK 9 {} {} {} {} {}", id, model, high, low, humidity);
+        if let Some(rssi) = rssi {
+            line.push_str(&format!(" {}", rssi));
+        }
+        line.push_str("\r\n");
+        line.into_bytes()
+    })
+}
+
+/// FS20 messages of culfw, with and without extension and RSSI
+pub fn cul() -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 4..=6).prop_map(|data| {
+        let mut line = String::from("F");
+        for byte in data {
+            line.push_str(&format!("{:02X}", byte));
+        }
+        line.push_str("\r\n");
+        line.into_bytes()
+    })
+}
+
+/// ESP3 radio telegrams (ERP1) of any RORG, with and without optional data
+pub fn enocean() -> impl Strategy<Value = Vec<u8>> {
+    (
+        any::<u8>(),
+        vec(any::<u8>(), 1..=4),
+        any::<[u8; 5]>(),
+        vec(any::<u8>(), 0..=7),
+    )
+        .prop_map(|(rorg, user_data, sender_status, optional)| {
+            let mut data = vec![rorg];
+            data.extend(user_data);
+            data.extend(sender_status);
+            let header = [
+                (data.len() >> 8) as u8,
+                data.len() as u8,
+                optional.len() as u8,
+                0x01,
+            ];
+            let mut packet = vec![0x55];
+            packet.extend(header);
+            packet.push(crc8(0x07, &header));
+            let body: Vec<u8> = data.into_iter().chain(optional).collect();
+            let crc = crc8(0x07, &body);
+            packet.extend(body);
+            packet.push(crc);
+            packet
+        })
+}
+
+/// LOOP packets of the Davis Vantage consoles, with arbitrary readings
+pub fn davis() -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 92).prop_map(|readings| {
+        let mut packet = b"LOO".to_vec();
+        packet.extend(readings);
+        packet.extend(b"\n\r");
+        let crc = crc16_xmodem(&packet);
+        packet.extend(crc.to_be_bytes());
+        packet
+    })
+}
+
+/// Data frames of the Plantower PMS sensors
+pub fn pms() -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u16>(), 13).prop_map(|words| {
+        let mut frame = vec![0x42, 0x4d, 0x00, 0x1c];
+        for word in words {
+            frame.extend(word.to_be_bytes());
+        }
+        frame.extend(sum16(&frame).to_be_bytes());
+        frame
+    })
+}
+
+/// Responses of an ELM327 adapter: lines of hex bytes, terminated by the prompt
+pub fn elm327() -> impl Strategy<Value = Vec<u8>> {
+    vec(vec(any::<u8>(), 1..8), 1..4).prop_map(|lines| {
+        let mut response = String::new();
+        for line in lines {
+            let bytes: Vec<_> = line.iter().map(|byte| format!("{:02X}", byte)).collect();
+            response.push_str(&bytes.join(" "));
+            response.push('\r');
+        }
+        response.push_str("\r>");
+        response.into_bytes()
+    })
+}
+
+/// HCI messages of an IMST iM871A, with any combination of the optional trailers
+pub fn imst() -> impl Strategy<Value = Vec<u8>> {
+    (
+        0..8u8,
+        any::<u8>(),
+        vec(any::<u8>(), 0..64),
+        any::<[u8; 7]>(),
+    )
+        .prop_map(|(flags, message, payload, trailer)| {
+            let control = 0x02 | flags << 5;
+            let mut frame = vec![0xA5, control, message, payload.len() as u8];
+            frame.extend(payload);
+            let trailer_len = [4, 1, 2]
+                .iter()
+                .enumerate()
+                .filter(|(bit, _)| flags & (1 << bit) != 0)
+                .map(|(_, len)| len)
+                .sum();
+            frame.extend(&trailer[..trailer_len]);
+            frame
+        })
+}
+
+/// Data indications of an Amber AMB8465, with and without RSSI
+pub fn amber() -> impl Strategy<Value = Vec<u8>> {
+    (vec(any::<u8>(), 0..64), option::of(any::<u8>())).prop_map(|(telegram, rssi)| {
+        let mut payload = vec![telegram.len() as u8];
+        payload.extend(telegram);
+        payload.extend(rssi);
+        let mut frame = vec![0xFF, 0x03, payload.len() as u8];
+        frame.extend(payload);
+        frame.push(xor8(&frame));
+        frame
+    })
+}
+
+/// Arbitrary bytes, e.g. noise on a serial line
+pub fn garbage() -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..64)
+}
+
+/// Up to 8 frames sent one after the other
+pub fn stream<S: Strategy<Value = Vec<u8>>>(frames: S) -> impl Strategy<Value = Vec<Vec<u8>>> {
+    vec(frames, 1..=8)
+}
+
+/// Frames interleaved with garbage, which may contain partial or corrupt frames
+pub fn adversarial<S>(frames: S) -> impl Strategy<Value = Vec<u8>>
+where
+    S: Strategy<Value = Vec<u8>> + 'static,
+{
+    let frames = frames.boxed();
+    vec(
+        prop_oneof![
+            frames.clone(),
+            garbage(),
+            // Truncated frame
+            frames.clone().prop_flat_map(|frame| {
+                let len = frame.len();
+                (Just(frame), 0..len).prop_map(|(frame, len)| frame[..len].to_vec())
+            }),
+            // Frame with a flipped bit
+            frames.prop_flat_map(|frame| {
+                let bits = frame.len() * 8;
+                (Just(frame), 0..bits).prop_map(|(mut frame, bit)| {
+                    frame[bit / 8] ^= 1 << (bit % 8);
+                    frame
+                })
+            }),
+        ],
+        0..8,
+    )
+    .prop_map(|parts| parts.concat())
+}
+
+/// Size of the reads of a serial port
+pub fn reads() -> impl Strategy<Value = usize> {
+    1..=64usize
+}
+
+/// Frames found in `data`, fed to [Frame::check] in reads of `chunk` bytes. Fails if a frame
+/// is returned without consuming input.
+fn check_all<F: Frame>(data: &[u8], chunk: usize) -> Result<Vec<BytesMut>, TestCaseError> {
+    let mut buffer = BytesMut::new();
+    let mut frames = Vec::new();
+    for read in data.chunks(chunk.max(1)) {
+        buffer.extend_from_slice(read);
+        loop {
+            let len = buffer.len();
+            match F::check(&mut buffer) {
+                Ok(frame) => {
+                    prop_assert!(buffer.len() < len, "Frame returned without consuming input");
+                    frames.push(frame);
+                }
+                Err(FrameCheckError::ChecksumError { .. }) => {
+                    prop_assert!(buffer.len() < len, "Checksum error without consuming input");
+                }
+                Err(_) => break,
+            }
+        }
+    }
+    Ok(frames)
+}
+
+/// Every frame of a stream is found and parses to the same value whatever the size of the
+/// reads
+pub fn roundtrip<F>(frames: &[Vec<u8>], chunk: usize) -> Result<(), TestCaseError>
+where
+    F: Frame + PartialEq + Debug,
+{
+    let mut expected = Vec::new();
+    for frame in frames {
+        let checked = check_all::<F>(frame, frame.len())?;
+        prop_assert_eq!(checked.len(), 1, "Not a single frame: {:02x?}", frame);
+        let parsed = F::parse(checked[0].clone());
+        prop_assert!(parsed.is_ok(), "{:?}: {:02x?}", parsed, frame);
+        expected.extend(parsed.ok());
+    }
+    let parsed = check_all::<F>(&frames.concat(), chunk)?
+        .into_iter()
+        .map(|frame| F::parse(frame).map_err(|e| TestCaseError::fail(e.to_string())))
+        .collect::<Result<Vec<_>, _>>()?;
+    prop_assert_eq!(parsed, expected);
+    Ok(())
+}
+
+/// Every frame of a stream is parsed again from its encoding
+pub fn reencode<F>(frames: &[Vec<u8>]) -> Result<(), TestCaseError>
+where
+    F: EncodableFrame + PartialEq + Debug,
+{
+    for frame in check_all::<F>(&frames.concat(), usize::MAX)? {
+        let Ok(frame) = F::parse(frame) else {
+            continue;
+        };
+        let encoded = frame.encode();
+        let checked = check_all::<F>(&encoded, encoded.len())?;
+        prop_assert_eq!(
+            checked.len(),
+            1,
+            "Not a single frame: {:02x?}",
+            &encoded[..]
+        );
+        let parsed = F::parse(checked[0].clone());
+        prop_assert!(parsed.is_ok(), "{:?}: {:02x?}", parsed, &encoded[..]);
+        prop_assert_eq!(parsed.ok(), Some(frame));
+    }
+    Ok(())
+}
+
+/// Parsing arbitrary bytes neither panics nor stalls
+pub fn robust<F: Frame>(data: &[u8], chunk: usize) -> Result<(), TestCaseError> {
+    for frame in check_all::<F>(data, chunk)? {
+        let _ = F::parse(frame);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::{
+        cul::CulFrame, davis::LoopPacket, elm327::ElmResponse, enocean::EnOceanFrame,
+        jeelink::JeeLinkFrame, pms::PmsFrame, wmbus::AmberFrame, wmbus::ImstFrame,
+    };
+
+    macro_rules! properties {
+        ($($name:ident: $frame:ty = $strategy:expr;)*) => {
+            $(
+                mod $name {
+                    use super::*;
+
+                    proptest! {
+                        #[test]
+                        fn test_roundtrip(frames in stream($strategy), chunk in reads()) {
+                            roundtrip::<$frame>(&frames, chunk)?;
+                        }
+
+                        #[test]
+                        fn test_reencode(frames in stream($strategy)) {
+                            reencode::<$frame>(&frames)?;
+                        }
+
+                        #[test]
+                        fn test_adversarial(data in adversarial($strategy), chunk in reads()) {
+                            robust::<$frame>(&data, chunk)?;
+                        }
+                    }
+                }
+            )*
+        };
+    }
+
+    properties! {
+        jeelink: JeeLinkFrame = jeelink();
+        cul: CulFrame = cul();
+        enocean: EnOceanFrame = enocean();
+        davis: LoopPacket = davis();
+        pms: PmsFrame = pms();
+        elm327: ElmResponse = elm327();
+        imst: ImstFrame = imst();
+        amber: AmberFrame = amber();
+    }
+}
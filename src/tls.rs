@@ -0,0 +1,252 @@
+//! TLS settings of network inputs and outputs.
+//!
+//! Brokers and servers with certificates of a private CA, or requiring client certificates, are
+//! reached directly instead of through a reverse proxy. A `tls` table configures the CA bundle
+//! to trust in addition to the built-in roots, the client certificate and key for mutual TLS,
+//! and whether to skip the verification of the server certificate, which is meant for tests
+//! only. HTTP clients pick up the settings with [TlsConfig::http_client], MQTT connections with
+//! [TlsConfig::mqtt_transport]. Both are built on rustls and require the `tls` feature.
+//!
+//! ```toml
+//! [[inputs]]
+//! type = "zigbee2mqtt"
+//! broker = "mosquitto.home"
+//! tls = { ca_file = "/etc/sensorflow/home-ca.pem" }
+//!
+//! [[outputs]]
+//! type = "remote_write"
+//! url = "https://mimir.home/api/v1/push"
+//! tls = { cert_file = "/etc/sensorflow/client.pem", key_file = "/etc/sensorflow/client.key" }
+//! ```
+#[cfg(feature = "tls")]
+use anyhow::Context;
+use serde::Deserialize;
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Trusted CAs, client certificate and verification
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// CA certificates to trust in addition to the built-in roots, PEM encoded
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate chain, PEM encoded
+    pub cert_file: Option<PathBuf>,
+    /// Private key of the client certificate, PEM encoded
+    pub key_file: Option<PathBuf>,
+    /// Accept any server certificate. Never use this outside of tests.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+#[cfg(feature = "tls")]
+fn read_pem(path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+#[cfg(not(feature = "tls"))]
+fn unsupported() -> anyhow::Error {
+    anyhow::anyhow!("TLS settings require the `tls` feature")
+}
+
+impl TlsConfig {
+    /// Client certificate and key, if configured
+    #[cfg(feature = "tls")]
+    fn client_auth(&self) -> anyhow::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        match (&self.cert_file, &self.key_file) {
+            (Some(cert), Some(key)) => Ok(Some((read_pem(cert)?, read_pem(key)?))),
+            (None, None) => Ok(None),
+            _ => Err(anyhow::anyhow!(
+                "Client certificate and key must be configured together"
+            )),
+        }
+    }
+
+    /// Apply the settings to an HTTP client
+    #[cfg(feature = "tls")]
+    pub fn http_client(
+        &self,
+        mut builder: reqwest::ClientBuilder,
+    ) -> anyhow::Result<reqwest::ClientBuilder> {
+        builder = builder.use_rustls_tls();
+        if let Some(ca_file) = &self.ca_file {
+            for cert in reqwest::Certificate::from_pem_bundle(&read_pem(ca_file)?)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if let Some((mut pem, key)) = self.client_auth()? {
+            pem.push(b'\n');
+            pem.extend(key);
+            builder = builder.identity(reqwest::Identity::from_pem(&pem)?);
+        }
+        Ok(builder.danger_accept_invalid_certs(self.insecure_skip_verify))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn http_client(
+        &self,
+        _builder: reqwest::ClientBuilder,
+    ) -> anyhow::Result<reqwest::ClientBuilder> {
+        Err(unsupported())
+    }
+
+    /// Transport of an MQTT connection with the settings
+    #[cfg(all(feature = "tls", feature = "mqtt"))]
+    pub fn mqtt_transport(&self) -> anyhow::Result<rumqttc::Transport> {
+        use rumqttc::{TlsConfiguration, Transport};
+        use rustls::{ClientConfig, RootCertStore};
+        use std::sync::Arc;
+
+        let mut roots = RootCertStore::empty();
+        // A CA bundle may be all there is on minimal systems
+        roots.add_parsable_certificates(
+            rustls_native_certs::load_native_certs().unwrap_or_default(),
+        );
+        if let Some(ca_file) = &self.ca_file {
+            for cert in rustls_pemfile::certs(&mut read_pem(ca_file)?.as_slice()) {
+                roots.add(cert?)?;
+            }
+        }
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let mut config = match self.client_auth()? {
+            Some((cert, key)) => {
+                let chain =
+                    rustls_pemfile::certs(&mut cert.as_slice()).collect::<Result<_, _>>()?;
+                let key = rustls_pemfile::private_key(&mut key.as_slice())?
+                    .context("No private key in key_file")?;
+                builder.with_client_auth_cert(chain, key)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        if self.insecure_skip_verify {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(insecure::AcceptAny::new()));
+        }
+        Ok(Transport::tls_with_config(TlsConfiguration::Rustls(
+            Arc::new(config),
+        )))
+    }
+
+    #[cfg(all(not(feature = "tls"), feature = "mqtt"))]
+    pub fn mqtt_transport(&self) -> anyhow::Result<rumqttc::Transport> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(all(feature = "tls", feature = "mqtt"))]
+mod insecure {
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::crypto::{
+        verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms,
+    };
+    use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    use rustls::{DigitallySignedStruct, Error, SignatureScheme};
+
+    /// Verifier accepting any server certificate, signatures of the handshake are still checked
+    #[derive(Debug)]
+    pub struct AcceptAny(WebPkiSupportedAlgorithms);
+
+    impl AcceptAny {
+        pub fn new() -> AcceptAny {
+            AcceptAny(rustls::crypto::ring::default_provider().signature_verification_algorithms)
+        }
+    }
+
+    impl ServerCertVerifier for AcceptAny {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, Error> {
+            verify_tls12_signature(message, cert, dss, &self.0)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, Error> {
+            verify_tls13_signature(message, cert, dss, &self.0)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.supported_schemes()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TlsConfig;
+
+    #[test]
+    fn test_config() {
+        let config: TlsConfig = toml::from_str(
+            r#"
+            ca_file = "/etc/sensorflow/home-ca.pem"
+            cert_file = "/etc/sensorflow/client.pem"
+            "#,
+        )
+        .unwrap();
+        assert!(!config.insecure_skip_verify);
+        #[cfg(feature = "tls")]
+        assert_eq!(
+            config.client_auth().unwrap_err().to_string(),
+            "Client certificate and key must be configured together"
+        );
+        assert!(toml::from_str::<TlsConfig>("verify = false").is_err());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_http_client() {
+        let insecure = TlsConfig {
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        insecure
+            .http_client(reqwest::Client::builder())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let missing = TlsConfig {
+            ca_file: Some("/nonexistent/ca.pem".into()),
+            ..Default::default()
+        };
+        let error = missing.http_client(reqwest::Client::builder()).err();
+        assert_eq!(
+            error.unwrap().to_string(),
+            "Failed to read /nonexistent/ca.pem"
+        );
+    }
+
+    #[cfg(all(feature = "tls", feature = "mqtt"))]
+    #[test]
+    fn test_mqtt_transport() {
+        let insecure = TlsConfig {
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            insecure.mqtt_transport().unwrap(),
+            rumqttc::Transport::Tls(_)
+        ));
+    }
+}
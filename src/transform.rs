@@ -0,0 +1,54 @@
+//! Processing steps between inputs and outputs.
+use crate::Measurement;
+use chrono::{DateTime, Utc};
+use tags::Tags;
+
+pub mod battery;
+pub mod counter;
+pub mod events;
+pub mod identity;
+pub mod metadata;
+pub mod position;
+pub mod pressure;
+pub mod schema;
+pub mod tags;
+pub mod totals;
+pub mod watchdog;
+
+/// A processing step of a pipeline.
+///
+/// A transform receives every measurement and may modify, drop or multiply it.
+pub trait Transform: Send {
+    fn apply(&mut self, measurement: Measurement) -> Vec<Measurement>;
+
+    /// Called by the pipeline every [TICK](crate::pipeline::TICK) with the time of its clock,
+    /// e.g. to report something which did not happen. The measurements pass the following
+    /// transforms.
+    fn tick(&mut self, _now: DateTime<Utc>) -> Vec<Measurement> {
+        Vec::new()
+    }
+}
+
+/// Name and tags of a measurement, telling apart the sensors sending it
+pub(crate) fn sensor_key(measurement: &Measurement) -> String {
+    let mut key = measurement.name.clone();
+    for (name, value) in &measurement.tags {
+        key.push_str(&format!(",{}={}", name, value));
+    }
+    key
+}
+
+/// Whether `measurement` is named `name`, if set, and has all tags of `selector`
+pub(crate) fn selects(name: Option<&str>, selector: &Tags, measurement: &Measurement) -> bool {
+    name.is_none_or(|name| name == measurement.name)
+        && selector
+            .iter()
+            .all(|(key, value)| measurement.tag(key) == Some(value.as_str()))
+}
+
+/// `measurement` at `seconds` after the start of the tests
+#[cfg(test)]
+pub(crate) fn at(seconds: i64, measurement: Measurement) -> Measurement {
+    let start = DateTime::from_timestamp(1700000000, 0).unwrap();
+    measurement.add_time(Some(start + chrono::TimeDelta::seconds(seconds)))
+}
@@ -0,0 +1,256 @@
+//! Charge of batteries estimated from their voltage.
+//!
+//! ```toml
+//! [[pipeline.batteries]]
+//! match = { model = "WSDCGQ11LM" }
+//! field = "voltage"
+//! scale = 0.001
+//! chemistry = "cr2032"
+//!
+//! [[pipeline.batteries]]
+//! measurement = "obs_st"
+//! field = "battery"
+//! curve = [[2.35, 0], [2.5, 50], [2.8, 100]]
+//! ```
+//!
+//! Sensors are selected by the tags in `match`, e.g. their model, and optionally by the name of
+//! their measurements. [BatteryLevels] adds the charge in % as `battery_percent`, interpolated
+//! on the discharge curve of the `chemistry` or on the given `curve` of voltages per cell and
+//! percentages. The voltage is the field multiplied by `scale` and divided by the `cells` in
+//! series. The first matching battery applies.
+use super::{selects, tags::Tags, Transform};
+use crate::measurement::{Measurement, Unit};
+use serde::Deserialize;
+
+/// Kinds of batteries with typical discharge curves
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum Chemistry {
+    /// Alkaline AA or AAA cells
+    Alkaline,
+    /// Rechargeable NiMH cells
+    Nimh,
+    /// Lithium ion or polymer cells
+    Lithium,
+    /// Lithium iron phosphate cells
+    Lifepo4,
+    /// Lithium coin cells, e.g. CR2032
+    Cr2032,
+    /// Lithium thionyl chloride cells of LoRa sensors, e.g. ER14505
+    Lisocl2,
+}
+
+impl Chemistry {
+    /// Voltages per cell and percentages, by increasing voltage
+    fn curve(&self) -> &'static [(f64, f64)] {
+        match self {
+            Chemistry::Alkaline => &[
+                (1.0, 0.),
+                (1.1, 10.),
+                (1.2, 30.),
+                (1.3, 60.),
+                (1.4, 85.),
+                (1.5, 95.),
+                (1.6, 100.),
+            ],
+            Chemistry::Nimh => &[
+                (1.0, 0.),
+                (1.1, 5.),
+                (1.15, 15.),
+                (1.2, 40.),
+                (1.25, 70.),
+                (1.3, 90.),
+                (1.4, 100.),
+            ],
+            Chemistry::Lithium => &[
+                (3.0, 0.),
+                (3.3, 5.),
+                (3.6, 20.),
+                (3.7, 45.),
+                (3.8, 65.),
+                (3.9, 80.),
+                (4.0, 90.),
+                (4.2, 100.),
+            ],
+            Chemistry::Lifepo4 => &[
+                (2.5, 0.),
+                (3.0, 10.),
+                (3.2, 30.),
+                (3.25, 60.),
+                (3.3, 80.),
+                (3.4, 100.),
+            ],
+            Chemistry::Cr2032 => &[
+                (2.0, 0.),
+                (2.5, 10.),
+                (2.7, 30.),
+                (2.8, 60.),
+                (2.9, 80.),
+                (3.0, 100.),
+            ],
+            Chemistry::Lisocl2 => &[(3.0, 0.), (3.3, 20.), (3.5, 70.), (3.6, 100.)],
+        }
+    }
+}
+
+fn default_one() -> f64 {
+    1.
+}
+
+fn default_cells() -> u32 {
+    1
+}
+
+/// Battery of a sensor model
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct BatteryConfig {
+    /// Name of the measurements, any if not set
+    #[serde(default)]
+    pub measurement: Option<String>,
+    /// Tags the measurements must have
+    #[serde(default, rename = "match")]
+    pub selector: Tags,
+    /// Field with the voltage
+    pub field: String,
+    /// Factor converting the field to volts, e.g. 0.001 for millivolts
+    #[serde(default = "default_one")]
+    pub scale: f64,
+    /// Number of cells in series
+    #[serde(default = "default_cells")]
+    pub cells: u32,
+    /// Chemistry with the discharge curve
+    pub chemistry: Option<Chemistry>,
+    /// Voltages per cell and percentages, instead of the curve of the chemistry
+    #[serde(default)]
+    pub curve: Vec<(f64, f64)>,
+}
+
+impl BatteryConfig {
+    fn matches(&self, measurement: &Measurement) -> bool {
+        selects(self.measurement.as_deref(), &self.selector, measurement)
+    }
+}
+
+/// Percentage at `voltage` on `curve`, by increasing voltage
+pub fn interpolate(curve: &[(f64, f64)], voltage: f64) -> Option<f64> {
+    let (first, last) = (curve.first()?, curve.last()?);
+    if voltage <= first.0 {
+        return Some(first.1);
+    }
+    if voltage >= last.0 {
+        return Some(last.1);
+    }
+    curve.windows(2).find_map(|pair| {
+        let [(v0, p0), (v1, p1)] = [pair[0], pair[1]];
+        (voltage <= v1).then(|| p0 + (p1 - p0) * (voltage - v0) / (v1 - v0))
+    })
+}
+
+/// Transform adding the charge of batteries
+pub struct BatteryLevels {
+    batteries: Vec<(BatteryConfig, Vec<(f64, f64)>)>,
+}
+
+impl BatteryLevels {
+    pub fn new(configs: Vec<BatteryConfig>) -> anyhow::Result<BatteryLevels> {
+        let batteries = configs
+            .into_iter()
+            .map(|config| {
+                let mut curve = match (&config.chemistry, config.curve.is_empty()) {
+                    (_, false) => config.curve.clone(),
+                    (Some(chemistry), true) => chemistry.curve().to_vec(),
+                    (None, true) => {
+                        anyhow::bail!("Battery of {} needs a chemistry or a curve", config.field)
+                    }
+                };
+                curve.sort_by(|a, b| a.0.total_cmp(&b.0));
+                Ok((config, curve))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(BatteryLevels { batteries })
+    }
+}
+
+impl Transform for BatteryLevels {
+    fn apply(&mut self, measurement: Measurement) -> Vec<Measurement> {
+        let Some((config, curve)) = self
+            .batteries
+            .iter()
+            .find(|(config, _)| config.matches(&measurement))
+        else {
+            return vec![measurement];
+        };
+        let Some(value) = measurement.field(&config.field).and_then(|v| v.as_f64()) else {
+            return vec![measurement];
+        };
+        let voltage = value * config.scale / config.cells.max(1) as f64;
+        match interpolate(curve, voltage) {
+            Some(percent) => vec![measurement
+                .add_field("battery_percent", percent)
+                .add_field_meta("battery_percent", Unit::Percent)],
+            None => vec![measurement],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{interpolate, BatteryConfig, BatteryLevels, Chemistry};
+    use crate::{transform::Transform, Measurement};
+
+    #[test]
+    fn test_interpolate() {
+        let curve = Chemistry::Cr2032.curve();
+        assert_eq!(interpolate(curve, 1.5), Some(0.));
+        assert_eq!(interpolate(curve, 2.85).map(f64::round), Some(70.));
+        assert_eq!(interpolate(curve, 3.2), Some(100.));
+        assert_eq!(interpolate(&[], 3.), None);
+    }
+
+    #[test]
+    fn test_battery_levels() {
+        let configs: Vec<BatteryConfig> = toml::from_str::<toml::Table>(
+            r#"
+            batteries = [
+                { match = { model = "WSDCGQ11LM" }, field = "voltage", scale = 0.001, chemistry = "cr2032" },
+                { measurement = "node", field = "vbat", cells = 2, curve = [[1.2, 100], [0.9, 0]] },
+            ]
+            "#,
+        )
+        .unwrap()["batteries"]
+            .clone()
+            .try_into()
+            .unwrap();
+        let mut levels = BatteryLevels::new(configs).unwrap();
+        let mut percent = |measurement| {
+            levels
+                .apply(measurement)
+                .remove(0)
+                .field("battery_percent")
+                .and_then(|v| v.as_f64())
+                .map(f64::round)
+        };
+        let aqara = Measurement::new("zigbee").add_tag("model", "WSDCGQ11LM");
+        assert_eq!(
+            percent(aqara.clone().add_field("voltage", 2950u64)),
+            Some(90.)
+        );
+        assert_eq!(percent(aqara.add_field("temperature", 20.)), None);
+        let node = Measurement::new("node").add_field("vbat", 2.1);
+        assert_eq!(percent(node), Some(50.));
+
+        let config = BatteryConfig {
+            measurement: None,
+            selector: Default::default(),
+            field: "voltage".into(),
+            scale: 1.,
+            cells: 1,
+            chemistry: None,
+            curve: Vec::new(),
+        };
+        assert!(BatteryLevels::new(vec![config]).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+//! Deltas and rates of cumulative meters.
+//!
+//! Energy, water and gas meters report their totals. [CounterDeltas] adds to every measurement
+//! of such a meter, for each cumulative field `<field>`, the increase since the previous
+//! measurement of the same sensor as `<field>_delta` and the increase per second as
+//! `<field>_rate`. Cumulative fields are those marked as counters by their device and those
+//! configured in [CounterConfig::fields], which are marked as counters.
+//!
+//! A total below the previous one is a rollover if the field has a `rollover` value and the
+//! increase across it is less than half of that value, e.g. a mechanical register going from
+//! 99998 to 2. Otherwise the meter was reset, e.g. by a power loss, and counted from zero.
+//!
+//! Only totals newer than the previous one are compared. A reading delivered late or out of
+//! order gets no delta and rate, and is not taken for a reset.
+use super::{sensor_key, Transform};
+use crate::measurement::{FieldKind, FieldMeta, FieldValue, Measurement, Unit};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Fields of cumulative meters
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct CounterConfig {
+    /// Cumulative fields in addition to those marked as counters by their device
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// Value at which a field wraps around to zero, by field name
+    #[serde(default)]
+    pub rollover: BTreeMap<String, f64>,
+}
+
+/// Previous total of a field
+struct Total {
+    value: f64,
+    time: DateTime<Utc>,
+}
+
+/// Transform adding deltas and rates of cumulative fields
+pub struct CounterDeltas {
+    config: CounterConfig,
+    /// Previous totals by sensor and field
+    totals: HashMap<(String, String), Total>,
+}
+
+impl CounterDeltas {
+    pub fn new(config: CounterConfig) -> CounterDeltas {
+        CounterDeltas {
+            config,
+            totals: HashMap::new(),
+        }
+    }
+
+    /// Increase from `previous` to `value`
+    fn delta(&self, field: &str, previous: f64, value: f64) -> f64 {
+        if value >= previous {
+            return value - previous;
+        }
+        match self.config.rollover.get(field) {
+            Some(rollover) if rollover - previous + value < rollover / 2. => {
+                rollover - previous + value
+            }
+            _ => value,
+        }
+    }
+}
+
+/// Unit of the increase per second of a total in `unit`
+fn rate_unit(unit: &Unit) -> (Unit, f64) {
+    match unit {
+        Unit::KilowattHour => (Unit::Watt, 3_600_000.),
+        unit => (Unit::from_symbol(&format!("{}/s", unit.symbol())), 1.),
+    }
+}
+
+impl Transform for CounterDeltas {
+    fn apply(&mut self, mut measurement: Measurement) -> Vec<Measurement> {
+        let time = measurement.time.unwrap_or_else(Utc::now);
+        let key = sensor_key(&measurement);
+        let counters: Vec<(String, f64)> = measurement
+            .fields
+            .iter()
+            .filter(|(field, _)| {
+                measurement.field_meta(field).kind == FieldKind::Counter
+                    || self.config.fields.contains(field)
+            })
+            .filter_map(|(field, value)| match value {
+                FieldValue::String(_) => None,
+                value => Some((field.clone(), value.as_f64()?)),
+            })
+            .collect();
+        for (field, value) in counters {
+            let mut meta = measurement.field_meta(&field);
+            if meta.kind != FieldKind::Counter {
+                meta.kind = FieldKind::Counter;
+                measurement = measurement.add_field_meta(&field, meta.clone());
+            }
+            let total_key = (key.clone(), field.clone());
+            if self
+                .totals
+                .get(&total_key)
+                .is_some_and(|previous| time <= previous.time)
+            {
+                continue;
+            }
+            let total = Total { value, time };
+            let Some(previous) = self.totals.insert(total_key, total) else {
+                continue;
+            };
+            let delta = self.delta(&field, previous.value, value);
+            let delta_field = format!("{}_delta", field);
+            measurement = measurement.add_field(&delta_field, delta);
+            if let Some(unit) = &meta.unit {
+                measurement = measurement.add_field_meta(&delta_field, unit.clone());
+            }
+            let seconds = (time - previous.time).num_milliseconds() as f64 / 1000.;
+            if seconds > 0. {
+                let rate_field = format!("{}_rate", field);
+                let (unit, factor) = match &meta.unit {
+                    Some(unit) => rate_unit(unit),
+                    None => (Unit::from_symbol("1/s"), 1.),
+                };
+                measurement = measurement
+                    .add_field(&rate_field, delta * factor / seconds)
+                    .add_field_meta(&rate_field, FieldMeta::from(unit));
+            }
+        }
+        vec![measurement]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CounterConfig, CounterDeltas};
+    use crate::{
+        measurement::{FieldKind, FieldMeta, Unit},
+        transform::{at, Transform},
+        Measurement,
+    };
+
+    fn reading(seconds: i64, energy: f64, volume: u64) -> Measurement {
+        at(
+            seconds,
+            Measurement::new("meter")
+                .add_tag("meterId", 7)
+                .add_field("energy", energy)
+                .add_field_meta("energy", FieldMeta::counter(Some(Unit::KilowattHour)))
+                .add_field("volume", volume)
+                .add_field("temperature", 21.5),
+        )
+    }
+
+    #[test]
+    fn test_deltas_and_rates() {
+        let mut counters = CounterDeltas::new(CounterConfig {
+            fields: vec!["volume".into()],
+            rollover: [("volume".into(), 100000.)].into(),
+        });
+        let first = counters.apply(reading(0, 10., 99990)).remove(0);
+        assert_eq!(first.fields.len(), 3);
+        assert_eq!(first.field_meta("volume").kind, FieldKind::Counter);
+
+        let second = counters.apply(reading(3600, 10.5, 10)).remove(0);
+        let value = |name: &str| second.field(name).and_then(|value| value.as_f64());
+        assert_eq!(value("energy_delta"), Some(0.5));
+        assert_eq!(value("energy_rate"), Some(500.));
+        assert_eq!(second.field_meta("energy_rate").unit, Some(Unit::Watt));
+        // Rollover of the register
+        assert_eq!(value("volume_delta"), Some(20.));
+        assert_eq!(value("temperature_delta"), None);
+
+        // Reset of the meter
+        let third = counters.apply(reading(3660, 0.2, 50000)).remove(0);
+        let value = |name: &str| third.field(name).and_then(|value| value.as_f64());
+        assert_eq!(value("energy_delta"), Some(0.2));
+        assert_eq!(value("volume_delta"), Some(49990.));
+        assert_eq!(
+            third.field_meta("volume_rate").unit,
+            Some(Unit::Other("1/s".into()))
+        );
+
+        // Sensors are told apart by their tags
+        let other = counters
+            .apply(reading(3700, 1., 1).add_tag("phase", 2))
+            .remove(0);
+        assert_eq!(other.fields.len(), 3);
+    }
+
+    #[test]
+    fn test_late_readings_are_no_reset() {
+        let mut counters = CounterDeltas::new(CounterConfig::default());
+        counters.apply(reading(0, 10., 0));
+        counters.apply(reading(60, 11., 0));
+        let late = counters.apply(reading(30, 10.5, 0)).remove(0);
+        assert_eq!(late.field("energy_delta"), None);
+        let repeated = counters.apply(reading(60, 11., 0)).remove(0);
+        assert_eq!(repeated.field("energy_delta"), None);
+
+        let next = counters.apply(reading(120, 12., 0)).remove(0);
+        let value = |name: &str| next.field(name).and_then(|value| value.as_f64());
+        assert_eq!(value("energy_delta"), Some(1.));
+    }
+}
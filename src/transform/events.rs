@@ -0,0 +1,355 @@
+//! Discrete events detected in measurements, e.g. a door opened or the onset of frost.
+//!
+//! ```toml
+//! [[pipeline.events]]
+//! name = "door_open"
+//! measurement = "contact"
+//! match = { senderId = "0180A2F3" }
+//! field = "closed"
+//! becomes = false
+//!
+//! [[pipeline.events]]
+//! name = "freeze"
+//! field = "temperature"
+//! below = 0.0
+//! hysteresis = 0.5
+//!
+//! [[pipeline.events]]
+//! name = "rapid_temperature_change"
+//! field = "temperature"
+//! change = 3.0
+//! window = 600
+//! ```
+//!
+//! Each rule watches a field of the measurements of each sensor matching its `measurement` and
+//! `match` tags, with exactly one condition:
+//!
+//! - `becomes`: the field changes to the value
+//! - `below` or `above`: the field crosses the threshold. It has to get back past it by
+//!   `hysteresis` before the next crossing counts.
+//! - `change`: the field changed by at least that much, up or down, within `window` seconds
+//!
+//! When the condition becomes true, [EventDetector] adds an `event` measurement with the rule
+//! as `event`, the name of the measurement as `measurement`, its tags and the field with its
+//! value, plus `<field>_change` for changes. The first measurement of a sensor only sets the
+//! initial state. `event` measurements are listed as alerts by the email digest by default.
+use super::{selects, sensor_key, tags::Tags, Transform};
+use crate::measurement::{FieldValue, Measurement};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+
+fn default_window() -> u64 {
+    600
+}
+
+/// Value of a field in a rule
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(untagged)]
+pub enum RuleValue {
+    Boolean(bool),
+    Number(f64),
+    String(String),
+}
+
+impl RuleValue {
+    fn matches(&self, value: &FieldValue) -> bool {
+        match (self, value) {
+            (RuleValue::Boolean(rule), FieldValue::Boolean(value)) => rule == value,
+            (RuleValue::String(rule), FieldValue::String(value)) => rule == value,
+            (RuleValue::Number(rule), value) => value.as_f64() == Some(*rule),
+            _ => false,
+        }
+    }
+}
+
+/// Rule of an event
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct EventRule {
+    /// Name of the event, e.g. `door_open`
+    pub name: String,
+    /// Name of the measurements, any if not set
+    #[serde(default)]
+    pub measurement: Option<String>,
+    /// Tags the measurements must have
+    #[serde(default, rename = "match")]
+    pub selector: Tags,
+    pub field: String,
+    /// Value the field changes to
+    #[serde(default)]
+    pub becomes: Option<RuleValue>,
+    /// Threshold the field falls below
+    #[serde(default)]
+    pub below: Option<f64>,
+    /// Threshold the field rises above
+    #[serde(default)]
+    pub above: Option<f64>,
+    /// Distance from `below` or `above` to cross back before the next event
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// Minimum change of the field within `window`
+    #[serde(default)]
+    pub change: Option<f64>,
+    /// Seconds of the change
+    #[serde(default = "default_window")]
+    pub window: u64,
+}
+
+impl EventRule {
+    fn matches(&self, measurement: &Measurement) -> bool {
+        selects(self.measurement.as_deref(), &self.selector, measurement)
+    }
+
+    fn condition(&self) -> anyhow::Result<Condition> {
+        let conditions = [
+            self.becomes.clone().map(Condition::Becomes),
+            self.below.map(Condition::Below),
+            self.above.map(Condition::Above),
+            self.change
+                .map(|minimum| Condition::Change(minimum, TimeDelta::zero())),
+        ];
+        let mut conditions = conditions.into_iter().flatten();
+        match (conditions.next(), conditions.next()) {
+            (Some(Condition::Change(minimum, _)), None) => {
+                let window = i64::try_from(self.window)
+                    .ok()
+                    .and_then(TimeDelta::try_seconds)
+                    .filter(|window| !window.is_zero())
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Event {} needs a window of a valid length", self.name)
+                    })?;
+                Ok(Condition::Change(minimum, window))
+            }
+            (Some(condition), None) => Ok(condition),
+            _ => anyhow::bail!(
+                "Event {} needs one of becomes, below, above or change",
+                self.name
+            ),
+        }
+    }
+}
+
+/// Condition of a rule
+enum Condition {
+    Becomes(RuleValue),
+    Below(f64),
+    Above(f64),
+    /// Minimum change within the window
+    Change(f64, TimeDelta),
+}
+
+/// State of a rule for a sensor
+#[derive(Default)]
+struct Watch {
+    /// Whether the condition was met by the previous measurement
+    met: bool,
+    /// Values within the window of changes
+    history: VecDeque<(DateTime<Utc>, f64)>,
+}
+
+/// Transform adding events
+pub struct EventDetector {
+    rules: Vec<(EventRule, Condition)>,
+    /// States by rule and sensor
+    watches: HashMap<(usize, String), Watch>,
+}
+
+impl EventDetector {
+    pub fn new(rules: Vec<EventRule>) -> anyhow::Result<EventDetector> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let condition = rule.condition()?;
+                Ok((rule, condition))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(EventDetector {
+            rules,
+            watches: HashMap::new(),
+        })
+    }
+}
+
+impl Transform for EventDetector {
+    fn apply(&mut self, measurement: Measurement) -> Vec<Measurement> {
+        let time = measurement.time.unwrap_or_else(Utc::now);
+        let key = sensor_key(&measurement);
+        let mut measurements = Vec::new();
+        for (i, (rule, condition)) in self.rules.iter().enumerate() {
+            if !rule.matches(&measurement) {
+                continue;
+            }
+            let Some(value) = measurement.field(&rule.field) else {
+                continue;
+            };
+            let number = value.as_f64();
+            if number.is_none() && !matches!(condition, Condition::Becomes(_)) {
+                continue;
+            }
+            let first = !self.watches.contains_key(&(i, key.clone()));
+            let watch = self.watches.entry((i, key.clone())).or_default();
+            let mut change = None;
+            let met = match (condition, number) {
+                (Condition::Becomes(target), _) => target.matches(value),
+                (Condition::Below(threshold), Some(number)) => match watch.met {
+                    true => number < threshold + rule.hysteresis,
+                    false => number < *threshold,
+                },
+                (Condition::Above(threshold), Some(number)) => match watch.met {
+                    true => number > threshold - rule.hysteresis,
+                    false => number > *threshold,
+                },
+                (Condition::Change(minimum, window), Some(number)) => {
+                    let start = time.checked_sub_signed(*window);
+                    while watch
+                        .history
+                        .front()
+                        .is_some_and(|(t, _)| start.is_some_and(|start| *t < start))
+                    {
+                        watch.history.pop_front();
+                    }
+                    // The largest change within the window
+                    change = watch
+                        .history
+                        .iter()
+                        .map(|(_, previous)| number - previous)
+                        .max_by(|a, b| a.abs().total_cmp(&b.abs()));
+                    watch.history.push_back((time, number));
+                    change.is_some_and(|change| change.abs() >= *minimum)
+                }
+                _ => continue,
+            };
+            let happened = met && !watch.met && !first;
+            watch.met = met;
+            if !happened {
+                continue;
+            }
+            let mut event = measurement
+                .tags
+                .iter()
+                .fold(
+                    Measurement::new("event")
+                        .add_tag("event", &rule.name)
+                        .add_tag("measurement", &measurement.name),
+                    |event, (key, value)| event.add_tag(key, value),
+                )
+                .add_field(&rule.field, value.clone());
+            if let Some(change) = change {
+                event = event.add_field(format!("{}_change", rule.field), change);
+            }
+            measurements.push(event.add_time(Some(time)));
+        }
+        measurements.insert(0, measurement);
+        measurements
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EventDetector, EventRule};
+    use crate::{
+        transform::{at, Transform},
+        Measurement,
+    };
+
+    fn rules(toml: &str) -> Vec<EventRule> {
+        toml::from_str::<toml::Table>(toml).unwrap()["events"]
+            .clone()
+            .try_into()
+            .unwrap()
+    }
+
+    fn temperature(seconds: i64, temperature: f64) -> Measurement {
+        at(
+            seconds,
+            Measurement::new("tempHum")
+                .add_tag("sensorId", 12)
+                .add_field("temperature", temperature),
+        )
+    }
+
+    /// Names of the events of `measurement`
+    fn events(detector: &mut EventDetector, measurement: Measurement) -> Vec<String> {
+        detector
+            .apply(measurement)
+            .iter()
+            .skip(1)
+            .map(|event| event.tag("event").unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_transitions() {
+        let mut detector = EventDetector::new(rules(
+            r#"events = [{ name = "door_open", measurement = "contact", field = "closed", becomes = false }]"#,
+        ))
+        .unwrap();
+        let contact = |seconds, closed| {
+            at(
+                seconds,
+                Measurement::new("contact")
+                    .add_tag("senderId", "0180A2F3")
+                    .add_field("closed", closed),
+            )
+        };
+        // The initial state is no event
+        assert!(events(&mut detector, contact(0, false)).is_empty());
+        assert!(events(&mut detector, contact(1, true)).is_empty());
+        let measurements = detector.apply(contact(2, false));
+        assert_eq!(
+            measurements[1],
+            Measurement::new("event")
+                .add_tag("event", "door_open")
+                .add_tag("measurement", "contact")
+                .add_tag("senderId", "0180A2F3")
+                .add_field("closed", false)
+                .add_time(contact(2, false).time)
+        );
+        assert!(events(&mut detector, contact(3, false)).is_empty());
+    }
+
+    #[test]
+    fn test_thresholds_and_changes() {
+        let mut detector = EventDetector::new(rules(
+            r#"events = [
+                { name = "freeze", field = "temperature", below = 0.0, hysteresis = 0.5 },
+                { name = "rapid", field = "temperature", change = 3.0, window = 600 },
+            ]"#,
+        ))
+        .unwrap();
+        assert!(events(&mut detector, temperature(0, 1.)).is_empty());
+        assert_eq!(events(&mut detector, temperature(60, -0.2)), ["freeze"]);
+        // Within the hysteresis
+        assert!(events(&mut detector, temperature(120, 0.3)).is_empty());
+        assert!(events(&mut detector, temperature(180, -0.1)).is_empty());
+        assert!(events(&mut detector, temperature(240, 0.6)).is_empty());
+        assert_eq!(events(&mut detector, temperature(300, -0.5)), ["freeze"]);
+
+        // 3.1 °C up within 10 minutes of the measurement at 300 s
+        let measurements = detector.apply(temperature(900, 2.6));
+        assert_eq!(measurements.len(), 2);
+        let change = measurements[1].field("temperature_change").unwrap();
+        assert!((change.as_f64().unwrap() - 3.1).abs() < 1e-9);
+        assert!(events(&mut detector, temperature(960, 2.7)).is_empty());
+        // Slowly
+        assert!(events(&mut detector, temperature(2000, 6.)).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        for toml in [
+            r#"events = [{ name = "none", field = "temperature" }]"#,
+            r#"events = [{ name = "both", field = "temperature", below = 0.0, above = 30.0 }]"#,
+            r#"events = [{ name = "instant", field = "temperature", change = 1.0, window = 0 }]"#,
+            r#"events = [{ name = "ages", field = "temperature", change = 1.0, window = 9223372036854776 }]"#,
+        ] {
+            assert!(EventDetector::new(rules(toml)).is_err());
+        }
+        let mut rule = rules(r#"events = [{ name = "e", field = "t", change = 1.0 }]"#);
+        rule[0].window = u64::MAX;
+        assert!(EventDetector::new(rule).is_err());
+    }
+}
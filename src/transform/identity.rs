@@ -0,0 +1,417 @@
+//! Stable names of LaCrosse sensors across battery changes.
+//!
+//! LaCrosse IT+ sensors choose a new random id whenever their battery is changed and report a
+//! new battery in their `sensor_status` for a while. The resolver tags the `tempHum` and
+//! `sensor_status` measurements of configured sensors with their logical name as `sensor`.
+//! When an unknown id reports a new battery while exactly one configured sensor of the same
+//! type has been silent for a while, and the last readings of both are similar, the sensor is
+//! remapped to the new id, after confirmation if required.
+//!
+//! With a [State], the current ids and last readings of the sensors are kept across restarts,
+//! taking precedence over the ids of the configuration.
+//!
+//! ```toml
+//! [pipeline.identity]
+//! sensors = { garden = "12", kitchen = "23" }
+//! silence = 300
+//! max_temperature_delta = 1.5
+//! ```
+use super::Transform;
+use crate::{state::State, Measurement};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+/// Sensors and limits of the matching
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    /// Current ids of the sensors by their logical name
+    pub sensors: BTreeMap<String, String>,
+    /// Seconds a sensor has to be silent before its id may be replaced
+    pub silence: u64,
+    /// Largest difference of the temperatures in °C before and after the battery change
+    pub max_temperature_delta: f64,
+    /// Largest difference of the humidities in % before and after the battery change
+    pub max_humidity_delta: f64,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        IdentityConfig {
+            sensors: BTreeMap::new(),
+            silence: 120,
+            max_temperature_delta: 2.,
+            max_humidity_delta: 10.,
+        }
+    }
+}
+
+/// Proposed change of the id of a sensor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Remap {
+    pub name: String,
+    pub old_id: String,
+    pub new_id: String,
+}
+
+impl Display for Remap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Sensor {} with a new battery looks like {}, which was sensor {}",
+            self.new_id, self.name, self.old_id
+        )
+    }
+}
+
+/// Decision whether a remap is applied, e.g. by asking the user
+pub trait Confirm: Send {
+    fn confirm(&mut self, remap: &Remap) -> bool;
+}
+
+impl<F: FnMut(&Remap) -> bool + Send> Confirm for F {
+    fn confirm(&mut self, remap: &Remap) -> bool {
+        self(remap)
+    }
+}
+
+/// Last reading of a sensor
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Reading {
+    model: Option<String>,
+    temperature: Option<f64>,
+    humidity: Option<f64>,
+    time: DateTime<Utc>,
+}
+
+impl Reading {
+    fn of(measurement: &Measurement, time: DateTime<Utc>) -> Reading {
+        let number = |name| measurement.field(name)?.as_f64();
+        Reading {
+            model: measurement.tag("sensorType").map(String::from),
+            temperature: number("temperature"),
+            humidity: number("humidity"),
+            time,
+        }
+    }
+}
+
+fn similar(a: Option<f64>, b: Option<f64>, max_delta: f64) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => (a - b).abs() <= max_delta,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+struct Sensor {
+    name: String,
+    id: String,
+    last: Option<Reading>,
+}
+
+/// Persisted state of a sensor
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SavedSensor {
+    id: String,
+    last: Option<Reading>,
+}
+
+/// Section of the state
+const STATE_KEY: &str = "identity";
+
+/// Transform keeping the logical names of sensors, see the [module](self) documentation
+pub struct IdentityResolver {
+    config: IdentityConfig,
+    sensors: Vec<Sensor>,
+    /// Last readings of ids not assigned to a sensor
+    unknown: HashMap<String, Reading>,
+    /// Remaps not confirmed, by name and new id
+    rejected: HashSet<(String, String)>,
+    confirm: Box<dyn Confirm>,
+    state: Option<State>,
+}
+
+impl IdentityResolver {
+    /// Resolver applying all remaps found
+    pub fn new(config: IdentityConfig) -> IdentityResolver {
+        Self::with_confirm(config, Box::new(|_: &Remap| true))
+    }
+
+    /// Resolver applying only the remaps accepted by `confirm`
+    pub fn with_confirm(config: IdentityConfig, confirm: Box<dyn Confirm>) -> IdentityResolver {
+        let sensors = config
+            .sensors
+            .iter()
+            .map(|(name, id)| Sensor {
+                name: name.clone(),
+                id: id.clone(),
+                last: None,
+            })
+            .collect();
+        IdentityResolver {
+            config,
+            sensors,
+            unknown: HashMap::new(),
+            rejected: HashSet::new(),
+            confirm,
+            state: None,
+        }
+    }
+
+    /// Restore the sensors from `state` and keep them there
+    pub fn with_state(mut self, state: State) -> anyhow::Result<IdentityResolver> {
+        let saved: BTreeMap<String, SavedSensor> = state.get(STATE_KEY)?.unwrap_or_default();
+        for sensor in &mut self.sensors {
+            if let Some(saved) = saved.get(&sensor.name) {
+                sensor.id = saved.id.clone();
+                sensor.last = saved.last.clone();
+            }
+        }
+        self.state = Some(state);
+        Ok(self)
+    }
+
+    /// Update the state, if any. Failed writes are retried with the next change.
+    fn save(&self) {
+        let Some(state) = &self.state else {
+            return;
+        };
+        let saved: BTreeMap<_, _> = self
+            .sensors
+            .iter()
+            .map(|sensor| {
+                let saved = SavedSensor {
+                    id: sensor.id.clone(),
+                    last: sensor.last.clone(),
+                };
+                (sensor.name.as_str(), saved)
+            })
+            .collect();
+        let _ = state.set(STATE_KEY, &saved);
+    }
+
+    /// Current id of each sensor, by name
+    pub fn ids(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.sensors
+            .iter()
+            .map(|sensor| (sensor.name.as_str(), sensor.id.as_str()))
+    }
+
+    /// Remap a sensor to the unknown `id` which reported a new battery at `time`
+    fn resolve(&mut self, id: &str, time: DateTime<Utc>) {
+        let Some(reading) = self.unknown.get(id) else {
+            return;
+        };
+        let mut candidates = self.sensors.iter_mut().filter(|sensor| {
+            let Some(last) = &sensor.last else {
+                return false;
+            };
+            let silent = (time - last.time).to_std().unwrap_or_default()
+                >= Duration::from_secs(self.config.silence);
+            silent
+                && last.model == reading.model
+                && similar(
+                    last.temperature,
+                    reading.temperature,
+                    self.config.max_temperature_delta,
+                )
+                && similar(
+                    last.humidity,
+                    reading.humidity,
+                    self.config.max_humidity_delta,
+                )
+                && !self
+                    .rejected
+                    .contains(&(sensor.name.clone(), id.to_string()))
+        });
+        let (Some(sensor), None) = (candidates.next(), candidates.next()) else {
+            return;
+        };
+        let remap = Remap {
+            name: sensor.name.clone(),
+            old_id: sensor.id.clone(),
+            new_id: id.to_string(),
+        };
+        if self.confirm.confirm(&remap) {
+            sensor.id = remap.new_id;
+            sensor.last = self.unknown.remove(id);
+            self.save();
+        } else {
+            self.rejected.insert((remap.name, remap.new_id));
+        }
+    }
+}
+
+impl Transform for IdentityResolver {
+    fn apply(&mut self, measurement: Measurement) -> Vec<Measurement> {
+        let Some(id) = measurement.tag("sensorId").map(String::from) else {
+            return vec![measurement];
+        };
+        let time = measurement.time.unwrap_or_else(Utc::now);
+        match measurement.name.as_str() {
+            "tempHum" => {
+                let reading = Reading::of(&measurement, time);
+                match self.sensors.iter_mut().find(|sensor| sensor.id == id) {
+                    Some(sensor) => {
+                        sensor.last = Some(reading);
+                        self.save();
+                    }
+                    None => {
+                        self.unknown.insert(id.clone(), reading);
+                    }
+                }
+            }
+            "sensor_status" => {
+                let new_battery = measurement.field("battery").map(|b| b.to_string());
+                let known = self.sensors.iter().any(|sensor| sensor.id == id);
+                if new_battery.as_deref() == Some("new") && !known {
+                    self.resolve(&id, time);
+                }
+            }
+            _ => return vec![measurement],
+        }
+        match self.sensors.iter().find(|sensor| sensor.id == id) {
+            Some(sensor) => vec![measurement.add_tag("sensor", &sensor.name)],
+            None => vec![measurement],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IdentityConfig, IdentityResolver, Remap};
+    use crate::{
+        state::State,
+        transform::{at, Transform},
+        Measurement,
+    };
+    use std::sync::{Arc, Mutex};
+
+    fn reading(id: &str, temperature: f64, seconds: i64) -> Measurement {
+        at(
+            seconds,
+            Measurement::new("tempHum")
+                .add_tag("sensorId", id)
+                .add_tag("sensorType", 1)
+                .add_field("temperature", temperature)
+                .add_field("humidity", 50u64),
+        )
+    }
+
+    fn status(id: &str, battery: &str, seconds: i64) -> Measurement {
+        at(
+            seconds,
+            Measurement::new("sensor_status")
+                .add_tag("sensorId", id)
+                .add_field("battery", battery),
+        )
+    }
+
+    fn config() -> IdentityConfig {
+        IdentityConfig {
+            sensors: [
+                ("kitchen".into(), "50".into()),
+                ("garden".into(), "12".into()),
+            ]
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_remap_after_battery_change() {
+        let mut resolver = IdentityResolver::new(config());
+        let tagged = resolver.apply(reading("50", 21.5, 0));
+        assert_eq!(tagged[0].tag("sensor"), Some("kitchen"));
+        resolver.apply(reading("12", 5., 0));
+
+        // Too early, the old id may still be in use
+        resolver.apply(reading("23", 21.7, 60));
+        resolver.apply(status("23", "new", 60));
+        assert_eq!(
+            resolver.apply(reading("23", 21.7, 64))[0].tag("sensor"),
+            None
+        );
+
+        resolver.apply(reading("23", 21.7, 300));
+        let tagged = resolver.apply(status("23", "new", 300));
+        assert_eq!(tagged[0].tag("sensor"), Some("kitchen"));
+        assert_eq!(
+            resolver.apply(reading("23", 21.8, 304))[0].tag("sensor"),
+            Some("kitchen")
+        );
+        assert_eq!(
+            resolver.ids().collect::<Vec<_>>(),
+            [("garden", "12"), ("kitchen", "23")]
+        );
+
+        // Different values
+        resolver.apply(reading("7", 15., 600));
+        resolver.apply(status("7", "new", 600));
+        assert_eq!(
+            resolver.apply(reading("7", 15., 604))[0].tag("sensor"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rejected_remap() {
+        let asked = Arc::new(Mutex::new(vec![]));
+        let answers = asked.clone();
+        let mut resolver = IdentityResolver::with_confirm(
+            config(),
+            Box::new(move |remap: &Remap| {
+                answers.lock().unwrap().push(remap.clone());
+                false
+            }),
+        );
+        resolver.apply(reading("50", 21.5, 0));
+        resolver.apply(reading("23", 21.7, 300));
+        resolver.apply(status("23", "new", 300));
+        resolver.apply(status("23", "new", 304));
+        assert_eq!(
+            *asked.lock().unwrap(),
+            [Remap {
+                name: "kitchen".into(),
+                old_id: "50".into(),
+                new_id: "23".into()
+            }]
+        );
+        assert_eq!(
+            resolver.apply(reading("23", 21.7, 308))[0].tag("sensor"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_state() {
+        let path = std::env::temp_dir().join(format!("sensorflow-identity-{}", std::process::id()));
+        let state = State::open(&path).unwrap();
+        let mut resolver = IdentityResolver::new(config())
+            .with_state(state.clone())
+            .unwrap();
+        resolver.apply(reading("50", 21.5, 0));
+        resolver.apply(reading("23", 21.7, 300));
+        resolver.apply(status("23", "new", 300));
+        drop(resolver);
+        drop(state);
+
+        let state = State::open(&path).unwrap();
+        let mut resolver = IdentityResolver::new(config()).with_state(state).unwrap();
+        assert_eq!(
+            resolver.ids().collect::<Vec<_>>(),
+            [("garden", "12"), ("kitchen", "23")]
+        );
+        // The last reading is restored as well
+        resolver.apply(reading("31", 21.9, 900));
+        resolver.apply(status("31", "new", 900));
+        assert_eq!(resolver.ids().nth(1), Some(("kitchen", "31")));
+        drop(resolver);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+//! Attributes of sensors, e.g. where they are and who owns them, read from a TOML file.
+//!
+//! ```toml
+//! [[sensors]]
+//! match = { sensorId = "12" }
+//! location = "kitchen"
+//! floor = "1"
+//! owner = "alice"
+//! interval = 60
+//! altitude = 520.0
+//!
+//! [[sensors]]
+//! measurement = "particulates"
+//! match = { device = "/dev/ttyUSB1" }
+//! location = "balcony"
+//! tags = { model = "pms5003" }
+//! ```
+//!
+//! A sensor is selected by the tags in `match` and optionally by the name of its measurements.
+//! The first matching sensor applies. [MetadataTags] adds its `location`, `floor`, `owner` and
+//! `tags` to the measurements, never replacing tags set before. The expected `interval` in
+//! seconds between two reports of the sensor is used by the [Watchdog], its `altitude` in metres
+//! by [SeaLevelPressure].
+//!
+//! [Watchdog]: super::watchdog::Watchdog
+//! [SeaLevelPressure]: super::pressure::SeaLevelPressure
+use super::{
+    selects,
+    tags::{add_missing, Tags},
+    Transform,
+};
+use crate::Measurement;
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Attributes of a sensor and how to recognize its measurements
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct SensorEntry {
+    /// Name of the measurements, any if not set
+    #[serde(default)]
+    pub measurement: Option<String>,
+    /// Tags the measurements must have
+    #[serde(rename = "match")]
+    pub selector: Tags,
+    pub location: Option<String>,
+    pub floor: Option<String>,
+    pub owner: Option<String>,
+    /// Expected time in seconds between two reports
+    pub interval: Option<u64>,
+    /// Altitude in metres, for the sea level pressure
+    pub altitude: Option<f64>,
+    /// Further tags added to the measurements
+    #[serde(default)]
+    pub tags: Tags,
+}
+
+impl SensorEntry {
+    /// Whether `measurement` is one of the sensor
+    pub fn matches(&self, measurement: &Measurement) -> bool {
+        selects(self.measurement.as_deref(), &self.selector, measurement)
+    }
+
+    /// All tags added to the measurements of the sensor
+    pub fn all_tags(&self) -> Tags {
+        let mut tags = self.tags.clone();
+        for (key, value) in [
+            ("location", &self.location),
+            ("floor", &self.floor),
+            ("owner", &self.owner),
+        ] {
+            if let Some(value) = value {
+                tags.insert(key.into(), value.clone());
+            }
+        }
+        tags
+    }
+}
+
+/// Content of a sensor metadata file
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct SensorMetadata {
+    #[serde(default)]
+    pub sensors: Vec<SensorEntry>,
+}
+
+impl SensorMetadata {
+    pub fn from_toml(content: &str) -> anyhow::Result<SensorMetadata> {
+        Ok(toml::from_str(content)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<SensorMetadata> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        SensorMetadata::from_toml(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid sensor metadata {}: {}", path.display(), e))
+    }
+
+    /// First sensor matching `measurement`
+    pub fn find(&self, measurement: &Measurement) -> Option<&SensorEntry> {
+        self.sensors
+            .iter()
+            .find(|sensor| sensor.matches(measurement))
+    }
+
+    /// Expected time between two reports of the sensor of `measurement`
+    pub fn expected_interval(&self, measurement: &Measurement) -> Option<Duration> {
+        self.find(measurement)?.interval.map(Duration::from_secs)
+    }
+
+    /// Whether any sensor has an expected interval
+    pub fn has_intervals(&self) -> bool {
+        self.sensors.iter().any(|sensor| sensor.interval.is_some())
+    }
+}
+
+/// Transform adding the attributes of the sensors to their measurements
+pub struct MetadataTags {
+    metadata: Arc<SensorMetadata>,
+}
+
+impl MetadataTags {
+    pub fn new(metadata: Arc<SensorMetadata>) -> MetadataTags {
+        MetadataTags { metadata }
+    }
+}
+
+impl Transform for MetadataTags {
+    fn apply(&mut self, measurement: Measurement) -> Vec<Measurement> {
+        match self.metadata.find(&measurement) {
+            Some(sensor) => vec![add_missing(measurement, &sensor.all_tags())],
+            None => vec![measurement],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MetadataTags, SensorMetadata};
+    use crate::{transform::Transform, Measurement};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_metadata_tags() {
+        let metadata = SensorMetadata::from_toml(
+            r#"
+            [[sensors]]
+            measurement = "tempHum"
+            match = { sensorId = "12" }
+            location = "kitchen"
+            floor = "1"
+            interval = 60
+            tags = { room = "pantry" }
+
+            [[sensors]]
+            match = { sensorId = "12" }
+            owner = "alice"
+            "#,
+        )
+        .unwrap();
+        let reading = Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_tag("room", "kitchen");
+        assert_eq!(
+            metadata.expected_interval(&reading),
+            Some(Duration::from_secs(60))
+        );
+
+        let mut transform = MetadataTags::new(Arc::new(metadata));
+        let measurement = transform.apply(reading).remove(0);
+        assert_eq!(
+            measurement.tags,
+            [
+                ("sensorId".into(), "12".into()),
+                ("room".into(), "kitchen".into()),
+                ("floor".into(), "1".into()),
+                ("location".into(), "kitchen".into()),
+            ]
+        );
+        let measurement = transform
+            .apply(Measurement::new("sensor_status").add_tag("sensorId", 12))
+            .remove(0);
+        assert_eq!(measurement.tag("owner"), Some("alice"));
+        let measurement = transform
+            .apply(Measurement::new("sensor_status").add_tag("sensorId", 13))
+            .remove(0);
+        assert_eq!(measurement.tags.len(), 1);
+
+        assert!(SensorMetadata::from_toml("[[sensors]]\nmatch = {}\nroom = \"a\"").is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//! Positions of mobile deployments attached to their measurements, e.g. on boats or in vans.
+//!
+//! ```toml
+//! [pipeline.position]
+//! max_age = 30
+//! geohash = 6
+//! ```
+//!
+//! [GeoTagging] keeps the latest `position` measurement, e.g. of an [Nmea] receiver, and adds
+//! its `latitude`, `longitude` and `altitude` as fields to all other measurements within
+//! `max_age` seconds of it. Fields already present are kept. With `geohash`, the position is
+//! also added as a `geohash` tag of that many characters, e.g. 6 for cells of about 1 km, to
+//! group measurements by area without a tag per coordinate. Measurements are not tagged while
+//! the receiver has no fix or after it stopped reporting.
+//!
+//! [Nmea]: crate::devices::nmea::Nmea
+use super::Transform;
+use crate::measurement::{Measurement, Unit};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+/// Characters of geohashes
+const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Staleness and form of the attached positions
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(default, deny_unknown_fields)]
+pub struct PositionConfig {
+    /// Name of the measurements with the position
+    pub measurement: String,
+    /// Seconds a position is attached to measurements after it was received
+    pub max_age: u64,
+    /// Add the position as fields
+    pub fields: bool,
+    /// Characters of the `geohash` tag, no tag if not set
+    pub geohash: Option<usize>,
+}
+
+impl Default for PositionConfig {
+    fn default() -> Self {
+        PositionConfig {
+            measurement: "position".into(),
+            max_age: 60,
+            fields: true,
+            geohash: None,
+        }
+    }
+}
+
+/// Geohash of `precision` characters of a position in degrees
+pub fn geohash(latitude: f64, longitude: f64, precision: usize) -> String {
+    let (mut latitudes, mut longitudes) = ((-90., 90.), (-180., 180.));
+    let mut hash = String::with_capacity(precision);
+    let mut bits = 0;
+    // Bits alternate between longitude and latitude, starting with the longitude
+    for bit in 0..precision * 5 {
+        let (range, value): (&mut (f64, f64), f64) = match bit % 2 {
+            0 => (&mut longitudes, longitude),
+            _ => (&mut latitudes, latitude),
+        };
+        let mid = (range.0 + range.1) / 2.;
+        bits <<= 1;
+        if value >= mid {
+            bits |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        if bit % 5 == 4 {
+            hash.push(BASE32[bits] as char);
+            bits = 0;
+        }
+    }
+    hash
+}
+
+/// Latest fix
+struct Position {
+    latitude: f64,
+    longitude: f64,
+    altitude: Option<f64>,
+    time: DateTime<Utc>,
+}
+
+/// Transform adding the latest position
+pub struct GeoTagging {
+    config: PositionConfig,
+    position: Option<Position>,
+}
+
+impl GeoTagging {
+    pub fn new(config: PositionConfig) -> GeoTagging {
+        GeoTagging {
+            config,
+            position: None,
+        }
+    }
+}
+
+impl Transform for GeoTagging {
+    fn apply(&mut self, mut measurement: Measurement) -> Vec<Measurement> {
+        let time = measurement.time.unwrap_or_else(Utc::now);
+        let value = |field: &str| measurement.field(field).and_then(|v| v.as_f64());
+        if measurement.name == self.config.measurement {
+            if let (Some(latitude), Some(longitude)) = (value("latitude"), value("longitude")) {
+                self.position = Some(Position {
+                    latitude,
+                    longitude,
+                    altitude: value("altitude"),
+                    time,
+                });
+            }
+            return vec![measurement];
+        }
+        let Some(position) = &self.position else {
+            return vec![measurement];
+        };
+        if (time - position.time).num_seconds().unsigned_abs() > self.config.max_age {
+            return vec![measurement];
+        }
+        if self.config.fields {
+            let fields = [
+                ("latitude", Some(position.latitude), Unit::Degree),
+                ("longitude", Some(position.longitude), Unit::Degree),
+                ("altitude", position.altitude, Unit::from_symbol("m")),
+            ];
+            for (field, value, unit) in fields {
+                if let (Some(value), None) = (value, measurement.field(field)) {
+                    measurement = measurement
+                        .add_field(field, value)
+                        .add_field_meta(field, unit);
+                }
+            }
+        }
+        if let Some(precision) = self.config.geohash {
+            if measurement.tag("geohash").is_none() {
+                let hash = geohash(position.latitude, position.longitude, precision);
+                measurement = measurement.add_tag("geohash", hash);
+            }
+        }
+        vec![measurement]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{geohash, GeoTagging, PositionConfig};
+    use crate::{
+        transform::{at, Transform},
+        Measurement,
+    };
+
+    #[test]
+    fn test_geohash() {
+        assert_eq!(geohash(57.64911, 10.40744, 11), "u4pruydqqvj");
+        assert_eq!(geohash(-33.8688, 151.2093, 6), "r3gx2f");
+        assert_eq!(geohash(0., 0., 0), "");
+    }
+
+    #[test]
+    fn test_geo_tagging() {
+        let mut tagging = GeoTagging::new(PositionConfig {
+            max_age: 30,
+            geohash: Some(5),
+            ..Default::default()
+        });
+        let temperature = || Measurement::new("tempHum").add_field("temperature", 12.5);
+        let untouched = tagging.apply(at(0, temperature())).remove(0);
+        assert_eq!(untouched, at(0, temperature()));
+
+        let position = Measurement::new("position")
+            .add_field("latitude", 57.64911)
+            .add_field("longitude", 10.40744);
+        assert_eq!(tagging.apply(at(0, position.clone())), [at(0, position)]);
+        let tagged = tagging.apply(at(30, temperature())).remove(0);
+        let value = |field| tagged.field(field).and_then(|v| v.as_f64());
+        assert_eq!(value("latitude"), Some(57.64911));
+        assert_eq!(value("longitude"), Some(10.40744));
+        assert_eq!(value("altitude"), None);
+        assert_eq!(tagged.tag("geohash"), Some("u4pru"));
+        // Own coordinates are kept
+        let own = tagging
+            .apply(at(10, temperature().add_field("latitude", 1.)))
+            .remove(0);
+        assert_eq!(own.field("latitude").and_then(|v| v.as_f64()), Some(1.));
+        // Stale
+        let stale = tagging.apply(at(31, temperature())).remove(0);
+        assert_eq!(stale, at(31, temperature()));
+    }
+}
@@ -0,0 +1,141 @@
+//! Sea level pressure of barometric sensors.
+//!
+//! Sensors like the BME280 measure the pressure at their altitude, which is lower than the sea
+//! level pressure of weather reports by about 1 hPa per 8 m. [SeaLevelPressure] adds the
+//! pressure reduced to sea level as `<field>_sea_level` to measurements with a pressure field in
+//! hPa, keeping the measured one. The altitude in metres is that of the sensor in the
+//! [SensorMetadata] or the configured one.
+//!
+//! With the temperature of the measurement, the hypsometric formula is used, otherwise the
+//! international standard atmosphere.
+use super::{metadata::SensorMetadata, Transform};
+use crate::measurement::{Measurement, Unit};
+use serde::Deserialize;
+use std::sync::Arc;
+
+fn default_fields() -> Vec<String> {
+    vec!["pressure".into(), "station_pressure".into()]
+}
+
+fn default_temperature() -> Option<String> {
+    Some("temperature".into())
+}
+
+/// Altitude and fields of the sensors
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct PressureConfig {
+    /// Altitude in metres of sensors without one in the metadata
+    pub altitude: Option<f64>,
+    /// Fields with the pressure in hPa
+    #[serde(default = "default_fields")]
+    pub fields: Vec<String>,
+    /// Field with the temperature in °C, if measured together with the pressure
+    #[serde(default = "default_temperature")]
+    pub temperature: Option<String>,
+}
+
+impl Default for PressureConfig {
+    fn default() -> Self {
+        PressureConfig {
+            altitude: None,
+            fields: default_fields(),
+            temperature: default_temperature(),
+        }
+    }
+}
+
+/// Pressure at sea level of `pressure` measured at `altitude` in metres, at `temperature` in °C
+pub fn sea_level_pressure(pressure: f64, altitude: f64, temperature: Option<f64>) -> f64 {
+    match temperature {
+        Some(temperature) => {
+            let lapse = 0.0065 * altitude;
+            pressure * (1. - lapse / (temperature + lapse + 273.15)).powf(-5.257)
+        }
+        None => pressure / (1. - altitude / 44330.).powf(5.255),
+    }
+}
+
+/// Transform adding the sea level pressure
+pub struct SeaLevelPressure {
+    config: PressureConfig,
+    metadata: Arc<SensorMetadata>,
+}
+
+impl SeaLevelPressure {
+    pub fn new(config: PressureConfig, metadata: Arc<SensorMetadata>) -> SeaLevelPressure {
+        SeaLevelPressure { config, metadata }
+    }
+}
+
+impl Transform for SeaLevelPressure {
+    fn apply(&mut self, mut measurement: Measurement) -> Vec<Measurement> {
+        let altitude = self
+            .metadata
+            .find(&measurement)
+            .and_then(|sensor| sensor.altitude)
+            .or(self.config.altitude);
+        let Some(altitude) = altitude else {
+            return vec![measurement];
+        };
+        let temperature = self
+            .config
+            .temperature
+            .as_ref()
+            .and_then(|field| measurement.field(field)?.as_f64());
+        for field in &self.config.fields {
+            let Some(pressure) = measurement.field(field).and_then(|v| v.as_f64()) else {
+                continue;
+            };
+            let sea_level = format!("{}_sea_level", field);
+            measurement = measurement
+                .add_field(
+                    &sea_level,
+                    sea_level_pressure(pressure, altitude, temperature),
+                )
+                .add_field_meta(&sea_level, Unit::Hectopascal);
+        }
+        vec![measurement]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sea_level_pressure, PressureConfig, SeaLevelPressure};
+    use crate::{
+        transform::{metadata::SensorMetadata, Transform},
+        Measurement,
+    };
+    use std::sync::Arc;
+
+    #[test]
+    fn test_sea_level_pressure() {
+        assert!((sea_level_pressure(950., 520., Some(15.)) - 1010.06).abs() < 0.01);
+        assert!((sea_level_pressure(950., 520., None) - 1010.77).abs() < 0.01);
+        assert_eq!(sea_level_pressure(1013.25, 0., Some(20.)), 1013.25);
+
+        let metadata =
+            SensorMetadata::from_toml("[[sensors]]\nmatch = { chip = \"bme280\" }\naltitude = 0")
+                .unwrap();
+        let config = PressureConfig {
+            altitude: Some(520.),
+            ..Default::default()
+        };
+        let mut transform = SeaLevelPressure::new(config, Arc::new(metadata));
+        let reading = |chip: &str| {
+            Measurement::new("i2c")
+                .add_tag("chip", chip)
+                .add_field("temperature", 15.)
+                .add_field("pressure", 950.)
+        };
+        let measurement = transform.apply(reading("bmp280")).remove(0);
+        let sea_level = measurement.field("pressure_sea_level").unwrap().as_f64();
+        assert!((sea_level.unwrap() - 1010.06).abs() < 0.01);
+        assert_eq!(measurement.field("pressure").unwrap().as_f64(), Some(950.));
+        // Altitude of the metadata
+        let measurement = transform.apply(reading("bme280")).remove(0);
+        let sea_level = measurement.field("pressure_sea_level").unwrap().as_f64();
+        assert_eq!(sea_level, Some(950.));
+    }
+}
@@ -0,0 +1,99 @@
+//! Validation of measurements against the schemas of a [Registry].
+//!
+//! Nonconforming measurements are either dropped or passed on with the deviations from their
+//! schema in the `schemaError` tag, so a new or changed device does not silently write
+//! mistyped fields.
+use super::Transform;
+use crate::{schema::Registry, Measurement};
+use serde::Deserialize;
+
+/// Handling of measurements which do not conform to their schema
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum SchemaMode {
+    /// Drop them
+    Reject,
+    /// Tag them with `schemaError`
+    Flag,
+}
+
+/// Transform checking every measurement against a registry
+pub struct SchemaValidator {
+    registry: Registry,
+    mode: SchemaMode,
+    rejected: u64,
+}
+
+impl SchemaValidator {
+    pub fn new(registry: Registry, mode: SchemaMode) -> SchemaValidator {
+        SchemaValidator {
+            registry,
+            mode,
+            rejected: 0,
+        }
+    }
+
+    /// Validator against the schemas of all devices
+    pub fn builtin(mode: SchemaMode) -> SchemaValidator {
+        SchemaValidator::new(Registry::builtin(), mode)
+    }
+
+    /// Number of measurements dropped so far
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+}
+
+impl Transform for SchemaValidator {
+    fn apply(&mut self, measurement: Measurement) -> Vec<Measurement> {
+        let violations = self.registry.check(&measurement);
+        if violations.is_empty() {
+            return vec![measurement];
+        }
+        match self.mode {
+            SchemaMode::Reject => {
+                self.rejected += 1;
+                Vec::new()
+            }
+            SchemaMode::Flag => {
+                let errors = violations
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("; ");
+                vec![measurement.add_tag("schemaError", errors)]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SchemaMode, SchemaValidator};
+    use crate::{transform::Transform, Measurement};
+
+    #[test]
+    fn test_schema_validator() {
+        let valid = Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21.5);
+        let mistyped = Measurement::new("tempHum")
+            .add_tag("sensorId", 12)
+            .add_field("temperature", 21i64);
+        let unknown = Measurement::new("power").add_field("power", 100.);
+
+        let mut validator = SchemaValidator::builtin(SchemaMode::Reject);
+        assert_eq!(validator.apply(valid.clone()), std::slice::from_ref(&valid));
+        assert_eq!(validator.apply(mistyped.clone()), []);
+        assert_eq!(validator.apply(unknown.clone()), [unknown]);
+        assert_eq!(validator.rejected(), 1);
+
+        let mut validator = SchemaValidator::builtin(SchemaMode::Flag);
+        assert_eq!(validator.apply(valid.clone()), [valid]);
+        assert_eq!(
+            validator.apply(mistyped)[0].tag("schemaError"),
+            Some("field temperature is integer, expected float")
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! Static tags telling apart the measurements of several instances feeding one database.
+//!
+//! [StaticTags] adds the tags of the pipeline, e.g. `site` and `host`, to every measurement, and
+//! [tag_input] the tags of a single input, e.g. `room`. Tags of the input take precedence over
+//! those of the pipeline, and tags set by the device itself are never replaced.
+use super::Transform;
+use crate::{
+    clock::Clock,
+    devices::{Device, DeviceHealth},
+    input::FrameStats,
+    measurement::ToMeasurement,
+    output::ToOutput,
+    Measurement,
+};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::sync::Arc;
+
+/// Tags by name
+pub type Tags = BTreeMap<String, String>;
+
+pub(crate) fn add_missing(mut measurement: Measurement, tags: &Tags) -> Measurement {
+    for (key, value) in tags {
+        if measurement.tag(key).is_none() {
+            measurement = measurement.add_tag(key, value);
+        }
+    }
+    measurement
+}
+
+/// Transform adding tags to every measurement
+pub struct StaticTags {
+    tags: Tags,
+}
+
+impl StaticTags {
+    pub fn new(tags: Tags) -> StaticTags {
+        StaticTags { tags }
+    }
+}
+
+impl Transform for StaticTags {
+    fn apply(&mut self, measurement: Measurement) -> Vec<Measurement> {
+        vec![add_missing(measurement, &self.tags)]
+    }
+}
+
+/// Add `tags` to the measurements of every frame of `input`
+pub fn tag_input(input: Box<dyn Device>, tags: Tags) -> Box<dyn Device> {
+    match tags.is_empty() {
+        true => input,
+        false => Box::new(TaggedInput { input, tags }),
+    }
+}
+
+struct TaggedInput {
+    input: Box<dyn Device>,
+    tags: Tags,
+}
+
+#[async_trait]
+impl Device for TaggedInput {
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+        Ok(self.input.read_frame().await?.map(|frame| {
+            Box::new(TaggedFrame {
+                frame,
+                tags: self.tags.clone(),
+            }) as Box<dyn ToOutput>
+        }))
+    }
+
+    fn name(&self) -> &str {
+        self.input.name()
+    }
+
+    fn address(&self) -> &str {
+        self.input.address()
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.input.health()
+    }
+
+    fn frame_stats(&self) -> Option<FrameStats> {
+        self.input.frame_stats()
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.input.set_clock(clock)
+    }
+}
+
+/// Frame of a [TaggedInput], displayed like the original frame
+struct TaggedFrame {
+    frame: Box<dyn ToOutput>,
+    tags: Tags,
+}
+
+impl ToOutput for TaggedFrame {}
+
+impl Display for TaggedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.frame.to_string())
+    }
+}
+
+impl ToMeasurement for TaggedFrame {
+    fn to_measurement(&self) -> Measurement {
+        add_missing(self.frame.to_measurement(), &self.tags)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{tag_input, StaticTags, Tags};
+    use crate::{
+        clock::{Clock, MockClock},
+        devices::Device,
+        output::ToOutput,
+        transform::Transform,
+        Measurement,
+    };
+    use async_trait::async_trait;
+    use chrono::DateTime;
+    use std::sync::Arc;
+
+    struct Sensor;
+
+    #[async_trait]
+    impl Device for Sensor {
+        async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+            Ok(Some(Box::new(
+                Measurement::new("particulates").add_tag("sensor", "pms"),
+            )))
+        }
+
+        fn name(&self) -> &str {
+            "sensor"
+        }
+
+        fn address(&self) -> &str {
+            "memory"
+        }
+    }
+
+    /// Device stamping its frames with the clock it was given
+    #[derive(Default)]
+    struct Stamping {
+        clock: Option<Arc<dyn Clock>>,
+    }
+
+    #[async_trait]
+    impl Device for Stamping {
+        async fn read_frame(&mut self) -> anyhow::Result<Option<Box<dyn ToOutput>>> {
+            let time = self.clock.as_ref().map(|clock| clock.now());
+            Ok(Some(Box::new(Measurement::new("status").add_time(time))))
+        }
+
+        fn name(&self) -> &str {
+            "stamping"
+        }
+
+        fn address(&self) -> &str {
+            "memory"
+        }
+
+        fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+            self.clock = Some(clock);
+        }
+    }
+
+    fn tags(tags: &[(&str, &str)]) -> Tags {
+        tags.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_static_tags() {
+        let mut input = tag_input(
+            Box::new(Sensor),
+            tags(&[("room", "cellar"), ("sensor", "other")]),
+        );
+        let frame = input.read_frame().await.unwrap().unwrap();
+        let mut pipeline = StaticTags::new(tags(&[("room", "any"), ("site", "home")]));
+        let measurement = pipeline.apply(frame.to_measurement()).remove(0);
+        assert_eq!(
+            measurement.tags,
+            [
+                ("sensor".into(), "pms".into()),
+                ("room".into(), "cellar".into()),
+                ("site".into(), "home".into()),
+            ]
+        );
+        assert_eq!(
+            frame.to_string(),
+            Measurement::new("particulates")
+                .add_tag("sensor", "pms")
+                .to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tagged_input_uses_pipeline_clock() {
+        let time = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let mut input = tag_input(Box::<Stamping>::default(), tags(&[("room", "cellar")]));
+        input.set_clock(Arc::new(MockClock::new(time)));
+        let frame = input.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame.to_measurement().time, Some(time));
+    }
+}
@@ -0,0 +1,340 @@
+//! Running totals per calendar period, e.g. the energy of today or the rain of this month.
+//!
+//! ```toml
+//! [[pipeline.totals]]
+//! field = "energy_delta"
+//! period = "day"
+//! utc_offset = "+01:00"
+//!
+//! [[pipeline.totals]]
+//! measurement = "weather"
+//! field = "rain"
+//! period = "month"
+//! ```
+//!
+//! For every measurement with the field, [Totals] adds a `totals` measurement with the name of
+//! the measurement as `measurement`, its tags, the period as `period` and the sum of the field
+//! of the sensor since the start of the period. Increments like the `<field>_delta` of
+//! [CounterDeltas] are summed up, not totals of meters. Periods start at midnight, on Mondays
+//! and on the first day of a month or year in the time zone of `utc_offset`, UTC by default.
+//! With a [State], the totals are kept across restarts and reloads.
+//!
+//! [CounterDeltas]: super::counter::CounterDeltas
+use super::{sensor_key, Transform};
+use crate::{state::State, Measurement};
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Days, FixedOffset, NaiveDate, NaiveDateTime, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// Length of the periods
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl Period {
+    /// Start of the period containing `time`
+    fn start(&self, time: NaiveDateTime) -> NaiveDateTime {
+        let date = time.date();
+        let date = match self {
+            Period::Hour => return time.date().and_hms_opt(time.hour(), 0, 0).unwrap_or(time),
+            Period::Day => date,
+            Period::Week => date - Days::new(date.weekday().num_days_from_monday().into()),
+            Period::Month => NaiveDate::from_ymd_opt(date.year(), date.month(), 1).unwrap_or(date),
+            Period::Year => NaiveDate::from_ymd_opt(date.year(), 1, 1).unwrap_or(date),
+        };
+        date.and_time(Default::default())
+    }
+}
+
+impl Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Period::Hour => "hour",
+            Period::Day => "day",
+            Period::Week => "week",
+            Period::Month => "month",
+            Period::Year => "year",
+        })
+    }
+}
+
+impl FromStr for Period {
+    type Err = anyhow::Error;
+
+    /// Parse a period, e.g. `day`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Period::Hour,
+            Period::Day,
+            Period::Week,
+            Period::Month,
+            Period::Year,
+        ]
+        .into_iter()
+        .find(|period| period.to_string() == s)
+        .ok_or_else(|| anyhow::anyhow!("Unsupported period {}", s))
+    }
+}
+
+/// Field summed up per period
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(deny_unknown_fields)]
+pub struct TotalConfig {
+    /// Name of the measurements, any if not set
+    #[serde(default)]
+    pub measurement: Option<String>,
+    pub field: String,
+    pub period: Period,
+    /// Time zone of the period boundaries, e.g. `+01:00`
+    #[serde(default)]
+    pub utc_offset: Option<String>,
+}
+
+impl TotalConfig {
+    /// Key of the totals of the config in the state
+    fn state_key(&self) -> String {
+        match &self.measurement {
+            Some(measurement) => format!("{}.{}/{}", measurement, self.field, self.period),
+            None => format!("{}/{}", self.field, self.period),
+        }
+    }
+}
+
+/// Sum of a sensor in the current period
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Total {
+    start: DateTime<Utc>,
+    sum: f64,
+}
+
+/// Section of the state
+const STATE_KEY: &str = "totals";
+
+/// Transform adding running totals
+pub struct Totals {
+    configs: Vec<(TotalConfig, FixedOffset)>,
+    /// Totals by config and sensor
+    totals: HashMap<(usize, String), Total>,
+    state: Option<State>,
+}
+
+impl Totals {
+    pub fn new(configs: Vec<TotalConfig>) -> anyhow::Result<Totals> {
+        let configs = configs
+            .into_iter()
+            .map(|config| {
+                let offset = match &config.utc_offset {
+                    Some(offset) => offset
+                        .parse()
+                        .with_context(|| format!("Invalid UTC offset {}", offset))?,
+                    None => FixedOffset::east_opt(0).expect("zero offset"),
+                };
+                Ok((config, offset))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Totals {
+            configs,
+            totals: HashMap::new(),
+            state: None,
+        })
+    }
+
+    /// Restore the totals from `state` and keep them there
+    pub fn with_state(mut self, state: State) -> anyhow::Result<Totals> {
+        let saved: BTreeMap<String, BTreeMap<String, Total>> =
+            state.get(STATE_KEY)?.unwrap_or_default();
+        for (i, (config, _)) in self.configs.iter().enumerate() {
+            for (sensor, total) in saved.get(&config.state_key()).into_iter().flatten() {
+                self.totals.insert((i, sensor.clone()), total.clone());
+            }
+        }
+        self.state = Some(state);
+        Ok(self)
+    }
+
+    /// Update the state, if any. Failed writes are retried with the next change.
+    fn save(&self) {
+        let Some(state) = &self.state else {
+            return;
+        };
+        let mut saved: BTreeMap<String, BTreeMap<&str, &Total>> = BTreeMap::new();
+        for ((i, sensor), total) in &self.totals {
+            saved
+                .entry(self.configs[*i].0.state_key())
+                .or_default()
+                .insert(sensor, total);
+        }
+        let _ = state.set(STATE_KEY, &saved);
+    }
+}
+
+impl Transform for Totals {
+    fn apply(&mut self, measurement: Measurement) -> Vec<Measurement> {
+        let time = measurement.time.unwrap_or_else(Utc::now);
+        let key = sensor_key(&measurement);
+        let mut measurements = Vec::new();
+        for (i, (config, offset)) in self.configs.iter().enumerate() {
+            if config
+                .measurement
+                .as_ref()
+                .is_some_and(|name| *name != measurement.name)
+            {
+                continue;
+            }
+            let Some(value) = measurement.field(&config.field).and_then(|v| v.as_f64()) else {
+                continue;
+            };
+            let start = period_start(config.period, *offset, time);
+            let total = self
+                .totals
+                .entry((i, key.clone()))
+                .or_insert(Total { start, sum: 0. });
+            // Measurements of a past period are counted in the current one
+            if start > total.start {
+                *total = Total { start, sum: 0. };
+            }
+            total.sum += value;
+            let totals = measurement
+                .tags
+                .iter()
+                .fold(
+                    Measurement::new("totals").add_tag("measurement", &measurement.name),
+                    |totals, (key, value)| totals.add_tag(key, value),
+                )
+                .add_tag("period", config.period)
+                .add_field(&config.field, total.sum)
+                .add_time(Some(time));
+            measurements.push(totals);
+        }
+        if !measurements.is_empty() {
+            self.save();
+        }
+        measurements.insert(0, measurement);
+        measurements
+    }
+}
+
+/// Start of the period containing `time`
+fn period_start(period: Period, offset: FixedOffset, time: DateTime<Utc>) -> DateTime<Utc> {
+    let start = period.start(time.with_timezone(&offset).naive_local());
+    (start - offset).and_utc()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{period_start, Period, TotalConfig, Totals};
+    use crate::{state::State, transform::Transform, Measurement};
+    use chrono::{DateTime, FixedOffset};
+
+    fn rain(time: &str, rain: f64) -> Measurement {
+        Measurement::new("weather")
+            .add_tag("station", "davis")
+            .add_field("rain", rain)
+            .add_time(Some(DateTime::parse_from_rfc3339(time).unwrap().into()))
+    }
+
+    #[test]
+    fn test_totals() {
+        let config = |period, utc_offset: Option<&str>| TotalConfig {
+            measurement: Some("weather".into()),
+            field: "rain".into(),
+            period,
+            utc_offset: utc_offset.map(String::from),
+        };
+        let mut totals = Totals::new(vec![
+            config(Period::Day, Some("+01:00")),
+            config(Period::Month, None),
+        ])
+        .unwrap();
+        let sums = |totals: &mut Totals, measurement| {
+            totals
+                .apply(measurement)
+                .iter()
+                .skip(1)
+                .map(|m| {
+                    (
+                        m.tag("period").unwrap().to_string(),
+                        m.field("rain").unwrap().as_f64(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        sums(&mut totals, rain("2023-11-30T20:00:00Z", 1.));
+        assert_eq!(
+            sums(&mut totals, rain("2023-11-30T22:00:00Z", 0.5)),
+            [("day".into(), Some(1.5)), ("month".into(), Some(1.5))]
+        );
+        // Midnight at +01:00, still November in UTC
+        assert_eq!(
+            sums(&mut totals, rain("2023-11-30T23:30:00Z", 0.2)),
+            [("day".into(), Some(0.2)), ("month".into(), Some(1.7))]
+        );
+        assert_eq!(
+            sums(&mut totals, rain("2023-12-01T00:00:00Z", 0.3)),
+            [("day".into(), Some(0.5)), ("month".into(), Some(0.3))]
+        );
+        let measurements = totals.apply(rain("2023-12-01T01:00:00Z", 0.));
+        assert_eq!(measurements.len(), 3);
+        assert_eq!(measurements[1].name, "totals");
+        assert_eq!(measurements[1].tag("measurement"), Some("weather"));
+        assert_eq!(measurements[1].tag("station"), Some("davis"));
+        assert_eq!(totals.apply(Measurement::new("weather")).len(), 1);
+
+        assert!(Totals::new(vec![config(Period::Day, Some("CET"))]).is_err());
+        assert_eq!("week".parse::<Period>().unwrap(), Period::Week);
+    }
+
+    #[test]
+    fn test_state() {
+        let path = std::env::temp_dir().join(format!("sensorflow-totals-{}", std::process::id()));
+        let config = || {
+            vec![TotalConfig {
+                measurement: None,
+                field: "rain".into(),
+                period: Period::Day,
+                utc_offset: None,
+            }]
+        };
+        let state = State::open(&path).unwrap();
+        let mut totals = Totals::new(config())
+            .unwrap()
+            .with_state(state.clone())
+            .unwrap();
+        totals.apply(rain("2023-11-30T08:00:00Z", 1.));
+        totals.apply(rain("2023-11-30T09:00:00Z", 0.5));
+        drop(totals);
+        drop(state);
+
+        let state = State::open(&path).unwrap();
+        let mut totals = Totals::new(config()).unwrap().with_state(state).unwrap();
+        let measurements = totals.apply(rain("2023-11-30T10:00:00Z", 0.2));
+        assert_eq!(measurements[1].field("rain").unwrap().as_f64(), Some(1.7));
+        drop(totals);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_period_start() {
+        let time = DateTime::parse_from_rfc3339("2023-11-30T23:30:00Z")
+            .unwrap()
+            .into();
+        let offset = FixedOffset::east_opt(3600).unwrap();
+        let start = |period| period_start(period, offset, time).to_rfc3339();
+        assert_eq!(start(Period::Hour), "2023-11-30T23:00:00+00:00");
+        assert_eq!(start(Period::Day), "2023-11-30T23:00:00+00:00");
+        assert_eq!(start(Period::Week), "2023-11-26T23:00:00+00:00");
+        assert_eq!(start(Period::Month), "2023-11-30T23:00:00+00:00");
+        assert_eq!(start(Period::Year), "2022-12-31T23:00:00+00:00");
+    }
+}
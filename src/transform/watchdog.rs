@@ -0,0 +1,359 @@
+//! Detection of sensors which stopped reporting.
+//!
+//! The [Watchdog] keeps the time of the last measurement of every sensor and learns its typical
+//! interval between two reports as an exponentially weighted moving average. The interval of a
+//! sensor in the [SensorMetadata] takes precedence over the learned one. Whenever a measurement
+//! passes and on every tick of the pipeline, each sensor which missed `misses` consecutive
+//! expected reports is reported once by a `watchdog` measurement with the name and tags of its
+//! last measurement, the seconds since then as `silent` and the expected interval as
+//! `expected`. It is reported again only after it came back.
+//!
+//! With a [State], the last reports and learned intervals of the sensors are kept across
+//! restarts, so sensors which went silent meanwhile are still reported, and only once.
+use super::{metadata::SensorMetadata, sensor_key, Transform};
+use crate::{state::State, Measurement};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Limits of the watchdog
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchdogConfig {
+    /// Consecutive expected reports a sensor may miss before it is reported
+    pub misses: u32,
+    /// Weight of the latest interval in the learned interval, between 0 and 1
+    pub smoothing: f64,
+    /// Intervals to learn before a sensor without a configured interval is watched
+    pub min_samples: u32,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            misses: 3,
+            smoothing: 0.2,
+            min_samples: 3,
+        }
+    }
+}
+
+/// Last report of a sensor
+#[derive(Serialize, Deserialize)]
+struct Sensor {
+    name: String,
+    tags: Vec<(String, String)>,
+    /// Interval of the metadata, looked up again when restored
+    #[serde(skip)]
+    interval: Option<Duration>,
+    /// Moving average of the intervals in seconds
+    learned: f64,
+    samples: u32,
+    last_seen: DateTime<Utc>,
+    alerted: bool,
+}
+
+impl Sensor {
+    /// Expected interval, if configured or learned
+    fn expected(&self, config: &WatchdogConfig) -> Option<Duration> {
+        match self.interval {
+            Some(interval) => Some(interval),
+            None if self.samples >= config.min_samples.max(1) => {
+                Some(Duration::from_secs_f64(self.learned))
+            }
+            None => None,
+        }
+    }
+
+    /// Learn from a report at `now`
+    fn seen(&mut self, now: DateTime<Utc>, config: &WatchdogConfig) {
+        let interval = (now - self.last_seen).to_std().unwrap_or_default();
+        // The silence of an outage is not a typical interval
+        if !self.alerted {
+            self.learned = match self.samples {
+                0 => interval.as_secs_f64(),
+                _ => {
+                    config.smoothing * interval.as_secs_f64()
+                        + (1. - config.smoothing) * self.learned
+                }
+            };
+            self.samples = self.samples.saturating_add(1);
+        }
+        self.last_seen = now;
+        self.alerted = false;
+    }
+}
+
+/// Section of the state
+const STATE_KEY: &str = "watchdog";
+
+/// Transform adding alerts about silent sensors
+pub struct Watchdog {
+    metadata: Arc<SensorMetadata>,
+    config: WatchdogConfig,
+    /// Sensors by name and tags of their measurements
+    sensors: HashMap<String, Sensor>,
+    state: Option<State>,
+}
+
+impl Watchdog {
+    pub fn new(metadata: Arc<SensorMetadata>) -> Watchdog {
+        Watchdog {
+            metadata,
+            config: WatchdogConfig::default(),
+            sensors: HashMap::new(),
+            state: None,
+        }
+    }
+
+    pub fn with_config(
+        metadata: Arc<SensorMetadata>,
+        config: WatchdogConfig,
+    ) -> anyhow::Result<Watchdog> {
+        if !(0. ..=1.).contains(&config.smoothing) {
+            anyhow::bail!(
+                "Smoothing of the watchdog must be between 0 and 1, not {}",
+                config.smoothing
+            );
+        }
+        let mut watchdog = Watchdog::new(metadata);
+        watchdog.config = config;
+        Ok(watchdog)
+    }
+
+    /// Restore the sensors from `state` and keep them there
+    pub fn with_state(mut self, state: State) -> anyhow::Result<Watchdog> {
+        let saved: HashMap<String, Sensor> = state.get(STATE_KEY)?.unwrap_or_default();
+        for (key, mut sensor) in saved {
+            let measurement = sensor
+                .tags
+                .iter()
+                .fold(Measurement::new(&sensor.name), |m, (key, value)| {
+                    m.add_tag(key, value)
+                });
+            sensor.interval = self.metadata.expected_interval(&measurement);
+            self.sensors.insert(key, sensor);
+        }
+        self.state = Some(state);
+        Ok(self)
+    }
+
+    /// Update the state, if any. As every report changes it, it is only serialized when the
+    /// state is written and when the watchdog is dropped. Failed writes are retried later.
+    fn save(&self, force: bool) {
+        let Some(state) = &self.state else {
+            return;
+        };
+        if force || state.due() {
+            let _ = state.set(STATE_KEY, &self.sensors);
+        }
+    }
+
+    fn alert(sensor: &Sensor, expected: Duration, now: DateTime<Utc>) -> Measurement {
+        let silent = (now - sensor.last_seen).num_seconds();
+        sensor
+            .tags
+            .iter()
+            .fold(
+                Measurement::new("watchdog").add_tag("measurement", &sensor.name),
+                |alert, (key, value)| alert.add_tag(key, value),
+            )
+            .add_field("silent", silent.max(0) as u64)
+            .add_field("expected", expected.as_secs_f64().round() as u64)
+            .add_time(Some(now))
+    }
+
+    /// Alerts about the sensors silent at `now` which were not reported yet
+    fn check(&mut self, now: DateTime<Utc>) -> Vec<Measurement> {
+        let mut alerts = Vec::new();
+        for sensor in self.sensors.values_mut() {
+            let Some(expected) = sensor.expected(&self.config) else {
+                continue;
+            };
+            let silent = (now - sensor.last_seen).to_std().unwrap_or_default();
+            if !sensor.alerted && silent > expected * self.config.misses.max(1) {
+                sensor.alerted = true;
+                alerts.push(Watchdog::alert(sensor, expected, now));
+            }
+        }
+        alerts
+    }
+}
+
+impl Transform for Watchdog {
+    fn apply(&mut self, measurement: Measurement) -> Vec<Measurement> {
+        let now = measurement.time.unwrap_or_else(Utc::now);
+        let interval = self.metadata.expected_interval(&measurement);
+        match self.sensors.get_mut(&sensor_key(&measurement)) {
+            Some(sensor) => {
+                sensor.interval = interval;
+                sensor.seen(now, &self.config);
+            }
+            None => {
+                let sensor = Sensor {
+                    name: measurement.name.clone(),
+                    tags: measurement.tags.clone(),
+                    interval,
+                    learned: 0.,
+                    samples: 0,
+                    last_seen: now,
+                    alerted: false,
+                };
+                self.sensors.insert(sensor_key(&measurement), sensor);
+            }
+        }
+        let mut measurements = vec![measurement];
+        measurements.extend(self.check(now));
+        self.save(false);
+        measurements
+    }
+
+    fn tick(&mut self, now: DateTime<Utc>) -> Vec<Measurement> {
+        let alerts = self.check(now);
+        if !alerts.is_empty() {
+            self.save(false);
+        }
+        alerts
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.save(true);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Watchdog, WatchdogConfig};
+    use crate::{
+        state::State,
+        transform::{at, metadata::SensorMetadata, Transform},
+        Measurement,
+    };
+    use chrono::TimeDelta;
+    use std::sync::Arc;
+
+    /// Report of sensor `id` at `seconds`
+    fn report(seconds: i64, id: u8) -> Measurement {
+        at(seconds, Measurement::new("tempHum").add_tag("sensorId", id))
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let metadata =
+            SensorMetadata::from_toml("[[sensors]]\nmatch = { sensorId = \"12\" }\ninterval = 60")
+                .unwrap();
+        let config = WatchdogConfig {
+            misses: 1,
+            ..Default::default()
+        };
+        let mut watchdog = Watchdog::with_config(Arc::new(metadata), config).unwrap();
+
+        assert_eq!(watchdog.apply(report(0, 12)).len(), 1);
+        assert_eq!(watchdog.apply(report(60, 13)).len(), 1);
+        let measurements = watchdog.apply(report(90, 13));
+        assert_eq!(measurements.len(), 2);
+        assert_eq!(
+            measurements[1],
+            Measurement::new("watchdog")
+                .add_tag("measurement", "tempHum")
+                .add_tag("sensorId", 12)
+                .add_field("silent", 90u64)
+                .add_field("expected", 60u64)
+                .add_time(report(90, 13).time)
+        );
+        // Reported once, until it came back
+        assert_eq!(watchdog.apply(report(120, 13)).len(), 1);
+        assert_eq!(watchdog.apply(report(130, 12)).len(), 1);
+        assert_eq!(watchdog.apply(report(200, 13)).len(), 2);
+    }
+
+    #[test]
+    fn test_learned_interval() {
+        let mut watchdog = Watchdog::new(Arc::new(SensorMetadata::default()));
+        // Sensor 12 reports every 100 s, sensor 13 every 10 s
+        for time in (0..=300).step_by(10) {
+            if time % 100 == 0 {
+                assert_eq!(watchdog.apply(report(time, 12)).len(), 1);
+            }
+            assert_eq!(watchdog.apply(report(time, 13)).len(), 1);
+        }
+        // Two missed reports of 12 are tolerated
+        for time in (310..=600).step_by(10) {
+            assert_eq!(watchdog.apply(report(time, 13)).len(), 1);
+        }
+        let measurements = watchdog.apply(report(610, 13));
+        assert_eq!(measurements.len(), 2);
+        assert_eq!(measurements[1].tag("sensorId"), Some("12"));
+        assert_eq!(
+            measurements[1].field("expected").and_then(|v| v.as_f64()),
+            Some(100.)
+        );
+    }
+
+    #[test]
+    fn test_invalid_smoothing() {
+        for smoothing in [-0.1, 1.5, f64::NAN] {
+            let config = WatchdogConfig {
+                smoothing,
+                ..Default::default()
+            };
+            assert!(Watchdog::with_config(Arc::default(), config).is_err());
+        }
+    }
+
+    #[test]
+    fn test_single_sensor_stops() {
+        let mut watchdog = Watchdog::new(Arc::new(SensorMetadata::default()));
+        for time in (0..=300).step_by(60) {
+            assert_eq!(watchdog.apply(report(time, 12)).len(), 1);
+        }
+        let start = report(0, 12).time.unwrap();
+        assert!(watchdog.tick(start + TimeDelta::seconds(480)).is_empty());
+        let alerts = watchdog.tick(start + TimeDelta::seconds(490));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(
+            alerts[0].field("silent").and_then(|v| v.as_f64()),
+            Some(190.)
+        );
+        // Reported once
+        assert!(watchdog.tick(start + TimeDelta::seconds(900)).is_empty());
+    }
+
+    #[test]
+    fn test_state() {
+        let path = std::env::temp_dir().join(format!("sensorflow-watchdog-{}", std::process::id()));
+        let state = State::open(&path).unwrap();
+        let mut watchdog = Watchdog::new(Arc::default())
+            .with_state(state.clone())
+            .unwrap();
+        for time in (0..=300).step_by(60) {
+            watchdog.apply(report(time, 12));
+        }
+        drop(watchdog);
+        drop(state);
+
+        // The learned interval and the last report are restored
+        let state = State::open(&path).unwrap();
+        let mut watchdog = Watchdog::new(Arc::default())
+            .with_state(state.clone())
+            .unwrap();
+        let start = report(0, 12).time.unwrap();
+        let alerts = watchdog.tick(start + TimeDelta::seconds(490));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].tag("sensorId"), Some("12"));
+        drop(watchdog);
+        drop(state);
+
+        // Reported only once across restarts
+        let state = State::open(&path).unwrap();
+        let mut watchdog = Watchdog::new(Arc::default()).with_state(state).unwrap();
+        assert!(watchdog.tick(start + TimeDelta::seconds(900)).is_empty());
+        drop(watchdog);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+//! End-to-end tests of the JeeLink input on virtual serial ports.
+//!
+//! Every test opens a pseudo-terminal pair, attaches a [JeeLink] to the slave side and plays a
+//! scripted byte stream of the firmware into the master side, while the full pipeline writes
+//! the decoded measurements to a capturing sink. The blocking reader is tested on its own.
+#![cfg(unix)]
+use async_trait::async_trait;
+use sensorflow::{
+    devices::{
+        jeelink::{JeeLink, JeeLinkConfig, JeeLinkFrame},
+        Device, DeviceHealth,
+    },
+    input::{error::DeviceError, line::SerialSettings},
+    output::OutputSink,
+    pipeline::{ChannelConfig, Pipeline},
+    FramedListener, Measurement,
+};
+use serialport::{SerialPort, TTYPort};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Default)]
+struct CapturingSink {
+    written: Arc<Mutex<Vec<Measurement>>>,
+}
+
+#[async_trait]
+impl OutputSink for CapturingSink {
+    async fn write(&mut self, measurement: &Measurement) -> anyhow::Result<()> {
+        self.written.lock().unwrap().push(measurement.clone());
+        Ok(())
+    }
+}
+
+impl CapturingSink {
+    /// Temperatures of the LaCrosse readings written so far, by sensor
+    fn readings(&self) -> Vec<(String, String)> {
+        self.written
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.name == "tempHum")
+            .map(|m| {
+                let sensor = m.tag("sensorId").unwrap().to_string();
+                let temperature = m.field("temperature").unwrap().as_f64().unwrap();
+                (sensor, format!("{:.1}", temperature))
+            })
+            .collect()
+    }
+
+    async fn wait_for_readings(&self, count: usize) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while self.readings().len() < count {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("Readings did not arrive in time");
+    }
+}
+
+/// Virtual serial port, the JeeLink reads what is written to `firmware`
+struct Pty {
+    firmware: TTYPort,
+    path: String,
+}
+
+impl Pty {
+    fn open() -> Pty {
+        let (firmware, slave) = TTYPort::pair().expect("Failed to open a PTY pair");
+        Pty {
+            firmware,
+            path: slave.name().unwrap(),
+        }
+    }
+
+    fn jeelink(&self) -> Box<JeeLink> {
+        Box::new(JeeLink::new(self.path.as_str()).unwrap())
+    }
+
+    fn send(&mut self, bytes: &[u8]) {
+        self.firmware.write_all(bytes).unwrap();
+        self.firmware.flush().unwrap();
+    }
+}
+
+fn reading(sensor: &str, temperature: &str) -> (String, String) {
+    (sensor.to_string(), temperature.to_string())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_resync_after_noise() {
+    let mut pty = Pty::open();
+    let sink = CapturingSink::default();
+    let pipeline = Pipeline::new(ChannelConfig::default())
+        .add_input(pty.jeelink())
+        .add_output(Box::new(sink.clone()));
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let task = tokio::spawn(pipeline.run_until(async {
+        let _ = stopped.await;
+    }));
+
+    // Startup banner, the tail of a frame from before the port was opened and line noise
+    pty.send(b"\r\n[LaCrosseITPlusReader.10.1s (RFM69CW f:868300 r:17241)]\r\n");
+    pty.send(b"4 193 65\r\n\x00\xff\x13OK");
+    pty.send(b"OK 9 50 1 4 193 65\r\n");
+    pty.send(b"\x00\x00OK 9 12 1 4 200 55\r\n");
+    sink.wait_for_readings(2).await;
+
+    stop.send(()).unwrap();
+    task.await.unwrap().unwrap();
+    assert_eq!(
+        sink.readings(),
+        [reading("50", "21.7"), reading("12", "22.4")]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_frames_split_across_reads() {
+    let mut pty = Pty::open();
+    let sink = CapturingSink::default();
+    let pipeline = Pipeline::new(ChannelConfig::default())
+        .add_input(pty.jeelink())
+        .add_output(Box::new(sink.clone()));
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let task = tokio::spawn(pipeline.run_until(async {
+        let _ = stopped.await;
+    }));
+
+    // One byte at a time, so the frame arrives in many reads
+    for byte in b"OK 9 50 1 4 193 65\r" {
+        pty.send(&[*byte]);
+        tokio::time::sleep(Duration::from_millis(2)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(sink.readings().is_empty());
+    // Two frames in a single write, the end sequence split from the first
+    pty.send(b"\nOK 9 12 1 4 200 55\r\nOK 9 50 1 4 194 65\r\n");
+    sink.wait_for_readings(3).await;
+
+    stop.send(()).unwrap();
+    task.await.unwrap().unwrap();
+    assert_eq!(
+        sink.readings(),
+        [
+            reading("50", "21.7"),
+            reading("12", "22.4"),
+            reading("50", "21.8")
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reconnect_after_unplug() {
+    let mut first = Pty::open();
+    let sink = CapturingSink::default();
+    let pipeline = Pipeline::new(ChannelConfig::default()).add_output(Box::new(sink.clone()));
+    let metrics = pipeline.metrics();
+    let control = pipeline.control();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let task = tokio::spawn(pipeline.run_until(async {
+        let _ = stopped.await;
+    }));
+
+    let id = control.add_input(first.jeelink()).unwrap();
+    first.send(b"OK 9 50 1 4 193 65\r\n");
+    sink.wait_for_readings(1).await;
+
+    // Unplugging the stick closes the firmware side of the port
+    drop(first);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while metrics.devices()[0].health == DeviceHealth::Connected {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .expect("Unplugging was not noticed");
+
+    // Plugged in again, the stick shows up as a new port
+    let mut second = Pty::open();
+    control.remove_input(id).await;
+    control.add_input(second.jeelink()).unwrap();
+    second.send(b"OK 9 12 1 4 200 55\r\n");
+    sink.wait_for_readings(2).await;
+    assert_eq!(metrics.devices()[0].health, DeviceHealth::Connected);
+    assert_eq!(metrics.devices()[0].address, second.path);
+
+    drop(control);
+    stop.send(()).unwrap();
+    task.await.unwrap().unwrap();
+    assert_eq!(
+        sink.readings(),
+        [reading("50", "21.7"), reading("12", "22.4")]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_read_timeout_keeps_partial_frame() {
+    let mut pty = Pty::open();
+    let mut jeelink = pty.jeelink();
+    pty.send(b"OK 9 50 1 4");
+
+    let error = jeelink
+        .read_frame_timeout(Duration::from_millis(50))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        error.downcast_ref::<DeviceError>(),
+        Some(&DeviceError::Timeout(Duration::from_millis(50)))
+    );
+
+    // The bytes read before the timeout are not lost
+    pty.send(b" 193 65\r\n");
+    let frame = jeelink
+        .read_frame_timeout(Duration::from_secs(5))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(frame.to_measurement().tag("sensorId"), Some("50"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_auto_baud() {
+    let mut pty = Pty::open();
+    let config = JeeLinkConfig {
+        rssi_command: Some("1r".into()),
+        serial: SerialSettings {
+            auto_baud: vec![115200, 57600],
+            probe_timeout: 1,
+            ..SerialSettings::default()
+        },
+    };
+    let mut jeelink = JeeLink::with_config(pty.path.as_str(), config).unwrap();
+    let read = tokio::spawn(async move {
+        let frame = jeelink.read_frame().await.unwrap().unwrap();
+        (frame.to_measurement(), jeelink)
+    });
+
+    // Noise at the first rate, then a frame at the second
+    pty.send(b"\xf0\x0fOK 9 x\r\n");
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    pty.send(b"OK 9 50 1 4 193 65\r\n");
+    let (measurement, _jeelink) = read.await.unwrap();
+    assert_eq!(measurement.tag("sensorId"), Some("50"));
+
+    // The firmware command was sent at both rates
+    let mut written = Vec::new();
+    pty.firmware.set_timeout(Duration::from_secs(5)).unwrap();
+    while written.len() < 4 {
+        let mut buffer = [0; 8];
+        let n = std::io::Read::read(&mut pty.firmware, &mut buffer).unwrap();
+        written.extend_from_slice(&buffer[..n]);
+    }
+    assert_eq!(written, b"1r1r");
+}
+
+#[test]
+fn test_blocking_reader() {
+    let (mut firmware, port) = TTYPort::pair().unwrap();
+    let mut reader = FramedListener::<TTYPort, JeeLinkFrame>::new(port);
+
+    let started = Instant::now();
+    let error = reader
+        .read_frame_timeout(Duration::from_millis(50))
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<DeviceError>(),
+        Some(&DeviceError::Timeout(Duration::from_millis(50)))
+    );
+    assert!(started.elapsed() >= Duration::from_millis(50));
+
+    firmware
+        .write_all(b"OK 9 50 1 4 193 65\r\nOK 9 12")
+        .unwrap();
+    let frame = reader.read_frame().unwrap().unwrap();
+    assert_eq!(frame.id(), 50);
+
+    // Unplugged within a frame, then between frames
+    drop(firmware);
+    assert_eq!(
+        reader
+            .read_frame()
+            .unwrap_err()
+            .downcast_ref::<DeviceError>(),
+        Some(&DeviceError::ConnectionLost)
+    );
+    let (firmware, port) = TTYPort::pair().unwrap();
+    let mut reader = FramedListener::<TTYPort, JeeLinkFrame>::new(port);
+    drop(firmware);
+    assert!(reader.read_frame().unwrap().is_none());
+}
@@ -0,0 +1,206 @@
+//! WebAssembly bindings decoding captured byte streams in the browser.
+//!
+//! A [Decoder] is fed the bytes of a capture in chunks of any size, as a serial port would
+//! deliver them, and returns the frames found so far as JSON.
+use bytes::BytesMut;
+use sensorflow::{
+    devices::{cul::CulFrame, davis, elm327::ElmResponse, enocean::EnOceanFrame},
+    devices::{jeelink::JeeLinkFrame, pms, wmbus},
+    error::FrameCheckError,
+    input::hexdump,
+    measurement::ToMeasurement,
+    output::json::to_json,
+    Frame,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use wasm_bindgen::prelude::*;
+
+/// Sensor name of readings which carry none in their frames
+const SENSOR: &str = "capture";
+
+/// Protocol of a capture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    JeeLink,
+    Cul,
+    EnOcean,
+    Davis,
+    Pms,
+    Elm327,
+    Imst,
+    Amber,
+}
+
+impl Protocol {
+    const ALL: [(&'static str, Protocol); 8] = [
+        ("jeelink", Protocol::JeeLink),
+        ("cul", Protocol::Cul),
+        ("enocean", Protocol::EnOcean),
+        ("davis", Protocol::Davis),
+        ("pms", Protocol::Pms),
+        ("elm327", Protocol::Elm327),
+        ("imst", Protocol::Imst),
+        ("amber", Protocol::Amber),
+    ];
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Protocol::ALL
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, protocol)| *protocol)
+            .ok_or_else(|| format!("Unknown protocol {}", s))
+    }
+}
+
+/// A frame and its measurement
+fn decoded<T: Display + ToMeasurement>(frame: T) -> Value {
+    json!({
+        "frame": frame.to_string(),
+        "measurement": to_json(&frame.to_measurement()),
+    })
+}
+
+fn error(error: impl Display, bytes: &[u8]) -> Value {
+    json!({ "error": error.to_string(), "hexdump": hexdump(bytes) })
+}
+
+/// Frames of the telegrams of a wireless M-Bus receiver
+fn telegram(telegram: Option<&wmbus::Telegram>) -> Option<Value> {
+    let telegram = telegram?;
+    Some(
+        match telegram.decode(&HashMap::new(), wmbus::Mode::default()) {
+            Ok(reading) => decoded(reading),
+            Err(e) => error(e, telegram.data()),
+        },
+    )
+}
+
+/// All complete frames in `buffer`, converted by `convert`. Frames without a result, e.g.
+/// responses to commands, are skipped.
+fn frames<F: Frame>(buffer: &mut BytesMut, convert: impl Fn(F) -> Option<Value>) -> Vec<Value> {
+    let mut frames = Vec::new();
+    loop {
+        let data = match F::check(buffer) {
+            Ok(data) => data,
+            Err(FrameCheckError::Incomplete) => break,
+            // The frame has been dropped
+            Err(e @ FrameCheckError::ChecksumError { .. }) => {
+                frames.push(json!({ "error": e.to_string() }));
+                continue;
+            }
+            Err(e) => {
+                frames.push(error(e, buffer));
+                buffer.clear();
+                break;
+            }
+        };
+        let bytes = data.to_vec();
+        match F::parse(data) {
+            Ok(frame) => frames.extend(convert(frame)),
+            Err(e) => frames.push(error(format!("{:#}", e), &bytes)),
+        }
+    }
+    frames
+}
+
+/// Decoder of the byte stream of a device
+#[wasm_bindgen]
+pub struct Decoder {
+    protocol: Protocol,
+    buffer: BytesMut,
+}
+
+#[wasm_bindgen]
+impl Decoder {
+    /// Decoder of one of the [protocols]
+    #[wasm_bindgen(constructor)]
+    pub fn new(protocol: &str) -> Result<Decoder, String> {
+        Ok(Decoder {
+            protocol: protocol.parse()?,
+            buffer: BytesMut::new(),
+        })
+    }
+
+    /// Feed the next bytes of the stream. Returns a JSON array of the frames completed by
+    /// them, each either `{"frame", "measurement"}` or `{"error", "hexdump"}`.
+    pub fn push(&mut self, data: &[u8]) -> String {
+        self.buffer.extend_from_slice(data);
+        let buffer = &mut self.buffer;
+        let frames = match self.protocol {
+            Protocol::JeeLink => frames(buffer, |frame: JeeLinkFrame| Some(decoded(frame))),
+            Protocol::Cul => frames(buffer, |frame| match frame {
+                CulFrame::Unknown(_) => None,
+                frame => Some(decoded(frame)),
+            }),
+            Protocol::EnOcean => frames(buffer, |frame: EnOceanFrame| Some(decoded(frame))),
+            Protocol::Davis => frames(buffer, |packet| {
+                Some(decoded(davis::DavisReading {
+                    station: SENSOR.into(),
+                    packet,
+                }))
+            }),
+            Protocol::Pms => frames(buffer, |frame| match frame {
+                pms::PmsFrame::Data(reading) => Some(decoded(pms::PmsOutput {
+                    sensor: SENSOR.into(),
+                    reading,
+                })),
+                pms::PmsFrame::CommandResponse => None,
+            }),
+            Protocol::Elm327 => frames(buffer, |ElmResponse(lines)| {
+                Some(json!({ "frame": lines.join("\n") }))
+            }),
+            Protocol::Imst => frames(buffer, |frame: wmbus::ImstFrame| telegram(frame.telegram())),
+            Protocol::Amber => frames(buffer, |frame: wmbus::AmberFrame| {
+                telegram(frame.telegram())
+            }),
+        };
+        Value::from(frames).to_string()
+    }
+}
+
+/// Names of the supported protocols
+#[wasm_bindgen]
+pub fn protocols() -> Vec<String> {
+    Protocol::ALL
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+/// Decode a complete capture, see [Decoder::push]
+#[wasm_bindgen]
+pub fn decode(protocol: &str, data: &[u8]) -> Result<String, String> {
+    Ok(Decoder::new(protocol)?.push(data))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, Decoder};
+    use sensorflow::input::hexdump;
+    use serde_json::Value;
+
+    #[test]
+    fn test_decoder() {
+        let mut decoder = Decoder::new("jeelink").unwrap();
+        assert_eq!(decoder.push(b"OK 9 50 1 4 1"), "[]");
+        let frames: Value = serde_json::from_str(&decoder.push(b"93 65\r\nOK 9 x\r\n")).unwrap();
+        assert_eq!(frames[0]["measurement"]["tags"]["sensorId"], "50");
+        assert_eq!(
+            frames[0]["measurement"]["fields"]["temperature"],
+            21.7f32 as f64
+        );
+        assert_eq!(frames[1]["hexdump"], hexdump(b"x"));
+
+        assert_eq!(decode("dsmr", b"").unwrap_err(), "Unknown protocol dsmr");
+        let frames: Value =
+            serde_json::from_str(&decode("cul", b"V 1.67\r\nF12340011\r\n").unwrap()).unwrap();
+        assert_eq!(frames.as_array().unwrap().len(), 1);
+    }
+}